@@ -0,0 +1,91 @@
+use axum::{extract::Path, routing::get, Router};
+use criterion::{criterion_group, criterion_main, Criterion};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::StreamExt;
+use memobuild::cache::{HttpRemoteCache, RemoteCache};
+use std::io::Write;
+
+/// Small objects fetched per iteration, like the files of a build context
+const OBJECTS: usize = 200;
+
+fn digest(i: usize) -> String {
+    format!("{:064x}", i)
+}
+
+/// A local server answering every layer request with the same small blob.
+fn serve(rt: &tokio::runtime::Runtime) -> String {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&[7u8; 2048]).unwrap();
+    let blob = encoder.finish().unwrap();
+    let app = Router::new().route(
+        "/cache/layer/:hash",
+        get(move |Path(_hash): Path<String>| {
+            let blob = blob.clone();
+            async move { blob }
+        }),
+    );
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    rt.spawn(async move {
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service())
+            .await
+            .unwrap();
+    });
+    url
+}
+
+async fn fetch_batched(cache: &HttpRemoteCache) {
+    let mut downloads = futures::stream::iter((0..OBJECTS).map(digest))
+        .map(|hash| async move { cache.get_layer(&hash).await.unwrap() })
+        .buffer_unordered(memobuild::constants::DEFAULT_MAX_TRANSFERS);
+    while downloads.next().await.is_some() {}
+}
+
+fn bench_small_objects(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let url = serve(&rt);
+    let mut group = c.benchmark_group("remote small objects");
+    group.sample_size(10);
+
+    // Every request on a new client, so a new connection: the old prefetch
+    group.bench_function("new connection per request", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                for i in 0..OBJECTS {
+                    let cache = HttpRemoteCache::new(url.clone());
+                    cache.get_layer(&digest(i)).await.unwrap();
+                }
+            })
+        })
+    });
+
+    let pooled = HttpRemoteCache::new(url.clone());
+    group.bench_function("pooled, sequential", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                for i in 0..OBJECTS {
+                    pooled.get_layer(&digest(i)).await.unwrap();
+                }
+            })
+        })
+    });
+    group.bench_function("pooled, batched", |b| {
+        b.iter(|| rt.block_on(fetch_batched(&pooled)))
+    });
+
+    std::env::set_var("MEMOBUILD_HTTP2", "1");
+    let multiplexed = HttpRemoteCache::new(url.clone());
+    std::env::remove_var("MEMOBUILD_HTTP2");
+    group.bench_function("HTTP/2, batched", |b| {
+        b.iter(|| rt.block_on(fetch_batched(&multiplexed)))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_small_objects);
+criterion_main!(benches);
@@ -68,6 +68,8 @@ async fn main() -> anyhow::Result<()> {
                 platform_properties: HashMap::new(),
                 output_files: vec!["output.txt".to_string()],
                 output_directories: Vec::new(),
+                namespace: None,
+                priority: 0,
             };
 
             sch.execute(action).await
@@ -0,0 +1,306 @@
+//! Which steps a set of changed files would rebuild, worked out from the
+//! graph without hashing or running anything.
+
+use crate::graph::{BuildGraph, Node, NodeKind};
+use crate::hasher::IgnoreRules;
+use crate::history::BuildHistory;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Why a step is affected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum AffectedReason {
+    /// It copies the changed file
+    Source { path: String },
+    /// The file matches its `# memobuild:inputs=` patterns
+    Inputs { path: String },
+    /// It is written in the changed Dockerfile or fragment
+    Dockerfile { path: String },
+    /// `memobuild.lock` changed and the step is a FROM
+    Lockfile,
+    /// `.dockerignore` changed and the step copies from the context
+    Ignore,
+    /// A step it depends on is affected
+    Parent { id: usize },
+}
+
+impl std::fmt::Display for AffectedReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AffectedReason::Source { path } => write!(f, "copies {}", path),
+            AffectedReason::Inputs { path } => write!(f, "declares input {}", path),
+            AffectedReason::Dockerfile { path } => write!(f, "written in {}", path),
+            AffectedReason::Lockfile => write!(f, "base image digest may have moved"),
+            AffectedReason::Ignore => write!(f, ".dockerignore changed"),
+            AffectedReason::Parent { id } => write!(f, "depends on step {}", id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AffectedNode {
+    pub id: usize,
+    pub name: String,
+    /// Line of the instruction in its Dockerfile, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    pub reason: AffectedReason,
+    /// Average duration of the step in past builds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Affected {
+    /// Changed paths as given, that fall inside the build context
+    pub changed: Vec<String>,
+    /// Affected steps in graph order
+    pub nodes: Vec<AffectedNode>,
+    pub total_nodes: usize,
+    /// Sum of the known step durations; steps that never ran count as 0
+    pub estimated_ms: u64,
+    /// Affected steps without a duration in the history
+    pub unknown_duration: usize,
+}
+
+impl Affected {
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+/// Steps of `graph` whose cache keys change when the files in `changed`
+/// do. Relative paths are taken relative to the current directory, as git
+/// hooks and editors pass them when run from the repository root.
+pub fn affected(
+    graph: &BuildGraph,
+    context_dir: &Path,
+    changed: &[PathBuf],
+    history: &BuildHistory,
+) -> Affected {
+    let context = resolve(context_dir);
+    let ignore = IgnoreRules::from_file(&context.join(".dockerignore"));
+    let changed: Vec<PathBuf> = changed.iter().map(|p| resolve(p)).collect();
+
+    let mut reasons: Vec<Option<AffectedReason>> = vec![None; graph.nodes.len()];
+    for path in &changed {
+        for node in &graph.nodes {
+            if reasons[node.id].is_none() {
+                reasons[node.id] = own_reason(node, path, &context, &ignore);
+            }
+        }
+    }
+    for id in graph.topological_order() {
+        if reasons[id].is_some() {
+            continue;
+        }
+        reasons[id] = graph.nodes[id]
+            .deps
+            .iter()
+            .find(|&&dep| reasons.get(dep).is_some_and(Option::is_some))
+            .map(|&dep| AffectedReason::Parent { id: dep });
+    }
+
+    let nodes: Vec<AffectedNode> = graph
+        .nodes
+        .iter()
+        .filter_map(|node| {
+            let reason = reasons[node.id].clone()?;
+            Some(AffectedNode {
+                id: node.id,
+                name: node.name.clone(),
+                line: node.metadata.span.as_ref().map(|s| s.line),
+                reason,
+                estimated_ms: history.get(node).map(|h| h.avg_duration_ms),
+            })
+        })
+        .collect();
+    Affected {
+        changed: changed
+            .iter()
+            .filter_map(|p| p.strip_prefix(&context).ok())
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect(),
+        total_nodes: graph.nodes.len(),
+        estimated_ms: nodes.iter().filter_map(|n| n.estimated_ms).sum(),
+        unknown_duration: nodes.iter().filter(|n| n.estimated_ms.is_none()).count(),
+        nodes,
+    }
+}
+
+/// Why changing `path` changes `node` itself, if it does.
+fn own_reason(
+    node: &Node,
+    path: &Path,
+    context: &Path,
+    ignore: &IgnoreRules,
+) -> Option<AffectedReason> {
+    if let Some(ref span) = node.metadata.span {
+        if resolve(&span.file) == path {
+            return Some(AffectedReason::Dockerfile {
+                path: span.file.to_string_lossy().into_owned(),
+            });
+        }
+    }
+    let rel = path.strip_prefix(context).ok()?;
+    let display = rel.to_string_lossy().into_owned();
+    if rel == Path::new(crate::constants::LOCKFILE_NAME) {
+        return matches!(node.kind, NodeKind::From).then_some(AffectedReason::Lockfile);
+    }
+    let copies_context = node.source_path.is_some() && !matches!(node.kind, NodeKind::Git { .. });
+    if rel == Path::new(".dockerignore") {
+        return copies_context.then_some(AffectedReason::Ignore);
+    }
+    if ignore.is_ignored(rel) {
+        return None;
+    }
+    let src = match &node.kind {
+        NodeKind::Copy { src, .. } if excluded(node, &src.to_string_lossy(), rel) => None,
+        NodeKind::Copy { src, .. } | NodeKind::CopyExtend { src, .. } => {
+            Some(src.to_string_lossy().into_owned())
+        }
+        NodeKind::Add { src, .. } if node.source_path.is_some() => Some(src.clone()),
+        _ => None,
+    };
+    if src.is_some_and(|src| covers(&src, rel)) {
+        return Some(AffectedReason::Source { path: display });
+    }
+    let inputs = node
+        .metadata
+        .directives
+        .get(crate::docker::directives::INPUTS)?;
+    crate::docker::directives::list(inputs)
+        .into_iter()
+        .any(|pattern| covers(pattern, rel))
+        .then_some(AffectedReason::Inputs { path: display })
+}
+
+/// Whether `rel` lies in the directory `src` a COPY copies and matches one of
+/// the node's `--exclude` patterns, which apply relative to `src`.
+fn excluded(node: &Node, src: &str, rel: &Path) -> bool {
+    let excludes = node.metadata.excludes();
+    if excludes.is_empty() {
+        return false;
+    }
+    let src = src.trim_start_matches("./").trim_end_matches('/');
+    let inner = if src.is_empty() || src == "." {
+        rel
+    } else {
+        match rel.strip_prefix(src) {
+            Ok(inner) => inner,
+            Err(_) => return false,
+        }
+    };
+    IgnoreRules::empty()
+        .with_patterns(excludes)
+        .is_ignored(inner)
+}
+
+/// Whether the source pattern `src` (relative to the context, possibly a
+/// glob) takes in `rel`, directly or as part of a directory.
+fn covers(src: &str, rel: &Path) -> bool {
+    let src = src.trim_start_matches("./").trim_end_matches('/');
+    if src.is_empty() || src == "." {
+        return true;
+    }
+    let Ok(pattern) = glob::Pattern::new(src) else {
+        return false;
+    };
+    rel.ancestors()
+        .filter(|a| !a.as_os_str().is_empty())
+        .any(|a| pattern.matches_path(a))
+}
+
+/// Absolute form of `path` for comparing, also for files that were deleted.
+fn resolve(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+    let absolute = std::env::current_dir()
+        .map(|cwd| cwd.join(path))
+        .unwrap_or_else(|_| path.to_path_buf());
+    match (absolute.parent(), absolute.file_name()) {
+        (Some(parent), Some(name)) => resolve(parent).join(name),
+        _ => absolute,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::parser;
+
+    #[test]
+    fn test_affected_by_changed_paths() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let ctx = dir.path();
+        std::fs::create_dir_all(ctx.join("src")).unwrap();
+        std::fs::create_dir_all(ctx.join("docs")).unwrap();
+        std::fs::write(ctx.join("src/main.rs"), "fn main() {}").unwrap();
+        std::fs::write(ctx.join("docs/guide.md"), "guide").unwrap();
+        std::fs::write(ctx.join("Cargo.toml"), "[package]").unwrap();
+        std::fs::write(ctx.join(".dockerignore"), "src/*.tmp\n").unwrap();
+        let dockerfile = ctx.join("Dockerfile");
+        let content = "FROM rust\n\
+             COPY Cargo.toml .\n\
+             RUN cargo fetch\n\
+             COPY src/ src/\n\
+             RUN cargo build\n\
+             # memobuild:inputs=docs/*.md\n\
+             RUN make docs\n";
+        std::fs::write(&dockerfile, content).unwrap();
+        let graph = crate::docker::include::build_graph_with_includes(
+            parser::parse_dockerfile_spanned(content, &dockerfile),
+            ctx.to_path_buf(),
+        )
+        .unwrap();
+        let mut history = BuildHistory::default();
+        history.record(&graph.nodes[4], 60_000, None);
+
+        let result = affected(&graph, ctx, &[ctx.join("src/main.rs")], &history);
+        let ids: Vec<usize> = result.nodes.iter().map(|n| n.id).collect();
+        assert!(ids.contains(&3) && ids.contains(&4));
+        assert!(!ids.contains(&1) && !ids.contains(&2));
+        assert_eq!(
+            result.nodes[0].reason,
+            AffectedReason::Source {
+                path: "src/main.rs".into()
+            }
+        );
+        assert_eq!(result.estimated_ms, 60_000);
+        assert_eq!(result.changed, vec!["src/main.rs".to_string()]);
+
+        let result = affected(&graph, ctx, &[ctx.join("docs/guide.md")], &history);
+        assert!(result
+            .nodes
+            .iter()
+            .any(|n| matches!(n.reason, AffectedReason::Inputs { .. })));
+        assert!(result.nodes.iter().all(|n| n.id >= 5));
+
+        // Ignored and outside the context
+        assert!(affected(&graph, ctx, &[ctx.join("src/x.tmp")], &history).is_empty());
+        assert!(affected(&graph, ctx, &[PathBuf::from("/elsewhere/file")], &history).is_empty());
+
+        let result = affected(&graph, ctx, &[dockerfile], &history);
+        assert_eq!(result.nodes.len(), graph.nodes.len());
+    }
+
+    #[test]
+    fn test_excluded_paths_do_not_affect_copy() {
+        let ctx = Path::new("/ctx");
+        let content = "FROM node
+COPY --exclude=*.md --exclude=test . /app
+RUN npm ci
+";
+        let graph = crate::docker::dag::build_graph_from_spanned(
+            parser::parse_dockerfile_spanned(content, &ctx.join("Dockerfile")),
+            ctx.to_path_buf(),
+        );
+        let history = BuildHistory::default();
+        let changed = |path: &str| affected(&graph, ctx, &[ctx.join(path)], &history);
+        assert!(changed("docs/guide.md").is_empty());
+        assert!(changed("test/app.test.js").is_empty());
+        assert_eq!(changed("src/index.js").nodes.len(), 2);
+    }
+}
@@ -343,5 +343,4 @@ pub fn auth_routes(state: Arc<AuthState>) -> Router {
 pub fn rate_limit_layer() -> impl Clone {
     // Rate limiting is handled in auth_middleware
     // This is a placeholder for future integration
-    ()
 }
@@ -2,14 +2,39 @@ pub mod local;
 pub mod hybrid;
 pub mod remote;
 pub mod http;
+pub mod encrypted;
+pub mod breaker;
+pub mod cas;
+pub mod store;
+pub mod throttle;
+pub mod fs;
+pub mod integrity;
+pub mod limits;
+pub mod mirror;
+pub mod object_store;
 pub mod cluster;
 pub mod metadata;
 pub mod utils;
+pub mod upload;
+pub mod pin;
+pub mod origin;
+pub mod clock;
 
 pub use local::LocalCache;
 pub use hybrid::HybridCache;
 pub use metadata::{DatabaseStats, PostgresMetadataStore, ReplicatedMetadataStore};
-pub use remote::{RemoteCache, RemoteCacheEntry};
+pub use remote::{remote_from_url, RemoteCache, RemoteCacheEntry, RemoteReader};
 pub use http::HttpRemoteCache;
+pub use encrypted::EncryptedRemoteCache;
+pub use breaker::{CircuitBreakerRemoteCache, RemoteHealth};
+pub use throttle::ThrottledRemoteCache;
+pub use mirror::{MirrorStatus, MirroredRemoteCache};
+pub use upload::UploadStats;
+pub use pin::PinTarget;
+pub use origin::EntryOrigin;
+pub use cas::{ContentStore, StoreStats, Tree, TreeEntry};
+pub use store::ArtifactStore;
+pub use fs::FsRemoteCache;
+pub use object_store::StorageRemoteCache;
 pub use cluster::{CacheCluster, ClusterNode, ClusterStatus, DistributedCache};
 pub use utils::{ArtifactLayer, ArtifactManifest, FileEntry, merge_artifact, split_artifact};
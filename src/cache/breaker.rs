@@ -0,0 +1,361 @@
+use crate::cache::integrity::RejectedArtifact;
+use crate::cache::remote::{RemoteCache, RemoteReader};
+use crate::dashboard::BuildEvent;
+use crate::graph::BuildGraph;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Shared view of how the remote cache has been behaving during a build.
+#[derive(Debug, Default)]
+pub struct RemoteHealth {
+    /// Calls that failed or timed out
+    failures: AtomicU64,
+    /// Calls not attempted because the breaker was open
+    skipped: AtomicU64,
+    /// The breaker opened at least once
+    tripped: AtomicBool,
+    last_error: Mutex<Option<String>>,
+}
+
+impl RemoteHealth {
+    pub fn is_degraded(&self) -> bool {
+        self.tripped.load(Ordering::SeqCst) || self.failures.load(Ordering::SeqCst) > 0
+    }
+
+    /// One-line note for the end of the build, if anything went wrong.
+    pub fn summary(&self) -> Option<String> {
+        if !self.is_degraded() {
+            return None;
+        }
+        let mut note = format!(
+            "Remote cache degraded: {} failed call(s), {} skipped while offline",
+            self.failures.load(Ordering::SeqCst),
+            self.skipped.load(Ordering::SeqCst)
+        );
+        if let Some(ref err) = *self.last_error.lock().unwrap() {
+            note.push_str(&format!(" (last error: {})", err));
+        }
+        Some(note)
+    }
+}
+
+/// Wraps a remote cache so an outage degrades the build to local-only instead
+/// of failing or hanging it.
+///
+/// Every call is bounded by a timeout. Failed reads count as misses and failed
+/// writes are dropped. After `threshold` consecutive failures the breaker opens
+/// and the remote is skipped entirely; once `retry_after` has passed the next
+/// call goes through as a probe while the others keep skipping it, and a
+/// success closes the breaker again.
+pub struct CircuitBreakerRemoteCache {
+    inner: Arc<dyn RemoteCache>,
+    threshold: u32,
+    retry_after: Duration,
+    timeout: Duration,
+    consecutive_failures: AtomicU32,
+    open_until: Mutex<Option<Instant>>,
+    /// A probe of the open breaker is in flight
+    probing: AtomicBool,
+    health: Arc<RemoteHealth>,
+}
+
+/// Whether a call may go to the remote.
+enum Admission<'a> {
+    Closed,
+    /// The one call trying the remote after `retry_after`
+    Probe(ProbeGuard<'a>),
+    Open,
+}
+
+/// Ends the probe when dropped, also when the call is cancelled.
+struct ProbeGuard<'a>(&'a AtomicBool);
+
+impl Drop for ProbeGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl CircuitBreakerRemoteCache {
+    pub fn new(
+        inner: Arc<dyn RemoteCache>,
+        threshold: u32,
+        retry_after: Duration,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            inner,
+            threshold: threshold.max(1),
+            retry_after,
+            timeout,
+            consecutive_failures: AtomicU32::new(0),
+            open_until: Mutex::new(None),
+            probing: AtomicBool::new(false),
+            health: Arc::new(RemoteHealth::default()),
+        }
+    }
+
+    /// Settings from `MEMOBUILD_REMOTE_FAILURE_THRESHOLD`,
+    /// `MEMOBUILD_REMOTE_RETRY_SECS` and `MEMOBUILD_REMOTE_TIMEOUT_SECS`.
+    pub fn from_env(inner: Arc<dyn RemoteCache>) -> Self {
+        let var = |name: &str, default: u64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        Self::new(
+            inner,
+            var(
+                "MEMOBUILD_REMOTE_FAILURE_THRESHOLD",
+                crate::constants::DEFAULT_REMOTE_FAILURE_THRESHOLD as u64,
+            ) as u32,
+            Duration::from_secs(var(
+                "MEMOBUILD_REMOTE_RETRY_SECS",
+                crate::constants::DEFAULT_REMOTE_RETRY_SECS,
+            )),
+            Duration::from_secs(var(
+                "MEMOBUILD_REMOTE_TIMEOUT_SECS",
+                crate::constants::DEFAULT_REMOTE_TIMEOUT_SECS,
+            )),
+        )
+    }
+
+    pub fn health(&self) -> Arc<RemoteHealth> {
+        self.health.clone()
+    }
+
+    /// Check the remote once before the build starts. An unreachable remote
+    /// opens the breaker right away so the build doesn't wait on it.
+    pub async fn probe(&self) -> bool {
+        let ok = self
+            .call(false, async {
+                self.inner.has("memobuild-health-probe").await?;
+                Ok(true)
+            })
+            .await;
+        if !ok {
+            self.trip();
+        }
+        ok
+    }
+
+    fn admit(&self) -> Admission<'_> {
+        match *self.open_until.lock().unwrap() {
+            None => Admission::Closed,
+            Some(until) if Instant::now() < until => Admission::Open,
+            Some(_) => {
+                match self
+                    .probing
+                    .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                {
+                    Ok(_) => Admission::Probe(ProbeGuard(&self.probing)),
+                    Err(_) => Admission::Open,
+                }
+            }
+        }
+    }
+
+    fn trip(&self) {
+        *self.open_until.lock().unwrap() = Some(Instant::now() + self.retry_after);
+        if !self.health.tripped.swap(true, Ordering::SeqCst) {
+            eprintln!(
+                "⚠️  Remote cache unavailable, continuing with the local cache only (retrying in {}s)",
+                self.retry_after.as_secs()
+            );
+        }
+    }
+
+    fn record_success(&self) {
+        self.consecutive_failures.store(0, Ordering::SeqCst);
+        let mut open_until = self.open_until.lock().unwrap();
+        if open_until.take().is_some() {
+            println!("   ✅ Remote cache reachable again");
+        }
+    }
+
+    fn record_failure(&self, err: String) {
+        self.health.failures.fetch_add(1, Ordering::SeqCst);
+        *self.health.last_error.lock().unwrap() = Some(err);
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures >= self.threshold {
+            self.trip();
+        }
+    }
+
+    /// Run `op` unless the breaker is open; on error or timeout return `fallback`.
+    async fn call<T>(&self, fallback: T, op: impl Future<Output = Result<T>>) -> T {
+        let _probe = match self.admit() {
+            Admission::Open => {
+                self.health.skipped.fetch_add(1, Ordering::SeqCst);
+                return fallback;
+            }
+            Admission::Probe(guard) => Some(guard),
+            Admission::Closed => None,
+        };
+        match tokio::time::timeout(self.timeout, op).await {
+            Ok(Ok(value)) => {
+                self.record_success();
+                value
+            }
+            Ok(Err(e)) => {
+                self.record_failure(e.to_string());
+                fallback
+            }
+            Err(_) => {
+                self.record_failure(format!("timed out after {}s", self.timeout.as_secs()));
+                fallback
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl RemoteCache for CircuitBreakerRemoteCache {
+    async fn has(&self, hash: &str) -> Result<bool> {
+        Ok(self.call(false, self.inner.has(hash)).await)
+    }
+
+    async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.call(None, self.inner.get(hash)).await)
+    }
+
+    async fn get_reader(&self, hash: &str) -> Result<Option<RemoteReader>> {
+        Ok(self.call(None, self.inner.get_reader(hash)).await)
+    }
+
+    async fn put(&self, hash: &str, data: &[u8]) -> Result<()> {
+        self.call((), self.inner.put(hash, data)).await;
+        Ok(())
+    }
+
+    async fn has_layer(&self, hash: &str) -> Result<bool> {
+        Ok(self.call(false, self.inner.has_layer(hash)).await)
+    }
+
+    async fn get_layer(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.call(None, self.inner.get_layer(hash)).await)
+    }
+
+    async fn put_layer(&self, hash: &str, data: &[u8]) -> Result<()> {
+        self.call((), self.inner.put_layer(hash, data)).await;
+        Ok(())
+    }
+
+    async fn get_node_layers(&self, hash: &str) -> Result<Option<Vec<String>>> {
+        Ok(self.call(None, self.inner.get_node_layers(hash)).await)
+    }
+
+    async fn register_node_layers(
+        &self,
+        hash: &str,
+        layers: &[String],
+        total_size: u64,
+    ) -> Result<()> {
+        self.call(
+            (),
+            self.inner.register_node_layers(hash, layers, total_size),
+        )
+        .await;
+        Ok(())
+    }
+
+    async fn invalidate(&self, hash: &str) -> Result<()> {
+        // An explicit request; the caller needs to know if it did not happen
+        self.inner.invalidate(hash).await
+    }
+
+    async fn prefetch_hints(&self, hash: &str) -> Result<Vec<String>> {
+        Ok(self.call(Vec::new(), self.inner.prefetch_hints(hash)).await)
+    }
+
+    async fn report_corrupt_layer(&self, rejected: &RejectedArtifact) -> Result<bool> {
+        Ok(self
+            .call(false, self.inner.report_corrupt_layer(rejected))
+            .await)
+    }
+
+    async fn get_layer_from_alternates(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .call(None, self.inner.get_layer_from_alternates(hash))
+            .await)
+    }
+
+    fn authenticates_layers(&self) -> bool {
+        self.inner.authenticates_layers()
+    }
+
+    async fn report_build_event(&self, event: BuildEvent) -> Result<()> {
+        self.call((), self.inner.report_build_event(event)).await;
+        Ok(())
+    }
+
+    async fn report_dag(&self, dag: &BuildGraph) -> Result<()> {
+        self.call((), self.inner.report_dag(dag)).await;
+        Ok(())
+    }
+
+    async fn report_analytics(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()> {
+        self.call((), self.inner.report_analytics(dirty, cached, duration_ms))
+            .await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unreachable_remote_opens_breaker() {
+        // Nothing listens on the discard port
+        let inner: Arc<dyn RemoteCache> = Arc::new(crate::cache::HttpRemoteCache::new(
+            "http://127.0.0.1:9".to_string(),
+        ));
+        let cache = CircuitBreakerRemoteCache::new(
+            inner,
+            2,
+            Duration::from_secs(60),
+            Duration::from_secs(5),
+        );
+
+        assert!(cache.get_layer("abc").await.unwrap().is_none());
+        assert!(matches!(cache.admit(), Admission::Closed));
+        assert!(cache.get_layer("abc").await.unwrap().is_none());
+        assert!(matches!(cache.admit(), Admission::Open));
+
+        // Open breaker: no network call, writes are dropped quietly
+        cache.put_layer("abc", b"data").await.unwrap();
+        let health = cache.health();
+        assert!(health.is_degraded());
+        assert_eq!(health.skipped.load(Ordering::SeqCst), 1);
+        assert!(health.summary().unwrap().contains("2 failed"));
+    }
+
+    #[tokio::test]
+    async fn test_one_probe_at_a_time() {
+        let inner: Arc<dyn RemoteCache> = Arc::new(crate::cache::HttpRemoteCache::new(
+            "http://127.0.0.1:9".to_string(),
+        ));
+        let cache =
+            CircuitBreakerRemoteCache::new(inner, 1, Duration::ZERO, Duration::from_secs(5));
+        cache.trip();
+
+        // Past `retry_after`, one call probes and the rest still skip
+        let probe = cache.admit();
+        assert!(matches!(probe, Admission::Probe(_)));
+        assert!(matches!(cache.admit(), Admission::Open));
+        drop(probe);
+        assert!(matches!(cache.admit(), Admission::Probe(_)));
+
+        // A failed probe keeps the breaker open
+        assert!(!cache.has("abc").await.unwrap());
+        assert!(cache.open_until.lock().unwrap().is_some());
+        cache.record_success();
+        assert!(matches!(cache.admit(), Admission::Closed));
+    }
+}
@@ -0,0 +1,548 @@
+use crate::cache::remote::RemoteCache;
+use crate::hasher::file_hasher::hash_file;
+use crate::hasher::IgnoreRules;
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// One entry of a directory in a Merkle tree manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TreeEntry {
+    File {
+        name: String,
+        digest: String,
+        size: u64,
+        executable: bool,
+    },
+    Dir {
+        name: String,
+        digest: String,
+    },
+    Symlink {
+        name: String,
+        target: String,
+    },
+}
+
+impl TreeEntry {
+    pub fn name(&self) -> &str {
+        match self {
+            TreeEntry::File { name, .. }
+            | TreeEntry::Dir { name, .. }
+            | TreeEntry::Symlink { name, .. } => name,
+        }
+    }
+}
+
+/// A directory listing, sorted by name. Its digest is the BLAKE3 hash of its
+/// JSON encoding, so a directory's digest changes exactly when something
+/// below it does.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tree {
+    pub entries: Vec<TreeEntry>,
+}
+
+impl Tree {
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+
+    pub fn digest(&self) -> String {
+        blake3::hash(&self.encode()).to_hex().to_string()
+    }
+}
+
+/// Outcome of storing a directory.
+#[derive(Debug, Clone, Default)]
+pub struct StoreStats {
+    /// Digest of the root tree
+    pub root: String,
+    pub files: usize,
+    /// Files whose content was not in the store yet
+    pub new_files: usize,
+    pub new_bytes: u64,
+}
+
+/// Objects moved between the local store and a remote cache.
+#[derive(Debug, Clone, Default)]
+pub struct TransferStats {
+    pub objects: usize,
+    pub bytes: u64,
+}
+
+/// File-level content-addressable store for build contexts.
+///
+/// Every file is kept once under the hash of its content and every directory
+/// as a [`Tree`] object, so storing a context again after a small edit only
+/// writes the changed files and the trees on their path to the root.
+///
+/// A tree object is only written after everything below it, locally and on
+/// the remote, so having a tree implies having its whole subtree.
+#[derive(Clone)]
+pub struct ContentStore {
+    root: PathBuf,
+}
+
+impl ContentStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// The store kept inside a local cache directory.
+    pub fn in_cache_dir(cache_dir: &Path) -> Self {
+        Self::new(cache_dir.join("cas"))
+    }
+
+    fn object_path(&self, digest: &str) -> Result<PathBuf> {
+        if digest.len() < 2 || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+            anyhow::bail!("Invalid object digest '{}'", digest);
+        }
+        Ok(self.root.join(&digest[..2]).join(digest))
+    }
+
+    pub fn contains(&self, digest: &str) -> bool {
+        self.object_path(digest)
+            .map(|p| p.exists())
+            .unwrap_or(false)
+    }
+
+    pub fn get(&self, digest: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.object_path(digest)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read(path)?))
+    }
+
+    /// Up to `len` bytes of the object `digest` from `offset`, without
+    /// reading the rest of it.
+    pub fn read_at(&self, digest: &str, offset: u64, len: usize) -> Result<Option<Vec<u8>>> {
+        use std::io::{Read, Seek, SeekFrom};
+        let mut file = match fs::File::open(self.object_path(digest)?) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::with_capacity(len);
+        file.take(len as u64).read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+
+    /// Store `data`, returning its digest and whether it was new.
+    pub fn put(&self, data: &[u8]) -> Result<(String, bool)> {
+        let digest = blake3::hash(data).to_hex().to_string();
+        let path = self.object_path(&digest)?;
+        if path.exists() {
+            return Ok((digest, false));
+        }
+        self.write_object(&path, |tmp| Ok(fs::write(tmp, data)?))?;
+        Ok((digest, true))
+    }
+
+    /// Store the file at `path` without reading it twice when it is already
+    /// present. Returns its digest, size and whether it was new.
+    pub fn put_file(&self, path: &Path) -> Result<(String, u64, bool)> {
+        let digest = hash_file(path)?;
+        let size = fs::metadata(path)?.len();
+        let object = self.object_path(&digest)?;
+        if object.exists() {
+            return Ok((digest, size, false));
+        }
+        self.write_object(&object, |tmp| {
+            fs::copy(path, tmp).with_context(|| format!("Failed to store {}", path.display()))?;
+            Ok(())
+        })?;
+        Ok((digest, size, true))
+    }
+
+    /// Write through a temp file so readers never see a partial object.
+    fn write_object(&self, path: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+        let dir = path.parent().context("Object path has no parent")?;
+        fs::create_dir_all(dir)?;
+        let tmp = dir.join(format!(
+            ".tmp-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        write(&tmp)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn get_tree(&self, digest: &str) -> Result<Tree> {
+        let data = self
+            .get(digest)?
+            .with_context(|| format!("Tree {} is not in the store", digest))?;
+        serde_json::from_slice(&data).with_context(|| format!("Object {} is not a tree", digest))
+    }
+
+    /// Store a directory (or a single file, as a one-entry tree) and return
+    /// the root digest. Paths matched by `ignore` are left out.
+    pub fn store_dir(&self, path: &Path, ignore: &IgnoreRules) -> Result<StoreStats> {
+        let mut stats = StoreStats::default();
+        let tree = if path.is_dir() {
+            self.store_subtree(path, Path::new(""), ignore, &mut stats)?
+        } else {
+            let name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            Tree {
+                entries: vec![self.store_file(path, name, &mut stats)?],
+            }
+        };
+        stats.root = self.put(&tree.encode())?.0;
+        Ok(stats)
+    }
+
+    fn store_file(&self, path: &Path, name: String, stats: &mut StoreStats) -> Result<TreeEntry> {
+        let (digest, size, new) = self.put_file(path)?;
+        stats.files += 1;
+        if new {
+            stats.new_files += 1;
+            stats.new_bytes += size;
+        }
+        Ok(TreeEntry::File {
+            name,
+            digest,
+            size,
+            executable: is_executable(path),
+        })
+    }
+
+    fn store_subtree(
+        &self,
+        dir: &Path,
+        rel: &Path,
+        ignore: &IgnoreRules,
+        stats: &mut StoreStats,
+    ) -> Result<Tree> {
+        let mut children: Vec<(String, PathBuf, fs::FileType)> = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if ignore.is_ignored(&rel.join(&name)) {
+                continue;
+            }
+            children.push((name, entry.path(), entry.file_type()?));
+        }
+        children.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // Files of one directory are hashed in parallel, subdirectories in turn
+        let files: Vec<Result<(String, u64, bool)>> = children
+            .par_iter()
+            .map(|(_, path, file_type)| {
+                if file_type.is_file() {
+                    self.put_file(path)
+                } else {
+                    Ok(Default::default())
+                }
+            })
+            .collect();
+
+        let mut entries = Vec::with_capacity(children.len());
+        for ((name, path, file_type), stored) in children.into_iter().zip(files) {
+            if file_type.is_dir() {
+                let subtree = self.store_subtree(&path, &rel.join(&name), ignore, stats)?;
+                let (digest, _) = self.put(&subtree.encode())?;
+                entries.push(TreeEntry::Dir { name, digest });
+            } else if file_type.is_symlink() {
+                let target = fs::read_link(&path)?.to_string_lossy().to_string();
+                entries.push(TreeEntry::Symlink { name, target });
+            } else if file_type.is_file() {
+                let (digest, size, new) = stored?;
+                stats.files += 1;
+                if new {
+                    stats.new_files += 1;
+                    stats.new_bytes += size;
+                }
+                entries.push(TreeEntry::File {
+                    executable: is_executable(&path),
+                    name,
+                    digest,
+                    size,
+                });
+            }
+        }
+        Ok(Tree { entries })
+    }
+
+    /// Recreate the tree `root` under `dest`.
+    pub fn materialize(&self, root: &str, dest: &Path) -> Result<()> {
+        fs::create_dir_all(dest)?;
+        for entry in self.get_tree(root)?.entries {
+            let path = dest.join(entry.name());
+            match entry {
+                TreeEntry::File {
+                    digest, executable, ..
+                } => {
+                    let object = self.object_path(&digest)?;
+                    fs::copy(&object, &path).with_context(|| {
+                        format!("Failed to restore {} from {}", path.display(), digest)
+                    })?;
+                    set_executable(&path, executable)?;
+                }
+                TreeEntry::Dir { digest, .. } => self.materialize(&digest, &path)?,
+                TreeEntry::Symlink { target, .. } => create_symlink(&target, &path)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Push whatever part of the tree `root` the remote lacks, using the
+/// content-addressed layer endpoints. A subtree the remote already has is
+/// skipped without looking inside it.
+pub async fn upload_tree(
+    store: &ContentStore,
+    root: &str,
+    remote: &dyn RemoteCache,
+) -> Result<TransferStats> {
+    let mut stats = TransferStats::default();
+    // Post-order: a tree goes up only after its children
+    let mut stack = vec![(root.to_string(), false)];
+    while let Some((digest, children_done)) = stack.pop() {
+        if children_done {
+            let data = store.get(&digest)?.context("Tree vanished from store")?;
+            remote.put_layer(&digest, &data).await?;
+            stats.objects += 1;
+            stats.bytes += data.len() as u64;
+            continue;
+        }
+        if remote.has_layer(&digest).await? {
+            continue;
+        }
+        let tree = store.get_tree(&digest)?;
+        stack.push((digest, true));
+        for entry in tree.entries {
+            match entry {
+                TreeEntry::File { digest, .. } => {
+                    if !remote.has_layer(&digest).await? {
+                        let data = store
+                            .get(&digest)?
+                            .with_context(|| format!("File {} missing from store", digest))?;
+                        remote.put_layer(&digest, &data).await?;
+                        stats.objects += 1;
+                        stats.bytes += data.len() as u64;
+                    }
+                }
+                TreeEntry::Dir { digest, .. } => stack.push((digest, false)),
+                TreeEntry::Symlink { .. } => {}
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Pull whatever part of the tree `root` the local store lacks from the
+/// remote, verifying every object against its digest. The missing files of a
+/// directory are downloaded together, `MEMOBUILD_MAX_TRANSFERS` at a time, so
+/// a tree of many small files does not wait out one round trip per file.
+pub async fn fetch_tree(
+    store: &ContentStore,
+    root: &str,
+    remote: &dyn RemoteCache,
+) -> Result<TransferStats> {
+    let in_flight = crate::cache::throttle::max_transfers_from_env()?;
+    let mut stats = TransferStats::default();
+    let mut pending: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut stack = vec![(root.to_string(), false)];
+    while let Some((digest, children_done)) = stack.pop() {
+        if children_done {
+            if let Some(data) = pending.remove(&digest) {
+                store.put(&data)?;
+            }
+            continue;
+        }
+        if store.contains(&digest) {
+            continue;
+        }
+        let data = fetch_object(remote, &digest).await?;
+        stats.objects += 1;
+        stats.bytes += data.len() as u64;
+        let tree: Tree = serde_json::from_slice(&data)
+            .with_context(|| format!("Remote object {} is not a tree", digest))?;
+        pending.insert(digest.clone(), data);
+        stack.push((digest, true));
+
+        let mut missing = Vec::new();
+        let mut seen = HashSet::new();
+        for entry in tree.entries {
+            match entry {
+                TreeEntry::File { digest, .. } => {
+                    if !store.contains(&digest) && seen.insert(digest.clone()) {
+                        missing.push(digest);
+                    }
+                }
+                TreeEntry::Dir { digest, .. } => stack.push((digest, false)),
+                TreeEntry::Symlink { .. } => {}
+            }
+        }
+        // Owned digests: a stream of futures borrowing from `missing` is not
+        // `Send` for every lifetime, which spawned builds require
+        let mut files = futures::stream::iter(missing)
+            .map(|digest| async move { fetch_object(remote, &digest).await })
+            .buffer_unordered(in_flight);
+        while let Some(data) = files.next().await {
+            let data = data?;
+            stats.objects += 1;
+            stats.bytes += data.len() as u64;
+            store.put(&data)?;
+        }
+    }
+    Ok(stats)
+}
+
+async fn fetch_object(remote: &dyn RemoteCache, digest: &str) -> Result<Vec<u8>> {
+    let data = remote
+        .get_layer(digest)
+        .await?
+        .with_context(|| format!("Cache integrity failure: object {} missing", digest))?;
+    if blake3::hash(&data).to_hex().as_str() != digest {
+        anyhow::bail!("Cache integrity failure: object {} is corrupt", digest);
+    }
+    Ok(data)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .map(|m| m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path, executable: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = if executable { 0o755 } else { 0o644 };
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path, _executable: bool) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &str, path: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_symlink(target: &str, path: &Path) -> Result<()> {
+    anyhow::bail!(
+        "Cannot restore symlink {} -> {} on this platform",
+        path.display(),
+        target
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::FsRemoteCache;
+    use tempfile::TempDir;
+
+    fn make_context() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("main.rs"), "fn main() {}").unwrap();
+        fs::create_dir_all(dir.path().join("assets/img")).unwrap();
+        fs::write(dir.path().join("assets/img/logo.png"), vec![7u8; 4096]).unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/lib.rs"), "pub fn f() {}").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_small_edit_stores_only_changed_file() {
+        let ctx = make_context();
+        let store_dir = TempDir::new().unwrap();
+        let store = ContentStore::new(store_dir.path());
+
+        let first = store.store_dir(ctx.path(), &IgnoreRules::empty()).unwrap();
+        assert_eq!((first.files, first.new_files), (3, 3));
+
+        fs::write(ctx.path().join("src/lib.rs"), "pub fn g() {}").unwrap();
+        let second = store.store_dir(ctx.path(), &IgnoreRules::empty()).unwrap();
+        assert_eq!(second.new_files, 1);
+        assert_ne!(first.root, second.root);
+
+        // The untouched subtree keeps its digest
+        let dir_digest = |root: &str, name: &str| {
+            store
+                .get_tree(root)
+                .unwrap()
+                .entries
+                .into_iter()
+                .find(|e| e.name() == name)
+                .unwrap()
+        };
+        assert_eq!(
+            dir_digest(&first.root, "assets"),
+            dir_digest(&second.root, "assets")
+        );
+        assert_ne!(
+            dir_digest(&first.root, "src"),
+            dir_digest(&second.root, "src")
+        );
+
+        let out = TempDir::new().unwrap();
+        store.materialize(&second.root, out.path()).unwrap();
+        assert_eq!(
+            fs::read_to_string(out.path().join("src/lib.rs")).unwrap(),
+            "pub fn g() {}"
+        );
+        assert_eq!(
+            fs::read(out.path().join("assets/img/logo.png"))
+                .unwrap()
+                .len(),
+            4096
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upload_skips_known_subtrees() {
+        let ctx = make_context();
+        let store_dir = TempDir::new().unwrap();
+        let remote_dir = TempDir::new().unwrap();
+        let store = ContentStore::new(store_dir.path());
+        let remote = FsRemoteCache::new(remote_dir.path()).unwrap();
+
+        let first = store.store_dir(ctx.path(), &IgnoreRules::empty()).unwrap();
+        let up = upload_tree(&store, &first.root, &remote).await.unwrap();
+        // 3 files and 4 trees
+        assert_eq!(up.objects, 7);
+
+        fs::write(ctx.path().join("main.rs"), "fn main() { run() }").unwrap();
+        let second = store.store_dir(ctx.path(), &IgnoreRules::empty()).unwrap();
+        let up = upload_tree(&store, &second.root, &remote).await.unwrap();
+        // The new file and the new root tree
+        assert_eq!(up.objects, 2);
+
+        let fresh_dir = TempDir::new().unwrap();
+        let fresh = ContentStore::new(fresh_dir.path());
+        let down = fetch_tree(&fresh, &second.root, &remote).await.unwrap();
+        assert_eq!(down.objects, 7);
+        let out = TempDir::new().unwrap();
+        fresh.materialize(&second.root, out.path()).unwrap();
+        assert_eq!(
+            fs::read_to_string(out.path().join("main.rs")).unwrap(),
+            "fn main() { run() }"
+        );
+    }
+}
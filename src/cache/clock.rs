@@ -0,0 +1,109 @@
+//! Timestamps of cache entries, which never go backwards within a process and
+//! are aged with `MEMOBUILD_CLOCK_SKEW_SECS` of slack.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+/// Last stamp handed out, in milliseconds since the epoch
+static LAST_STAMP_MS: AtomicI64 = AtomicI64::new(0);
+
+/// Milliseconds since the epoch by the wall clock, but never earlier than a
+/// stamp this process returned before: when the clock steps back, stamps
+/// keep counting up from the last one until it catches up.
+pub fn stamp_ms() -> i64 {
+    let wall = chrono::Utc::now().timestamp_millis();
+    let mut last = LAST_STAMP_MS.load(Ordering::SeqCst);
+    loop {
+        let next = wall.max(last + 1);
+        match LAST_STAMP_MS.compare_exchange(last, next, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return next,
+            Err(current) => last = current,
+        }
+    }
+}
+
+/// [`stamp_ms`] in whole seconds, as the local cache index stores them.
+pub fn stamp_secs() -> i64 {
+    stamp_ms() / 1000
+}
+
+/// [`stamp_ms`] as RFC 3339, as the cache server's metadata stores it.
+pub fn stamp_rfc3339() -> String {
+    chrono::DateTime::from_timestamp_millis(stamp_ms())
+        .unwrap_or_else(chrono::Utc::now)
+        .to_rfc3339()
+}
+
+/// Clock skew tolerated between machines, from `MEMOBUILD_CLOCK_SKEW_SECS`.
+pub fn skew_tolerance() -> Duration {
+    let secs = std::env::var("MEMOBUILD_CLOCK_SKEW_SECS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(crate::constants::DEFAULT_CLOCK_SKEW_SECS);
+    Duration::from_secs(secs)
+}
+
+/// How long before `now_ms` the stamp `stamp_ms` is. `None` for a stamp
+/// further ahead of `now_ms` than `tolerance`; a stamp ahead by less counts
+/// as just written.
+pub fn age(stamp_ms: i64, now_ms: i64, tolerance: Duration) -> Option<Duration> {
+    let ahead = stamp_ms.saturating_sub(now_ms);
+    if ahead > tolerance.as_millis() as i64 {
+        return None;
+    }
+    Some(Duration::from_millis(
+        now_ms.saturating_sub(stamp_ms).max(0) as u64,
+    ))
+}
+
+/// Whether an entry last used at `stamp_ms` has been idle longer than `ttl`
+/// plus `tolerance`. Stamps from the future never expire.
+pub fn is_expired(stamp_ms: i64, ttl: Duration, now_ms: i64, tolerance: Duration) -> bool {
+    age(stamp_ms, now_ms, tolerance).is_some_and(|age| age > ttl + tolerance)
+}
+
+/// `stamp_ms`, or `now_ms` when the stamp is too far in the future to trust.
+pub fn clamp(stamp_ms: i64, now_ms: i64, tolerance: Duration) -> i64 {
+    match age(stamp_ms, now_ms, tolerance) {
+        Some(_) => stamp_ms,
+        None => now_ms,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skew_tolerant_expiry() {
+        let a = stamp_ms();
+        let b = stamp_ms();
+        assert!(b > a);
+
+        let minute = Duration::from_secs(60);
+        let day = Duration::from_secs(86_400);
+        let now = 1_000_000_000_000;
+        let ms = |d: Duration| d.as_millis() as i64;
+
+        // Past the TTL, but not by more than the tolerance
+        assert!(!is_expired(
+            now - ms(day) - ms(minute),
+            day,
+            now,
+            2 * minute
+        ));
+        assert!(is_expired(
+            now - ms(day) - ms(3 * minute),
+            day,
+            now,
+            2 * minute
+        ));
+        // Written by a clock a minute fast: just written
+        assert_eq!(age(now + ms(minute), now, 2 * minute), Some(Duration::ZERO));
+        // Written by a clock a year fast: kept, and restamped
+        let future = now + ms(365 * day);
+        assert!(!is_expired(future, day, now, 2 * minute));
+        assert_eq!(clamp(future, now, 2 * minute), now);
+        assert_eq!(clamp(now - 5, now, 2 * minute), now - 5);
+    }
+}
@@ -463,6 +463,21 @@ impl RemoteCache for DistributedCache {
             .await
     }
 
+    async fn invalidate(&self, hash: &str) -> Result<()> {
+        self.local_cache.invalidate(hash).await?;
+
+        // Every copy has to go, or a replica would serve the stale artifact
+        let mut nodes: Vec<String> =
+            self.cluster.get_primary_node(hash).await?.into_iter().collect();
+        nodes.extend(self.cluster.get_replica_nodes(hash).await?);
+        for node in nodes {
+            if let Some(client) = self.get_remote_client(&node).await {
+                client.invalidate(hash).await?;
+            }
+        }
+        Ok(())
+    }
+
     async fn report_build_event(&self, event: BuildEvent) -> Result<()> {
         self.local_cache.report_build_event(event).await
     }
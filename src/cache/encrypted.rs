@@ -0,0 +1,329 @@
+use crate::cache::integrity::RejectedArtifact;
+use crate::cache::remote::RemoteCache;
+use crate::dashboard::BuildEvent;
+use crate::graph::BuildGraph;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Prefix of every encrypted payload, followed by the 12 byte nonce.
+const MAGIC: &[u8; 4] = b"MBE2";
+const NONCE_LEN: usize = 12;
+
+/// Associated data of every layer. Layers are named by the hash of their
+/// ciphertext, which is checked on download.
+const LAYER_AAD: &[u8] = b"layer";
+
+/// Associated data of the artifact stored under `hash`, so its ciphertext
+/// does not decrypt under any other key.
+fn artifact_aad(hash: &str) -> Vec<u8> {
+    format!("artifact:{}", hash).into_bytes()
+}
+
+/// Wraps a `RemoteCache` so that artifacts and layers are encrypted with
+/// AES-256-GCM before they leave the machine. The local cache keeps plaintext.
+/// Layers are stored under the blake3 hash of their ciphertext.
+pub struct EncryptedRemoteCache {
+    inner: Arc<dyn RemoteCache>,
+    cipher: Aes256Gcm,
+    nonce_key: [u8; 32],
+    /// Plaintext layer hash -> ciphertext layer hash, for layers seen this run
+    layer_names: Mutex<HashMap<String, String>>,
+}
+
+impl EncryptedRemoteCache {
+    pub fn new(inner: Arc<dyn RemoteCache>, key: [u8; 32]) -> Self {
+        Self {
+            inner,
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+            nonce_key: blake3::derive_key("memobuild remote cache nonce v1", &key),
+            layer_names: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Read the 32 byte key from `MEMOBUILD_ENCRYPTION_KEY` (hex or base64) or
+    /// from the file named by `MEMOBUILD_ENCRYPTION_KEY_FILE`.
+    pub fn key_from_env() -> Result<Option<[u8; 32]>> {
+        let encoded = if let Ok(key) = std::env::var("MEMOBUILD_ENCRYPTION_KEY") {
+            key
+        } else if let Ok(path) = std::env::var("MEMOBUILD_ENCRYPTION_KEY_FILE") {
+            std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read encryption key file {}", path))?
+        } else {
+            return Ok(None);
+        };
+        parse_key(encoded.trim()).map(Some)
+    }
+
+    fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        // Equal layers get equal ciphertext, and a nonce is never reused with
+        // different associated data
+        let derived = blake3::Hasher::new_keyed(&self.nonce_key)
+            .update(&(aad.len() as u64).to_le_bytes())
+            .update(aad)
+            .update(plaintext)
+            .finalize();
+        let nonce = &derived.as_bytes()[..NONCE_LEN];
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt artifact"))?;
+
+        let mut out = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(nonce);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    fn decrypt(&self, payload: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let header = MAGIC.len() + NONCE_LEN;
+        if payload.len() < header || &payload[..MAGIC.len()] != MAGIC {
+            anyhow::bail!("Remote artifact is not encrypted in this format; refusing to use it");
+        }
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(&payload[MAGIC.len()..header]),
+                Payload {
+                    msg: &payload[header..],
+                    aad,
+                },
+            )
+            .map_err(|_| {
+                anyhow::anyhow!("Failed to decrypt remote artifact (wrong key or tampered data)")
+            })
+    }
+
+    /// Decrypt the layer stored as `name`, the hash of its ciphertext.
+    fn decrypt_layer(&self, name: &str, payload: &[u8]) -> Result<Vec<u8>> {
+        if blake3::hash(payload).to_hex().as_str() != name {
+            anyhow::bail!("Remote layer {} does not match its name", name);
+        }
+        self.decrypt(payload, LAYER_AAD)
+    }
+}
+
+fn parse_key(encoded: &str) -> Result<[u8; 32]> {
+    let bytes = hex::decode(encoded)
+        .or_else(|_| STANDARD.decode(encoded))
+        .context("Encryption key must be hex or base64 encoded")?;
+    bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| anyhow::anyhow!("Encryption key must be 32 bytes, got {}", b.len()))
+}
+
+#[async_trait]
+impl RemoteCache for EncryptedRemoteCache {
+    async fn has(&self, hash: &str) -> Result<bool> {
+        self.inner.has(hash).await
+    }
+
+    async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        match self.inner.get(hash).await? {
+            Some(payload) => Ok(Some(self.decrypt(&payload, &artifact_aad(hash))?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put(&self, hash: &str, data: &[u8]) -> Result<()> {
+        let payload = self.encrypt(data, &artifact_aad(hash))?;
+        self.inner.put(hash, &payload).await
+    }
+
+    async fn has_layer(&self, hash: &str) -> Result<bool> {
+        // The ciphertext name is only known once we have seen the data, so an
+        // unknown layer is reported missing and `put_layer` does the real check.
+        let name = self.layer_names.lock().unwrap().get(hash).cloned();
+        match name {
+            Some(name) => self.inner.has_layer(&name).await,
+            None => Ok(false),
+        }
+    }
+
+    async fn get_layer(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        // Node layer lists on the server already hold ciphertext names
+        match self.inner.get_layer(hash).await? {
+            Some(payload) => Ok(Some(self.decrypt_layer(hash, &payload)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put_layer(&self, hash: &str, data: &[u8]) -> Result<()> {
+        let payload = self.encrypt(data, LAYER_AAD)?;
+        let name = blake3::hash(&payload).to_hex().to_string();
+        self.layer_names
+            .lock()
+            .unwrap()
+            .insert(hash.to_string(), name.clone());
+
+        if self.inner.has_layer(&name).await? {
+            return Ok(());
+        }
+        self.inner.put_layer(&name, &payload).await
+    }
+
+    async fn get_node_layers(&self, hash: &str) -> Result<Option<Vec<String>>> {
+        self.inner.get_node_layers(hash).await
+    }
+
+    async fn register_node_layers(
+        &self,
+        hash: &str,
+        layers: &[String],
+        total_size: u64,
+    ) -> Result<()> {
+        let names: Vec<String> = {
+            let map = self.layer_names.lock().unwrap();
+            layers
+                .iter()
+                .map(|l| map.get(l).cloned().unwrap_or_else(|| l.clone()))
+                .collect()
+        };
+        self.inner
+            .register_node_layers(hash, &names, total_size)
+            .await
+    }
+
+    async fn invalidate(&self, hash: &str) -> Result<()> {
+        self.inner.invalidate(hash).await
+    }
+
+    async fn prefetch_hints(&self, hash: &str) -> Result<Vec<String>> {
+        self.inner.prefetch_hints(hash).await
+    }
+
+    async fn report_corrupt_layer(&self, rejected: &RejectedArtifact) -> Result<bool> {
+        self.inner.report_corrupt_layer(rejected).await
+    }
+
+    async fn get_layer_from_alternates(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        match self.inner.get_layer_from_alternates(hash).await? {
+            Some(payload) => Ok(Some(self.decrypt_layer(hash, &payload)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn authenticates_layers(&self) -> bool {
+        // Checked against the ciphertext name, then by AES-GCM
+        true
+    }
+
+    async fn report_build_event(&self, event: BuildEvent) -> Result<()> {
+        self.inner.report_build_event(event).await
+    }
+
+    async fn report_dag(&self, dag: &BuildGraph) -> Result<()> {
+        self.inner.report_dag(dag).await
+    }
+
+    async fn report_analytics(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()> {
+        self.inner
+            .report_analytics(dirty, cached, duration_ms)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(key: [u8; 32]) -> EncryptedRemoteCache {
+        // The inner cache is never touched by encrypt/decrypt
+        let inner: Arc<dyn RemoteCache> = Arc::new(crate::cache::HttpRemoteCache::new(
+            "http://127.0.0.1:9".to_string(),
+        ));
+        EncryptedRemoteCache::new(inner, key)
+    }
+
+    #[test]
+    fn test_roundtrip_is_deterministic() {
+        let c = cache([7u8; 32]);
+        let a = c.encrypt(b"layer data", LAYER_AAD).unwrap();
+        let b = c.encrypt(b"layer data", LAYER_AAD).unwrap();
+        assert_eq!(a, b);
+        assert_ne!(&a[MAGIC.len() + NONCE_LEN..], b"layer data");
+        assert_eq!(c.decrypt(&a, LAYER_AAD).unwrap(), b"layer data");
+        let name = blake3::hash(&a).to_hex().to_string();
+        assert_eq!(c.decrypt_layer(&name, &a).unwrap(), b"layer data");
+        assert!(c.decrypt_layer(&"0".repeat(64), &a).is_err());
+    }
+
+    #[test]
+    fn test_wrong_key_and_plaintext_are_rejected() {
+        let payload = cache([1u8; 32]).encrypt(b"secret", LAYER_AAD).unwrap();
+        assert!(cache([2u8; 32]).decrypt(&payload, LAYER_AAD).is_err());
+        assert!(cache([1u8; 32])
+            .decrypt(b"plain artifact", LAYER_AAD)
+            .is_err());
+    }
+
+    #[test]
+    fn test_swapped_artifacts_are_rejected() {
+        let c = cache([5u8; 32]);
+        let first = c.encrypt(b"release build", &artifact_aad("aaaa")).unwrap();
+        let second = c.encrypt(b"debug build", &artifact_aad("bbbb")).unwrap();
+        assert_eq!(
+            c.decrypt(&first, &artifact_aad("aaaa")).unwrap(),
+            b"release build"
+        );
+        // A server answering one key with the other's blob is caught
+        assert!(c.decrypt(&second, &artifact_aad("aaaa")).is_err());
+        assert!(c.decrypt(&first, &artifact_aad("bbbb")).is_err());
+        assert!(c.decrypt(&first, LAYER_AAD).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hybrid_cache_reads_encrypted_layers() {
+        use crate::cache::{FsRemoteCache, HybridCache, LocalCache};
+        use crate::report::CacheSource;
+        use std::io::Read;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let server: Arc<dyn RemoteCache> =
+            Arc::new(FsRemoteCache::new(&dir.path().join("remote")).unwrap());
+        let agent = |name: &str| {
+            let remote = EncryptedRemoteCache::new(server.clone(), [9u8; 32]);
+            let mut cache =
+                HybridCache::new(Some(Arc::new(remote) as Arc<dyn RemoteCache>)).unwrap();
+            cache.local = LocalCache::in_dir(dir.path().join(name)).unwrap();
+            cache
+        };
+        // Spans several layers
+        let data: Vec<u8> = (0..(2 * crate::cache::utils::CHUNK_SIZE + 5))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        agent("a").put_artifact("node", &data).await.unwrap();
+
+        // Layers are named by their ciphertext and still accepted
+        let reader = agent("b");
+        let (restored, source) = reader.lookup_artifact("node").await.unwrap().unwrap();
+        assert_eq!(source, CacheSource::Remote);
+        assert_eq!(restored, data);
+        assert!(reader.integrity.rejected().is_empty());
+
+        let streamer = agent("c");
+        let (mut stream, source) = streamer.lookup_reader("node").await.unwrap().unwrap();
+        assert_eq!(source, CacheSource::Remote);
+        let mut streamed = Vec::new();
+        stream.read_to_end(&mut streamed).unwrap();
+        assert_eq!(streamed, data);
+        assert!(streamer.integrity.rejected().is_empty());
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert!(parse_key(&"ab".repeat(32)).is_ok());
+        assert!(parse_key(&STANDARD.encode([3u8; 32])).is_ok());
+        assert!(parse_key("abcd").is_err());
+    }
+}
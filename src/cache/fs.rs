@@ -0,0 +1,444 @@
+use crate::cache::remote::{RemoteCache, RemoteReader};
+use crate::dashboard::BuildEvent;
+use crate::graph::BuildGraph;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Locks older than this are assumed to belong to a crashed writer.
+const LOCK_STALE_AFTER: Duration = Duration::from_secs(120);
+/// How long to wait for another writer before giving up on a lock.
+const LOCK_WAIT: Duration = Duration::from_secs(30);
+const LOCK_POLL: Duration = Duration::from_millis(50);
+
+/// A `RemoteCache` backed by a shared directory, typically an NFS or SMB mount.
+///
+/// Layout under the root:
+/// * `objects/ab/cd/<hash>` — artifacts and layers
+/// * `nodes/ab/cd/<hash>.json` — layer lists per node
+/// * `tmp/` — in-flight writes
+///
+/// Every write goes to `tmp/` first and is renamed into place, so readers on
+/// other machines never observe a partial file. A `<target>.lock` file created
+/// with `O_EXCL` keeps concurrent writers of the same entry from racing.
+///
+/// The share can be slow to answer, so every filesystem call runs on the
+/// blocking pool and waiting for a lock does not hold up a runtime thread.
+pub struct FsRemoteCache {
+    root: PathBuf,
+}
+
+impl FsRemoteCache {
+    pub fn new(root: &Path) -> Result<Self> {
+        for dir in ["objects", "nodes", "tmp"] {
+            fs::create_dir_all(root.join(dir))
+                .with_context(|| format!("Failed to create {}", root.join(dir).display()))?;
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    fn sharded(&self, kind: &str, name: &str) -> PathBuf {
+        let dir = self.root.join(kind);
+        if name.len() < 4 {
+            return dir.join(name);
+        }
+        dir.join(&name[0..2]).join(&name[2..4]).join(name)
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.sharded("objects", hash)
+    }
+
+    fn node_path(&self, hash: &str) -> PathBuf {
+        self.sharded("nodes", &format!("{}.json", hash))
+    }
+
+    fn read(path: &Path) -> Result<Option<Vec<u8>>> {
+        match fs::read(path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    /// Write `data` to `target` atomically. With `overwrite == false` an
+    /// existing file is left alone, which is what content-addressed entries want.
+    async fn write_atomic(&self, target: PathBuf, data: &[u8], overwrite: bool) -> Result<()> {
+        let present = {
+            let target = target.clone();
+            blocking(move || {
+                if !overwrite && target.exists() {
+                    return Ok(true);
+                }
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                Ok(false)
+            })
+            .await?
+        };
+        if present {
+            return Ok(());
+        }
+
+        let Some(lock) = FileLock::acquire(&target).await? else {
+            // Another writer held the lock for the whole wait; for immutable
+            // entries it has most likely produced the same content already.
+            let check = target.clone();
+            if !overwrite && blocking(move || Ok(check.exists())).await? {
+                return Ok(());
+            }
+            anyhow::bail!("Timed out waiting for lock on {}", target.display());
+        };
+
+        let tmp = self.root.join("tmp").join(format!(
+            "{}.{}.{}.tmp",
+            target.file_name().unwrap_or_default().to_string_lossy(),
+            std::process::id(),
+            uuid::Uuid::new_v4()
+        ));
+        let data = data.to_vec();
+        blocking(move || {
+            // Released once the entry is in place
+            let _lock = lock;
+            if !overwrite && target.exists() {
+                return Ok(());
+            }
+            let result = write_synced(&tmp, &data).and_then(|_| fs::rename(&tmp, &target));
+            if result.is_err() {
+                let _ = fs::remove_file(&tmp);
+            }
+            result.with_context(|| format!("Failed to write {}", target.display()))
+        })
+        .await
+    }
+}
+
+/// Run `f` on the blocking pool.
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await?
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// An exclusive lock held by the existence of a `.lock` file next to the target.
+struct FileLock {
+    path: PathBuf,
+}
+
+impl FileLock {
+    /// Returns `None` if the lock is still held by someone else after `LOCK_WAIT`.
+    async fn acquire(target: &Path) -> Result<Option<Self>> {
+        let mut name = target.file_name().unwrap_or_default().to_os_string();
+        name.push(".lock");
+        let path = target.with_file_name(name);
+        let deadline = Instant::now() + LOCK_WAIT;
+
+        loop {
+            let attempt = path.clone();
+            if blocking(move || Self::try_create(&attempt)).await? {
+                return Ok(Some(Self { path }));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            tokio::time::sleep(LOCK_POLL).await;
+        }
+    }
+
+    /// Create the lock file at `path`, breaking a stale one. Returns whether
+    /// the lock is now ours.
+    fn try_create(path: &Path) -> Result<bool> {
+        loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+            {
+                Ok(mut file) => {
+                    let _ = writeln!(file, "{}", std::process::id());
+                    return Ok(true);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    if !Self::is_stale(path) || !Self::break_stale(path) {
+                        return Ok(false);
+                    }
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to create lock {}", path.display()))
+                }
+            }
+        }
+    }
+
+    /// Move the stale lock at `path` out of the way. Of several writers
+    /// breaking it, only the one whose rename succeeds goes on; one that
+    /// moved a lock taken fresh in the meantime puts it back.
+    fn break_stale(path: &Path) -> bool {
+        static BROKEN: AtomicU64 = AtomicU64::new(0);
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(
+            ".stale-{}-{}",
+            std::process::id(),
+            BROKEN.fetch_add(1, Ordering::Relaxed)
+        ));
+        let moved = path.with_file_name(name);
+        if fs::rename(path, &moved).is_err() {
+            // Someone else broke or released it first
+            return true;
+        }
+        if !Self::is_stale(&moved) {
+            let _ = fs::hard_link(&moved, path);
+            let _ = fs::remove_file(&moved);
+            return false;
+        }
+        let _ = fs::remove_file(&moved);
+        true
+    }
+
+    fn is_stale(path: &Path) -> bool {
+        fs::metadata(path)
+            .and_then(|m| m.modified())
+            .map(|modified| {
+                SystemTime::now()
+                    .duration_since(modified)
+                    .unwrap_or_default()
+                    > LOCK_STALE_AFTER
+            })
+            .unwrap_or(false)
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[async_trait]
+impl RemoteCache for FsRemoteCache {
+    async fn has(&self, hash: &str) -> Result<bool> {
+        let path = self.object_path(hash);
+        blocking(move || Ok(path.exists())).await
+    }
+
+    async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.object_path(hash);
+        blocking(move || Self::read(&path)).await
+    }
+
+    async fn get_reader(&self, hash: &str) -> Result<Option<RemoteReader>> {
+        let path = self.object_path(hash);
+        blocking(move || {
+            let file = match fs::File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to read {}", path.display()))
+                }
+            };
+            Ok(Some(RemoteReader {
+                transferred: file.metadata()?.len(),
+                reader: Box::new(std::io::BufReader::new(file)),
+            }))
+        })
+        .await
+    }
+
+    async fn put(&self, hash: &str, data: &[u8]) -> Result<()> {
+        self.write_atomic(self.object_path(hash), data, false).await
+    }
+
+    async fn has_layer(&self, hash: &str) -> Result<bool> {
+        self.has(hash).await
+    }
+
+    async fn get_layer(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.object_path(hash);
+        let hash = hash.to_string();
+        blocking(move || {
+            let Some(data) = Self::read(&path)? else {
+                return Ok(None);
+            };
+            // Shares can be written by older clients or damaged in transit;
+            // treat a corrupt layer as a miss so it gets rebuilt and rewritten.
+            if blake3::hash(&data).to_hex().as_str() != hash {
+                eprintln!("⚠️  Corrupt layer {} in shared cache, discarding", hash);
+                let _ = fs::remove_file(&path);
+                return Ok(None);
+            }
+            Ok(Some(data))
+        })
+        .await
+    }
+
+    async fn put_layer(&self, hash: &str, data: &[u8]) -> Result<()> {
+        self.write_atomic(self.object_path(hash), data, false).await
+    }
+
+    async fn get_node_layers(&self, hash: &str) -> Result<Option<Vec<String>>> {
+        let path = self.node_path(hash);
+        match blocking(move || Self::read(&path)).await? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn register_node_layers(
+        &self,
+        hash: &str,
+        layers: &[String],
+        _total_size: u64,
+    ) -> Result<()> {
+        let data = serde_json::to_vec(layers)?;
+        self.write_atomic(self.node_path(hash), &data, true).await
+    }
+
+    async fn invalidate(&self, hash: &str) -> Result<()> {
+        let paths = [self.node_path(hash), self.object_path(hash)];
+        blocking(move || {
+            for path in paths {
+                match fs::remove_file(&path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(e)
+                            .with_context(|| format!("Failed to remove {}", path.display()))
+                    }
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn report_build_event(&self, _event: BuildEvent) -> Result<()> {
+        Ok(())
+    }
+
+    async fn report_dag(&self, _dag: &BuildGraph) -> Result<()> {
+        Ok(())
+    }
+
+    async fn report_analytics(&self, _dirty: u32, _cached: u32, _duration_ms: u64) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_sharded_atomic_roundtrip() {
+        let dir = TempDir::new().unwrap();
+        let cache = FsRemoteCache::new(dir.path()).unwrap();
+        let hash = blake3::hash(b"layer").to_hex().to_string();
+
+        cache.put_layer(&hash, b"layer").await.unwrap();
+        assert!(cache.has_layer(&hash).await.unwrap());
+        assert_eq!(cache.get_layer(&hash).await.unwrap().unwrap(), b"layer");
+        assert!(cache
+            .object_path(&hash)
+            .starts_with(dir.path().join("objects").join(&hash[0..2])));
+
+        // Nothing is left behind in tmp/ and the lock is released
+        assert_eq!(fs::read_dir(dir.path().join("tmp")).unwrap().count(), 0);
+        let mut lock = cache.object_path(&hash).into_os_string();
+        lock.push(".lock");
+        assert!(!Path::new(&lock).exists());
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_layer_is_a_miss() {
+        let dir = TempDir::new().unwrap();
+        let cache = FsRemoteCache::new(dir.path()).unwrap();
+        let hash = blake3::hash(b"layer").to_hex().to_string();
+
+        cache.put_layer(&hash, b"tampered").await.unwrap();
+        assert!(cache.get_layer(&hash).await.unwrap().is_none());
+        assert!(!cache.has_layer(&hash).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_stale_lock_is_broken() {
+        let dir = TempDir::new().unwrap();
+        let target = dir.path().join("entry");
+        let lock = dir.path().join("entry.lock");
+        fs::write(&lock, "123").unwrap();
+        let old = SystemTime::now() - LOCK_STALE_AFTER * 2;
+        fs::File::options()
+            .write(true)
+            .open(&lock)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        assert!(FileLock::acquire(&target).await.unwrap().is_some());
+    }
+
+    #[test]
+    fn test_one_writer_breaks_a_stale_lock() {
+        let dir = TempDir::new().unwrap();
+        let lock = dir.path().join("entry.lock");
+        let make_stale = || {
+            fs::write(&lock, "123").unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&lock)
+                .unwrap()
+                .set_modified(SystemTime::now() - LOCK_STALE_AFTER * 2)
+                .unwrap();
+        };
+
+        // The second writer saw the lock stale, but the first broke and
+        // took it before the second got to break it
+        make_stale();
+        assert!(FileLock::is_stale(&lock));
+        assert!(FileLock::try_create(&lock).unwrap());
+        assert!(!FileLock::break_stale(&lock));
+        assert!(!FileLock::try_create(&lock).unwrap());
+        fs::remove_file(&lock).unwrap();
+
+        for _ in 0..50 {
+            make_stale();
+
+            let barrier = std::sync::Barrier::new(2);
+            let won = std::thread::scope(|s| {
+                let writers: Vec<_> = (0..2)
+                    .map(|_| {
+                        s.spawn(|| {
+                            barrier.wait();
+                            FileLock::try_create(&lock).unwrap()
+                        })
+                    })
+                    .collect();
+                writers
+                    .into_iter()
+                    .map(|w| w.join().unwrap())
+                    .filter(|&won| won)
+                    .count()
+            });
+            assert_eq!(won, 1);
+            fs::remove_file(&lock).unwrap();
+        }
+        // Broken locks are not left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -1,14 +1,18 @@
-use crate::cache::remote::RemoteCache;
+use crate::cache::integrity::RejectedArtifact;
+use crate::cache::limits::ArtifactTooLarge;
+use crate::cache::pin::PinTarget;
+use crate::cache::remote::{RemoteCache, RemoteReader};
 use crate::dashboard::BuildEvent;
 use crate::graph::BuildGraph;
 use crate::error::{RetryConfig, calculate_backoff};
 use anyhow::Result;
 use async_trait::async_trait;
-use reqwest::Client;
+use reqwest::header::{CONTENT_RANGE, ETAG, IF_RANGE, RANGE};
+use reqwest::{Client, StatusCode};
 use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::time::Duration;
 
 #[derive(Clone)]
@@ -28,9 +32,52 @@ impl HttpRemoteCache {
             "X-MemoBuild-API-Version",
             reqwest::header::HeaderValue::from_static("1.0"),
         );
+        // Lets the server hide entries stored by other key derivations
+        headers.insert(
+            crate::constants::KEY_VERSION_HEADER,
+            reqwest::header::HeaderValue::from(crate::constants::CACHE_KEY_VERSION),
+        );
+        if let Ok(platform) = reqwest::header::HeaderValue::from_str(&crate::env::host_platform()) {
+            headers.insert(crate::constants::PLATFORM_HEADER, platform);
+        }
+        if let Some(namespace) = std::env::var("MEMOBUILD_NAMESPACE")
+            .ok()
+            .and_then(|ns| reqwest::header::HeaderValue::from_str(&ns).ok())
+        {
+            headers.insert(crate::constants::NAMESPACE_HEADER, namespace);
+        }
+        if let Some(build_id) = crate::cache::pin::build_id_from_env()
+            .and_then(|id| reqwest::header::HeaderValue::from_str(&id).ok())
+        {
+            headers.insert(crate::constants::BUILD_ID_HEADER, build_id);
+        }
+        // Lets the server pair up the artifacts this build fetches
+        if let Ok(session) = reqwest::header::HeaderValue::from_str(session_id()) {
+            headers.insert(crate::constants::SESSION_HEADER, session);
+        }
+        // Lets the server trace the entries this client stores back to it
+        if let Some(origin) = serde_json::to_string(&crate::cache::origin::EntryOrigin::detect())
+            .ok()
+            .and_then(|o| reqwest::header::HeaderValue::from_str(&o).ok())
+        {
+            headers.insert(crate::constants::ORIGIN_HEADER, origin);
+        }
 
+        // One pool of kept-alive connections for every request; over TLS the
+        // server is offered HTTP/2 and concurrent transfers share a connection
         let mut builder = Client::builder()
-            .default_headers(headers);
+            .default_headers(headers)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .tcp_keepalive(Duration::from_secs(60))
+            .tcp_nodelay(true)
+            .http2_adaptive_window(true)
+            .http2_keep_alive_interval(Duration::from_secs(30))
+            .http2_keep_alive_while_idle(true);
+        // Plain-text servers cannot negotiate HTTP/2; MemoBuild's own speaks
+        // it to clients that start with it
+        if http2_prior_knowledge_from_env() {
+            builder = builder.http2_prior_knowledge();
+        }
 
         if let Some(tls) = tls_config {
             if let Ok(client_config) = tls.client_config() {
@@ -42,6 +89,198 @@ impl HttpRemoteCache {
 
         Self { base_url, client }
     }
+
+    /// Download a blob, retrying with backoff. A retry after an interrupted
+    /// transfer asks only for the missing bytes, conditional on the blob's
+    /// ETag, so large artifacts are not fetched from the start again.
+    /// Returns `None` if the server does not have it.
+    async fn download(&self, url: &str, timeout: Duration) -> Result<Option<Vec<u8>>> {
+        let mut data = Vec::new();
+        Ok(self
+            .download_into(url, timeout, &mut data)
+            .await?
+            .then_some(data))
+    }
+
+    /// Like [`download`](Self::download), writing to `sink`. Returns whether
+    /// the blob exists.
+    async fn download_into<S: DownloadSink>(
+        &self,
+        url: &str,
+        timeout: Duration,
+        sink: &mut S,
+    ) -> Result<bool> {
+        let config = RetryConfig::default();
+        let mut etag = None;
+        let mut attempt = 0;
+        loop {
+            match self.fetch_into(url, timeout, sink, &mut etag).await {
+                Ok(found) => return Ok(found),
+                Err(e) => {
+                    attempt += 1;
+                    if attempt >= config.max_attempts {
+                        return Err(anyhow::anyhow!(
+                            "Download failed after {} attempts: {}",
+                            config.max_attempts,
+                            e
+                        ));
+                    }
+                    let backoff_ms = calculate_backoff(attempt - 1, &config);
+                    eprintln!(
+                        "⚠️  Download interrupted after {} bytes, resuming in {}ms: {}",
+                        sink.len(),
+                        backoff_ms,
+                        e
+                    );
+                    tokio::time::sleep(Duration::from_millis(backoff_ms)).await;
+                }
+            }
+        }
+    }
+
+    /// One download attempt, appending to `sink`. Returns whether the blob exists.
+    async fn fetch_into<S: DownloadSink>(
+        &self,
+        url: &str,
+        timeout: Duration,
+        sink: &mut S,
+        etag: &mut Option<String>,
+    ) -> Result<bool> {
+        let mut request = self.client.get(url).timeout(timeout);
+        if let (false, Some(tag)) = (sink.len() == 0, etag.as_deref()) {
+            request = request
+                .header(RANGE, format!("bytes={}-", sink.len()))
+                .header(IF_RANGE, tag);
+        }
+        let mut resp = request.send().await?;
+        let range = resp
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .map(content_range);
+        match resp.status() {
+            StatusCode::NOT_FOUND => return Ok(false),
+            // Nothing left to fetch: the previous attempt got every byte
+            StatusCode::RANGE_NOT_SATISFIABLE
+                if range.is_some_and(|(_, total)| total == Some(sink.len())) =>
+            {
+                return Ok(true)
+            }
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                let len = sink.len();
+                sink.clear()?;
+                anyhow::bail!("Remote cache cannot resume at byte {}", len);
+            }
+            StatusCode::PARTIAL_CONTENT => {
+                let start = range.and_then(|(start, _)| start);
+                let len = sink.len();
+                if start != Some(len) {
+                    // Appending would corrupt the blob; start over
+                    sink.clear()?;
+                    anyhow::bail!(
+                        "Remote cache resumed at {:?} instead of byte {}",
+                        start,
+                        len
+                    );
+                }
+            }
+            // The whole blob: a first attempt, or the server ignored the range
+            status if status.is_success() => sink.clear()?,
+            status => anyhow::bail!("Remote cache error: {}", status),
+        }
+        *etag = resp
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        while let Some(chunk) = resp.chunk().await? {
+            sink.append(&chunk)?;
+        }
+        Ok(true)
+    }
+
+    /// Ask the server to drop entries stored under key versions other than
+    /// `keep`, authenticating with `MEMOBUILD_ADMIN_TOKEN`. Returns how many
+    /// were deleted.
+    pub async fn prune_key_versions(&self, keep: u32) -> Result<u64> {
+        let url = format!("{}/gc/versions?keep={}", self.base_url, keep);
+        let request = match std::env::var("MEMOBUILD_ADMIN_TOKEN") {
+            Ok(token) => self.client.post(&url).bearer_auth(token),
+            Err(_) => self.client.post(&url),
+        };
+        let resp = request.send().await?;
+        if resp.status() == StatusCode::CONFLICT {
+            anyhow::bail!(
+                "The cache server does not use key version v{}: {}",
+                keep,
+                resp.text().await.unwrap_or_default()
+            );
+        }
+        if !resp.status().is_success() {
+            anyhow::bail!("Failed to prune key versions: {}", resp.status());
+        }
+        let body: serde_json::Value = resp.json().await?;
+        Ok(body["deleted"].as_u64().unwrap_or(0))
+    }
+
+    /// Pin the entries `target` names under `label`, or unpin them when
+    /// `label` is `None`, authenticating with `MEMOBUILD_ADMIN_TOKEN`.
+    /// Returns how many entries the server changed.
+    pub async fn set_pin(&self, target: &PinTarget, label: Option<&str>) -> Result<u64> {
+        let url = format!("{}/api/v1/pins", self.base_url);
+        let request = match label {
+            Some(label) => {
+                let (field, value) = target.query();
+                self.client
+                    .post(&url)
+                    .json(&serde_json::json!({ field: value, "label": label }))
+            }
+            None => self.client.delete(&url).query(&[target.query()]),
+        };
+        let request = match std::env::var("MEMOBUILD_ADMIN_TOKEN") {
+            Ok(token) => request.bearer_auth(token),
+            Err(_) => request,
+        };
+        let resp = request.send().await?;
+        match resp.status() {
+            StatusCode::NOT_FOUND => Ok(0),
+            status if status.is_success() => {
+                let body: serde_json::Value = resp.json().await?;
+                Ok(body["entries"].as_u64().unwrap_or(0))
+            }
+            status => anyhow::bail!("Failed to update pins for {}: {}", target, status),
+        }
+    }
+
+    /// Current head of the server's transparency log.
+    pub async fn log_head(&self) -> Result<crate::server::transparency::LogHead> {
+        let url = format!("{}/log/head", self.base_url);
+        let resp = self.client.get(&url).send().await?;
+        if !resp.status().is_success() {
+            anyhow::bail!("Failed to fetch the transparency log head: {}", resp.status());
+        }
+        Ok(resp.json().await?)
+    }
+
+    /// Entries that take the transparency log from `from` entries to its
+    /// current size. Unverified; see `transparency::verify_consistency`.
+    pub async fn log_consistency(
+        &self,
+        from: u64,
+    ) -> Result<crate::server::transparency::ConsistencyProof> {
+        let url = format!("{}/log/consistency?from={}", self.base_url, from);
+        let resp = self.client.get(&url).send().await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            anyhow::bail!(
+                "The transparency log has fewer than {} entries; it was truncated",
+                from
+            );
+        }
+        if !resp.status().is_success() {
+            anyhow::bail!("Failed to fetch a consistency proof: {}", resp.status());
+        }
+        Ok(resp.json().await?)
+    }
 }
 
 /// Helper for retrying operations with exponential backoff
@@ -58,6 +297,10 @@ where
         match operation().await {
             Ok(result) => return Ok(result),
             Err(e) => {
+                // The same upload would be rejected again
+                if e.downcast_ref::<ArtifactTooLarge>().is_some() {
+                    return Err(e);
+                }
                 attempt += 1;
                 if attempt >= config.max_attempts {
                     return Err(anyhow::anyhow!(
@@ -78,6 +321,122 @@ where
     }
 }
 
+/// Error for an upload the server answered with 413.
+fn too_large(hash: &str, size: u64) -> anyhow::Error {
+    ArtifactTooLarge {
+        key: hash.to_string(),
+        size,
+        max: None,
+    }
+    .into()
+}
+
+/// Where a download is written: memory, or a spool file for artifacts read
+/// through [`RemoteCache::get_reader`].
+/// Start and total length from a `Content-Range` value, `bytes 10-99/100`
+/// or `bytes */100`; `None` where a part is missing or unknown.
+fn content_range(value: &str) -> (Option<u64>, Option<u64>) {
+    let Some(range) = value.trim().strip_prefix("bytes ") else {
+        return (None, None);
+    };
+    let (span, total) = range.split_once('/').unwrap_or((range, "*"));
+    let start = span
+        .split_once('-')
+        .and_then(|(start, _)| start.parse().ok());
+    (start, total.parse().ok())
+}
+
+trait DownloadSink: Send {
+    fn len(&self) -> u64;
+    fn clear(&mut self) -> std::io::Result<()>;
+    fn append(&mut self, chunk: &[u8]) -> std::io::Result<()>;
+}
+
+impl DownloadSink for Vec<u8> {
+    fn len(&self) -> u64 {
+        Vec::len(self) as u64
+    }
+
+    fn clear(&mut self) -> std::io::Result<()> {
+        Vec::clear(self);
+        Ok(())
+    }
+
+    fn append(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+        self.extend_from_slice(chunk);
+        Ok(())
+    }
+}
+
+/// A file in the temporary directory holding a download, removed when
+/// dropped.
+struct SpoolFile {
+    file: std::fs::File,
+    path: std::path::PathBuf,
+    len: u64,
+}
+
+impl SpoolFile {
+    fn create() -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "memobuild-download-{}",
+            uuid::Uuid::new_v4().simple()
+        ));
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Self { file, path, len: 0 })
+    }
+}
+
+impl DownloadSink for SpoolFile {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn clear(&mut self) -> std::io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.len = 0;
+        Ok(())
+    }
+
+    fn append(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(chunk)?;
+        self.len += chunk.len() as u64;
+        Ok(())
+    }
+}
+
+impl Read for SpoolFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Whether `MEMOBUILD_HTTP2` asks to speak HTTP/2 to a plain `http://`
+/// cache without negotiating it.
+fn http2_prior_knowledge_from_env() -> bool {
+    matches!(
+        std::env::var("MEMOBUILD_HTTP2").as_deref(),
+        Ok("1") | Ok("true")
+    )
+}
+
+/// Random id of this process, the same for every client it creates.
+fn session_id() -> &'static str {
+    static SESSION: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+    SESSION.get_or_init(|| uuid::Uuid::new_v4().simple().to_string())
+}
+
 #[async_trait]
 impl RemoteCache for HttpRemoteCache {
     async fn has(&self, hash: &str) -> Result<bool> {
@@ -99,40 +458,41 @@ impl RemoteCache for HttpRemoteCache {
     }
 
     async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
-        let config = RetryConfig::default();
-        retry_with_backoff(
-            || async {
-                let url = format!("{}/cache/{}", self.base_url, hash);
-                let resp = self
-                    .client
-                    .get(&url)
-                    .timeout(Duration::from_secs(30))
-                    .send()
-                    .await?;
+        let url = format!("{}/cache/{}", self.base_url, hash);
+        let Some(compressed_data) = self.download(&url, Duration::from_secs(30)).await? else {
+            return Ok(None);
+        };
 
-                if resp.status().is_success() {
-                    let compressed_data = resp.bytes().await?;
+        // Decompress
+        let mut decoder = GzDecoder::new(&compressed_data[..]);
+        let mut decompressed_data = Vec::new();
+        decoder.read_to_end(&mut decompressed_data)?;
 
-                    // Decompress
-                    let mut decoder = GzDecoder::new(&compressed_data[..]);
-                    let mut decompressed_data = Vec::new();
-                    decoder.read_to_end(&mut decompressed_data)?;
+        Ok(Some(decompressed_data))
+    }
 
-                    Ok(Some(decompressed_data))
-                } else if resp.status() == 404 {
-                    Ok(None)
-                } else {
-                    anyhow::bail!("Remote cache error: {}", resp.status());
-                }
-            },
-            &config,
-        )
-        .await
+    /// Spools the compressed blob to a temporary file and decompresses it
+    /// as it is read, so the artifact is never held in memory.
+    async fn get_reader(&self, hash: &str) -> Result<Option<RemoteReader>> {
+        let url = format!("{}/cache/{}", self.base_url, hash);
+        let mut spool = SpoolFile::create()?;
+        if !self
+            .download_into(&url, Duration::from_secs(30), &mut spool)
+            .await?
+        {
+            return Ok(None);
+        }
+        spool.file.seek(SeekFrom::Start(0))?;
+        Ok(Some(RemoteReader {
+            transferred: spool.len,
+            reader: Box::new(GzDecoder::new(std::io::BufReader::new(spool))),
+        }))
     }
 
     async fn put(&self, hash: &str, data: &[u8]) -> Result<()> {
-        // Incremental Layer Update: check if exists before uploading
-        if self.has(hash).await? {
+        // Incremental Layer Update: check if exists before uploading. If the
+        // check fails, uploading anyway is always correct.
+        if self.has(hash).await.unwrap_or(false) {
             println!("   (skip upload: remote already has {})", &hash[..8]);
             return Ok(());
         }
@@ -155,6 +515,9 @@ impl RemoteCache for HttpRemoteCache {
                     .send()
                     .await?;
 
+                if resp.status() == StatusCode::PAYLOAD_TOO_LARGE {
+                    return Err(too_large(hash, data.len() as u64));
+                }
                 if !resp.status().is_success() {
                     anyhow::bail!("Failed to upload to remote cache: {}", resp.status());
                 }
@@ -166,27 +529,33 @@ impl RemoteCache for HttpRemoteCache {
     }
 
     async fn has_layer(&self, hash: &str) -> Result<bool> {
-        let url = format!("{}/cache/layer/{}", self.base_url, hash);
-        let resp = self.client.head(&url).send().await?;
-        Ok(resp.status().is_success())
+        let config = RetryConfig::default();
+        retry_with_backoff(
+            || async {
+                let url = format!("{}/cache/layer/{}", self.base_url, hash);
+                let resp = self
+                    .client
+                    .head(&url)
+                    .timeout(Duration::from_secs(10))
+                    .send()
+                    .await?;
+                Ok(resp.status().is_success())
+            },
+            &config,
+        )
+        .await
     }
 
     async fn get_layer(&self, hash: &str) -> Result<Option<Vec<u8>>> {
         let url = format!("{}/cache/layer/{}", self.base_url, hash);
-        let resp = self.client.get(&url).send().await?;
-
-        if resp.status().is_success() {
-            let compressed_data = resp.bytes().await?;
-            // Decompress
-            let mut decoder = GzDecoder::new(&compressed_data[..]);
-            let mut decompressed_data = Vec::new();
-            decoder.read_to_end(&mut decompressed_data)?;
-            Ok(Some(decompressed_data))
-        } else if resp.status() == 404 {
-            Ok(None)
-        } else {
-            anyhow::bail!("Remote layer cache error: {}", resp.status());
-        }
+        let Some(compressed_data) = self.download(&url, Duration::from_secs(60)).await? else {
+            return Ok(None);
+        };
+        // Decompress
+        let mut decoder = GzDecoder::new(&compressed_data[..]);
+        let mut decompressed_data = Vec::new();
+        decoder.read_to_end(&mut decompressed_data)?;
+        Ok(Some(decompressed_data))
     }
 
     async fn put_layer(&self, hash: &str, data: &[u8]) -> Result<()> {
@@ -194,11 +563,23 @@ impl RemoteCache for HttpRemoteCache {
         let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
         encoder.write_all(data)?;
         let compressed_data = encoder.finish()?;
-        let resp = self.client.put(&url).body(compressed_data).send().await?;
-        if !resp.status().is_success() {
-            anyhow::bail!("Failed to upload layer to remote cache: {}", resp.status());
-        }
-        Ok(())
+        let config = RetryConfig::default();
+        retry_with_backoff(
+            || async {
+                let resp = self
+                    .client
+                    .put(&url)
+                    .body(compressed_data.clone())
+                    .send()
+                    .await?;
+                if !resp.status().is_success() {
+                    anyhow::bail!("Failed to upload layer to remote cache: {}", resp.status());
+                }
+                Ok(())
+            },
+            &config,
+        )
+        .await
     }
 
     async fn get_node_layers(&self, hash: &str) -> Result<Option<Vec<String>>> {
@@ -226,12 +607,57 @@ impl RemoteCache for HttpRemoteCache {
             "total_size": total_size
         });
         let resp = self.client.post(&url).json(&payload).send().await?;
+        if resp.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            return Err(too_large(hash, total_size));
+        }
         if !resp.status().is_success() {
             anyhow::bail!("Failed to register node layers: {}", resp.status());
         }
         Ok(())
     }
 
+    /// Deleting entries is for admins; authenticates with
+    /// `MEMOBUILD_ADMIN_TOKEN`.
+    async fn invalidate(&self, hash: &str) -> Result<()> {
+        let url = format!("{}/api/v1/artifacts/{}", self.base_url, hash);
+        let request = self.client.delete(&url);
+        let request = match std::env::var("MEMOBUILD_ADMIN_TOKEN") {
+            Ok(token) => request.bearer_auth(token),
+            Err(_) => request,
+        };
+        let resp = request.send().await?;
+        if !resp.status().is_success() && resp.status() != 404 {
+            anyhow::bail!("Failed to invalidate {}: {}", hash, resp.status());
+        }
+        Ok(())
+    }
+
+    async fn prefetch_hints(&self, hash: &str) -> Result<Vec<String>> {
+        let url = format!("{}/cache/hints/{}", self.base_url, hash);
+        let resp = self.client.get(&url).send().await?;
+        // Servers from before hints answer 404
+        if !resp.status().is_success() {
+            return Ok(Vec::new());
+        }
+        Ok(resp.json().await?)
+    }
+
+    async fn report_corrupt_layer(&self, rejected: &RejectedArtifact) -> Result<bool> {
+        let url = format!("{}/cache/layer/{}/report", self.base_url, rejected.expected);
+        let resp = self
+            .client
+            .post(&url)
+            .json(&serde_json::json!({ "key": rejected.key, "actual": rejected.actual }))
+            .send()
+            .await?;
+        // Servers from before quarantine answer 404, as do ones that lost the layer
+        if !resp.status().is_success() {
+            return Ok(false);
+        }
+        let verdict: serde_json::Value = resp.json().await?;
+        Ok(verdict["quarantined"].as_bool().unwrap_or(false))
+    }
+
     async fn report_build_event(&self, event: BuildEvent) -> Result<()> {
         let url = format!("{}/build-event", self.base_url);
         let resp = self.client.post(&url).json(&event).send().await?;
@@ -282,4 +708,12 @@ pub mod tests {
     {
         retry_with_backoff(operation, config).await
     }
+
+    #[test]
+    fn test_content_range() {
+        assert_eq!(content_range("bytes 10-99/100"), (Some(10), Some(100)));
+        assert_eq!(content_range("bytes 0-9/*"), (Some(0), None));
+        assert_eq!(content_range("bytes */100"), (None, Some(100)));
+        assert_eq!(content_range("items 1-2/3"), (None, None));
+    }
 }
\ No newline at end of file
@@ -1,11 +1,32 @@
 use crate::cache::remote::RemoteCache;
+use crate::cache::cas::{ContentStore, StoreStats};
+use crate::cache::integrity::{self, RejectedArtifact};
 use crate::cache::local::LocalCache;
+use crate::hasher::IgnoreRules;
+use crate::report::CacheSource;
 use anyhow::Result;
+use std::io::{Read, Write};
 use std::sync::Arc;
 
 pub struct HybridCache {
     pub local: LocalCache,
     pub remote: Option<Arc<dyn RemoteCache>>,
+    /// Set when the remote sits behind a circuit breaker
+    pub remote_health: Option<Arc<crate::cache::breaker::RemoteHealth>>,
+    /// Set when remote writes are copied to mirrors
+    pub mirrors: Option<Arc<crate::cache::mirror::MirrorStatus>>,
+    /// Remote artifacts rejected for not matching their digests
+    pub integrity: Arc<crate::cache::integrity::IntegrityLog>,
+    /// Layers uploaded, and skipped because the remote had them
+    pub uploads: Arc<crate::cache::upload::UploadStats>,
+    /// Ask the remote for each layer before uploading it
+    pub dedup_uploads: bool,
+    /// Prefetch what the remote says is fetched together with a remote hit
+    pub prefetch_hints: bool,
+    /// Keys the remote was already asked for hints about
+    pub hinted: std::sync::Mutex<std::collections::HashSet<String>>,
+    /// Artifacts larger than this are not cached
+    pub max_artifact_bytes: Option<u64>,
 }
 
 impl HybridCache {
@@ -13,17 +34,51 @@ impl HybridCache {
         Ok(Self {
             local: LocalCache::new()?,
             remote,
+            remote_health: None,
+            mirrors: None,
+            integrity: Default::default(),
+            uploads: Default::default(),
+            dedup_uploads: crate::cache::upload::enabled_from_env(),
+            prefetch_hints: prefetch_hints_from_env(),
+            hinted: Default::default(),
+            max_artifact_bytes: crate::cache::limits::max_artifact_bytes(),
         })
     }
 
+    /// Whether to skip uploading layers the remote already has. Defaults to
+    /// `MEMOBUILD_UPLOAD_DEDUP`.
+    pub fn with_upload_dedup(mut self, enabled: bool) -> Self {
+        self.dedup_uploads = enabled;
+        self
+    }
+
+    pub fn with_remote_health(
+        mut self,
+        health: Arc<crate::cache::breaker::RemoteHealth>,
+    ) -> Self {
+        self.remote_health = Some(health);
+        self
+    }
+
+    pub fn with_mirrors(mut self, status: Arc<crate::cache::mirror::MirrorStatus>) -> Self {
+        self.mirrors = Some(status);
+        self
+    }
+
     pub fn new_with_box(remote: Option<Arc<dyn RemoteCache>>) -> Result<Self> {
         Self::new(remote)
     }
 
     pub async fn get_artifact(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.lookup_artifact(key).await?.map(|(data, _)| data))
+    }
+
+    /// Like [`get_artifact`](Self::get_artifact), also telling which cache
+    /// the artifact came from.
+    pub async fn lookup_artifact(&self, key: &str) -> Result<Option<(Vec<u8>, CacheSource)>> {
         // 1. Try local
         if let Some(data) = self.local.get_data(key)? {
-            return Ok(Some(data));
+            return Ok(Some((data, CacheSource::Local)));
         }
 
         // 2. Try remote (Layered protocol)
@@ -34,36 +89,155 @@ impl HybridCache {
                     layer_hashes.len()
                 );
                 let mut layers_data = Vec::with_capacity(layer_hashes.len());
-                for hash in layer_hashes {
-                    if let Some(layer) = remote.get_layer(&hash).await? {
-                        layers_data.push(layer);
-                    } else {
-                        anyhow::bail!(
-                            "Cache integrity failure: layer {} missing for node {}",
-                            hash,
-                            key
-                        );
+                // Nothing unverified reaches the local cache or the workspace
+                for hash in &layer_hashes {
+                    match self.fetch_layer(remote.as_ref(), key, hash).await? {
+                        Some(layer) => layers_data.push(layer),
+                        None => return Ok(None),
                     }
                 }
                 let data = crate::cache::utils::merge_artifact(layers_data);
                 self.local.put(key, &data)?;
-                return Ok(Some(data));
+                return Ok(Some((data, CacheSource::Remote)));
             }
 
             // Fallback for non-layered artifacts
             if let Some(data) = remote.get(key).await? {
                 // Populate local cache
                 self.local.put(key, &data)?;
-                return Ok(Some(data));
+                return Ok(Some((data, CacheSource::Remote)));
             }
         }
 
         Ok(None)
     }
 
+    /// Like [`lookup_artifact`](Self::lookup_artifact), as a reader over the
+    /// local copy. A remote artifact is fetched layer by layer, each checked
+    /// and appended to the local cache, so at most one layer is held in
+    /// memory whatever the artifact's size.
+    pub async fn lookup_reader(
+        &self,
+        key: &str,
+    ) -> Result<Option<(Box<dyn Read + Send>, CacheSource)>> {
+        if let Some(reader) = self.local.get_reader(key)? {
+            return Ok(Some((reader, CacheSource::Local)));
+        }
+        let Some(ref remote) = self.remote else {
+            return Ok(None);
+        };
+
+        if let Some(layer_hashes) = remote.get_node_layers(key).await? {
+            // Nothing is visible in the local cache until every layer checked out
+            let mut local = self.local.writer(key, "artifact")?;
+            for hash in &layer_hashes {
+                let Some(layer) = self.fetch_layer(remote.as_ref(), key, hash).await? else {
+                    return Ok(None);
+                };
+                local.write_all(&layer)?;
+            }
+            local.commit()?;
+        } else {
+            // Fallback for non-layered artifacts
+            let Some(mut remote_reader) = remote.get_reader(key).await? else {
+                return Ok(None);
+            };
+            self.local
+                .put_from_reader(key, "artifact", &mut remote_reader.reader)?;
+        }
+
+        Ok(self
+            .local
+            .get_reader(key)?
+            .map(|reader| (reader, CacheSource::Remote)))
+    }
+
+    /// Layer `hash` of the artifact `key`, checked against its digest unless
+    /// the remote authenticated it already. A layer the remote lost or served
+    /// bad is reported to it for quarantine and fetched from the remote's
+    /// other sources; `None`, for the node to be rebuilt, when none of them
+    /// has a good copy.
+    async fn fetch_layer(
+        &self,
+        remote: &dyn RemoteCache,
+        key: &str,
+        hash: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        let actual = match remote.get_layer(hash).await? {
+            Some(layer)
+                if remote.authenticates_layers() || integrity::layer_matches(hash, &layer) =>
+            {
+                return Ok(Some(layer))
+            }
+            Some(layer) => blake3::hash(&layer).to_hex().to_string(),
+            None => integrity::MISSING.to_string(),
+        };
+        let rejected = RejectedArtifact {
+            key: key.to_string(),
+            expected: hash.to_string(),
+            actual,
+        };
+        eprintln!(
+            "⚠️  Remote artifact {} failed verification (layer {} has digest {}); trying other sources",
+            key, rejected.expected, rejected.actual
+        );
+        match remote.report_corrupt_layer(&rejected).await {
+            Ok(true) => eprintln!("   ☣️  The remote quarantined its copy of layer {}", hash),
+            Ok(false) => {}
+            Err(e) => eprintln!("⚠️  Could not report layer {}: {}", hash, e),
+        }
+
+        match remote.get_layer_from_alternates(hash).await {
+            Ok(Some(layer)) => {
+                println!("   🩹 Layer {} recovered from another source", hash);
+                self.integrity.recover(rejected);
+                Ok(Some(layer))
+            }
+            result => {
+                if let Err(e) = result {
+                    eprintln!("⚠️  Other sources failed for layer {}: {}", hash, e);
+                }
+                eprintln!("⚠️  No good copy of remote artifact {}; rebuilding", key);
+                self.integrity.reject(rejected);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Remove `key` from the local cache and, unless `local_only`, the remote
+    /// one. Returns whether the local cache had it.
+    pub async fn invalidate(&self, key: &str, local_only: bool) -> Result<bool> {
+        let was_local = self.local.remove(key)?;
+        if !local_only {
+            if let Some(ref remote) = self.remote {
+                remote.invalidate(key).await?;
+            }
+        }
+        Ok(was_local)
+    }
+
+    /// Whether the remote cache could serve `key`, without downloading it.
+    pub async fn remote_has_artifact(&self, key: &str) -> Result<bool> {
+        let Some(ref remote) = self.remote else {
+            return Ok(false);
+        };
+        if remote.get_node_layers(key).await?.is_some() {
+            return Ok(true);
+        }
+        remote.has(key).await
+    }
+
     pub async fn put_artifact(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.put_artifact_named(key, "artifact", data).await
+    }
+
+    /// Like [`put_artifact`](Self::put_artifact); `name` labels the local
+    /// store path when the cache uses a store directory.
+    pub async fn put_artifact_named(&self, key: &str, name: &str, data: &[u8]) -> Result<()> {
+        crate::cache::limits::check_artifact_size(key, data.len() as u64, self.max_artifact_bytes)?;
+
         // 1. Put local
-        self.local.put(key, data)?;
+        self.local.put_named(key, name, data)?;
 
         // 2. Put remote (Layered protocol)
         if let Some(ref remote) = self.remote {
@@ -71,10 +245,9 @@ impl HybridCache {
             let mut layer_hashes = Vec::new();
 
             for layer in layers {
-                layer_hashes.push(layer.hash.clone());
-                if !remote.has_layer(&layer.hash).await? {
-                    remote.put_layer(&layer.hash, &layer.data).await?;
-                }
+                self.upload_layer(remote.as_ref(), &layer.hash, &layer.data)
+                    .await?;
+                layer_hashes.push(layer.hash);
             }
 
             remote
@@ -85,6 +258,64 @@ impl HybridCache {
         Ok(())
     }
 
+    /// Like [`put_artifact_named`](Self::put_artifact_named), reading the
+    /// artifact from `reader` one layer at a time: it is written to the local
+    /// cache and uploaded as it is read, so only one layer is held in memory.
+    /// Returns the artifact's size.
+    pub async fn put_from_reader(
+        &self,
+        key: &str,
+        name: &str,
+        reader: &mut (dyn Read + Send),
+    ) -> Result<u64> {
+        let mut local = self.local.writer(key, name)?;
+        let mut layer_hashes = Vec::new();
+        let mut size = 0u64;
+        loop {
+            let mut chunk = Vec::with_capacity(crate::cache::utils::CHUNK_SIZE);
+            (&mut *reader)
+                .take(crate::cache::utils::CHUNK_SIZE as u64)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                break;
+            }
+            size += chunk.len() as u64;
+            crate::cache::limits::check_artifact_size(key, size, self.max_artifact_bytes)?;
+            local.write_all(&chunk)?;
+            if let Some(ref remote) = self.remote {
+                let hash = blake3::hash(&chunk).to_hex().to_string();
+                self.upload_layer(remote.as_ref(), &hash, &chunk).await?;
+                layer_hashes.push(hash);
+            }
+        }
+        local.commit()?;
+
+        if let Some(ref remote) = self.remote {
+            remote
+                .register_node_layers(key, &layer_hashes, size)
+                .await?;
+        }
+        Ok(size)
+    }
+
+    async fn upload_layer(&self, remote: &dyn RemoteCache, hash: &str, data: &[u8]) -> Result<()> {
+        if self.integrity.is_poisoned(hash) {
+            // The remote's copy exists but is wrong; replace it
+            remote.put_layer(hash, data).await?;
+            self.integrity.repaired(hash);
+            self.uploads.record_upload(data.len());
+        } else if self.dedup_uploads
+            // A failed check only costs an upload
+            && remote.has_layer(hash).await.unwrap_or(false)
+        {
+            self.uploads.record_skip(data.len());
+        } else {
+            remote.put_layer(hash, data).await?;
+            self.uploads.record_upload(data.len());
+        }
+        Ok(())
+    }
+
     pub async fn report_analytics(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()> {
         if let Some(ref remote) = self.remote {
             remote.report_analytics(dirty, cached, duration_ms).await?;
@@ -92,44 +323,146 @@ impl HybridCache {
         Ok(())
     }
 
-    /// Smart Prefetching: Start downloading artifacts in the background
+    /// Smart Prefetching: Start downloading artifacts in the background.
+    ///
+    /// One task keeps up to `MEMOBUILD_MAX_TRANSFERS` downloads in flight
+    /// over the remote's pooled connections, instead of a task and possibly
+    /// a connection per artifact.
     pub fn prefetch_artifacts(self: Arc<Self>, hashes: Vec<String>) {
-        for hash in hashes {
-            // Check local existence first (lightweight)
-            if self.local.exists(&hash) {
-                continue;
-            }
+        use futures::StreamExt;
 
-            let cache_clone = self.clone();
-            let hash_clone = hash.clone();
-
-            // Spawn background task to fetch from remote
-            tokio::task::spawn(async move {
-                if let Some(ref remote) = cache_clone.remote {
-                    // Try to get from remote
-                    match remote.get(&hash_clone).await {
-                        Ok(Some(data)) => {
-                            // Successfully fetched, store in local cache
-                            if let Err(e) = cache_clone.local.put(&hash_clone, &data) {
-                                eprintln!("⚠️ Prefetch write error for {}: {}", hash_clone, e);
-                            } else {
-                                println!("   📥 Prefetched {} from remote", &hash_clone[..8]);
-                            }
-                        }
-                        Ok(None) => {
-                            // Not in remote cache, which is fine
-                        }
-                        Err(e) => {
-                            eprintln!("⚠️ Prefetch fetch error for {}: {}", hash_clone, e);
+        let Some(remote) = self.remote.clone() else {
+            return;
+        };
+        // Check local existence first (lightweight)
+        let missing: Vec<String> = hashes
+            .into_iter()
+            .filter(|hash| !self.local.exists(hash))
+            .collect();
+        if missing.is_empty() {
+            return;
+        }
+        let in_flight = crate::cache::throttle::max_transfers_from_env()
+            .unwrap_or(crate::constants::DEFAULT_MAX_TRANSFERS);
+
+        tokio::task::spawn(async move {
+            let mut downloads = futures::stream::iter(missing)
+                .map(|hash| {
+                    let remote = remote.clone();
+                    async move {
+                        let result = remote.get(&hash).await;
+                        (hash, result)
+                    }
+                })
+                .buffer_unordered(in_flight);
+            let mut fetched = 0;
+            while let Some((hash, result)) = downloads.next().await {
+                match result {
+                    Ok(Some(data)) => {
+                        // Successfully fetched, store in local cache
+                        if let Err(e) = self.local.put(&hash, &data) {
+                            eprintln!("⚠️ Prefetch write error for {}: {}", hash, e);
+                        } else {
+                            fetched += 1;
                         }
                     }
+                    Ok(None) => {
+                        // Not in remote cache, which is fine
+                    }
+                    Err(e) => {
+                        eprintln!("⚠️ Prefetch fetch error for {}: {}", hash, e);
+                    }
                 }
-            });
+            }
+            if fetched > 0 {
+                println!("   📥 Prefetched {} artifact(s) from remote", fetched);
+            }
+        });
+    }
+
+    /// After a remote hit on `key`, download in the background what other
+    /// builds fetched together with it. The remote is asked once per key.
+    pub fn prefetch_siblings(self: Arc<Self>, key: &str) {
+        if !self.prefetch_hints {
+            return;
+        }
+        let Some(remote) = self.remote.clone() else {
+            return;
+        };
+        if !self.hinted.lock().unwrap().insert(key.to_string()) {
+            return;
         }
+        let key = key.to_string();
+        tokio::task::spawn(async move {
+            match remote.prefetch_hints(&key).await {
+                Ok(hints) if !hints.is_empty() => self.prefetch_artifacts(hints),
+                Ok(_) => {}
+                Err(e) => eprintln!("⚠️ Prefetch hints error for {}: {}", key, e),
+            }
+        });
     }
 }
 
+/// Whether `MEMOBUILD_PREFETCH_HINTS` leaves hinted prefetching on (the
+/// default).
+fn prefetch_hints_from_env() -> bool {
+    !matches!(
+        std::env::var("MEMOBUILD_PREFETCH_HINTS").as_deref(),
+        Ok("0") | Ok("false")
+    )
+}
+
 impl HybridCache {
+    /// File-level store for build contexts, next to the local artifacts.
+    pub fn content_store(&self) -> ContentStore {
+        ContentStore::in_cache_dir(self.local.cache_dir())
+    }
+
+    /// Store a COPY source in the content store and push the files and trees
+    /// the remote does not have yet. Returns the stats with the root digest.
+    pub async fn put_tree(
+        &self,
+        path: &std::path::Path,
+        ignore: &IgnoreRules,
+    ) -> Result<StoreStats> {
+        let store = self.content_store();
+        let stats = store.store_dir(path, ignore)?;
+        if let Some(ref remote) = self.remote {
+            let uploaded =
+                crate::cache::cas::upload_tree(&store, &stats.root, remote.as_ref()).await?;
+            if uploaded.objects > 0 {
+                println!(
+                    "   📤 Uploaded {} new context object(s), {} bytes",
+                    uploaded.objects, uploaded.bytes
+                );
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Recreate the tree `root` under `dest`, fetching missing objects from
+    /// the remote first.
+    pub async fn materialize_tree(&self, root: &str, dest: &std::path::Path) -> Result<()> {
+        let store = self.content_store();
+        if let Some(ref remote) = self.remote {
+            crate::cache::cas::fetch_tree(&store, root, remote.as_ref()).await?;
+        }
+        store.materialize(root, dest)
+    }
+
+    /// The tree `root` of the content store, loaded as it is read: objects
+    /// the local store lacks are fetched from the remote on first use.
+    /// Fetches block on the current runtime, so reads must come from other
+    /// threads, as FUSE requests do.
+    pub fn lazy_tree(&self, root: &str) -> crate::sandbox::lazy::LazyTree {
+        let fetch = self.remote.clone().map(|remote| {
+            let handle = tokio::runtime::Handle::current();
+            Arc::new(move |digest: &str| handle.block_on(remote.get_layer(digest)))
+                as crate::sandbox::lazy::Fetch
+        });
+        crate::sandbox::lazy::LazyTree::new(self.content_store(), root, fetch)
+    }
+
     pub async fn upload_manifest_and_files(
         &self,
         manifest: &crate::cache::utils::ArtifactManifest,
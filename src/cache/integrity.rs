@@ -0,0 +1,147 @@
+//! Checks of downloaded layers against their digests, and the log of artifacts
+//! rejected and recovered during a build.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// A remote artifact whose content did not match its recorded digests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedArtifact {
+    /// Cache key of the node
+    pub key: String,
+    /// Digest the layer was registered under
+    pub expected: String,
+    /// Digest of what was downloaded, or [`MISSING`]
+    pub actual: String,
+}
+
+/// [`RejectedArtifact::actual`] of a layer the remote did not have
+pub const MISSING: &str = "missing";
+
+/// Whether `data` is the layer registered under `digest`.
+pub fn layer_matches(digest: &str, data: &[u8]) -> bool {
+    blake3::hash(data).to_hex().as_str() == digest
+}
+
+/// Check downloaded layers against the digests they were registered under.
+/// Returns the first layer that does not match.
+pub fn verify_layers(
+    key: &str,
+    expected: &[String],
+    layers: &[Vec<u8>],
+) -> Result<(), RejectedArtifact> {
+    for (digest, data) in expected.iter().zip(layers) {
+        if !layer_matches(digest, data) {
+            return Err(RejectedArtifact {
+                key: key.to_string(),
+                expected: digest.clone(),
+                actual: blake3::hash(data).to_hex().to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Rejections during one process, shared by every user of the cache.
+#[derive(Debug, Default)]
+pub struct IntegrityLog {
+    rejected: Mutex<Vec<RejectedArtifact>>,
+    /// Bad layers a good copy was found for elsewhere
+    recovered: Mutex<Vec<RejectedArtifact>>,
+    /// Layers the remote served with the wrong content
+    poisoned: Mutex<HashSet<String>>,
+}
+
+impl IntegrityLog {
+    pub fn reject(&self, rejected: RejectedArtifact) {
+        self.poisoned
+            .lock()
+            .unwrap()
+            .insert(rejected.expected.clone());
+        self.rejected.lock().unwrap().push(rejected);
+    }
+
+    pub fn rejected(&self) -> Vec<RejectedArtifact> {
+        self.rejected.lock().unwrap().clone()
+    }
+
+    /// Note a bad layer that another source had a good copy of.
+    pub fn recover(&self, rejected: RejectedArtifact) {
+        self.recovered.lock().unwrap().push(rejected);
+    }
+
+    pub fn recovered(&self) -> Vec<RejectedArtifact> {
+        self.recovered.lock().unwrap().clone()
+    }
+
+    /// Whether the remote's copy of `layer` is known to be bad and has to be
+    /// uploaded again even though it exists.
+    pub fn is_poisoned(&self, layer: &str) -> bool {
+        self.poisoned.lock().unwrap().contains(layer)
+    }
+
+    /// Forget that `layer` is bad, once a good copy was uploaded.
+    pub fn repaired(&self, layer: &str) {
+        self.poisoned.lock().unwrap().remove(layer);
+    }
+
+    /// One-line note for the end of the build, if anything was rejected
+    /// or recovered.
+    pub fn summary(&self) -> Option<String> {
+        let rejected = self.rejected.lock().unwrap();
+        let recovered = self.recovered.lock().unwrap().len();
+        let recovered_note = format!(
+            "{} bad remote layer(s) recovered from another source",
+            recovered
+        );
+        if rejected.is_empty() {
+            return (recovered > 0).then_some(recovered_note);
+        }
+        let keys: Vec<&str> = rejected
+            .iter()
+            .map(|r| &r.key[..r.key.len().min(12)])
+            .collect();
+        let mut note = format!(
+            "{} remote artifact(s) failed verification and were rebuilt: {}",
+            rejected.len(),
+            keys.join(", ")
+        );
+        if recovered > 0 {
+            note.push_str(&format!("; {}", recovered_note));
+        }
+        Some(note)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_layers() {
+        let good = b"layer".to_vec();
+        let digest = blake3::hash(&good).to_hex().to_string();
+        let digests = [digest.clone()];
+        assert!(verify_layers("key", &digests, &[good]).is_ok());
+
+        let rejected = verify_layers("key", &digests, &[b"evil".to_vec()]).unwrap_err();
+        assert_eq!(rejected.expected, digest);
+        assert_eq!(rejected.actual, blake3::hash(b"evil").to_hex().to_string());
+
+        let log = IntegrityLog::default();
+        assert!(log.summary().is_none());
+        log.reject(rejected);
+        assert!(log.is_poisoned(&digest));
+        assert!(log.summary().unwrap().starts_with("1 remote artifact(s)"));
+        log.repaired(&digest);
+        assert!(!log.is_poisoned(&digest));
+        assert_eq!(log.rejected().len(), 1);
+
+        let recovered = IntegrityLog::default();
+        recovered.recover(verify_layers("key", &[digest], &[b"evil".to_vec()]).unwrap_err());
+        assert_eq!(
+            recovered.summary().unwrap(),
+            "1 bad remote layer(s) recovered from another source"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Artifact size limits from `MEMOBUILD_MAX_ARTIFACT_BYTES` and
+//! `MEMOBUILD_LARGE_ARTIFACT_BYTES`, where `0` is no limit.
+
+use anyhow::Result;
+
+/// Largest artifact cached, from `MEMOBUILD_MAX_ARTIFACT_BYTES`. `None` when
+/// there is no limit.
+pub fn max_artifact_bytes() -> Option<u64> {
+    limit_from_env(
+        "MEMOBUILD_MAX_ARTIFACT_BYTES",
+        crate::constants::DEFAULT_MAX_ARTIFACT_BYTES,
+    )
+}
+
+/// Size above which an artifact is reported as large, from
+/// `MEMOBUILD_LARGE_ARTIFACT_BYTES`. `None` when nothing is reported.
+pub fn large_artifact_bytes() -> Option<u64> {
+    limit_from_env(
+        "MEMOBUILD_LARGE_ARTIFACT_BYTES",
+        crate::constants::DEFAULT_LARGE_ARTIFACT_BYTES,
+    )
+}
+
+fn limit_from_env(var: &str, default: u64) -> Option<u64> {
+    let bytes = std::env::var(var)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(default);
+    (bytes > 0).then_some(bytes)
+}
+
+/// An artifact over the client's or the cache server's size limit.
+#[derive(Debug)]
+pub struct ArtifactTooLarge {
+    pub key: String,
+    pub size: u64,
+    /// The limit, when known; the server does not say what its limit is
+    pub max: Option<u64>,
+}
+
+impl std::fmt::Display for ArtifactTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let size = crate::plan::format_bytes(self.size);
+        match self.max {
+            Some(max) => write!(
+                f,
+                "artifact {} is {}, over the {} limit (MEMOBUILD_MAX_ARTIFACT_BYTES)",
+                self.key,
+                size,
+                crate::plan::format_bytes(max)
+            )?,
+            None => write!(
+                f,
+                "the remote cache rejected artifact {} ({}) as too large",
+                self.key, size
+            )?,
+        }
+        write!(f, "; split the step so it writes less, or raise the limit")
+    }
+}
+
+impl std::error::Error for ArtifactTooLarge {}
+
+/// Fail with [`ArtifactTooLarge`] when `size` bytes for `key` is over `max`.
+pub fn check_artifact_size(key: &str, size: u64, max: Option<u64>) -> Result<()> {
+    match max {
+        Some(max) if size > max => Err(ArtifactTooLarge {
+            key: key.to_string(),
+            size,
+            max: Some(max),
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_artifact_size() {
+        assert!(check_artifact_size("abc", 10, None).is_ok());
+        assert!(check_artifact_size("abc", 10, Some(10)).is_ok());
+        let err = check_artifact_size("abc", 11, Some(10)).unwrap_err();
+        assert!(err.to_string().contains("MEMOBUILD_MAX_ARTIFACT_BYTES"));
+        assert!(err.downcast_ref::<ArtifactTooLarge>().is_some());
+    }
+}
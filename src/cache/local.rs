@@ -1,45 +1,255 @@
+use crate::cache::clock;
+use crate::cache::pin::PinTarget;
+use crate::cache::store::ArtifactStore;
+use crate::gc::{GcCandidate, GcPlan, GcReason};
+use crate::storage::local::AtomicFile;
 use anyhow::{Context, Result};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 
+/// Which builds share a local cache directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheScope {
+    /// One directory per project, so unrelated projects never see each
+    /// other's keys
+    Project,
+    /// A single directory shared by every project on the machine
+    Global,
+}
+
+impl CacheScope {
+    /// Read `MEMOBUILD_CACHE_SCOPE` (`project` or `global`); unset means
+    /// [`CacheScope::Project`].
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("MEMOBUILD_CACHE_SCOPE") {
+            Ok(value) => value.parse(),
+            Err(_) => Ok(CacheScope::Project),
+        }
+    }
+}
+
+impl std::str::FromStr for CacheScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "project" => Ok(CacheScope::Project),
+            "global" => Ok(CacheScope::Global),
+            other => anyhow::bail!(
+                "invalid MEMOBUILD_CACHE_SCOPE '{}': expected project or global",
+                other
+            ),
+        }
+    }
+}
+
+static WORKSPACE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Record the build context the command works on, so a project-scoped
+/// cache is chosen for its project rather than the current directory.
+/// Only the first call has an effect.
+pub fn set_workspace(path: &Path) {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let _ = WORKSPACE.set(path);
+}
+
+/// The project `path` belongs to: the closest directory at or above it
+/// holding a `.git`, or `path` itself outside a repository.
+pub fn project_root(path: &Path) -> PathBuf {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    path.ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .map(Path::to_path_buf)
+        .unwrap_or(path)
+}
+
+/// Directory name of a project's cache: the project's name, for people
+/// reading the cache root, and a hash of its full path, so two checkouts
+/// named alike stay apart.
+fn project_dir_name(project: &Path) -> String {
+    let name: String = project
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "root".to_string())
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let hash = blake3::hash(project.to_string_lossy().as_bytes()).to_hex();
+    format!("{}-{}", name, &hash[..16])
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct CacheEntry {
     pub cache_key: String,
     pub created_at: i64,
     pub artifact_path: PathBuf,
     pub size: u64,
+    /// [`CACHE_KEY_VERSION`](crate::constants::CACHE_KEY_VERSION) the key was
+    /// derived with; 0 for entries written before key versioning
+    #[serde(default)]
+    pub key_version: u32,
+    /// `MEMOBUILD_BUILD_ID` of the build that stored the entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_id: Option<String>,
+    /// Label of the pin keeping the entry from being pruned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pin: Option<String>,
+    /// BLAKE3 digest of the artifact, checked by [`LocalCache::verify`];
+    /// missing for entries written before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// When the entry was last read, in seconds since the epoch; missing
+    /// until the first read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_access: Option<i64>,
+    /// Position of the write in this cache, counting up regardless of what
+    /// the clock said; 0 for entries written before it was recorded
+    #[serde(default)]
+    pub seq: u64,
+}
+
+impl CacheEntry {
+    /// When the entry was last written or read, in seconds since the epoch
+    pub fn last_used(&self) -> i64 {
+        self.last_access
+            .unwrap_or(self.created_at)
+            .max(self.created_at)
+    }
+}
+
+fn gc_candidate(entry: &CacheEntry, reason: GcReason) -> GcCandidate {
+    GcCandidate {
+        key: entry.cache_key.clone(),
+        reason,
+        bytes: entry.size,
+        last_used: last_used_rfc3339(entry),
+    }
+}
+
+fn last_used_rfc3339(entry: &CacheEntry) -> Option<String> {
+    chrono::DateTime::from_timestamp(entry.last_used(), 0).map(|at| at.to_rfc3339())
+}
+
+/// What [`LocalCache::verify`] found wrong with an entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Damage {
+    /// The artifact file is gone
+    Missing,
+    /// The file is shorter or longer than when it was stored
+    SizeMismatch { expected: u64, actual: u64 },
+    /// The content no longer matches its digest
+    Corrupted,
+}
+
+impl std::fmt::Display for Damage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Damage::Missing => write!(f, "artifact file missing"),
+            Damage::SizeMismatch { expected, actual } => {
+                write!(f, "{} bytes on disk, {} expected", actual, expected)
+            }
+            Damage::Corrupted => write!(f, "content does not match its digest"),
+        }
+    }
+}
+
+/// Outcome of a [`LocalCache::verify`] pass.
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Entries checked
+    pub checked: usize,
+    /// Entries found damaged and removed, with what was wrong
+    pub removed: Vec<(String, Damage)>,
 }
 
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
 
+#[derive(Clone)]
 pub struct LocalCache {
     cache_dir: PathBuf,
     store: Arc<RwLock<HashMap<String, CacheEntry>>>,
     index_path: PathBuf,
+    /// Set in store mode: artifacts live in the store, entries link to them
+    artifact_store: Option<ArtifactStore>,
+    /// `seq` of the next entry written
+    next_seq: Arc<AtomicU64>,
+    /// Reads updated `last_access` since the index was last saved
+    access_dirty: Arc<AtomicBool>,
 }
 
 impl LocalCache {
     pub fn new() -> Result<Self> {
-        let cache_dir = Self::get_cache_dir()?;
+        Ok(Self::in_dir(Self::get_cache_dir()?)?
+            .with_artifact_store_opt(ArtifactStore::from_env()?))
+    }
+
+    pub fn in_dir(cache_dir: PathBuf) -> Result<Self> {
         fs::create_dir_all(&cache_dir)?;
 
         let index_path = cache_dir.join("index.json");
         let store = Self::load_index(&index_path)?;
+        let next_seq = store.values().map(|entry| entry.seq).max().unwrap_or(0) + 1;
 
         Ok(Self {
             cache_dir,
             store: Arc::new(RwLock::new(store)),
             index_path,
+            artifact_store: None,
+            next_seq: Arc::new(AtomicU64::new(next_seq)),
+            access_dirty: Arc::new(AtomicBool::new(false)),
         })
     }
 
+    /// Keep artifacts in a read-only content-addressed store.
+    pub fn with_artifact_store(self, store: ArtifactStore) -> Self {
+        self.with_artifact_store_opt(Some(store))
+    }
+
+    fn with_artifact_store_opt(mut self, store: Option<ArtifactStore>) -> Self {
+        self.artifact_store = store;
+        self
+    }
+
+    pub fn artifact_store(&self) -> Option<&ArtifactStore> {
+        self.artifact_store.as_ref()
+    }
+
+    /// `MEMOBUILD_CACHE_DIR` when set, used as is. Otherwise a directory
+    /// under `~/.memobuild/cache` chosen by [`CacheScope::from_env`]: one
+    /// per project by default, or the cache root itself when shared.
     fn get_cache_dir() -> Result<PathBuf> {
         if let Ok(dir) = std::env::var("MEMOBUILD_CACHE_DIR") {
             return Ok(PathBuf::from(dir));
         }
         let home = std::env::var("HOME").context("HOME environment variable not set")?;
-        Ok(PathBuf::from(home).join(".memobuild").join("cache"))
+        let root = PathBuf::from(home).join(".memobuild").join("cache");
+        match CacheScope::from_env()? {
+            CacheScope::Global => Ok(root),
+            CacheScope::Project => {
+                let workspace = match WORKSPACE.get() {
+                    Some(path) => path.clone(),
+                    None => std::env::current_dir()?,
+                };
+                let project = project_root(&workspace);
+                let dir = root.join("projects").join(project_dir_name(&project));
+                fs::create_dir_all(&dir)?;
+                // Lets `ls ~/.memobuild/cache/projects` be traced back to a checkout
+                let marker = dir.join("workspace");
+                if !marker.exists() {
+                    fs::write(&marker, project.to_string_lossy().as_bytes())?;
+                }
+                Ok(dir)
+            }
+        }
     }
 
     fn load_index(path: &Path) -> Result<HashMap<String, CacheEntry>> {
@@ -47,8 +257,19 @@ impl LocalCache {
             return Ok(HashMap::new());
         }
         let content = fs::read_to_string(path)?;
-        let store: HashMap<String, CacheEntry> = serde_json::from_str(&content).unwrap_or_default();
+        let mut store: HashMap<String, CacheEntry> =
+            serde_json::from_str(&content).unwrap_or_default();
 
+        // Stamps from a clock that ran ahead would otherwise keep their
+        // entries from ever looking idle
+        let now = clock::stamp_secs();
+        let tolerance = clock::skew_tolerance();
+        for entry in store.values_mut() {
+            entry.created_at = clock::clamp(entry.created_at * 1000, now * 1000, tolerance) / 1000;
+            entry.last_access = entry
+                .last_access
+                .map(|at| clock::clamp(at * 1000, now * 1000, tolerance) / 1000);
+        }
         Ok(store)
     }
 
@@ -58,36 +279,135 @@ impl LocalCache {
             .read()
             .map_err(|_| anyhow::anyhow!("Poisoned lock"))?;
         let content = serde_json::to_string_pretty(&*store)?;
-        fs::write(&self.index_path, content)?;
+        crate::storage::local::write_atomic(&self.index_path, content.as_bytes())?;
         Ok(())
     }
 
     pub fn get_data(&self, key: &str) -> Result<Option<Vec<u8>>> {
-        let store = self
+        let Some(path) = self.artifact_file(key)? else {
+            return Ok(None);
+        };
+        if let Some(target) = self.store_target(&path) {
+            return ArtifactStore::read(&target);
+        }
+        if path.exists() {
+            return Ok(Some(fs::read(path)?));
+        }
+        Ok(None)
+    }
+
+    /// Like [`get_data`](Self::get_data), reading the artifact from disk as
+    /// it is consumed instead of loading it whole. Store paths are still read
+    /// whole, since their content is checked against the digest first.
+    pub fn get_reader(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        let Some(path) = self.artifact_file(key)? else {
+            return Ok(None);
+        };
+        if let Some(target) = self.store_target(&path) {
+            return Ok(ArtifactStore::read(&target)?
+                .map(|data| Box::new(std::io::Cursor::new(data)) as Box<dyn Read + Send>));
+        }
+        match fs::File::open(&path) {
+            Ok(file) => Ok(Some(Box::new(std::io::BufReader::new(file)))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Path of the artifact file of `key`, if it has an entry, marking the
+    /// entry as read.
+    fn artifact_file(&self, key: &str) -> Result<Option<PathBuf>> {
+        let mut store = self
             .store
-            .read()
+            .write()
             .map_err(|_| anyhow::anyhow!("Poisoned lock"))?;
-        if let Some(entry) = store.get(key) {
-            let path = self.cache_dir.join(&entry.artifact_path);
-            if path.exists() {
-                return Ok(Some(fs::read(path)?));
-            }
+        Ok(store.get_mut(key).map(|entry| {
+            entry.last_access = Some(clock::stamp_secs());
+            self.access_dirty.store(true, Ordering::Relaxed);
+            self.cache_dir.join(&entry.artifact_path)
+        }))
+    }
+
+    /// Save the read times recorded since the index was last written. Reads
+    /// do not write the index themselves, so a build with only hits costs
+    /// one write at the end instead of one per hit.
+    pub fn flush_access(&self) -> Result<()> {
+        if self.access_dirty.swap(false, Ordering::Relaxed) {
+            self.save_index()?;
         }
-        Ok(None)
+        Ok(())
+    }
+
+    /// The store path an entry's link resolves to, in store mode.
+    fn store_target(&self, path: &Path) -> Option<PathBuf> {
+        let artifact_store = self.artifact_store.as_ref()?;
+        let target = path.canonicalize().ok()?;
+        artifact_store.contains(&target).then_some(target)
     }
 
     pub fn put(&self, key: &str, data: &[u8]) -> Result<()> {
-        let artifact_filename = format!("{}.bin", key);
-        let artifact_path = PathBuf::from(&artifact_filename);
-        let full_path = self.cache_dir.join(&artifact_path);
+        self.put_named(key, "artifact", data)
+    }
 
-        fs::write(&full_path, data)?;
+    /// Like [`put`](Self::put); `name` labels the store path in store mode.
+    pub fn put_named(&self, key: &str, name: &str, data: &[u8]) -> Result<()> {
+        let mut writer = self.writer(key, name)?;
+        writer.write_all(data)?;
+        writer.commit()?;
+        Ok(())
+    }
 
-        let entry = CacheEntry {
+    /// Store everything `reader` yields under `key` without holding it in
+    /// memory. Returns the artifact's size.
+    pub fn put_from_reader(&self, key: &str, name: &str, reader: &mut dyn Read) -> Result<u64> {
+        let mut writer = self.writer(key, name)?;
+        std::io::copy(reader, &mut writer)?;
+        writer.commit()
+    }
+
+    /// Start writing the artifact of `key`. Nothing is visible until
+    /// [`ArtifactWriter::commit`]; a writer dropped before that leaves the
+    /// cache as it was.
+    pub fn writer(&self, key: &str, name: &str) -> Result<ArtifactWriter<'_>> {
+        let sink = match self.artifact_store {
+            // The store path depends on the content, so it is known only at
+            // the end
+            Some(_) => Sink::Buffer(Vec::new()),
+            // A crash mid-write must not leave a truncated artifact that a
+            // later build takes for a hit
+            None => Sink::File(AtomicFile::create(
+                &self.cache_dir.join(format!("{}.bin", key)),
+            )?),
+        };
+        Ok(ArtifactWriter {
+            cache: self,
+            key: key.to_string(),
+            name: name.to_string(),
+            sink,
+            hasher: blake3::Hasher::new(),
+            size: 0,
+        })
+    }
+
+    /// Record the entry of an artifact written to `artifact_path`.
+    fn record_entry(
+        &self,
+        key: &str,
+        artifact_path: PathBuf,
+        size: u64,
+        digest: String,
+    ) -> Result<()> {
+        let mut entry = CacheEntry {
             cache_key: key.to_string(),
-            created_at: chrono::Utc::now().timestamp(),
+            created_at: clock::stamp_secs(),
             artifact_path,
-            size: data.len() as u64,
+            size,
+            key_version: crate::constants::CACHE_KEY_VERSION,
+            build_id: crate::cache::pin::build_id_from_env(),
+            pin: None,
+            digest: Some(digest),
+            last_access: None,
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
         };
 
         {
@@ -95,16 +415,456 @@ impl LocalCache {
                 .store
                 .write()
                 .map_err(|_| anyhow::anyhow!("Poisoned lock"))?;
+            // Rewriting a pinned entry keeps it pinned
+            entry.pin = store.get(key).and_then(|old| old.pin.clone());
             store.insert(key.to_string(), entry);
         }
 
-        self.save_index()?;
-
-        Ok(())
+        self.save_index()
     }
 
     pub fn exists(&self, key: &str) -> bool {
         let store = self.store.read().ok();
         store.map(|s| s.contains_key(key)).unwrap_or(false)
     }
-}
\ No newline at end of file
+
+    /// Drop an entry and its artifact file. Returns whether it was cached.
+    pub fn remove(&self, key: &str) -> Result<bool> {
+        self.remove_if(key, |_| true)
+    }
+
+    /// Like [`remove`](Self::remove), but only when `keep_going` accepts the
+    /// current entry, checked under the index lock.
+    fn remove_if(&self, key: &str, keep_going: impl Fn(&CacheEntry) -> bool) -> Result<bool> {
+        let entry = {
+            let mut store = self
+                .store
+                .write()
+                .map_err(|_| anyhow::anyhow!("Poisoned lock"))?;
+            match store.get(key) {
+                Some(entry) if keep_going(entry) => store.remove(key),
+                _ => None,
+            }
+        };
+        let Some(entry) = entry else {
+            return Ok(false);
+        };
+        let path = self.cache_dir.join(&entry.artifact_path);
+        // Store paths may be shared with other entries; only the link goes
+        let in_store = self
+            .artifact_store
+            .as_ref()
+            .is_some_and(|store| store.contains(&path));
+        if !in_store && fs::symlink_metadata(&path).is_ok() {
+            fs::remove_file(&path)?;
+        }
+        self.save_index()?;
+        Ok(true)
+    }
+
+    /// Drop every entry whose key was derived by another key version, unless
+    /// it is pinned. Returns how many were removed.
+    pub fn prune_key_versions(&self) -> Result<usize> {
+        let stale: Vec<String> = {
+            let store = self
+                .store
+                .read()
+                .map_err(|_| anyhow::anyhow!("Poisoned lock"))?;
+            store
+                .values()
+                .filter(|entry| entry.key_version != crate::constants::CACHE_KEY_VERSION)
+                .filter(|entry| entry.pin.is_none())
+                .map(|entry| entry.cache_key.clone())
+                .collect()
+        };
+        for key in &stale {
+            self.remove(key)?;
+        }
+        Ok(stale.len())
+    }
+
+    /// Drop every entry neither written nor read for longer than `max_idle`,
+    /// unless it is pinned. Idle times are judged with the clock skew
+    /// tolerance of [`clock`], so entries stamped by a clock that ran ahead
+    /// or behind are not dropped early. Returns how many were removed.
+    pub fn prune_idle(&self, max_idle: std::time::Duration) -> Result<usize> {
+        let now = clock::stamp_ms();
+        let tolerance = clock::skew_tolerance();
+        let idle: Vec<(String, u64)> = {
+            let store = self
+                .store
+                .read()
+                .map_err(|_| anyhow::anyhow!("Poisoned lock"))?;
+            store
+                .values()
+                .filter(|entry| entry.pin.is_none())
+                .filter(|entry| {
+                    clock::is_expired(entry.last_used() * 1000, max_idle, now, tolerance)
+                })
+                .map(|entry| (entry.cache_key.clone(), entry.seq))
+                .collect()
+        };
+        let mut removed = 0;
+        for (key, seq) in idle {
+            // An entry rewritten or read since is no longer idle
+            let still_idle = self.remove_if(&key, |current| {
+                current.seq == seq
+                    && current.pin.is_none()
+                    && clock::is_expired(current.last_used() * 1000, max_idle, now, tolerance)
+            })?;
+            if still_idle {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// What pruning would delete, without deleting it: unpinned entries
+    /// idle for longer than `max_idle` (judged like
+    /// [`prune_idle`](Self::prune_idle)), then the least recently used other
+    /// unpinned entries until the cache fits `max_size`, then artifact files
+    /// no entry points at. Files younger than an hour are left alone, as a
+    /// running build may be about to record them.
+    pub fn plan_prune(
+        &self,
+        max_idle: std::time::Duration,
+        max_size: Option<u64>,
+    ) -> Result<GcPlan> {
+        let now = clock::stamp_ms();
+        let tolerance = clock::skew_tolerance();
+        let mut plan = GcPlan::default();
+        let referenced: HashSet<PathBuf> = {
+            let store = self
+                .store
+                .read()
+                .map_err(|_| anyhow::anyhow!("Poisoned lock"))?;
+            let mut used: u64 = store.values().map(|entry| entry.size).sum();
+            let mut unpinned: Vec<&CacheEntry> =
+                store.values().filter(|entry| entry.pin.is_none()).collect();
+            unpinned.sort_by_key(|entry| (entry.last_used(), entry.seq));
+
+            let mut recent = Vec::new();
+            for entry in unpinned {
+                if clock::is_expired(entry.last_used() * 1000, max_idle, now, tolerance) {
+                    used = used.saturating_sub(entry.size);
+                    plan.push(gc_candidate(entry, GcReason::Age));
+                } else {
+                    recent.push(entry);
+                }
+            }
+            if let Some(max_size) = max_size {
+                for entry in recent {
+                    if used <= max_size {
+                        break;
+                    }
+                    used = used.saturating_sub(entry.size);
+                    plan.push(gc_candidate(entry, GcReason::Lru));
+                }
+            }
+            store
+                .values()
+                .map(|entry| self.cache_dir.join(&entry.artifact_path))
+                .collect()
+        };
+
+        let grace = std::time::Duration::from_secs(3600);
+        for file in fs::read_dir(&self.cache_dir)? {
+            let file = file?;
+            let path = file.path();
+            let metadata = file.metadata()?;
+            let fresh = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_none_or(|age| age < grace);
+            if metadata.is_file()
+                && path.extension().is_some_and(|ext| ext == "bin")
+                && !referenced.contains(&path)
+                && !fresh
+            {
+                plan.push(GcCandidate {
+                    key: file.file_name().to_string_lossy().into_owned(),
+                    reason: GcReason::Unreferenced,
+                    bytes: metadata.len(),
+                    last_used: None,
+                });
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Delete what `plan` lists, except entries used, rewritten or pinned
+    /// and files referenced since it was made. Returns what was deleted.
+    pub fn apply_prune(&self, plan: &GcPlan) -> Result<GcPlan> {
+        let mut removed = GcPlan::default();
+        for candidate in &plan.candidates {
+            let gone = if candidate.reason == GcReason::Unreferenced {
+                let path = self.cache_dir.join(&candidate.key);
+                let referenced = self
+                    .store
+                    .read()
+                    .map_err(|_| anyhow::anyhow!("Poisoned lock"))?
+                    .values()
+                    .any(|entry| self.cache_dir.join(&entry.artifact_path) == path);
+                !referenced && fs::remove_file(&path).is_ok()
+            } else {
+                self.remove_if(&candidate.key, |current| {
+                    current.pin.is_none() && last_used_rfc3339(current) == candidate.last_used
+                })?
+            };
+            if gone {
+                removed.push(candidate.clone());
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Pin the entries `target` names under `label`, or unpin them when
+    /// `label` is `None`. Returns how many entries were changed.
+    pub fn set_pin(&self, target: &PinTarget, label: Option<&str>) -> Result<usize> {
+        let changed = {
+            let mut store = self
+                .store
+                .write()
+                .map_err(|_| anyhow::anyhow!("Poisoned lock"))?;
+            let mut changed = 0;
+            for entry in store.values_mut() {
+                let selected = match target {
+                    PinTarget::Key(key) => entry.cache_key == *key,
+                    PinTarget::Build(id) => entry.build_id.as_deref() == Some(id.as_str()),
+                };
+                if selected && entry.pin.as_deref() != label {
+                    entry.pin = label.map(str::to_string);
+                    changed += 1;
+                }
+            }
+            changed
+        };
+        if changed > 0 {
+            self.save_index()?;
+        }
+        Ok(changed)
+    }
+
+    /// Label of the pin on `key`, if it is pinned
+    pub fn pin(&self, key: &str) -> Option<String> {
+        let store = self.store.read().ok()?;
+        store.get(key).and_then(|entry| entry.pin.clone())
+    }
+
+    /// Size in bytes of a cached artifact
+    pub fn size(&self, key: &str) -> Option<u64> {
+        let store = self.store.read().ok()?;
+        store.get(key).map(|entry| entry.size)
+    }
+
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// Re-read every artifact and check it against the size and digest it
+    /// was stored with, removing entries whose file is missing, truncated or
+    /// corrupted, so they are rebuilt or downloaded again instead of served.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let entries: Vec<CacheEntry> = {
+            let store = self
+                .store
+                .read()
+                .map_err(|_| anyhow::anyhow!("Poisoned lock"))?;
+            store.values().cloned().collect()
+        };
+
+        self.remove_stale_temp_files()?;
+        let mut report = VerifyReport::default();
+        for entry in entries {
+            report.checked += 1;
+            let Some(damage) = self.check_entry(&entry)? else {
+                continue;
+            };
+            // An entry rewritten while it was checked is left alone
+            let removed = self.remove_if(&entry.cache_key, |current| {
+                current.seq == entry.seq
+                    && current.created_at == entry.created_at
+                    && current.digest == entry.digest
+            })?;
+            if removed {
+                report.removed.push((entry.cache_key, damage));
+            }
+        }
+        Ok(report)
+    }
+
+    /// Delete temporary files left by writes that died before their rename.
+    /// Recent ones may belong to a write still in progress.
+    fn remove_stale_temp_files(&self) -> Result<()> {
+        let stale_after = std::time::Duration::from_secs(3600);
+        for entry in fs::read_dir(&self.cache_dir)? {
+            let entry = entry?;
+            if !entry.file_name().to_string_lossy().contains(".tmp-") {
+                continue;
+            }
+            let stale = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .is_ok_and(|at| at.elapsed().is_ok_and(|age| age > stale_after));
+            if stale {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        Ok(())
+    }
+
+    fn check_entry(&self, entry: &CacheEntry) -> Result<Option<Damage>> {
+        let path = self.cache_dir.join(&entry.artifact_path);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Some(Damage::Missing)),
+            Err(e) => return Err(e.into()),
+        };
+        if data.len() as u64 != entry.size {
+            return Ok(Some(Damage::SizeMismatch {
+                expected: entry.size,
+                actual: data.len() as u64,
+            }));
+        }
+        let intact = match entry.digest {
+            Some(ref digest) => blake3::hash(&data).to_hex().as_str() == digest,
+            // Older entries only have the digest in their store path, if any
+            None => match (&self.artifact_store, path.canonicalize()) {
+                (Some(store), Ok(target)) if store.contains(&target) => {
+                    ArtifactStore::verify(&target)?
+                }
+                _ => true,
+            },
+        };
+        Ok((!intact).then_some(Damage::Corrupted))
+    }
+
+    /// Run [`verify`](Self::verify) on a background thread when the last
+    /// pass is more than `interval` old, as recorded in the cache directory.
+    /// Damaged entries are reported on stderr.
+    pub fn verify_if_due(
+        &self,
+        interval: std::time::Duration,
+    ) -> Option<std::thread::JoinHandle<()>> {
+        let marker = self.cache_dir.join(VERIFY_MARKER);
+        let due = fs::metadata(&marker)
+            .and_then(|m| m.modified())
+            .map_or(true, |at| at.elapsed().map_or(true, |age| age >= interval));
+        if !due {
+            return None;
+        }
+        // Written up front, so concurrent builds do not all start a pass
+        if let Err(e) = fs::write(&marker, chrono::Utc::now().to_rfc3339()) {
+            eprintln!("⚠️  Cannot record cache verification: {}", e);
+            return None;
+        }
+        let cache = self.clone();
+        Some(std::thread::spawn(move || match cache.verify() {
+            Ok(report) => {
+                for (key, damage) in &report.removed {
+                    eprintln!("⚠️  Removed damaged cache entry {}: {}", key, damage);
+                }
+            }
+            Err(e) => eprintln!("⚠️  Cache verification failed: {}", e),
+        }))
+    }
+}
+
+/// An artifact being written to a [`LocalCache`]; see [`LocalCache::writer`].
+pub struct ArtifactWriter<'a> {
+    cache: &'a LocalCache,
+    key: String,
+    name: String,
+    sink: Sink,
+    hasher: blake3::Hasher,
+    size: u64,
+}
+
+enum Sink {
+    File(AtomicFile),
+    /// In store mode
+    Buffer(Vec<u8>),
+}
+
+impl Write for ArtifactWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = match self.sink {
+            Sink::File(ref mut file) => file.write(buf)?,
+            Sink::Buffer(ref mut data) => data.write(buf)?,
+        };
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.sink {
+            Sink::File(ref mut file) => file.flush(),
+            Sink::Buffer(_) => Ok(()),
+        }
+    }
+}
+
+impl ArtifactWriter<'_> {
+    /// Move the artifact into place and record its entry. Returns its size.
+    pub fn commit(self) -> Result<u64> {
+        let cache = self.cache;
+        let artifact_filename = format!("{}.bin", self.key);
+        let full_path = cache.cache_dir.join(&artifact_filename);
+
+        // Never write through a link into the store
+        if fs::symlink_metadata(&full_path).is_ok_and(|m| m.file_type().is_symlink()) {
+            fs::remove_file(&full_path)?;
+        }
+
+        let artifact_path = match (self.sink, &cache.artifact_store) {
+            (Sink::Buffer(data), Some(artifact_store)) => {
+                let target = artifact_store.add(&data, &self.name)?;
+                if full_path.exists() {
+                    fs::remove_file(&full_path)?;
+                }
+                link_to_store(&target, &full_path, PathBuf::from(&artifact_filename))?
+            }
+            (Sink::File(file), _) => {
+                file.commit()?;
+                PathBuf::from(&artifact_filename)
+            }
+            (Sink::Buffer(data), None) => {
+                crate::storage::local::write_atomic(&full_path, &data)?;
+                PathBuf::from(&artifact_filename)
+            }
+        };
+
+        let digest = self.hasher.finalize().to_hex().to_string();
+        cache.record_entry(&self.key, artifact_path, self.size, digest)?;
+        Ok(self.size)
+    }
+}
+
+/// File in the cache directory whose mtime is the last verification pass
+const VERIFY_MARKER: &str = "last-verify";
+
+/// Interval between background verification passes, from
+/// `MEMOBUILD_VERIFY_INTERVAL_HOURS`. `None` (the default) turns them off.
+pub fn verify_interval_from_env() -> Option<std::time::Duration> {
+    std::env::var("MEMOBUILD_VERIFY_INTERVAL_HOURS")
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|&hours| hours > 0)
+        .map(|hours| std::time::Duration::from_secs(hours * 3600))
+}
+
+/// Point `link` at the store path `target`; returns the path the cache entry
+/// records.
+#[cfg(unix)]
+fn link_to_store(target: &Path, link: &Path, entry_path: PathBuf) -> Result<PathBuf> {
+    std::os::unix::fs::symlink(target, link)?;
+    Ok(entry_path)
+}
+
+/// Without symlinks the entry records the store path itself.
+#[cfg(not(unix))]
+fn link_to_store(target: &Path, _link: &Path, _entry_path: PathBuf) -> Result<PathBuf> {
+    Ok(target.to_path_buf())
+}
@@ -0,0 +1,362 @@
+//! Remote cache mirrors from `MEMOBUILD_REMOTE_MIRRORS`, written to in the
+//! background and read from when the primary remote fails.
+
+use crate::cache::integrity::{layer_matches, RejectedArtifact};
+use crate::cache::remote::{RemoteCache, RemoteReader};
+use crate::dashboard::BuildEvent;
+use crate::graph::BuildGraph;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+
+/// Writes queued per mirror before writers wait for the mirror to catch up
+const MIRROR_QUEUE_LEN: usize = 64;
+
+/// How the mirrors have been doing during a build.
+#[derive(Debug, Default)]
+pub struct MirrorStatus {
+    /// Writes queued and not yet done
+    pending: AtomicU64,
+    mirrored: AtomicU64,
+    failed: AtomicU64,
+    /// Reads the primary failed and a mirror answered
+    failovers: AtomicU64,
+    last_error: Mutex<Option<String>>,
+    idle: Notify,
+}
+
+impl MirrorStatus {
+    /// Wait until every queued write is done, or `timeout` passes. Returns
+    /// whether the queues drained.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, async {
+            loop {
+                let idle = self.idle.notified();
+                if self.pending.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    pub fn pending(&self) -> u64 {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// One-line note for the end of the build, if anything was mirrored or
+    /// failed over.
+    pub fn summary(&self) -> Option<String> {
+        let mirrored = self.mirrored.load(Ordering::SeqCst);
+        let failed = self.failed.load(Ordering::SeqCst);
+        let failovers = self.failovers.load(Ordering::SeqCst);
+        if mirrored + failed + failovers == 0 {
+            return None;
+        }
+        let mut note = format!("Mirrored {} write(s)", mirrored);
+        if failovers > 0 {
+            note.push_str(&format!(", {} read(s) served by a mirror", failovers));
+        }
+        if failed > 0 {
+            note.push_str(&format!(", {} mirror write(s) failed", failed));
+            if let Some(ref err) = *self.last_error.lock().unwrap() {
+                note.push_str(&format!(" (last error: {})", err));
+            }
+        }
+        Some(note)
+    }
+
+    fn finish(&self, result: Result<()>) {
+        match result {
+            Ok(()) => self.mirrored.fetch_add(1, Ordering::SeqCst),
+            Err(e) => {
+                *self.last_error.lock().unwrap() = Some(e.to_string());
+                self.failed.fetch_add(1, Ordering::SeqCst)
+            }
+        };
+        if self.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.idle.notify_waiters();
+        }
+    }
+}
+
+/// A write to copy to a mirror.
+enum MirrorOp {
+    Put(String, Vec<u8>),
+    PutLayer(String, Vec<u8>),
+    RegisterLayers(String, Vec<String>, u64),
+}
+
+struct Mirror {
+    remote: Arc<dyn RemoteCache>,
+    queue: mpsc::Sender<MirrorOp>,
+}
+
+/// A primary remote whose writes are copied to mirrors in the background,
+/// and whose failed reads are retried on them.
+pub struct MirroredRemoteCache {
+    primary: Arc<dyn RemoteCache>,
+    mirrors: Vec<Mirror>,
+    status: Arc<MirrorStatus>,
+}
+
+impl MirroredRemoteCache {
+    /// Start a background uploader for each mirror; needs a Tokio runtime.
+    pub fn new(primary: Arc<dyn RemoteCache>, mirrors: Vec<Arc<dyn RemoteCache>>) -> Self {
+        let status = Arc::new(MirrorStatus::default());
+        let mirrors = mirrors
+            .into_iter()
+            .map(|remote| {
+                let (queue, mut ops) = mpsc::channel(MIRROR_QUEUE_LEN);
+                let (worker, status) = (remote.clone(), status.clone());
+                tokio::spawn(async move {
+                    while let Some(op) = ops.recv().await {
+                        let result = match op {
+                            MirrorOp::Put(hash, data) => worker.put(&hash, &data).await,
+                            MirrorOp::PutLayer(hash, data) => worker.put_layer(&hash, &data).await,
+                            MirrorOp::RegisterLayers(hash, layers, total_size) => {
+                                worker
+                                    .register_node_layers(&hash, &layers, total_size)
+                                    .await
+                            }
+                        };
+                        status.finish(result);
+                    }
+                });
+                Mirror { remote, queue }
+            })
+            .collect();
+        Self {
+            primary,
+            mirrors,
+            status,
+        }
+    }
+
+    /// Mirrors from `MEMOBUILD_REMOTE_MIRRORS`, a comma-separated list of
+    /// remote URLs in the forms `MEMOBUILD_REMOTE_URL` takes.
+    pub fn mirrors_from_env() -> Result<Vec<Arc<dyn RemoteCache>>> {
+        let Ok(urls) = std::env::var("MEMOBUILD_REMOTE_MIRRORS") else {
+            return Ok(Vec::new());
+        };
+        urls.split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(crate::cache::remote_from_url)
+            .collect()
+    }
+
+    pub fn status(&self) -> Arc<MirrorStatus> {
+        self.status.clone()
+    }
+
+    /// Queue a copy of a write for every mirror. Waits only when a mirror's
+    /// queue is full.
+    async fn mirror(&self, op: impl Fn() -> MirrorOp) {
+        for mirror in &self.mirrors {
+            self.status.pending.fetch_add(1, Ordering::SeqCst);
+            if mirror.queue.send(op()).await.is_err() {
+                self.status
+                    .finish(Err(anyhow::anyhow!("mirror uploader stopped")));
+            }
+        }
+    }
+
+    /// Run `op` on the primary, then on each mirror in turn while it fails.
+    /// The primary's error is returned when every one fails.
+    async fn read<T, Fut>(&self, op: impl Fn(Arc<dyn RemoteCache>) -> Fut) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        let err = match op(self.primary.clone()).await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        for mirror in &self.mirrors {
+            if let Ok(value) = op(mirror.remote.clone()).await {
+                self.status.failovers.fetch_add(1, Ordering::SeqCst);
+                return Ok(value);
+            }
+        }
+        Err(err)
+    }
+}
+
+#[async_trait]
+impl RemoteCache for MirroredRemoteCache {
+    async fn has(&self, hash: &str) -> Result<bool> {
+        self.read(|r| async move { r.has(hash).await }).await
+    }
+
+    async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        self.read(|r| async move { r.get(hash).await }).await
+    }
+
+    async fn get_reader(&self, hash: &str) -> Result<Option<RemoteReader>> {
+        self.read(|r| async move { r.get_reader(hash).await }).await
+    }
+
+    async fn put(&self, hash: &str, data: &[u8]) -> Result<()> {
+        self.mirror(|| MirrorOp::Put(hash.to_string(), data.to_vec()))
+            .await;
+        self.primary.put(hash, data).await
+    }
+
+    async fn has_layer(&self, hash: &str) -> Result<bool> {
+        self.read(|r| async move { r.has_layer(hash).await }).await
+    }
+
+    async fn get_layer(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        self.read(|r| async move { r.get_layer(hash).await }).await
+    }
+
+    async fn put_layer(&self, hash: &str, data: &[u8]) -> Result<()> {
+        self.mirror(|| MirrorOp::PutLayer(hash.to_string(), data.to_vec()))
+            .await;
+        self.primary.put_layer(hash, data).await
+    }
+
+    async fn get_node_layers(&self, hash: &str) -> Result<Option<Vec<String>>> {
+        self.read(|r| async move { r.get_node_layers(hash).await })
+            .await
+    }
+
+    async fn register_node_layers(
+        &self,
+        hash: &str,
+        layers: &[String],
+        total_size: u64,
+    ) -> Result<()> {
+        self.mirror(|| MirrorOp::RegisterLayers(hash.to_string(), layers.to_vec(), total_size))
+            .await;
+        self.primary
+            .register_node_layers(hash, layers, total_size)
+            .await
+    }
+
+    async fn invalidate(&self, hash: &str) -> Result<()> {
+        // Not queued: a mirror that kept the entry would serve it on failover
+        self.primary.invalidate(hash).await?;
+        for mirror in &self.mirrors {
+            mirror.remote.invalidate(hash).await?;
+        }
+        Ok(())
+    }
+
+    async fn prefetch_hints(&self, hash: &str) -> Result<Vec<String>> {
+        self.primary.prefetch_hints(hash).await
+    }
+
+    async fn report_corrupt_layer(&self, rejected: &RejectedArtifact) -> Result<bool> {
+        self.primary.report_corrupt_layer(rejected).await
+    }
+
+    /// The first mirror's copy that checks out, in the order they are listed.
+    async fn get_layer_from_alternates(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        for mirror in &self.mirrors {
+            match mirror.remote.get_layer(hash).await {
+                Ok(Some(layer)) if layer_matches(hash, &layer) => {
+                    self.status.failovers.fetch_add(1, Ordering::SeqCst);
+                    return Ok(Some(layer));
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    async fn report_build_event(&self, event: BuildEvent) -> Result<()> {
+        self.primary.report_build_event(event).await
+    }
+
+    async fn report_dag(&self, dag: &BuildGraph) -> Result<()> {
+        self.primary.report_dag(dag).await
+    }
+
+    async fn report_analytics(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()> {
+        self.primary
+            .report_analytics(dirty, cached, duration_ms)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::FsRemoteCache;
+
+    #[tokio::test]
+    async fn test_writes_are_mirrored_and_reads_fail_over() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let primary: Arc<dyn RemoteCache> =
+            Arc::new(FsRemoteCache::new(&dir.path().join("primary")).unwrap());
+        let durable: Arc<dyn RemoteCache> =
+            Arc::new(FsRemoteCache::new(&dir.path().join("durable")).unwrap());
+        let cache = MirroredRemoteCache::new(primary.clone(), vec![durable.clone()]);
+
+        cache.put("abc", b"artifact").await.unwrap();
+        assert!(cache.status().flush(Duration::from_secs(5)).await);
+        assert_eq!(durable.get("abc").await.unwrap().unwrap(), b"artifact");
+        assert_eq!(cache.status().pending(), 0);
+
+        // Nothing listens on the discard port
+        let down: Arc<dyn RemoteCache> = Arc::new(crate::cache::HttpRemoteCache::new(
+            "http://127.0.0.1:9".to_string(),
+        ));
+        let cache = MirroredRemoteCache::new(down, vec![durable]);
+        assert_eq!(cache.get("abc").await.unwrap().unwrap(), b"artifact");
+        assert!(cache.status().summary().unwrap().contains("1 read(s)"));
+    }
+
+    #[tokio::test]
+    async fn test_bad_layer_recovered_from_mirror() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let primary: Arc<dyn RemoteCache> =
+            Arc::new(FsRemoteCache::new(&dir.path().join("primary")).unwrap());
+        let durable: Arc<dyn RemoteCache> =
+            Arc::new(FsRemoteCache::new(&dir.path().join("durable")).unwrap());
+        let good = b"layer".to_vec();
+        let hash = blake3::hash(&good).to_hex().to_string();
+        let lost = blake3::hash(b"lost").to_hex().to_string();
+        primary.put_layer(&hash, b"bit rot").await.unwrap();
+        primary
+            .register_node_layers("node", std::slice::from_ref(&hash), 5)
+            .await
+            .unwrap();
+        primary
+            .register_node_layers("gone", &[lost], 4)
+            .await
+            .unwrap();
+        durable.put_layer(&hash, &good).await.unwrap();
+
+        let remote = MirroredRemoteCache::new(primary, vec![durable]);
+        let status = remote.status();
+        let cache = crate::cache::HybridCache {
+            local: crate::cache::LocalCache::in_dir(dir.path().join("local")).unwrap(),
+            remote: Some(Arc::new(remote) as Arc<dyn RemoteCache>),
+            remote_health: None,
+            mirrors: Some(status),
+            integrity: Default::default(),
+            uploads: Default::default(),
+            dedup_uploads: true,
+            prefetch_hints: false,
+            hinted: Default::default(),
+            max_artifact_bytes: None,
+        };
+
+        let (data, _) = cache.lookup_artifact("node").await.unwrap().unwrap();
+        assert_eq!(data, crate::cache::utils::merge_artifact(vec![good]));
+        assert_eq!(cache.integrity.recovered().len(), 1);
+        assert!(cache.integrity.rejected().is_empty());
+
+        // No source has it: the node is rebuilt
+        assert!(cache.lookup_artifact("gone").await.unwrap().is_none());
+        assert_eq!(cache.integrity.rejected()[0].actual, "missing");
+    }
+}
@@ -0,0 +1,112 @@
+use crate::cache::remote::RemoteCache;
+use crate::dashboard::BuildEvent;
+use crate::graph::BuildGraph;
+use crate::storage::ArtifactStorage;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// A `RemoteCache` that talks straight to an object store (S3, GCS, Azure)
+/// without a MemoBuild server in between.
+///
+/// Artifacts and layers share the bucket, keyed by hash. A node's layer list
+/// is stored as a small JSON object next to them. Build events and analytics
+/// have nowhere to go and are dropped.
+pub struct StorageRemoteCache {
+    storage: Arc<dyn ArtifactStorage>,
+}
+
+impl StorageRemoteCache {
+    pub fn new(storage: Arc<dyn ArtifactStorage>) -> Self {
+        Self { storage }
+    }
+
+    fn layers_key(hash: &str) -> String {
+        format!("{}.layers", hash)
+    }
+}
+
+#[async_trait]
+impl RemoteCache for StorageRemoteCache {
+    async fn has(&self, hash: &str) -> Result<bool> {
+        self.storage.exists(hash)
+    }
+
+    async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        self.storage.get(hash)
+    }
+
+    async fn put(&self, hash: &str, data: &[u8]) -> Result<()> {
+        self.storage.put(hash, data).map(|_| ())
+    }
+
+    async fn has_layer(&self, hash: &str) -> Result<bool> {
+        self.storage.exists(hash)
+    }
+
+    async fn get_layer(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        self.storage.get(hash)
+    }
+
+    async fn put_layer(&self, hash: &str, data: &[u8]) -> Result<()> {
+        self.storage.put(hash, data).map(|_| ())
+    }
+
+    async fn get_node_layers(&self, hash: &str) -> Result<Option<Vec<String>>> {
+        match self.storage.get(&Self::layers_key(hash))? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn register_node_layers(
+        &self,
+        hash: &str,
+        layers: &[String],
+        _total_size: u64,
+    ) -> Result<()> {
+        let data = serde_json::to_vec(layers)?;
+        self.storage.put(&Self::layers_key(hash), &data).map(|_| ())
+    }
+
+    async fn invalidate(&self, hash: &str) -> Result<()> {
+        self.storage.delete(&Self::layers_key(hash))?;
+        self.storage.delete(hash)
+    }
+
+    async fn report_build_event(&self, _event: BuildEvent) -> Result<()> {
+        Ok(())
+    }
+
+    async fn report_dag(&self, _dag: &BuildGraph) -> Result<()> {
+        Ok(())
+    }
+
+    async fn report_analytics(&self, _dirty: u32, _cached: u32, _duration_ms: u64) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_layers_roundtrip() {
+        let dir = TempDir::new().unwrap();
+        let cache = StorageRemoteCache::new(Arc::new(LocalStorage::new(dir.path()).unwrap()));
+
+        cache.put_layer("aaaa1111", b"layer").await.unwrap();
+        assert!(cache.has_layer("aaaa1111").await.unwrap());
+
+        let layers = vec!["aaaa1111".to_string()];
+        cache
+            .register_node_layers("node1", &layers, 5)
+            .await
+            .unwrap();
+        assert_eq!(cache.get_node_layers("node1").await.unwrap(), Some(layers));
+        assert_eq!(cache.get_node_layers("node2").await.unwrap(), None);
+    }
+}
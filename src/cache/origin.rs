@@ -0,0 +1,143 @@
+//! Where cache entries come from, as clients report it to the remote cache.
+
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// Longest value kept for any field; longer ones are cut.
+const MAX_FIELD_LEN: usize = 256;
+
+/// Who produced a cache entry. Every field is optional: older clients and
+/// builds outside CI leave some out.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryOrigin {
+    /// Host name, or `MEMOBUILD_BUILDER`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub builder: Option<String>,
+    /// CI job URL or id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ci_job: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    /// MemoBuild version of the client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// RFC 3339 time the build started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub built_at: Option<String>,
+}
+
+impl EntryOrigin {
+    /// The origin of builds run by this process, from the environment CI
+    /// systems set and, for the commit, `git` in the working directory.
+    /// Detected once; `built_at` is the time of the first call.
+    pub fn detect() -> Self {
+        static DETECTED: OnceLock<EntryOrigin> = OnceLock::new();
+        DETECTED.get_or_init(Self::from_environment).clone()
+    }
+
+    fn from_environment() -> Self {
+        Self {
+            builder: env("MEMOBUILD_BUILDER")
+                .or_else(|| env("HOSTNAME"))
+                .or_else(|| {
+                    std::fs::read_to_string("/etc/hostname")
+                        .ok()
+                        .map(|h| h.trim().to_string())
+                        .filter(|h| !h.is_empty())
+                }),
+            ci_job: ci_job(),
+            git_commit: env("GITHUB_SHA")
+                .or_else(|| env("CI_COMMIT_SHA"))
+                .or_else(|| env("GIT_COMMIT"))
+                .or_else(head_commit),
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            built_at: Some(chrono::Utc::now().to_rfc3339()),
+        }
+        .sanitized()
+    }
+
+    /// Parse the origin a client sent in
+    /// [`ORIGIN_HEADER`](crate::constants::ORIGIN_HEADER).
+    pub fn from_header(value: &str) -> Option<Self> {
+        serde_json::from_str::<Self>(value)
+            .ok()
+            .map(Self::sanitized)
+            .filter(|origin| !origin.is_empty())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn sanitized(self) -> Self {
+        let clean = |field: Option<String>| {
+            field
+                .map(|v| {
+                    v.chars()
+                        .filter(|c| !c.is_control())
+                        .take(MAX_FIELD_LEN)
+                        .collect()
+                })
+                .filter(|v: &String| !v.is_empty())
+        };
+        Self {
+            builder: clean(self.builder),
+            ci_job: clean(self.ci_job),
+            git_commit: clean(self.git_commit),
+            version: clean(self.version),
+            built_at: clean(self.built_at),
+        }
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+/// The CI job the build runs in: GitHub Actions and GitLab CI are detected,
+/// other systems can set `MEMOBUILD_CI_JOB`.
+fn ci_job() -> Option<String> {
+    if let Some(job) = env("MEMOBUILD_CI_JOB") {
+        return Some(job);
+    }
+    if let (Some(server), Some(repo), Some(run)) = (
+        env("GITHUB_SERVER_URL"),
+        env("GITHUB_REPOSITORY"),
+        env("GITHUB_RUN_ID"),
+    ) {
+        return Some(format!("{}/{}/actions/runs/{}", server, repo, run));
+    }
+    env("CI_JOB_URL").or_else(|| env("BUILD_URL"))
+}
+
+fn head_commit() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string()).filter(|c| !c.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_is_sanitized() {
+        let origin = EntryOrigin::from_header(&format!(
+            r#"{{"builder": "ci-7\n", "git_commit": "{}", "ci_job": ""}}"#,
+            "a".repeat(1000)
+        ))
+        .unwrap();
+        assert_eq!(origin.builder.as_deref(), Some("ci-7"));
+        assert_eq!(origin.git_commit.unwrap().len(), MAX_FIELD_LEN);
+        assert!(origin.ci_job.is_none());
+
+        assert!(EntryOrigin::from_header("{}").is_none());
+        assert!(EntryOrigin::from_header("not json").is_none());
+    }
+}
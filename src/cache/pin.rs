@@ -0,0 +1,48 @@
+//! Pins, labels on cache entries that GC, eviction and key version migration
+//! skip.
+
+use anyhow::Result;
+
+/// Entries a pin or unpin applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PinTarget {
+    /// The entry with this cache key
+    Key(String),
+    /// Every entry stored by the build with this id
+    Build(String),
+}
+
+impl PinTarget {
+    /// The target named by exactly one of `key` and `build_id`.
+    pub fn from_parts(key: Option<String>, build_id: Option<String>) -> Result<Self> {
+        match (key, build_id) {
+            (Some(key), None) => Ok(Self::Key(key)),
+            (None, Some(build_id)) => Ok(Self::Build(build_id)),
+            _ => anyhow::bail!("Name either a cache key or a build id"),
+        }
+    }
+
+    /// Query string naming the target, for the cache server's pin API.
+    pub fn query(&self) -> (&'static str, &str) {
+        match self {
+            Self::Key(key) => ("key", key),
+            Self::Build(id) => ("build_id", id),
+        }
+    }
+}
+
+impl std::fmt::Display for PinTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Key(key) => write!(f, "{}", key),
+            Self::Build(id) => write!(f, "build {}", id),
+        }
+    }
+}
+
+/// Id of the running build, from `MEMOBUILD_BUILD_ID`.
+pub fn build_id_from_env() -> Option<String> {
+    std::env::var("MEMOBUILD_BUILD_ID")
+        .ok()
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+}
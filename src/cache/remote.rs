@@ -1,8 +1,11 @@
+use crate::cache::integrity::RejectedArtifact;
 use crate::dashboard::BuildEvent;
 use crate::graph::BuildGraph;
 use anyhow::Result;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RemoteCacheEntry {
@@ -10,12 +13,43 @@ pub struct RemoteCacheEntry {
     pub size: u64,
 }
 
+/// A remote artifact opened for reading; see [`RemoteCache::get_reader`].
+pub struct RemoteReader {
+    pub reader: Box<dyn Read + Send>,
+    /// Bytes transferred to fetch it, for bandwidth accounting
+    pub transferred: u64,
+}
+
+impl RemoteReader {
+    /// A reader over an artifact already in memory.
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        Self {
+            transferred: data.len() as u64,
+            reader: Box::new(std::io::Cursor::new(data)),
+        }
+    }
+}
+
 #[async_trait]
 pub trait RemoteCache: Send + Sync {
     async fn has(&self, hash: &str) -> Result<bool>;
     async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>>;
     async fn put(&self, hash: &str, data: &[u8]) -> Result<()>;
 
+    /// Like [`get`](Self::get), as a reader. The default holds the whole
+    /// artifact in memory; remotes that can keep it on disk override it.
+    async fn get_reader(&self, hash: &str) -> Result<Option<RemoteReader>> {
+        Ok(self.get(hash).await?.map(RemoteReader::from_bytes))
+    }
+
+    /// Like [`put`](Self::put), from a reader. The default reads the whole
+    /// artifact into memory first.
+    async fn put_from_reader(&self, hash: &str, mut reader: Box<dyn Read + Send>) -> Result<()> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        self.put(hash, &data).await
+    }
+
     // Layered cache methods
     async fn has_layer(&self, hash: &str) -> Result<bool>;
     async fn get_layer(&self, hash: &str) -> Result<Option<Vec<u8>>>;
@@ -28,7 +62,64 @@ pub trait RemoteCache: Send + Sync {
         total_size: u64,
     ) -> Result<()>;
 
+    /// Forget the artifact stored under `hash` so the next build recomputes it.
+    /// Layers it references are left for garbage collection.
+    async fn invalidate(&self, hash: &str) -> Result<()>;
+
+    /// Artifacts other builds fetched together with `hash`, most likely
+    /// needed first. Remotes that do not track this have no hints.
+    async fn prefetch_hints(&self, _hash: &str) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Tell the remote a layer it served was missing or did not match its
+    /// digest. The remote checks its own copy and quarantines it if it is
+    /// bad; returns whether it did. Remotes without quarantine ignore it.
+    async fn report_corrupt_layer(&self, _rejected: &RejectedArtifact) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// A copy of layer `hash` matching its digest from somewhere other than
+    /// where [`get_layer`](Self::get_layer) reads, such as a mirror. Remotes
+    /// with a single source have none.
+    async fn get_layer_from_alternates(&self, _hash: &str) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Whether layers from [`get_layer`](Self::get_layer) were already
+    /// checked against the name they are stored under, which is then not
+    /// their own digest, as with encrypted layers.
+    fn authenticates_layers(&self) -> bool {
+        false
+    }
+
     async fn report_build_event(&self, event: BuildEvent) -> Result<()>;
     async fn report_dag(&self, dag: &BuildGraph) -> Result<()>;
     async fn report_analytics(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()>;
-}
\ No newline at end of file
+}
+
+/// Build a remote cache from a URL: `s3://`, `gs://` and `az://` talk to the
+/// object store directly, `file://` or an absolute path uses a shared
+/// directory, anything else is treated as a MemoBuild server.
+pub fn remote_from_url(url: &str) -> Result<Arc<dyn RemoteCache>> {
+    if ["s3://", "gs://", "az://"]
+        .iter()
+        .any(|s| url.starts_with(s))
+    {
+        let storage = crate::storage::storage_from_uri(url)?;
+        return Ok(Arc::new(
+            crate::cache::object_store::StorageRemoteCache::new(storage.into()),
+        ));
+    }
+    let shared_dir = url
+        .strip_prefix("file://")
+        .or(url.starts_with('/').then_some(url));
+    if let Some(path) = shared_dir {
+        return Ok(Arc::new(crate::cache::FsRemoteCache::new(
+            std::path::Path::new(path),
+        )?));
+    }
+    Ok(Arc::new(crate::cache::HttpRemoteCache::new(
+        url.to_string(),
+    )))
+}
@@ -0,0 +1,199 @@
+//! Read-only artifact store under `MEMOBUILD_STORE_DIR`, named by content
+//! digest, that cache entries link into.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Hex characters of the BLAKE3 digest kept in store paths (128 bits)
+const DIGEST_LEN: usize = 32;
+/// Longest name suffix of a store path
+const MAX_NAME_LEN: usize = 48;
+
+#[derive(Clone)]
+pub struct ArtifactStore {
+    root: PathBuf,
+}
+
+impl ArtifactStore {
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)
+            .with_context(|| format!("Failed to create store {}", root.display()))?;
+        Ok(Self {
+            root: root.canonicalize()?,
+        })
+    }
+
+    /// The store at `MEMOBUILD_STORE_DIR`, if set.
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var("MEMOBUILD_STORE_DIR") {
+            Ok(dir) if !dir.is_empty() => Ok(Some(Self::new(dir)?)),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where `data` lives in the store under `name`.
+    pub fn path_for(&self, data: &[u8], name: &str) -> PathBuf {
+        let digest = blake3::hash(data).to_hex();
+        self.root
+            .join(format!("{}-{}", &digest[..DIGEST_LEN], store_name(name)))
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        path.starts_with(&self.root)
+    }
+
+    /// Add `data` to the store and return its path. Adding the same content
+    /// twice is a no-op; a path whose content no longer matches is replaced.
+    pub fn add(&self, data: &[u8], name: &str) -> Result<PathBuf> {
+        let path = self.path_for(data, name);
+        if path.exists() {
+            if Self::verify(&path)? {
+                return Ok(path);
+            }
+            eprintln!(
+                "⚠️  Store path {} was modified, rewriting it",
+                path.display()
+            );
+            Self::remove(&path)?;
+        }
+
+        // Written under a temporary name and renamed, so a store path is
+        // either absent or complete
+        let tmp = self
+            .root
+            .join(format!(".tmp-{}", uuid::Uuid::new_v4().simple()));
+        fs::write(&tmp, data)?;
+        let mut perms = fs::metadata(&tmp)?.permissions();
+        perms.set_readonly(true);
+        fs::set_permissions(&tmp, perms)?;
+        if let Err(e) = fs::rename(&tmp, &path) {
+            let _ = Self::remove(&tmp);
+            // Another build stored the same content first
+            if !path.exists() {
+                return Err(e.into());
+            }
+        }
+        Ok(path)
+    }
+
+    /// Contents of a store path, or `None` if it is missing or no longer
+    /// matches its digest.
+    pub fn read(path: &Path) -> Result<Option<Vec<u8>>> {
+        let Some(expected) = path_digest(path) else {
+            anyhow::bail!("{} is not a store path", path.display());
+        };
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if &blake3::hash(&data).to_hex()[..DIGEST_LEN] != expected {
+            eprintln!(
+                "⚠️  Store path {} was modified in place, ignoring it",
+                path.display()
+            );
+            return Ok(None);
+        }
+        Ok(Some(data))
+    }
+
+    /// Whether the content at `path` still matches its digest.
+    pub fn verify(path: &Path) -> Result<bool> {
+        let Some(expected) = path_digest(path) else {
+            return Ok(false);
+        };
+        let data = fs::read(path)?;
+        Ok(&blake3::hash(&data).to_hex()[..DIGEST_LEN] == expected)
+    }
+
+    /// Delete a store path, clearing the read-only bit first (needed on Windows).
+    pub fn remove(path: &Path) -> Result<()> {
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            let mut perms = meta.permissions();
+            #[allow(clippy::permissions_set_readonly_false)]
+            perms.set_readonly(false);
+            let _ = fs::set_permissions(path, perms);
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Digest part of a store path's file name.
+fn path_digest(path: &Path) -> Option<&str> {
+    let name = path.file_name()?.to_str()?;
+    let (digest, _) = name.split_once('-')?;
+    (digest.len() == DIGEST_LEN).then_some(digest)
+}
+
+/// `RUN npm ci --omit=dev` → `run-npm-ci-omit-dev`
+pub fn store_name(name: &str) -> String {
+    let mut out = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+            out.push(c.to_ascii_lowercase());
+        } else if !out.is_empty() && !out.ends_with('-') {
+            out.push('-');
+        }
+        if out.len() >= MAX_NAME_LEN {
+            break;
+        }
+    }
+    let out = out.trim_end_matches('-');
+    if out.is_empty() {
+        "artifact".to_string()
+    } else {
+        out.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::LocalCache;
+
+    #[test]
+    fn test_store_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ArtifactStore::new(dir.path().join("store")).unwrap();
+
+        let path = store.add(b"output", "RUN npm ci --omit=dev").unwrap();
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        assert!(name.ends_with("-run-npm-ci-omit-dev"));
+        assert!(fs::metadata(&path).unwrap().permissions().readonly());
+        assert_eq!(store.add(b"output", "RUN npm ci --omit=dev").unwrap(), path);
+
+        let cache = LocalCache::in_dir(dir.path().join("cache"))
+            .unwrap()
+            .with_artifact_store(store);
+        cache
+            .put_named("key1", "RUN npm ci --omit=dev", b"output")
+            .unwrap();
+        assert_eq!(cache.get_data("key1").unwrap().unwrap(), b"output");
+        #[cfg(unix)]
+        assert_eq!(
+            fs::read_link(dir.path().join("cache/key1.bin")).unwrap(),
+            path
+        );
+
+        // Mutating the stored output in place turns the entry into a miss
+        let mut perms = fs::metadata(&path).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        perms.set_readonly(false);
+        fs::set_permissions(&path, perms).unwrap();
+        fs::write(&path, b"tampered").unwrap();
+        assert!(cache.get_data("key1").unwrap().is_none());
+
+        // Storing it again repairs the path
+        cache
+            .put_named("key1", "RUN npm ci --omit=dev", b"output")
+            .unwrap();
+        assert_eq!(cache.get_data("key1").unwrap().unwrap(), b"output");
+    }
+}
@@ -0,0 +1,256 @@
+use crate::cache::integrity::RejectedArtifact;
+use crate::cache::remote::{RemoteCache, RemoteReader};
+use crate::dashboard::BuildEvent;
+use crate::graph::BuildGraph;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Semaphore};
+
+/// Aggregate byte-rate limit shared by all transfers.
+///
+/// Transfers may overdraw the bucket; the debt is paid by sleeping, so the
+/// long-run average stays at `rate` even for artifacts larger than a second's
+/// worth of tokens.
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// `rate` in bytes per second, with up to one second of burst.
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        Self {
+            rate,
+            capacity: rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Take `bytes` tokens, sleeping as long as needed to stay under the rate.
+    pub async fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().await;
+            let (ref mut tokens, ref mut last) = *state;
+            let now = Instant::now();
+            *tokens =
+                (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.capacity);
+            *last = now;
+            *tokens -= bytes as f64;
+            if *tokens < 0.0 {
+                Duration::from_secs_f64(-*tokens / self.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Parse a rate such as `500K`, `10MB`, `1.5MiB/s` or a plain byte count.
+/// Suffixes are binary (K = 1024).
+pub fn parse_rate(s: &str) -> Result<u64> {
+    let s = s.trim().trim_end_matches("/s").trim();
+    let lower = s.to_ascii_lowercase();
+    let digits_end = lower
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(digits_end);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid bandwidth limit '{}'", s))?;
+    let multiplier = match unit.trim().trim_end_matches("ib").trim_end_matches('b') {
+        "" => 1.0,
+        "k" => 1024.0,
+        "m" => 1024.0 * 1024.0,
+        "g" => 1024.0 * 1024.0 * 1024.0,
+        _ => anyhow::bail!("Invalid bandwidth unit in '{}'", s),
+    };
+    Ok((number * multiplier) as u64)
+}
+
+/// Transfers allowed in flight at once, from `MEMOBUILD_MAX_TRANSFERS`.
+/// Batched downloads keep this many requests going.
+pub fn max_transfers_from_env() -> Result<usize> {
+    match std::env::var("MEMOBUILD_MAX_TRANSFERS") {
+        Ok(v) => v
+            .parse::<usize>()
+            .map(|n| n.max(1))
+            .map_err(|_| anyhow::anyhow!("Invalid MEMOBUILD_MAX_TRANSFERS '{}'", v)),
+        Err(_) => Ok(crate::constants::DEFAULT_MAX_TRANSFERS),
+    }
+}
+
+/// Limits how many transfers run at once and how fast they go in aggregate,
+/// so a cold cache pulling hundreds of artifacts does not saturate the uplink.
+/// Metadata calls (`has`, layer lists, reports) are not limited.
+pub struct ThrottledRemoteCache {
+    inner: Arc<dyn RemoteCache>,
+    transfers: Semaphore,
+    bandwidth: Option<TokenBucket>,
+}
+
+impl ThrottledRemoteCache {
+    pub fn new(
+        inner: Arc<dyn RemoteCache>,
+        max_transfers: usize,
+        bytes_per_sec: Option<u64>,
+    ) -> Self {
+        Self {
+            inner,
+            transfers: Semaphore::new(max_transfers.max(1)),
+            bandwidth: bytes_per_sec.map(TokenBucket::new),
+        }
+    }
+
+    /// Settings from `MEMOBUILD_MAX_TRANSFERS` and `MEMOBUILD_BANDWIDTH_LIMIT`.
+    pub fn from_env(inner: Arc<dyn RemoteCache>) -> Result<Self> {
+        let max_transfers = max_transfers_from_env()?;
+        let bandwidth = match std::env::var("MEMOBUILD_BANDWIDTH_LIMIT") {
+            Ok(v) if !v.trim().is_empty() => Some(parse_rate(&v)?),
+            _ => None,
+        };
+        Ok(Self::new(inner, max_transfers, bandwidth))
+    }
+
+    async fn pay(&self, bytes: usize) {
+        if let Some(ref bucket) = self.bandwidth {
+            bucket.consume(bytes as u64).await;
+        }
+    }
+
+    async fn download(
+        &self,
+        fetch: impl std::future::Future<Output = Result<Option<Vec<u8>>>>,
+    ) -> Result<Option<Vec<u8>>> {
+        let _permit = self.transfers.acquire().await?;
+        let data = fetch.await?;
+        // Size is only known afterwards; paying the debt here holds back the next transfer
+        if let Some(ref data) = data {
+            self.pay(data.len()).await;
+        }
+        Ok(data)
+    }
+}
+
+#[async_trait]
+impl RemoteCache for ThrottledRemoteCache {
+    async fn has(&self, hash: &str) -> Result<bool> {
+        self.inner.has(hash).await
+    }
+
+    async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        self.download(self.inner.get(hash)).await
+    }
+
+    async fn get_reader(&self, hash: &str) -> Result<Option<RemoteReader>> {
+        let _permit = self.transfers.acquire().await?;
+        let reader = self.inner.get_reader(hash).await?;
+        if let Some(ref reader) = reader {
+            self.pay(reader.transferred as usize).await;
+        }
+        Ok(reader)
+    }
+
+    async fn put(&self, hash: &str, data: &[u8]) -> Result<()> {
+        let _permit = self.transfers.acquire().await?;
+        self.pay(data.len()).await;
+        self.inner.put(hash, data).await
+    }
+
+    async fn has_layer(&self, hash: &str) -> Result<bool> {
+        self.inner.has_layer(hash).await
+    }
+
+    async fn get_layer(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        self.download(self.inner.get_layer(hash)).await
+    }
+
+    async fn put_layer(&self, hash: &str, data: &[u8]) -> Result<()> {
+        let _permit = self.transfers.acquire().await?;
+        self.pay(data.len()).await;
+        self.inner.put_layer(hash, data).await
+    }
+
+    async fn get_node_layers(&self, hash: &str) -> Result<Option<Vec<String>>> {
+        self.inner.get_node_layers(hash).await
+    }
+
+    async fn register_node_layers(
+        &self,
+        hash: &str,
+        layers: &[String],
+        total_size: u64,
+    ) -> Result<()> {
+        self.inner
+            .register_node_layers(hash, layers, total_size)
+            .await
+    }
+
+    async fn invalidate(&self, hash: &str) -> Result<()> {
+        self.inner.invalidate(hash).await
+    }
+
+    async fn prefetch_hints(&self, hash: &str) -> Result<Vec<String>> {
+        self.inner.prefetch_hints(hash).await
+    }
+
+    async fn report_corrupt_layer(&self, rejected: &RejectedArtifact) -> Result<bool> {
+        self.inner.report_corrupt_layer(rejected).await
+    }
+
+    async fn get_layer_from_alternates(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        self.download(self.inner.get_layer_from_alternates(hash))
+            .await
+    }
+
+    fn authenticates_layers(&self) -> bool {
+        self.inner.authenticates_layers()
+    }
+
+    async fn report_build_event(&self, event: BuildEvent) -> Result<()> {
+        self.inner.report_build_event(event).await
+    }
+
+    async fn report_dag(&self, dag: &BuildGraph) -> Result<()> {
+        self.inner.report_dag(dag).await
+    }
+
+    async fn report_analytics(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()> {
+        self.inner
+            .report_analytics(dirty, cached, duration_ms)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("2048").unwrap(), 2048);
+        assert_eq!(parse_rate("500K").unwrap(), 500 * 1024);
+        assert_eq!(parse_rate("10MB/s").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_rate("1.5mib").unwrap(), 3 * 512 * 1024);
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("10x").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bucket_enforces_rate() {
+        let bucket = TokenBucket::new(10_000);
+        let start = Instant::now();
+        // One second of burst is free, the next 5000 bytes cost half a second
+        bucket.consume(10_000).await;
+        bucket.consume(5_000).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,66 @@
+//! Skipping uploads of layers the remote already has, unless
+//! `MEMOBUILD_UPLOAD_DEDUP=0`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Whether `MEMOBUILD_UPLOAD_DEDUP` leaves the check on (the default).
+pub fn enabled_from_env() -> bool {
+    !matches!(
+        std::env::var("MEMOBUILD_UPLOAD_DEDUP").as_deref(),
+        Ok("0") | Ok("false")
+    )
+}
+
+/// Layers uploaded and skipped during one process.
+#[derive(Debug, Default)]
+pub struct UploadStats {
+    uploaded: AtomicU64,
+    uploaded_bytes: AtomicU64,
+    skipped: AtomicU64,
+    skipped_bytes: AtomicU64,
+}
+
+impl UploadStats {
+    pub fn record_upload(&self, bytes: usize) {
+        self.uploaded.fetch_add(1, Ordering::Relaxed);
+        self.uploaded_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_skip(&self, bytes: usize) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+        self.skipped_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn uploaded(&self) -> (u64, u64) {
+        (
+            self.uploaded.load(Ordering::Relaxed),
+            self.uploaded_bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Layers the remote already had, and their size.
+    pub fn skipped(&self) -> (u64, u64) {
+        (
+            self.skipped.load(Ordering::Relaxed),
+            self.skipped_bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    /// One-line note for the end of the build, if any upload was skipped.
+    pub fn summary(&self) -> Option<String> {
+        let (skipped, skipped_bytes) = self.skipped();
+        if skipped == 0 {
+            return None;
+        }
+        let (uploaded, uploaded_bytes) = self.uploaded();
+        Some(format!(
+            "Skipped {} upload(s) already in the remote cache ({:.1} MB saved, {} uploaded, {:.1} MB)",
+            skipped,
+            skipped_bytes as f64 / 1_048_576.0,
+            uploaded,
+            uploaded_bytes as f64 / 1_048_576.0
+        ))
+    }
+}
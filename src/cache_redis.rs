@@ -28,7 +28,8 @@ impl RedisCache {
         let config = RedisConfig::from_url(url)?;
         let client = RedisClient::new(config, None, None);
 
-        let _ = client.connect();
+        // The connection task runs in the background; readiness is awaited below
+        drop(client.connect());
         client.wait_for_connect().await?;
 
         tracing::info!("Redis cache connected to {}", url);
@@ -63,13 +64,13 @@ impl RedisCache {
 #[async_trait]
 impl RemoteCache for RedisCache {
     async fn has(&self, hash: &str) -> Result<bool> {
-        let exists: bool = self.client.exists(&self.blob_key(hash)).await?;
+        let exists: bool = self.client.exists(self.blob_key(hash)).await?;
         Ok(exists)
     }
 
     async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
         let data: Option<fred::types::RedisValue> =
-            self.client.get(&self.blob_key(hash)).await?;
+            self.client.get(self.blob_key(hash)).await?;
         match data {
             Some(val) => Ok(Some(val.convert::<Vec<u8>>()?)),
             None => Ok(None),
@@ -90,13 +91,13 @@ impl RemoteCache for RedisCache {
     }
 
     async fn has_layer(&self, hash: &str) -> Result<bool> {
-        let exists: bool = self.client.exists(&self.layer_key(hash)).await?;
+        let exists: bool = self.client.exists(self.layer_key(hash)).await?;
         Ok(exists)
     }
 
     async fn get_layer(&self, hash: &str) -> Result<Option<Vec<u8>>> {
         let data: Option<fred::types::RedisValue> =
-            self.client.get(&self.layer_key(hash)).await?;
+            self.client.get(self.layer_key(hash)).await?;
         match data {
             Some(val) => Ok(Some(val.convert::<Vec<u8>>()?)),
             None => Ok(None),
@@ -117,7 +118,7 @@ impl RemoteCache for RedisCache {
     }
 
     async fn get_node_layers(&self, hash: &str) -> Result<Option<Vec<String>>> {
-        let val: Option<String> = self.client.get(&self.node_layers_key(hash)).await?;
+        let val: Option<String> = self.client.get(self.node_layers_key(hash)).await?;
         match val {
             Some(json_str) => {
                 let layers: Vec<String> = serde_json::from_str(&json_str)?;
@@ -161,9 +162,9 @@ impl RemoteCache for RedisCache {
 
 impl RedisCache {
     pub async fn evict(&self, hash: &str) -> Result<()> {
-        let _: () = self.client.del(&self.blob_key(hash)).await?;
-        let _: () = self.client.del(&self.layer_key(hash)).await?;
-        let _: () = self.client.del(&self.node_layers_key(hash)).await?;
+        let _: () = self.client.del(self.blob_key(hash)).await?;
+        let _: () = self.client.del(self.layer_key(hash)).await?;
+        let _: () = self.client.del(self.node_layers_key(hash)).await?;
         self.publish_evict(hash).await;
         Ok(())
     }
@@ -26,3 +26,124 @@ pub const MAX_WS_BROADCAST_CAPACITY: usize = 100;
 
 /// Number of past builds to return for analytics queries
 pub const ANALYTICS_DB_LIMIT: usize = 50;
+
+/// Consecutive remote cache failures before the build stops trying the remote
+pub const DEFAULT_REMOTE_FAILURE_THRESHOLD: u32 = 3;
+
+/// Seconds to stay offline before probing the remote cache again
+pub const DEFAULT_REMOTE_RETRY_SECS: u64 = 30;
+
+/// Upper bound for a single remote cache call
+pub const DEFAULT_REMOTE_TIMEOUT_SECS: u64 = 120;
+
+/// How long a build waits at the end for queued remote cache mirror uploads
+pub const MIRROR_FLUSH_TIMEOUT_SECS: u64 = 600;
+
+/// Remote cache uploads and downloads allowed in flight at once
+pub const DEFAULT_MAX_TRANSFERS: usize = 8;
+
+/// File name of the lockfile, kept in the root of the build context
+pub const LOCKFILE_NAME: &str = "memobuild.lock";
+
+/// Host variables sandboxed commands may see when `MEMOBUILD_ENV_PASSTHROUGH`
+/// is unset (`SYSTEMROOT` is needed for anything to start on Windows)
+pub const DEFAULT_ENV_PASSTHROUGH: &[&str] = &["PATH", "HOME", "SYSTEMROOT"];
+
+/// Kubernetes namespace for build Jobs when `MEMOBUILD_K8S_NAMESPACE` is unset
+pub const DEFAULT_K8S_NAMESPACE: &str = "default";
+
+/// Image the memobuild helper containers of a build Job run
+pub const DEFAULT_K8S_TOOL_IMAGE: &str = "ghcr.io/nrelab/memobuild:latest";
+
+/// Build Jobs running in the cluster at once
+pub const DEFAULT_K8S_PARALLELISM: usize = 16;
+
+/// Seconds a finished build Job is kept before Kubernetes deletes it
+pub const DEFAULT_K8S_JOB_TTL_SECS: i32 = 600;
+
+/// Free space the cache server keeps on its volume beyond an incoming upload
+pub const DEFAULT_MIN_FREE_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Largest artifact cached by the client or accepted by the cache server when
+/// `MEMOBUILD_MAX_ARTIFACT_BYTES` is unset (8 GB)
+pub const DEFAULT_MAX_ARTIFACT_BYTES: u64 = 8 * 1024 * 1024 * 1024;
+
+/// Artifacts above this size are listed at the end of the build when
+/// `MEMOBUILD_LARGE_ARTIFACT_BYTES` is unset (512 MB)
+pub const DEFAULT_LARGE_ARTIFACT_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Seconds the cache server waits for in-flight requests after SIGTERM
+pub const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 30;
+
+/// Hours a blob goes unread before a tiered cache server moves it to cold
+/// storage
+pub const DEFAULT_TIER_IDLE_HOURS: u64 = 7 * 24;
+
+/// Seconds between demotion passes of a tiered cache server
+pub const TIER_DEMOTION_INTERVAL_SECS: u64 = 3600;
+
+/// Minutes between validation passes of the cache server when
+/// `MEMOBUILD_VALIDATE_INTERVAL_MINS` is unset
+pub const DEFAULT_VALIDATE_INTERVAL_MINS: u64 = 60;
+
+/// Blobs a validation pass hashes when `MEMOBUILD_VALIDATE_SAMPLE` is unset
+pub const DEFAULT_VALIDATE_SAMPLE: usize = 200;
+
+/// Findings the cache server keeps for `/api/validation`
+pub const MAX_VALIDATION_FINDINGS: usize = 100;
+
+/// Directory in the build context WASM plugins are loaded from when
+/// `MEMOBUILD_PLUGIN_DIR` is unset
+pub const DEFAULT_PLUGIN_DIR: &str = ".memobuild/plugins";
+
+/// PostgreSQL connections the cache server's metadata pool holds when
+/// `MEMOBUILD_METADATA_POOL_SIZE` is unset
+pub const DEFAULT_METADATA_POOL_SIZE: usize = 16;
+
+/// Artifacts per page of `/api/v1/artifacts`, by default and at most
+pub const DEFAULT_ARTIFACT_PAGE_SIZE: u64 = 100;
+pub const MAX_ARTIFACT_PAGE_SIZE: u64 = 1000;
+
+/// Version of the cache key derivation, hashed into every key. Bump it whenever
+/// hashing or key inputs change, so entries from older versions are never reused
+pub const CACHE_KEY_VERSION: u32 = 2;
+
+/// Header carrying the client's `CACHE_KEY_VERSION` to the remote cache
+pub const KEY_VERSION_HEADER: &str = "X-MemoBuild-Key-Version";
+
+/// Header carrying the client's `os/arch` to the remote cache, recorded with
+/// the entries it stores
+pub const PLATFORM_HEADER: &str = "X-MemoBuild-Platform";
+
+/// Header naming the team or project a client's cache usage is counted under
+pub const NAMESPACE_HEADER: &str = "X-MemoBuild-Namespace";
+
+/// Header carrying the client's `MEMOBUILD_BUILD_ID`, recorded with the
+/// entries it stores so they can be pinned together
+pub const BUILD_ID_HEADER: &str = "X-MemoBuild-Build-Id";
+
+/// Header carrying the client's `EntryOrigin` as JSON, recorded with the
+/// entries it stores
+pub const ORIGIN_HEADER: &str = "X-MemoBuild-Origin";
+
+/// Header carrying a random id of the client process, so the remote cache can
+/// tell which artifacts one build fetched when it has no `MEMOBUILD_BUILD_ID`
+pub const SESSION_HEADER: &str = "X-MemoBuild-Session";
+
+/// Prefetch hints the remote cache returns for one artifact, by default and
+/// at most
+pub const DEFAULT_PREFETCH_HINTS: usize = 32;
+pub const MAX_PREFETCH_HINTS: usize = 256;
+
+/// Namespace for clients that do not set `MEMOBUILD_NAMESPACE`
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// Days covered by `/stats` unless the query asks otherwise
+pub const DEFAULT_STATS_DAYS: u32 = 30;
+
+/// Most transparency log entries served per `/log` page
+pub const LOG_PAGE_SIZE: u64 = 1000;
+
+/// Seconds two machines' clocks may disagree by before cache entry ages are
+/// taken at face value, when `MEMOBUILD_CLOCK_SKEW_SECS` is unset
+pub const DEFAULT_CLOCK_SKEW_SECS: u64 = 300;
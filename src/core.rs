@@ -1,10 +1,14 @@
 use crate::env::EnvFingerprint;
-use crate::graph::BuildGraph;
+use crate::graph::{BuildGraph, DirtyReason, Node};
+use crate::hasher::{ContentFilters, HashProgress, IgnoreRules, MerkleState, MerkleTree};
+use crate::history::{BuildHistory, NodeInputs};
 
 #[allow(dead_code)]
 pub fn detect_changes(graph: &mut BuildGraph) {
+    // Without a previous build to compare against, every node is new;
+    // explain_changes refines this once hashes are known
     for node in &mut graph.nodes {
-        node.dirty = true;
+        node.mark_dirty(DirtyReason::NewNode);
     }
 }
 
@@ -13,26 +17,161 @@ pub fn propagate_dirty(graph: &mut BuildGraph) {
     let node_count = graph.nodes.len();
     for i in 0..node_count {
         if !graph.nodes[i].dirty {
-            for dep in &graph.nodes[i].deps {
-                if *dep < node_count && graph.nodes[*dep].dirty {
-                    graph.nodes[i].dirty = true;
-                    break;
-                }
+            let dirty_dep = graph.nodes[i]
+                .deps
+                .iter()
+                .copied()
+                .find(|&dep| dep < node_count && graph.nodes[dep].dirty);
+            if let Some(dep) = dirty_dep {
+                graph.nodes[i].mark_dirty(DirtyReason::ParentDirty(dep));
             }
         }
     }
 }
 
+/// Replace each node's dirty reason with what changed since the build
+/// `history` recorded. Hashes must already be computed. Nodes whose key and
+/// dependencies are the same as last time are left without a reason.
+pub fn explain_changes(graph: &mut BuildGraph, history: &BuildHistory) {
+    for i in 0..graph.nodes.len() {
+        let node = &graph.nodes[i];
+        // Dependencies come first, so their reasons are already current
+        let dirty_dep = node
+            .deps
+            .iter()
+            .copied()
+            .find(|&dep| dep < i && graph.nodes[dep].metadata.dirty_reason.is_some());
+        let reason = dirty_reason(node, history.inputs(node), dirty_dep);
+        graph.nodes[i].metadata.dirty_reason = reason;
+    }
+}
+
+fn dirty_reason(
+    node: &Node,
+    previous: Option<&NodeInputs>,
+    dirty_dep: Option<usize>,
+) -> Option<DirtyReason> {
+    if node.metadata.no_cache() {
+        return Some(DirtyReason::Forced);
+    }
+    let Some(previous) = previous else {
+        return Some(DirtyReason::NewNode);
+    };
+    if previous.hash == node.hash {
+        return dirty_dep.map(DirtyReason::ParentDirty);
+    }
+    if previous.source_hash != node.metadata.source_content_hash {
+        let path = node
+            .metadata
+            .changed_paths
+            .first()
+            .cloned()
+            .or_else(|| node.source_path.as_ref().map(|p| p.display().to_string()))
+            .unwrap_or_default();
+        return Some(DirtyReason::InputFileChanged(path));
+    }
+    let env = &node.metadata.build_env;
+    if let Some(key) = previous
+        .env
+        .keys()
+        .chain(env.keys())
+        .find(|key| previous.env.get(*key) != env.get(*key))
+    {
+        return Some(DirtyReason::EnvChanged(key.clone()));
+    }
+    Some(dirty_dep.map_or(DirtyReason::ContentChanged, DirtyReason::ParentDirty))
+}
+
+/// Hash every COPY source as a Merkle tree, reusing digests from `state` for
+/// files that were not touched, and record where each source changed.
+/// Sets `source_content_hash` and `changed_paths`; `state` is updated in place.
+///
+/// Files are hashed through `filters`, whose digest becomes part of each
+/// source's cache key. Trees found in `live` (kept current by `memobuild
+/// daemon`) are taken as they are, without scanning the source at all, when
+/// they were built with the same filters. Files and bytes hashed are
+/// counted into `progress`, and cancelling it stops hashing with
+/// [`Cancelled`](crate::hasher::Cancelled).
+pub fn hash_sources(
+    graph: &mut BuildGraph,
+    state: &mut MerkleState,
+    live: Option<&MerkleState>,
+    ignore: &IgnoreRules,
+    filters: &ContentFilters,
+    progress: &HashProgress,
+) -> anyhow::Result<()> {
+    for node in &mut graph.nodes {
+        let Some(ref path) = node.source_path else {
+            continue;
+        };
+        node.metadata.content_filters = filters.digest().map(String::from);
+        if !path.is_dir() {
+            if path.is_file() {
+                progress.add_total(1);
+                progress.file_started(path);
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                node.metadata.source_content_hash = filters.hash_file(&name, path, progress)?;
+                progress.file_done();
+            }
+            continue;
+        }
+        let previous = state.get(path);
+        let excludes = node.metadata.excludes();
+        let live_tree = live
+            .and_then(|l| l.get(path))
+            .filter(|tree| tree.filters.as_deref() == filters.digest());
+        let tree = match live_tree {
+            // The daemon's trees only leave out what .dockerignore does
+            Some(tree) if excludes.is_empty() => tree.clone(),
+            _ if excludes.is_empty() => {
+                MerkleTree::build_filtered(path, ignore, filters, previous, progress)?
+            }
+            _ => {
+                let ignore = ignore.clone().with_patterns(excludes);
+                MerkleTree::build_filtered(path, &ignore, filters, previous, progress)?
+            }
+        };
+        node.metadata.changed_paths = previous
+            .map(|prev| tree.changed_dirs(prev))
+            .unwrap_or_default();
+        node.metadata.source_content_hash = Some(tree.root.clone());
+        state.insert(path, tree);
+    }
+    Ok(())
+}
+
 #[allow(dead_code)]
-pub fn compute_composite_hashes(graph: &mut BuildGraph, _env_fp: &EnvFingerprint) {
+pub fn compute_composite_hashes(graph: &mut BuildGraph, env_fp: &EnvFingerprint) {
+    let platform = env_fp.platform();
     for node in &mut graph.nodes {
         use blake3::Hasher;
         let mut hasher = Hasher::new();
+        hash_key_version(&mut hasher);
+        // Artifacts are only reused on the platform that produced them, unless
+        // the node is declared platform-independent
+        node.metadata.platform = if node.metadata.platform_independent() {
+            None
+        } else {
+            hasher.update(b"platform=");
+            hasher.update(platform.as_bytes());
+            Some(platform.clone())
+        };
         hasher.update(node.content.as_bytes());
+        if let Some(ref source) = node.metadata.source_content_hash {
+            hasher.update(b"source=");
+            hasher.update(source.as_bytes());
+        }
+        node.metadata.hash_key_inputs(&mut hasher);
         node.hash = hasher.finalize().to_hex().to_string();
     }
 }
 
+/// Prefix a cache key with [`CACHE_KEY_VERSION`](crate::constants::CACHE_KEY_VERSION),
+/// so keys from different key derivations never collide.
+pub fn hash_key_version(hasher: &mut blake3::Hasher) {
+    hasher.update(format!("memobuild-key-v{}\0", crate::constants::CACHE_KEY_VERSION).as_bytes());
+}
+
 #[allow(dead_code)]
 pub fn propagate_manifests(graph: &mut BuildGraph) -> std::collections::HashMap<String, serde_json::Value> {
     let mut manifests = std::collections::HashMap::new();
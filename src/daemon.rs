@@ -0,0 +1,718 @@
+pub mod rpc;
+
+use crate::cache::HybridCache;
+use crate::dashboard::{BroadcastObserver, BuildEvent, BuildObserver};
+use crate::graph::BuildGraph;
+use crate::hasher::{IgnoreRules, MerkleState, MerkleTree};
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use rpc::RpcError;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Cookie files written next to the daemon's socket to know when all
+/// earlier events have been delivered
+const COOKIE_PREFIX: &str = ".memobuild-cookie-";
+
+/// How long a sync waits for its cookie before falling back to a rescan
+const COOKIE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often queued events are folded into the trees between syncs
+const FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+fn daemon_id(context_dir: &Path) -> String {
+    let context = MerkleState::key(context_dir);
+    blake3::hash(context.as_bytes()).to_hex()[..12].to_string()
+}
+
+/// Socket a daemon for `context_dir` listens on. One daemon per context.
+pub fn socket_path(cache_dir: &Path, context_dir: &Path) -> PathBuf {
+    cache_dir.join(format!("daemon-{}.sock", daemon_id(context_dir)))
+}
+
+/// Directory a daemon for `context_dir` writes its cookies to, outside the
+/// context so they never reach a hashed tree.
+pub fn state_dir(cache_dir: &Path, context_dir: &Path) -> PathBuf {
+    cache_dir.join(format!("daemon-{}", daemon_id(context_dir)))
+}
+
+#[derive(Default)]
+struct Pending {
+    paths: BTreeSet<PathBuf>,
+    rescan: bool,
+    /// `.dockerignore` changed; every tree is rescanned with the new rules
+    ignore_changed: bool,
+    cookies: HashSet<PathBuf>,
+}
+
+/// Keeps the Merkle trees of a context's COPY sources current by following
+/// filesystem events, so builds can take them without scanning.
+pub struct HashDaemon {
+    context_dir: PathBuf,
+    /// Where cookies are written; watched along with the context
+    state_dir: PathBuf,
+    ignore: Mutex<IgnoreRules>,
+    sources: Vec<PathBuf>,
+    trees: Mutex<MerkleState>,
+    pending: Mutex<Pending>,
+    cookie_seen: Condvar,
+    next_cookie: AtomicU64,
+}
+
+impl HashDaemon {
+    /// Find the directory sources of `dockerfile` and hash them once, reusing
+    /// `previous` (usually the last build's state) for untouched files.
+    /// Cookies go to `state_dir`, which is created if missing.
+    pub fn new(
+        context_dir: &Path,
+        dockerfile: &Path,
+        previous: &MerkleState,
+        state_dir: &Path,
+    ) -> Result<Self> {
+        let context_dir = context_dir
+            .canonicalize()
+            .with_context(|| format!("Context {} not found", context_dir.display()))?;
+        std::fs::create_dir_all(state_dir)
+            .with_context(|| format!("Failed to create {}", state_dir.display()))?;
+        let state_dir = state_dir.canonicalize()?;
+        let content = std::fs::read_to_string(dockerfile)
+            .with_context(|| format!("Failed to read Dockerfile at {}", dockerfile.display()))?;
+        let instructions = crate::docker::parser::parse_dockerfile_spanned(&content, dockerfile);
+        let graph =
+            crate::docker::include::build_graph_with_includes(instructions, context_dir.clone())?;
+
+        let mut sources: Vec<PathBuf> = graph
+            .nodes
+            .iter()
+            .filter_map(|n| n.source_path.as_ref())
+            .filter(|p| p.is_dir())
+            .filter_map(|p| p.canonicalize().ok())
+            .collect();
+        sources.sort();
+        sources.dedup();
+
+        let ignore = IgnoreRules::from_file(&context_dir.join(".dockerignore"));
+        let mut trees = MerkleState::default();
+        for source in &sources {
+            trees.insert(
+                source,
+                MerkleTree::build(source, &ignore, previous.get(source))?,
+            );
+        }
+
+        Ok(Self {
+            context_dir,
+            state_dir,
+            ignore: Mutex::new(ignore),
+            sources,
+            trees: Mutex::new(trees),
+            pending: Mutex::new(Pending::default()),
+            cookie_seen: Condvar::new(),
+            next_cookie: AtomicU64::new(0),
+        })
+    }
+
+    pub fn context_dir(&self) -> &Path {
+        &self.context_dir
+    }
+
+    pub fn state_dir(&self) -> &Path {
+        &self.state_dir
+    }
+
+    pub fn sources(&self) -> &[PathBuf] {
+        &self.sources
+    }
+
+    /// The ignore rules the current trees were hashed with.
+    pub fn ignore(&self) -> IgnoreRules {
+        self.ignore.lock().unwrap().clone()
+    }
+
+    fn ignore_file(&self) -> PathBuf {
+        self.context_dir.join(".dockerignore")
+    }
+
+    /// Queue a filesystem event. Called from the watcher thread.
+    pub fn handle_event(&self, event: notify::Result<notify::Event>) {
+        let mut pending = self.pending.lock().unwrap();
+        let event = match event {
+            Ok(event) => event,
+            Err(_) => {
+                pending.rescan = true;
+                return;
+            }
+        };
+        if event.need_rescan() {
+            pending.rescan = true;
+        }
+        for path in event.paths {
+            let is_cookie = path.starts_with(&self.state_dir)
+                && path
+                    .file_name()
+                    .is_some_and(|n| n.to_string_lossy().starts_with(COOKIE_PREFIX));
+            if !is_cookie {
+                if path == self.ignore_file() {
+                    pending.ignore_changed = true;
+                    pending.rescan = true;
+                }
+                pending.paths.insert(path);
+            } else if path.exists() {
+                // Only the creation matters; the later removal event is ignored
+                pending.cookies.insert(path);
+                self.cookie_seen.notify_all();
+            }
+        }
+    }
+
+    /// Fold queued events into the trees, rehashing only what they touched.
+    pub fn flush(&self) -> Result<()> {
+        let (paths, rescan, ignore_changed) = {
+            let mut pending = self.pending.lock().unwrap();
+            (
+                std::mem::take(&mut pending.paths),
+                std::mem::take(&mut pending.rescan),
+                std::mem::take(&mut pending.ignore_changed),
+            )
+        };
+        if paths.is_empty() && !rescan {
+            return Ok(());
+        }
+
+        let mut trees = self.trees.lock().unwrap();
+        let mut ignore = self.ignore.lock().unwrap();
+        if ignore_changed {
+            *ignore = IgnoreRules::from_file(&self.ignore_file());
+        }
+        for source in &self.sources {
+            let key = MerkleState::key(source);
+            let replaced = paths.iter().any(|p| source.starts_with(p) && p != source);
+            if rescan || replaced || !trees.trees.contains_key(&key) {
+                // Lost events or the source itself moved: scan it again
+                let tree = MerkleTree::build(source, &ignore, trees.trees.get(&key))?;
+                trees.trees.insert(key, tree);
+                continue;
+            }
+            let changed: Vec<PathBuf> = paths
+                .iter()
+                .filter(|p| p.starts_with(source))
+                .cloned()
+                .collect();
+            if let (false, Some(tree)) = (changed.is_empty(), trees.trees.get_mut(&key)) {
+                tree.update_paths(source, &ignore, &changed)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Current trees, guaranteed to include every change made before the call.
+    ///
+    /// A cookie file is written into the state directory, watched by the
+    /// same watcher as the context, and the call waits until its event
+    /// arrives; events are delivered in order, so everything before it has
+    /// been queued by then.
+    pub fn sync(&self) -> Result<MerkleState> {
+        let cookie = self.state_dir.join(format!(
+            "{}{}-{}",
+            COOKIE_PREFIX,
+            std::process::id(),
+            self.next_cookie.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::write(&cookie, b"")
+            .with_context(|| format!("Failed to write {}", cookie.display()))?;
+
+        let pending = self.pending.lock().unwrap();
+        let (mut pending, wait) = self
+            .cookie_seen
+            .wait_timeout_while(pending, COOKIE_TIMEOUT, |p| !p.cookies.contains(&cookie))
+            .unwrap();
+        pending.cookies.remove(&cookie);
+        if wait.timed_out() {
+            pending.rescan = true;
+        }
+        drop(pending);
+        let _ = std::fs::remove_file(&cookie);
+
+        self.flush()?;
+        Ok(self.trees.lock().unwrap().clone())
+    }
+}
+
+/// Runs a build on behalf of a client; supplied by the binary, which owns the
+/// build pipeline. Events go to the observer.
+pub type BuildFn = Arc<
+    dyn Fn(rpc::BuildParams, Arc<dyn BuildObserver>) -> BoxFuture<'static, Result<()>>
+        + Send
+        + Sync,
+>;
+
+/// How the last build started through the daemon ended.
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildOutcome {
+    pub ok: bool,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+    pub dry_run: bool,
+}
+
+/// Answers JSON-RPC calls for one daemon. See [`rpc`] for the methods.
+pub struct DaemonServer {
+    daemon: Arc<HashDaemon>,
+    dockerfile: PathBuf,
+    cache: Arc<HybridCache>,
+    build: Option<BuildFn>,
+    events: broadcast::Sender<BuildEvent>,
+    building: AtomicBool,
+    last_build: Mutex<Option<BuildOutcome>>,
+}
+
+impl DaemonServer {
+    pub fn new(
+        daemon: Arc<HashDaemon>,
+        dockerfile: &Path,
+        cache: Arc<HybridCache>,
+        build: Option<BuildFn>,
+    ) -> Self {
+        let (events, _) = broadcast::channel(crate::constants::MAX_WS_BROADCAST_CAPACITY);
+        Self {
+            daemon,
+            dockerfile: dockerfile.to_path_buf(),
+            cache,
+            build,
+            events,
+            building: AtomicBool::new(false),
+            last_build: Mutex::new(None),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<BuildEvent> {
+        self.events.subscribe()
+    }
+
+    /// The graph as the next build would see it: live source trees, base
+    /// images pinned from the lockfile only, and keys computed as a build
+    /// computes them.
+    async fn graph(&self) -> Result<BuildGraph> {
+        let daemon = self.daemon.clone();
+        let live = tokio::task::spawn_blocking(move || daemon.sync()).await??;
+
+        let context_dir = self.daemon.context_dir().to_path_buf();
+        let content = std::fs::read_to_string(&self.dockerfile).with_context(|| {
+            format!("Failed to read Dockerfile at {}", self.dockerfile.display())
+        })?;
+        let instructions =
+            crate::docker::parser::parse_dockerfile_spanned(&content, &self.dockerfile);
+        let mut graph =
+            crate::docker::include::build_graph_with_includes(instructions, context_dir.clone())?;
+
+        let mut lock =
+            crate::lockfile::Lockfile::load(&context_dir.join(crate::constants::LOCKFILE_NAME))?;
+        // No registry lookups here; images missing from the lockfile keep their tag key
+        let _ = crate::docker::resolve::pin_base_images(
+            &mut graph,
+            &mut lock,
+            crate::docker::resolve::LockMode::Locked,
+            &crate::docker::resolve::RegistryDigestSource,
+            &crate::docker::resolve::stage_aliases(&content),
+            &crate::docker::policy::PolicySet::from_env(),
+            None,
+        );
+        let cache_dir = self.cache.local.cache_dir();
+        let env_fp = crate::prepare::env_fingerprint(&context_dir, cache_dir)?;
+        crate::prepare::configure_steps(&mut graph, &context_dir, &env_fp)?;
+
+        let mut state = MerkleState::load(&MerkleState::path_in(cache_dir));
+        crate::core::hash_sources(
+            &mut graph,
+            &mut state,
+            Some(&live),
+            &self.daemon.ignore(),
+            &crate::hasher::ContentFilters::load(&context_dir)?,
+            &crate::hasher::HashProgress::default(),
+        )?;
+        let traces = crate::prepare::load_traces(cache_dir)?;
+        crate::prepare::finish_keys(
+            &mut graph,
+            &self.cache,
+            &context_dir,
+            &env_fp,
+            traces.as_ref(),
+        )
+        .await?;
+        Ok(graph)
+    }
+
+    fn start_build(self: &Arc<Self>, params: rpc::BuildParams) -> Result<Value, RpcError> {
+        let Some(ref build) = self.build else {
+            return Err(RpcError::new(
+                rpc::SERVER_ERROR,
+                "This daemon cannot run builds",
+            ));
+        };
+        if self.building.swap(true, Ordering::SeqCst) {
+            return Err(RpcError::new(
+                rpc::BUILD_RUNNING,
+                "A build is already running",
+            ));
+        }
+        let dry_run = params.dry_run;
+        let observer: Arc<dyn BuildObserver> =
+            Arc::new(BroadcastObserver::new(self.events.clone()));
+        let future = build(params, observer);
+        let server = self.clone();
+        tokio::spawn(async move {
+            let start = std::time::Instant::now();
+            let result = future.await;
+            if let Err(ref e) = result {
+                eprintln!("❌ Daemon build failed: {:#}", e);
+            }
+            *server.last_build.lock().unwrap() = Some(BuildOutcome {
+                ok: result.is_ok(),
+                error: result.err().map(|e| format!("{:#}", e)),
+                duration_ms: start.elapsed().as_millis() as u64,
+                dry_run,
+            });
+            server.building.store(false, Ordering::SeqCst);
+        });
+        Ok(json!({ "started": true }))
+    }
+
+    /// Handle one call. `subscribe` is handled by the connection itself.
+    pub async fn dispatch(
+        self: &Arc<Self>,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, RpcError> {
+        let server_error = |e: anyhow::Error| RpcError::new(rpc::SERVER_ERROR, format!("{:#}", e));
+        match method {
+            "sync" => {
+                let daemon = self.daemon.clone();
+                let state = tokio::task::spawn_blocking(move || daemon.sync())
+                    .await
+                    .map_err(|e| server_error(e.into()))?
+                    .map_err(server_error)?;
+                serde_json::to_value(state).map_err(|e| server_error(e.into()))
+            }
+            "status" => Ok(json!({
+                "context_dir": self.daemon.context_dir(),
+                "dockerfile": self.dockerfile,
+                "sources": self.daemon.sources(),
+                "building": self.building.load(Ordering::SeqCst),
+                "last_build": self.last_build.lock().unwrap().clone(),
+            })),
+            "graph" => {
+                let graph = self.graph().await.map_err(server_error)?;
+                serde_json::to_value(graph).map_err(|e| server_error(e.into()))
+            }
+            "cache.status" => {
+                let graph = self.graph().await.map_err(server_error)?;
+                let history = crate::history::BuildHistory::load(
+                    &crate::history::BuildHistory::path_in(self.cache.local.cache_dir()),
+                )
+                .map_err(server_error)?;
+                let plan = crate::plan::plan_build(&graph, &self.cache, &history)
+                    .await
+                    .map_err(server_error)?;
+                serde_json::to_value(plan).map_err(|e| server_error(e.into()))
+            }
+            "build" => {
+                let params: rpc::BuildParams = if params.is_null() {
+                    Default::default()
+                } else {
+                    serde_json::from_value(params)
+                        .map_err(|e| RpcError::new(rpc::INVALID_PARAMS, e))?
+                };
+                self.start_build(params)
+            }
+            other => Err(RpcError::new(
+                rpc::METHOD_NOT_FOUND,
+                format!("Unknown method '{}'", other),
+            )),
+        }
+    }
+}
+
+/// Run a daemon for `context_dir` until interrupted. Without `build` the
+/// daemon only answers queries.
+#[cfg(unix)]
+pub async fn run(
+    context_dir: &Path,
+    dockerfile: &Path,
+    cache: Arc<HybridCache>,
+    build: Option<BuildFn>,
+) -> Result<()> {
+    use notify::Watcher;
+
+    let cache_dir = cache.local.cache_dir().to_path_buf();
+    let previous = MerkleState::load(&MerkleState::path_in(&cache_dir));
+    let daemon = Arc::new(HashDaemon::new(
+        context_dir,
+        dockerfile,
+        &previous,
+        &state_dir(&cache_dir, context_dir),
+    )?);
+
+    let handler = daemon.clone();
+    let mut watcher = notify::recommended_watcher(move |event| handler.handle_event(event))
+        .context("Failed to start file watcher")?;
+    watcher
+        .watch(daemon.context_dir(), notify::RecursiveMode::Recursive)
+        .with_context(|| format!("Failed to watch {}", daemon.context_dir().display()))?;
+    watcher
+        .watch(daemon.state_dir(), notify::RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {}", daemon.state_dir().display()))?;
+
+    let socket = socket_path(&cache_dir, context_dir);
+    let _ = std::fs::remove_file(&socket);
+    let listener = tokio::net::UnixListener::bind(&socket)
+        .with_context(|| format!("Failed to bind {}", socket.display()))?;
+    println!(
+        "👀 Watching {} ({} source tree(s)), listening on {}",
+        daemon.context_dir().display(),
+        daemon.sources().len(),
+        socket.display()
+    );
+
+    let flusher = daemon.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let daemon = flusher.clone();
+            match tokio::task::spawn_blocking(move || daemon.flush()).await {
+                Ok(Err(e)) => eprintln!("⚠️  Failed to update source hashes: {}", e),
+                Err(e) => eprintln!("⚠️  Hash update task failed: {}", e),
+                Ok(Ok(())) => {}
+            }
+        }
+    });
+
+    let server = Arc::new(DaemonServer::new(daemon, dockerfile, cache, build));
+    let result = tokio::select! {
+        result = accept_loop(listener, server) => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+    let _ = std::fs::remove_file(&socket);
+    result
+}
+
+#[cfg(not(unix))]
+pub async fn run(
+    _context_dir: &Path,
+    _dockerfile: &Path,
+    _cache: Arc<HybridCache>,
+    _build: Option<BuildFn>,
+) -> Result<()> {
+    anyhow::bail!("memobuild daemon needs Unix domain sockets, which this platform lacks")
+}
+
+#[cfg(unix)]
+async fn accept_loop(listener: tokio::net::UnixListener, server: Arc<DaemonServer>) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(serve_connection(stream, server.clone()));
+    }
+}
+
+/// Read requests line by line; responses and event notifications share one
+/// writer task so they never interleave mid-line.
+#[cfg(unix)]
+async fn serve_connection(stream: tokio::net::UnixStream, server: Arc<DaemonServer>) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (read, mut write) = stream.into_split();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let writer = tokio::spawn(async move {
+        while let Some(mut line) = rx.recv().await {
+            line.push('\n');
+            if write.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let request: rpc::Request = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                let response =
+                    rpc::Response::new(Value::Null, Err(RpcError::new(rpc::PARSE_ERROR, e)));
+                let _ = tx.send(serde_json::to_string(&response).unwrap_or_default());
+                continue;
+            }
+        };
+        if request.jsonrpc != "2.0" {
+            let response = rpc::Response::new(
+                request.id.unwrap_or(Value::Null),
+                Err(RpcError::new(
+                    rpc::INVALID_REQUEST,
+                    "jsonrpc must be \"2.0\"",
+                )),
+            );
+            let _ = tx.send(serde_json::to_string(&response).unwrap_or_default());
+            continue;
+        }
+
+        if request.method == "subscribe" {
+            let mut events = server.subscribe();
+            let events_tx = tx.clone();
+            tokio::spawn(async move {
+                loop {
+                    let event = match events.recv().await {
+                        Ok(event) => event,
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    };
+                    let params = serde_json::to_value(event).unwrap_or_default();
+                    let message = rpc::Notification::new(rpc::EVENT_NOTIFICATION, params);
+                    if events_tx
+                        .send(serde_json::to_string(&message).unwrap_or_default())
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+            if let Some(id) = request.id {
+                let response = rpc::Response::new(id, Ok(json!({ "subscribed": true })));
+                let _ = tx.send(serde_json::to_string(&response).unwrap_or_default());
+            }
+            continue;
+        }
+
+        // Calls run concurrently so a slow one doesn't hold up the rest
+        let server = server.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let outcome = server.dispatch(&request.method, request.params).await;
+            if let Some(id) = request.id {
+                let response = rpc::Response::new(id, outcome);
+                let _ = tx.send(serde_json::to_string(&response).unwrap_or_default());
+            }
+        });
+    }
+    drop(tx);
+    let _ = writer.await;
+}
+
+/// Ask a running daemon for up-to-date trees of `context_dir`. Returns `None`
+/// when no daemon is running, so the caller scans as usual.
+#[cfg(unix)]
+pub async fn sync(cache_dir: &Path, context_dir: &Path) -> Option<MerkleState> {
+    let mut client = rpc::Client::connect(cache_dir, context_dir).await.ok()?;
+    let exchange = async {
+        let state = client.call("sync", Value::Null).await?;
+        Ok::<_, anyhow::Error>(serde_json::from_value::<MerkleState>(state)?)
+    };
+    match tokio::time::timeout(Duration::from_secs(30), exchange).await {
+        Ok(Ok(state)) => Some(state),
+        Ok(Err(e)) => {
+            eprintln!("⚠️  memobuild daemon sync failed, scanning instead: {}", e);
+            None
+        }
+        Err(_) => {
+            eprintln!("⚠️  memobuild daemon did not answer, scanning instead");
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn sync(_cache_dir: &Path, _context_dir: &Path) -> Option<MerkleState> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_events_update_trees() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(
+            dir.path().join("Dockerfile"),
+            "FROM alpine\nCOPY src /app/src\n",
+        )
+        .unwrap();
+
+        let daemon = HashDaemon::new(
+            dir.path(),
+            &dir.path().join("Dockerfile"),
+            &MerkleState::default(),
+            &dir.path().join("state"),
+        )
+        .unwrap();
+        let src = dir.path().join("src").canonicalize().unwrap();
+        assert_eq!(daemon.sources(), std::slice::from_ref(&src));
+        let before = daemon.trees.lock().unwrap().get(&src).unwrap().root.clone();
+
+        fs::write(src.join("lib.rs"), "pub fn f() {}").unwrap();
+        daemon.handle_event(Ok(
+            notify::Event::new(notify::EventKind::Any).add_path(src.join("lib.rs"))
+        ));
+        daemon.flush().unwrap();
+
+        let trees = daemon.trees.lock().unwrap();
+        let tree = trees.get(&src).unwrap();
+        assert_ne!(tree.root, before);
+        assert_eq!(tree.rehashed, 1);
+        assert_eq!(
+            tree.root,
+            MerkleTree::build(&src, &IgnoreRules::empty(), None)
+                .unwrap()
+                .root
+        );
+    }
+
+    #[test]
+    fn test_dockerignore_changes_rescan() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(dir.path().join("src/debug.log"), "noise").unwrap();
+        fs::write(
+            dir.path().join("Dockerfile"),
+            "FROM alpine\nCOPY src /src\n",
+        )
+        .unwrap();
+        let state = TempDir::new().unwrap();
+        let daemon = HashDaemon::new(
+            dir.path(),
+            &dir.path().join("Dockerfile"),
+            &MerkleState::default(),
+            state.path(),
+        )
+        .unwrap();
+        let context = dir.path().canonicalize().unwrap();
+        let src = context.join("src");
+        assert!(!daemon.state_dir().starts_with(&context));
+
+        fs::write(context.join(".dockerignore"), "**/*.log\n").unwrap();
+        daemon.handle_event(Ok(
+            notify::Event::new(notify::EventKind::Any).add_path(context.join(".dockerignore"))
+        ));
+        daemon.flush().unwrap();
+
+        let rules = IgnoreRules::from_file(&context.join(".dockerignore"));
+        let expected = MerkleTree::build(&src, &rules, None).unwrap().root;
+        let unfiltered = MerkleTree::build(&src, &IgnoreRules::empty(), None)
+            .unwrap()
+            .root;
+        assert_ne!(expected, unfiltered);
+        assert_eq!(
+            daemon.trees.lock().unwrap().get(&src).unwrap().root,
+            expected
+        );
+    }
+}
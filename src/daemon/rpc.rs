@@ -0,0 +1,229 @@
+//! JSON-RPC 2.0 over the daemon socket, one message per line.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const SERVER_ERROR: i64 = -32000;
+/// A `build` call while another build is still running
+pub const BUILD_RUNNING: i64 = -32001;
+
+/// Method name of event notifications
+pub const EVENT_NOTIFICATION: &str = "build.event";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    /// Absent for notifications, which get no response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+impl Request {
+    pub fn new(id: u64, method: &str, params: Value) -> Self {
+        Self {
+            jsonrpc: "2.0".into(),
+            id: Some(id.into()),
+            method: method.into(),
+            params,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl std::fmt::Display) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl Response {
+    pub fn new(id: Value, outcome: std::result::Result<Value, RpcError>) -> Self {
+        let (result, error) = match outcome {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            jsonrpc: "2.0".into(),
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    pub jsonrpc: String,
+    pub method: String,
+    pub params: Value,
+}
+
+impl Notification {
+    pub fn new(method: &str, params: Value) -> Self {
+        Self {
+            jsonrpc: "2.0".into(),
+            method: method.into(),
+            params,
+        }
+    }
+}
+
+/// A line sent by the daemon.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Message {
+    Response(Response),
+    Notification(Notification),
+}
+
+/// Parameters of `build`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BuildParams {
+    #[serde(default)]
+    pub reproducible: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// A connection to a running daemon.
+#[cfg(unix)]
+pub struct Client {
+    lines: tokio::io::Lines<tokio::io::BufReader<tokio::net::unix::OwnedReadHalf>>,
+    write: tokio::net::unix::OwnedWriteHalf,
+    next_id: u64,
+    /// Notifications that arrived while waiting for a response
+    queued: std::collections::VecDeque<Notification>,
+}
+
+#[cfg(unix)]
+impl Client {
+    /// Connect to the daemon watching `context_dir`.
+    pub async fn connect(cache_dir: &Path, context_dir: &Path) -> Result<Self> {
+        use tokio::io::AsyncBufReadExt;
+
+        let socket = super::socket_path(cache_dir, context_dir);
+        let stream = tokio::net::UnixStream::connect(&socket)
+            .await
+            .with_context(|| format!("No memobuild daemon at {}", socket.display()))?;
+        let (read, write) = stream.into_split();
+        Ok(Self {
+            lines: tokio::io::BufReader::new(read).lines(),
+            write,
+            next_id: 1,
+            queued: Default::default(),
+        })
+    }
+
+    /// Call `method` and wait for its result.
+    pub async fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        use tokio::io::AsyncWriteExt;
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut line = serde_json::to_string(&Request::new(id, method, params))?;
+        line.push('\n');
+        self.write.write_all(line.as_bytes()).await?;
+
+        loop {
+            match self.read_message().await? {
+                Message::Response(response) if response.id == id => {
+                    if let Some(error) = response.error {
+                        anyhow::bail!("{} failed: {}", method, error);
+                    }
+                    return Ok(response.result.unwrap_or(Value::Null));
+                }
+                Message::Response(_) => {}
+                Message::Notification(n) => self.queued.push_back(n),
+            }
+        }
+    }
+
+    /// Next notification, after `subscribe`. `None` once the daemon hangs up.
+    pub async fn next_notification(&mut self) -> Result<Option<Notification>> {
+        if let Some(n) = self.queued.pop_front() {
+            return Ok(Some(n));
+        }
+        loop {
+            match self.read_message().await {
+                Ok(Message::Notification(n)) => return Ok(Some(n)),
+                Ok(Message::Response(_)) => {}
+                Err(_) => return Ok(None),
+            }
+        }
+    }
+
+    async fn read_message(&mut self) -> Result<Message> {
+        let line = self
+            .lines
+            .next_line()
+            .await?
+            .context("memobuild daemon closed the connection")?;
+        serde_json::from_str(&line).context("Malformed message from memobuild daemon")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_kinds() {
+        let response: Message =
+            serde_json::from_str(r#"{"jsonrpc":"2.0","id":3,"result":{"ok":true}}"#).unwrap();
+        assert!(matches!(response, Message::Response(ref r) if r.id == 3));
+
+        let notification: Message = serde_json::from_str(
+            r#"{"jsonrpc":"2.0","method":"build.event","params":{"BuildStarted":{"total_nodes":2}}}"#,
+        )
+        .unwrap();
+        let Message::Notification(n) = notification else {
+            panic!("expected a notification");
+        };
+        let event: crate::dashboard::BuildEvent = serde_json::from_value(n.params).unwrap();
+        assert!(matches!(
+            event,
+            crate::dashboard::BuildEvent::BuildStarted { total_nodes: 2 }
+        ));
+
+        let error = serde_json::to_value(Response::new(
+            Value::from(1),
+            Err(RpcError::new(METHOD_NOT_FOUND, "no such method")),
+        ))
+        .unwrap();
+        assert!(error.get("result").is_none());
+        assert_eq!(error["error"]["code"], METHOD_NOT_FOUND);
+    }
+}
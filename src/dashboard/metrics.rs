@@ -42,6 +42,13 @@ pub enum BuildEvent {
         cache_hits: usize,
         executed_nodes: usize,
     },
+    /// Sent while COPY sources are hashed, before any node runs
+    HashingProgress {
+        files: u64,
+        total_files: u64,
+        bytes: u64,
+        current: String,
+    },
 }
 
 pub trait BuildObserver: Send + Sync {
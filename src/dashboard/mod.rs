@@ -1,5 +1,7 @@
 pub mod dag_ws;
 pub mod metrics;
+pub mod summary;
 
 pub use dag_ws::{BroadcastObserver, RemoteObserver};
 pub use metrics::{BuildEvent, BuildObserver, BuildStatus, NodeEvent};
+pub use summary::BuildSummary;
@@ -0,0 +1,396 @@
+//! End-of-build summary for the terminal: where the time and bytes went,
+//! which steps keep missing the cache, and how the build compares with the
+//! previous one.
+
+use crate::history::{BuildHistory, BuildTotals};
+use crate::plan::{format_bytes, format_duration};
+use crate::report::{BuildReport, NodeOutcome};
+use crate::sandbox::usage::ResourceUsage;
+use colored::*;
+
+/// Rows shown per table
+pub const DEFAULT_TOP_N: usize = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SummaryRow {
+    pub id: usize,
+    pub name: String,
+    /// Milliseconds for the slowest table, bytes for the largest
+    pub value: u64,
+}
+
+/// A step that ran, with what it cost.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageRow {
+    pub id: usize,
+    pub name: String,
+    pub usage: ResourceUsage,
+}
+
+/// A step that ran in many past builds instead of coming from the cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheOffender {
+    pub name: String,
+    pub runs: u32,
+    pub avg_duration_ms: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct BuildSummary {
+    pub slowest: Vec<SummaryRow>,
+    pub largest: Vec<SummaryRow>,
+    /// Steps that used the most CPU time
+    pub heaviest: Vec<UsageRow>,
+    pub offenders: Vec<CacheOffender>,
+    /// Artifacts over the large-artifact threshold, largest first
+    pub oversized: Vec<SummaryRow>,
+    /// Nodes that ran, counted by why they were dirty
+    pub reasons: std::collections::BTreeMap<String, usize>,
+    /// Builds in the history, this one included
+    pub builds: u32,
+    pub current: BuildTotals,
+    pub previous: Option<BuildTotals>,
+}
+
+impl BuildSummary {
+    /// Summarize `report`, keeping the `top` entries of each table. `history`
+    /// should already hold this build's node runs but not its totals, so that
+    /// `last_build` is still the previous build. Every artifact over
+    /// `large_threshold` bytes is listed, not just the `top`.
+    pub fn new(
+        report: &BuildReport,
+        history: &BuildHistory,
+        size_of: impl Fn(&str) -> Option<u64>,
+        top: usize,
+        large_threshold: Option<u64>,
+    ) -> Self {
+        let mut slowest: Vec<SummaryRow> = report
+            .nodes
+            .iter()
+            .filter(|n| matches!(n.outcome, NodeOutcome::Executed | NodeOutcome::Failed))
+            .filter(|n| n.duration_ms > 0)
+            .map(|n| SummaryRow {
+                id: n.id,
+                name: n.name.clone(),
+                value: n.duration_ms,
+            })
+            .collect();
+        slowest.sort_by(|a, b| b.value.cmp(&a.value).then(a.id.cmp(&b.id)));
+        slowest.truncate(top);
+
+        let mut largest: Vec<SummaryRow> = report
+            .nodes
+            .iter()
+            .filter(|n| matches!(n.outcome, NodeOutcome::Executed | NodeOutcome::Cached))
+            .filter_map(|n| {
+                size_of(&n.hash).map(|size| SummaryRow {
+                    id: n.id,
+                    name: n.name.clone(),
+                    value: size,
+                })
+            })
+            .collect();
+        largest.sort_by(|a, b| b.value.cmp(&a.value).then(a.id.cmp(&b.id)));
+        largest.truncate(top);
+
+        let mut heaviest: Vec<UsageRow> = report
+            .nodes
+            .iter()
+            .filter_map(|n| {
+                n.usage.map(|usage| UsageRow {
+                    id: n.id,
+                    name: n.name.clone(),
+                    usage,
+                })
+            })
+            .filter(|row| row.usage.cpu_ms() > 0 || row.usage.peak_rss_bytes > 0)
+            .collect();
+        heaviest.sort_by(|a, b| {
+            b.usage
+                .cpu_ms()
+                .cmp(&a.usage.cpu_ms())
+                .then(b.usage.peak_rss_bytes.cmp(&a.usage.peak_rss_bytes))
+                .then(a.id.cmp(&b.id))
+        });
+        heaviest.truncate(top);
+
+        let oversized = large_threshold
+            .map(|threshold| {
+                report
+                    .large_artifacts(threshold)
+                    .into_iter()
+                    .map(|n| SummaryRow {
+                        id: n.id,
+                        name: n.name.clone(),
+                        value: n.artifact_bytes.unwrap_or_default(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // A step that ran once was just built for the first time
+        let mut offenders: Vec<CacheOffender> = history
+            .nodes
+            .iter()
+            .filter(|(_, h)| h.runs > 1)
+            .map(|(name, h)| CacheOffender {
+                name: name.clone(),
+                runs: h.runs,
+                avg_duration_ms: h.avg_duration_ms,
+            })
+            .collect();
+        offenders.sort_by(|a, b| {
+            b.runs
+                .cmp(&a.runs)
+                .then(b.avg_duration_ms.cmp(&a.avg_duration_ms))
+                .then(a.name.cmp(&b.name))
+        });
+        offenders.truncate(top);
+
+        Self {
+            slowest,
+            largest,
+            heaviest,
+            offenders,
+            oversized,
+            reasons: report.dirty_reasons(),
+            builds: history.builds + 1,
+            current: BuildTotals::from_report(report),
+            previous: history.last_build.clone(),
+        }
+    }
+
+    /// Change in wall-clock time against the previous build, in percent.
+    pub fn trend_percent(&self) -> Option<f64> {
+        let previous = self.previous.as_ref()?;
+        if previous.duration_ms == 0 {
+            return None;
+        }
+        Some(
+            (self.current.duration_ms as f64 - previous.duration_ms as f64) * 100.0
+                / previous.duration_ms as f64,
+        )
+    }
+
+    pub fn print(&self) {
+        println!("\n{}", "📊 Build Summary".bold().cyan());
+
+        if !self.slowest.is_empty() {
+            println!(
+                "\n{}",
+                format!("{:>4}  {:>10}  {}", "ID", "TIME", "SLOWEST").bold()
+            );
+            for row in &self.slowest {
+                println!(
+                    "{:>4}  {:>10}  {}",
+                    row.id,
+                    format_duration(row.value),
+                    row.name
+                );
+            }
+        }
+
+        if !self.largest.is_empty() {
+            println!(
+                "\n{}",
+                format!("{:>4}  {:>10}  {}", "ID", "SIZE", "LARGEST").bold()
+            );
+            for row in &self.largest {
+                println!(
+                    "{:>4}  {:>10}  {}",
+                    row.id,
+                    format_bytes(row.value),
+                    row.name
+                );
+            }
+        }
+
+        if !self.heaviest.is_empty() {
+            println!(
+                "\n{}",
+                format!(
+                    "{:>4}  {:>10}  {:>10}  {:>10}  {}",
+                    "ID", "CPU", "PEAK RSS", "IO", "HEAVIEST"
+                )
+                .bold()
+            );
+            for row in &self.heaviest {
+                println!(
+                    "{:>4}  {:>10}  {:>10}  {:>10}  {}",
+                    row.id,
+                    format_duration(row.usage.cpu_ms()),
+                    format_bytes(row.usage.peak_rss_bytes),
+                    format_bytes(row.usage.io_bytes()),
+                    row.name
+                );
+            }
+        }
+
+        if !self.offenders.is_empty() {
+            println!(
+                "\n{}",
+                format!("{:>4}  {:>10}  {}", "RUNS", "AVG TIME", "REBUILT MOST").bold()
+            );
+            for offender in &self.offenders {
+                println!(
+                    "{:>4}  {:>10}  {}",
+                    offender.runs,
+                    format_duration(offender.avg_duration_ms),
+                    offender.name
+                );
+            }
+        }
+
+        if !self.oversized.is_empty() {
+            println!(
+                "\n{}",
+                "⚠️  Large artifacts: every build stores, uploads and downloads these; \
+                 consider splitting the steps or cleaning up what they write"
+                    .yellow()
+            );
+            for row in &self.oversized {
+                println!(
+                    "{:>4}  {:>10}  {}",
+                    row.id,
+                    format_bytes(row.value),
+                    row.name
+                );
+            }
+        }
+
+        // A single group would only repeat the totals
+        if self.current.groups.len() > 1 {
+            println!(
+                "\n{}",
+                format!(
+                    "{:>5}  {:>7}  {:>10}  {:>10}  {}",
+                    "STEPS", "CACHED", "TIME", "SIZE", "GROUP"
+                )
+                .bold()
+            );
+            for group in &self.current.groups {
+                let before = self
+                    .previous
+                    .as_ref()
+                    .and_then(|p| p.group(&group.group))
+                    .map(|p| format!(" (was {})", format_duration(p.duration_ms)))
+                    .unwrap_or_default();
+                println!(
+                    "{:>5}  {:>6.0}%  {:>10}  {:>10}  {}{}",
+                    group.nodes,
+                    group.hit_rate() * 100.0,
+                    format_duration(group.duration_ms),
+                    format_bytes(group.artifact_bytes),
+                    group.group,
+                    before.dimmed()
+                );
+            }
+        }
+
+        if !self.reasons.is_empty() {
+            let reasons: Vec<String> = self
+                .reasons
+                .iter()
+                .map(|(reason, count)| format!("{} {}", count, reason.replace('_', " ")))
+                .collect();
+            println!("\n{} {}", "Rebuilt because:".bold(), reasons.join(", "));
+        }
+
+        let totals = format!(
+            "{} executed  |  {} cached  |  {}",
+            self.current.executed,
+            self.current.cached,
+            format_duration(self.current.duration_ms)
+        );
+        match (&self.previous, self.trend_percent()) {
+            (Some(previous), Some(pct)) => {
+                let trend = format!(
+                    "{} {:.0}% vs previous build ({}, {} executed)",
+                    if pct > 0.0 { "▲" } else { "▼" },
+                    pct.abs(),
+                    format_duration(previous.duration_ms),
+                    previous.executed
+                );
+                let trend = if pct > 10.0 {
+                    trend.red()
+                } else if pct < -10.0 {
+                    trend.green()
+                } else {
+                    trend.normal()
+                };
+                println!("\n{}  |  {}", totals, trend);
+            }
+            _ => println!("\n{}  |  first recorded build", totals),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::{dag, parser};
+    use crate::report::{CacheSource, NodeReport};
+
+    #[test]
+    fn test_summary_tables_and_trend() {
+        let mut graph = dag::build_graph_from_instructions(
+            parser::parse_dockerfile("FROM alpine\nRUN make\nRUN make test\n"),
+            std::path::PathBuf::from("."),
+        );
+        for node in &mut graph.nodes {
+            node.hash = format!("hash{}", node.id);
+        }
+        let mut report = BuildReport::default();
+        report.record(NodeReport {
+            outcome: NodeOutcome::Cached,
+            cache_source: CacheSource::Local,
+            ..NodeReport::skipped(&graph.nodes[0])
+        });
+        report.record(NodeReport {
+            outcome: NodeOutcome::Executed,
+            duration_ms: 3000,
+            artifact_bytes: Some(600 << 20),
+            ..NodeReport::skipped(&graph.nodes[1])
+        });
+        report.record(NodeReport {
+            outcome: NodeOutcome::Executed,
+            duration_ms: 500,
+            usage: Some(ResourceUsage {
+                cpu_user_ms: 1800,
+                peak_rss_bytes: 2 << 30,
+                ..Default::default()
+            }),
+            ..NodeReport::skipped(&graph.nodes[2])
+        });
+        report.finish(&graph);
+        report.total_duration_ms = 4000;
+
+        let mut history = BuildHistory::default();
+        history.record(&graph.nodes[1], 3000, None);
+        history.record(&graph.nodes[1], 3000, None);
+        history.record(&graph.nodes[2], 500, None);
+        history.record_build(BuildTotals {
+            duration_ms: 2000,
+            executed: 1,
+            cached: 2,
+            ..Default::default()
+        });
+
+        let sizes = |hash: &str| (hash == "hash0").then_some(10 << 20);
+        let summary = BuildSummary::new(&report, &history, sizes, 1, Some(512 << 20));
+
+        assert_eq!(summary.slowest.len(), 1);
+        assert_eq!(summary.slowest[0].id, 1);
+        assert_eq!(summary.largest[0].id, 0);
+        // Ranked by what it used, not how long it took
+        assert_eq!(summary.heaviest.len(), 1);
+        assert_eq!(summary.heaviest[0].id, 2);
+        assert_eq!(summary.offenders.len(), 1);
+        assert_eq!(summary.offenders[0].runs, 2);
+        assert_eq!(summary.oversized.len(), 1);
+        assert_eq!(summary.oversized[0].id, 1);
+        assert_eq!(summary.builds, 2);
+        assert_eq!(summary.current.executed, 2);
+        assert_eq!(summary.trend_percent(), Some(100.0));
+    }
+}
@@ -0,0 +1,307 @@
+//! ADD sources: URLs downloaded while the graph is prepared and keyed by their
+//! content, and local tar archives extracted into the destination.
+
+use crate::cache::HybridCache;
+use crate::graph::{BuildGraph, Node, NodeKind};
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+/// Prefix of the source digest of downloaded sources
+const SHA256_PREFIX: &str = "sha256:";
+
+/// Whether an ADD source is a URL rather than a path in the context.
+pub fn is_url(src: &str) -> bool {
+    src.starts_with("http://") || src.starts_with("https://")
+}
+
+/// Cache key the content of a download is stored under, derived from its
+/// SHA-256.
+pub fn cache_key(sha256: &str) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"add\0");
+    hasher.update(sha256.to_ascii_lowercase().as_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Hex SHA-256 a `--checksum` pins, if it is one this understands.
+fn pinned_sha256(checksum: Option<&str>) -> Result<Option<String>> {
+    let Some(checksum) = checksum else {
+        return Ok(None);
+    };
+    match checksum.strip_prefix(SHA256_PREFIX) {
+        Some(hex) if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Ok(Some(hex.to_ascii_lowercase()))
+        }
+        _ => anyhow::bail!(
+            "Unsupported ADD --checksum {} (expected sha256:<64 hex digits>)",
+            checksum
+        ),
+    }
+}
+
+/// Download every remote ADD source, store it in the cache and record its
+/// digest as the node's source hash. Returns how many were downloaded.
+pub async fn fetch_remote_sources(graph: &mut BuildGraph, cache: &HybridCache) -> Result<usize> {
+    let mut downloaded = 0;
+    for node in &mut graph.nodes {
+        let NodeKind::Add {
+            ref src,
+            ref checksum,
+            ..
+        } = node.kind
+        else {
+            continue;
+        };
+        if !is_url(src) {
+            continue;
+        }
+        let pinned = pinned_sha256(checksum.as_deref())
+            .with_context(|| format!("{}{}", node.location_prefix(), node.name))?;
+
+        let sha256 = match pinned {
+            Some(ref sha256) if cache.get_artifact(&cache_key(sha256)).await?.is_some() => {
+                sha256.clone()
+            }
+            _ => {
+                let data = fetch(src)
+                    .await
+                    .with_context(|| format!("{}ADD {}", node.location_prefix(), src))?;
+                let actual = hex::encode(Sha256::digest(&data));
+                if let Some(expected) = pinned {
+                    if actual != expected {
+                        anyhow::bail!(
+                            "{}ADD {} has SHA-256 {}, but --checksum expects {}",
+                            node.location_prefix(),
+                            src,
+                            actual,
+                            expected
+                        );
+                    }
+                }
+                cache
+                    .put_artifact_named(&cache_key(&actual), &file_name(src, None), &data)
+                    .await?;
+                downloaded += 1;
+                actual
+            }
+        };
+        node.metadata.source_content_hash = Some(format!("{}{}", SHA256_PREFIX, sha256));
+    }
+    Ok(downloaded)
+}
+
+async fn fetch(url: &str) -> Result<Vec<u8>> {
+    let resp = reqwest::get(url)
+        .await
+        .with_context(|| format!("Failed to download {}", url))?;
+    if !resp.status().is_success() {
+        anyhow::bail!("Failed to download {}: {}", url, resp.status());
+    }
+    Ok(resp.bytes().await?.to_vec())
+}
+
+/// Name the added file gets: the last segment of `dst`, or of the URL when
+/// `dst` is a directory.
+fn file_name(src: &str, dst: Option<&Path>) -> String {
+    let from_dst = dst
+        .filter(|d| !d.to_string_lossy().ends_with('/'))
+        .and_then(|d| d.file_name())
+        .map(|n| n.to_string_lossy().to_string());
+    from_dst.unwrap_or_else(|| {
+        let url = src.split(['?', '#']).next().unwrap_or_default();
+        // The last segment of the path; the host is no file name
+        let path = match url.split_once("://") {
+            Some((_, rest)) => rest.split_once('/').map_or("", |(_, path)| path),
+            None => url,
+        };
+        path.trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .filter(|n| !n.is_empty())
+            .unwrap_or("download")
+            .to_string()
+    })
+}
+
+/// Whether `data` starts like a tar archive, gzip-compressed or not.
+fn archive_kind(data: &[u8]) -> Option<Archive> {
+    if data.starts_with(&[0x1f, 0x8b]) {
+        Some(Archive::Gzip)
+    } else if data.len() > 262 && &data[257..262] == b"ustar" {
+        Some(Archive::Tar)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Archive {
+    Tar,
+    Gzip,
+}
+
+/// Store what an ADD node puts in the image in the content store and return
+/// the tree's root digest, like COPY does.
+pub async fn store(node: &Node, cache: &HybridCache) -> Result<String> {
+    let NodeKind::Add {
+        ref src, ref dst, ..
+    } = node.kind
+    else {
+        anyhow::bail!("{} is not an ADD step", node.name);
+    };
+
+    let staging =
+        std::env::temp_dir().join(format!("memobuild-add-{}", uuid::Uuid::new_v4().simple()));
+    let result = store_in(node, src, dst, cache, &staging).await;
+    let _ = std::fs::remove_dir_all(&staging);
+    result.with_context(|| format!("{}{}", node.location_prefix(), node.name))
+}
+
+async fn store_in(
+    node: &Node,
+    src: &str,
+    dst: &Path,
+    cache: &HybridCache,
+    staging: &Path,
+) -> Result<String> {
+    if is_url(src) {
+        let sha256 = node
+            .metadata
+            .source_content_hash
+            .as_deref()
+            .and_then(|d| d.strip_prefix(SHA256_PREFIX))
+            .with_context(|| format!("{} was not downloaded before the build", src))?;
+        let data = cache
+            .get_artifact(&cache_key(sha256))
+            .await?
+            .with_context(|| format!("Download of {} is missing from the cache", src))?;
+        std::fs::create_dir_all(staging)?;
+        std::fs::write(staging.join(file_name(src, Some(dst))), data)?;
+        return stage_tree(cache, staging).await;
+    }
+
+    let path = node
+        .source_path
+        .as_ref()
+        .with_context(|| format!("{} has no source path", node.name))?;
+    match local_archive(path)? {
+        Some(kind) => {
+            extract(path, kind, staging)?;
+            stage_tree(cache, staging).await
+        }
+        None => stage_tree(cache, path).await,
+    }
+}
+
+async fn stage_tree(cache: &HybridCache, path: &Path) -> Result<String> {
+    let stored = cache
+        .put_tree(path, &crate::hasher::IgnoreRules::empty())
+        .await?;
+    Ok(stored.root)
+}
+
+/// Whether ADD extracts the context file at `path` into its destination
+/// rather than adding it as a file.
+pub fn extracts(path: &Path) -> bool {
+    matches!(local_archive(path), Ok(Some(_)))
+}
+
+fn local_archive(path: &Path) -> Result<Option<Archive>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    Ok(archive_kind(&read_head(path)?))
+}
+
+/// First bytes of a file, enough to recognise an archive.
+fn read_head(path: &Path) -> Result<Vec<u8>> {
+    use std::io::Read;
+    let mut head = Vec::with_capacity(512);
+    std::fs::File::open(path)?
+        .take(512)
+        .read_to_end(&mut head)?;
+    Ok(head)
+}
+
+fn extract(path: &Path, kind: Archive, dest: &Path) -> Result<()> {
+    std::fs::create_dir_all(dest)?;
+    let file = std::fs::File::open(path)?;
+    match kind {
+        Archive::Gzip => tar::Archive::new(flate2::read::GzDecoder::new(file)).unpack(dest),
+        Archive::Tar => tar::Archive::new(file).unpack(dest),
+    }
+    .with_context(|| format!("Failed to extract {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_sources() {
+        assert!(is_url("https://example.com/a.tgz"));
+        assert!(!is_url("vendor/a.tgz"));
+        assert_eq!(
+            file_name(
+                "https://example.com/dl/tool.tar.gz?sig=1",
+                Some(Path::new("/opt/"))
+            ),
+            "tool.tar.gz"
+        );
+        assert_eq!(
+            file_name("https://example.com/dl/tool", Some(Path::new("/usr/bin/t"))),
+            "t"
+        );
+        assert_eq!(file_name("https://example.com/", None), "download");
+        let instructions = crate::docker::parser::parse_dockerfile(
+            "ADD --chown=app --checksum=sha256:ab https://example.com/t.tgz /opt/\n",
+        );
+        assert!(matches!(
+            &instructions[0],
+            crate::docker::parser::Instruction::Add(src, dst, Some(sum))
+                if src == "https://example.com/t.tgz" && dst == "/opt/" && sum == "sha256:ab"
+        ));
+        assert!(pinned_sha256(Some("md5:abc")).is_err());
+        assert_eq!(
+            pinned_sha256(Some(&format!("sha256:{}", "A".repeat(64)))).unwrap(),
+            Some("a".repeat(64))
+        );
+
+        let mut tarball = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tarball
+            .append_data(&mut header, "pkg/hello.txt", &b"hello"[..])
+            .unwrap();
+        let gz = tarball.into_inner().unwrap().finish().unwrap();
+        assert_eq!(archive_kind(&gz), Some(Archive::Gzip));
+        assert_eq!(archive_kind(b"PK\x03\x04 zip"), None);
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let archive = dir.path().join("pkg.tgz");
+        std::fs::write(&archive, &gz).unwrap();
+        let out = dir.path().join("out");
+        extract(&archive, Archive::Gzip, &out).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(out.join("pkg/hello.txt")).unwrap(),
+            "hello"
+        );
+
+        // Extracted into /opt, so a later copy under it is ordered after it
+        assert!(extracts(&archive));
+        assert!(!extracts(&out));
+        let graph = crate::docker::dag::build_graph_from_instructions(
+            crate::docker::parser::parse_dockerfile(
+                "FROM alpine\nADD pkg.tgz /opt/\nCOPY hello.txt /opt/pkg/hello.txt\n",
+            ),
+            dir.path().to_path_buf(),
+        );
+        assert!(graph.nodes[2].deps.contains(&1));
+    }
+}
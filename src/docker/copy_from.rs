@@ -0,0 +1,525 @@
+//! COPY --from=<image> sources, extracted from the image into the content store
+//! while the graph is prepared.
+
+use crate::cache::HybridCache;
+use crate::docker::resolve::{DigestSource, ImageRef, RegistryDigestSource};
+use crate::export::registry::RegistryClient;
+use crate::graph::{BuildGraph, Node, NodeKind};
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+/// Prefix of whiteout entries, which delete a file of a lower layer
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Whiteout that empties the directory it is in
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Cache key the tree digest of `src` in the image with `digest` is stored
+/// under.
+pub fn cache_key(digest: &str, src: &str) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"copy-from\0");
+    hasher.update(digest.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(normalize(src).as_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Where an image's files are read from.
+enum Origin {
+    Registry(ImageRef),
+    Docker,
+}
+
+/// Extract the source of every COPY --from=<image> step, store it in the
+/// content store and record its tree digest as the node's source hash.
+/// Returns how many were extracted rather than found in the cache.
+pub async fn fetch_image_sources(graph: &mut BuildGraph, cache: &HybridCache) -> Result<usize> {
+    let mut extracted = 0;
+    for node in &mut graph.nodes {
+        let NodeKind::CopyFrom {
+            ref image, ref src, ..
+        } = node.kind
+        else {
+            continue;
+        };
+        let context = || format!("{}COPY --from={}", node.location_prefix(), image);
+        let src = src.to_string_lossy().to_string();
+
+        let image_name = image.clone();
+        let (digest, origin) = tokio::task::spawn_blocking(move || resolve(&image_name))
+            .await?
+            .with_context(context)?;
+        let key = cache_key(&digest, &src);
+
+        let root = match cache.get_artifact(&key).await? {
+            Some(root) => String::from_utf8(root).context("Corrupt COPY --from cache entry")?,
+            None => {
+                let staging = std::env::temp_dir().join(format!(
+                    "memobuild-copy-from-{}",
+                    uuid::Uuid::new_v4().simple()
+                ));
+                let result = extract_and_store(origin, image, &src, &staging, cache).await;
+                let _ = std::fs::remove_dir_all(&staging);
+                let root = result.with_context(context)?;
+                cache.put_artifact(&key, root.as_bytes()).await?;
+                extracted += 1;
+                root
+            }
+        };
+        node.metadata.source_content_hash = Some(root);
+    }
+    Ok(extracted)
+}
+
+/// The artifact of a COPY --from=<image> node: the digest of the tree
+/// [`fetch_image_sources`] stored, like COPY from the context.
+pub fn store(node: &Node) -> Result<String> {
+    let NodeKind::CopyFrom { ref image, .. } = node.kind else {
+        anyhow::bail!("{} is not a COPY --from=<image> step", node.name);
+    };
+    node.metadata
+        .source_content_hash
+        .clone()
+        .with_context(|| format!("{} was not extracted before the build", image))
+}
+
+/// Digest of `image`, and where its files can be read. A digest pinned in
+/// the reference is used as is; an image the registry cannot resolve is
+/// looked for in the local Docker daemon.
+fn resolve(image: &str) -> Result<(String, Origin)> {
+    let image_ref = ImageRef::parse(image);
+    if let Some(digest) = image_ref.digest.clone() {
+        return Ok((digest, Origin::Registry(image_ref)));
+    }
+    match RegistryDigestSource.resolve(&image_ref) {
+        Ok(digest) => Ok((digest, Origin::Registry(image_ref))),
+        Err(err) => match docker_image_id(image) {
+            Ok(id) => Ok((id, Origin::Docker)),
+            Err(_) => Err(err.context(format!("Failed to resolve image {}", image))),
+        },
+    }
+}
+
+async fn extract_and_store(
+    origin: Origin,
+    image: &str,
+    src: &str,
+    staging: &Path,
+    cache: &HybridCache,
+) -> Result<String> {
+    let (image, src_owned, staging_owned) =
+        (image.to_string(), src.to_string(), staging.to_path_buf());
+    let tree = tokio::task::spawn_blocking(move || match origin {
+        Origin::Registry(image_ref) => {
+            extract_from_registry(&image_ref, &src_owned, &staging_owned)
+        }
+        Origin::Docker => extract_with_docker(&image, &src_owned, &staging_owned),
+    })
+    .await??;
+    let stored = cache
+        .put_tree(&tree, &crate::hasher::IgnoreRules::empty())
+        .await?;
+    Ok(stored.root)
+}
+
+/// `src` as a path inside the image: no leading `/` or `./`, no trailing
+/// `/`; empty for the root.
+fn normalize(src: &str) -> String {
+    src.trim_start_matches("./")
+        .trim_start_matches('/')
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// Whether the layer entry at `entry` (normalized) is `wanted` or inside
+/// it; every entry is for the root.
+fn is_wanted(entry: &str, wanted: &str) -> bool {
+    wanted.is_empty()
+        || entry == wanted
+        || entry
+            .strip_prefix(wanted)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// What to store once `wanted` is unpacked under `staging`: a directory's
+/// contents, or a directory holding the file under its name, as COPY does.
+fn staged_tree(wanted: &str, staging: &Path) -> PathBuf {
+    let path = staging.join(wanted);
+    match std::fs::symlink_metadata(&path) {
+        Ok(meta) if meta.is_dir() => path,
+        _ => path.parent().unwrap_or(staging).to_path_buf(),
+    }
+}
+
+/// Refuse layer paths that are absolute or climb out with `..`.
+fn check_entry_path(path: &Path) -> Result<()> {
+    let escapes = path
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+    if escapes {
+        anyhow::bail!("Layer entry {} points outside the image", path.display());
+    }
+    Ok(())
+}
+
+/// Refuse `path` if anything from `staging` down to it, `path` included, is
+/// a symlink: a layer could point one outside `staging` and have later
+/// entries write or delete through it.
+fn check_no_symlinks(staging: &Path, path: &Path) -> Result<()> {
+    let Ok(rel) = path.strip_prefix(staging) else {
+        anyhow::bail!("{} is outside {}", path.display(), staging.display());
+    };
+    let mut current = staging.to_path_buf();
+    for component in rel.components() {
+        current.push(component);
+        if std::fs::symlink_metadata(&current).is_ok_and(|m| m.file_type().is_symlink()) {
+            anyhow::bail!(
+                "Layer entry {} is reached through the symlink {}",
+                rel.display(),
+                current.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// [`check_no_symlinks`] for the directory `path` is in.
+fn check_parents(staging: &Path, path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) if parent != staging => check_no_symlinks(staging, parent),
+        _ => Ok(()),
+    }
+}
+
+/// Apply every layer of the image to `staging`, keeping only `src`, and
+/// return the path to store.
+fn extract_from_registry(image: &ImageRef, src: &str, staging: &Path) -> Result<PathBuf> {
+    let client = RegistryClient::new(image.api_host(), &image.repository);
+    let wanted = normalize(src);
+    std::fs::create_dir_all(staging)?;
+    let mut found = false;
+    for digest in client.layer_digests(image.reference())? {
+        let blob = client.open_blob(&digest)?;
+        found |= apply_layer(blob, &wanted, staging)
+            .with_context(|| format!("Failed to read layer {}", digest))?;
+    }
+    if !found {
+        anyhow::bail!("{} does not exist in the image", src);
+    }
+    Ok(staged_tree(&wanted, staging))
+}
+
+/// Unpack the entries of one layer (a tar, gzip-compressed or not) that fall
+/// under `wanted` into `staging`, at their paths in the image, honouring
+/// whiteouts. Returns whether any entry was under `wanted`.
+fn apply_layer(layer: impl Read, wanted: &str, staging: &Path) -> Result<bool> {
+    let mut layer = std::io::BufReader::new(layer);
+    let gzip = {
+        use std::io::BufRead;
+        layer.fill_buf()?.starts_with(&[0x1f, 0x8b])
+    };
+    if gzip {
+        let layer = flate2::read::GzDecoder::new(layer);
+        apply_entries(tar::Archive::new(layer), wanted, staging)
+    } else {
+        apply_entries(tar::Archive::new(layer), wanted, staging)
+    }
+}
+
+fn apply_entries<R: Read>(
+    mut archive: tar::Archive<R>,
+    wanted: &str,
+    staging: &Path,
+) -> Result<bool> {
+    std::fs::create_dir_all(staging)?;
+    let mut found = false;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let raw = entry.path()?.into_owned();
+        check_entry_path(&raw)?;
+        let path = normalize(&raw.to_string_lossy());
+        let (dir, name) = match path.rsplit_once('/') {
+            Some((dir, name)) => (dir, name),
+            None => ("", path.as_str()),
+        };
+
+        if name == OPAQUE_WHITEOUT {
+            let target = staging.join(dir);
+            if is_wanted(dir, wanted) && target.is_dir() {
+                check_no_symlinks(staging, &target)?;
+                for child in std::fs::read_dir(&target)? {
+                    remove(&child?.path())?;
+                }
+            }
+            continue;
+        }
+        if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            let hidden = if dir.is_empty() {
+                hidden.to_string()
+            } else {
+                format!("{}/{}", dir, hidden)
+            };
+            if is_wanted(&hidden, wanted) {
+                let target = staging.join(&hidden);
+                check_parents(staging, &target)?;
+                remove(&target)?;
+            }
+            continue;
+        }
+
+        if !is_wanted(&path, wanted) {
+            continue;
+        }
+        found = true;
+        let target = staging.join(&path);
+        check_parents(staging, &target)?;
+        if !entry.header().entry_type().is_dir() {
+            // A later layer replaces the file, whatever it was
+            remove(&target)?;
+        }
+        if entry.header().entry_type().is_hard_link() {
+            let Some(link) = entry.link_name()?.map(|l| l.into_owned()) else {
+                continue;
+            };
+            check_entry_path(&link)?;
+            let link = normalize(&link.to_string_lossy());
+            let linked = staging.join(&link);
+            if is_wanted(&link, wanted) && linked.is_file() {
+                check_no_symlinks(staging, &linked)?;
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::copy(linked, &target)?;
+            }
+            continue;
+        }
+        if !entry.unpack_in(staging)? {
+            anyhow::bail!("Layer entry {} points outside the image", path);
+        }
+    }
+    Ok(found)
+}
+
+fn remove(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(path)?,
+        Ok(_) => std::fs::remove_file(path)?,
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+fn docker() -> Command {
+    Command::new("docker")
+}
+
+/// Run docker with `args`, returning its trimmed stdout.
+fn run_docker(args: &[&str]) -> Result<String> {
+    let output = docker()
+        .args(args)
+        .output()
+        .context("Failed to run docker")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "docker {} failed: {}",
+            args.first().copied().unwrap_or_default(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Id of `image` in the local Docker daemon, pulling it if it is missing.
+fn docker_image_id(image: &str) -> Result<String> {
+    let inspect = ["image", "inspect", "--format", "{{.Id}}", image];
+    run_docker(&inspect).or_else(|_| {
+        run_docker(&["pull", "--quiet", image])?;
+        run_docker(&inspect)
+    })
+}
+
+/// Copy `src` out of a container created from `image` and return the path
+/// to store.
+fn extract_with_docker(image: &str, src: &str, staging: &Path) -> Result<PathBuf> {
+    let container = run_docker(&["create", image])?;
+    let result = (|| {
+        std::fs::create_dir_all(staging)?;
+        let name = Path::new(&normalize(src))
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "rootfs".to_string());
+        let target = staging.join(name);
+        run_docker(&[
+            "cp",
+            &format!("{}:{}", container, src),
+            &target.to_string_lossy(),
+        ])?;
+        Ok(if target.is_dir() {
+            target
+        } else {
+            staging.to_path_buf()
+        })
+    })();
+    let _ = run_docker(&["rm", "--force", &container]);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(entries: &[(&str, Option<&[u8]>)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            match data {
+                Some(data) => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_size(data.len() as u64);
+                    header.set_mode(0o644);
+                    header.set_cksum();
+                    builder.append_data(&mut header, path, *data).unwrap();
+                }
+                None => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_size(0);
+                    header.set_mode(0o755);
+                    header.set_cksum();
+                    builder.append_data(&mut header, path, &[][..]).unwrap();
+                }
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_apply_layers() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let base = layer(&[
+            ("usr/", None),
+            ("usr/local/go/", None),
+            ("usr/local/go/bin/go", Some(b"go")),
+            ("usr/local/go/VERSION", Some(b"1.21")),
+            ("usr/local/go/old.txt", Some(b"old")),
+            ("etc/passwd", Some(b"root")),
+        ]);
+        let upper = layer(&[
+            ("usr/local/go/VERSION", Some(b"1.22")),
+            ("usr/local/go/.wh.old.txt", Some(b"")),
+        ]);
+
+        let staging = dir.path().join("go");
+        assert!(apply_layer(&base[..], "usr/local/go", &staging).unwrap());
+        assert!(apply_layer(&upper[..], "usr/local/go", &staging).unwrap());
+        let tree = staged_tree("usr/local/go", &staging);
+        assert_eq!(tree, staging.join("usr/local/go"));
+        assert_eq!(std::fs::read(tree.join("bin/go")).unwrap(), b"go");
+        assert_eq!(std::fs::read(tree.join("VERSION")).unwrap(), b"1.22");
+        assert!(!tree.join("old.txt").exists());
+        assert!(!staging.join("passwd").exists());
+
+        let file = dir.path().join("passwd");
+        assert!(apply_layer(&base[..], "etc/passwd", &file).unwrap());
+        let tree = staged_tree("etc/passwd", &file);
+        assert_eq!(tree, file.join("etc"));
+        assert_eq!(std::fs::read_dir(&tree).unwrap().count(), 1);
+        assert_eq!(std::fs::read(tree.join("passwd")).unwrap(), b"root");
+        assert!(!apply_layer(&upper[..], "opt", &dir.path().join("none")).unwrap());
+    }
+
+    /// A tar entry with `path` written as is, which `tar::Builder` refuses
+    /// for paths with `..`.
+    fn raw_entry(
+        builder: &mut tar::Builder<Vec<u8>>,
+        path: &str,
+        kind: tar::EntryType,
+        link: Option<&str>,
+    ) {
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_entry_type(kind);
+        if let Some(link) = link {
+            header.set_link_name(link).unwrap();
+        }
+        header.set_size(0);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append(&header, &[][..]).unwrap();
+    }
+
+    #[test]
+    fn test_layers_cannot_escape_staging() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let outside = dir.path().join("outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("victim"), "keep").unwrap();
+        let staging = dir.path().join("staging");
+
+        let mut climbing = tar::Builder::new(Vec::new());
+        raw_entry(
+            &mut climbing,
+            "opt/../../outside/escaped",
+            tar::EntryType::Regular,
+            None,
+        );
+        let climbing = climbing.into_inner().unwrap();
+        assert!(apply_layer(&climbing[..], "", &staging).is_err());
+        assert!(!outside.join("escaped").exists());
+
+        // A symlink to the outside, then a whiteout through it
+        let mut whiteout = tar::Builder::new(Vec::new());
+        raw_entry(
+            &mut whiteout,
+            "opt/link",
+            tar::EntryType::Symlink,
+            Some(outside.to_str().unwrap()),
+        );
+        raw_entry(
+            &mut whiteout,
+            "opt/link/.wh.victim",
+            tar::EntryType::Regular,
+            None,
+        );
+        let whiteout = whiteout.into_inner().unwrap();
+        assert!(apply_layer(&whiteout[..], "opt", &staging).is_err());
+        assert!(outside.join("victim").exists());
+
+        let mut writing = tar::Builder::new(Vec::new());
+        raw_entry(
+            &mut writing,
+            "opt/link/escaped",
+            tar::EntryType::Regular,
+            None,
+        );
+        let writing = writing.into_inner().unwrap();
+        assert!(apply_layer(&writing[..], "opt", &staging).is_err());
+        assert!(!outside.join("escaped").exists());
+    }
+
+    #[test]
+    fn test_copy_from_parsing() {
+        use crate::docker::parser::{parse_dockerfile, Instruction};
+
+        let instructions = parse_dockerfile(
+            "FROM golang:1.22 AS builder\n\
+             FROM alpine\n\
+             COPY --from=builder /out /out\n\
+             COPY --from=0 /out /out\n\
+             COPY --chown=app --from=ghcr.io/org/tools:1.0 /usr/bin/tool /usr/bin/\n",
+        );
+        assert!(matches!(&instructions[2], Instruction::Copy(..)));
+        assert!(matches!(&instructions[3], Instruction::Copy(..)));
+        assert!(matches!(
+            &instructions[4],
+            Instruction::CopyFrom(image, src, dst)
+                if image == "ghcr.io/org/tools:1.0" && src == "/usr/bin/tool" && dst == "/usr/bin/"
+        ));
+        assert_eq!(
+            cache_key("sha256:ab", "/opt/"),
+            cache_key("sha256:ab", "opt")
+        );
+        assert_ne!(cache_key("sha256:ab", "opt"), cache_key("sha256:cd", "opt"));
+    }
+}
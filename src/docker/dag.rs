@@ -1,7 +1,7 @@
-use crate::docker::parser::Instruction;
+use crate::docker::parser::{Instruction, Span, Spanned};
 use crate::graph::{BuildGraph, Node, NodeMetadata};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 /// Convert a flat list of Dockerfile instructions into a dependency graph.
 /// Supports DAG construction with conditional branching and smart dependency tracking.
@@ -9,24 +9,89 @@ use std::path::PathBuf;
 /// Key features:
 /// - COPY nodes create dependencies on source files
 /// - RUN commands depend on preceding COPY operations for their sources
+/// - COPY/ADD steps only depend on the last step that carries state (RUN,
+///   ENV, WORKDIR, ...) and on earlier copies to overlapping paths, so
+///   independent copies run in parallel
 /// - Multi-stage builds and conditional branching support
 /// - Content-addressed identities for incremental builds
 pub fn build_graph_from_instructions(
     instructions: Vec<Instruction>,
     project_root: PathBuf,
 ) -> BuildGraph {
+    build_graph(
+        instructions.into_iter().map(|i| (i, None)).collect(),
+        project_root,
+    )
+}
+
+/// Same as [`build_graph_from_instructions`], but records each instruction's
+/// source location and `# memobuild:` directives in the node metadata.
+pub fn build_graph_from_spanned(
+    instructions: Vec<Spanned<Instruction>>,
+    project_root: PathBuf,
+) -> BuildGraph {
+    let directives: Vec<_> = instructions.iter().map(|s| s.directives.clone()).collect();
+    let mut graph = build_graph(
+        instructions
+            .into_iter()
+            .map(|s| (s.node, Some(s.span)))
+            .collect(),
+        project_root,
+    );
+
+    // One node per instruction, in order
+    for (node, directives) in graph.nodes.iter_mut().zip(directives) {
+        node.metadata.directives = directives;
+    }
+    assign_groups(&mut graph);
+    graph
+}
+
+/// Put every step in the group of its stage: the stage's `AS` name (or
+/// `group` directive), `stage <n>` for unnamed stages. A `group` directive
+/// on any other step moves just that step.
+fn assign_groups(graph: &mut BuildGraph) {
+    let mut stage: Option<String> = None;
+    let mut stages = 0;
+    for node in &mut graph.nodes {
+        let group = node
+            .metadata
+            .directives
+            .get(crate::docker::directives::GROUP)
+            .cloned();
+        if node.kind == crate::graph::NodeKind::From {
+            stage = Some(group.clone().unwrap_or_else(|| format!("stage {}", stages)));
+            stages += 1;
+        }
+        node.metadata.group = group.or_else(|| stage.clone());
+    }
+}
+
+fn build_graph(instructions: Vec<(Instruction, Option<Span>)>, project_root: PathBuf) -> BuildGraph {
     let mut nodes: Vec<Node> = Vec::new();
     let mut copy_sources: HashMap<String, usize> = HashMap::new(); // Track COPY operations by source
     let mut env_vars: HashMap<String, String> = HashMap::new(); // Track environment variables
     let mut _workdir: Option<String> = None; // Track current working directory
+    let mut shell: Option<Vec<String>> = None; // SHELL of the current stage
+
+    // Last step that carries state into the ones after it, and the file
+    // copies made since then, which are independent of each other
+    let mut barrier: Option<usize> = None;
+    let mut pending: Vec<(usize, String)> = Vec::new();
 
-    for (i, instr) in instructions.iter().enumerate() {
+    for (i, (instr, span)) in instructions.iter().enumerate() {
         let name = format!("{:?}", instr);
         let mut env = std::collections::HashMap::new();
-        let mut metadata = NodeMetadata::default();
+        let mut metadata = NodeMetadata {
+            span: span.clone(),
+            ..Default::default()
+        };
+        let mut pending_target = None;
 
         let (content, source_path, kind, deps, _parallelizable) = match instr {
             Instruction::From(img) => {
+                // A new stage starts with the default shell
+                shell = None;
                 // FROM nodes have no dependencies (base image)
                 (
                     format!("FROM {}", img),
@@ -38,8 +103,8 @@ pub fn build_graph_from_instructions(
             }
             Instruction::Workdir(dir) => {
                 _workdir = Some(dir.clone());
-                // WORKDIR depends on previous operations that might affect the filesystem
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                // WORKDIR depends on every filesystem operation since the last step
+                let deps = step_deps(barrier, &pending);
                 metadata.parallelizable = true; // WORKDIR operations can be parallelized if independent
                 (
                     format!("WORKDIR {}", dir),
@@ -60,8 +125,11 @@ pub fn build_graph_from_instructions(
                 // Track this COPY operation for potential RUN dependencies
                 copy_sources.insert(src.clone(), i);
 
-                // COPY depends on previous filesystem operations
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                // COPY depends on the last state-carrying step and on earlier
+                // copies it may overwrite, not on unrelated copies
+                let target = copy_target(src, dst, &project_root);
+                let deps = copy_deps(barrier, &pending, &target);
+                pending_target = Some(target);
 
                 metadata.parallelizable = true; // COPY operations can be parallelized
                 metadata.tags.push("copy".to_string());
@@ -77,9 +145,70 @@ pub fn build_graph_from_instructions(
                     true,
                 )
             }
+            Instruction::CopyFrom(image, src, dst) => {
+                // Extracted from the image and hashed, see docker::copy_from.
+                // Whether the path is a file there is unknown here, so the
+                // copy is ordered like a directory copy.
+                let target = copy_target(&format!("{}/", src), dst, &project_root);
+                let deps = copy_deps(barrier, &pending, &target);
+                pending_target = Some(target);
+
+                metadata.parallelizable = true;
+                metadata.tags.push("copy".to_string());
+
+                (
+                    format!("COPY --from={} {} {}", image, src, dst),
+                    None,
+                    crate::graph::NodeKind::CopyFrom {
+                        image: image.clone(),
+                        src: PathBuf::from(src),
+                        dst: PathBuf::from(dst),
+                    },
+                    deps,
+                    true,
+                )
+            }
+            Instruction::Add(src, dst, checksum) => {
+                // Remote sources are hashed once downloaded, see docker::add
+                let path = if crate::docker::add::is_url(src) {
+                    None
+                } else {
+                    copy_sources.insert(src.clone(), i);
+                    Some(project_root.join(src))
+                };
+
+                // A local tar is unpacked into `dst`, never written as a file
+                let target = match path {
+                    Some(ref path) if crate::docker::add::extracts(path) => {
+                        copy_target(&format!("{}/", src), dst, &project_root)
+                    }
+                    _ => copy_target(src, dst, &project_root),
+                };
+                let deps = copy_deps(barrier, &pending, &target);
+                pending_target = Some(target);
+
+                metadata.parallelizable = true;
+                metadata.tags.push("add".to_string());
+
+                let content = match checksum {
+                    Some(checksum) => format!("ADD --checksum={} {} {}", checksum, src, dst),
+                    None => format!("ADD {} {}", src, dst),
+                };
+                (
+                    content,
+                    path,
+                    crate::graph::NodeKind::Add {
+                        src: src.clone(),
+                        dst: PathBuf::from(dst),
+                        checksum: checksum.clone(),
+                    },
+                    deps,
+                    true,
+                )
+            }
             Instruction::Run(cmd) => {
                 // Analyze RUN command to determine dependencies
-                let mut deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let mut deps = step_deps(barrier, &pending);
 
                 // Check if RUN command references files that were copied
                 for (src_path, copy_idx) in &copy_sources {
@@ -109,7 +238,7 @@ pub fn build_graph_from_instructions(
                 env_vars.insert(key.clone(), value.clone());
 
                 // ENV operations can be parallelized if they don't conflict
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let deps = step_deps(barrier, &pending);
                 metadata.parallelizable = true;
                 metadata.tags.push("env".to_string());
 
@@ -122,7 +251,7 @@ pub fn build_graph_from_instructions(
                 )
             }
             Instruction::Cmd(cmd) => {
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let deps = step_deps(barrier, &pending);
                 metadata.parallelizable = true;
                 metadata.tags.push("cmd".to_string());
 
@@ -134,8 +263,33 @@ pub fn build_graph_from_instructions(
                     true,
                 )
             }
+            Instruction::Shell(words) => {
+                shell = Some(words.clone());
+                let deps = step_deps(barrier, &pending);
+                metadata.tags.push("shell".to_string());
+
+                (
+                    format!("SHELL {}", serde_json::to_string(words).unwrap_or_default()),
+                    None,
+                    crate::graph::NodeKind::Other,
+                    deps,
+                    true,
+                )
+            }
+            Instruction::Healthcheck(args) => {
+                let deps = step_deps(barrier, &pending);
+                metadata.tags.push(crate::docker::healthcheck::TAG.to_string());
+
+                (
+                    format!("HEALTHCHECK {}", args),
+                    None,
+                    crate::graph::NodeKind::Other,
+                    deps,
+                    true,
+                )
+            }
             Instruction::Git(url, target) => {
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let deps = step_deps(barrier, &pending);
                 metadata.parallelizable = true;
                 metadata.tags.push("git".to_string());
 
@@ -151,7 +305,7 @@ pub fn build_graph_from_instructions(
                 )
             }
             Instruction::RunExtend(cmd, parallelizable) => {
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let deps = step_deps(barrier, &pending);
                 metadata.parallelizable = *parallelizable;
                 metadata.tags.push("extension".to_string());
                 metadata.tags.push("run-extend".to_string());
@@ -168,7 +322,9 @@ pub fn build_graph_from_instructions(
                 )
             }
             Instruction::CopyExtend(src, dst, tags) => {
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let target = copy_target(src, dst, &project_root);
+                let deps = copy_deps(barrier, &pending, &target);
+                pending_target = Some(target);
                 metadata.parallelizable = true;
                 metadata.tags.extend(tags.clone());
                 metadata.tags.push("extension".to_string());
@@ -192,7 +348,7 @@ pub fn build_graph_from_instructions(
                 )
             }
             Instruction::Hook(name, params) => {
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let deps = step_deps(barrier, &pending);
                 metadata.parallelizable = false; // Hooks execute sequentially by default
                 metadata.tags.push("hook".to_string());
 
@@ -207,8 +363,41 @@ pub fn build_graph_from_instructions(
                     false,
                 )
             }
+            Instruction::Include(path) => {
+                // Unexpanded include (see docker::include); keeps ordering but does nothing
+                let deps = step_deps(barrier, &pending);
+                metadata.tags.push("include".to_string());
+
+                (
+                    format!("INCLUDE {}", path),
+                    None,
+                    crate::graph::NodeKind::Other,
+                    deps,
+                    false,
+                )
+            }
+            Instruction::Build(context, dockerfile) => {
+                // The nested build's steps are merged in later, see docker::nested
+                let deps = step_deps(barrier, &pending);
+                metadata.tags.push("build".to_string());
+
+                let content = match dockerfile {
+                    Some(file) => format!("BUILD --file={} {}", file, context),
+                    None => format!("BUILD {}", context),
+                };
+                (
+                    content,
+                    None,
+                    crate::graph::NodeKind::Build {
+                        context: PathBuf::from(context),
+                        dockerfile: PathBuf::from(dockerfile.as_deref().unwrap_or("Dockerfile")),
+                    },
+                    deps,
+                    false,
+                )
+            }
             Instruction::Other(s) => {
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let deps = step_deps(barrier, &pending);
                 metadata.tags.push("other".to_string());
 
                 (
@@ -221,6 +410,33 @@ pub fn build_graph_from_instructions(
             }
         };
 
+        // Unknown instructions may turn out to be handled by a plugin
+        if matches!(
+            kind,
+            crate::graph::NodeKind::Run
+                | crate::graph::NodeKind::RunExtend { .. }
+                | crate::graph::NodeKind::CustomHook { .. }
+                | crate::graph::NodeKind::Other
+        ) {
+            metadata.build_env = env_vars.clone().into_iter().collect();
+        }
+        if matches!(
+            kind,
+            crate::graph::NodeKind::Run | crate::graph::NodeKind::RunExtend { .. }
+        ) {
+            metadata.shell = shell.clone();
+        }
+
+        // Every step but a file copy carries state (the filesystem, ENV,
+        // WORKDIR, SHELL) into what follows
+        match pending_target {
+            Some(target) => pending.push((i, target)),
+            None => {
+                barrier = Some(i);
+                pending.clear();
+            }
+        }
+
         let node = Node {
             id: i,
             name,
@@ -240,3 +456,70 @@ pub fn build_graph_from_instructions(
 
     BuildGraph { nodes }
 }
+
+/// Dependencies of a step that sees everything before it: the last
+/// state-carrying step and every copy made since.
+fn step_deps(barrier: Option<usize>, pending: &[(usize, String)]) -> Vec<usize> {
+    barrier
+        .into_iter()
+        .chain(pending.iter().map(|(id, _)| *id))
+        .collect()
+}
+
+/// Dependencies of a file copy to `target`: the last state-carrying step,
+/// plus copies since then to the same, an enclosing or an enclosed path, so
+/// overlapping copies still apply in order.
+fn copy_deps(barrier: Option<usize>, pending: &[(usize, String)], target: &str) -> Vec<usize> {
+    barrier
+        .into_iter()
+        .chain(
+            pending
+                .iter()
+                .filter(|(_, other)| paths_overlap(target, other))
+                .map(|(id, _)| *id),
+        )
+        .collect()
+}
+
+/// Path a copy writes: `dst` itself, or for a single file copied into a
+/// directory (`COPY package.json .`), the file inside it.
+fn copy_target(src: &str, dst: &str, project_root: &Path) -> String {
+    let dir = dst.trim_start_matches("./");
+    let into_dir = dst.ends_with('/') || dir == "." || dir.is_empty();
+    let dir = match dir.trim_end_matches('/') {
+        "" if dst.starts_with('/') => "/",
+        "" => ".",
+        d => d,
+    };
+
+    let file_name = src.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+    let is_file = !src.ends_with('/')
+        && !file_name.is_empty()
+        && !matches!(file_name, "." | "..")
+        && !file_name.contains('*')
+        && (crate::docker::add::is_url(src) || !project_root.join(src).is_dir());
+    if !into_dir || !is_file {
+        return dir.to_string();
+    }
+    match dir {
+        "." => file_name.to_string(),
+        "/" => format!("/{}", file_name),
+        d => format!("{}/{}", d, file_name),
+    }
+}
+
+/// Whether two copy targets may touch the same files. Relative targets are
+/// under WORKDIR, which may be anywhere, so they overlap any absolute one.
+fn paths_overlap(a: &str, b: &str) -> bool {
+    if a.starts_with('/') != b.starts_with('/') {
+        return true;
+    }
+    let within = |inner: &str, outer: &str| {
+        inner == outer
+            || matches!(outer, "." | "/")
+            || inner
+                .strip_prefix(outer)
+                .is_some_and(|rest| rest.starts_with('/'))
+    };
+    within(a, b) || within(b, a)
+}
@@ -0,0 +1,199 @@
+//! Per-step caching directives, read from the `# memobuild:` comments the parser
+//! keeps with each instruction.
+
+use crate::graph::{BuildGraph, NodeKind};
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::time::Duration;
+
+pub const NO_CACHE: &str = "no-cache";
+pub const INPUTS: &str = "inputs";
+pub const TIMEOUT: &str = "timeout";
+pub const REQUIRES: &str = "requires";
+pub const GROUP: &str = "group";
+pub const POOL: &str = "pool";
+pub const EXCLUDE: &str = "exclude";
+
+/// Items of a comma-separated directive value.
+pub fn list(value: &str) -> Vec<&str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+pub fn parse_timeout(value: &str) -> Result<Duration> {
+    let timeout = crate::docker::healthcheck::parse_duration(value)?;
+    if timeout.is_zero() {
+        anyhow::bail!("timeout must be longer than 0");
+    }
+    Ok(timeout)
+}
+
+/// Check the directives of every node and key the ones that declare inputs
+/// by those files. Must run before composite hashes are computed.
+pub fn apply(graph: &mut BuildGraph, context_dir: &Path) -> Result<()> {
+    for node in &mut graph.nodes {
+        let at = node.location_prefix();
+        if let Some(value) = node.metadata.directives.get(TIMEOUT) {
+            parse_timeout(value).with_context(|| format!("{}invalid timeout directive", at))?;
+        }
+        if let Some(value) = node.metadata.directives.get(POOL) {
+            value
+                .parse::<crate::execution::concurrency::Pool>()
+                .with_context(|| format!("{}invalid pool directive", at))?;
+        }
+        let excludes = node.metadata.excludes();
+        if !excludes.is_empty() {
+            if !matches!(node.kind, NodeKind::Copy { .. }) {
+                anyhow::bail!("{}exclude only applies to COPY from the context", at);
+            }
+            for pattern in excludes {
+                glob::Pattern::new(pattern)
+                    .with_context(|| format!("{}invalid exclude pattern {}", at, pattern))?;
+            }
+        }
+        // A task's inputs are part of its declaration
+        if let NodeKind::Task { ref inputs, .. } = node.kind {
+            if !inputs.is_empty() {
+                let patterns: Vec<&str> = inputs.iter().map(String::as_str).collect();
+                node.metadata.declared_inputs = Some(
+                    inputs_digest(context_dir, &patterns)
+                        .with_context(|| format!("{}invalid task inputs", at))?,
+                );
+            }
+            continue;
+        }
+        let Some(value) = node.metadata.directives.get(INPUTS) else {
+            continue;
+        };
+        if !matches!(node.kind, NodeKind::Run | NodeKind::RunExtend { .. }) {
+            anyhow::bail!("{}the inputs directive only applies to RUN", at);
+        }
+        node.metadata.declared_inputs = Some(
+            inputs_digest(context_dir, &list(value))
+                .with_context(|| format!("{}invalid inputs directive", at))?,
+        );
+    }
+    Ok(())
+}
+
+/// Digest of the files matching `patterns` under `context_dir`: their paths
+/// relative to it and their content. A pattern matching nothing is an error,
+/// since a misspelt one would leave the step keyed by nothing.
+pub fn inputs_digest(context_dir: &Path, patterns: &[&str]) -> Result<String> {
+    if patterns.is_empty() {
+        anyhow::bail!("no patterns given");
+    }
+    let mut files = BTreeSet::new();
+    for pattern in patterns {
+        let full = context_dir.join(pattern);
+        let mut matched = false;
+        for path in glob::glob(&full.to_string_lossy())
+            .with_context(|| format!("Invalid pattern {}", pattern))?
+        {
+            let path = path?;
+            if path.is_file() {
+                matched = true;
+                files.insert(path);
+            } else if path.is_dir() {
+                matched = true;
+                files.extend(crate::hasher::walker::walk_dir(
+                    &path,
+                    &crate::hasher::IgnoreRules::empty(),
+                ));
+            }
+        }
+        if !matched {
+            anyhow::bail!("{} matches no files in {}", pattern, context_dir.display());
+        }
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    for file in files {
+        let rel = file.strip_prefix(context_dir).unwrap_or(&file);
+        hasher.update(rel.to_string_lossy().as_bytes());
+        hasher.update(b"\0");
+        hasher.update(crate::hasher::file_hasher::hash_file(&file)?.as_bytes());
+        hasher.update(b"\n");
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::{dag, parser};
+
+    #[test]
+    fn test_caching_directives() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("src/lib")).unwrap();
+        std::fs::write(dir.path().join("src/lib/a.ts"), "a").unwrap();
+        std::fs::write(dir.path().join("package.json"), "{}").unwrap();
+        std::fs::write(dir.path().join("README.md"), "docs").unwrap();
+
+        let dockerfile = "FROM node\n\
+            # memobuild: inputs=src/**, package.json\n\
+            # memobuild: timeout=300s no-cache\n\
+            RUN npm run build\n";
+        let mut graph = dag::build_graph_from_spanned(
+            parser::parse_dockerfile_spanned(dockerfile, Path::new("Dockerfile")),
+            dir.path().to_path_buf(),
+        );
+        apply(&mut graph, dir.path()).unwrap();
+        let run = &graph.nodes[1];
+        assert!(run.metadata.no_cache());
+        assert_eq!(run.metadata.timeout(), Some(Duration::from_secs(300)));
+        let digest = run.metadata.declared_inputs.clone().unwrap();
+
+        // Files outside the inputs do not change the digest, files inside do
+        std::fs::write(dir.path().join("README.md"), "more docs").unwrap();
+        assert_eq!(
+            inputs_digest(dir.path(), &["src/**", "package.json"]).unwrap(),
+            digest
+        );
+        std::fs::write(dir.path().join("src/lib/a.ts"), "b").unwrap();
+        assert_ne!(
+            inputs_digest(dir.path(), &["src/**", "package.json"]).unwrap(),
+            digest
+        );
+
+        assert!(inputs_digest(dir.path(), &["srcs/**"]).is_err());
+        assert!(parse_timeout("0s").is_err());
+        assert!(parse_timeout("soon").is_err());
+    }
+
+    #[test]
+    fn test_copy_exclude() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let dockerfile = "FROM node\n\
+            # memobuild: exclude=coverage\n\
+            COPY --exclude=*.md --exclude=test . /app\n";
+        let mut graph = dag::build_graph_from_spanned(
+            parser::parse_dockerfile_spanned(dockerfile, Path::new("Dockerfile")),
+            dir.path().to_path_buf(),
+        );
+        apply(&mut graph, dir.path()).unwrap();
+        let copy = &graph.nodes[1];
+        assert_eq!(
+            copy.kind,
+            NodeKind::Copy {
+                src: ".".into(),
+                dst: "/app".into()
+            }
+        );
+        assert_eq!(copy.metadata.excludes(), vec!["coverage", "*.md", "test"]);
+
+        let mut graph = dag::build_graph_from_spanned(
+            parser::parse_dockerfile_spanned(
+                "FROM node\n# memobuild: exclude=test\nRUN npm test\n",
+                Path::new("Dockerfile"),
+            ),
+            dir.path().to_path_buf(),
+        );
+        assert!(apply(&mut graph, dir.path()).is_err());
+    }
+}
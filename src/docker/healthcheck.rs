@@ -0,0 +1,204 @@
+//! HEALTHCHECK instructions, the last of which in the final stage is what
+//! `memobuild build --smoke-test` runs.
+
+use crate::graph::{BuildGraph, NodeKind};
+use anyhow::{Context, Result};
+use std::time::Duration;
+
+/// Tag of the graph nodes HEALTHCHECK instructions become
+pub const TAG: &str = "healthcheck";
+
+/// A parsed `HEALTHCHECK CMD`, with Docker's defaults for missing options.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Healthcheck {
+    /// Argv of the check; the shell form runs under `/bin/sh -c`
+    pub test: Vec<String>,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Failures during this time after start do not count
+    pub start_period: Duration,
+    /// Consecutive failures after which the container is unhealthy
+    pub retries: u32,
+}
+
+impl Default for Healthcheck {
+    fn default() -> Self {
+        Self {
+            test: Vec::new(),
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(30),
+            start_period: Duration::ZERO,
+            retries: 3,
+        }
+    }
+}
+
+/// Parse the arguments of a HEALTHCHECK instruction. `Ok(None)` is
+/// `HEALTHCHECK NONE`.
+pub fn parse(args: &str) -> Result<Option<Healthcheck>> {
+    let mut check = Healthcheck::default();
+    let mut rest = args.trim();
+    while let Some(option) = rest.strip_prefix("--") {
+        let (option, tail) = option
+            .split_once(char::is_whitespace)
+            .unwrap_or((option, ""));
+        let (name, value) = option
+            .split_once('=')
+            .with_context(|| format!("HEALTHCHECK option --{} needs a value", option))?;
+        match name {
+            "interval" => check.interval = parse_duration(value)?,
+            "timeout" => check.timeout = parse_duration(value)?,
+            "start-period" => check.start_period = parse_duration(value)?,
+            // Accepted for compatibility; probes here run at the plain interval
+            "start-interval" => {
+                parse_duration(value)?;
+            }
+            "retries" => {
+                check.retries = value
+                    .parse()
+                    .with_context(|| format!("Invalid HEALTHCHECK --retries {}", value))?
+            }
+            _ => anyhow::bail!("Unknown HEALTHCHECK option --{}", name),
+        }
+        rest = tail.trim_start();
+    }
+
+    let (keyword, command) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    match keyword.to_uppercase().as_str() {
+        "NONE" if command.trim().is_empty() => Ok(None),
+        "CMD" => {
+            let command = command.trim();
+            check.test = if command.starts_with('[') {
+                serde_json::from_str(command).with_context(|| {
+                    format!(
+                        "HEALTHCHECK CMD must be a JSON array of strings, got {}",
+                        command
+                    )
+                })?
+            } else {
+                let mut argv = crate::sandbox::shell::container_default();
+                argv.push(command.to_string());
+                argv
+            };
+            if check.test.is_empty() || check.test[0].is_empty() {
+                anyhow::bail!("HEALTHCHECK CMD has no command");
+            }
+            Ok(Some(check))
+        }
+        _ => anyhow::bail!("HEALTHCHECK expects CMD <command> or NONE, got {}", rest),
+    }
+}
+
+/// Parse a Docker duration such as `30s`, `1m30s` or `500ms`.
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        anyhow::bail!("Empty duration");
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .with_context(|| format!("Duration '{}' needs a unit (ms, s, m or h)", value))?;
+        let number: u64 = rest[..digits]
+            .parse()
+            .with_context(|| format!("Invalid duration '{}'", value))?;
+        rest = &rest[digits..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        total += match &rest[..unit_len] {
+            "ms" => Duration::from_millis(number),
+            "s" => Duration::from_secs(number),
+            "m" => Duration::from_secs(number * 60),
+            "h" => Duration::from_secs(number * 3600),
+            unit => anyhow::bail!("Unknown unit '{}' in duration '{}'", unit, value),
+        };
+        rest = &rest[unit_len..];
+    }
+    Ok(total)
+}
+
+/// The healthcheck of the image `graph` builds: the last HEALTHCHECK of its
+/// final stage, unless that is `HEALTHCHECK NONE`.
+pub fn for_image(graph: &BuildGraph) -> Result<Option<Healthcheck>> {
+    let final_stage = graph
+        .nodes
+        .iter()
+        .rposition(|n| n.kind == NodeKind::From)
+        .unwrap_or(0);
+    let Some(node) = graph.nodes[final_stage..]
+        .iter()
+        .rev()
+        .find(|n| n.metadata.tags.iter().any(|t| t == TAG))
+    else {
+        return Ok(None);
+    };
+    let args = node
+        .content
+        .trim_start()
+        .get("HEALTHCHECK".len()..)
+        .unwrap_or_default();
+    parse(args).with_context(|| format!("{}{}", node.location_prefix(), node.name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::{dag, parser};
+
+    #[test]
+    fn test_parse_healthcheck() {
+        let check = parse("--interval=5s --retries=2 CMD curl -f http://localhost/")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            check.test,
+            vec!["/bin/sh", "-c", "curl -f http://localhost/"]
+        );
+        assert_eq!(check.interval, Duration::from_secs(5));
+        assert_eq!(check.timeout, Duration::from_secs(30));
+        assert_eq!(check.retries, 2);
+
+        let check = parse(r#"--start-period=1m30s CMD ["pg_isready", "-q"]"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(check.test, vec!["pg_isready", "-q"]);
+        assert_eq!(check.start_period, Duration::from_secs(90));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+
+        assert_eq!(parse("NONE").unwrap(), None);
+        assert!(parse("--interval=5 CMD true").is_err());
+        assert!(parse("--color=red CMD true").is_err());
+        assert!(parse("true").is_err());
+    }
+
+    #[test]
+    fn test_healthcheck_of_final_stage() {
+        let graph = |dockerfile: &str| {
+            dag::build_graph_from_instructions(
+                parser::parse_dockerfile(dockerfile),
+                std::path::PathBuf::from("."),
+            )
+        };
+        let check = for_image(&graph(
+            "FROM alpine\nHEALTHCHECK CMD a\nFROM alpine\nHEALTHCHECK CMD b\nRUN make\n",
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(check.test, vec!["/bin/sh", "-c", "b"]);
+
+        // A check in an earlier stage does not describe the image
+        assert_eq!(
+            for_image(&graph(
+                "FROM alpine AS build\nHEALTHCHECK CMD a\nFROM alpine\n"
+            ))
+            .unwrap(),
+            None
+        );
+        assert_eq!(
+            for_image(&graph("FROM alpine\nHEALTHCHECK CMD a\nHEALTHCHECK NONE\n")).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,480 @@
+//! Seeding the cache with the RUN layers of an image Docker already built from
+//! the same Dockerfile.
+
+use crate::cache::HybridCache;
+use crate::graph::{BuildGraph, NodeKind};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Where the image to import is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageStore {
+    /// The local Docker daemon, through `docker save`
+    #[default]
+    Docker,
+    /// containerd's content store, through `ctr images export`, in the
+    /// namespace `CONTAINERD_NAMESPACE` names
+    Containerd,
+    /// A `docker save` archive on disk
+    Archive,
+}
+
+impl std::str::FromStr for ImageStore {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "docker" => Ok(ImageStore::Docker),
+            "containerd" => Ok(ImageStore::Containerd),
+            "archive" => Ok(ImageStore::Archive),
+            other => anyhow::bail!(
+                "Unknown image store '{}', expected docker, containerd or archive",
+                other
+            ),
+        }
+    }
+}
+
+/// Write `image` from `store` to a `docker save` archive at `dest`.
+pub fn save_image(store: ImageStore, image: &str, dest: &Path) -> Result<()> {
+    let dest = dest.to_string_lossy();
+    let mut command = match store {
+        ImageStore::Docker => {
+            let mut command = Command::new("docker");
+            command.args(["save", "--output", &dest, image]);
+            command
+        }
+        ImageStore::Containerd => {
+            let mut command = Command::new("ctr");
+            command.args(["images", "export", &dest, image]);
+            command
+        }
+        ImageStore::Archive => anyhow::bail!("{} is already an archive", image),
+    };
+    let program = command.get_program().to_string_lossy().to_string();
+    let output = command
+        .output()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} could not export {}: {}",
+            program,
+            image,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// One entry of the image's history.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageStep {
+    pub created_by: String,
+    /// Archive entry of the layer the step created; `None` for steps that
+    /// only changed the config, such as ENV
+    pub layer: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SaveManifest {
+    #[serde(rename = "Config")]
+    config: String,
+    #[serde(rename = "Layers")]
+    layers: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ImageConfig {
+    #[serde(default)]
+    history: Vec<HistoryEntry>,
+}
+
+#[derive(Deserialize)]
+struct HistoryEntry {
+    #[serde(default)]
+    created_by: String,
+    #[serde(default)]
+    empty_layer: bool,
+}
+
+/// A `docker save` archive, as written by `docker save` and
+/// `ctr images export`: a `manifest.json` naming the config and the layer
+/// tars, plain or gzip-compressed.
+pub struct ImageArchive {
+    path: PathBuf,
+    /// Offset and size of every file in the archive, by name
+    entries: HashMap<String, (u64, u64)>,
+    pub steps: Vec<ImageStep>,
+}
+
+impl ImageArchive {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut entries = HashMap::new();
+        let mut links = HashMap::new();
+        for entry in tar::Archive::new(file).entries()? {
+            let entry = entry?;
+            let name = normalize(&entry.path()?.to_string_lossy());
+            let kind = entry.header().entry_type();
+            if kind.is_symlink() || kind.is_hard_link() {
+                // Older `docker save` links layers shared by several images
+                if let Some(target) = entry.link_name()? {
+                    let target = target.to_string_lossy().to_string();
+                    let target = match (kind.is_symlink(), name.rsplit_once('/')) {
+                        (true, Some((dir, _))) => resolve(dir, &target),
+                        _ => normalize(&target),
+                    };
+                    links.insert(name, target);
+                }
+            } else if kind.is_file() {
+                entries.insert(name, (entry.raw_file_position(), entry.size()));
+            }
+        }
+        for (name, target) in links {
+            if let Some(&position) = entries.get(&target) {
+                entries.insert(name, position);
+            }
+        }
+
+        let mut archive = Self {
+            path: path.to_path_buf(),
+            entries,
+            steps: Vec::new(),
+        };
+        let manifests: Vec<SaveManifest> = serde_json::from_slice(
+            &archive
+                .read("manifest.json")
+                .with_context(|| format!("{} is not a docker save archive", path.display()))?,
+        )
+        .context("Malformed manifest.json")?;
+        let manifest = manifests
+            .into_iter()
+            .next()
+            .with_context(|| format!("{} holds no image", path.display()))?;
+        let config: ImageConfig = serde_json::from_slice(&archive.read(&manifest.config)?)
+            .context("Malformed image config")?;
+
+        let mut layers = manifest.layers.into_iter();
+        archive.steps = config
+            .history
+            .into_iter()
+            .map(|entry| ImageStep {
+                layer: if entry.empty_layer {
+                    None
+                } else {
+                    layers.next().map(|layer| normalize(&layer))
+                },
+                created_by: entry.created_by,
+            })
+            .collect();
+        Ok(archive)
+    }
+
+    /// The file `name` of the archive, as a reader that can be sent across
+    /// tasks. Compressed layers are decompressed.
+    pub fn open_entry(&self, name: &str) -> Result<Box<dyn Read + Send>> {
+        let &(offset, size) = self
+            .entries
+            .get(&normalize(name))
+            .with_context(|| format!("{} is not in {}", name, self.path.display()))?;
+        let mut file = std::fs::File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = std::io::BufReader::new(file.take(size));
+        if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+            return Ok(Box::new(flate2::read::GzDecoder::new(reader)));
+        }
+        Ok(Box::new(reader))
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.open_entry(name)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// `path` without a leading `./` or `/`.
+fn normalize(path: &str) -> String {
+    path.trim_start_matches("./")
+        .trim_start_matches('/')
+        .to_string()
+}
+
+/// `target` of a symlink in `dir`, as a path from the archive root.
+fn resolve(dir: &str, target: &str) -> String {
+    let mut parts: Vec<&str> = dir.split('/').filter(|p| !p.is_empty()).collect();
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// The instruction a history entry records and its arguments, e.g.
+/// `("RUN", "make")`, from either builder's wording: BuildKit's
+/// `RUN /bin/sh -c make # buildkit` or the legacy builder's
+/// `/bin/sh -c make` and `/bin/sh -c #(nop) COPY dir:… in /app`.
+fn parse_created_by(created_by: &str) -> Option<(String, String)> {
+    let mut text = created_by.trim();
+    text = text.strip_suffix("# buildkit").unwrap_or(text).trim_end();
+    let run = match text.strip_prefix("RUN ") {
+        Some(rest) => {
+            text = rest.trim_start();
+            true
+        }
+        None => false,
+    };
+    // Build args the step ran with: `|2 A=1 B=2 /bin/sh -c …`
+    if let Some(rest) = text.strip_prefix('|') {
+        let (count, mut rest) = rest.split_once(' ')?;
+        for _ in 0..count.parse::<usize>().ok()? {
+            rest = rest.split_once(' ').map(|(_, r)| r).unwrap_or_default();
+        }
+        text = rest.trim_start();
+    }
+    if let Some(rest) = text.strip_prefix("/bin/sh -c ") {
+        match rest.trim_start().strip_prefix("#(nop)") {
+            Some(instruction) if !run => text = instruction.trim(),
+            _ => return Some(("RUN".to_string(), collapse(rest))),
+        }
+    } else if run {
+        return Some(("RUN".to_string(), collapse(text)));
+    }
+    let (keyword, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    Some((keyword.to_ascii_uppercase(), collapse(args)))
+}
+
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Whether the image step `step` is the one `kind` with `content` would
+/// have created. COPY and ADD are compared by destination, which both
+/// builders record as written.
+fn same_step(kind: &NodeKind, content: &str, step: &ImageStep) -> bool {
+    let Some((keyword, args)) = parse_created_by(&step.created_by) else {
+        return false;
+    };
+    let destination = |dst: &Path| {
+        let dst = dst.to_string_lossy();
+        let recorded = args.rsplit(' ').next().unwrap_or_default();
+        recorded.trim_end_matches('/') == dst.trim_end_matches('/')
+    };
+    match kind {
+        NodeKind::Run => keyword == "RUN" && args == collapse(content),
+        NodeKind::Copy { dst, .. } | NodeKind::CopyFrom { dst, .. } => {
+            keyword == "COPY" && destination(dst)
+        }
+        NodeKind::Add { dst, .. } => keyword == "ADD" && destination(dst),
+        _ => false,
+    }
+}
+
+/// RUN nodes of the last stage of `graph` paired with the image steps
+/// that created them, in order, and the first step the image lacks.
+pub fn match_steps(
+    graph: &BuildGraph,
+    steps: &[ImageStep],
+) -> (Vec<(usize, usize)>, Option<usize>) {
+    let stage_start = graph
+        .nodes
+        .iter()
+        .rposition(|node| node.kind == NodeKind::From)
+        .unwrap_or(0);
+    let mut pairs = Vec::new();
+    let mut next = 0;
+    for node in &graph.nodes[stage_start..] {
+        if !matches!(
+            node.kind,
+            NodeKind::Run
+                | NodeKind::Copy { .. }
+                | NodeKind::CopyFrom { .. }
+                | NodeKind::Add { .. }
+        ) {
+            continue;
+        }
+        let found = steps[next..]
+            .iter()
+            .position(|step| same_step(&node.kind, &node.content, step));
+        let Some(offset) = found else {
+            return (pairs, Some(node.id));
+        };
+        next += offset;
+        if node.kind == NodeKind::Run && steps[next].layer.is_some() {
+            pairs.push((node.id, next));
+        }
+        next += 1;
+    }
+    (pairs, None)
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// RUN steps paired with a layer
+    pub matched: usize,
+    /// Layers stored in the cache
+    pub imported: usize,
+    /// Steps the cache already had
+    pub present: usize,
+    pub bytes: u64,
+    /// First step the image has no layer for, where pairing stopped
+    pub stopped_at: Option<usize>,
+}
+
+/// Store the layer of every RUN step of `graph` found in `archive` under the
+/// step's cache key. `graph` must be keyed, as for a build.
+pub async fn import(
+    graph: &BuildGraph,
+    archive: &ImageArchive,
+    cache: &HybridCache,
+) -> Result<ImportReport> {
+    let (pairs, stopped_at) = match_steps(graph, &archive.steps);
+    let mut report = ImportReport {
+        matched: pairs.len(),
+        stopped_at,
+        ..Default::default()
+    };
+    for (node_id, step) in pairs {
+        let node = &graph.nodes[node_id];
+        if node.metadata.no_cache() {
+            continue;
+        }
+        if cache.local.exists(&node.hash) {
+            report.present += 1;
+            continue;
+        }
+        let layer = archive.steps[step].layer.as_deref().unwrap_or_default();
+        let mut reader = archive.open_entry(layer)?;
+        report.bytes += cache
+            .put_from_reader(&node.hash, &node.name, &mut *reader)
+            .await
+            .with_context(|| format!("Failed to import the layer of {}", node.name))?;
+        report.imported += 1;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn append(builder: &mut tar::Builder<Vec<u8>>, name: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, data).unwrap();
+    }
+
+    fn layer(file: &str) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, file, b"built");
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_parse_created_by() {
+        let parse = |s: &str| parse_created_by(s).unwrap();
+        assert_eq!(
+            parse("RUN /bin/sh -c apt-get  update # buildkit"),
+            ("RUN".into(), "apt-get update".into())
+        );
+        assert_eq!(
+            parse("|1 VERSION=2 /bin/sh -c make"),
+            ("RUN".into(), "make".into())
+        );
+        assert_eq!(
+            parse("RUN |1 VERSION=2 /bin/sh -c make # buildkit"),
+            ("RUN".into(), "make".into())
+        );
+        assert_eq!(
+            parse("/bin/sh -c #(nop) COPY dir:4f2a in /app "),
+            ("COPY".into(), "dir:4f2a in /app".into())
+        );
+        assert_eq!(parse("WORKDIR /app"), ("WORKDIR".into(), "/app".into()));
+    }
+
+    #[tokio::test]
+    async fn test_import_docker_save_archive() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = serde_json::json!({
+            "history": [
+                { "created_by": "/bin/sh -c #(nop) ADD file:ab in / " },
+                { "created_by": "/bin/sh -c #(nop)  CMD [\"sh\"]", "empty_layer": true },
+                { "created_by": "WORKDIR /app", "empty_layer": true },
+                { "created_by": "RUN /bin/sh -c apk add make # buildkit" },
+                { "created_by": "COPY . . # buildkit" },
+                { "created_by": "RUN /bin/sh -c make # buildkit" },
+            ]
+        });
+        let mut builder = tar::Builder::new(Vec::new());
+        append(
+            &mut builder,
+            "manifest.json",
+            br#"[{"Config": "config.json", "Layers": ["base/layer.tar", "apk/layer.tar", "copy/layer.tar", "make/layer.tar"]}]"#,
+        );
+        append(&mut builder, "config.json", config.to_string().as_bytes());
+        for (name, file) in [
+            ("base", "bin/sh"),
+            ("apk", "usr/bin/make"),
+            ("copy", "app/Makefile"),
+        ] {
+            append(&mut builder, &format!("{}/layer.tar", name), &layer(file));
+        }
+        // The last layer is compressed, as containerd exports them
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut gzip, &layer("app/app")).unwrap();
+        append(&mut builder, "make/layer.tar", &gzip.finish().unwrap());
+        let path = dir.path().join("image.tar");
+        std::fs::write(&path, builder.into_inner().unwrap()).unwrap();
+
+        let mut graph = crate::docker::dag::build_graph_from_instructions(
+            crate::docker::parser::parse_dockerfile(
+                "FROM alpine\nWORKDIR /app\nRUN apk add make\nCOPY . .\nRUN make\nRUN make test\n",
+            ),
+            PathBuf::from("."),
+        );
+        for node in &mut graph.nodes {
+            node.hash = format!("key-{}", node.id);
+        }
+        let archive = ImageArchive::open(&path).unwrap();
+        let cache = HybridCache {
+            local: crate::cache::LocalCache::in_dir(dir.path().join("cache")).unwrap(),
+            remote: None,
+            remote_health: None,
+            mirrors: None,
+            integrity: Default::default(),
+            uploads: Default::default(),
+            dedup_uploads: true,
+            prefetch_hints: false,
+            hinted: Default::default(),
+            max_artifact_bytes: None,
+        };
+
+        let report = import(&graph, &archive, &cache).await.unwrap();
+        assert_eq!((report.matched, report.imported), (2, 2));
+        // `make test` never ran in the image
+        assert_eq!(report.stopped_at, Some(5));
+        assert_eq!(
+            cache.get_artifact("key-2").await.unwrap().unwrap(),
+            layer("usr/bin/make")
+        );
+        assert_eq!(
+            cache.get_artifact("key-4").await.unwrap().unwrap(),
+            layer("app/app")
+        );
+
+        let again = import(&graph, &archive, &cache).await.unwrap();
+        assert_eq!((again.imported, again.present), (0, 2));
+    }
+}
@@ -0,0 +1,161 @@
+use crate::docker::parser::{parse_dockerfile_spanned, Instruction, Spanned};
+use crate::graph::BuildGraph;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Maximum INCLUDE nesting before we assume something is wrong.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// An instruction after INCLUDE expansion.
+#[derive(Debug, Clone)]
+pub struct ExpandedInstruction {
+    pub instruction: Spanned<Instruction>,
+    /// Digest of the chain of fragments this instruction was included from,
+    /// `None` for instructions written directly in the top-level Dockerfile.
+    pub include_digest: Option<String>,
+}
+
+/// Inline every `INCLUDE <path>` instruction with the contents of the referenced
+/// fragment. Paths are resolved relative to the file containing the INCLUDE.
+pub fn expand_includes(
+    instructions: Vec<Spanned<Instruction>>,
+) -> Result<Vec<ExpandedInstruction>> {
+    let mut out = Vec::new();
+    let mut stack = Vec::new();
+    expand_into(instructions, None, &mut stack, &mut out)?;
+    Ok(out)
+}
+
+fn expand_into(
+    instructions: Vec<Spanned<Instruction>>,
+    parent_digest: Option<&str>,
+    stack: &mut Vec<PathBuf>,
+    out: &mut Vec<ExpandedInstruction>,
+) -> Result<()> {
+    for spanned in instructions {
+        let Instruction::Include(ref rel) = spanned.node else {
+            out.push(ExpandedInstruction {
+                instruction: spanned,
+                include_digest: parent_digest.map(str::to_string),
+            });
+            continue;
+        };
+
+        let base = spanned.span.file.parent().unwrap_or(Path::new("."));
+        let path = base.join(rel);
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("{}: included file {} not found", spanned.span, rel))?;
+
+        if stack.contains(&canonical) {
+            anyhow::bail!(
+                "{}: INCLUDE cycle detected through {}",
+                spanned.span,
+                canonical.display()
+            );
+        }
+        if stack.len() >= MAX_INCLUDE_DEPTH {
+            anyhow::bail!(
+                "{}: INCLUDE nesting deeper than {} levels",
+                spanned.span,
+                MAX_INCLUDE_DEPTH
+            );
+        }
+
+        let content = std::fs::read_to_string(&canonical)
+            .with_context(|| format!("{}: failed to read {}", spanned.span, path.display()))?;
+
+        let mut hasher = blake3::Hasher::new();
+        if let Some(parent) = parent_digest {
+            hasher.update(parent.as_bytes());
+        }
+        hasher.update(content.as_bytes());
+        let digest = hasher.finalize().to_hex().to_string();
+
+        stack.push(canonical);
+        expand_into(
+            parse_dockerfile_spanned(&content, &path),
+            Some(&digest),
+            stack,
+            out,
+        )?;
+        stack.pop();
+    }
+    Ok(())
+}
+
+/// Expand includes and build the dependency graph, recording each included
+/// node's fragment digest so edits to a shared fragment invalidate its steps.
+/// Nested builds are merged in, see [`crate::docker::nested`].
+pub fn build_graph_with_includes(
+    instructions: Vec<Spanned<Instruction>>,
+    project_root: PathBuf,
+) -> Result<BuildGraph> {
+    let mut graph = graph_with_includes(instructions, project_root.clone())?;
+    crate::docker::nested::expand(&mut graph, &project_root)?;
+    Ok(graph)
+}
+
+/// [`build_graph_with_includes`] without expanding nested builds.
+pub(crate) fn graph_with_includes(
+    instructions: Vec<Spanned<Instruction>>,
+    project_root: PathBuf,
+) -> Result<BuildGraph> {
+    let expanded = expand_includes(instructions)?;
+    let digests: Vec<Option<String>> = expanded.iter().map(|e| e.include_digest.clone()).collect();
+
+    let mut graph = crate::docker::dag::build_graph_from_spanned(
+        expanded.into_iter().map(|e| e.instruction).collect(),
+        project_root,
+    );
+
+    // The dag builder emits exactly one node per instruction, in order
+    for (node, digest) in graph.nodes.iter_mut().zip(digests) {
+        if digest.is_some() {
+            node.metadata.tags.push("included".to_string());
+        }
+        node.metadata.include_digest = digest;
+    }
+
+    Ok(graph)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn parse(dir: &TempDir, content: &str) -> Vec<Spanned<Instruction>> {
+        let path = dir.path().join("Dockerfile");
+        std::fs::write(&path, content).unwrap();
+        parse_dockerfile_spanned(content, &path)
+    }
+
+    #[test]
+    fn test_include_is_inlined_with_digest() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join("deps.inc"), "COPY package.json .\nRUN npm ci\n").unwrap();
+        let instructions = parse(&dir, "FROM node:20\nINCLUDE deps.inc\nCMD node app.js\n");
+
+        let graph = build_graph_with_includes(instructions, dir.path().to_path_buf()).unwrap();
+        assert_eq!(graph.nodes.len(), 4);
+        assert!(graph.nodes[0].metadata.include_digest.is_none());
+        assert!(graph.nodes[1].metadata.include_digest.is_some());
+        assert_eq!(
+            graph.nodes[1].metadata.include_digest,
+            graph.nodes[2].metadata.include_digest
+        );
+        assert!(graph.nodes[2].metadata.span.as_ref().unwrap().file.ends_with("deps.inc"));
+    }
+
+    #[test]
+    fn test_include_cycle_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join("a.inc"), "INCLUDE b.inc\n").unwrap();
+        std::fs::write(dir.path().join("b.inc"), "INCLUDE a.inc\n").unwrap();
+        let instructions = parse(&dir, "FROM scratch\nINCLUDE a.inc\n");
+
+        let err = expand_includes(instructions).unwrap_err();
+        assert!(err.to_string().contains("cycle"));
+    }
+}
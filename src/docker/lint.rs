@@ -0,0 +1,378 @@
+use crate::docker::parser::{Instruction, Span};
+use crate::hasher::IgnoreRules;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Directories that are almost never needed inside a build context and
+/// blow up hashing/upload time when they are not excluded.
+const HEAVY_DIRS: &[&str] = &["node_modules", ".git", "target", ".venv", "__pycache__"];
+
+/// Command fragments that indicate a dependency install step.
+const DEPENDENCY_INSTALL_PATTERNS: &[&str] = &[
+    "npm install",
+    "npm ci",
+    "yarn install",
+    "pnpm install",
+    "pip install",
+    "poetry install",
+    "bundle install",
+    "go mod download",
+    "cargo fetch",
+    "composer install",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LintRule {
+    /// A line the parser cannot make an instruction of
+    SyntaxError,
+    /// `FROM` without a tag, with `:latest`, or without a digest
+    UnpinnedBaseImage,
+    /// `COPY . .` placed before a dependency install step
+    CacheBustingCopy,
+    /// Large directory present in the context but not in .dockerignore
+    MissingDockerignore,
+    /// Independent steps ordered so frequent changes invalidate rare ones
+    CacheUnfriendlyOrder,
+    /// Build stage that the final stage never depends on
+    UnreachableStage,
+}
+
+impl LintRule {
+    pub fn code(&self) -> &'static str {
+        match self {
+            LintRule::SyntaxError => "MB000",
+            LintRule::UnpinnedBaseImage => "MB001",
+            LintRule::CacheBustingCopy => "MB002",
+            LintRule::MissingDockerignore => "MB003",
+            LintRule::UnreachableStage => "MB004",
+            LintRule::CacheUnfriendlyOrder => "MB005",
+        }
+    }
+}
+
+/// A single actionable finding produced by the lint pass.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub rule: LintRule,
+    pub severity: Severity,
+    /// Location in the Dockerfile, if the finding maps to an instruction
+    pub span: Option<Span>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.span {
+            Some(ref span) => write!(
+                f,
+                "{}: {}[{}]: {}",
+                span,
+                self.severity,
+                self.rule.code(),
+                self.message
+            ),
+            None => write!(
+                f,
+                "Dockerfile: {}[{}]: {}",
+                self.severity,
+                self.rule.code(),
+                self.message
+            ),
+        }
+    }
+}
+
+struct Stage {
+    span: Span,
+    name: Option<String>,
+    /// Names or indices of stages this one pulls from
+    refs: Vec<String>,
+}
+
+/// Run all lint rules over a Dockerfile and its build context.
+///
+/// Rules see the instructions the parser makes of the file, at the parser's
+/// lines. Stage names and `--from=` flags, which it does not keep, are read
+/// from the instruction's own line. Diagnostics are returned sorted by line.
+pub fn lint_dockerfile(content: &str, dockerfile: &Path, context_dir: &Path) -> Vec<Diagnostic> {
+    let (instructions, errors) =
+        crate::docker::parser::parse_dockerfile_checked(content, dockerfile);
+    let lines: Vec<&str> = content.lines().collect();
+
+    let mut diagnostics = Vec::new();
+    let mut stages: Vec<Stage> = Vec::new();
+    let mut pending_copy_all: Option<Span> = None;
+    let mut first_copy_all: Option<Span> = None;
+
+    for instruction in &instructions {
+        let span = &instruction.span;
+        let words: Vec<&str> = lines[span.line - 1].split_whitespace().collect();
+        let args: Vec<&str> = words[1..]
+            .iter()
+            .copied()
+            .filter(|a| !a.starts_with("--"))
+            .collect();
+
+        match instruction.node {
+            Instruction::From(ref image) => {
+                pending_copy_all = None;
+                let name = match (args.get(1), args.get(2)) {
+                    (Some(kw), Some(name)) if kw.eq_ignore_ascii_case("as") => {
+                        Some(name.to_string())
+                    }
+                    _ => None,
+                };
+
+                let known_stage = stages.iter().any(|s| s.name.as_ref() == Some(image));
+                let mut refs = Vec::new();
+                if known_stage {
+                    refs.push(image.clone());
+                } else if let Some(reason) = unpinned_reason(image) {
+                    diagnostics.push(Diagnostic {
+                        rule: LintRule::UnpinnedBaseImage,
+                        severity: Severity::Warning,
+                        span: Some(span.clone()),
+                        message: format!(
+                            "base image '{}' {}; pin a tag and digest (image:tag@sha256:...)",
+                            image, reason
+                        ),
+                    });
+                }
+
+                stages.push(Stage {
+                    span: span.clone(),
+                    name,
+                    refs,
+                });
+            }
+            Instruction::Copy(..) | Instruction::CopyFrom(..) => {
+                if let Some(from) = words[1..].iter().find_map(|a| a.strip_prefix("--from=")) {
+                    if let Some(stage) = stages.last_mut() {
+                        stage.refs.push(from.to_string());
+                    }
+                }
+                if matches!(args.first(), Some(&".") | Some(&"./")) {
+                    pending_copy_all.get_or_insert_with(|| span.clone());
+                    first_copy_all.get_or_insert_with(|| span.clone());
+                }
+            }
+            Instruction::Run(ref cmd) => {
+                if let Some(ref copy_span) = pending_copy_all {
+                    if let Some(pattern) = DEPENDENCY_INSTALL_PATTERNS
+                        .iter()
+                        .find(|p| cmd.contains(*p))
+                    {
+                        diagnostics.push(Diagnostic {
+                            rule: LintRule::CacheBustingCopy,
+                            severity: Severity::Warning,
+                            span: Some(copy_span.clone()),
+                            message: format!(
+                                "copying the whole context before '{}' (line {}) invalidates the install step on every source change; copy the dependency manifests first",
+                                pattern, span.line
+                            ),
+                        });
+                        pending_copy_all = None;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    diagnostics.extend(unreachable_stages(&stages));
+    diagnostics.extend(missing_dockerignore(context_dir, first_copy_all));
+    diagnostics.extend(errors.into_iter().map(|e| Diagnostic {
+        rule: LintRule::SyntaxError,
+        severity: Severity::Error,
+        span: Some(e.span),
+        message: e.message,
+    }));
+
+    diagnostics.sort_by_key(|d| d.span.as_ref().map(|s| s.line).unwrap_or(0));
+    diagnostics
+}
+
+/// Returns why an image reference is considered unpinned, if it is.
+fn unpinned_reason(image: &str) -> Option<&'static str> {
+    if image == "scratch" || image.contains("@sha256:") {
+        return None;
+    }
+    // Only the last path component carries the tag; earlier ':' is a registry port
+    let last = image.rsplit('/').next().unwrap_or(image);
+    match last.split_once(':') {
+        None => Some("has no tag"),
+        Some((_, "latest")) => Some("uses the mutable 'latest' tag"),
+        Some(_) => Some("is not pinned to a digest"),
+    }
+}
+
+fn unreachable_stages(stages: &[Stage]) -> Vec<Diagnostic> {
+    if stages.len() < 2 {
+        return Vec::new();
+    }
+
+    let mut index_by_ref: HashMap<String, usize> = HashMap::new();
+    for (i, stage) in stages.iter().enumerate() {
+        index_by_ref.insert(i.to_string(), i);
+        if let Some(ref name) = stage.name {
+            index_by_ref.insert(name.clone(), i);
+        }
+    }
+
+    let mut reachable = HashSet::new();
+    let mut stack = vec![stages.len() - 1];
+    while let Some(i) = stack.pop() {
+        if !reachable.insert(i) {
+            continue;
+        }
+        for r in &stages[i].refs {
+            if let Some(&dep) = index_by_ref.get(r) {
+                stack.push(dep);
+            }
+        }
+    }
+
+    stages
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !reachable.contains(i))
+        .map(|(i, stage)| Diagnostic {
+            rule: LintRule::UnreachableStage,
+            severity: Severity::Warning,
+            span: Some(stage.span.clone()),
+            message: format!(
+                "stage '{}' is never used by the final stage and will not contribute to the image",
+                stage.name.clone().unwrap_or_else(|| i.to_string())
+            ),
+        })
+        .collect()
+}
+
+fn missing_dockerignore(context_dir: &Path, copy_all: Option<Span>) -> Vec<Diagnostic> {
+    let rules = IgnoreRules::from_file(&context_dir.join(".dockerignore"));
+    HEAVY_DIRS
+        .iter()
+        .filter(|dir| context_dir.join(dir).is_dir())
+        .filter(|dir| !rules.is_ignored(Path::new(dir)))
+        .map(|dir| Diagnostic {
+            rule: LintRule::MissingDockerignore,
+            severity: Severity::Warning,
+            span: copy_all.clone(),
+            message: format!(
+                "'{}' exists in the build context but is not listed in .dockerignore",
+                dir
+            ),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn lint(content: &str, dir: &TempDir) -> Vec<Diagnostic> {
+        lint_dockerfile(content, Path::new("Dockerfile"), dir.path())
+    }
+
+    fn line(diag: &Diagnostic) -> Option<usize> {
+        diag.span.as_ref().map(|s| s.line)
+    }
+
+    fn rules(diags: &[Diagnostic]) -> Vec<LintRule> {
+        diags.iter().map(|d| d.rule).collect()
+    }
+
+    #[test]
+    fn test_unpinned_base_image() {
+        let dir = TempDir::new().unwrap();
+        let diags = lint("FROM node\nFROM localhost:5000/app:latest\n", &dir);
+        assert_eq!(diags.len(), 3);
+        assert_eq!(diags[0].rule, LintRule::UnpinnedBaseImage);
+        assert_eq!(line(&diags[0]), Some(1));
+
+        let pinned = lint("FROM node:18@sha256:abcd\n", &dir);
+        assert!(pinned.is_empty());
+    }
+
+    #[test]
+    fn test_cache_busting_copy() {
+        let dir = TempDir::new().unwrap();
+        let content = "FROM scratch\nWORKDIR /app\nCOPY . .\nRUN npm ci\n";
+        let diags = lint(content, &dir);
+        assert_eq!(rules(&diags), vec![LintRule::CacheBustingCopy]);
+        assert_eq!(line(&diags[0]), Some(3));
+    }
+
+    #[test]
+    fn test_unreachable_stage() {
+        let dir = TempDir::new().unwrap();
+        let content = "FROM scratch AS unused\nFROM scratch AS builder\nFROM scratch\nCOPY --from=builder /out /out\n";
+        let diags = lint(content, &dir);
+        assert_eq!(rules(&diags), vec![LintRule::UnreachableStage]);
+        assert_eq!(line(&diags[0]), Some(1));
+    }
+
+    #[test]
+    fn test_missing_dockerignore() {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join("node_modules")).unwrap();
+        let diags = lint("FROM scratch\nCOPY . .\n", &dir);
+        assert_eq!(rules(&diags), vec![LintRule::MissingDockerignore]);
+
+        std::fs::write(dir.path().join(".dockerignore"), "node_modules\n").unwrap();
+        assert!(lint("FROM scratch\nCOPY . .\n", &dir).is_empty());
+    }
+
+    #[test]
+    fn test_syntax_errors_are_all_reported() {
+        let dir = TempDir::new().unwrap();
+        let content = "FROM scratch\nCOPY src\nRUN make\nENV DEBUG\nWORKDIR /app\n";
+        let diags = lint(content, &dir);
+        assert_eq!(
+            rules(&diags),
+            vec![LintRule::SyntaxError, LintRule::SyntaxError]
+        );
+        assert_eq!(line(&diags[0]), Some(2));
+        assert_eq!(line(&diags[1]), Some(4));
+        assert!(diags.iter().all(|d| d.severity == Severity::Error));
+    }
+
+    #[test]
+    fn test_spans_match_the_parser() {
+        let dir = TempDir::new().unwrap();
+        let content = "FROM node:20\nRUN apt-get update \\\n    && apt-get install -y git\n  COPY . .\nRUN npm install\n";
+        let diags = lint(content, &dir);
+        let copy = diags
+            .iter()
+            .find(|d| d.rule == LintRule::CacheBustingCopy)
+            .unwrap();
+
+        let (instructions, _) =
+            crate::docker::parser::parse_dockerfile_checked(content, Path::new("Dockerfile"));
+        let parsed = instructions
+            .iter()
+            .find(|i| matches!(i.node, Instruction::Copy(..)))
+            .unwrap();
+        assert_eq!(copy.span.as_ref(), Some(&parsed.span));
+        assert_eq!((parsed.span.line, parsed.span.column), (4, 3));
+    }
+}
@@ -1,3 +1,14 @@
+pub mod add;
+pub mod copy_from;
 pub mod dag;
+pub mod directives;
 pub mod extensions;
+pub mod healthcheck;
+pub mod import;
+pub mod include;
+pub mod lint;
+pub mod nested;
 pub mod parser;
+pub mod policy;
+pub mod reorder;
+pub mod resolve;
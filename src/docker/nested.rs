@@ -0,0 +1,188 @@
+//! Nested builds: `BUILD [--file=<Dockerfile>] <context>` steps merged into the
+//! parent graph.
+
+use crate::docker::include::graph_with_includes;
+use crate::docker::parser::parse_dockerfile_spanned;
+use crate::docker::resolve::{stage_aliases, STAGE_REF_TAG};
+use crate::graph::{BuildGraph, NodeKind};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Maximum BUILD nesting before we assume something is wrong.
+const MAX_NESTING_DEPTH: usize = 8;
+
+/// Merge the steps of every BUILD node into `graph`. Nested contexts are
+/// resolved against, and must stay inside, `context_dir`.
+pub fn expand(graph: &mut BuildGraph, context_dir: &Path) -> Result<()> {
+    if !graph
+        .nodes
+        .iter()
+        .any(|n| matches!(n.kind, NodeKind::Build { .. }))
+    {
+        return Ok(());
+    }
+    let root = context_dir
+        .canonicalize()
+        .with_context(|| format!("Build context {} not found", context_dir.display()))?;
+    let mut stack = Vec::new();
+    expand_in(graph, &root, &root, &mut stack)
+}
+
+fn expand_in(
+    graph: &mut BuildGraph,
+    root: &Path,
+    context_dir: &Path,
+    stack: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let builds: Vec<usize> = graph
+        .nodes
+        .iter()
+        .filter(|n| matches!(n.kind, NodeKind::Build { .. }))
+        .map(|n| n.id)
+        .collect();
+
+    for id in builds {
+        let node = &graph.nodes[id];
+        let NodeKind::Build {
+            ref context,
+            ref dockerfile,
+        } = node.kind
+        else {
+            continue;
+        };
+        let prefix = node.location_prefix();
+
+        let sub_context = context_dir.join(context).canonicalize().with_context(|| {
+            format!(
+                "{}nested build context {} not found",
+                prefix,
+                context.display()
+            )
+        })?;
+        let rel = match sub_context.strip_prefix(root) {
+            Ok(rel) if rel.as_os_str().is_empty() => ".".to_string(),
+            Ok(rel) => rel.to_string_lossy().to_string(),
+            Err(_) => anyhow::bail!(
+                "{}nested build context {} is outside the build context",
+                prefix,
+                context.display()
+            ),
+        };
+        let path = sub_context.join(dockerfile);
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("{}{} not found", prefix, path.display()))?;
+
+        if stack.contains(&canonical) {
+            anyhow::bail!(
+                "{}BUILD cycle detected through {}",
+                prefix,
+                canonical.display()
+            );
+        }
+        if stack.len() >= MAX_NESTING_DEPTH {
+            anyhow::bail!(
+                "{}BUILD nesting deeper than {} levels",
+                prefix,
+                MAX_NESTING_DEPTH
+            );
+        }
+
+        let content = std::fs::read_to_string(&canonical)
+            .with_context(|| format!("{}failed to read {}", prefix, path.display()))?;
+        let mut sub = graph_with_includes(
+            parse_dockerfile_spanned(&content, &path),
+            sub_context.clone(),
+        )?;
+        stack.push(canonical);
+        expand_in(&mut sub, root, &sub_context, stack)?;
+        stack.pop();
+
+        // Steps of builds nested deeper were named when they were merged
+        let aliases = stage_aliases(&content);
+        for step in sub.nodes.iter_mut().filter(|n| n.metadata.nested.is_none()) {
+            let stage_ref = step.kind == NodeKind::From
+                && step
+                    .content
+                    .strip_prefix("FROM ")
+                    .is_some_and(|image| aliases.contains(image.trim()));
+            if stage_ref {
+                step.metadata.tags.push(STAGE_REF_TAG.to_string());
+            }
+            step.metadata.nested = Some(rel.clone());
+            step.metadata.group = Some(match step.metadata.group.take() {
+                Some(group) => format!("[{}] {}", rel, group),
+                None => format!("[{}]", rel),
+            });
+            step.name = format!("[{}] {}", rel, step.name);
+        }
+
+        // The BUILD step waits for every step nothing else in the nested
+        // build depends on
+        let depended: Vec<bool> = (0..sub.nodes.len())
+            .map(|i| sub.nodes.iter().any(|n| n.deps.contains(&i)))
+            .collect();
+        let ids = graph.merge(sub, None);
+        let last: Vec<usize> = ids
+            .into_iter()
+            .zip(depended)
+            .filter(|(_, depended)| !depended)
+            .map(|(id, _)| id)
+            .collect();
+        graph.nodes[id].deps.extend(last);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::include::build_graph_with_includes;
+    use tempfile::TempDir;
+
+    fn build(dir: &TempDir, content: &str) -> Result<BuildGraph> {
+        let path = dir.path().join("Dockerfile");
+        std::fs::write(&path, content).unwrap();
+        build_graph_with_includes(
+            parse_dockerfile_spanned(content, &path),
+            dir.path().to_path_buf(),
+        )
+    }
+
+    #[test]
+    fn test_nested_build_is_merged() {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("packages/api")).unwrap();
+        std::fs::write(
+            dir.path().join("packages/api/Dockerfile"),
+            "FROM golang:1.22 AS builder\nRUN go build ./...\nFROM builder\nCOPY . /app\n",
+        )
+        .unwrap();
+
+        let graph = build(&dir, "FROM alpine\nBUILD packages/api\nRUN echo done\n").unwrap();
+        assert_eq!(graph.nodes.len(), 7);
+
+        let nested: Vec<_> = graph.nodes[3..].iter().collect();
+        assert!(nested
+            .iter()
+            .all(|n| n.metadata.nested.as_deref() == Some("packages/api")));
+        assert!(nested[0].name.starts_with("[packages/api] "));
+        assert!(!nested[0].metadata.tags.iter().any(|t| t == STAGE_REF_TAG));
+        assert!(nested[2].metadata.tags.iter().any(|t| t == STAGE_REF_TAG));
+
+        // BUILD waits for both nested stages, the parent's next step for BUILD
+        let mut deps = graph.nodes[1].deps.clone();
+        deps.sort();
+        assert_eq!(deps, vec![0, 4, 6]);
+        let levels = graph.levels();
+        let level_of = |id: usize| levels.iter().position(|l| l.contains(&id)).unwrap();
+        assert!(level_of(2) > level_of(6));
+    }
+
+    #[test]
+    fn test_nested_build_cycle_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        let err = build(&dir, "FROM alpine\nBUILD .\n").unwrap_err();
+        assert!(err.to_string().contains("cycle"));
+    }
+}
@@ -1,25 +1,123 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Comments of the form `# memobuild:key=value` are directives for the next instruction.
+pub const DIRECTIVE_PREFIX: &str = "memobuild:";
+
+/// Location of an instruction in its source Dockerfile (1-based line and column).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file.display(), self.line, self.column)
+    }
+}
+
+/// A parsed value together with the place it came from.
+#[derive(Debug, Clone)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+    /// `# memobuild:` directives written directly above the instruction
+    pub directives: BTreeMap<String, String>,
+}
+
 #[derive(Debug, Clone)]
 pub enum Instruction {
     From(String),
     Workdir(String),
     Copy(String, String),
+    CopyFrom(String, String, String),    // (image, src, dst)
+    Add(String, String, Option<String>), // (src, dst, checksum)
     Run(String),
     Env(String, String),
     Cmd(String),
+    Shell(Vec<String>),                      // (shell argv)
+    Healthcheck(String),                     // arguments, validated
     Git(String, String),                     // (url, target_dir)
     RunExtend(String, bool),                 // (command, parallelizable)
     CopyExtend(String, String, Vec<String>), // (src, dst, tags)
     Hook(String, Vec<String>),               // (hook_name, params)
+    Include(String),                         // path to a Dockerfile fragment
+    Build(String, Option<String>),           // (context, dockerfile)
     Other(String),
 }
 
 pub fn parse_dockerfile(content: &str) -> Vec<Instruction> {
-    let mut instructions = Vec::new();
+    parse_dockerfile_spanned(content, Path::new("Dockerfile"))
+        .into_iter()
+        .map(|s| s.node)
+        .collect()
+}
+
+/// A line the parser could not make an instruction of, such as `COPY src`
+/// without a destination.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParseError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
 
-    for line in content.lines() {
-        let line = line.trim();
+/// Parse a Dockerfile, keeping the source location of every instruction so
+/// diagnostics and build errors can point back at the offending line.
+/// Malformed lines are skipped; see [`parse_dockerfile_checked`] for the
+/// errors.
+pub fn parse_dockerfile_spanned(content: &str, file: &Path) -> Vec<Spanned<Instruction>> {
+    parse_dockerfile_checked(content, file).0
+}
+
+/// Parse a Dockerfile like [`parse_dockerfile_spanned`], also returning an
+/// error for every malformed line. Parsing goes on after one, so all of them
+/// are reported at once.
+pub fn parse_dockerfile_checked(
+    content: &str,
+    file: &Path,
+) -> (Vec<Spanned<Instruction>>, Vec<ParseError>) {
+    let mut instructions = Vec::new();
+    let mut errors = Vec::new();
+    let mut pending_directives: BTreeMap<String, String> = BTreeMap::new();
+    // Names given with `FROM <image> AS <name>`, to tell `COPY --from=<stage>`
+    // apart from `COPY --from=<image>`
+    let mut stages: Vec<String> = Vec::new();
 
-        if line.is_empty() || line.starts_with('#') {
+    for (line_idx, raw_line) in content.lines().enumerate() {
+        let line = raw_line.trim();
+        if let Some(comment) = line.strip_prefix('#') {
+            if let Some(directives) = comment.trim().strip_prefix(DIRECTIVE_PREFIX) {
+                // A value ending in a comma continues with the next word, so
+                // lists can be written `inputs=src/**, package.json`
+                let mut continued: Option<String> = None;
+                for token in directives.split_whitespace() {
+                    if let Some(key) = continued.take() {
+                        let value = pending_directives.entry(key.clone()).or_default();
+                        value.push_str(token);
+                        if token.ends_with(',') {
+                            continued = Some(key);
+                        }
+                        continue;
+                    }
+                    let (key, value) = token.split_once('=').unwrap_or((token, ""));
+                    pending_directives.insert(key.to_string(), value.to_string());
+                    if value.ends_with(',') {
+                        continued = Some(key.to_string());
+                    }
+                }
+            }
+            continue;
+        }
+        if line.is_empty() {
             continue;
         }
 
@@ -27,6 +125,40 @@ pub fn parse_dockerfile(content: &str) -> Vec<Instruction> {
         if parts.is_empty() {
             continue;
         }
+        // `COPY --exclude=<glob>` is kept as `# memobuild:exclude=<glob>`
+        if parts[0].eq_ignore_ascii_case("COPY") {
+            for pattern in parts[1..]
+                .iter()
+                .filter_map(|p| p.strip_prefix("--exclude="))
+            {
+                let value = pending_directives
+                    .entry(crate::docker::directives::EXCLUDE.to_string())
+                    .or_default();
+                if !value.is_empty() {
+                    value.push_str(", ");
+                }
+                value.push_str(pattern);
+            }
+        }
+
+        let span = Span {
+            file: file.to_path_buf(),
+            line: line_idx + 1,
+            column: raw_line.len() - raw_line.trim_start().len() + 1,
+        };
+        let mut push = |instr: Instruction| {
+            instructions.push(Spanned {
+                node: instr,
+                span: span.clone(),
+                directives: std::mem::take(&mut pending_directives),
+            })
+        };
+        let mut error = |message: String| {
+            errors.push(ParseError {
+                span: span.clone(),
+                message,
+            })
+        };
 
         let keyword = parts[0].to_uppercase();
         let args = if line.len() > keyword.len() {
@@ -37,49 +169,141 @@ pub fn parse_dockerfile(content: &str) -> Vec<Instruction> {
 
         match keyword.as_str() {
             "FROM" => {
+                let stage = match parts.as_slice() {
+                    [_, _, as_kw, name, ..] if as_kw.eq_ignore_ascii_case("AS") => Some(*name),
+                    _ => None,
+                };
+                if let Some(stage) = stage {
+                    stages.push(stage.to_ascii_lowercase());
+                }
                 if parts.len() >= 2 {
-                    instructions.push(Instruction::From(parts[1].to_string()));
+                    push(Instruction::From(parts[1].to_string()));
+                    // A named stage is reported under its name
+                    if let (Some(stage), Some(from)) = (stage, instructions.last_mut()) {
+                        from.directives
+                            .entry(crate::docker::directives::GROUP.to_string())
+                            .or_insert_with(|| stage.to_string());
+                    }
+                } else {
+                    error("FROM needs a base image".to_string());
                 }
             }
             "WORKDIR" => {
                 if parts.len() >= 2 {
-                    instructions.push(Instruction::Workdir(parts[1].to_string()));
+                    push(Instruction::Workdir(parts[1].to_string()));
+                } else {
+                    error("WORKDIR needs a path".to_string());
                 }
             }
             "COPY" => {
-                if parts.len() >= 3 {
-                    instructions.push(Instruction::Copy(
-                        parts[1].to_string(),
-                        parts[2].to_string(),
+                let image = parts[1..]
+                    .iter()
+                    .find_map(|p| p.strip_prefix("--from="))
+                    .filter(|from| !is_stage_ref(from, &stages));
+                if let Some(image) = image {
+                    // COPY --from=<image> [--flag...] src dst
+                    let operands: Vec<&str> = parts[1..]
+                        .iter()
+                        .filter(|p| !p.starts_with("--"))
+                        .copied()
+                        .collect();
+                    if operands.len() >= 2 {
+                        push(Instruction::CopyFrom(
+                            image.to_string(),
+                            operands[0].to_string(),
+                            operands[1].to_string(),
+                        ));
+                    } else {
+                        error("COPY --from needs a source and a destination".to_string());
+                    }
+                } else {
+                    let operands: Vec<&str> = parts[1..]
+                        .iter()
+                        .filter(|p| !p.starts_with("--exclude="))
+                        .copied()
+                        .collect();
+                    if operands.len() >= 2 {
+                        push(Instruction::Copy(
+                            operands[0].to_string(),
+                            operands[1].to_string(),
+                        ));
+                    } else {
+                        error("COPY needs a source and a destination".to_string());
+                    }
+                }
+            }
+            "ADD" => {
+                // ADD [--checksum=<digest>] [--flag...] src dst
+                let mut checksum = None;
+                let mut operands = Vec::new();
+                for part in &parts[1..] {
+                    if let Some(flag) = part.strip_prefix("--") {
+                        if let Some(value) = flag.strip_prefix("checksum=") {
+                            checksum = Some(value.to_string());
+                        }
+                    } else {
+                        operands.push(part.to_string());
+                    }
+                }
+                if operands.len() >= 2 {
+                    push(Instruction::Add(
+                        operands[0].clone(),
+                        operands[1].clone(),
+                        checksum,
                     ));
+                } else {
+                    error("ADD needs a source and a destination".to_string());
                 }
             }
             "RUN" => {
-                instructions.push(Instruction::Run(args.to_string()));
+                if args.is_empty() {
+                    error("RUN needs a command".to_string());
+                } else {
+                    push(Instruction::Run(args.to_string()));
+                }
             }
             "ENV" => {
                 let env_parts: Vec<&str> = args.splitn(2, [' ', '=']).collect();
                 if env_parts.len() == 2 {
-                    instructions.push(Instruction::Env(
+                    push(Instruction::Env(
                         env_parts[0].to_string(),
                         env_parts[1].to_string(),
                     ));
+                } else {
+                    error("ENV needs a name and a value".to_string());
                 }
             }
             "CMD" => {
-                instructions.push(Instruction::Cmd(args.to_string()));
+                push(Instruction::Cmd(args.to_string()));
             }
+            // Invalid ones stay in the graph as no-op steps
+            "SHELL" => match crate::sandbox::shell::parse(args) {
+                Ok(shell) => push(Instruction::Shell(shell)),
+                Err(e) => {
+                    error(format!("invalid SHELL: {}", e));
+                    push(Instruction::Other(line.to_string()))
+                }
+            },
+            "HEALTHCHECK" => match crate::docker::healthcheck::parse(args) {
+                Ok(_) => push(Instruction::Healthcheck(args.to_string())),
+                Err(e) => {
+                    error(format!("invalid HEALTHCHECK: {}", e));
+                    push(Instruction::Other(line.to_string()))
+                }
+            },
             "GIT" => {
                 if parts.len() >= 3 {
-                    instructions.push(Instruction::Git(parts[1].to_string(), parts[2].to_string()));
+                    push(Instruction::Git(parts[1].to_string(), parts[2].to_string()));
                 } else if parts.len() == 2 {
                     // Default target dir to the repo name or "."
-                    instructions.push(Instruction::Git(parts[1].to_string(), ".".to_string()));
+                    push(Instruction::Git(parts[1].to_string(), ".".to_string()));
+                } else {
+                    error("GIT needs a repository URL".to_string());
                 }
             }
             "RUN_EXTEND" => {
                 // Defaults parallelizable=true
-                instructions.push(Instruction::RunExtend(args.to_string(), true));
+                push(Instruction::RunExtend(args.to_string(), true));
             }
             "COPY_EXTEND" => {
                 // copy_extend src dst [tags...]
@@ -87,7 +311,9 @@ pub fn parse_dockerfile(content: &str) -> Vec<Instruction> {
                     let src = parts[1].to_string();
                     let dst = parts[2].to_string();
                     let tags: Vec<String> = parts[3..].iter().map(|s| s.to_string()).collect();
-                    instructions.push(Instruction::CopyExtend(src, dst, tags));
+                    push(Instruction::CopyExtend(src, dst, tags));
+                } else {
+                    error("COPY_EXTEND needs a source and a destination".to_string());
                 }
             }
             "HOOK" => {
@@ -95,14 +321,71 @@ pub fn parse_dockerfile(content: &str) -> Vec<Instruction> {
                 if parts.len() >= 2 {
                     let hook_name = parts[1].to_string();
                     let params = parts[2..].iter().map(|s| s.to_string()).collect();
-                    instructions.push(Instruction::Hook(hook_name, params));
+                    push(Instruction::Hook(hook_name, params));
+                } else {
+                    error("HOOK needs a hook name".to_string());
+                }
+            }
+            "INCLUDE" => {
+                if parts.len() >= 2 {
+                    push(Instruction::Include(parts[1].to_string()));
+                } else {
+                    error("INCLUDE needs a path".to_string());
+                }
+            }
+            "BUILD" => {
+                // BUILD [--file=<Dockerfile>] <context>
+                let mut file = None;
+                let mut operands = Vec::new();
+                for part in &parts[1..] {
+                    if let Some(flag) = part.strip_prefix("--") {
+                        if let Some(value) = flag.strip_prefix("file=") {
+                            file = Some(value.to_string());
+                        }
+                    } else {
+                        operands.push(part.to_string());
+                    }
+                }
+                if let Some(context) = operands.first() {
+                    push(Instruction::Build(context.clone(), file));
+                } else {
+                    error("BUILD needs a context directory".to_string());
                 }
             }
             _ => {
-                instructions.push(Instruction::Other(line.to_string()));
+                push(Instruction::Other(line.to_string()));
             }
         }
+        // Directives above a malformed instruction do not leak onto the next one
+        pending_directives.clear();
     }
 
-    instructions
+    (instructions, errors)
+}
+
+/// Whether `COPY --from=<from>` names a build stage, by index or by an alias
+/// declared earlier, rather than an image.
+fn is_stage_ref(from: &str, stages: &[String]) -> bool {
+    from.chars().all(|c| c.is_ascii_digit()) || stages.contains(&from.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directive_lists_continue_after_commas() {
+        let instructions = parse_dockerfile_spanned(
+            "FROM node\n\
+             # memobuild: inputs=src/**, package.json, tsconfig.json timeout=5s\n\
+             # memobuild: no-cache\n\
+             RUN npm run build\n",
+            Path::new("Dockerfile"),
+        );
+        let directives = &instructions[1].directives;
+        assert_eq!(directives["inputs"], "src/**,package.json,tsconfig.json");
+        assert_eq!(directives["timeout"], "5s");
+        assert_eq!(directives["no-cache"], "");
+        assert!(instructions[0].directives.is_empty());
+    }
 }
@@ -0,0 +1,252 @@
+use crate::docker::parser::Span;
+use crate::docker::resolve::ImageRef;
+use crate::error::MemoBuildError;
+
+/// Everything known about a base image at the time its FROM is resolved.
+#[derive(Debug)]
+pub struct BaseImageInfo<'a> {
+    /// The reference as written in the Dockerfile
+    pub image: &'a str,
+    pub reference: &'a ImageRef,
+    /// Manifest digest, `None` if the registry could not be reached
+    pub digest: Option<&'a str>,
+    /// Image creation time in unix seconds, if the registry reported it
+    pub created: Option<i64>,
+    pub span: Option<&'a Span>,
+}
+
+/// A check run against every resolved base image.
+///
+/// Return `Err` with a human readable reason to reject the image.
+pub trait BaseImagePolicy: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn check(&self, info: &BaseImageInfo) -> Result<(), String>;
+
+    /// Whether this policy looks at `BaseImageInfo::created`. Fetching the
+    /// creation date costs extra registry round-trips, so it is skipped
+    /// unless some policy asks for it.
+    fn needs_created(&self) -> bool {
+        false
+    }
+}
+
+/// Rejects images created more than `max_age_secs` ago. Images whose age
+/// cannot be determined pass.
+pub struct MaxImageAge {
+    pub max_age_secs: i64,
+}
+
+impl BaseImagePolicy for MaxImageAge {
+    fn name(&self) -> &str {
+        "max-image-age"
+    }
+
+    fn check(&self, info: &BaseImageInfo) -> Result<(), String> {
+        let Some(created) = info.created else {
+            return Ok(());
+        };
+        let age = chrono::Utc::now().timestamp() - created;
+        if age > self.max_age_secs {
+            return Err(format!(
+                "image is {} days old, the limit is {} days",
+                age / 86400,
+                self.max_age_secs / 86400
+            ));
+        }
+        Ok(())
+    }
+
+    fn needs_created(&self) -> bool {
+        true
+    }
+}
+
+/// Only allows images from the listed registries (e.g. `docker.io`, `ghcr.io`).
+pub struct AllowedRegistries {
+    pub registries: Vec<String>,
+}
+
+impl BaseImagePolicy for AllowedRegistries {
+    fn name(&self) -> &str {
+        "allowed-registries"
+    }
+
+    fn check(&self, info: &BaseImageInfo) -> Result<(), String> {
+        if self.registries.contains(&info.reference.registry) {
+            return Ok(());
+        }
+        Err(format!(
+            "registry '{}' is not in the allowed list ({})",
+            info.reference.registry,
+            self.registries.join(", ")
+        ))
+    }
+}
+
+/// Rejects images using a denylisted tag. Entries are either a bare tag
+/// (`latest`) or a full reference as written in FROM (`node:16`).
+pub struct DeniedTags {
+    pub tags: Vec<String>,
+}
+
+impl BaseImagePolicy for DeniedTags {
+    fn name(&self) -> &str {
+        "denied-tags"
+    }
+
+    fn check(&self, info: &BaseImageInfo) -> Result<(), String> {
+        // A digest pins the image, so only a reference with neither is `latest`
+        let tag = match (&info.reference.tag, &info.reference.digest) {
+            (Some(tag), _) => Some(tag.as_str()),
+            (None, Some(_)) => None,
+            (None, None) => Some("latest"),
+        };
+        match self
+            .tags
+            .iter()
+            .find(|t| tag == Some(t.as_str()) || *t == info.image)
+        {
+            Some(denied) => Err(format!("'{}' is denylisted", denied)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The set of policies applied during a build.
+#[derive(Default)]
+pub struct PolicySet {
+    policies: Vec<Box<dyn BaseImagePolicy>>,
+}
+
+impl PolicySet {
+    /// Build the policy set from `MEMOBUILD_POLICY_*` environment variables:
+    /// `MAX_IMAGE_AGE_DAYS`, `ALLOWED_REGISTRIES` and `DENIED_TAGS`
+    /// (the latter two comma separated).
+    pub fn from_env() -> Self {
+        let list = |var: &str| -> Option<Vec<String>> {
+            let value = std::env::var(var).ok()?;
+            let items: Vec<String> = value
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+            (!items.is_empty()).then_some(items)
+        };
+
+        let mut set = Self::default();
+        if let Some(days) = std::env::var("MEMOBUILD_POLICY_MAX_IMAGE_AGE_DAYS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+        {
+            set.add(MaxImageAge {
+                max_age_secs: days * 86400,
+            });
+        }
+        if let Some(registries) = list("MEMOBUILD_POLICY_ALLOWED_REGISTRIES") {
+            set.add(AllowedRegistries { registries });
+        }
+        if let Some(tags) = list("MEMOBUILD_POLICY_DENIED_TAGS") {
+            set.add(DeniedTags { tags });
+        }
+        set
+    }
+
+    pub fn add<P: BaseImagePolicy + 'static>(&mut self, policy: P) {
+        self.policies.push(Box::new(policy));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+
+    pub fn needs_created(&self) -> bool {
+        self.policies.iter().any(|p| p.needs_created())
+    }
+
+    /// Run every policy, failing on the first violation.
+    pub fn check(&self, info: &BaseImageInfo) -> Result<(), MemoBuildError> {
+        for policy in &self.policies {
+            if let Err(reason) = policy.check(info) {
+                let location = info.span.map(|s| format!("{}: ", s)).unwrap_or_default();
+                return Err(MemoBuildError::ConstraintViolation {
+                    reason: format!(
+                        "{}base image '{}' rejected by policy '{}': {}",
+                        location,
+                        info.image,
+                        policy.name(),
+                        reason
+                    ),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info<'a>(
+        image: &'a str,
+        reference: &'a ImageRef,
+        created: Option<i64>,
+    ) -> BaseImageInfo<'a> {
+        BaseImageInfo {
+            image,
+            reference,
+            digest: Some("sha256:1111"),
+            created,
+            span: None,
+        }
+    }
+
+    #[test]
+    fn test_registry_and_tag_policies() {
+        let mut set = PolicySet::default();
+        set.add(AllowedRegistries {
+            registries: vec!["docker.io".to_string()],
+        });
+        set.add(DeniedTags {
+            tags: vec!["latest".to_string(), "node:16".to_string()],
+        });
+
+        let ok = ImageRef::parse("node:20");
+        assert!(set.check(&info("node:20", &ok, None)).is_ok());
+
+        let foreign = ImageRef::parse("ghcr.io/org/app:1.0");
+        let err = set
+            .check(&info("ghcr.io/org/app:1.0", &foreign, None))
+            .unwrap_err();
+        assert!(matches!(err, MemoBuildError::ConstraintViolation { .. }));
+        assert!(err.to_string().contains("allowed-registries"));
+
+        let untagged = ImageRef::parse("nginx");
+        assert!(set.check(&info("nginx", &untagged, None)).is_err());
+        let pinned_ref = format!("nginx@sha256:{}", "a".repeat(64));
+        let pinned = ImageRef::parse(&pinned_ref);
+        assert!(set.check(&info(&pinned_ref, &pinned, None)).is_ok());
+        let old = ImageRef::parse("node:16");
+        assert!(set.check(&info("node:16", &old, None)).is_err());
+    }
+
+    #[test]
+    fn test_max_image_age() {
+        let mut set = PolicySet::default();
+        set.add(MaxImageAge {
+            max_age_secs: 30 * 86400,
+        });
+        assert!(set.needs_created());
+
+        let image = ImageRef::parse("node:20");
+        let now = chrono::Utc::now().timestamp();
+        assert!(set
+            .check(&info("node:20", &image, Some(now - 86400)))
+            .is_ok());
+        assert!(set
+            .check(&info("node:20", &image, Some(now - 90 * 86400)))
+            .is_err());
+        assert!(set.check(&info("node:20", &image, None)).is_ok());
+    }
+}
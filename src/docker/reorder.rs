@@ -0,0 +1,449 @@
+//! Rewriting a Dockerfile into an order where steps rebuild less often, with
+//! only moves that keep the build's result.
+
+use crate::docker::lint::{Diagnostic, LintRule, Severity};
+use crate::docker::parser::{self, Span};
+use crate::history::BuildHistory;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Dependency install commands and the files that determine what they install.
+/// `pnpm install` comes before `npm install`, which it contains.
+const INSTALL_MANIFESTS: &[(&str, &[&str])] = &[
+    ("pnpm install", &["package.json", "pnpm-lock.yaml"]),
+    (
+        "yarn install",
+        &["package.json", "yarn.lock", ".yarnrc.yml"],
+    ),
+    (
+        "npm ci",
+        &["package.json", "package-lock.json", "npm-shrinkwrap.json"],
+    ),
+    (
+        "npm install",
+        &["package.json", "package-lock.json", "npm-shrinkwrap.json"],
+    ),
+    ("pip install", &["requirements.txt"]),
+    ("poetry install", &["pyproject.toml", "poetry.lock"]),
+    ("bundle install", &["Gemfile", "Gemfile.lock"]),
+    ("go mod download", &["go.mod", "go.sum"]),
+    ("cargo fetch", &["Cargo.toml", "Cargo.lock"]),
+    ("composer install", &["composer.json", "composer.lock"]),
+];
+
+/// Instructions that neither read nor write the filesystem of the image
+const METADATA_INSTRUCTIONS: &[&str] = &["ENV", "ARG", "LABEL", "EXPOSE"];
+
+/// One instruction with the comments and blank lines written above it, which
+/// move along with it (directives apply to the instruction below them).
+#[derive(Debug, Clone)]
+struct Block {
+    lines: Vec<String>,
+    /// 1-based line of the instruction itself
+    line: usize,
+    keyword: String,
+    /// Instruction text with continuations joined
+    text: String,
+}
+
+impl Block {
+    /// Arguments without `--flag` options
+    fn args(&self) -> Vec<&str> {
+        self.text
+            .split_whitespace()
+            .skip(1)
+            .filter(|a| !a.starts_with("--"))
+            .collect()
+    }
+
+    fn flags(&self) -> Vec<&str> {
+        self.text
+            .split_whitespace()
+            .skip(1)
+            .filter(|a| a.starts_with("--"))
+            .collect()
+    }
+
+    fn copies_whole_context(&self) -> bool {
+        self.keyword == "COPY"
+            && self.args().len() == 2
+            && matches!(self.args()[0], "." | "./")
+            && !self.text.contains('$')
+    }
+}
+
+/// Result of [`suggest`].
+#[derive(Debug, Clone)]
+pub struct Reordering {
+    /// Orderings not already reported by the lint pass
+    pub diagnostics: Vec<Diagnostic>,
+    /// The Dockerfile with every suggested move applied
+    pub fixed: String,
+    /// Number of moves applied in `fixed`
+    pub fixes: usize,
+}
+
+/// Look for cache-hostile orderings in `content` and rewrite them. `history`
+/// supplies how often each step rebuilt; without it, only the install rule
+/// applies.
+pub fn suggest(
+    content: &str,
+    dockerfile: &Path,
+    context_dir: &Path,
+    history: &BuildHistory,
+) -> Reordering {
+    let (stages, trailing) = split_blocks(content);
+    let rebuilds = rebuild_counts(content, dockerfile, history);
+
+    let mut diagnostics = Vec::new();
+    let mut fixes = 0;
+    let mut out: Vec<Block> = Vec::new();
+    for mut stage in stages {
+        fixes += hoist_installs(&mut stage, context_dir);
+        fixes += sort_copies(
+            &mut stage,
+            dockerfile,
+            &rebuilds,
+            history.builds,
+            &mut diagnostics,
+        );
+        out.extend(stage);
+    }
+
+    let mut lines: Vec<String> = out.into_iter().flat_map(|b| b.lines).collect();
+    lines.extend(trailing);
+    let mut fixed = lines.join("\n");
+    if content.ends_with('\n') {
+        fixed.push('\n');
+    }
+    Reordering {
+        diagnostics,
+        fixed,
+        fixes,
+    }
+}
+
+/// Split `content` into blocks grouped by stage, plus lines after the last
+/// instruction.
+fn split_blocks(content: &str) -> (Vec<Vec<Block>>, Vec<String>) {
+    let mut stages: Vec<Vec<Block>> = vec![Vec::new()];
+    let mut pending: Vec<String> = Vec::new();
+    let mut current: Option<Block> = None;
+
+    for (i, raw) in content.lines().enumerate() {
+        if let Some(ref mut block) = current {
+            block.lines.push(raw.to_string());
+            block
+                .text
+                .push_str(&format!(" {}", raw.trim().trim_end_matches('\\')));
+            if !raw.trim_end().ends_with('\\') {
+                push_block(&mut stages, current.take().unwrap());
+            }
+            continue;
+        }
+
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            pending.push(raw.to_string());
+            continue;
+        }
+        let mut lines = std::mem::take(&mut pending);
+        lines.push(raw.to_string());
+        let block = Block {
+            lines,
+            line: i + 1,
+            keyword: line.split_whitespace().next().unwrap_or("").to_uppercase(),
+            text: line.trim_end_matches('\\').trim().to_string(),
+        };
+        if line.ends_with('\\') {
+            current = Some(block);
+        } else {
+            push_block(&mut stages, block);
+        }
+    }
+    if let Some(block) = current {
+        push_block(&mut stages, block);
+    }
+    (stages, pending)
+}
+
+fn push_block(stages: &mut Vec<Vec<Block>>, block: Block) {
+    if block.keyword == "FROM" && !stages.last().map(Vec::is_empty).unwrap_or(true) {
+        stages.push(Vec::new());
+    }
+    stages.last_mut().unwrap().push(block);
+}
+
+/// Times each instruction line ran in past builds.
+fn rebuild_counts(content: &str, dockerfile: &Path, history: &BuildHistory) -> HashMap<usize, u32> {
+    parser::parse_dockerfile_spanned(content, dockerfile)
+        .into_iter()
+        .filter_map(|s| {
+            let name = format!("{:?}", s.node);
+            history.nodes.get(&name).map(|h| (s.span.line, h.runs))
+        })
+        .collect()
+}
+
+/// `COPY . <dst>` followed by a dependency install: copy the install's
+/// manifests, install, then copy the rest. Only ENV/ARG/LABEL/EXPOSE may sit
+/// between the two; they move up with the install.
+fn hoist_installs(stage: &mut Vec<Block>, context_dir: &Path) -> usize {
+    let mut fixes = 0;
+    let mut i = 0;
+    while i < stage.len() {
+        if !stage[i].copies_whole_context() {
+            i += 1;
+            continue;
+        }
+        let Some(offset) = stage[i + 1..]
+            .iter()
+            .position(|b| !METADATA_INSTRUCTIONS.contains(&b.keyword.as_str()))
+        else {
+            break;
+        };
+        let run = i + 1 + offset;
+        let manifests = match install_manifests(&stage[run]) {
+            Some(manifests) if stage[run].keyword == "RUN" => manifests,
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+
+        let copied_before: Vec<&str> = stage[..i]
+            .iter()
+            .filter(|b| b.keyword == "COPY")
+            .flat_map(|b| {
+                let args = b.args();
+                args[..args.len().saturating_sub(1)].to_vec()
+            })
+            .collect();
+        let copied = |m: &str| {
+            copied_before
+                .iter()
+                .any(|c| c.trim_start_matches("./") == m)
+        };
+        let missing: Vec<&str> = manifests
+            .iter()
+            .copied()
+            .filter(|m| context_dir.join(m).is_file() && !copied(m))
+            .collect();
+        let already_copied = manifests.iter().any(|m| copied(m));
+        if missing.is_empty() && !already_copied {
+            // Nothing in the context to copy ahead of the install
+            i += 1;
+            continue;
+        }
+
+        let copy_all = stage.remove(i);
+        let mut moved: Vec<Block> = stage.drain(i..run).collect();
+        if !missing.is_empty() {
+            let args = copy_all.args();
+            let dst = args[1];
+            let dst = if dst.ends_with('/') {
+                dst.to_string()
+            } else {
+                format!("{}/", dst)
+            };
+            let mut parts = vec!["COPY".to_string()];
+            parts.extend(copy_all.flags().iter().map(|f| f.to_string()));
+            parts.extend(missing.iter().map(|m| m.to_string()));
+            parts.push(dst);
+            let text = parts.join(" ");
+            let run_index = moved.len() - 1;
+            moved.insert(
+                run_index,
+                Block {
+                    lines: vec![text.clone()],
+                    line: copy_all.line,
+                    keyword: "COPY".into(),
+                    text,
+                },
+            );
+        }
+        let len = moved.len();
+        moved.push(copy_all);
+        for (k, block) in moved.into_iter().enumerate() {
+            stage.insert(i + k, block);
+        }
+        fixes += 1;
+        i += len + 1;
+    }
+    fixes
+}
+
+fn install_manifests(block: &Block) -> Option<&'static [&'static str]> {
+    INSTALL_MANIFESTS
+        .iter()
+        .find(|(pattern, _)| block.text.contains(pattern))
+        .map(|(_, manifests)| *manifests)
+}
+
+/// Consecutive COPY steps into separate destinations can run in any order;
+/// put the ones that rebuild least first so a frequent change does not
+/// invalidate the others.
+fn sort_copies(
+    stage: &mut [Block],
+    dockerfile: &Path,
+    rebuilds: &HashMap<usize, u32>,
+    builds: u32,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> usize {
+    if builds < 2 {
+        return 0;
+    }
+    let mut fixes = 0;
+    let mut start = 0;
+    while start < stage.len() {
+        let mut end = start;
+        while end < stage.len() && independent_copy(&stage[start..end], &stage[end]) {
+            end += 1;
+        }
+        if end - start < 2 {
+            start = end.max(start + 1);
+            continue;
+        }
+
+        let run = &mut stage[start..end];
+        let counts: Option<Vec<u32>> = run.iter().map(|b| rebuilds.get(&b.line).copied()).collect();
+        if let Some(counts) = counts {
+            let mut order: Vec<usize> = (0..run.len()).collect();
+            order.sort_by_key(|&k| counts[k]);
+            if order.iter().enumerate().any(|(pos, &k)| pos != k) {
+                let first = &run[order[0]];
+                let displaced = &run[0];
+                diagnostics.push(Diagnostic {
+                    rule: LintRule::CacheUnfriendlyOrder,
+                    severity: Severity::Info,
+                    span: Some(Span {
+                        file: dockerfile.to_path_buf(),
+                        line: displaced.line,
+                        column: 1,
+                    }),
+                    message: format!(
+                        "this COPY rebuilt in {} of {} builds, the one on line {} in {}; copy rarely changing files first",
+                        counts[0], builds, first.line, counts[order[0]]
+                    ),
+                });
+                let sorted: Vec<Block> = order.iter().map(|&k| run[k].clone()).collect();
+                run.clone_from_slice(&sorted);
+                fixes += 1;
+            }
+        }
+        start = end;
+    }
+    fixes
+}
+
+/// Whether `block` is a COPY whose destination overlaps none in `run`.
+fn independent_copy(run: &[Block], block: &Block) -> bool {
+    if block.keyword != "COPY" || block.text.contains('$') || block.args().len() < 2 {
+        return false;
+    }
+    if block.args()[..block.args().len() - 1]
+        .iter()
+        .any(|src| matches!(*src, "." | "./"))
+    {
+        return false;
+    }
+    let dst = destination(block);
+    run.iter().all(|other| {
+        let other = destination(other);
+        // Relative and absolute paths cannot be compared without WORKDIR
+        other.starts_with('/') == dst.starts_with('/')
+            && !dst.is_empty()
+            && !other.is_empty()
+            && !is_within(&dst, &other)
+            && !is_within(&other, &dst)
+    })
+}
+
+fn destination(block: &Block) -> String {
+    let args = block.args();
+    let dst = args.last().copied().unwrap_or("");
+    let dst = dst.trim_start_matches("./").trim_end_matches('/');
+    if dst == "." {
+        String::new()
+    } else {
+        dst.to_string()
+    }
+}
+
+fn is_within(path: &str, dir: &str) -> bool {
+    path == dir || path.starts_with(&format!("{}/", dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_install_moves_ahead_of_copy_all() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join("package.json"), "{}").unwrap();
+        std::fs::write(dir.path().join("package-lock.json"), "{}").unwrap();
+        let content = "FROM node:20\nWORKDIR /app\n# copy everything\nCOPY --chown=node . .\nENV CI=1\nRUN npm ci\nRUN npm run build\n";
+
+        let result = suggest(
+            content,
+            Path::new("Dockerfile"),
+            dir.path(),
+            &BuildHistory::default(),
+        );
+        assert_eq!(result.fixes, 1);
+        assert_eq!(
+            result.fixed,
+            "FROM node:20\nWORKDIR /app\nENV CI=1\nCOPY --chown=node package.json package-lock.json ./\nRUN npm ci\n# copy everything\nCOPY --chown=node . .\nRUN npm run build\n"
+        );
+
+        // Applying the fix again changes nothing
+        let again = suggest(
+            &result.fixed,
+            Path::new("Dockerfile"),
+            dir.path(),
+            &BuildHistory::default(),
+        );
+        assert_eq!(again.fixes, 0);
+        assert_eq!(again.fixed, result.fixed);
+    }
+
+    #[test]
+    fn test_copies_sorted_by_rebuilds() {
+        let dir = TempDir::new().unwrap();
+        let content = "FROM alpine\nCOPY src /app/src\nCOPY config /app/config\nRUN make\n";
+        let mut history = BuildHistory {
+            builds: 10,
+            ..Default::default()
+        };
+        for instruction in parser::parse_dockerfile(content) {
+            let runs = match format!("{:?}", instruction) {
+                name if name.contains("src") => 9,
+                name if name.contains("config") => 1,
+                _ => continue,
+            };
+            history.nodes.insert(
+                format!("{:?}", instruction),
+                crate::history::NodeHistory {
+                    runs,
+                    ..Default::default()
+                },
+            );
+        }
+
+        let result = suggest(content, Path::new("Dockerfile"), dir.path(), &history);
+        assert_eq!(result.fixes, 1);
+        assert_eq!(result.diagnostics.len(), 1);
+        assert_eq!(result.diagnostics[0].rule, LintRule::CacheUnfriendlyOrder);
+        assert_eq!(
+            result.fixed,
+            "FROM alpine\nCOPY config /app/config\nCOPY src /app/src\nRUN make\n"
+        );
+
+        // Overlapping destinations keep their order
+        let nested = "FROM alpine\nCOPY src /app\nCOPY config /app/config\n";
+        let result = suggest(nested, Path::new("Dockerfile"), dir.path(), &history);
+        assert_eq!(result.fixes, 0);
+    }
+}
@@ -0,0 +1,574 @@
+use crate::docker::policy::{BaseImageInfo, PolicySet};
+use crate::export::registry::RegistryClient;
+use crate::graph::{BuildGraph, Node, NodeKind};
+use crate::lockfile::{LockedImage, Lockfile};
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+const DOCKER_HUB: &str = "docker.io";
+const DOCKER_HUB_API: &str = "registry-1.docker.io";
+
+/// A parsed image reference such as `ghcr.io/org/app:1.2@sha256:...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    pub registry: String,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl ImageRef {
+    pub fn parse(image: &str) -> Self {
+        let (name, digest) = match image.split_once('@') {
+            Some((name, digest)) => (name, Some(digest.to_string())),
+            None => (image, None),
+        };
+
+        // Only the last path component carries the tag; earlier ':' is a registry port
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((n, t)) if !t.contains('/') => (n, Some(t.to_string())),
+            _ => (name, None),
+        };
+
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, rest))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host.to_string(), rest.to_string())
+            }
+            _ => (DOCKER_HUB.to_string(), name.to_string()),
+        };
+
+        let repository = if registry == DOCKER_HUB && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository
+        };
+
+        Self {
+            registry,
+            repository,
+            tag,
+            digest,
+        }
+    }
+
+    /// Host to talk to for the registry API
+    pub fn api_host(&self) -> &str {
+        if self.registry == DOCKER_HUB {
+            DOCKER_HUB_API
+        } else {
+            &self.registry
+        }
+    }
+
+    /// Reference to ask the registry for: the digest if pinned, else the tag
+    pub fn reference(&self) -> &str {
+        self.digest
+            .as_deref()
+            .or(self.tag.as_deref())
+            .unwrap_or("latest")
+    }
+}
+
+/// Something that can turn an image reference into a manifest digest.
+pub trait DigestSource {
+    fn resolve(&self, image: &ImageRef) -> Result<String>;
+
+    /// Creation time (unix seconds) of the image behind `digest`, if known.
+    fn created(&self, _image: &ImageRef, _digest: &str) -> Result<Option<i64>> {
+        Ok(None)
+    }
+}
+
+/// Resolves digests by asking the image's registry.
+pub struct RegistryDigestSource;
+
+impl DigestSource for RegistryDigestSource {
+    fn resolve(&self, image: &ImageRef) -> Result<String> {
+        RegistryClient::new(image.api_host(), &image.repository).resolve_digest(image.reference())
+    }
+
+    fn created(&self, image: &ImageRef, digest: &str) -> Result<Option<i64>> {
+        RegistryClient::new(image.api_host(), &image.repository).image_created(digest)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Resolve tags against the registry and record the result in the lockfile
+    Resolve,
+    /// Only use digests already in the lockfile; never contact the registry
+    Locked,
+}
+
+/// Parse a freshness window such as `900`, `30m`, `12h` or `7d` into seconds.
+pub fn parse_max_age(value: &str) -> Result<i64> {
+    let value = value.trim();
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&value[..i], c),
+        _ => (value, 's'),
+    };
+    let scale = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        _ => anyhow::bail!(
+            "Unknown unit in max-age '{}' (expected s, m, h or d)",
+            value
+        ),
+    };
+    let number: i64 = number
+        .parse()
+        .with_context(|| format!("Invalid max-age '{}'", value))?;
+    Ok(number * scale)
+}
+
+/// Global freshness window for tag-based base images
+/// (`MEMOBUILD_BASE_MAX_AGE`), overridden per FROM by `# memobuild:max-age=`.
+pub fn default_max_age_from_env() -> Result<Option<i64>> {
+    match std::env::var("MEMOBUILD_BASE_MAX_AGE") {
+        Ok(value) if !value.trim().is_empty() => parse_max_age(&value)
+            .map(Some)
+            .context("Invalid MEMOBUILD_BASE_MAX_AGE"),
+        _ => Ok(None),
+    }
+}
+
+/// How long a locked digest of `node`'s image may be used without waiting on
+/// the registry. `None` (or `0`) resolves it on every build.
+fn max_age(node: &Node, default: Option<i64>) -> Result<Option<i64>> {
+    let age = match node.metadata.directives.get("max-age") {
+        Some(value) => Some(
+            parse_max_age(value)
+                .with_context(|| format!("{}invalid max-age directive", node.location_prefix()))?,
+        ),
+        None => default,
+    };
+    Ok(age.filter(|&secs| secs > 0))
+}
+
+/// Result of [`pin_base_images`].
+#[derive(Debug, Default)]
+pub struct PinOutcome {
+    /// The lockfile was modified and should be saved
+    pub changed: bool,
+    /// Images served from a fresh lockfile entry, to re-resolve in the background
+    pub revalidate: Vec<String>,
+}
+
+/// Tag on FROM nodes of a nested build that start from one of that build's
+/// own stages; the aliases of the top-level Dockerfile do not cover them.
+pub const STAGE_REF_TAG: &str = "stage-ref";
+
+/// Names introduced with `FROM <image> AS <name>`. The parser drops the alias,
+/// so later `FROM <name>` lines would otherwise look like registry images.
+pub fn stage_aliases(dockerfile: &str) -> HashSet<String> {
+    dockerfile
+        .lines()
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts.as_slice() {
+                [kw, .., as_kw, name]
+                    if kw.eq_ignore_ascii_case("FROM") && as_kw.eq_ignore_ascii_case("AS") =>
+                {
+                    Some(name.to_string())
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Whether a FROM node was tagged [`STAGE_REF_TAG`].
+pub fn is_stage_ref(node: &Node) -> bool {
+    node.metadata.tags.iter().any(|t| t == STAGE_REF_TAG)
+}
+
+/// Pin every FROM node in the graph to a manifest digest.
+///
+/// The digest is stored in the node metadata and becomes part of the node's
+/// cache key, so a retagged base image invalidates everything built on it.
+/// Each resolution is then checked against `policies`; a rejected image fails
+/// with a `ConstraintViolation`.
+///
+/// In [`LockMode::Resolve`], an image with a freshness window (`max-age`
+/// directive, or `default_max_age` seconds) whose lockfile entry was resolved
+/// within it uses the locked digest straight away and is listed in
+/// [`PinOutcome::revalidate`] instead of waiting on the registry.
+pub fn pin_base_images(
+    graph: &mut BuildGraph,
+    lock: &mut Lockfile,
+    mode: LockMode,
+    source: &dyn DigestSource,
+    aliases: &HashSet<String>,
+    policies: &PolicySet,
+    default_max_age: Option<i64>,
+) -> Result<PinOutcome> {
+    let mut outcome = PinOutcome::default();
+    let now = chrono::Utc::now().timestamp();
+
+    for node in graph.nodes.iter_mut() {
+        if node.kind != NodeKind::From {
+            continue;
+        }
+        let Some(image) = node.content.strip_prefix("FROM ") else {
+            continue;
+        };
+        let image = image.trim();
+        if image == "scratch" || aliases.contains(image) || is_stage_ref(node) {
+            continue;
+        }
+
+        let parsed = ImageRef::parse(image);
+        let locked = lock.base_images.get(image).map(|l| l.digest.clone());
+        let digest = if let Some(ref digest) = parsed.digest {
+            // Already pinned in the Dockerfile
+            Some(digest.clone())
+        } else {
+            match mode {
+                LockMode::Locked => match locked {
+                    Some(digest) => Some(digest),
+                    None => anyhow::bail!(
+                        "{}base image '{}' is not in the lockfile; run without --locked to resolve it",
+                        node.location_prefix(),
+                        image
+                    ),
+                },
+                LockMode::Resolve => match max_age(node, default_max_age)? {
+                    Some(age)
+                        if lock
+                            .base_images
+                            .get(image)
+                            .is_some_and(|l| now - l.resolved_at < age) =>
+                    {
+                        if !outcome.revalidate.iter().any(|i| i == image) {
+                            outcome.revalidate.push(image.to_string());
+                        }
+                        locked
+                    }
+                    age => match source.resolve(&parsed) {
+                        Ok(digest) => {
+                            if locked.as_deref() != Some(digest.as_str()) {
+                                lock.base_images.insert(
+                                    image.to_string(),
+                                    LockedImage {
+                                        digest: digest.clone(),
+                                        resolved_at: now,
+                                        created: None,
+                                    },
+                                );
+                                outcome.changed = true;
+                            } else if let (Some(_), Some(entry)) =
+                                (age, lock.base_images.get_mut(image))
+                            {
+                                // Confirmed: restart the freshness window
+                                entry.resolved_at = now;
+                                outcome.changed = true;
+                            }
+                            Some(digest)
+                        }
+                        Err(e) => {
+                            let fallback = if locked.is_some() {
+                                "using locked digest"
+                            } else {
+                                "caching by tag only"
+                            };
+                            println!(
+                                "   ⚠️  Could not resolve {} ({}), {}",
+                                image, e, fallback
+                            );
+                            locked
+                        }
+                    },
+                },
+            }
+        };
+
+        let entry = lock
+            .base_images
+            .get_mut(image)
+            .filter(|l| Some(&l.digest) == digest.as_ref());
+        let mut created = entry.as_ref().and_then(|l| l.created);
+        if created.is_none() && mode == LockMode::Resolve && policies.needs_created() {
+            if let Some(ref digest) = digest {
+                match source.created(&parsed, digest) {
+                    Ok(c) => created = c,
+                    Err(e) => println!("   ⚠️  Could not read creation date of {} ({})", image, e),
+                }
+                if let (Some(entry), Some(_)) = (entry, created) {
+                    entry.created = created;
+                    outcome.changed = true;
+                }
+            }
+        }
+
+        policies.check(&BaseImageInfo {
+            image,
+            reference: &parsed,
+            digest: digest.as_deref(),
+            created,
+            span: node.metadata.span.as_ref(),
+        })?;
+
+        node.metadata.base_image_digest = digest;
+    }
+
+    Ok(outcome)
+}
+
+/// Re-resolve `images` and record the results in the lockfile at
+/// `lock_path`, for the next build. Returns the images whose digest moved.
+pub fn revalidate(
+    lock_path: &Path,
+    images: &[String],
+    source: &dyn DigestSource,
+) -> Result<Vec<String>> {
+    let mut resolved = Vec::new();
+    for image in images {
+        match source.resolve(&ImageRef::parse(image)) {
+            Ok(digest) => resolved.push((image, digest)),
+            Err(e) => println!("   ⚠️  Could not revalidate {} ({})", image, e),
+        }
+    }
+    if resolved.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Reloaded so entries written since pinning are kept
+    let mut lock = Lockfile::load(lock_path)?;
+    let now = chrono::Utc::now().timestamp();
+    let mut moved = Vec::new();
+    for (image, digest) in resolved {
+        match lock.base_images.get_mut(image.as_str()) {
+            Some(entry) if entry.digest == digest => entry.resolved_at = now,
+            _ => {
+                lock.base_images.insert(
+                    image.clone(),
+                    LockedImage {
+                        digest,
+                        resolved_at: now,
+                        created: None,
+                    },
+                );
+                moved.push(image.clone());
+            }
+        }
+    }
+    lock.save(lock_path)?;
+    Ok(moved)
+}
+
+/// Background [`revalidate`] of the images a build took from fresh lockfile
+/// entries. Dropping it waits for the lookups to finish, so the lockfile is
+/// up to date when the process exits.
+pub struct Revalidation {
+    handle: Option<std::thread::JoinHandle<Result<Vec<String>>>>,
+    lock_path: PathBuf,
+}
+
+impl Revalidation {
+    pub fn spawn(
+        lock_path: PathBuf,
+        images: Vec<String>,
+        source: Box<dyn DigestSource + Send>,
+    ) -> Self {
+        let path = lock_path.clone();
+        let handle = (!images.is_empty())
+            .then(|| std::thread::spawn(move || revalidate(&path, &images, source.as_ref())));
+        Self { handle, lock_path }
+    }
+}
+
+impl Drop for Revalidation {
+    fn drop(&mut self) {
+        let Some(handle) = self.handle.take() else {
+            return;
+        };
+        match handle.join() {
+            Ok(Ok(moved)) => {
+                for image in moved {
+                    println!(
+                        "   🔄 {} moved; {} updated for the next build",
+                        image,
+                        self.lock_path.display()
+                    );
+                }
+            }
+            Ok(Err(e)) => println!("   ⚠️  Background revalidation failed: {:#}", e),
+            Err(_) => println!("   ⚠️  Background revalidation panicked"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::dag::build_graph_from_spanned;
+    use crate::docker::parser::parse_dockerfile_spanned;
+    use std::path::PathBuf;
+
+    struct FixedSource(&'static str);
+
+    impl DigestSource for FixedSource {
+        fn resolve(&self, _image: &ImageRef) -> Result<String> {
+            Ok(self.0.to_string())
+        }
+    }
+
+    fn graph(content: &str) -> BuildGraph {
+        build_graph_from_spanned(
+            parse_dockerfile_spanned(content, Path::new("Dockerfile")),
+            PathBuf::from("."),
+        )
+    }
+
+    #[test]
+    fn test_image_ref_parse() {
+        let hub = ImageRef::parse("nginx");
+        assert_eq!(hub.registry, "docker.io");
+        assert_eq!(hub.repository, "library/nginx");
+        assert_eq!(hub.reference(), "latest");
+        assert_eq!(hub.api_host(), "registry-1.docker.io");
+
+        let private = ImageRef::parse("localhost:5000/team/app:1.2@sha256:abcd");
+        assert_eq!(private.registry, "localhost:5000");
+        assert_eq!(private.repository, "team/app");
+        assert_eq!(private.tag.as_deref(), Some("1.2"));
+        assert_eq!(private.reference(), "sha256:abcd");
+    }
+
+    #[test]
+    fn test_pin_records_lock_and_changes_key_input() {
+        let content = "FROM node:20 AS build\nRUN npm ci\nFROM build\n";
+        let mut g = graph(content);
+        let mut lock = Lockfile::default();
+
+        let changed = pin_base_images(
+            &mut g,
+            &mut lock,
+            LockMode::Resolve,
+            &FixedSource("sha256:1111"),
+            &stage_aliases(content),
+            &PolicySet::default(),
+            None,
+        )
+        .unwrap()
+        .changed;
+
+        assert!(changed);
+        assert_eq!(
+            g.nodes[0].metadata.base_image_digest.as_deref(),
+            Some("sha256:1111")
+        );
+        assert!(g.nodes[2].metadata.base_image_digest.is_none());
+        assert_eq!(lock.base_images["node:20"].digest, "sha256:1111");
+    }
+
+    #[test]
+    fn test_locked_mode_requires_entry() {
+        let mut g = graph("FROM node:20\n");
+        let mut lock = Lockfile::default();
+        let source = FixedSource("sha256:1111");
+
+        assert!(pin_base_images(
+            &mut g,
+            &mut lock,
+            LockMode::Locked,
+            &source,
+            &HashSet::new(),
+            &PolicySet::default(),
+            None,
+        )
+        .is_err());
+
+        pin_base_images(
+            &mut g,
+            &mut lock,
+            LockMode::Resolve,
+            &source,
+            &HashSet::new(),
+            &PolicySet::default(),
+            None,
+        )
+        .unwrap();
+        let changed = pin_base_images(
+            &mut g,
+            &mut lock,
+            LockMode::Locked,
+            &FixedSource("sha256:2222"),
+            &HashSet::new(),
+            &PolicySet::default(),
+            None,
+        )
+        .unwrap()
+        .changed;
+        assert!(!changed);
+        assert_eq!(
+            g.nodes[0].metadata.base_image_digest.as_deref(),
+            Some("sha256:1111")
+        );
+    }
+
+    #[test]
+    fn test_fresh_entry_skips_registry_until_revalidated() {
+        let content = "# memobuild:max-age=1h\nFROM node:20\n";
+        let dir = tempfile::TempDir::new().unwrap();
+        let lock_path = dir.path().join("memobuild.lock");
+        let mut lock = Lockfile::default();
+        lock.base_images.insert(
+            "node:20".into(),
+            LockedImage {
+                digest: "sha256:1111".into(),
+                resolved_at: chrono::Utc::now().timestamp() - 60,
+                created: None,
+            },
+        );
+        lock.save(&lock_path).unwrap();
+
+        let mut g = graph(content);
+        let outcome = pin_base_images(
+            &mut g,
+            &mut lock,
+            LockMode::Resolve,
+            &FixedSource("sha256:2222"),
+            &HashSet::new(),
+            &PolicySet::default(),
+            None,
+        )
+        .unwrap();
+        assert!(!outcome.changed);
+        assert_eq!(outcome.revalidate, vec!["node:20".to_string()]);
+        assert_eq!(
+            g.nodes[0].metadata.base_image_digest.as_deref(),
+            Some("sha256:1111")
+        );
+
+        let moved =
+            revalidate(&lock_path, &outcome.revalidate, &FixedSource("sha256:2222")).unwrap();
+        assert_eq!(moved, vec!["node:20".to_string()]);
+        assert_eq!(
+            Lockfile::load(&lock_path).unwrap().base_images["node:20"].digest,
+            "sha256:2222"
+        );
+
+        // Outside the window the registry answers before the build goes on
+        let mut g = graph("FROM node:20\n");
+        let outcome = pin_base_images(
+            &mut g,
+            &mut lock,
+            LockMode::Resolve,
+            &FixedSource("sha256:3333"),
+            &HashSet::new(),
+            &PolicySet::default(),
+            Some(0),
+        )
+        .unwrap();
+        assert!(outcome.changed);
+        assert!(outcome.revalidate.is_empty());
+        assert_eq!(parse_max_age("7d").unwrap(), 7 * 86400);
+        assert!(parse_max_age("5w").is_err());
+    }
+}
@@ -0,0 +1,198 @@
+//! What changed in the build environment since the previous build.
+
+use crate::env::EnvFingerprint;
+use crate::graph::BuildGraph;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvChangeKind {
+    Os,
+    Arch,
+    Var,
+    Tool,
+}
+
+impl std::fmt::Display for EnvChangeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvChangeKind::Os => write!(f, "os"),
+            EnvChangeKind::Arch => write!(f, "arch"),
+            EnvChangeKind::Var => write!(f, "env"),
+            EnvChangeKind::Tool => write!(f, "tool"),
+        }
+    }
+}
+
+/// One difference between two fingerprints. `None` means absent on that side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvChange {
+    pub kind: EnvChangeKind,
+    pub name: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl EnvChange {
+    /// Whether the change alone gives steps new cache keys. Of the
+    /// fingerprint, only the platform is part of them.
+    pub fn affects_keys(&self) -> bool {
+        matches!(self.kind, EnvChangeKind::Os | EnvChangeKind::Arch)
+    }
+}
+
+impl std::fmt::Display for EnvChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.before, &self.after) {
+            (Some(before), Some(after)) => {
+                write!(f, "{} {}: {} → {}", self.kind, self.name, before, after)
+            }
+            (None, Some(after)) => write!(f, "{} {}: added ({})", self.kind, self.name, after),
+            (Some(before), None) => {
+                write!(f, "{} {}: removed (was {})", self.kind, self.name, before)
+            }
+            (None, None) => write!(f, "{} {}", self.kind, self.name),
+        }
+    }
+}
+
+/// Every difference from `previous` to `current`, sorted by kind and name.
+pub fn diff(previous: &EnvFingerprint, current: &EnvFingerprint) -> Vec<EnvChange> {
+    let mut changes = Vec::new();
+    if previous.os != current.os {
+        changes.push(EnvChange {
+            kind: EnvChangeKind::Os,
+            name: "os".into(),
+            before: Some(previous.os.clone()),
+            after: Some(current.os.clone()),
+        });
+    }
+    if previous.arch != current.arch {
+        changes.push(EnvChange {
+            kind: EnvChangeKind::Arch,
+            name: "arch".into(),
+            before: Some(previous.arch.clone()),
+            after: Some(current.arch.clone()),
+        });
+    }
+    diff_maps(
+        EnvChangeKind::Var,
+        &previous.env_vars,
+        &current.env_vars,
+        &mut changes,
+    );
+    diff_maps(
+        EnvChangeKind::Tool,
+        &previous.toolchain,
+        &current.toolchain,
+        &mut changes,
+    );
+    changes
+}
+
+fn diff_maps(
+    kind: EnvChangeKind,
+    before: &BTreeMap<String, String>,
+    after: &BTreeMap<String, String>,
+    out: &mut Vec<EnvChange>,
+) {
+    let names: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    for name in names {
+        let (b, a) = (before.get(name), after.get(name));
+        if b != a {
+            out.push(EnvChange {
+                kind,
+                name: name.clone(),
+                before: b.cloned(),
+                after: a.cloned(),
+            });
+        }
+    }
+}
+
+/// Nodes of `graph` (hashed under `current`) whose cache key would have been
+/// different under `previous`, i.e. the nodes the drift alone invalidated.
+/// Tool and variable changes invalidate none; see [`EnvChange::affects_keys`].
+pub fn invalidated_nodes(
+    graph: &BuildGraph,
+    previous: &EnvFingerprint,
+    current: &EnvFingerprint,
+) -> usize {
+    if previous.platform() == current.platform() {
+        return 0;
+    }
+    let mut before = graph.clone();
+    crate::core::compute_composite_hashes(&mut before, previous);
+    before
+        .nodes
+        .iter()
+        .zip(&graph.nodes)
+        .filter(|(b, a)| b.hash != a.hash)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_reports_each_change() {
+        let mut previous = EnvFingerprint {
+            os: "linux".into(),
+            arch: "x86_64".into(),
+            ..Default::default()
+        };
+        previous.env_vars.insert("LANG".into(), "C".into());
+        previous.toolchain.insert("node".into(), "v18.17.0".into());
+        previous.toolchain.insert("go".into(), "go1.21".into());
+
+        let mut current = previous.clone();
+        current.toolchain.insert("node".into(), "v20.1.0".into());
+        current.toolchain.remove("go");
+        current
+            .env_vars
+            .insert("NODE_ENV".into(), "production".into());
+
+        let changes = diff(&previous, &current);
+        let lines: Vec<String> = changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "env NODE_ENV: added (production)",
+                "tool go: removed (was go1.21)",
+                "tool node: v18.17.0 → v20.1.0",
+            ]
+        );
+        assert!(diff(&current, &current).is_empty());
+    }
+
+    #[test]
+    fn test_only_platform_drift_invalidates_nodes() {
+        use crate::docker::{dag, parser};
+
+        let previous = EnvFingerprint {
+            os: "linux".into(),
+            arch: "x86_64".into(),
+            ..Default::default()
+        };
+        let mut graph = dag::build_graph_from_instructions(
+            parser::parse_dockerfile("FROM alpine\nRUN make\n"),
+            std::path::PathBuf::from("."),
+        );
+
+        // Keys do not include tools, so a new compiler reuses cached nodes
+        let mut current = previous.clone();
+        current.toolchain.insert("go".into(), "go1.22".into());
+        crate::core::compute_composite_hashes(&mut graph, &current);
+        let changes = diff(&previous, &current);
+        assert_eq!(changes.len(), 1);
+        assert!(!changes[0].affects_keys());
+        assert_eq!(invalidated_nodes(&graph, &previous, &current), 0);
+
+        current.arch = "aarch64".into();
+        crate::core::compute_composite_hashes(&mut graph, &current);
+        assert!(diff(&previous, &current)
+            .iter()
+            .any(EnvChange::affects_keys));
+        assert_eq!(invalidated_nodes(&graph, &previous, &current), 2);
+    }
+}
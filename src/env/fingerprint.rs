@@ -50,6 +50,33 @@ impl EnvFingerprint {
         }
     }
 
+    /// Record pinned toolchains in place of whatever the host has installed.
+    pub fn pin_toolchains(&mut self, toolchains: &[crate::toolchains::InstalledToolchain]) {
+        for t in toolchains {
+            self.toolchain.insert(
+                t.name.clone(),
+                format!("{} (sha256:{})", t.version, t.sha256),
+            );
+        }
+    }
+
+    /// `os/arch` of the fingerprinted machine, e.g. `linux/amd64`.
+    pub fn platform(&self) -> String {
+        super::platform::oci_platform(&self.os, &self.arch)
+    }
+
+    /// This fingerprint as seen by a build for `target`: the same tools and
+    /// variables, but the target's OS and architecture (variant included).
+    pub fn for_platform(&self, target: &super::Platform) -> Self {
+        let mut fingerprint = self.clone();
+        fingerprint.os = target.os.clone();
+        fingerprint.arch = match target.variant {
+            Some(ref variant) => format!("{}/{}", target.architecture, variant),
+            None => target.architecture.clone(),
+        };
+        fingerprint
+    }
+
     pub fn hash(&self) -> String {
         let mut hasher = blake3::Hasher::new();
         hasher.update(self.os.as_bytes());
@@ -1,2 +1,5 @@
+pub mod drift;
 pub mod fingerprint;
+pub mod platform;
 pub use fingerprint::EnvFingerprint;
+pub use platform::{host_platform, oci_platform, Platform};
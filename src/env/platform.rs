@@ -0,0 +1,185 @@
+//! Target platforms for multi-platform builds, each with its own instance of
+//! the build graph.
+
+use crate::env::EnvFingerprint;
+use crate::graph::BuildGraph;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// OCI platform name (`linux/amd64`, `darwin/arm64`) for a Rust target OS
+/// and architecture as found in `std::env::consts`.
+pub fn oci_platform(os: &str, arch: &str) -> String {
+    format!("{}/{}", oci_os(os), oci_arch(arch))
+}
+
+fn oci_os(os: &str) -> &str {
+    match os {
+        "macos" => "darwin",
+        other => other,
+    }
+}
+
+fn oci_arch(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        other => other,
+    }
+}
+
+/// OCI platform of this machine.
+pub fn host_platform() -> String {
+    oci_platform(std::env::consts::OS, std::env::consts::ARCH)
+}
+
+/// An OCI platform, serialized the way image indexes describe manifests.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl std::str::FromStr for Platform {
+    type Err = anyhow::Error;
+
+    /// `os/arch` or `os/arch/variant`, e.g. `linux/arm/v7`.
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.trim().split('/').collect();
+        match parts.as_slice() {
+            [os, arch] | [os, arch, _] if !os.is_empty() && !arch.is_empty() => {
+                let variant = parts.get(2).filter(|v| !v.is_empty());
+                Ok(Self {
+                    os: oci_os(&os.to_ascii_lowercase()).to_string(),
+                    architecture: oci_arch(&arch.to_ascii_lowercase()).to_string(),
+                    variant: variant.map(|v| v.to_ascii_lowercase()),
+                })
+            }
+            _ => anyhow::bail!(
+                "Invalid platform '{}' (expected os/arch or os/arch/variant, e.g. linux/arm64)",
+                s
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(ref variant) = self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+impl Platform {
+    /// The platform this machine runs.
+    pub fn host() -> Self {
+        Self {
+            os: oci_os(std::env::consts::OS).to_string(),
+            architecture: oci_arch(std::env::consts::ARCH).to_string(),
+            variant: None,
+        }
+    }
+
+    /// Whether commands built for this platform run here natively.
+    pub fn is_native(&self) -> bool {
+        let host = Self::host();
+        self.os == host.os && self.architecture == host.architecture
+    }
+
+    /// Architecture name QEMU registers its binfmt handler under.
+    fn qemu_arch(&self) -> &str {
+        match self.architecture.as_str() {
+            "amd64" => "x86_64",
+            "arm64" => "aarch64",
+            "386" => "i386",
+            other => other,
+        }
+    }
+
+    /// Whether this machine can run the platform's binaries: natively, or
+    /// through a QEMU user-mode handler registered with binfmt_misc.
+    pub fn runnable(&self) -> bool {
+        if self.is_native() {
+            return true;
+        }
+        self.os == "linux"
+            && std::env::consts::OS == "linux"
+            && std::path::Path::new("/proc/sys/fs/binfmt_misc")
+                .join(format!("qemu-{}", self.qemu_arch()))
+                .exists()
+    }
+
+    /// The automatic platform variables buildx puts in scope.
+    pub fn build_args(&self) -> BTreeMap<String, String> {
+        let build = Self::host();
+        BTreeMap::from([
+            ("TARGETPLATFORM".to_string(), self.to_string()),
+            ("TARGETOS".to_string(), self.os.clone()),
+            ("TARGETARCH".to_string(), self.architecture.clone()),
+            (
+                "TARGETVARIANT".to_string(),
+                self.variant.clone().unwrap_or_default(),
+            ),
+            ("BUILDPLATFORM".to_string(), build.to_string()),
+            ("BUILDOS".to_string(), build.os.clone()),
+            ("BUILDARCH".to_string(), build.architecture.clone()),
+        ])
+    }
+}
+
+/// A copy of `graph` to build for `target`, with the platform variables in
+/// scope (an explicit ENV of the same name wins) and keys hashed under the
+/// target's platform.
+pub fn instantiate(graph: &BuildGraph, env_fp: &EnvFingerprint, target: &Platform) -> BuildGraph {
+    let mut graph = graph.clone();
+    let args = target.build_args();
+    for node in &mut graph.nodes {
+        for (name, value) in &args {
+            node.metadata
+                .build_env
+                .entry(name.clone())
+                .or_insert_with(|| value.clone());
+        }
+    }
+    crate::core::compute_composite_hashes(&mut graph, &env_fp.for_platform(target));
+    graph
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::{dag, parser};
+
+    #[test]
+    fn test_parse_and_instantiate() {
+        let arm: Platform = "linux/arm/v7".parse().unwrap();
+        assert_eq!(arm.architecture, "arm");
+        assert_eq!(arm.variant.as_deref(), Some("v7"));
+        assert_eq!(arm.to_string(), "linux/arm/v7");
+        assert_eq!(
+            "linux/x86_64".parse::<Platform>().unwrap().to_string(),
+            "linux/amd64"
+        );
+        assert!("linux".parse::<Platform>().is_err());
+
+        let graph = dag::build_graph_from_instructions(
+            parser::parse_dockerfile("FROM alpine\nENV TARGETOS=custom\nRUN make\n"),
+            std::path::PathBuf::from("."),
+        );
+        let env_fp = EnvFingerprint::default();
+        let amd64 = instantiate(&graph, &env_fp, &"linux/amd64".parse().unwrap());
+        let arm64 = instantiate(&graph, &env_fp, &"linux/arm64".parse().unwrap());
+
+        let run = &arm64.nodes[2];
+        assert_eq!(run.metadata.platform.as_deref(), Some("linux/arm64"));
+        assert_eq!(run.metadata.build_env["TARGETARCH"], "arm64");
+        assert_eq!(run.metadata.build_env["TARGETOS"], "custom");
+        assert_ne!(amd64.nodes[2].hash, run.hash);
+    }
+}
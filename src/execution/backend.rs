@@ -0,0 +1,368 @@
+//! Backends that turn one runnable node into its artifact, chosen per node kind.
+
+use crate::cache::HybridCache;
+use crate::graph::{Node, NodeKind};
+use crate::sandbox::debug::OnFailure;
+use crate::sandbox::usage::ResourceUsage;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[async_trait]
+pub trait ExecutorBackend: Send + Sync {
+    /// Short name for logs, e.g. `sandbox`
+    fn name(&self) -> &'static str;
+
+    /// Run `node` and return its artifact. Errors fail the node.
+    async fn execute(&self, node: &Node, cache: &HybridCache) -> Result<Vec<u8>>;
+
+    /// Get ready to run `node` while its parents still run, so `execute`
+    /// has less to do. Must not rely on anything the parents produce.
+    async fn prepare(&self, _node: &Node, _cache: &HybridCache) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether artifacts from this backend may be stored in the cache.
+    fn caches_outputs(&self) -> bool {
+        true
+    }
+
+    /// What the last run of `node` cost, once; `None` when the backend
+    /// cannot tell.
+    fn take_usage(&self, _node: &Node) -> Option<ResourceUsage> {
+        None
+    }
+}
+
+/// Runs nodes in a local [`Sandbox`](crate::sandbox::Sandbox): plain, overlay
+/// or containerd.
+pub struct SandboxBackend {
+    sandbox: Arc<dyn crate::sandbox::Sandbox>,
+    /// Usage of nodes that ran, by cache key, until the executor takes it
+    usage: UsageLog,
+    on_failure: OnFailure,
+}
+
+impl SandboxBackend {
+    pub fn new(sandbox: Arc<dyn crate::sandbox::Sandbox>) -> Self {
+        Self {
+            sandbox,
+            usage: UsageLog::default(),
+            on_failure: OnFailure::default(),
+        }
+    }
+
+    /// What to do with the sandbox of a command that fails, before it is
+    /// cleaned up.
+    pub fn with_on_failure(mut self, on_failure: OnFailure) -> Self {
+        self.on_failure = on_failure;
+        self
+    }
+}
+
+#[async_trait]
+impl ExecutorBackend for SandboxBackend {
+    fn name(&self) -> &'static str {
+        "sandbox"
+    }
+
+    async fn execute(&self, node: &Node, _cache: &HybridCache) -> Result<Vec<u8>> {
+        if let NodeKind::RunExtend { command, .. } = &node.kind {
+            println!("⚡ Executing extended RUN: {}", command);
+        } else if let NodeKind::CopyExtend { src, dst, .. } = &node.kind {
+            println!(
+                "⚡ Executing extended COPY: {} -> {}",
+                src.display(),
+                dst.display()
+            );
+        } else if let NodeKind::CustomHook { hook_name, .. } = &node.kind {
+            println!("⚡ Running custom hook: {}", hook_name);
+        }
+
+        let env = self.sandbox.prepare(node).await?;
+
+        // Execute command, always releasing the sandbox (e.g. overlay mounts)
+        let exec_result = self.sandbox.execute(&env, node).await;
+        if let (OnFailure::Shell, Ok(result)) = (self.on_failure, &exec_result) {
+            if result.exit_code != 0 {
+                if let Err(e) = self.sandbox.shell(&env, node, result.exit_code).await {
+                    eprintln!("⚠️  {}", e);
+                }
+            }
+        }
+        // A failed cleanup is reported, not mistaken for the command's outcome
+        let cleanup = self.sandbox.cleanup(&env).await;
+        let exec_result = exec_result?;
+        if let Err(e) = cleanup {
+            eprintln!(
+                "⚠️  Failed to clean up the sandbox of {}: {:#}",
+                node.name, e
+            );
+        }
+        self.usage.record(node, exec_result.usage);
+
+        if exec_result.exit_code != 0 {
+            anyhow::bail!(
+                "{}Command failed with exit code {}: {}",
+                node.location_prefix(),
+                exec_result.exit_code,
+                String::from_utf8_lossy(&exec_result.stderr)
+            );
+        }
+
+        // A captured output diff is the precise artifact; stdout is the fallback
+        Ok(exec_result.output_diff.unwrap_or(exec_result.stdout))
+    }
+
+    fn take_usage(&self, node: &Node) -> Option<ResourceUsage> {
+        self.usage.take(node)
+    }
+}
+
+/// Usage of finished runs, kept by cache key for [`ExecutorBackend::take_usage`].
+#[derive(Default)]
+struct UsageLog(std::sync::Mutex<HashMap<String, ResourceUsage>>);
+
+impl UsageLog {
+    fn record(&self, node: &Node, usage: Option<ResourceUsage>) {
+        if let Some(usage) = usage {
+            self.0.lock().unwrap().insert(node.hash.clone(), usage);
+        }
+    }
+
+    fn take(&self, node: &Node) -> Option<ResourceUsage> {
+        self.0.lock().unwrap().remove(&node.hash)
+    }
+}
+
+/// Make sure the node's input manifest and files are in the CAS, for
+/// backends that run it away from this machine.
+pub(crate) async fn upload_inputs(node: &Node, cache: &HybridCache) -> Result<()> {
+    if node.metadata.input_manifest_hash.is_some() {
+        // If it's a COPY node, we can re-generate and upload
+        if let Some(ref path) = node.source_path {
+            if let Ok(manifest) = crate::cache::utils::ArtifactManifest::from_dir(path) {
+                println!("📤 Uploading input manifest for {}...", node.name);
+                cache.upload_manifest_and_files(&manifest, path).await?;
+            }
+        }
+        // For RUN nodes the manifest was built from parents, whose files
+        // are already in the CAS from their own put_artifact
+    }
+    Ok(())
+}
+
+/// Dispatches nodes to a build farm through a
+/// [`RemoteExecutor`](crate::remote_exec::RemoteExecutor).
+pub struct RemoteBackend {
+    executor: Arc<dyn crate::remote_exec::RemoteExecutor>,
+    /// Nodes whose inputs [`prepare`](ExecutorBackend::prepare) uploaded
+    prepared: std::sync::Mutex<std::collections::HashSet<String>>,
+    /// What workers reported the nodes cost
+    usage: UsageLog,
+}
+
+impl RemoteBackend {
+    pub fn new(executor: Arc<dyn crate::remote_exec::RemoteExecutor>) -> Self {
+        Self {
+            executor,
+            prepared: Default::default(),
+            usage: UsageLog::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl ExecutorBackend for RemoteBackend {
+    fn name(&self) -> &'static str {
+        "remote"
+    }
+
+    async fn prepare(&self, node: &Node, cache: &HybridCache) -> Result<()> {
+        upload_inputs(node, cache).await?;
+        self.prepared.lock().unwrap().insert(node.hash.clone());
+        Ok(())
+    }
+
+    async fn execute(&self, node: &Node, cache: &HybridCache) -> Result<Vec<u8>> {
+        if !self.prepared.lock().unwrap().remove(&node.hash) {
+            upload_inputs(node, cache).await?;
+        }
+
+        println!(
+            "📡 [RemoteExec] Dispatching node {} to build farm",
+            node.name
+        );
+        let action = crate::remote_exec::ActionRequest {
+            command: crate::sandbox::shell::argv(
+                node,
+                &node.content,
+                crate::sandbox::shell::container_default(),
+            ),
+            env: node
+                .metadata
+                .build_env
+                .iter()
+                .chain(&node.env)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            input_root_digest: crate::remote_exec::Digest {
+                hash: node
+                    .metadata
+                    .input_manifest_hash
+                    .clone()
+                    .unwrap_or_else(|| node.hash.clone()),
+                size_bytes: 0, // Placeholder
+            },
+            timeout: Duration::from_secs(crate::constants::DEFAULT_REMOTE_EXECUTION_TIMEOUT_SECS),
+            platform_properties: platform_properties(node)?,
+            output_files: Vec::new(),
+            output_directories: Vec::new(),
+            namespace: std::env::var("MEMOBUILD_NAMESPACE").ok(),
+            priority: node.metadata.priority,
+        };
+
+        let result = self.executor.execute(action).await?;
+        self.usage
+            .record(node, result.execution_metadata.resource_usage);
+        if result.exit_code != 0 {
+            anyhow::bail!(
+                "{}Remote execution failed with exit code {}: {}",
+                node.location_prefix(),
+                result.exit_code,
+                String::from_utf8_lossy(&result.stderr_raw)
+            );
+        }
+        Ok(result.stdout_raw)
+    }
+
+    fn take_usage(&self, node: &Node) -> Option<ResourceUsage> {
+        self.usage.take(node)
+    }
+}
+
+/// What the node needs from a worker: its platform, and what
+/// `# memobuild:requires=` asks for (`memory=8G`, or bare tags such as `gpu`).
+fn platform_properties(node: &Node) -> Result<HashMap<String, String>> {
+    let mut properties = HashMap::new();
+    if let Some(ref platform) = node.metadata.platform {
+        properties.insert("platform".to_string(), platform.clone());
+    }
+    let Some(requires) = node
+        .metadata
+        .directives
+        .get(crate::docker::directives::REQUIRES)
+    else {
+        return Ok(properties);
+    };
+    let mut tags = Vec::new();
+    for item in crate::docker::directives::list(requires) {
+        match item.split_once('=') {
+            Some((key, value)) => {
+                properties.insert(key.trim().to_string(), value.trim().to_string());
+            }
+            None => tags.push(item),
+        }
+    }
+    if !tags.is_empty() {
+        properties.insert("tags".to_string(), tags.join(","));
+    }
+    // Fail here rather than on the scheduler
+    crate::remote_exec::queue::Requirements::from_properties(&properties)
+        .with_context(|| format!("{}invalid requires directive", node.location_prefix()))?;
+    Ok(properties)
+}
+
+/// Runs nothing: every node succeeds after `latency` with a placeholder
+/// artifact. For exercising scheduling, observers and reports; its
+/// artifacts are never cached.
+#[derive(Default)]
+pub struct SimulationBackend {
+    latency: Duration,
+}
+
+impl SimulationBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+}
+
+#[async_trait]
+impl ExecutorBackend for SimulationBackend {
+    fn name(&self) -> &'static str {
+        "simulation"
+    }
+
+    async fn execute(&self, node: &Node, _cache: &HybridCache) -> Result<Vec<u8>> {
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+        Ok(format!("simulated:{}", node.hash).into_bytes())
+    }
+
+    fn caches_outputs(&self) -> bool {
+        false
+    }
+}
+
+/// Picks the backend for a node: an override for its kind, else the default.
+#[derive(Clone)]
+pub struct BackendSelector {
+    default: Arc<dyn ExecutorBackend>,
+    by_kind: HashMap<&'static str, Arc<dyn ExecutorBackend>>,
+}
+
+impl BackendSelector {
+    pub fn new(default: Arc<dyn ExecutorBackend>) -> Self {
+        Self {
+            default,
+            by_kind: HashMap::new(),
+        }
+    }
+
+    pub fn set_default(&mut self, backend: Arc<dyn ExecutorBackend>) {
+        self.default = backend;
+    }
+
+    /// Route nodes whose [`NodeKind::label`] is `kind` to `backend`.
+    pub fn set_for_kind(&mut self, kind: &'static str, backend: Arc<dyn ExecutorBackend>) {
+        self.by_kind.insert(kind, backend);
+    }
+
+    pub fn select(&self, kind: &NodeKind) -> Arc<dyn ExecutorBackend> {
+        self.by_kind
+            .get(kind.label())
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selector_prefers_kind_override() {
+        let sandbox = crate::sandbox::local::LocalSandbox::new(std::env::temp_dir());
+        let mut selector = BackendSelector::new(Arc::new(SandboxBackend::new(Arc::new(sandbox))));
+        selector.set_for_kind("git", Arc::new(SimulationBackend::new()));
+
+        assert_eq!(selector.select(&NodeKind::Run).name(), "sandbox");
+        let git = NodeKind::Git {
+            url: "https://example.com/repo.git".into(),
+            target: "/src".into(),
+        };
+        assert_eq!(selector.select(&git).name(), "simulation");
+
+        selector.set_default(Arc::new(SimulationBackend::new()));
+        assert_eq!(selector.select(&NodeKind::Run).name(), "simulation");
+        assert!(!selector.select(&NodeKind::Run).caches_outputs());
+    }
+}
@@ -0,0 +1,539 @@
+//! How many nodes of a level run at once, per `--jobs` and its pools.
+
+use crate::graph::{Node, NodeKind};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// How often the adaptive limit looks at the load
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// CPU busy fraction above which fewer nodes should run
+const CPU_HIGH: f64 = 0.90;
+/// CPU busy fraction below which more nodes may run
+const CPU_LOW: f64 = 0.75;
+/// Share of CPU time waiting on IO above which fewer nodes should run
+const IO_WAIT_HIGH: f64 = 0.20;
+/// Available memory fraction below which the limit is halved
+const MEMORY_LOW: f64 = 0.10;
+/// Available memory fraction needed to raise the limit
+const MEMORY_OK: f64 = 0.25;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Jobs {
+    /// Every node of a level at once
+    #[default]
+    Unlimited,
+    Fixed(usize),
+    /// Follow the machine's load
+    Adaptive,
+}
+
+impl std::str::FromStr for Jobs {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.trim() {
+            "auto" | "adaptive" => Ok(Jobs::Adaptive),
+            n => match n.parse::<usize>() {
+                Ok(0) => Ok(Jobs::Unlimited),
+                Ok(n) => Ok(Jobs::Fixed(n)),
+                Err(_) => anyhow::bail!("Expected a number of jobs or 'auto', got '{}'", s),
+            },
+        }
+    }
+}
+
+/// A separately limited kind of work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Pool {
+    Cpu,
+    Io,
+}
+
+impl std::str::FromStr for Pool {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.trim() {
+            "cpu" => Ok(Pool::Cpu),
+            "io" => Ok(Pool::Io),
+            _ => anyhow::bail!("Unknown pool '{}', expected cpu or io", s),
+        }
+    }
+}
+
+impl std::fmt::Display for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Pool::Cpu => "cpu",
+            Pool::Io => "io",
+        })
+    }
+}
+
+/// Commands that spend their time waiting on the network or the disk,
+/// matched against the start of each command of a RUN
+const IO_COMMANDS: &[&str] = &[
+    "curl",
+    "wget",
+    "git clone",
+    "git fetch",
+    "git checkout",
+    "apt-get update",
+    "apt-get install",
+    "apt update",
+    "apt install",
+    "apk add",
+    "apk update",
+    "yum install",
+    "dnf install",
+    "pip install",
+    "pip3 install",
+    "npm ci",
+    "npm install",
+    "yarn install",
+    "pnpm install",
+    "go mod download",
+    "cargo fetch",
+    "bundle install",
+    "cp",
+    "mv",
+    "rm",
+    "ln",
+    "mkdir",
+    "chmod",
+    "chown",
+    "tar",
+    "unzip",
+    "echo",
+    "cd",
+];
+
+/// The pool `node` runs in: its `pool` directive, else a guess from what
+/// it does.
+pub fn classify(node: &Node) -> Pool {
+    if let Some(pool) = node
+        .metadata
+        .directives
+        .get(crate::docker::directives::POOL)
+        .and_then(|value| value.parse().ok())
+    {
+        return pool;
+    }
+    let command = match &node.kind {
+        NodeKind::Run => &node.content,
+        NodeKind::RunExtend { command, .. } => command,
+        NodeKind::Task { cmd, .. } => cmd,
+        NodeKind::CustomHook { .. } | NodeKind::Plugin { .. } => return Pool::Cpu,
+        _ => return Pool::Io,
+    };
+    let io_bound = command
+        .split(['&', ';', '|', '\n'])
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .all(|part| {
+            let part = part.strip_prefix("RUN ").unwrap_or(part).trim_start();
+            IO_COMMANDS.iter().any(|io| {
+                part.strip_prefix(io)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+            })
+        });
+    if io_bound {
+        Pool::Io
+    } else {
+        Pool::Cpu
+    }
+}
+
+/// What the machine is busy with, as fractions between 0 and 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LoadSample {
+    pub cpu_busy: f64,
+    pub io_wait: f64,
+    pub memory_available: f64,
+}
+
+/// Cumulative CPU counters of the first line of `/proc/stat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CpuTimes {
+    busy: u64,
+    iowait: u64,
+    total: u64,
+}
+
+fn parse_cpu_times(stat: &str) -> Option<CpuTimes> {
+    let fields: Vec<u64> = stat
+        .lines()
+        .find(|line| line.starts_with("cpu "))?
+        .split_whitespace()
+        .skip(1)
+        .filter_map(|n| n.parse().ok())
+        .collect();
+    // user nice system idle iowait irq softirq steal ...
+    if fields.len() < 5 {
+        return None;
+    }
+    let total = fields.iter().take(8).sum();
+    let (idle, iowait) = (fields[3], fields[4]);
+    Some(CpuTimes {
+        busy: total - idle - iowait,
+        iowait,
+        total,
+    })
+}
+
+/// `MemAvailable` as a fraction of `MemTotal`.
+fn parse_memory_available(meminfo: &str) -> Option<f64> {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|rest| rest.trim_start_matches(':').split_whitespace().next())
+            .and_then(|kb| kb.parse::<u64>().ok())
+    };
+    let total = field("MemTotal")?;
+    (total > 0).then(|| field("MemAvailable").unwrap_or(0) as f64 / total as f64)
+}
+
+/// Reads the load from `/proc`, CPU shares over the time since the
+/// previous sample.
+#[derive(Debug, Default)]
+struct LoadMonitor {
+    last: Option<CpuTimes>,
+}
+
+impl LoadMonitor {
+    fn sample(&mut self) -> Option<LoadSample> {
+        let now = parse_cpu_times(&std::fs::read_to_string("/proc/stat").ok()?)?;
+        let memory_available =
+            parse_memory_available(&std::fs::read_to_string("/proc/meminfo").ok()?)?;
+        let last = self.last.replace(now)?;
+        let total = now.total.saturating_sub(last.total);
+        if total == 0 {
+            return None;
+        }
+        Some(LoadSample {
+            cpu_busy: now.busy.saturating_sub(last.busy) as f64 / total as f64,
+            io_wait: now.iowait.saturating_sub(last.iowait) as f64 / total as f64,
+            memory_available,
+        })
+    }
+}
+
+/// The limit after `sample`, with `running` nodes holding slots. A
+/// `pool` only reacts to the pressure its kind of work causes; without
+/// one, to both.
+pub fn next_limit(
+    limit: usize,
+    running: usize,
+    max: usize,
+    sample: &LoadSample,
+    pool: Option<Pool>,
+) -> usize {
+    let cpu_busy = match pool {
+        Some(Pool::Io) => 0.0,
+        _ => sample.cpu_busy,
+    };
+    let io_wait = match pool {
+        Some(Pool::Cpu) => 0.0,
+        _ => sample.io_wait,
+    };
+    if sample.memory_available < MEMORY_LOW {
+        (limit / 2).max(1)
+    } else if cpu_busy > CPU_HIGH || io_wait > IO_WAIT_HIGH {
+        limit.saturating_sub(1).max(1)
+    } else if cpu_busy < CPU_LOW && sample.memory_available > MEMORY_OK && running >= limit {
+        (limit + 1).min(max)
+    } else {
+        limit
+    }
+}
+
+struct LimiterState {
+    running: usize,
+    limit: usize,
+    monitor: Option<LoadMonitor>,
+    sampled_at: Instant,
+}
+
+/// Hands out slots to start nodes, at most `limit` at a time.
+pub struct Limiter {
+    state: Mutex<LimiterState>,
+    released: Notify,
+    max: usize,
+    /// The pool it limits, when pools are limited separately
+    pool: Option<Pool>,
+}
+
+/// A slot; released when dropped.
+pub struct Slot {
+    limiter: Arc<Limiter>,
+}
+
+impl Limiter {
+    /// `None` for [`Jobs::Unlimited`], which needs no slots.
+    pub fn new(jobs: Jobs, pool: Option<Pool>) -> Option<Arc<Self>> {
+        let cpus = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        // Waiting on IO leaves the CPUs to others
+        let scale = if pool == Some(Pool::Io) { 2 } else { 1 };
+        let (limit, max, monitor) = match jobs {
+            Jobs::Unlimited => return None,
+            Jobs::Fixed(n) => (n, n, None),
+            Jobs::Adaptive => (cpus * scale, cpus * 2 * scale, Some(LoadMonitor::default())),
+        };
+        let mut state = LimiterState {
+            running: 0,
+            limit,
+            monitor,
+            sampled_at: Instant::now(),
+        };
+        // The first sample only sets the baseline for the next
+        if let Some(ref mut monitor) = state.monitor {
+            monitor.sample();
+        }
+        Some(Arc::new(Self {
+            state: Mutex::new(state),
+            released: Notify::new(),
+            max,
+            pool,
+        }))
+    }
+
+    /// How many nodes may run at once right now.
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// Wait for a free slot.
+    pub async fn acquire(self: &Arc<Self>) -> Slot {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            if self.try_acquire() {
+                return Slot {
+                    limiter: self.clone(),
+                };
+            }
+            // Woken by a released slot, or in time to sample the load again
+            let _ = tokio::time::timeout(SAMPLE_INTERVAL, released).await;
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.sampled_at.elapsed() >= SAMPLE_INTERVAL {
+            state.sampled_at = Instant::now();
+            let (running, limit) = (state.running, state.limit);
+            if let Some(sample) = state.monitor.as_mut().and_then(LoadMonitor::sample) {
+                let next = next_limit(limit, running, self.max, &sample, self.pool);
+                if next != limit {
+                    tracing::debug!(limit = next, pool = ?self.pool, ?sample, "Adjusted concurrency");
+                    state.limit = next;
+                }
+                if next > limit {
+                    // Other waiters may fit too
+                    self.released.notify_waiters();
+                }
+            }
+        }
+        if state.running < state.limit {
+            state.running += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// `--jobs`: a limit shared by every node, and limits of their own for
+/// pools, e.g. `8`, `auto`, `io=32` or `auto,io=16`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Concurrency {
+    pub jobs: Jobs,
+    pub pools: BTreeMap<Pool, Jobs>,
+}
+
+impl std::str::FromStr for Concurrency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut concurrency = Concurrency::default();
+        let mut shared = None;
+        for item in crate::docker::directives::list(s) {
+            match item.split_once('=') {
+                Some((pool, jobs)) => {
+                    concurrency.pools.insert(pool.parse()?, jobs.parse()?);
+                }
+                None if shared.is_none() => shared = Some(item.parse()?),
+                None => anyhow::bail!("More than one shared number of jobs in '{}'", s),
+            }
+        }
+        concurrency.jobs = shared.unwrap_or_default();
+        Ok(concurrency)
+    }
+}
+
+/// The limiters of a build, one per pool or one shared by both.
+#[derive(Default, Clone)]
+pub struct Limiters {
+    cpu: Option<Arc<Limiter>>,
+    io: Option<Arc<Limiter>>,
+}
+
+impl Limiters {
+    pub fn new(concurrency: &Concurrency) -> Self {
+        if concurrency.pools.is_empty() {
+            let shared = Limiter::new(concurrency.jobs, None);
+            return Self {
+                cpu: shared.clone(),
+                io: shared,
+            };
+        }
+        let pool = |pool: Pool| {
+            let jobs = concurrency
+                .pools
+                .get(&pool)
+                .copied()
+                .unwrap_or(concurrency.jobs);
+            Limiter::new(jobs, Some(pool))
+        };
+        Self {
+            cpu: pool(Pool::Cpu),
+            io: pool(Pool::Io),
+        }
+    }
+
+    /// The limiter `node` waits on, if its pool is limited.
+    pub fn for_node(&self, node: &Node) -> Option<Arc<Limiter>> {
+        match classify(node) {
+            Pool::Cpu => self.cpu.clone(),
+            Pool::Io => self.io.clone(),
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().running -= 1;
+        self.limiter.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_limit_follows_load() {
+        assert_eq!("auto".parse::<Jobs>().unwrap(), Jobs::Adaptive);
+        assert_eq!("4".parse::<Jobs>().unwrap(), Jobs::Fixed(4));
+        assert_eq!("0".parse::<Jobs>().unwrap(), Jobs::Unlimited);
+        assert!("many".parse::<Jobs>().is_err());
+
+        let idle = LoadSample {
+            cpu_busy: 0.2,
+            io_wait: 0.0,
+            memory_available: 0.6,
+        };
+        assert_eq!(next_limit(4, 4, 16, &idle, None), 5);
+        // Not every slot is taken, so more would not help
+        assert_eq!(next_limit(4, 2, 16, &idle, None), 4);
+        assert_eq!(next_limit(16, 16, 16, &idle, None), 16);
+        let busy = LoadSample {
+            cpu_busy: 0.98,
+            ..idle
+        };
+        assert_eq!(next_limit(4, 4, 16, &busy, None), 3);
+        // Busy CPUs do not hold back downloads
+        assert_eq!(next_limit(4, 4, 16, &busy, Some(Pool::Io)), 5);
+        let io_bound = LoadSample {
+            io_wait: 0.4,
+            ..idle
+        };
+        assert_eq!(next_limit(1, 1, 16, &io_bound, None), 1);
+        assert_eq!(next_limit(4, 4, 16, &io_bound, Some(Pool::Io)), 3);
+        let swapping = LoadSample {
+            memory_available: 0.05,
+            ..idle
+        };
+        assert_eq!(next_limit(8, 8, 16, &swapping, Some(Pool::Io)), 4);
+
+        let stat = "cpu  100 0 50 800 50 0 0 0 0 0\ncpu0 100 0 50 800 50 0 0 0 0 0\n";
+        assert_eq!(
+            parse_cpu_times(stat),
+            Some(CpuTimes {
+                busy: 150,
+                iowait: 50,
+                total: 1000
+            })
+        );
+        let meminfo = "MemTotal:       16000 kB\nMemFree:  1000 kB\nMemAvailable:    4000 kB\n";
+        assert_eq!(parse_memory_available(meminfo), Some(0.25));
+    }
+
+    #[tokio::test]
+    async fn test_fixed_jobs_cap_running_nodes() {
+        assert!(Limiter::new(Jobs::Unlimited, None).is_none());
+        let limiter = Limiter::new(Jobs::Fixed(2), None).unwrap();
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<_> = (0..6)
+            .map(|_| {
+                let (limiter, running, peak) = (limiter.clone(), running.clone(), peak.clone());
+                tokio::spawn(async move {
+                    let _slot = limiter.acquire().await;
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(limiter.limit(), 2);
+    }
+
+    #[test]
+    fn test_pools() {
+        let graph = crate::docker::dag::build_graph_from_spanned(
+            crate::docker::parser::parse_dockerfile_spanned(
+                "FROM rust\nCOPY . /src\nRUN apt-get update && apt-get install -y protobuf-compiler\n\
+                 RUN cargo build --release\n# memobuild:pool=io\nRUN ./fetch-assets.sh\n",
+                std::path::Path::new("Dockerfile"),
+            ),
+            std::path::PathBuf::from("."),
+        );
+        let pools: Vec<Pool> = graph.nodes.iter().map(classify).collect();
+        assert_eq!(
+            pools,
+            vec![Pool::Io, Pool::Io, Pool::Io, Pool::Cpu, Pool::Io]
+        );
+
+        let concurrency: Concurrency = "auto, io=32".parse().unwrap();
+        assert_eq!(concurrency.jobs, Jobs::Adaptive);
+        assert_eq!(concurrency.pools[&Pool::Io], Jobs::Fixed(32));
+        let limiters = Limiters::new(&concurrency);
+        assert_eq!(limiters.for_node(&graph.nodes[2]).unwrap().limit(), 32);
+        assert!(!Arc::ptr_eq(
+            &limiters.for_node(&graph.nodes[3]).unwrap(),
+            &limiters.for_node(&graph.nodes[2]).unwrap()
+        ));
+        // Without pools, every node shares one limit
+        let limiters = Limiters::new(&"4".parse().unwrap());
+        assert!(Arc::ptr_eq(
+            &limiters.for_node(&graph.nodes[3]).unwrap(),
+            &limiters.for_node(&graph.nodes[2]).unwrap()
+        ));
+        assert!("gpu=2".parse::<Concurrency>().is_err());
+        assert!("4,8".parse::<Concurrency>().is_err());
+    }
+}
@@ -1,5 +1,10 @@
 use crate::cache::hybrid::HybridCache;
+use crate::execution::backend::{BackendSelector, ExecutorBackend, RemoteBackend, SandboxBackend};
+use crate::execution::concurrency::{Concurrency, Limiters};
+use crate::execution::hooks::{BuildHook, HookSet, NodeEnd};
 use crate::graph::BuildGraph;
+use crate::report::{ArtifactInfo, BuildReport, CacheSource, NodeOutcome, NodeReport};
+use crate::sandbox::usage::ResourceUsage;
 use anyhow::Result;
 use colored::*;
 use indicatif::{ProgressBar, ProgressStyle};
@@ -13,8 +18,15 @@ pub struct IncrementalExecutor {
     observer: Option<Arc<dyn crate::dashboard::BuildObserver>>,
     reproducible: bool,
     dry_run: bool,
-    sandbox: Arc<dyn crate::sandbox::Sandbox>,
-    remote_executor: Option<Arc<dyn crate::remote_exec::RemoteExecutor>>,
+    backends: BackendSelector,
+    report: BuildReport,
+    /// Suppress the progress bar, level lines and summary
+    quiet: bool,
+    hooks: HookSet,
+    /// Past builds, to prepare likely-dirty children while their parents run
+    speculation: Option<Arc<crate::history::BuildHistory>>,
+    /// Caps how many parallel nodes of each pool run at once
+    limiters: Limiters,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -35,10 +47,16 @@ impl IncrementalExecutor {
             observer: None,
             reproducible: false,
             dry_run: false,
-            sandbox: Arc::new(crate::sandbox::local::LocalSandbox::new(
-                std::env::current_dir().unwrap_or_else(|_| std::path::PathBuf::from(".")),
-            )),
-            remote_executor: None,
+            backends: BackendSelector::new(Arc::new(SandboxBackend::new(Arc::new(
+                crate::sandbox::local::LocalSandbox::new(
+                    std::env::current_dir().unwrap_or_else(|_| std::path::PathBuf::from(".")),
+                ),
+            )))),
+            report: BuildReport::default(),
+            quiet: false,
+            hooks: HookSet::registered(),
+            speculation: None,
+            limiters: Limiters::default(),
         }
     }
 
@@ -51,12 +69,31 @@ impl IncrementalExecutor {
         mut self,
         exec: Arc<dyn crate::remote_exec::RemoteExecutor>,
     ) -> Self {
-        self.remote_executor = Some(exec);
+        self.backends
+            .set_default(Arc::new(RemoteBackend::new(exec)));
         self
     }
 
     pub fn with_sandbox(mut self, sandbox: Arc<dyn crate::sandbox::Sandbox>) -> Self {
-        self.sandbox = sandbox;
+        self.backends
+            .set_default(Arc::new(SandboxBackend::new(sandbox)));
+        self
+    }
+
+    /// Run every node kind without an override on `backend`.
+    pub fn with_backend(mut self, backend: Arc<dyn ExecutorBackend>) -> Self {
+        self.backends.set_default(backend);
+        self
+    }
+
+    /// Run nodes whose [`NodeKind::label`](crate::graph::NodeKind::label) is
+    /// `kind` on `backend`.
+    pub fn with_backend_for(
+        mut self,
+        kind: &'static str,
+        backend: Arc<dyn ExecutorBackend>,
+    ) -> Self {
+        self.backends.set_for_kind(kind, backend);
         self
     }
 
@@ -70,6 +107,42 @@ impl IncrementalExecutor {
         self
     }
 
+    /// Call `hook` during every build, after the hooks added before it.
+    pub fn with_hook(mut self, hook: Arc<dyn BuildHook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Run without console output; results are in [`report`](Self::report).
+    pub fn with_quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    /// While a level runs, prepare the nodes of the next one whose parents
+    /// `history` says almost always invalidate their children: store their
+    /// COPY sources and let the backend get ready (e.g. upload inputs).
+    /// Nothing runs ahead of its parents.
+    pub fn with_speculation(mut self, history: Arc<crate::history::BuildHistory>) -> Self {
+        self.speculation = Some(history);
+        self
+    }
+
+    /// How many parallel nodes may run at once: all of a level, a fixed
+    /// number, or as many as the machine's load allows, shared by every
+    /// node or per pool.
+    pub fn with_jobs(mut self, concurrency: &Concurrency) -> Self {
+        self.limiters = Limiters::new(concurrency);
+        self
+    }
+
+    /// Per-node results of the last [`execute`](Self::execute), also after
+    /// it failed: the failing nodes carry their error and nodes that never
+    /// ran are marked skipped.
+    pub fn report(&self) -> &BuildReport {
+        &self.report
+    }
+
     /// Execute the build graph with parallel and incremental capabilities
     pub async fn execute(&mut self, graph: &mut BuildGraph) -> Result<ExecutionStats> {
         let start_time = Instant::now();
@@ -77,10 +150,14 @@ impl IncrementalExecutor {
         // Reset stats
         self.execution_stats = ExecutionStats::default();
         self.execution_stats.total_nodes = graph.nodes.len();
+        self.report = BuildReport::default();
 
         // Get execution levels for parallel processing
         let levels = graph.levels();
         self.execution_stats.parallel_levels = levels.len();
+        self.report.parallel_levels = levels.len();
+
+        self.hooks.graph_resolved(graph)?;
 
         if let Some(ref obs) = self.observer {
             obs.on_event(crate::dashboard::BuildEvent::BuildStarted {
@@ -88,12 +165,18 @@ impl IncrementalExecutor {
             });
         }
 
-        println!(
-            "🚀 Starting incremental execution with {} levels",
-            levels.len().to_string().cyan()
-        );
+        if !self.quiet {
+            println!(
+                "🚀 Starting incremental execution with {} levels",
+                levels.len().to_string().cyan()
+            );
+        }
 
-        let pb = ProgressBar::new(self.execution_stats.total_nodes as u64);
+        let pb = if self.quiet {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new(self.execution_stats.total_nodes as u64)
+        };
         pb.set_style(
             ProgressStyle::default_bar()
                 .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}")
@@ -101,34 +184,58 @@ impl IncrementalExecutor {
                 .progress_chars("#>-"),
         );
 
-        for (level_idx, level) in levels.iter().enumerate() {
-            if level.is_empty() {
-                continue;
-            }
+        let mut preparing = Vec::new();
+        let outcome = async {
+            for (level_idx, level) in levels.iter().enumerate() {
+                // Whatever is still being prepared is work the level would do anyway
+                for task in preparing.drain(..) {
+                    let _ = task.await;
+                }
+                if level.is_empty() {
+                    continue;
+                }
+                if let Some(next) = levels.get(level_idx + 1) {
+                    preparing = self.prepare_ahead(graph, level, next);
+                }
 
-            println!(" Executing level {}: {} nodes", level_idx, level.len());
+                if !self.quiet {
+                    println!(" Executing level {}: {} nodes", level_idx, level.len());
+                }
 
-            let (parallel_nodes, sequential_nodes): (Vec<_>, Vec<_>) = level
-                .iter()
-                .partition(|&&node_id| graph.nodes[node_id].metadata.parallelizable);
+                let (parallel_nodes, sequential_nodes): (Vec<_>, Vec<_>) = level
+                    .iter()
+                    .partition(|&&node_id| graph.nodes[node_id].metadata.parallelizable);
 
-            // Execute parallel nodes first
-            if !parallel_nodes.is_empty() {
-                self.execute_parallel_nodes(graph, &parallel_nodes, &pb)
-                    .await?;
-            }
+                // Execute parallel nodes first
+                if !parallel_nodes.is_empty() {
+                    self.execute_parallel_nodes(graph, &parallel_nodes, &pb)
+                        .await?;
+                }
 
-            // Execute sequential nodes
-            if !sequential_nodes.is_empty() {
-                self.execute_sequential_nodes(graph, &sequential_nodes, &pb)
-                    .await?;
+                // Execute sequential nodes
+                if !sequential_nodes.is_empty() {
+                    self.execute_sequential_nodes(graph, &sequential_nodes, &pb)
+                        .await?;
+                }
             }
-            // Finalize execute
+            Ok::<(), anyhow::Error>(())
+        }
+        .await;
+        for task in preparing {
+            task.abort();
         }
-
-        pb.finish_with_message("Execution completed".green().to_string());
 
         self.execution_stats.total_execution_time_ms = start_time.elapsed().as_millis() as u64;
+        self.report.total_duration_ms = self.execution_stats.total_execution_time_ms;
+        self.report.finish(graph);
+        self.hooks.build_end(&self.report, outcome.as_ref().err());
+
+        if let Err(e) = outcome {
+            pb.abandon();
+            return Err(e);
+        }
+
+        pb.finish_with_message("Execution completed".green().to_string());
 
         if let Some(ref obs) = self.observer {
             obs.on_event(crate::dashboard::BuildEvent::BuildCompleted {
@@ -138,7 +245,9 @@ impl IncrementalExecutor {
             });
         }
 
-        self.print_execution_summary();
+        if !self.quiet {
+            self.print_execution_summary();
+        }
 
         Ok(self.execution_stats.clone())
     }
@@ -162,12 +271,17 @@ impl IncrementalExecutor {
             let kind = node.kind.clone();
             let cache = self.cache.clone();
             let observer = self.observer.clone();
-            let sandbox = self.sandbox.clone();
-            let remote_executor = self.remote_executor.clone();
+            let backend = self.backends.select(&kind);
             let reproducible = self.reproducible;
             let dry_run = self.dry_run;
+            let hooks = self.hooks.clone();
+            let limiter = self.limiters.for_node(&node);
 
             futures.push(async move {
+                let _slot = match limiter {
+                    Some(ref limiter) => Some(limiter.acquire().await),
+                    None => None,
+                };
                 if let Some(ref obs) = observer {
                     obs.on_event(crate::dashboard::BuildEvent::NodeStarted {
                         node_id,
@@ -175,30 +289,36 @@ impl IncrementalExecutor {
                     });
                 }
                 let start_time = Instant::now();
-                let result = Self::execute_node_logic(
-                    cache,
-                    node_id,
-                    &name,
-                    &hash,
-                    dirty,
-                    &kind,
-                    reproducible,
-                    dry_run,
-                    sandbox,
-                    remote_executor,
-                    &node,
-                )
-                .await;
+                let result = match hooks.node_start(&node) {
+                    Ok(()) => {
+                        Self::execute_node_logic(
+                            cache,
+                            node_id,
+                            &name,
+                            &hash,
+                            dirty,
+                            &kind,
+                            reproducible,
+                            dry_run,
+                            backend,
+                            &node,
+                            &hooks,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
                 let execution_time = start_time.elapsed().as_millis() as u64;
+                Self::notify_hooks(&hooks, &node, &result, execution_time);
 
                 if let Some(ref obs) = observer {
                     match &result {
-                        Ok((_, cache_hit)) => {
+                        Ok((_, source, _)) => {
                             obs.on_event(crate::dashboard::BuildEvent::NodeCompleted {
                                 node_id,
                                 name: name.clone(),
                                 duration_ms: execution_time,
-                                cache_hit: *cache_hit,
+                                cache_hit: *source != CacheSource::None,
                             })
                         }
                         Err(e) => obs.on_event(crate::dashboard::BuildEvent::NodeFailed {
@@ -214,25 +334,24 @@ impl IncrementalExecutor {
 
         let results = futures::future::join_all(futures).await;
 
-        // Update graph status and stats
+        // Update graph status and stats; every result is recorded before the
+        // first failure is returned
+        let mut first_error = None;
         for (node_id, result, execution_time) in results {
-            let (dirty, cache_hit) = result?;
-
-            graph.nodes[node_id].dirty = dirty;
-            graph.nodes[node_id].cache_hit = cache_hit;
-            graph.nodes[node_id].metadata.last_executed = Some(std::time::SystemTime::now());
-            graph.nodes[node_id].metadata.execution_time_ms = Some(execution_time);
-
-            if cache_hit {
-                self.execution_stats.cache_hits += 1;
-            } else {
-                self.execution_stats.cache_misses += 1;
-                self.execution_stats.executed_nodes += 1;
+            match result {
+                Ok(run) => self.record_node(graph, node_id, run, execution_time),
+                Err(e) => {
+                    self.record_failure(graph, node_id, &e, execution_time);
+                    first_error.get_or_insert(e);
+                }
             }
             pb.inc(1);
         }
 
-        Ok(())
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
 
     /// Execute nodes sequentially
@@ -258,31 +377,37 @@ impl IncrementalExecutor {
                 });
             }
 
-            let result = Self::execute_node_logic(
-                self.cache.clone(),
-                node_id,
-                &node.name,
-                &node.hash,
-                node.dirty,
-                &node.kind,
-                self.reproducible,
-                self.dry_run,
-                self.sandbox.clone(),
-                self.remote_executor.clone(),
-                node,
-            )
-            .await;
+            let result = match self.hooks.node_start(node) {
+                Ok(()) => {
+                    Self::execute_node_logic(
+                        self.cache.clone(),
+                        node_id,
+                        &node.name,
+                        &node.hash,
+                        node.dirty,
+                        &node.kind,
+                        self.reproducible,
+                        self.dry_run,
+                        self.backends.select(&node.kind),
+                        node,
+                        &self.hooks,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
 
             let execution_time = start_time.elapsed().as_millis() as u64;
+            Self::notify_hooks(&self.hooks, node, &result, execution_time);
 
             if let Some(ref obs) = self.observer {
                 match &result {
-                    Ok((_, cache_hit)) => {
+                    Ok((_, source, _)) => {
                         obs.on_event(crate::dashboard::BuildEvent::NodeCompleted {
                             node_id,
                             name: node.name.clone(),
                             duration_ms: execution_time,
-                            cache_hit: *cache_hit,
+                            cache_hit: *source != CacheSource::None,
                         })
                     }
                     Err(e) => obs.on_event(crate::dashboard::BuildEvent::NodeFailed {
@@ -293,18 +418,12 @@ impl IncrementalExecutor {
                 }
             }
 
-            let (dirty, cache_hit) = result?;
-
-            graph.nodes[node_id].dirty = dirty;
-            graph.nodes[node_id].cache_hit = cache_hit;
-            graph.nodes[node_id].metadata.last_executed = Some(std::time::SystemTime::now());
-            graph.nodes[node_id].metadata.execution_time_ms = Some(execution_time);
-
-            if cache_hit {
-                self.execution_stats.cache_hits += 1;
-            } else {
-                self.execution_stats.cache_misses += 1;
-                self.execution_stats.executed_nodes += 1;
+            match result {
+                Ok(run) => self.record_node(graph, node_id, run, execution_time),
+                Err(e) => {
+                    self.record_failure(graph, node_id, &e, execution_time);
+                    return Err(e);
+                }
             }
             pb.inc(1);
         }
@@ -312,6 +431,161 @@ impl IncrementalExecutor {
         Ok(())
     }
 
+    /// Start preparing the nodes of `next` that depend on a node of `level`
+    /// likely to invalidate them. Parents already in the local cache are
+    /// expected to hit, and so are their children.
+    fn prepare_ahead(
+        &self,
+        graph: &BuildGraph,
+        level: &[usize],
+        next: &[usize],
+    ) -> Vec<tokio::task::JoinHandle<()>> {
+        let Some(ref history) = self.speculation else {
+            return Vec::new();
+        };
+        if self.dry_run {
+            return Vec::new();
+        }
+        next.iter()
+            .map(|&id| &graph.nodes[id])
+            .filter(|node| {
+                !self.cache.local.exists(&node.hash)
+                    && node.deps.iter().any(|&dep| {
+                        level.contains(&dep)
+                            && !self.cache.local.exists(&graph.nodes[dep].hash)
+                            && history.invalidates_children(&graph.nodes[dep])
+                    })
+            })
+            .map(|node| {
+                let node = node.clone();
+                let cache = self.cache.clone();
+                let backend = self.backends.select(&node.kind);
+                tokio::spawn(async move {
+                    if let Err(e) = Self::prepare_node(&cache, backend, &node).await {
+                        // The node itself will run into it, if it is real
+                        eprintln!("⚠️ Could not prepare {} ahead: {}", node.name, e);
+                    }
+                })
+            })
+            .collect()
+    }
+
+    async fn prepare_node(
+        cache: &HybridCache,
+        backend: Arc<dyn ExecutorBackend>,
+        node: &crate::graph::Node,
+    ) -> Result<()> {
+        match (&node.kind, &node.source_path) {
+            (
+                crate::graph::NodeKind::Copy { .. } | crate::graph::NodeKind::CopyExtend { .. },
+                Some(path),
+            ) => {
+                // Storing it again when the node runs finds every file present
+                let ignore =
+                    crate::hasher::IgnoreRules::empty().with_patterns(node.metadata.excludes());
+                cache.put_tree(path, &ignore).await?;
+                Ok(())
+            }
+            _ => backend.prepare(node, cache).await,
+        }
+    }
+
+    fn notify_hooks(
+        hooks: &HookSet,
+        node: &crate::graph::Node,
+        result: &Result<(bool, CacheSource, Option<ArtifactInfo>)>,
+        execution_time: u64,
+    ) {
+        if hooks.is_empty() {
+            return;
+        }
+        let cache_source = match result {
+            Ok((_, source, _)) => *source,
+            Err(_) => CacheSource::None,
+        };
+        if cache_source != CacheSource::None {
+            hooks.cache_hit(node, cache_source);
+        }
+        hooks.node_end(
+            node,
+            &NodeEnd {
+                duration_ms: execution_time,
+                cache_source,
+                error: result.as_ref().err(),
+            },
+        );
+    }
+
+    /// Apply a finished node to the graph, the stats and the report.
+    fn record_node(
+        &mut self,
+        graph: &mut BuildGraph,
+        node_id: usize,
+        run: (bool, CacheSource, Option<ArtifactInfo>),
+        execution_time: u64,
+    ) {
+        let (dirty, cache_source, artifact) = run;
+        let cache_hit = cache_source != CacheSource::None;
+        let usage = self.take_usage(&graph.nodes[node_id]);
+
+        graph.nodes[node_id].dirty = dirty;
+        graph.nodes[node_id].cache_hit = cache_hit;
+        graph.nodes[node_id].metadata.last_executed = Some(std::time::SystemTime::now());
+        graph.nodes[node_id].metadata.execution_time_ms = Some(execution_time);
+        graph.nodes[node_id].metadata.resource_usage = usage;
+
+        if cache_hit {
+            self.execution_stats.cache_hits += 1;
+        } else {
+            self.execution_stats.cache_misses += 1;
+            self.execution_stats.executed_nodes += 1;
+        }
+
+        let outcome = if cache_hit {
+            NodeOutcome::Cached
+        } else if self.dry_run {
+            NodeOutcome::Skipped
+        } else {
+            NodeOutcome::Executed
+        };
+        self.report.record(NodeReport {
+            outcome,
+            cache_source,
+            duration_ms: execution_time,
+            artifact_bytes: artifact.as_ref().map(|a| a.bytes),
+            artifact_digest: artifact.map(|a| a.digest),
+            // A cache hit did not have to rebuild, whatever changed
+            dirty_reason: if cache_hit {
+                None
+            } else {
+                graph.nodes[node_id].metadata.dirty_reason.clone()
+            },
+            usage,
+            ..NodeReport::skipped(&graph.nodes[node_id])
+        });
+    }
+
+    fn record_failure(
+        &mut self,
+        graph: &BuildGraph,
+        node_id: usize,
+        error: &anyhow::Error,
+        execution_time: u64,
+    ) {
+        self.report.record(NodeReport {
+            outcome: NodeOutcome::Failed,
+            duration_ms: execution_time,
+            error: Some(format!("{:#}", error)),
+            usage: self.take_usage(&graph.nodes[node_id]),
+            ..NodeReport::skipped(&graph.nodes[node_id])
+        });
+    }
+
+    /// What running `node` cost, if its backend measured it.
+    fn take_usage(&self, node: &crate::graph::Node) -> Option<ResourceUsage> {
+        self.backends.select(&node.kind).take_usage(node)
+    }
+
     #[allow(clippy::too_many_arguments)]
     async fn execute_node_logic(
         cache: Arc<HybridCache>,
@@ -322,18 +596,32 @@ impl IncrementalExecutor {
         _kind: &crate::graph::NodeKind,
         reproducible: bool,
         dry_run: bool,
-        sandbox: Arc<dyn crate::sandbox::Sandbox>,
-        remote_executor: Option<Arc<dyn crate::remote_exec::RemoteExecutor>>,
+        backend: Arc<dyn ExecutorBackend>,
         node: &crate::graph::Node,
-    ) -> Result<(bool, bool)> {
-        // 1. Check cache first
-        match cache.get_artifact(hash).await {
-            Ok(Some(_data)) => {
-                // Return silently, progress bar handles message visually without spam
-                return Ok((false, true));
+        hooks: &HookSet,
+    ) -> Result<(bool, CacheSource, Option<ArtifactInfo>)> {
+        // 1. Check cache first, unless the step opted out with `no-cache`
+        let no_cache = node.metadata.no_cache();
+        if !no_cache {
+            // Hashed as it is read, so a hit never holds the artifact in memory
+            let hit = match cache.lookup_reader(hash).await {
+                Ok(Some((mut reader, source))) => ArtifactInfo::from_reader(&mut reader)
+                    .map(|artifact| Some((artifact, source)))
+                    .map_err(anyhow::Error::from),
+                Ok(None) => Ok(None),
+                Err(e) => Err(e),
+            };
+            match hit {
+                Ok(Some((artifact, source))) => {
+                    if source == CacheSource::Remote {
+                        cache.clone().prefetch_siblings(hash);
+                    }
+                    // Return silently, progress bar handles message visually without spam
+                    return Ok((false, source, Some(artifact)));
+                }
+                Err(e) => eprintln!("{}", format!("⚠️ Cache error for {}: {}", name, e).red()),
+                _ => {}
             }
-            Err(e) => eprintln!("{}", format!("⚠️ Cache error for {}: {}", name, e).red()),
-            _ => {}
         }
 
         if dry_run {
@@ -341,96 +629,54 @@ impl IncrementalExecutor {
                 "{}",
                 format!("Dry-run mode, skipping execution for {}", name).yellow()
             );
-            return Ok((dirty, false));
+            return Ok((dirty, CacheSource::None, None));
         }
 
-        // Check if node type needs actual execution in build farm
+        // Only commands go to a backend; COPY and metadata nodes are handled here
         let is_runnable = matches!(
             node.kind,
             crate::graph::NodeKind::Run
                 | crate::graph::NodeKind::RunExtend { .. }
                 | crate::graph::NodeKind::CustomHook { .. }
+                | crate::graph::NodeKind::Plugin { .. }
                 | crate::graph::NodeKind::Git { .. }
+                | crate::graph::NodeKind::Task { .. }
         );
 
         let mut artifact_data = if is_runnable {
-            if let Some(remote) = remote_executor.as_ref() {
-                // Ensure input manifest and required files are in CAS
-                if let Some(ref _manifest_hash) = node.metadata.input_manifest_hash {
-                    // If it's a COPY node, we can re-generate and upload
-                    if let Some(ref path) = node.source_path {
-                        if let Ok(manifest) = crate::cache::utils::ArtifactManifest::from_dir(path) {
-                            println!("📤 Uploading input manifest for {}...", name);
-                            cache.upload_manifest_and_files(&manifest, path).await?;
-                        }
-                    } else {
-                        // For RUN nodes, the manifest was built from parents.
-                        // We should ensure the manifest itself is in the CAS.
-                        // (The files should already be there from previous steps' put_artifact)
-                        // TODO: Implement manifest persistence across steps if needed
-                    }
-                }
-
-                println!("📡 [RemoteExec] Dispatching node {} to build farm", name);
-                let action = crate::remote_exec::ActionRequest {
-                    command: vec!["/bin/sh".into(), "-c".into(), node.content.clone()],
-                    env: node.env.clone(),
-                    input_root_digest: crate::remote_exec::Digest {
-                        hash: node
-                            .metadata
-                            .input_manifest_hash
-                            .clone()
-                            .unwrap_or_else(|| hash.to_string()),
-                        size_bytes: 0, // Placeholder
-                    },
-                    timeout: std::time::Duration::from_secs(
-                        crate::constants::DEFAULT_REMOTE_EXECUTION_TIMEOUT_SECS,
-                    ),
-                    platform_properties: std::collections::HashMap::new(),
-                    output_files: Vec::new(),
-                    output_directories: Vec::new(),
-                };
-
-                let result = remote.execute(action).await?;
-                if result.exit_code != 0 {
-                    anyhow::bail!(
-                        "Remote execution failed with exit code {}: {}",
-                        result.exit_code,
-                        String::from_utf8_lossy(&result.stderr_raw)
-                    );
-                }
-                result.stdout_raw
-            } else {
-                // Prepare sandbox
-                if let crate::graph::NodeKind::RunExtend { command, .. } = &node.kind {
-                    println!("⚡ Executing extended RUN: {}", command);
-                } else if let crate::graph::NodeKind::CopyExtend { src, dst, .. } = &node.kind {
-                    println!(
-                        "⚡ Executing extended COPY: {} -> {}",
-                        src.display(),
-                        dst.display()
-                    );
-                } else if let crate::graph::NodeKind::CustomHook { hook_name, .. } = &node.kind {
-                    println!("⚡ Running custom hook: {}", hook_name);
-                }
-
-                let env = sandbox.prepare(node).await?;
-
-                // Execute command
-                let exec_result = sandbox.execute(&env, node).await?;
-
-                if exec_result.exit_code != 0 {
-                    anyhow::bail!(
-                        "Command failed with exit code {}: {}",
-                        exec_result.exit_code,
-                        String::from_utf8_lossy(&exec_result.stderr)
-                    );
-                }
-
-                let data = exec_result.stdout;
-                sandbox.cleanup(&env).await?;
-                data
+            match node.metadata.timeout() {
+                Some(limit) => tokio::time::timeout(limit, backend.execute(node, &cache))
+                    .await
+                    .map_err(|_| {
+                        anyhow::anyhow!(
+                            "{}{} timed out after {:?}",
+                            node.location_prefix(),
+                            name,
+                            limit
+                        )
+                    })??,
+                None => backend.execute(node, &cache).await?,
             }
+        } else if let crate::graph::NodeKind::Add { .. } = node.kind {
+            crate::docker::add::store(node, &cache).await?.into_bytes()
+        } else if let crate::graph::NodeKind::CopyFrom { .. } = node.kind {
+            crate::docker::copy_from::store(node)?.into_bytes()
+        } else if let (
+            Some(ref path),
+            crate::graph::NodeKind::Copy { .. } | crate::graph::NodeKind::CopyExtend { .. },
+        ) = (&node.source_path, &node.kind)
+        {
+            // Files are stored once by content; the artifact is the tree's root digest
+            let ignore =
+                crate::hasher::IgnoreRules::empty().with_patterns(node.metadata.excludes());
+            let stored = cache.put_tree(path, &ignore).await?;
+            if stored.new_files > 0 {
+                println!(
+                    "   🗂️  {}: {} of {} file(s) new in the context store",
+                    name, stored.new_files, stored.files
+                );
+            }
+            stored.root.into_bytes()
         } else {
             Vec::new() // Default empty artifact data for non-runnable nodes
         };
@@ -439,11 +685,18 @@ impl IncrementalExecutor {
             artifact_data = crate::reproducible::normalize_artifact(artifact_data)?;
         }
 
-        if let Err(e) = cache.put_artifact(hash, &artifact_data).await {
+        let artifact = ArtifactInfo::of(&artifact_data);
+        if (is_runnable && !backend.caches_outputs())
+            || no_cache
+            || !hooks.allow_store(node, &artifact_data)
+        {
+            return Ok((false, CacheSource::None, Some(artifact)));
+        }
+        if let Err(e) = cache.put_artifact_named(hash, name, &artifact_data).await {
             eprintln!("⚠️ Cache put error for {}: {}", name, e);
         }
 
-        Ok((false, false))
+        Ok((false, CacheSource::None, Some(artifact)))
     }
 
     /// Print execution summary
@@ -481,20 +734,28 @@ impl IncrementalExecutor {
                 * 100.0;
             println!("  Cache hit rate: {:.1}%", cache_hit_rate);
         }
+
+        let remote_hits = self.report.count_source(CacheSource::Remote);
+        if remote_hits > 0 {
+            println!("  Downloaded from remote: {}", remote_hits);
+        }
     }
 }
 
-/// Legacy function for backward compatibility
+/// Execute `graph` with default settings and return the per-node report.
+///
+/// On failure the error is returned instead; use [`IncrementalExecutor::report`]
+/// to inspect a failed build.
 pub async fn execute_graph(
     graph: &mut BuildGraph,
     cache: Arc<HybridCache>,
     observer: Option<Arc<dyn crate::dashboard::BuildObserver>>,
     reproducible: bool,
-) -> Result<()> {
+) -> Result<BuildReport> {
     let mut executor = IncrementalExecutor::new(cache).with_reproducible(reproducible);
     if let Some(obs) = observer {
         executor = executor.with_observer(obs);
     }
     executor.execute(graph).await?;
-    Ok(())
-}
\ No newline at end of file
+    Ok(executor.report)
+}
@@ -0,0 +1,141 @@
+//! Build hooks, called at fixed points of every build.
+
+use crate::graph::{BuildGraph, Node};
+use crate::report::{BuildReport, CacheSource};
+use anyhow::{Context, Result};
+use std::sync::{Arc, Mutex};
+
+/// How a node ended, passed to [`BuildHook::on_node_end`].
+#[derive(Debug)]
+pub struct NodeEnd<'a> {
+    pub duration_ms: u64,
+    /// `None` when the node ran rather than being restored
+    pub cache_source: CacheSource,
+    pub error: Option<&'a anyhow::Error>,
+}
+
+/// Callbacks into a build. Every method has a default that does nothing, so
+/// a hook only implements the ones it needs.
+///
+/// Hooks of parallel nodes are called from several tasks at once.
+pub trait BuildHook: Send + Sync {
+    /// Name used in errors from this hook
+    fn name(&self) -> &str;
+
+    /// Before any node runs, with every cache key computed. An error aborts
+    /// the build.
+    fn on_graph_resolved(&self, _graph: &BuildGraph) -> Result<()> {
+        Ok(())
+    }
+
+    /// Before a node is looked up in the cache. An error fails the node.
+    fn on_node_start(&self, _node: &Node) -> Result<()> {
+        Ok(())
+    }
+
+    /// A node's artifact was restored from a cache instead of running it.
+    fn on_cache_hit(&self, _node: &Node, _source: CacheSource) {}
+
+    /// Whether the artifact a node produced may be stored in the cache, and
+    /// from there uploaded to the remote one. A node whose artifact is kept
+    /// out still succeeds; it runs again in the next build.
+    fn allow_store(&self, _node: &Node, _artifact: &[u8]) -> bool {
+        true
+    }
+
+    /// A node finished, successfully or not.
+    fn on_node_end(&self, _node: &Node, _end: &NodeEnd<'_>) {}
+
+    /// The build finished; `error` is set when it failed.
+    fn on_build_end(&self, _report: &BuildReport, _error: Option<&anyhow::Error>) {}
+}
+
+static REGISTERED: Mutex<Vec<Arc<dyn BuildHook>>> = Mutex::new(Vec::new());
+
+/// Add `hook` to every executor created from now on.
+pub fn register(hook: Arc<dyn BuildHook>) {
+    REGISTERED.lock().unwrap().push(hook);
+}
+
+/// The hooks of one executor, called in the order they were added.
+#[derive(Clone, Default)]
+pub struct HookSet {
+    hooks: Vec<Arc<dyn BuildHook>>,
+}
+
+impl HookSet {
+    /// The hooks added with [`register`].
+    pub fn registered() -> Self {
+        Self {
+            hooks: REGISTERED.lock().unwrap().clone(),
+        }
+    }
+
+    pub fn push(&mut self, hook: Arc<dyn BuildHook>) {
+        self.hooks.push(hook);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub fn graph_resolved(&self, graph: &BuildGraph) -> Result<()> {
+        for hook in &self.hooks {
+            hook.on_graph_resolved(graph)
+                .with_context(|| format!("Hook {} rejected the build", hook.name()))?;
+        }
+        Ok(())
+    }
+
+    pub fn node_start(&self, node: &Node) -> Result<()> {
+        for hook in &self.hooks {
+            hook.on_node_start(node).with_context(|| {
+                format!(
+                    "{}Hook {} rejected {}",
+                    node.location_prefix(),
+                    hook.name(),
+                    node.name
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn cache_hit(&self, node: &Node, source: CacheSource) {
+        for hook in &self.hooks {
+            hook.on_cache_hit(node, source);
+        }
+    }
+
+    /// Whether every hook allows storing `artifact`; the first to refuse is
+    /// named in the output.
+    pub fn allow_store(&self, node: &Node, artifact: &[u8]) -> bool {
+        match self
+            .hooks
+            .iter()
+            .find(|hook| !hook.allow_store(node, artifact))
+        {
+            Some(hook) => {
+                println!(
+                    "   🚫 {}: not cached, refused by hook {}",
+                    node.name,
+                    hook.name()
+                );
+                false
+            }
+            None => true,
+        }
+    }
+
+    pub fn node_end(&self, node: &Node, end: &NodeEnd<'_>) {
+        for hook in &self.hooks {
+            hook.on_node_end(node, end);
+        }
+    }
+
+    pub fn build_end(&self, report: &BuildReport, error: Option<&anyhow::Error>) {
+        for hook in &self.hooks {
+            hook.on_build_end(report, error);
+        }
+    }
+}
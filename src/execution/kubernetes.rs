@@ -0,0 +1,493 @@
+//! Runs dirty nodes as Kubernetes Jobs, which exchange inputs and artifacts
+//! with this machine through the remote cache.
+
+use crate::cache::HybridCache;
+use crate::execution::backend::ExecutorBackend;
+use crate::graph::{Node, NodeKind};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use k8s_openapi::api::batch::v1::{Job, JobSpec};
+use k8s_openapi::api::core::v1::{
+    Container, EmptyDirVolumeSource, EnvVar, Pod, PodTemplateSpec, Volume, VolumeMount,
+};
+use kube::api::{DeleteParams, ListParams, LogParams, ObjectMeta, PostParams};
+use kube::{Api, Client};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const WORKSPACE: &str = "/workspace";
+const STATE_DIR: &str = "/memobuild";
+const EXIT_CODE_FILE: &str = "/memobuild/exit-code";
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone)]
+pub struct KubernetesConfig {
+    pub namespace: String,
+    /// Image the node commands run in
+    pub image: String,
+    /// Image with the memobuild binary, for the init and sidecar containers
+    pub tool_image: String,
+    /// Remote cache URL as reachable from inside the cluster
+    pub cache_url: String,
+    /// Base pod template. A container named `build` in it is the starting
+    /// point for the command container (resources, env, security context).
+    pub pod_template: Option<PodTemplateSpec>,
+    /// Jobs running at once
+    pub parallelism: usize,
+    pub timeout: Duration,
+    pub ttl_after_finished_secs: i32,
+}
+
+impl KubernetesConfig {
+    /// Configuration from `MEMOBUILD_K8S_*`. The builder image is required;
+    /// the cache URL falls back to `MEMOBUILD_REMOTE_URL`.
+    pub fn from_env() -> Result<Self> {
+        let image = std::env::var("MEMOBUILD_K8S_IMAGE")
+            .context("MEMOBUILD_K8S_IMAGE must name the image build commands run in")?;
+        let cache_url = std::env::var("MEMOBUILD_K8S_CACHE_URL")
+            .or_else(|_| std::env::var("MEMOBUILD_REMOTE_URL"))
+            .context("Kubernetes builds need a remote cache: set MEMOBUILD_K8S_CACHE_URL or MEMOBUILD_REMOTE_URL")?;
+
+        let pod_template = match std::env::var("MEMOBUILD_K8S_POD_TEMPLATE") {
+            Ok(path) => {
+                let data = std::fs::read(&path)
+                    .with_context(|| format!("Failed to read pod template {}", path))?;
+                Some(
+                    serde_json::from_slice(&data)
+                        .with_context(|| format!("Invalid pod template {}", path))?,
+                )
+            }
+            Err(_) => None,
+        };
+
+        let parallelism = match std::env::var("MEMOBUILD_K8S_PARALLELISM") {
+            Ok(value) => value
+                .parse()
+                .with_context(|| format!("Invalid MEMOBUILD_K8S_PARALLELISM: {}", value))?,
+            Err(_) => crate::constants::DEFAULT_K8S_PARALLELISM,
+        };
+
+        Ok(Self {
+            namespace: std::env::var("MEMOBUILD_K8S_NAMESPACE")
+                .unwrap_or_else(|_| crate::constants::DEFAULT_K8S_NAMESPACE.to_string()),
+            image,
+            tool_image: std::env::var("MEMOBUILD_K8S_TOOL_IMAGE")
+                .unwrap_or_else(|_| crate::constants::DEFAULT_K8S_TOOL_IMAGE.to_string()),
+            cache_url,
+            pod_template,
+            parallelism: parallelism.max(1),
+            timeout: Duration::from_secs(crate::constants::DEFAULT_REMOTE_EXECUTION_TIMEOUT_SECS),
+            ttl_after_finished_secs: crate::constants::DEFAULT_K8S_JOB_TTL_SECS,
+        })
+    }
+
+    /// The Job that builds `node`, named `name`.
+    pub fn job_for(&self, node: &Node, name: &str) -> Job {
+        let mut labels = BTreeMap::new();
+        labels.insert(
+            "app.kubernetes.io/managed-by".to_string(),
+            "memobuild".to_string(),
+        );
+        labels.insert("memobuild.io/node".to_string(), label_value(&node.hash));
+
+        let mut template = self.pod_template.clone().unwrap_or_default();
+        let mut metadata = template.metadata.take().unwrap_or_default();
+        metadata
+            .labels
+            .get_or_insert_with(BTreeMap::new)
+            .extend(labels.clone());
+        let mut spec = template.spec.take().unwrap_or_default();
+
+        let mounts = vec![
+            VolumeMount {
+                name: "workspace".into(),
+                mount_path: WORKSPACE.into(),
+                ..Default::default()
+            },
+            VolumeMount {
+                name: "memobuild".into(),
+                mount_path: STATE_DIR.into(),
+                ..Default::default()
+            },
+        ];
+        let cache_env = vec![
+            env_var("MEMOBUILD_REMOTE_URL", &self.cache_url),
+            env_var("MEMOBUILD_CACHE_DIR", &format!("{}/cache", STATE_DIR)),
+        ];
+
+        // The command container starts from the template's `build` container
+        let mut build = match spec.containers.iter().position(|c| c.name == "build") {
+            Some(i) => spec.containers.remove(i),
+            None => Container::default(),
+        };
+        build.name = "build".into();
+        build.image = Some(self.image.clone());
+        build.working_dir = Some(WORKSPACE.into());
+        // The exit code is written even when the command fails, so the
+        // sidecar always finishes. The node's shell comes in as "$@"
+        let mut command = vec![
+            "/bin/sh".into(),
+            "-c".into(),
+            format!(
+                "\"$@\" \"$MEMOBUILD_COMMAND\"; code=$?; echo $code > {}; exit $code",
+                EXIT_CODE_FILE
+            ),
+            "memobuild".into(),
+        ];
+        command.extend(
+            node.metadata
+                .shell
+                .clone()
+                .unwrap_or_else(crate::sandbox::shell::container_default),
+        );
+        build.command = Some(command);
+        build.args = None;
+        let env = build.env.get_or_insert_with(Vec::new);
+        let mut node_env: Vec<(&String, &String)> =
+            node.metadata.build_env.iter().chain(&node.env).collect();
+        node_env.sort();
+        env.extend(node_env.into_iter().map(|(k, v)| env_var(k, v)));
+        env.push(env_var("MEMOBUILD_COMMAND", &node_command(node)));
+        build
+            .volume_mounts
+            .get_or_insert_with(Vec::new)
+            .extend(mounts.clone());
+
+        let upload = Container {
+            name: "upload-outputs".into(),
+            image: Some(self.tool_image.clone()),
+            command: Some(vec![
+                "memobuild".into(),
+                "pod".into(),
+                "upload-outputs".into(),
+                "--key".into(),
+                node.hash.clone(),
+                "--dir".into(),
+                WORKSPACE.into(),
+                "--exit-code-file".into(),
+                EXIT_CODE_FILE.into(),
+            ]),
+            env: Some(cache_env.clone()),
+            volume_mounts: Some(mounts.clone()),
+            ..Default::default()
+        };
+        spec.containers.insert(0, build);
+        spec.containers.push(upload);
+
+        if let Some(ref manifest) = node.metadata.input_manifest_hash {
+            spec.init_containers
+                .get_or_insert_with(Vec::new)
+                .push(Container {
+                    name: "fetch-inputs".into(),
+                    image: Some(self.tool_image.clone()),
+                    command: Some(vec![
+                        "memobuild".into(),
+                        "pod".into(),
+                        "fetch-inputs".into(),
+                        "--manifest".into(),
+                        manifest.clone(),
+                        "--dest".into(),
+                        WORKSPACE.into(),
+                    ]),
+                    env: Some(cache_env),
+                    volume_mounts: Some(mounts),
+                    ..Default::default()
+                });
+        }
+
+        let volumes = spec.volumes.get_or_insert_with(Vec::new);
+        for name in ["workspace", "memobuild"] {
+            volumes.push(Volume {
+                name: name.into(),
+                empty_dir: Some(EmptyDirVolumeSource::default()),
+                ..Default::default()
+            });
+        }
+        spec.restart_policy = Some("Never".into());
+
+        Job {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                namespace: Some(self.namespace.clone()),
+                labels: Some(labels),
+                ..Default::default()
+            },
+            spec: Some(JobSpec {
+                backoff_limit: Some(0),
+                active_deadline_seconds: Some(self.timeout.as_secs() as i64),
+                ttl_seconds_after_finished: Some(self.ttl_after_finished_secs),
+                template: PodTemplateSpec {
+                    metadata: Some(metadata),
+                    spec: Some(spec),
+                },
+                ..Default::default()
+            }),
+            status: None,
+        }
+    }
+}
+
+/// Runs nodes as Jobs in a Kubernetes cluster, at most
+/// [`KubernetesConfig::parallelism`] at a time.
+pub struct KubernetesBackend {
+    config: KubernetesConfig,
+    jobs: Api<Job>,
+    pods: Api<Pod>,
+    permits: Arc<tokio::sync::Semaphore>,
+}
+
+impl KubernetesBackend {
+    /// Connect with the in-cluster service account or the local kubeconfig.
+    pub async fn new(config: KubernetesConfig) -> Result<Self> {
+        let client = Client::try_default()
+            .await
+            .context("Failed to connect to the Kubernetes API")?;
+        Ok(Self {
+            jobs: Api::namespaced(client.clone(), &config.namespace),
+            pods: Api::namespaced(client, &config.namespace),
+            permits: Arc::new(tokio::sync::Semaphore::new(config.parallelism)),
+            config,
+        })
+    }
+
+    async fn wait_for(&self, name: &str) -> Result<()> {
+        // Kubernetes enforces the timeout through activeDeadlineSeconds; this
+        // only guards against a Job that never reports back
+        let deadline = Instant::now() + self.config.timeout + Duration::from_secs(60);
+        loop {
+            let job = self.jobs.get(name).await?;
+            if let Some(status) = job.status {
+                if status.succeeded.unwrap_or(0) > 0 {
+                    return Ok(());
+                }
+                if status.failed.unwrap_or(0) > 0 {
+                    anyhow::bail!("Job {} failed:\n{}", name, self.build_logs(name).await);
+                }
+            }
+            if Instant::now() > deadline {
+                anyhow::bail!("Job {} did not finish in time", name);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Tail of the `build` container's log, best effort.
+    async fn build_logs(&self, job: &str) -> String {
+        let params = ListParams::default().labels(&format!("job-name={}", job));
+        let Ok(pods) = self.pods.list(&params).await else {
+            return String::new();
+        };
+        let Some(name) = pods.items.first().and_then(|p| p.metadata.name.clone()) else {
+            return String::new();
+        };
+        let log_params = LogParams {
+            container: Some("build".into()),
+            tail_lines: Some(50),
+            ..Default::default()
+        };
+        self.pods.logs(&name, &log_params).await.unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl ExecutorBackend for KubernetesBackend {
+    fn name(&self) -> &'static str {
+        "kubernetes"
+    }
+
+    async fn execute(&self, node: &Node, cache: &HybridCache) -> Result<Vec<u8>> {
+        let _permit = self.permits.acquire().await?;
+        crate::execution::backend::upload_inputs(node, cache).await?;
+
+        let name = job_name(&node.hash);
+        println!(
+            "☸️  Running {} as Job {}/{}",
+            node.name, self.config.namespace, name
+        );
+        self.jobs
+            .create(&PostParams::default(), &self.config.job_for(node, &name))
+            .await
+            .with_context(|| format!("Failed to create Job {}", name))?;
+
+        // Failed Jobs stay until their TTL, for inspection
+        self.wait_for(&name).await.with_context(|| {
+            format!(
+                "{}{} failed on Kubernetes",
+                node.location_prefix(),
+                node.name
+            )
+        })?;
+        if let Err(e) = self.jobs.delete(&name, &DeleteParams::background()).await {
+            eprintln!("⚠️ Failed to delete Job {}: {}", name, e);
+        }
+
+        cache.get_artifact(&node.hash).await?.with_context(|| {
+            format!(
+                "Job {} succeeded but uploaded no artifact for {}",
+                name, node.name
+            )
+        })
+    }
+}
+
+/// `memobuild pod fetch-inputs`: restore an input manifest into `dest`.
+pub async fn fetch_inputs(cache: Arc<HybridCache>, manifest_hash: &str, dest: &Path) -> Result<()> {
+    let data = cache
+        .get_artifact(manifest_hash)
+        .await?
+        .with_context(|| format!("Input manifest {} is not in the cache", manifest_hash))?;
+    let manifest: crate::cache::utils::ArtifactManifest =
+        serde_json::from_slice(&data).context("Malformed input manifest")?;
+    println!("📥 Restoring {} input file(s)", manifest.files.len());
+    manifest
+        .reconstruct(dest, move |hash| {
+            let cache = cache.clone();
+            async move { cache.get_artifact(&hash).await }
+        })
+        .await
+}
+
+/// `memobuild pod upload-outputs`: wait for the build container to write its
+/// exit code and, if it succeeded, store `dir` under `key`.
+pub async fn upload_outputs(
+    cache: &HybridCache,
+    key: &str,
+    dir: &Path,
+    exit_code_file: &Path,
+) -> Result<()> {
+    let code: i32 = loop {
+        if let Ok(text) = std::fs::read_to_string(exit_code_file) {
+            if let Ok(code) = text.trim().parse() {
+                break code;
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    };
+    if code != 0 {
+        println!("Build exited with code {}, nothing to upload", code);
+        return Ok(());
+    }
+
+    let stored = cache
+        .put_tree(dir, &crate::hasher::IgnoreRules::empty())
+        .await?;
+    cache.put_artifact(key, stored.root.as_bytes()).await?;
+    println!(
+        "📤 Stored {} file(s) as {} for {}",
+        stored.files, stored.root, key
+    );
+    Ok(())
+}
+
+/// The shell command a runnable node stands for.
+fn node_command(node: &Node) -> String {
+    match &node.kind {
+        NodeKind::RunExtend { command, .. } => command.clone(),
+        NodeKind::CustomHook { hook_name, params } => {
+            format!("{} {}", hook_name, params.join(" "))
+        }
+        NodeKind::Git { url, target } => {
+            format!("git clone {} {}", url, target.display())
+        }
+        _ => node.content.clone(),
+    }
+}
+
+/// Unique DNS-1123 name for a node's Job.
+fn job_name(hash: &str) -> String {
+    let prefix: String = hash
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .take(12)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    let suffix = uuid::Uuid::new_v4().simple().to_string();
+    format!("memobuild-{}-{}", prefix, &suffix[..8])
+}
+
+/// Label values are limited to 63 characters.
+fn label_value(value: &str) -> String {
+    value.chars().take(63).collect()
+}
+
+fn env_var(name: &str, value: &str) -> EnvVar {
+    EnvVar {
+        name: name.to_string(),
+        value: Some(value.to_string()),
+        value_from: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::NodeMetadata;
+
+    #[test]
+    fn test_job_spec() {
+        let config = KubernetesConfig {
+            namespace: "builds".into(),
+            image: "node:20".into(),
+            tool_image: "memobuild:test".into(),
+            cache_url: "http://cache.builds.svc:8080".into(),
+            pod_template: Some(
+                serde_json::from_str(
+                    r#"{"spec":{"containers":[{"name":"build","resources":{"limits":{"cpu":"2"}}}],"nodeSelector":{"pool":"builds"}}}"#,
+                )
+                .unwrap(),
+            ),
+            parallelism: 4,
+            timeout: Duration::from_secs(600),
+            ttl_after_finished_secs: 60,
+        };
+        let node = Node {
+            id: 3,
+            name: "RUN npm ci".into(),
+            kind: NodeKind::Run,
+            content: "npm ci".into(),
+            hash: "abc123".into(),
+            deps: vec![],
+            dirty: true,
+            source_path: None,
+            env: [("CI".to_string(), "1".to_string())].into_iter().collect(),
+            cache_hit: false,
+            metadata: NodeMetadata {
+                input_manifest_hash: Some("manifest1".into()),
+                ..Default::default()
+            },
+        };
+
+        let name = job_name(&node.hash);
+        assert!(name.starts_with("memobuild-abc123-"));
+        let job = config.job_for(&node, &name);
+        let spec = job.spec.unwrap();
+        assert_eq!(spec.backoff_limit, Some(0));
+        assert_eq!(spec.active_deadline_seconds, Some(600));
+
+        let pod = spec.template.spec.unwrap();
+        assert_eq!(pod.restart_policy.as_deref(), Some("Never"));
+        assert!(pod.node_selector.is_some());
+        let names: Vec<&str> = pod.containers.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["build", "upload-outputs"]);
+
+        let build = &pod.containers[0];
+        assert_eq!(build.image.as_deref(), Some("node:20"));
+        // Template settings survive
+        assert!(build.resources.is_some());
+        let env = build.env.as_ref().unwrap();
+        assert!(env
+            .iter()
+            .any(|e| e.name == "MEMOBUILD_COMMAND" && e.value.as_deref() == Some("npm ci")));
+        assert!(env.iter().any(|e| e.name == "CI"));
+
+        let init = &pod.init_containers.unwrap()[0];
+        assert!(init
+            .command
+            .as_ref()
+            .unwrap()
+            .contains(&"manifest1".to_string()));
+        assert_eq!(pod.volumes.unwrap().len(), 2);
+    }
+}
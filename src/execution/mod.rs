@@ -1,2 +1,10 @@
+pub mod backend;
+pub mod concurrency;
 pub mod executor;
+pub mod hooks;
+pub mod kubernetes;
+pub mod partial;
+pub use backend::{BackendSelector, ExecutorBackend};
+pub use concurrency::{Concurrency, Jobs};
 pub use executor::*;
+pub use hooks::{BuildHook, HookSet, NodeEnd};
@@ -0,0 +1,276 @@
+//! Subgraphs exported with their cache keys by `memobuild export-graph` and
+//! executed elsewhere by `memobuild run-graph`.
+
+use crate::cache::HybridCache;
+use crate::env::drift::EnvChange;
+use crate::env::EnvFingerprint;
+use crate::graph::{BuildGraph, NodeKind};
+use crate::hasher::IgnoreRules;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+/// Version of the file format; files of other versions are refused
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartialGraph {
+    pub version: u32,
+    /// Environment the keys were computed in
+    pub env: EnvFingerprint,
+    /// The exported steps, numbered from 0, with source paths relative to
+    /// the build context
+    pub graph: BuildGraph,
+    /// Content store root of the sources of each COPY step, by step id
+    #[serde(default)]
+    pub inputs: BTreeMap<usize, String>,
+}
+
+/// The steps of `graph` matching `steps` by id, name or instruction, with
+/// every step they depend on, renumbered in order; all of them when `steps`
+/// is empty.
+pub fn select(graph: &BuildGraph, steps: &[String]) -> Result<BuildGraph> {
+    if steps.is_empty() {
+        return Ok(graph.clone());
+    }
+    let mut pending = Vec::new();
+    for step in steps {
+        let before = pending.len();
+        pending.extend(
+            graph
+                .nodes
+                .iter()
+                .filter(|n| n.id.to_string() == *step || n.name == *step || n.content == *step)
+                .map(|n| n.id),
+        );
+        if pending.len() == before {
+            anyhow::bail!("No step matches '{}'", step);
+        }
+    }
+    let mut keep = BTreeSet::new();
+    while let Some(id) = pending.pop() {
+        if id < graph.nodes.len() && keep.insert(id) {
+            pending.extend(graph.nodes[id].deps.iter().copied());
+        }
+    }
+
+    let ids: BTreeMap<usize, usize> = keep
+        .iter()
+        .enumerate()
+        .map(|(new, &old)| (old, new))
+        .collect();
+    let nodes = keep
+        .iter()
+        .map(|&old| {
+            let mut node = graph.nodes[old].clone();
+            node.id = ids[&old];
+            node.deps = node
+                .deps
+                .iter()
+                .filter_map(|d| ids.get(d).copied())
+                .collect();
+            node
+        })
+        .collect();
+    Ok(BuildGraph { nodes })
+}
+
+impl PartialGraph {
+    /// Export `graph`, keyed in `env`, pushing the sources of its COPY steps
+    /// to `cache` so workers can restore them.
+    pub async fn export(
+        mut graph: BuildGraph,
+        env: EnvFingerprint,
+        context_dir: &Path,
+        cache: &HybridCache,
+    ) -> Result<Self> {
+        let mut inputs = BTreeMap::new();
+        for node in &mut graph.nodes {
+            let Some(path) = node.source_path.take() else {
+                continue;
+            };
+            if matches!(
+                node.kind,
+                NodeKind::Copy { .. } | NodeKind::CopyExtend { .. }
+            ) {
+                let ignore = IgnoreRules::empty().with_patterns(node.metadata.excludes());
+                let stored = cache
+                    .put_tree(&path, &ignore)
+                    .await
+                    .with_context(|| format!("Failed to store the sources of {}", node.name))?;
+                inputs.insert(node.id, stored.root);
+            }
+            // Paths of this machine mean nothing on a worker
+            node.source_path = Some(
+                path.strip_prefix(context_dir)
+                    .unwrap_or(&path)
+                    .to_path_buf(),
+            );
+        }
+        Ok(Self {
+            version: FORMAT_VERSION,
+            env,
+            graph,
+            inputs,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let data =
+            std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let partial: Self = serde_json::from_slice(&data)
+            .with_context(|| format!("Malformed graph file {}", path.display()))?;
+        partial
+            .validate()
+            .with_context(|| format!("Invalid graph file {}", path.display()))?;
+        Ok(partial)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.version != FORMAT_VERSION {
+            anyhow::bail!(
+                "format version {} is not supported (expected {})",
+                self.version,
+                FORMAT_VERSION
+            );
+        }
+        let len = self.graph.nodes.len();
+        for (i, node) in self.graph.nodes.iter().enumerate() {
+            if node.id != i {
+                anyhow::bail!("step {} is numbered {}", i, node.id);
+            }
+            if node.hash.is_empty() {
+                anyhow::bail!("{} has no cache key", node.name);
+            }
+            if let Some(dep) = node.deps.iter().find(|&&d| d >= len || d == i) {
+                anyhow::bail!("{} depends on unknown step {}", node.name, dep);
+            }
+        }
+        if let Some(id) = self.inputs.keys().find(|&&id| id >= len) {
+            anyhow::bail!("sources recorded for unknown step {}", id);
+        }
+        Ok(())
+    }
+
+    /// Differences between the environment the keys were computed in and
+    /// `current`.
+    pub fn env_drift(&self, current: &EnvFingerprint) -> Vec<EnvChange> {
+        crate::env::drift::diff(&self.env, current)
+    }
+
+    /// The graph to execute in `context_dir`, keys untouched. The sources of
+    /// COPY steps neither cache has yet are restored under `staging`.
+    pub async fn resolve(
+        &self,
+        cache: &HybridCache,
+        context_dir: &Path,
+        staging: &Path,
+    ) -> Result<BuildGraph> {
+        let mut graph = self.graph.clone();
+        for node in &mut graph.nodes {
+            if let Some(ref rel) = node.source_path {
+                node.source_path = Some(context_dir.join(rel));
+            }
+            let Some(root) = self.inputs.get(&node.id) else {
+                continue;
+            };
+            if cache.local.exists(&node.hash) || cache.remote_has_artifact(&node.hash).await? {
+                continue;
+            }
+            let dest = staging.join(node.id.to_string());
+            cache
+                .materialize_tree(root, &dest)
+                .await
+                .with_context(|| format!("Failed to restore the sources of {}", node.name))?;
+            // A single file comes back as the only entry of a directory,
+            // which stores as the same tree
+            node.source_path = Some(dest);
+        }
+        Ok(graph)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn test_export_and_resolve_keep_keys() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = HybridCache {
+            local: crate::cache::LocalCache::in_dir(dir.path().join("cache")).unwrap(),
+            remote: None,
+            remote_health: None,
+            mirrors: None,
+            integrity: Default::default(),
+            uploads: Default::default(),
+            dedup_uploads: true,
+            prefetch_hints: false,
+            hinted: Default::default(),
+            max_artifact_bytes: None,
+        };
+        let context = dir.path().join("app");
+        std::fs::create_dir_all(context.join("src")).unwrap();
+        std::fs::write(context.join("src/main.c"), "int main() {}").unwrap();
+        let mut graph = crate::docker::dag::build_graph_from_instructions(
+            crate::docker::parser::parse_dockerfile(
+                "FROM scratch AS docs\nRUN make docs\nFROM scratch\nCOPY src /src\nRUN make\n",
+            ),
+            context.clone(),
+        );
+        for node in &mut graph.nodes {
+            node.hash = format!("key-{}", node.id);
+        }
+
+        // The build step and what it needs, without the docs stage
+        let subset = select(&graph, &["make".to_string()]).unwrap();
+        let contents: Vec<&str> = subset.nodes.iter().map(|n| n.content.as_str()).collect();
+        assert_eq!(contents, vec!["FROM scratch", "COPY src /src", "make"]);
+        assert!(subset.nodes[2].deps.contains(&1));
+        assert_eq!(subset.nodes[2].hash, "key-4");
+        assert!(select(&graph, &["make install".to_string()]).is_err());
+
+        let path = dir.path().join("graph.json");
+        PartialGraph::export(subset, EnvFingerprint::default(), &context, &cache)
+            .await
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        let partial = PartialGraph::load(&path).unwrap();
+        assert_eq!(
+            partial.graph.nodes[1].source_path,
+            Some(PathBuf::from("src"))
+        );
+        assert!(partial.env_drift(&EnvFingerprint::default()).is_empty());
+
+        // A worker without the checkout gets the sources from the cache
+        let worker = dir.path().join("worker");
+        let resolved = partial
+            .resolve(&cache, &worker, &dir.path().join("staging"))
+            .await
+            .unwrap();
+        let restored = resolved.nodes[1].source_path.clone().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(restored.join("main.c")).unwrap(),
+            "int main() {}"
+        );
+        let ignore = IgnoreRules::empty();
+        assert_eq!(
+            cache.put_tree(&restored, &ignore).await.unwrap().root,
+            partial.inputs[&1]
+        );
+        assert_eq!(resolved.nodes[2].source_path, None);
+
+        let mut tampered = partial.clone();
+        tampered.graph.nodes[2].deps = vec![7];
+        tampered.save(&path).unwrap();
+        assert!(PartialGraph::load(&path).is_err());
+    }
+}
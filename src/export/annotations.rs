@@ -0,0 +1,256 @@
+//! Inline CI annotations for lint findings, Dockerfile errors and failed
+//! nodes: GitHub Actions workflow commands on stdout, or a GitLab code
+//! quality report, so problems show up on the lines of the pull request.
+
+use crate::docker::lint::{Diagnostic, Severity};
+use crate::docker::parser::Span;
+use crate::graph::BuildGraph;
+use crate::report::BuildReport;
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+
+/// File the GitLab report is written to, as expected by
+/// `artifacts:reports:codequality`
+pub const GITLAB_REPORT_FILE: &str = "gl-code-quality-report.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnotationFormat {
+    Github,
+    Gitlab,
+}
+
+impl std::str::FromStr for AnnotationFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "github" => Ok(AnnotationFormat::Github),
+            "gitlab" => Ok(AnnotationFormat::Gitlab),
+            other => anyhow::bail!(
+                "Unknown annotation format '{}' (expected github or gitlab)",
+                other
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for AnnotationFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnnotationFormat::Github => write!(f, "github"),
+            AnnotationFormat::Gitlab => write!(f, "gitlab"),
+        }
+    }
+}
+
+/// One problem to surface inline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Annotation {
+    pub severity: Severity,
+    /// Short identifier of the check, e.g. `MB001` or `build-failure`
+    pub check: String,
+    pub title: String,
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl From<&Diagnostic> for Annotation {
+    fn from(d: &Diagnostic) -> Self {
+        Self {
+            severity: d.severity,
+            check: d.rule.code().to_string(),
+            title: format!("{:?}", d.rule),
+            message: d.message.clone(),
+            span: d.span.clone(),
+        }
+    }
+}
+
+impl Annotation {
+    /// An error that stopped the build before it ran, such as a broken
+    /// INCLUDE. Messages that start with `file:line:col: ` are placed there.
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        let message = format!("{:#}", error);
+        let (span, message) = match split_location(&message) {
+            Some((span, rest)) => (Some(span), rest.to_string()),
+            None => (None, message),
+        };
+        Self {
+            severity: Severity::Error,
+            check: "dockerfile-error".into(),
+            title: "Dockerfile error".into(),
+            message,
+            span,
+        }
+    }
+}
+
+/// Annotations for every failed node of a build.
+pub fn from_report(graph: &BuildGraph, report: &BuildReport) -> Vec<Annotation> {
+    report
+        .failures()
+        .map(|run| {
+            let span = graph
+                .nodes
+                .get(run.id)
+                .and_then(|n| n.metadata.span.clone());
+            let error = run.error.as_deref().unwrap_or("failed");
+            // Node errors carry the location prefix too; it is in the span
+            let message = match (&span, split_location(error)) {
+                (Some(_), Some((_, rest))) => rest.to_string(),
+                _ => error.to_string(),
+            };
+            Annotation {
+                severity: Severity::Error,
+                check: "build-failure".into(),
+                title: format!("{} failed", run.name),
+                message,
+                span,
+            }
+        })
+        .collect()
+}
+
+/// Parse a leading `file:line:col: ` off `message`.
+fn split_location(message: &str) -> Option<(Span, &str)> {
+    let (location, rest) = message.split_once(": ")?;
+    let mut parts = location.rsplitn(3, ':');
+    let column = parts.next()?.parse().ok()?;
+    let line = parts.next()?.parse().ok()?;
+    let file = parts.next().filter(|f| !f.is_empty())?;
+    Some((
+        Span {
+            file: PathBuf::from(file),
+            line,
+            column,
+        },
+        rest,
+    ))
+}
+
+/// `::error file=Dockerfile,line=12,col=1,title=...::message`
+pub fn github_command(annotation: &Annotation) -> String {
+    let command = match annotation.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+        Severity::Info => "notice",
+    };
+    let mut props = Vec::new();
+    if let Some(ref span) = annotation.span {
+        props.push(format!(
+            "file={}",
+            escape_property(&span.file.display().to_string())
+        ));
+        props.push(format!("line={}", span.line));
+        props.push(format!("col={}", span.column));
+    }
+    props.push(format!(
+        "title={}",
+        escape_property(&format!("{} ({})", annotation.title, annotation.check))
+    ));
+    format!(
+        "::{} {}::{}",
+        command,
+        props.join(","),
+        escape_data(&annotation.message)
+    )
+}
+
+fn escape_data(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(s: &str) -> String {
+    escape_data(s).replace(':', "%3A").replace(',', "%2C")
+}
+
+/// GitLab code quality report (a subset of the Code Climate format).
+pub fn gitlab_report(annotations: &[Annotation]) -> serde_json::Value {
+    let issues: Vec<serde_json::Value> = annotations
+        .iter()
+        .map(|a| {
+            let (path, line) = match a.span {
+                Some(ref span) => (span.file.display().to_string(), span.line),
+                None => ("Dockerfile".to_string(), 1),
+            };
+            let severity = match a.severity {
+                Severity::Error => "major",
+                Severity::Warning => "minor",
+                Severity::Info => "info",
+            };
+            let fingerprint =
+                blake3::hash(format!("{}\0{}\0{}\0{}", a.check, path, line, a.message).as_bytes())
+                    .to_hex()[..32]
+                    .to_string();
+            json!({
+                "description": format!("{}: {}", a.title, a.message),
+                "check_name": a.check,
+                "fingerprint": fingerprint,
+                "severity": severity,
+                "location": { "path": path, "lines": { "begin": line } },
+            })
+        })
+        .collect();
+    serde_json::Value::Array(issues)
+}
+
+/// Emit `annotations` in `format`: workflow commands go to stdout, the GitLab
+/// report to [`GITLAB_REPORT_FILE`] in `output_dir`.
+pub fn emit(format: AnnotationFormat, annotations: &[Annotation], output_dir: &Path) -> Result<()> {
+    match format {
+        AnnotationFormat::Github => {
+            for annotation in annotations {
+                println!("{}", github_command(annotation));
+            }
+        }
+        AnnotationFormat::Gitlab => {
+            let path = output_dir.join(GITLAB_REPORT_FILE);
+            std::fs::write(
+                &path,
+                serde_json::to_string_pretty(&gitlab_report(annotations))?,
+            )
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        let error = anyhow::anyhow!("Dockerfile:3:1: included file base.df not found");
+        let annotation = Annotation::from_error(&error);
+        assert_eq!(annotation.span.as_ref().unwrap().line, 3);
+        assert_eq!(annotation.message, "included file base.df not found");
+        assert_eq!(
+            github_command(&annotation),
+            "::error file=Dockerfile,line=3,col=1,title=Dockerfile error (dockerfile-error)::included file base.df not found"
+        );
+
+        let warning = Annotation {
+            severity: Severity::Warning,
+            check: "MB001".into(),
+            title: "UnpinnedBaseImage".into(),
+            message: "pin it\n100% please".into(),
+            span: None,
+        };
+        assert_eq!(
+            github_command(&warning),
+            "::warning title=UnpinnedBaseImage (MB001)::pin it%0A100%25 please"
+        );
+
+        let report = gitlab_report(&[annotation, warning]);
+        assert_eq!(report[0]["location"]["lines"]["begin"], 3);
+        assert_eq!(report[0]["severity"], "major");
+        assert_eq!(report[1]["severity"], "minor");
+        assert_ne!(report[0]["fingerprint"], report[1]["fingerprint"]);
+
+        assert!(split_location("no location here").is_none());
+    }
+}
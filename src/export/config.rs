@@ -1,3 +1,4 @@
+use crate::env::Platform;
 use crate::export::layer::LayerInfo;
 use crate::graph::BuildGraph;
 use chrono::Utc;
@@ -7,6 +8,8 @@ use serde::{Deserialize, Serialize};
 pub struct OCIConfig {
     pub architecture: String,
     pub os: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
     pub config: OCIImageConfig,
     pub rootfs: OCIRootFS,
     pub history: Vec<OCIHistory>,
@@ -37,7 +40,13 @@ pub struct OCIHistory {
     pub empty_layer: Option<bool>,
 }
 
-pub fn create_config(graph: &BuildGraph, layers: &[LayerInfo], reproducible: bool) -> OCIConfig {
+/// Image config for `graph`, for `platform` or `linux/amd64` if there is none.
+pub fn create_config(
+    graph: &BuildGraph,
+    layers: &[LayerInfo],
+    reproducible: bool,
+    platform: Option<&Platform>,
+) -> OCIConfig {
     let mut env = Vec::new();
     for node in &graph.nodes {
         for (k, v) in &node.env {
@@ -52,8 +61,9 @@ pub fn create_config(graph: &BuildGraph, layers: &[LayerInfo], reproducible: boo
     };
 
     OCIConfig {
-        architecture: "amd64".to_string(),
-        os: "linux".to_string(),
+        architecture: platform.map_or("amd64", |p| &p.architecture).to_string(),
+        os: platform.map_or("linux", |p| &p.os).to_string(),
+        variant: platform.and_then(|p| p.variant.clone()),
         config: OCIImageConfig {
             env,
             cmd: Some(vec!["/bin/sh".to_string()]),
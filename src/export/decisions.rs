@@ -0,0 +1,303 @@
+//! Per-node cache decision records, sent to `MEMOBUILD_DECISIONS_SINK` after
+//! each build.
+
+use crate::graph::BuildGraph;
+use crate::report::{BuildReport, CacheSource, NodeOutcome};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Version of the record layout, for consumers to branch on
+pub const RECORD_VERSION: u32 = 1;
+
+/// How the cache decided about a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    LocalHit,
+    RemoteHit,
+    /// Not cached; the node ran
+    Miss,
+    /// Not cached; the node ran and failed
+    Failed,
+    /// The node did not run
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DecisionRecord {
+    pub version: u32,
+    /// `MEMOBUILD_BUILD_ID` of the build
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_id: Option<String>,
+    /// `MEMOBUILD_NAMESPACE` of the build, e.g. the team or repository
+    pub namespace: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    /// RFC 3339 time the build finished
+    pub recorded_at: String,
+    pub node_id: usize,
+    pub name: String,
+    /// Stage or group of the node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Instruction kind, e.g. `run` or `copy`
+    pub kind: String,
+    /// Cache key of the node
+    pub key: String,
+    pub decision: Decision,
+    /// Why a miss ran, e.g. `input_file_changed`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// The reason with its detail, e.g. the changed file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason_detail: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    pub duration_ms: u64,
+}
+
+/// One record per node of a finished build.
+pub fn records(
+    graph: &BuildGraph,
+    report: &BuildReport,
+    platform: Option<&str>,
+) -> Vec<DecisionRecord> {
+    let build_id = crate::cache::pin::build_id_from_env();
+    let namespace = std::env::var("MEMOBUILD_NAMESPACE")
+        .unwrap_or_else(|_| crate::constants::DEFAULT_NAMESPACE.to_string());
+    let recorded_at = chrono::Utc::now().to_rfc3339();
+    report
+        .nodes
+        .iter()
+        .map(|node| {
+            let decision = match (node.outcome, node.cache_source) {
+                (NodeOutcome::Cached, CacheSource::Remote) => Decision::RemoteHit,
+                (NodeOutcome::Cached, _) => Decision::LocalHit,
+                (NodeOutcome::Executed, _) => Decision::Miss,
+                (NodeOutcome::Failed, _) => Decision::Failed,
+                (NodeOutcome::Skipped, _) => Decision::Skipped,
+            };
+            let ran = matches!(decision, Decision::Miss | Decision::Failed);
+            let reason = node.dirty_reason.as_ref().filter(|_| ran);
+            DecisionRecord {
+                version: RECORD_VERSION,
+                build_id: build_id.clone(),
+                namespace: namespace.clone(),
+                platform: platform.map(str::to_string),
+                recorded_at: recorded_at.clone(),
+                node_id: node.id,
+                name: node.name.clone(),
+                group: node.group.clone(),
+                kind: graph
+                    .nodes
+                    .get(node.id)
+                    .map(|n| n.kind.label().to_string())
+                    .unwrap_or_default(),
+                key: node.hash.clone(),
+                decision,
+                reason: reason.map(|r| r.category().to_string()),
+                reason_detail: reason.map(|r| r.to_string()),
+                bytes: node.artifact_bytes,
+                duration_ms: node.duration_ms,
+            }
+        })
+        .collect()
+}
+
+/// Where records are sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecisionSink {
+    File(PathBuf),
+    Http { url: String, token: Option<String> },
+    Kafka { brokers: Vec<String>, topic: String },
+}
+
+impl std::str::FromStr for DecisionSink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.starts_with("http://") || s.starts_with("https://") {
+            return Ok(DecisionSink::Http {
+                url: s.to_string(),
+                token: None,
+            });
+        }
+        if let Some(rest) = s.strip_prefix("kafka://") {
+            let (brokers, topic) = rest
+                .split_once('/')
+                .filter(|(brokers, topic)| !brokers.is_empty() && !topic.is_empty())
+                .with_context(|| format!("Expected kafka://<brokers>/<topic>, got '{}'", s))?;
+            return Ok(DecisionSink::Kafka {
+                brokers: brokers.split(',').map(str::to_string).collect(),
+                topic: topic.to_string(),
+            });
+        }
+        let path = s.strip_prefix("file://").unwrap_or(s);
+        if path.is_empty() {
+            anyhow::bail!("Empty decision sink");
+        }
+        Ok(DecisionSink::File(PathBuf::from(path)))
+    }
+}
+
+impl DecisionSink {
+    /// The sink `MEMOBUILD_DECISIONS_SINK` names, if any.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(value) = std::env::var("MEMOBUILD_DECISIONS_SINK") else {
+            return Ok(None);
+        };
+        if value.trim().is_empty() {
+            return Ok(None);
+        }
+        let mut sink: DecisionSink = value.parse().context("Invalid MEMOBUILD_DECISIONS_SINK")?;
+        if let DecisionSink::Http { ref mut token, .. } = sink {
+            *token = std::env::var("MEMOBUILD_DECISIONS_TOKEN").ok();
+        }
+        Ok(Some(sink))
+    }
+
+    pub async fn send(&self, records: &[DecisionRecord]) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        match self {
+            DecisionSink::File(path) => {
+                let mut lines = Vec::new();
+                for record in records {
+                    serde_json::to_writer(&mut lines, record)?;
+                    lines.push(b'\n');
+                }
+                // One write per build, so concurrent builds appending to a
+                // shared file do not interleave within a line
+                let mut file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                file.write_all(&lines)?;
+                Ok(())
+            }
+            DecisionSink::Http { url, token } => {
+                let mut request = reqwest::Client::new().post(url).json(records);
+                if let Some(token) = token {
+                    request = request.bearer_auth(token);
+                }
+                let response = request
+                    .timeout(std::time::Duration::from_secs(30))
+                    .send()
+                    .await
+                    .with_context(|| format!("Failed to send decisions to {}", url))?;
+                if !response.status().is_success() {
+                    anyhow::bail!("{} rejected the decisions: {}", url, response.status());
+                }
+                Ok(())
+            }
+            DecisionSink::Kafka { brokers, topic } => {
+                send_kafka(brokers.clone(), topic.clone(), records.to_vec()).await
+            }
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+async fn send_kafka(
+    brokers: Vec<String>,
+    topic: String,
+    records: Vec<DecisionRecord>,
+) -> Result<()> {
+    use kafka::producer::{Producer, Record, RequiredAcks};
+    tokio::task::spawn_blocking(move || {
+        let mut producer = Producer::from_hosts(brokers)
+            .with_ack_timeout(std::time::Duration::from_secs(10))
+            .with_required_acks(RequiredAcks::One)
+            .create()
+            .context("Failed to connect to Kafka")?;
+        let payloads = records
+            .iter()
+            .map(|record| Ok((record.key.clone(), serde_json::to_vec(record)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let messages: Vec<_> = payloads
+            .iter()
+            .map(|(key, value)| Record::from_key_value(&topic, key.as_bytes(), value.as_slice()))
+            .collect();
+        producer
+            .send_all(&messages)
+            .with_context(|| format!("Failed to produce to Kafka topic {}", topic))?;
+        Ok(())
+    })
+    .await?
+}
+
+#[cfg(not(feature = "kafka"))]
+async fn send_kafka(
+    _brokers: Vec<String>,
+    _topic: String,
+    _records: Vec<DecisionRecord>,
+) -> Result<()> {
+    anyhow::bail!("Kafka sinks need a memobuild built with the kafka feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::NodeReport;
+
+    #[tokio::test]
+    async fn test_decisions_appended_to_file() {
+        let graph = crate::docker::dag::build_graph_from_instructions(
+            crate::docker::parser::parse_dockerfile("FROM alpine\nRUN make\nRUN make test\n"),
+            PathBuf::from("."),
+        );
+        let mut report = BuildReport::default();
+        for (id, outcome, source) in [
+            (0, NodeOutcome::Cached, CacheSource::Remote),
+            (1, NodeOutcome::Executed, CacheSource::None),
+            (2, NodeOutcome::Failed, CacheSource::None),
+        ] {
+            let mut node = NodeReport::skipped(&graph.nodes[id]);
+            node.outcome = outcome;
+            node.cache_source = source;
+            node.dirty_reason = Some(crate::graph::DirtyReason::InputFileChanged(
+                "Makefile".into(),
+            ));
+            report.record(node);
+        }
+
+        let records = records(&graph, &report, Some("linux/amd64"));
+        let decisions: Vec<Decision> = records.iter().map(|r| r.decision).collect();
+        assert_eq!(
+            decisions,
+            vec![Decision::RemoteHit, Decision::Miss, Decision::Failed]
+        );
+        // Hits did not need a reason
+        assert_eq!(records[0].reason, None);
+        assert_eq!(records[1].reason.as_deref(), Some("input_file_changed"));
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("decisions.jsonl");
+        let sink: DecisionSink = format!("file://{}", path.display()).parse().unwrap();
+        sink.send(&records).await.unwrap();
+        sink.send(&records[..1]).await.unwrap();
+        let lines: Vec<DecisionRecord> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[3], records[0]);
+
+        assert_eq!(
+            "kafka://a:9092,b:9092/cache"
+                .parse::<DecisionSink>()
+                .unwrap(),
+            DecisionSink::Kafka {
+                brokers: vec!["a:9092".into(), "b:9092".into()],
+                topic: "cache".into()
+            }
+        );
+        assert!("kafka://a:9092".parse::<DecisionSink>().is_err());
+    }
+}
@@ -0,0 +1,447 @@
+//! `docker load` archives of a built image, assembled from the cached artifacts
+//! of its last stage.
+
+use crate::cache::HybridCache;
+use crate::env::Platform;
+use crate::export::config::{OCIConfig, OCIHistory, OCIImageConfig, OCIRootFS};
+use crate::graph::{BuildGraph, Node, NodeKind};
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default)]
+pub struct DockerArchive {
+    /// Layers in the image, the base image's included
+    pub layers: usize,
+    /// Size of the archive
+    pub bytes: u64,
+}
+
+/// A layer written to the staging directory.
+struct StagedLayer {
+    path: PathBuf,
+    /// sha256 of the uncompressed tar, without prefix
+    diff_id: String,
+}
+
+/// Write the image `graph` built, tagged `tag`, as a `docker load` archive
+/// at `dest`. Every step must be in `cache`.
+pub async fn write(
+    graph: &BuildGraph,
+    cache: &HybridCache,
+    tag: &str,
+    dest: &Path,
+    reproducible: bool,
+    platform: Option<&Platform>,
+) -> Result<DockerArchive> {
+    let staging = std::env::temp_dir().join(format!(
+        "memobuild-docker-archive-{}",
+        uuid::Uuid::new_v4().simple()
+    ));
+    std::fs::create_dir_all(&staging)?;
+    let result = write_in(graph, cache, tag, dest, reproducible, platform, &staging).await;
+    let _ = std::fs::remove_dir_all(&staging);
+    result
+}
+
+async fn write_in(
+    graph: &BuildGraph,
+    cache: &HybridCache,
+    tag: &str,
+    dest: &Path,
+    reproducible: bool,
+    platform: Option<&Platform>,
+    staging: &Path,
+) -> Result<DockerArchive> {
+    let created = if reproducible {
+        "1970-01-01T00:00:00Z".to_string()
+    } else {
+        chrono::Utc::now().to_rfc3339()
+    };
+    let history_entry = |created_by: String, empty: bool| OCIHistory {
+        created: created.clone(),
+        created_by,
+        empty_layer: empty.then_some(true),
+    };
+
+    let mut layers = Vec::new();
+    let mut history = Vec::new();
+    let mut env = Vec::new();
+    let mut workdir: Option<String> = None;
+    let mut cmd = None;
+    let stage_start = graph
+        .nodes
+        .iter()
+        .rposition(|node| node.kind == NodeKind::From)
+        .unwrap_or(0);
+    for node in &graph.nodes[stage_start..] {
+        let layer = match &node.kind {
+            NodeKind::From => {
+                let image = base_image(node);
+                if image != "scratch" {
+                    let base = base_layers(&image, staging).await?;
+                    for _ in &base {
+                        history.push(history_entry(format!("FROM {}", image), false));
+                    }
+                    layers.extend(base);
+                }
+                continue;
+            }
+            NodeKind::Env => {
+                let mut vars: Vec<_> = node
+                    .env
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect();
+                vars.sort();
+                env.extend(vars);
+                None
+            }
+            NodeKind::Workdir => {
+                let dir = node.content.trim_start_matches("WORKDIR").trim();
+                workdir = Some(format!("/{}", resolve(workdir.as_deref(), dir)));
+                None
+            }
+            NodeKind::Cmd => {
+                cmd = Some(parse_cmd(node.content.trim_start_matches("CMD").trim()));
+                None
+            }
+            NodeKind::Copy { dst, .. }
+            | NodeKind::CopyExtend { dst, .. }
+            | NodeKind::CopyFrom { dst, .. }
+            | NodeKind::Add { dst, .. } => {
+                let root = String::from_utf8(artifact(cache, node).await?)
+                    .with_context(|| format!("Corrupt cache entry of {}", node.name))?;
+                let target = resolve(workdir.as_deref(), &dst.to_string_lossy());
+                Some(stage_tree(cache, &root, &target, staging, layers.len()).await?)
+            }
+            NodeKind::Run
+            | NodeKind::RunExtend { .. }
+            | NodeKind::CustomHook { .. }
+            | NodeKind::Plugin { .. }
+            | NodeKind::Git { .. } => {
+                let data = artifact(cache, node).await?;
+                // Without a captured diff the artifact is the command's output
+                if is_tar(&data) {
+                    Some(stage(staging, layers.len(), |out| {
+                        Ok(out.write_all(&data)?)
+                    })?)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        history.push(history_entry(node.content.clone(), layer.is_none()));
+        layers.extend(layer);
+    }
+
+    let config = OCIConfig {
+        architecture: platform.map_or("amd64", |p| &p.architecture).to_string(),
+        os: platform.map_or("linux", |p| &p.os).to_string(),
+        variant: platform.and_then(|p| p.variant.clone()),
+        config: OCIImageConfig {
+            env,
+            cmd,
+            working_dir: workdir,
+        },
+        rootfs: OCIRootFS {
+            fs_type: "layers".to_string(),
+            diff_ids: layers
+                .iter()
+                .map(|l| format!("sha256:{}", l.diff_id))
+                .collect(),
+        },
+        history,
+    };
+    let config_json = serde_json::to_vec(&config)?;
+    let config_name = format!("{}.json", crate::export::utils::sha256_bytes(&config_json));
+    let layer_names: Vec<String> = layers
+        .iter()
+        .map(|l| format!("{}/layer.tar", l.diff_id))
+        .collect();
+    let manifest = serde_json::json!([{
+        "Config": config_name,
+        "RepoTags": [tag],
+        "Layers": layer_names,
+    }]);
+
+    let file =
+        File::create(dest).with_context(|| format!("Failed to create {}", dest.display()))?;
+    let mut archive = tar::Builder::new(std::io::BufWriter::new(file));
+    archive.mode(tar::HeaderMode::Deterministic);
+    let mut written = HashSet::new();
+    for (layer, name) in layers.iter().zip(&layer_names) {
+        // Steps that changed the same files the same way share a layer
+        if written.insert(name) {
+            archive.append_path_with_name(&layer.path, name)?;
+        }
+    }
+    append(&mut archive, &config_name, &config_json)?;
+    append(
+        &mut archive,
+        "manifest.json",
+        &serde_json::to_vec(&manifest)?,
+    )?;
+    archive.into_inner()?.flush()?;
+
+    Ok(DockerArchive {
+        layers: layers.len(),
+        bytes: std::fs::metadata(dest)?.len(),
+    })
+}
+
+fn append<W: Write>(archive: &mut tar::Builder<W>, name: &str, data: &[u8]) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header.set_cksum();
+    archive.append_data(&mut header, name, data)?;
+    Ok(())
+}
+
+async fn artifact(cache: &HybridCache, node: &Node) -> Result<Vec<u8>> {
+    cache
+        .get_artifact(&node.hash)
+        .await?
+        .with_context(|| format!("{} is not in the cache; build the image first", node.name))
+}
+
+/// Image of a FROM step, without its `AS` name.
+fn base_image(node: &Node) -> String {
+    node.content
+        .trim_start_matches("FROM")
+        .split_whitespace()
+        .find(|word| !word.starts_with("--"))
+        .unwrap_or("scratch")
+        .to_string()
+}
+
+/// `path` inside the image, relative to its root: absolute paths as they
+/// are, others under `workdir`.
+fn resolve(workdir: Option<&str>, path: &str) -> String {
+    let full = match (path.starts_with('/'), workdir) {
+        (false, Some(dir)) => format!("{}/{}", dir, path),
+        _ => path.to_string(),
+    };
+    full.split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Exec form (`["app", "--serve"]`) as it is, shell form run by `/bin/sh -c`.
+fn parse_cmd(cmd: &str) -> Vec<String> {
+    serde_json::from_str(cmd)
+        .unwrap_or_else(|_| vec!["/bin/sh".to_string(), "-c".to_string(), cmd.to_string()])
+}
+
+/// Whether `data` starts with a tar header.
+fn is_tar(data: &[u8]) -> bool {
+    data.get(257..262) == Some(b"ustar")
+}
+
+/// Write layer `index` to the staging directory with `fill`, hashing it as
+/// it is written.
+fn stage(
+    staging: &Path,
+    index: usize,
+    fill: impl FnOnce(&mut HashingWriter<File>) -> Result<()>,
+) -> Result<StagedLayer> {
+    let path = staging.join(format!("layer-{}.tar", index));
+    let mut out = HashingWriter {
+        inner: File::create(&path)?,
+        hasher: Sha256::new(),
+    };
+    fill(&mut out)?;
+    out.inner.flush()?;
+    Ok(StagedLayer {
+        path,
+        diff_id: hex::encode(out.hasher.finalize()),
+    })
+}
+
+/// A layer holding the content store tree `root` at `target`.
+async fn stage_tree(
+    cache: &HybridCache,
+    root: &str,
+    target: &str,
+    staging: &Path,
+    index: usize,
+) -> Result<StagedLayer> {
+    let tree = staging.join(format!("tree-{}", index));
+    cache.materialize_tree(root, &tree).await?;
+    let layer = stage(staging, index, |out| {
+        let mut builder = tar::Builder::new(out);
+        builder.mode(tar::HeaderMode::Deterministic);
+        builder.follow_symlinks(false);
+        for entry in walkdir::WalkDir::new(&tree)
+            .min_depth(1)
+            .sort_by_file_name()
+        {
+            let entry = entry?;
+            let rel = entry.path().strip_prefix(&tree)?;
+            builder.append_path_with_name(entry.path(), Path::new(target).join(rel))?;
+        }
+        builder.finish()?;
+        Ok(())
+    });
+    let _ = std::fs::remove_dir_all(&tree);
+    layer
+}
+
+/// The layers of `image`, fetched from its registry and decompressed.
+async fn base_layers(image: &str, staging: &Path) -> Result<Vec<StagedLayer>> {
+    let (image, staging) = (image.to_string(), staging.to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        let image_ref = crate::docker::resolve::ImageRef::parse(&image);
+        let client = crate::export::registry::RegistryClient::new(
+            image_ref.api_host(),
+            &image_ref.repository,
+        );
+        let digests = client
+            .layer_digests(image_ref.reference())
+            .with_context(|| format!("Failed to fetch the layers of base image {}", image))?;
+        digests
+            .iter()
+            .enumerate()
+            .map(|(index, digest)| {
+                let blob = client.open_blob(digest)?;
+                stage(&staging, index, |out| {
+                    let mut blob = std::io::BufReader::new(blob);
+                    let gzip = {
+                        use std::io::BufRead;
+                        blob.fill_buf()?.starts_with(&[0x1f, 0x8b])
+                    };
+                    if gzip {
+                        std::io::copy(&mut flate2::read::GzDecoder::new(blob), out)?;
+                    } else {
+                        std::io::copy(&mut blob, out)?;
+                    }
+                    Ok(())
+                })
+                .with_context(|| format!("Failed to download layer {} of {}", digest, image))
+            })
+            .collect()
+    })
+    .await?
+}
+
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn read_entry(archive: &Path, name: &str) -> Option<Vec<u8>> {
+        let mut archive = tar::Archive::new(File::open(archive).ok()?);
+        for entry in archive.entries().ok()? {
+            let mut entry = entry.ok()?;
+            if entry.path().ok()?.to_string_lossy() == name {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).ok()?;
+                return Some(data);
+            }
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn test_archive_of_cached_steps() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = HybridCache {
+            local: crate::cache::LocalCache::in_dir(dir.path().join("cache")).unwrap(),
+            remote: None,
+            remote_health: None,
+            mirrors: None,
+            integrity: Default::default(),
+            uploads: Default::default(),
+            dedup_uploads: true,
+            prefetch_hints: false,
+            hinted: Default::default(),
+            max_artifact_bytes: None,
+        };
+        let mut graph = crate::docker::dag::build_graph_from_instructions(
+            crate::docker::parser::parse_dockerfile(
+                "FROM scratch\nWORKDIR /app\nCOPY src .\nRUN make\nENV MODE=prod\nCMD [\"/app/app\"]\n",
+            ),
+            PathBuf::from("."),
+        );
+        for node in &mut graph.nodes {
+            node.hash = format!("key-{}", node.id);
+        }
+
+        let src = dir.path().join("src");
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::write(src.join("main.c"), "int main() {}").unwrap();
+        let tree = cache
+            .put_tree(&src, &crate::hasher::IgnoreRules::empty())
+            .await
+            .unwrap();
+        cache
+            .put_artifact("key-2", tree.root.as_bytes())
+            .await
+            .unwrap();
+        let mut diff = tar::Builder::new(Vec::new());
+        append(&mut diff, "app/app", b"binary").unwrap();
+        cache
+            .put_artifact("key-3", &diff.into_inner().unwrap())
+            .await
+            .unwrap();
+
+        let path = dir.path().join("image.tar");
+        let written = write(&graph, &cache, "app:latest", &path, true, None)
+            .await
+            .unwrap();
+        assert_eq!(written.layers, 2);
+
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&read_entry(&path, "manifest.json").unwrap()).unwrap();
+        assert_eq!(manifest[0]["RepoTags"][0], "app:latest");
+        let config: OCIConfig = serde_json::from_slice(
+            &read_entry(&path, manifest[0]["Config"].as_str().unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(config.config.env, vec!["MODE=prod"]);
+        assert_eq!(config.config.working_dir.as_deref(), Some("/app"));
+        assert_eq!(config.config.cmd, Some(vec!["/app/app".to_string()]));
+        assert_eq!(config.history.len(), 5);
+
+        // The COPY layer holds the tree under the WORKDIR
+        let copy = read_entry(&path, manifest[0]["Layers"][0].as_str().unwrap()).unwrap();
+        let names: Vec<String> = tar::Archive::new(copy.as_slice())
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, vec!["app/main.c"]);
+        let digest = format!("sha256:{}", crate::export::utils::sha256_bytes(&copy));
+        assert_eq!(config.rootfs.diff_ids[0], digest);
+
+        // Missing steps are not skipped
+        cache.invalidate("key-3", true).await.unwrap();
+        assert!(write(&graph, &cache, "app:latest", &path, true, None)
+            .await
+            .is_err());
+    }
+}
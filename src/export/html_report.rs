@@ -0,0 +1,298 @@
+//! Standalone HTML build report with its data embedded as JSON.
+
+use crate::graph::BuildGraph;
+use crate::report::BuildReport;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Debug, Serialize)]
+struct ReportNode<'a> {
+    id: usize,
+    name: &'a str,
+    kind: &'static str,
+    content: &'a str,
+    deps: &'a [usize],
+    hash: &'a str,
+    /// `Dockerfile:12:1`, when known
+    location: Option<String>,
+    outcome: String,
+    cache_source: crate::report::CacheSource,
+    duration_ms: u64,
+    artifact_digest: Option<&'a str>,
+    log: Option<&'a str>,
+    group: Option<&'a str>,
+    usage: Option<crate::sandbox::usage::ResourceUsage>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReportData<'a> {
+    title: &'a str,
+    generated_at: String,
+    total_duration_ms: u64,
+    succeeded: bool,
+    nodes: Vec<ReportNode<'a>>,
+    groups: Vec<crate::report::GroupSummary>,
+}
+
+/// Render the report for a build of `graph` as an HTML document.
+pub fn render(graph: &BuildGraph, report: &BuildReport, title: &str) -> Result<String> {
+    let nodes = graph
+        .nodes
+        .iter()
+        .map(|node| {
+            let run = report.node(node.id);
+            ReportNode {
+                id: node.id,
+                name: &node.name,
+                kind: node.kind.label(),
+                content: &node.content,
+                deps: &node.deps,
+                hash: &node.hash,
+                location: node.metadata.span.as_ref().map(|s| s.to_string()),
+                outcome: run
+                    .map(|r| r.outcome.to_string())
+                    .unwrap_or_else(|| "skipped".into()),
+                cache_source: run
+                    .map(|r| r.cache_source)
+                    .unwrap_or(crate::report::CacheSource::None),
+                duration_ms: run.map(|r| r.duration_ms).unwrap_or_default(),
+                artifact_digest: run.and_then(|r| r.artifact_digest.as_deref()),
+                log: run.and_then(|r| r.error.as_deref()),
+                group: node.metadata.group.as_deref(),
+                usage: run.and_then(|r| r.usage),
+            }
+        })
+        .collect();
+
+    let data = ReportData {
+        title,
+        generated_at: chrono::Utc::now().to_rfc3339(),
+        total_duration_ms: report.total_duration_ms,
+        succeeded: report.succeeded(),
+        nodes,
+        groups: report.groups(),
+    };
+    // `</` would end the script element the JSON is embedded in
+    let json = serde_json::to_string(&data)?.replace("</", "<\\/");
+
+    Ok(TEMPLATE
+        .replace("{{TITLE}}", &escape_html(title))
+        .replace("{{DATA}}", &json))
+}
+
+/// Write the report to `path`.
+pub fn write(path: &Path, graph: &BuildGraph, report: &BuildReport, title: &str) -> Result<()> {
+    std::fs::write(path, render(graph, report, title)?)
+        .with_context(|| format!("Failed to write HTML report to {}", path.display()))
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{{TITLE}}</title>
+<style>
+  body { font-family: system-ui, sans-serif; margin: 0; color: #1f2328; }
+  header { padding: 12px 20px; border-bottom: 1px solid #d0d7de; }
+  header h1 { font-size: 18px; margin: 0 0 4px; }
+  main { display: grid; grid-template-columns: minmax(0, 3fr) minmax(0, 2fr); }
+  #graph { overflow: auto; border-right: 1px solid #d0d7de; }
+  #details { padding: 12px 20px; }
+  #details pre { background: #f6f8fa; padding: 8px; white-space: pre-wrap; word-break: break-all; }
+  table { border-collapse: collapse; width: 100%; font-size: 13px; }
+  th, td { text-align: left; padding: 4px 8px; border-bottom: 1px solid #eaeef2; }
+  td.num { text-align: right; font-variant-numeric: tabular-nums; }
+  tr.sel { background: #ddf4ff; }
+  tbody tr { cursor: pointer; }
+  .executed { fill: #fff8c5; color: #9a6700; }
+  .cached { fill: #dafbe1; color: #1a7f37; }
+  .failed { fill: #ffebe9; color: #cf222e; }
+  .skipped { fill: #f6f8fa; color: #656d76; }
+  svg text { font-size: 11px; fill: #1f2328; pointer-events: none; }
+  svg rect { stroke: #8c959f; cursor: pointer; }
+  svg rect.sel { stroke: #0969da; stroke-width: 2; }
+  svg line { stroke: #afb8c1; }
+</style>
+</head>
+<body>
+<header><h1>{{TITLE}}</h1><div id="meta"></div></header>
+<main>
+  <div id="graph"></div>
+  <div id="details"><p>Select a node.</p></div>
+</main>
+<section id="groups" style="padding: 12px 20px" hidden>
+  <table>
+    <thead><tr><th>Group</th><th>Steps</th><th>Cached</th><th>Failed</th><th>Time</th><th>Size</th></tr></thead>
+    <tbody id="group-rows"></tbody>
+  </table>
+</section>
+<section style="padding: 12px 20px">
+  <table>
+    <thead><tr><th>ID</th><th>Node</th><th>Outcome</th><th>Source</th><th>Time</th></tr></thead>
+    <tbody id="rows"></tbody>
+  </table>
+</section>
+<script type="application/json" id="data">{{DATA}}</script>
+<script>
+(function () {
+  var data = JSON.parse(document.getElementById("data").textContent);
+  var nodes = data.nodes, byId = {};
+  nodes.forEach(function (n) { byId[n.id] = n; });
+
+  function ms(v) {
+    return v < 1000 ? v + "ms" : v < 60000 ? (v / 1000).toFixed(1) + "s"
+      : Math.floor(v / 60000) + "m" + String(Math.floor(v % 60000 / 1000)).padStart(2, "0") + "s";
+  }
+  function el(tag, attrs, text) {
+    var e = document.createElementNS(tag === "svg" || attrs.svg ? "http://www.w3.org/2000/svg" : "http://www.w3.org/1999/xhtml", tag);
+    Object.keys(attrs).forEach(function (k) { if (k !== "svg") e.setAttribute(k, attrs[k]); });
+    if (text !== undefined) e.textContent = text;
+    return e;
+  }
+
+  document.getElementById("meta").textContent = (data.succeeded ? "Succeeded" : "Failed") +
+    " in " + ms(data.total_duration_ms) + " · " + nodes.length + " nodes · generated " + data.generated_at;
+
+  // Columns by depth: a node sits one column right of its deepest dependency
+  var depth = {}, columns = [];
+  nodes.forEach(function (n) {
+    depth[n.id] = n.deps.reduce(function (d, id) { return Math.max(d, (depth[id] || 0) + 1); }, 0);
+    (columns[depth[n.id]] = columns[depth[n.id]] || []).push(n);
+  });
+  var W = 180, H = 28, GX = 40, GY = 12, pos = {};
+  columns.forEach(function (col, x) {
+    col.forEach(function (n, y) { pos[n.id] = { x: 10 + x * (W + GX), y: 10 + y * (H + GY) }; });
+  });
+  var rows = Math.max.apply(null, columns.map(function (c) { return c.length; }).concat([1]));
+  var svg = el("svg", { width: 20 + columns.length * (W + GX), height: 20 + rows * (H + GY) });
+  nodes.forEach(function (n) {
+    n.deps.forEach(function (id) {
+      if (!pos[id]) return;
+      svg.appendChild(el("line", { svg: 1, x1: pos[id].x + W, y1: pos[id].y + H / 2, x2: pos[n.id].x, y2: pos[n.id].y + H / 2 }));
+    });
+  });
+  var rects = {}, trs = {};
+  nodes.forEach(function (n) {
+    var p = pos[n.id];
+    var r = el("rect", { svg: 1, x: p.x, y: p.y, width: W, height: H, rx: 4, "class": n.outcome });
+    r.addEventListener("click", function () { select(n.id); });
+    rects[n.id] = r;
+    svg.appendChild(r);
+    var label = n.name.length > 26 ? n.name.slice(0, 25) + "…" : n.name;
+    svg.appendChild(el("text", { svg: 1, x: p.x + 6, y: p.y + 18 }, label));
+  });
+  document.getElementById("graph").appendChild(svg);
+
+  function bytes(v) {
+    var units = ["B", "KB", "MB", "GB", "TB"], i = 0;
+    while (v >= 1024 && i < units.length - 1) { v /= 1024; i++; }
+    return (i ? v.toFixed(1) : v) + " " + units[i];
+  }
+  if (data.groups.length > 1) {
+    var groupRows = document.getElementById("group-rows");
+    data.groups.forEach(function (g) {
+      var ran = g.executed + g.cached + g.failed;
+      var tr = el("tr", {});
+      [g.group, g.nodes, ran ? Math.round(100 * g.cached / ran) + "%" : "-", g.failed,
+       ms(g.duration_ms), bytes(g.artifact_bytes)].forEach(function (v, i) {
+        tr.appendChild(el("td", i ? { "class": "num" } : {}, String(v)));
+      });
+      groupRows.appendChild(tr);
+    });
+    document.getElementById("groups").hidden = false;
+  }
+
+  var tbody = document.getElementById("rows");
+  nodes.forEach(function (n) {
+    var tr = el("tr", {});
+    [n.id, n.name, n.outcome, n.cache_source, ms(n.duration_ms)].forEach(function (v, i) {
+      var td = el("td", i === 4 ? { "class": "num" } : i === 2 ? { "class": n.outcome } : {}, String(v));
+      tr.appendChild(td);
+    });
+    tr.addEventListener("click", function () { select(n.id); });
+    trs[n.id] = tr;
+    tbody.appendChild(tr);
+  });
+
+  function select(id) {
+    var n = byId[id];
+    Object.keys(rects).forEach(function (k) {
+      rects[k].classList.toggle("sel", +k === id);
+      trs[k].classList.toggle("sel", +k === id);
+    });
+    var d = document.getElementById("details");
+    d.textContent = "";
+    d.appendChild(el("h2", {}, n.name));
+    var t = el("table", {});
+    [["Outcome", n.outcome], ["Cache source", n.cache_source], ["Time", ms(n.duration_ms)],
+     ["Kind", n.kind], ["Group", n.group || "-"], ["Location", n.location || "-"],
+     ["Cache key", n.hash || "-"],
+     ["Artifact digest", n.artifact_digest || "-"],
+     ["CPU", n.usage ? ms(n.usage.cpu_user_ms + n.usage.cpu_system_ms) : "-"],
+     ["Peak memory", n.usage ? bytes(n.usage.peak_rss_bytes) : "-"],
+     ["Disk IO", n.usage ? bytes(n.usage.read_bytes) + " read, " + bytes(n.usage.write_bytes) + " written" : "-"],
+     ["Depends on", n.deps.map(function (i) { return byId[i] ? byId[i].name : i; }).join(", ") || "-"]
+    ].forEach(function (row) {
+      var tr = el("tr", {});
+      tr.appendChild(el("th", {}, row[0]));
+      tr.appendChild(el("td", {}, row[1]));
+      t.appendChild(tr);
+    });
+    d.appendChild(t);
+    d.appendChild(el("h3", {}, "Instruction"));
+    d.appendChild(el("pre", {}, n.content));
+    if (n.log) {
+      d.appendChild(el("h3", {}, "Log"));
+      d.appendChild(el("pre", {}, n.log));
+    }
+  }
+
+  var failed = nodes.filter(function (n) { return n.outcome === "failed"; })[0];
+  if (failed || nodes.length) select((failed || nodes[0]).id);
+})();
+</script>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::{dag, parser};
+    use crate::report::{NodeOutcome, NodeReport};
+
+    #[test]
+    fn test_render_embeds_escaped_data() {
+        let graph = dag::build_graph_from_instructions(
+            parser::parse_dockerfile("FROM alpine\nRUN echo '</script>'\n"),
+            std::path::PathBuf::from("."),
+        );
+        let mut report = BuildReport::default();
+        report.record(NodeReport {
+            outcome: NodeOutcome::Failed,
+            error: Some("exit code 1".into()),
+            ..NodeReport::skipped(&graph.nodes[1])
+        });
+        report.finish(&graph);
+
+        let html = render(&graph, &report, "app <latest>").unwrap();
+        assert!(html.contains("<title>app &lt;latest&gt;</title>"));
+        assert_eq!(html.matches("</script>").count(), 2);
+
+        let start = html.find(r#"id="data">"#).unwrap() + r#"id="data">"#.len();
+        let end = start + html[start..].find("</script>").unwrap();
+        let data: serde_json::Value = serde_json::from_str(&html[start..end]).unwrap();
+        assert_eq!(data["succeeded"], false);
+        assert_eq!(data["nodes"][1]["outcome"], "failed");
+        assert_eq!(data["nodes"][1]["log"], "exit code 1");
+        assert_eq!(data["nodes"][1]["deps"][0], 0);
+    }
+}
@@ -0,0 +1,174 @@
+//! JUnit XML view of a build, with one test case per node.
+
+use crate::graph::BuildGraph;
+use crate::report::{BuildReport, CacheSource, NodeOutcome};
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::path::Path;
+
+/// Render `report` as a JUnit test suite named after `suite` (usually the
+/// Dockerfile path).
+pub fn render(graph: &BuildGraph, report: &BuildReport, suite: &str) -> String {
+    let mut cases = String::new();
+    for run in &report.nodes {
+        let location = graph
+            .nodes
+            .get(run.id)
+            .and_then(|n| n.metadata.span.as_ref())
+            .map(|s| s.to_string());
+        let class = match run.group {
+            Some(ref group) => format!("{}.{}", suite, group),
+            None => suite.to_string(),
+        };
+        let _ = write!(
+            cases,
+            "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+            escape(&class),
+            escape(&format!("[{}] {}", run.id, run.name)),
+            run.duration_ms as f64 / 1000.0
+        );
+        if let Some(ref location) = location {
+            let _ = write!(cases, " file=\"{}\"", escape(location));
+        }
+
+        match run.outcome {
+            NodeOutcome::Executed => cases.push_str("/>\n"),
+            NodeOutcome::Failed => {
+                let error = run.error.as_deref().unwrap_or("failed");
+                let message = error.lines().next().unwrap_or_default();
+                let _ = write!(
+                    cases,
+                    ">\n      <failure message=\"{}\" type=\"BuildError\">{}</failure>\n    </testcase>\n",
+                    escape(message),
+                    escape(error)
+                );
+            }
+            NodeOutcome::Cached => {
+                let source = match run.cache_source {
+                    CacheSource::Remote => "remote cache",
+                    _ => "local cache",
+                };
+                let _ = write!(
+                    cases,
+                    ">\n      <skipped message=\"restored from {}\"/>\n    </testcase>\n",
+                    source
+                );
+            }
+            NodeOutcome::Skipped => {
+                cases.push_str(">\n      <skipped message=\"not run\"/>\n    </testcase>\n")
+            }
+        }
+    }
+
+    let mut failures = report.count(NodeOutcome::Failed);
+    if let Some(ref smoke) = report.smoke_test {
+        let _ = write!(
+            cases,
+            "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+            escape(suite),
+            escape(&format!("HEALTHCHECK {}", smoke.command.join(" "))),
+            smoke.duration_ms as f64 / 1000.0
+        );
+        if smoke.passed {
+            cases.push_str("/>\n");
+        } else {
+            failures += 1;
+            let _ = write!(
+                cases,
+                ">\n      <failure message=\"{}\" type=\"SmokeTest\">{}</failure>\n    </testcase>\n",
+                escape(smoke.error.as_deref().unwrap_or("failed")),
+                escape(&smoke.output)
+            );
+        }
+    }
+
+    let skipped = report.count(NodeOutcome::Cached) + report.count(NodeOutcome::Skipped);
+    let attrs = format!(
+        "name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\" skipped=\"{}\" time=\"{:.3}\"",
+        escape(suite),
+        report.nodes.len() + usize::from(report.smoke_test.is_some()),
+        failures,
+        skipped,
+        report.total_duration_ms as f64 / 1000.0
+    );
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites {attrs}>\n  <testsuite {attrs}>\n{cases}  </testsuite>\n</testsuites>\n"
+    )
+}
+
+/// Write the report to `path`.
+pub fn write(path: &Path, graph: &BuildGraph, report: &BuildReport, suite: &str) -> Result<()> {
+    std::fs::write(path, render(graph, report, suite))
+        .with_context(|| format!("Failed to write JUnit report to {}", path.display()))
+}
+
+/// Escape text for XML attributes and content, dropping characters XML 1.0
+/// cannot carry (ANSI escapes from command output, for one).
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if (c as u32) < 0x20 => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::{dag, parser};
+    use crate::report::NodeReport;
+
+    #[test]
+    fn test_junit_outcomes() {
+        let graph = dag::build_graph_from_instructions(
+            parser::parse_dockerfile("FROM alpine\nRUN make\nRUN make test\n"),
+            std::path::PathBuf::from("."),
+        );
+        let mut report = BuildReport::default();
+        report.record(NodeReport {
+            outcome: NodeOutcome::Cached,
+            cache_source: CacheSource::Remote,
+            ..NodeReport::skipped(&graph.nodes[0])
+        });
+        report.record(NodeReport {
+            outcome: NodeOutcome::Failed,
+            duration_ms: 1500,
+            error: Some("exit code 2: \u{1b}[31m<missing>\u{1b}[0m".into()),
+            ..NodeReport::skipped(&graph.nodes[1])
+        });
+        report.finish(&graph);
+
+        let xml = render(&graph, &report, "Dockerfile");
+        assert!(xml.contains(r#"tests="3" failures="1" errors="0" skipped="2""#));
+        assert!(xml.contains(r#"<skipped message="restored from remote cache"/>"#));
+        assert!(xml.contains(r#"<skipped message="not run"/>"#));
+        assert!(xml.contains(&format!(
+            r#"name="[1] {}" time="1.500""#,
+            escape(&graph.nodes[1].name)
+        )));
+        assert!(xml.contains("exit code 2: [31m&lt;missing&gt;[0m"));
+        assert!(!xml.contains('\u{1b}'));
+
+        report.smoke_test = Some(crate::report::SmokeTestReport {
+            image: "sha256:abc".into(),
+            command: vec!["/bin/sh".into(), "-c".into(), "curl -f localhost".into()],
+            passed: false,
+            attempts: 3,
+            duration_ms: 100,
+            output: "connection refused".into(),
+            error: Some("HEALTHCHECK failed 3 time(s) in a row".into()),
+        });
+        let xml = render(&graph, &report, "Dockerfile");
+        assert!(xml.contains(r#"tests="4" failures="2""#));
+        assert!(xml.contains(r#"type="SmokeTest">connection refused</failure>"#));
+    }
+}
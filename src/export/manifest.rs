@@ -16,11 +16,17 @@ pub struct OCIDescriptor {
     pub media_type: String,
     pub digest: String,
     pub size: u64,
+    /// Platform of an image manifest listed in a multi-platform index
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<crate::env::Platform>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OCIIndex {
     #[serde(rename = "schemaVersion")]
     pub schema_version: u32,
+    /// Set when the index is pushed to a registry as a multi-platform image
+    #[serde(rename = "mediaType", default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
     pub manifests: Vec<OCIDescriptor>,
 }
@@ -1,15 +1,23 @@
+pub mod annotations;
 pub mod config;
+pub mod decisions;
+pub mod docker_archive;
+pub mod html_report;
+pub mod junit;
 pub mod layer;
 pub mod manifest;
 pub mod oci_exporter;
 pub mod registry;
+pub mod sbom;
+pub mod smoke;
 pub mod utils;
 
 pub use oci_exporter::OciExporter;
 
 use crate::graph::BuildGraph;
 use anyhow::Result;
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 
 pub fn export_image(graph: &BuildGraph, image_name: &str, reproducible: bool) -> Result<PathBuf> {
     let output_dir = PathBuf::from(".memobuild-output").join(image_name.replace(':', "-"));
@@ -24,3 +32,73 @@ pub fn export_image(graph: &BuildGraph, image_name: &str, reproducible: bool) ->
 
     exporter.write_manifest(graph, reproducible)
 }
+
+/// Export one image per platform into a single layout whose `index.json` is
+/// a multi-platform image index.
+pub fn export_image_index(
+    builds: &[(crate::env::Platform, &BuildGraph)],
+    image_name: &str,
+    reproducible: bool,
+) -> Result<PathBuf> {
+    let output_dir = PathBuf::from(".memobuild-output").join(image_name.replace(':', "-"));
+
+    let mut manifests = Vec::new();
+    for (platform, graph) in builds {
+        let mut exporter = OciExporter::new(&output_dir).with_platform(platform.clone());
+        for node in &graph.nodes {
+            let layer_info = exporter.create_layer(node)?;
+            exporter.add_layer(layer_info)?;
+        }
+        manifests.push(exporter.write_image(graph, reproducible)?);
+    }
+    oci_exporter::write_index(&output_dir, manifests)?;
+
+    println!(
+        "✅ OCI image index for {} platform(s) written to: {}",
+        builds.len(),
+        output_dir.display()
+    );
+    Ok(output_dir)
+}
+
+/// `sha256:` digest of the image manifest in the layout at `output_dir`.
+pub fn image_digest(output_dir: &Path) -> Result<String> {
+    let index: manifest::OCIIndex =
+        serde_json::from_str(&std::fs::read_to_string(output_dir.join("index.json"))?)?;
+    index
+        .manifests
+        .into_iter()
+        .next()
+        .map(|m| m.digest)
+        .ok_or_else(|| anyhow::anyhow!("{} has no image manifest", output_dir.display()))
+}
+
+/// Write an SBOM for `graph` into the image layout at `output_dir`. GIT
+/// sources are listed at the commit their remote HEAD points at now.
+pub fn export_sbom(
+    graph: &BuildGraph,
+    image_name: &str,
+    output_dir: &Path,
+    format: sbom::SbomFormat,
+    aliases: &HashSet<String>,
+    reproducible: bool,
+) -> Result<PathBuf> {
+    let components = sbom::collect_components(graph, aliases, &|url| {
+        crate::git::get_remote_head_hash(url).ok()
+    });
+    let created = if reproducible {
+        "1970-01-01T00:00:00Z".to_string()
+    } else {
+        chrono::Utc::now().to_rfc3339()
+    };
+    let path = output_dir.join(format.file_name());
+    let document = sbom::render(format, image_name, &components, &created)?;
+    std::fs::write(&path, document)?;
+    println!(
+        "📋 {} SBOM with {} components written to: {}",
+        format,
+        components.len(),
+        path.display()
+    );
+    Ok(path)
+}
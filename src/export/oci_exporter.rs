@@ -1,3 +1,4 @@
+use crate::env::Platform;
 use crate::export::{
     config, layer,
     manifest::{OCIDescriptor, OCIIndex, OCIManifest},
@@ -8,9 +9,13 @@ use anyhow::Result;
 use std::fs;
 use std::path::{Path, PathBuf};
 
+pub const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+
 pub struct OciExporter {
     output_dir: PathBuf,
     layers: Vec<layer::LayerInfo>,
+    platform: Option<Platform>,
 }
 
 impl OciExporter {
@@ -19,9 +24,17 @@ impl OciExporter {
         Self {
             output_dir,
             layers: Vec::new(),
+            platform: None,
         }
     }
 
+    /// Describe the image as built for `platform`, in its config and in the
+    /// index entry for its manifest.
+    pub fn with_platform(mut self, platform: Platform) -> Self {
+        self.platform = Some(platform);
+        self
+    }
+
     pub fn create_layer(&self, node: &Node) -> Result<layer::LayerInfo> {
         layer::create_layer_tar(&self.output_dir, node)
     }
@@ -31,17 +44,20 @@ impl OciExporter {
         Ok(())
     }
 
-    pub fn write_manifest(
+    /// Write the config and manifest blobs, returning the descriptor of the
+    /// manifest for an index to list.
+    pub fn write_image(
         &self,
         graph: &crate::graph::BuildGraph,
         reproducible: bool,
-    ) -> Result<PathBuf> {
+    ) -> Result<OCIDescriptor> {
         fs::create_dir_all(&self.output_dir)?;
         let blobs_dir = self.output_dir.join("blobs").join("sha256");
         fs::create_dir_all(&blobs_dir)?;
 
         // 1. Create config
-        let oci_config = config::create_config(graph, &self.layers, reproducible);
+        let oci_config =
+            config::create_config(graph, &self.layers, reproducible, self.platform.as_ref());
         let config_json = serde_json::to_string_pretty(&oci_config)?;
         let config_digest = format!("sha256:{}", utils::sha256_string(&config_json));
 
@@ -50,11 +66,12 @@ impl OciExporter {
         // 2. Create manifest
         let manifest = OCIManifest {
             schema_version: 2,
-            media_type: "application/vnd.oci.image.manifest.v1+json".to_string(),
+            media_type: MANIFEST_MEDIA_TYPE.to_string(),
             config: OCIDescriptor {
                 media_type: "application/vnd.oci.image.config.v1+json".to_string(),
                 digest: config_digest,
                 size: config_json.len() as u64,
+                platform: None,
             },
             layers: self
                 .layers
@@ -63,6 +80,7 @@ impl OciExporter {
                     media_type: "application/vnd.oci.image.layer.v1.tar+gzip".to_string(),
                     digest: l.digest.clone(),
                     size: l.size,
+                    platform: None,
                 })
                 .collect(),
         };
@@ -71,25 +89,21 @@ impl OciExporter {
         let manifest_digest = format!("sha256:{}", utils::sha256_string(&manifest_json));
         fs::write(blobs_dir.join(&manifest_digest[7..]), &manifest_json)?;
 
-        // 3. Create index.json
-        let index = OCIIndex {
-            schema_version: 2,
-            manifests: vec![OCIDescriptor {
-                media_type: "application/vnd.oci.image.manifest.v1+json".to_string(),
-                digest: manifest_digest,
-                size: manifest_json.len() as u64,
-            }],
-        };
-        fs::write(
-            self.output_dir.join("index.json"),
-            serde_json::to_string_pretty(&index)?,
-        )?;
+        Ok(OCIDescriptor {
+            media_type: MANIFEST_MEDIA_TYPE.to_string(),
+            digest: manifest_digest,
+            size: manifest_json.len() as u64,
+            platform: self.platform.clone(),
+        })
+    }
 
-        // 4. Create oci-layout
-        fs::write(
-            self.output_dir.join("oci-layout"),
-            r#"{"imageLayoutVersion": "1.0.0"}"#,
-        )?;
+    pub fn write_manifest(
+        &self,
+        graph: &crate::graph::BuildGraph,
+        reproducible: bool,
+    ) -> Result<PathBuf> {
+        let descriptor = self.write_image(graph, reproducible)?;
+        write_index(&self.output_dir, vec![descriptor])?;
 
         println!(
             "✅ OCI Image manifest written to: {}",
@@ -98,3 +112,23 @@ impl OciExporter {
         Ok(self.output_dir.clone())
     }
 }
+
+/// Write `index.json` and `oci-layout` for the manifests in `output_dir`.
+/// More than one manifest makes it a multi-platform image index.
+pub fn write_index(output_dir: &Path, manifests: Vec<OCIDescriptor>) -> Result<()> {
+    let index = OCIIndex {
+        schema_version: 2,
+        media_type: (manifests.len() > 1).then(|| INDEX_MEDIA_TYPE.to_string()),
+        manifests,
+    };
+    fs::write(
+        output_dir.join("index.json"),
+        serde_json::to_string_pretty(&index)?,
+    )?;
+
+    fs::write(
+        output_dir.join("oci-layout"),
+        r#"{"imageLayoutVersion": "1.0.0"}"#,
+    )?;
+    Ok(())
+}
@@ -1,10 +1,18 @@
-use crate::export::manifest::OCIManifest;
+use crate::export::manifest::{OCIIndex, OCIManifest};
+use crate::export::oci_exporter::{INDEX_MEDIA_TYPE, MANIFEST_MEDIA_TYPE};
 use anyhow::{Context, Result};
 use reqwest::blocking::Client;
 
 use std::fs;
 use std::path::Path;
 
+/// Media types accepted when resolving a tag, so multi-arch indexes resolve
+/// to the index digest rather than one platform's manifest.
+const MANIFEST_ACCEPT: &str = "application/vnd.oci.image.index.v1+json, \
+application/vnd.docker.distribution.manifest.list.v2+json, \
+application/vnd.oci.image.manifest.v1+json, \
+application/vnd.docker.distribution.manifest.v2+json";
+
 pub struct RegistryClient {
     client: Client,
     base_url: String, // e.g., https://index.docker.io/v2
@@ -32,48 +40,215 @@ impl RegistryClient {
         self.token = Some(token.to_string());
     }
 
-    /// Push an OCI layout directory to the registry
+    /// Push an OCI layout directory to the registry. A layout with several
+    /// manifests is pushed as a multi-platform image: every manifest by
+    /// digest, then the index under the tag.
     pub fn push(&self, layout_dir: &Path) -> Result<()> {
         println!("🚀 Pushing image to {}/{}...", self.base_url, self.repo);
 
-        // 1. Read index.json to find the manifest
+        // 1. Read index.json to find the manifests
         let index_path = layout_dir.join("index.json");
         let index_content = fs::read_to_string(&index_path)?;
-        let index: serde_json::Value = serde_json::from_str(&index_content)?;
-        let manifest_descriptor = &index["manifests"][0];
-        let manifest_digest = manifest_descriptor["digest"]
-            .as_str()
+        let index: OCIIndex = serde_json::from_str(&index_content)?;
+        let first = index
+            .manifests
+            .first()
             .context("No manifest found in index.json")?;
 
-        // 2. Read the manifest to find layers
-        let manifest_path = layout_dir
-            .join("blobs")
-            .join("sha256")
-            .join(&manifest_digest[7..]);
-        let manifest_content = fs::read_to_string(&manifest_path)?;
+        if index.manifests.len() == 1 {
+            let content = self.push_image(layout_dir, &first.digest)?;
+            self.upload_manifest("latest", MANIFEST_MEDIA_TYPE, &content)?;
+        } else {
+            for descriptor in &index.manifests {
+                let content = self.push_image(layout_dir, &descriptor.digest)?;
+                self.upload_manifest(&descriptor.digest, MANIFEST_MEDIA_TYPE, &content)?;
+            }
+            // Pushed as written, so its digest matches the layout's
+            self.upload_manifest("latest", INDEX_MEDIA_TYPE, &index_content)?;
+        }
+
+        println!("✅ Image pushed successfully!");
+        Ok(())
+    }
+
+    /// Upload the layers and config of the manifest `manifest_digest`,
+    /// returning the manifest for the caller to upload.
+    fn push_image(&self, layout_dir: &Path, manifest_digest: &str) -> Result<String> {
+        let blobs = layout_dir.join("blobs").join("sha256");
+        let manifest_content = fs::read_to_string(blobs.join(&manifest_digest[7..]))?;
         let manifest: OCIManifest = serde_json::from_str(&manifest_content)?;
 
-        // 3. Push layers
         for layer in &manifest.layers {
-            let layer_path = layout_dir
-                .join("blobs")
-                .join("sha256")
-                .join(&layer.digest[7..]);
-            self.upload_blob(&layer.digest, &layer_path)?;
+            self.upload_blob(&layer.digest, &blobs.join(&layer.digest[7..]))?;
         }
+        self.upload_blob(
+            &manifest.config.digest,
+            &blobs.join(&manifest.config.digest[7..]),
+        )?;
+        Ok(manifest_content)
+    }
 
-        // 4. Push config
-        let config_path = layout_dir
-            .join("blobs")
-            .join("sha256")
-            .join(&manifest.config.digest[7..]);
-        self.upload_blob(&manifest.config.digest, &config_path)?;
+    /// Resolve a tag (or digest) to the registry's manifest digest.
+    pub fn resolve_digest(&self, reference: &str) -> Result<String> {
+        let url = format!("{}/{}/manifests/{}", self.base_url, self.repo, reference);
+        let resp = self.send_authorized(reqwest::Method::HEAD, &url, MANIFEST_ACCEPT)?;
+        if !resp.status().is_success() {
+            anyhow::bail!(
+                "Failed to resolve {}:{}: {}",
+                self.repo,
+                reference,
+                resp.status()
+            );
+        }
 
-        // 5. Push manifest
-        self.upload_manifest(manifest_digest, &manifest_content)?;
+        resp.headers()
+            .get("Docker-Content-Digest")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+            .context("Registry response has no Docker-Content-Digest header")
+    }
 
-        println!("✅ Image pushed successfully!");
-        Ok(())
+    /// Read the `created` timestamp (unix seconds) from the image config.
+    /// For multi-arch indexes the manifest matching the host platform is used.
+    pub fn image_created(&self, digest: &str) -> Result<Option<i64>> {
+        let manifest = self.platform_manifest(digest)?;
+        let config_digest = manifest["config"]["digest"]
+            .as_str()
+            .context("Manifest has no config descriptor")?;
+        let config = self.fetch_json(&format!(
+            "{}/{}/blobs/{}",
+            self.base_url, self.repo, config_digest
+        ))?;
+
+        Ok(config["created"]
+            .as_str()
+            .and_then(|c| chrono::DateTime::parse_from_rfc3339(c).ok())
+            .map(|c| c.timestamp()))
+    }
+
+    /// Digests of the layers of the image behind `reference`, lowest first,
+    /// from the manifest matching the host platform.
+    pub fn layer_digests(&self, reference: &str) -> Result<Vec<String>> {
+        let manifest = self.platform_manifest(reference)?;
+        manifest["layers"]
+            .as_array()
+            .context("Manifest has no layers")?
+            .iter()
+            .map(|layer| {
+                layer["digest"]
+                    .as_str()
+                    .map(str::to_string)
+                    .context("Layer descriptor has no digest")
+            })
+            .collect()
+    }
+
+    /// Start downloading a blob; the response is read as it arrives.
+    pub fn open_blob(&self, digest: &str) -> Result<reqwest::blocking::Response> {
+        let url = format!("{}/{}/blobs/{}", self.base_url, self.repo, digest);
+        let resp = self.send_authorized(reqwest::Method::GET, &url, "*/*")?;
+        if !resp.status().is_success() {
+            anyhow::bail!("Failed to download blob {}: {}", digest, resp.status());
+        }
+        Ok(resp)
+    }
+
+    /// Manifest behind `reference`; for a multi-arch index, the one matching
+    /// the host platform (or the first).
+    fn platform_manifest(&self, reference: &str) -> Result<serde_json::Value> {
+        let mut manifest = self.fetch_json(&format!(
+            "{}/{}/manifests/{}",
+            self.base_url, self.repo, reference
+        ))?;
+
+        if let Some(entries) = manifest["manifests"].as_array() {
+            let arch = match std::env::consts::ARCH {
+                "x86_64" => "amd64",
+                "aarch64" => "arm64",
+                other => other,
+            };
+            let chosen = entries
+                .iter()
+                .find(|m| m["platform"]["os"] == "linux" && m["platform"]["architecture"] == arch)
+                .or_else(|| entries.first())
+                .and_then(|m| m["digest"].as_str())
+                .context("Image index has no manifests")?
+                .to_string();
+            manifest = self.fetch_json(&format!(
+                "{}/{}/manifests/{}",
+                self.base_url, self.repo, chosen
+            ))?;
+        }
+        Ok(manifest)
+    }
+
+    fn fetch_json(&self, url: &str) -> Result<serde_json::Value> {
+        let resp = self.send_authorized(reqwest::Method::GET, url, MANIFEST_ACCEPT)?;
+        if !resp.status().is_success() {
+            anyhow::bail!("Failed to fetch {}: {}", url, resp.status());
+        }
+        Ok(resp.json()?)
+    }
+
+    /// Send a request, requesting an anonymous bearer token on demand when the
+    /// registry answers with a `WWW-Authenticate` challenge (e.g. Docker Hub).
+    fn send_authorized(
+        &self,
+        method: reqwest::Method,
+        url: &str,
+        accept: &str,
+    ) -> Result<reqwest::blocking::Response> {
+        let send = |token: Option<&str>| {
+            let mut rb = self
+                .client
+                .request(method.clone(), url)
+                .header("Accept", accept);
+            if let Some(t) = token {
+                rb = rb.bearer_auth(t);
+            }
+            rb.send()
+        };
+
+        let resp = send(self.token.as_deref())?;
+        if resp.status() != reqwest::StatusCode::UNAUTHORIZED || self.token.is_some() {
+            return Ok(resp);
+        }
+
+        let challenge = resp
+            .headers()
+            .get("WWW-Authenticate")
+            .and_then(|v| v.to_str().ok())
+            .context("Registry requires auth but sent no WWW-Authenticate challenge")?
+            .to_string();
+        let token = self.anonymous_token(&challenge)?;
+        Ok(send(Some(&token))?)
+    }
+
+    fn anonymous_token(&self, challenge: &str) -> Result<String> {
+        let params: std::collections::HashMap<String, String> =
+            regex::Regex::new(r#"(\w+)="([^"]*)""#)?
+                .captures_iter(challenge)
+                .map(|c| (c[1].to_string(), c[2].to_string()))
+                .collect();
+        let realm = params.get("realm").context("Auth challenge has no realm")?;
+
+        let mut query = Vec::new();
+        for key in ["service", "scope"] {
+            if let Some(value) = params.get(key) {
+                query.push((key, value.as_str()));
+            }
+        }
+
+        let resp = self.client.get(realm).query(&query).send()?;
+        if !resp.status().is_success() {
+            anyhow::bail!("Failed to obtain registry token: {}", resp.status());
+        }
+        let body: serde_json::Value = resp.json()?;
+        body["token"]
+            .as_str()
+            .or_else(|| body["access_token"].as_str())
+            .map(str::to_string)
+            .context("Token response has no token")
     }
 
     /// Pull an image from the registry into an OCI layout directory
@@ -126,10 +301,12 @@ impl RegistryClient {
         // 4. Create index.json
         let index = crate::export::manifest::OCIIndex {
             schema_version: 2,
+            media_type: None,
             manifests: vec![crate::export::manifest::OCIDescriptor {
                 media_type: "application/vnd.oci.image.manifest.v1+json".to_string(),
                 digest: manifest_digest,
                 size: manifest_content.len() as u64,
+                platform: None,
             }],
         };
         fs::write(
@@ -227,14 +404,14 @@ impl RegistryClient {
         Ok(resp.status().is_success())
     }
 
-    fn upload_manifest(&self, digest: &str, content: &str) -> Result<()> {
-        println!("   📜 Uploading manifest: {}...", status_hash(digest));
-        let url = format!("{}/{}/manifests/latest", self.base_url, self.repo); // Using 'latest' tag
+    fn upload_manifest(&self, reference: &str, media_type: &str, content: &str) -> Result<()> {
+        println!("   📜 Uploading manifest: {}...", status_hash(reference));
+        let url = format!("{}/{}/manifests/{}", self.base_url, self.repo, reference);
 
         let mut rb = self
             .client
             .put(&url)
-            .header("Content-Type", "application/vnd.oci.image.manifest.v1+json")
+            .header("Content-Type", media_type)
             .body(content.to_string());
 
         if let Some(ref t) = self.token {
@@ -0,0 +1,579 @@
+//! Software bill of materials for exported images, derived from the build
+//! graph as SPDX 2.3 or CycloneDX 1.5 JSON.
+
+use crate::graph::{BuildGraph, NodeKind};
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbomFormat {
+    Spdx,
+    CycloneDx,
+}
+
+impl SbomFormat {
+    /// File the SBOM is written to, next to the image layout
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            SbomFormat::Spdx => "sbom.spdx.json",
+            SbomFormat::CycloneDx => "sbom.cdx.json",
+        }
+    }
+}
+
+impl std::str::FromStr for SbomFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "spdx" => Ok(SbomFormat::Spdx),
+            "cyclonedx" | "cdx" => Ok(SbomFormat::CycloneDx),
+            other => anyhow::bail!(
+                "Unknown SBOM format '{}' (expected spdx or cyclonedx)",
+                other
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for SbomFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SbomFormat::Spdx => write!(f, "spdx"),
+            SbomFormat::CycloneDx => write!(f, "cyclonedx"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ComponentKind {
+    /// Image named by a FROM instruction
+    BaseImage,
+    /// Package installed by a package manager in a RUN step
+    Package,
+    /// Repository fetched by a GIT instruction
+    Source,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Component {
+    pub kind: ComponentKind,
+    pub name: String,
+    /// Version as written in the Dockerfile, if pinned there
+    pub version: Option<String>,
+    pub purl: Option<String>,
+    /// Manifest digest for base images, commit for GIT sources
+    pub digest: Option<String>,
+    /// Where the component can be fetched from
+    pub location: Option<String>,
+    /// Node that brought the component in
+    pub node_id: usize,
+}
+
+/// How a package manager is invoked and how it spells versions.
+struct PackageManager {
+    programs: &'static [&'static str],
+    install: &'static [&'static str],
+    purl_type: &'static str,
+    /// Separator between name and pinned version, e.g. `==` for pip
+    version_sep: Option<&'static str>,
+}
+
+const PACKAGE_MANAGERS: &[PackageManager] = &[
+    PackageManager {
+        programs: &["apt-get", "apt"],
+        install: &["install"],
+        purl_type: "deb",
+        version_sep: Some("="),
+    },
+    PackageManager {
+        programs: &["apk"],
+        install: &["add"],
+        purl_type: "apk",
+        version_sep: Some("="),
+    },
+    PackageManager {
+        programs: &["yum", "dnf", "microdnf"],
+        install: &["install"],
+        purl_type: "rpm",
+        version_sep: None,
+    },
+    PackageManager {
+        programs: &["pip", "pip3"],
+        install: &["install"],
+        purl_type: "pypi",
+        version_sep: Some("=="),
+    },
+    PackageManager {
+        programs: &["npm", "yarn", "pnpm"],
+        install: &["install", "i", "add"],
+        purl_type: "npm",
+        version_sep: Some("@"),
+    },
+    PackageManager {
+        programs: &["cargo"],
+        install: &["install"],
+        purl_type: "cargo",
+        version_sep: Some("@"),
+    },
+    PackageManager {
+        programs: &["gem"],
+        install: &["install"],
+        purl_type: "gem",
+        version_sep: Some(":"),
+    },
+    PackageManager {
+        programs: &["go"],
+        install: &["install", "get"],
+        purl_type: "golang",
+        version_sep: Some("@"),
+    },
+];
+
+/// Flags whose value is a separate argument rather than a package
+const VALUE_FLAGS: &[&str] = &[
+    "-r",
+    "--requirement",
+    "-c",
+    "--constraint",
+    "-t",
+    "--target",
+    "-i",
+    "--index-url",
+    "--extra-index-url",
+    "--prefix",
+    "--root",
+    "-v",
+    "--version",
+    "--registry",
+    "--repository",
+    "-o",
+];
+
+/// Files passed instead of package names
+const FILE_SUFFIXES: &[&str] = &[
+    ".txt", ".whl", ".tar.gz", ".tgz", ".deb", ".rpm", ".apk", ".gem",
+];
+
+/// A package named in a package-manager invocation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstalledPackage {
+    pub purl_type: &'static str,
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl InstalledPackage {
+    pub fn purl(&self) -> String {
+        // Scoped npm packages keep their '@' escaped
+        let name = self.name.replace('@', "%40");
+        match self.version {
+            Some(ref v) => format!("pkg:{}/{}@{}", self.purl_type, name, v),
+            None => format!("pkg:{}/{}", self.purl_type, name),
+        }
+    }
+}
+
+/// Packages named in install commands of a shell command line, e.g.
+/// `apt-get install -y curl=7.88.1-10` → `curl` at `7.88.1-10`. Installs from
+/// lock or requirements files name no packages and are not listed.
+pub fn installed_packages(command: &str) -> Vec<InstalledPackage> {
+    let mut packages = Vec::new();
+    for segment in command.split(['&', ';', '|', '\n']) {
+        let tokens: Vec<&str> = segment
+            .split_whitespace()
+            .map(|t| t.trim_matches(|c| c == '"' || c == '\''))
+            .collect();
+        let Some((manager, args)) = find_install(&tokens) else {
+            continue;
+        };
+
+        let mut skip_value = false;
+        for arg in args {
+            if skip_value {
+                skip_value = false;
+                continue;
+            }
+            if arg.starts_with('-') {
+                skip_value = VALUE_FLAGS.contains(arg);
+                continue;
+            }
+            if arg.is_empty()
+                || arg.contains('$')
+                || arg.contains("://")
+                || arg.starts_with(['.', '/', '~'])
+                || FILE_SUFFIXES.iter().any(|s| arg.ends_with(s))
+            {
+                continue;
+            }
+            packages.push(split_version(manager, arg));
+        }
+    }
+    packages
+}
+
+/// The package manager a command runs and the arguments after its install verb.
+fn find_install<'a>(tokens: &'a [&'a str]) -> Option<(&'static PackageManager, &'a [&'a str])> {
+    for (i, token) in tokens.iter().enumerate() {
+        let program = token.rsplit('/').next().unwrap_or(token);
+        let Some(manager) = PACKAGE_MANAGERS
+            .iter()
+            .find(|m| m.programs.contains(&program))
+        else {
+            continue;
+        };
+        // Global flags may come before the verb: `apt-get -y install`
+        let rest = &tokens[i + 1..];
+        let verb = rest.iter().position(|t| !t.starts_with('-'))?;
+        if manager.install.contains(&rest[verb]) {
+            return Some((manager, &rest[verb + 1..]));
+        }
+        return None;
+    }
+    None
+}
+
+fn split_version(manager: &PackageManager, arg: &str) -> InstalledPackage {
+    let (name, version) = match manager.version_sep {
+        // The leading '@' of a scoped npm package is part of its name
+        Some("@") => match arg.rfind('@').filter(|&i| i > 0) {
+            Some(i) => (&arg[..i], Some(&arg[i + 1..])),
+            None => (arg, None),
+        },
+        Some(sep) => match arg.split_once(sep) {
+            Some((name, version)) => (name, Some(version)),
+            None => (arg, None),
+        },
+        None => (arg, None),
+    };
+    // Ranges such as `requests>=2` pin nothing
+    let name = name
+        .split(['<', '>', '=', '!', '~', '['])
+        .next()
+        .unwrap_or(name);
+    InstalledPackage {
+        purl_type: manager.purl_type,
+        name: name.to_string(),
+        version: version.filter(|v| !v.is_empty()).map(str::to_string),
+    }
+}
+
+/// Everything the image was built from, in graph order. Stage references in
+/// FROM (`aliases`) and `scratch` are not components. `git_revision` resolves
+/// a GIT repository to the commit it points at.
+pub fn collect_components(
+    graph: &BuildGraph,
+    aliases: &HashSet<String>,
+    git_revision: &dyn Fn(&str) -> Option<String>,
+) -> Vec<Component> {
+    let mut components = Vec::new();
+    for node in &graph.nodes {
+        match &node.kind {
+            NodeKind::From => {
+                let Some(image) = node.content.strip_prefix("FROM ") else {
+                    continue;
+                };
+                let image = image.trim();
+                if image == "scratch"
+                    || aliases.contains(image)
+                    || crate::docker::resolve::is_stage_ref(node)
+                {
+                    continue;
+                }
+                let parsed = crate::docker::resolve::ImageRef::parse(image);
+                let digest = parsed
+                    .digest
+                    .clone()
+                    .or_else(|| node.metadata.base_image_digest.clone());
+                let name = parsed.repository.rsplit('/').next().unwrap_or(image);
+                let mut purl = format!("pkg:oci/{}", name);
+                if let Some(ref digest) = digest {
+                    purl.push_str(&format!("@{}", digest.replace(':', "%3A")));
+                }
+                purl.push_str(&format!(
+                    "?repository_url={}/{}",
+                    parsed.registry, parsed.repository
+                ));
+                if let Some(ref tag) = parsed.tag {
+                    purl.push_str(&format!("&tag={}", tag));
+                }
+                components.push(Component {
+                    kind: ComponentKind::BaseImage,
+                    name: format!("{}/{}", parsed.registry, parsed.repository),
+                    version: parsed.tag.clone(),
+                    purl: Some(purl),
+                    digest,
+                    location: Some(image.to_string()),
+                    node_id: node.id,
+                });
+            }
+            NodeKind::Run | NodeKind::RunExtend { .. } => {
+                let command = node.content.strip_prefix("RUN ").unwrap_or(&node.content);
+                for package in installed_packages(command) {
+                    components.push(Component {
+                        kind: ComponentKind::Package,
+                        purl: Some(package.purl()),
+                        name: package.name,
+                        version: package.version,
+                        digest: None,
+                        location: None,
+                        node_id: node.id,
+                    });
+                }
+            }
+            NodeKind::Git { url, .. } => {
+                let revision = git_revision(url);
+                components.push(Component {
+                    kind: ComponentKind::Source,
+                    name: url.clone(),
+                    version: revision.clone(),
+                    purl: None,
+                    digest: revision,
+                    location: Some(url.clone()),
+                    node_id: node.id,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    // The same package installed twice is listed once
+    let mut seen = HashSet::new();
+    components.retain(|c| seen.insert((c.kind, c.name.clone(), c.version.clone())));
+    components
+}
+
+/// Render `components` of `image_name` as an SBOM document. `created` is an
+/// RFC 3339 timestamp; everything else is derived from the inputs, so equal
+/// inputs give byte-identical documents.
+pub fn render(
+    format: SbomFormat,
+    image_name: &str,
+    components: &[Component],
+    created: &str,
+) -> Result<String> {
+    let id = document_id(image_name, components);
+    let tool = format!("memobuild-{}", env!("CARGO_PKG_VERSION"));
+    let doc = match format {
+        SbomFormat::Spdx => {
+            let mut packages = vec![json!({
+                "name": image_name,
+                "SPDXID": "SPDXRef-Image",
+                "downloadLocation": "NOASSERTION",
+                "filesAnalyzed": false,
+                "primaryPackagePurpose": "CONTAINER",
+            })];
+            let mut relationships = vec![json!({
+                "spdxElementId": "SPDXRef-DOCUMENT",
+                "relationshipType": "DESCRIBES",
+                "relatedSpdxElement": "SPDXRef-Image",
+            })];
+            for (i, c) in components.iter().enumerate() {
+                let spdx_id = format!("SPDXRef-Component-{}", i);
+                let mut package = json!({
+                    "name": c.name,
+                    "SPDXID": spdx_id,
+                    "versionInfo": c.version.as_deref().unwrap_or("NOASSERTION"),
+                    "downloadLocation": c.location.as_deref().unwrap_or("NOASSERTION"),
+                    "filesAnalyzed": false,
+                    "primaryPackagePurpose": match c.kind {
+                        ComponentKind::BaseImage => "CONTAINER",
+                        ComponentKind::Package => "LIBRARY",
+                        ComponentKind::Source => "SOURCE",
+                    },
+                });
+                if let Some(ref purl) = c.purl {
+                    package["externalRefs"] = json!([{
+                        "referenceCategory": "PACKAGE-MANAGER",
+                        "referenceType": "purl",
+                        "referenceLocator": purl,
+                    }]);
+                }
+                if let Some(hex) = c.digest.as_deref().and_then(|d| d.strip_prefix("sha256:")) {
+                    package["checksums"] = json!([{ "algorithm": "SHA256", "checksumValue": hex }]);
+                }
+                packages.push(package);
+                relationships.push(json!({
+                    "spdxElementId": "SPDXRef-Image",
+                    "relationshipType": match c.kind {
+                        ComponentKind::BaseImage => "DESCENDANT_OF",
+                        _ => "CONTAINS",
+                    },
+                    "relatedSpdxElement": spdx_id,
+                }));
+            }
+            json!({
+                "spdxVersion": "SPDX-2.3",
+                "dataLicense": "CC0-1.0",
+                "SPDXID": "SPDXRef-DOCUMENT",
+                "name": image_name,
+                "documentNamespace": format!("https://memobuild.dev/spdx/{}", id),
+                "creationInfo": {
+                    "created": created,
+                    "creators": [format!("Tool: {}", tool)],
+                },
+                "packages": packages,
+                "relationships": relationships,
+            })
+        }
+        SbomFormat::CycloneDx => {
+            let entries: Vec<_> = components
+                .iter()
+                .enumerate()
+                .map(|(i, c)| {
+                    let mut entry = json!({
+                        "bom-ref": format!("component-{}", i),
+                        "type": match c.kind {
+                            ComponentKind::BaseImage => "container",
+                            ComponentKind::Package => "library",
+                            ComponentKind::Source => "application",
+                        },
+                        "name": c.name,
+                    });
+                    if let Some(ref version) = c.version {
+                        entry["version"] = json!(version);
+                    }
+                    if let Some(ref purl) = c.purl {
+                        entry["purl"] = json!(purl);
+                    }
+                    if let Some(hex) = c.digest.as_deref().and_then(|d| d.strip_prefix("sha256:")) {
+                        entry["hashes"] = json!([{ "alg": "SHA-256", "content": hex }]);
+                    }
+                    if let Some(ref location) = c.location {
+                        entry["externalReferences"] = json!([{
+                            "type": match c.kind {
+                                ComponentKind::Source => "vcs",
+                                _ => "distribution",
+                            },
+                            "url": location,
+                        }]);
+                    }
+                    entry
+                })
+                .collect();
+            json!({
+                "bomFormat": "CycloneDX",
+                "specVersion": "1.5",
+                "serialNumber": format!("urn:uuid:{}", id),
+                "version": 1,
+                "metadata": {
+                    "timestamp": created,
+                    "tools": [{
+                        "vendor": "MemoBuild",
+                        "name": "memobuild",
+                        "version": env!("CARGO_PKG_VERSION"),
+                    }],
+                    "component": {
+                        "type": "container",
+                        "name": image_name,
+                        "bom-ref": "image",
+                    },
+                },
+                "components": entries,
+                "dependencies": [{
+                    "ref": "image",
+                    "dependsOn": (0..components.len())
+                        .map(|i| format!("component-{}", i))
+                        .collect::<Vec<_>>(),
+                }],
+            })
+        }
+    };
+    Ok(serde_json::to_string_pretty(&doc)?)
+}
+
+/// UUID-shaped digest of the document's inputs.
+fn document_id(image_name: &str, components: &[Component]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(image_name.as_bytes());
+    for c in components {
+        hasher.update(serde_json::to_string(c).unwrap_or_default().as_bytes());
+    }
+    let hex = hasher.finalize().to_hex();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::dag::build_graph_from_instructions;
+    use crate::docker::parser::parse_dockerfile;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_collect_components() {
+        let dockerfile = "FROM node:18 AS build\n\
+            RUN apt-get update && apt-get install -y --no-install-recommends curl=7.88.1-10 git\n\
+            RUN npm install -g @angular/cli@17.0.1 typescript\n\
+            RUN pip install -r requirements.txt requests==2.31.0\n\
+            GIT https://github.com/org/lib.git /src/lib\n\
+            FROM build\n";
+        let mut graph =
+            build_graph_from_instructions(parse_dockerfile(dockerfile), PathBuf::from("."));
+        graph.nodes[0].metadata.base_image_digest = Some("sha256:abc".into());
+        let aliases = crate::docker::resolve::stage_aliases(dockerfile);
+
+        let components = collect_components(&graph, &aliases, &|_| Some("deadbeef".into()));
+        let purls: Vec<_> = components
+            .iter()
+            .filter_map(|c| c.purl.as_deref())
+            .collect();
+        assert_eq!(
+            purls,
+            vec![
+                "pkg:oci/node@sha256%3Aabc?repository_url=docker.io/library/node&tag=18",
+                "pkg:deb/curl@7.88.1-10",
+                "pkg:deb/git",
+                "pkg:npm/%40angular/cli@17.0.1",
+                "pkg:npm/typescript",
+                "pkg:pypi/requests@2.31.0",
+            ]
+        );
+        let git = components.last().unwrap();
+        assert_eq!(git.kind, ComponentKind::Source);
+        assert_eq!(git.digest.as_deref(), Some("deadbeef"));
+
+        let spdx = render(
+            SbomFormat::Spdx,
+            "app:latest",
+            &components,
+            "1970-01-01T00:00:00Z",
+        )
+        .unwrap();
+        let doc: serde_json::Value = serde_json::from_str(&spdx).unwrap();
+        assert_eq!(
+            doc["packages"].as_array().unwrap().len(),
+            components.len() + 1
+        );
+        assert_eq!(
+            spdx,
+            render(
+                SbomFormat::Spdx,
+                "app:latest",
+                &components,
+                "1970-01-01T00:00:00Z"
+            )
+            .unwrap()
+        );
+
+        let cdx = render(
+            SbomFormat::CycloneDx,
+            "app:latest",
+            &components,
+            "1970-01-01T00:00:00Z",
+        )
+        .unwrap();
+        let doc: serde_json::Value = serde_json::from_str(&cdx).unwrap();
+        assert_eq!(doc["components"][0]["hashes"][0]["content"], "abc");
+    }
+}
@@ -0,0 +1,222 @@
+//! Post-build smoke test: the image's HEALTHCHECK run in a container of the
+//! exported image.
+
+use crate::docker::healthcheck::Healthcheck;
+use crate::report::SmokeTestReport;
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Outcome of one run of the check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Probe {
+    pub passed: bool,
+    pub output: String,
+}
+
+/// Run `probe` the way Docker evaluates a healthcheck: every `interval`,
+/// healthy on the first pass, unhealthy after `retries` consecutive
+/// failures once the start period is over. `wait` is called between probes.
+pub fn evaluate(
+    check: &Healthcheck,
+    mut probe: impl FnMut() -> Result<Probe>,
+    mut wait: impl FnMut(Duration),
+) -> (bool, u32, Probe) {
+    let mut attempts = 0;
+    let mut failures = 0;
+    let mut elapsed = Duration::ZERO;
+    loop {
+        wait(check.interval);
+        elapsed += check.interval;
+        attempts += 1;
+        let result = probe().unwrap_or_else(|e| Probe {
+            passed: false,
+            output: format!("{:#}", e),
+        });
+        if result.passed {
+            return (true, attempts, result);
+        }
+        if elapsed > check.start_period {
+            failures += 1;
+        }
+        if failures >= check.retries.max(1) {
+            return (false, attempts, result);
+        }
+    }
+}
+
+/// Load the OCI layout in `output_dir` into Docker, start it and run its
+/// healthcheck. Failing to start is reported as a failed smoke test, not
+/// as an error.
+pub fn run(output_dir: &Path, check: &Healthcheck) -> Result<SmokeTestReport> {
+    let started = Instant::now();
+    let image = load_image(output_dir)?;
+    let mut report = SmokeTestReport {
+        image: image.clone(),
+        command: check.test.clone(),
+        passed: false,
+        attempts: 0,
+        duration_ms: 0,
+        output: String::new(),
+        error: None,
+    };
+
+    match start_container(&image) {
+        Ok(container) => {
+            let (passed, attempts, last) =
+                evaluate(check, || exec_probe(&container, check), std::thread::sleep);
+            let _ = docker()
+                .args(["rm", "-f", &container])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+            report.passed = passed;
+            report.attempts = attempts;
+            report.output = last.output;
+            if !passed {
+                report.error = Some(format!(
+                    "HEALTHCHECK failed {} time(s) in a row",
+                    check.retries.max(1)
+                ));
+            }
+        }
+        Err(e) => report.error = Some(format!("{:#}", e)),
+    }
+    report.duration_ms = started.elapsed().as_millis() as u64;
+    Ok(report)
+}
+
+fn docker() -> Command {
+    Command::new("docker")
+}
+
+/// `docker load` the layout and return the image reference it reports.
+fn load_image(output_dir: &Path) -> Result<String> {
+    let mut archive = tar::Builder::new(Vec::new());
+    archive
+        .append_dir_all(".", output_dir)
+        .with_context(|| format!("Failed to archive {}", output_dir.display()))?;
+    let archive = archive.into_inner()?;
+
+    let mut child = docker()
+        .arg("load")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run docker; the smoke test needs a Docker daemon")?;
+    child
+        .stdin
+        .take()
+        .context("docker load has no stdin")?
+        .write_all(&archive)?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "docker load failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    parse_loaded(&String::from_utf8_lossy(&output.stdout))
+        .context("docker load did not report the loaded image")
+}
+
+/// Image reference from `docker load` output: `Loaded image: <ref>` or
+/// `Loaded image ID: <id>`.
+fn parse_loaded(stdout: &str) -> Option<String> {
+    stdout.lines().rev().find_map(|line| {
+        line.strip_prefix("Loaded image ID: ")
+            .or_else(|| line.strip_prefix("Loaded image: "))
+            .map(|r| r.trim().to_string())
+    })
+}
+
+fn start_container(image: &str) -> Result<String> {
+    let output = docker()
+        .args(["run", "-d", "--label", "memobuild.smoke-test=1", image])
+        .output()
+        .context("Failed to run docker")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Container did not start: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Run the check once inside `container`, killing it after the timeout.
+fn exec_probe(container: &str, check: &Healthcheck) -> Result<Probe> {
+    let mut child = docker()
+        .args(["exec", container])
+        .args(&check.test)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let deadline = Instant::now() + check.timeout;
+    while child.try_wait()?.is_none() {
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(Probe {
+                passed: false,
+                output: format!("Timed out after {:?}", check.timeout),
+            });
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let output = child.wait_with_output()?;
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok(Probe {
+        passed: output.status.success(),
+        output: text.trim().to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate_healthcheck() {
+        let check = Healthcheck {
+            test: vec!["true".into()],
+            interval: Duration::from_secs(10),
+            start_period: Duration::from_secs(20),
+            retries: 2,
+            ..Default::default()
+        };
+        let probe = |results: Vec<bool>| {
+            let mut results = results.into_iter();
+            move || {
+                let passed = results.next().unwrap_or(false);
+                Ok::<_, anyhow::Error>(Probe {
+                    passed,
+                    output: String::new(),
+                })
+            }
+        };
+        let mut waited = Duration::ZERO;
+
+        // Passes on the third probe
+        let (passed, attempts, _) =
+            evaluate(&check, probe(vec![false, false, true]), |d| waited += d);
+        assert!(passed);
+        assert_eq!(attempts, 3);
+        assert_eq!(waited, Duration::from_secs(30));
+
+        // Failures in the start period do not count towards the retries
+        let (passed, attempts, _) = evaluate(&check, probe(vec![]), |_| {});
+        assert!(!passed);
+        assert_eq!(attempts, 4);
+
+        assert_eq!(
+            parse_loaded("Loading layer 1/1\nLoaded image ID: sha256:abc\n"),
+            Some("sha256:abc".to_string())
+        );
+        assert_eq!(parse_loaded("nothing"), None);
+    }
+}
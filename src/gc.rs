@@ -8,9 +8,15 @@
 //!   `MEMOBUILD_GC_INTERVAL_HOURS` — schedule interval (default: 6)
 //!   `MEMOBUILD_GC_MAX_AGE_DAYS` — max age before eviction (default: 30)
 //!   `MEMOBUILD_GC_MAX_SIZE_BYTES` — LRU eviction target (default: 0 = unlimited)
+//!
+//! A sweep first [plans](plan) what to delete, each candidate with the
+//! reason it goes and the bytes it frees, then deletes exactly that plan; a
+//! dry run stops after planning.
 
+use crate::server::metadata::{EntryDetails, EntryFilter, MetadataBackend};
 use anyhow::Result;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::sync::RwLock;
@@ -101,10 +107,15 @@ impl GarbageCollector {
         })
     }
 
-    /// Execute a single GC sweep against a `MetadataStore` and `ArtifactStorage`.
+    /// What a sweep would delete under this collector's policy.
+    pub fn plan(&self, metadata: &dyn MetadataBackend) -> Result<GcPlan> {
+        plan(metadata, &self.policy)
+    }
+
+    /// Execute a single GC sweep against a `MetadataBackend` and `ArtifactStorage`.
     pub async fn sweep(
         &self,
-        metadata: &crate::server::metadata::MetadataStore,
+        metadata: &dyn MetadataBackend,
         storage: &dyn crate::storage::ArtifactStorage,
     ) -> Result<GcRunResult> {
         if self.running.load(Ordering::SeqCst) {
@@ -114,33 +125,16 @@ impl GarbageCollector {
         self.running.store(true, Ordering::SeqCst);
 
         let start = std::time::Instant::now();
-        let mut deleted_artifacts = 0u64;
-        let mut deleted_layers = 0u64;
-        let mut freed_bytes = 0u64;
-
-        // Age-based eviction
-        if self.policy.max_age_days > 0 {
-            if let Ok(old_hashes) = metadata.get_old_entries(self.policy.max_age_days) {
-                for hash in old_hashes {
-                    if let Ok(Some(entry)) = metadata.get(&hash) {
-                        freed_bytes += entry.size;
-                        let _ = storage.delete(&hash);
-                        let _ = metadata.delete(&hash);
-                        deleted_artifacts += 1;
-                    }
-                }
-            }
-
-            // Clean up unused layers
-            if let Ok(unused_layers) = metadata.get_unused_layers() {
-                for (hash, _path) in unused_layers {
-                    freed_bytes += 0; // size tracked via metadata
-                    let _ = storage.delete(&hash);
-                    let _ = metadata.delete_layer_metadata(&hash);
-                    deleted_layers += 1;
-                }
-            }
-        }
+        let planned = plan(metadata, &self.policy).unwrap_or_else(|e| {
+            tracing::warn!("GC planning failed: {}", e);
+            GcPlan::default()
+        });
+        let GcRunResult {
+            deleted_artifacts,
+            deleted_layers,
+            freed_bytes,
+            ..
+        } = apply(&planned, metadata, storage);
 
         let duration = start.elapsed().as_millis() as u64;
         let total = deleted_artifacts + deleted_layers;
@@ -203,6 +197,209 @@ pub struct GcRunResult {
     pub duration_ms: u64,
 }
 
+/// Why GC deletes something.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GcReason {
+    /// Unused for longer than the retention period
+    Age,
+    /// Least recently used while the cache is over its size limit
+    Lru,
+    /// Nothing refers to it any more: a layer no entry uses, or an artifact
+    /// file no local entry points at
+    Unreferenced,
+}
+
+impl std::fmt::Display for GcReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            GcReason::Age => "age",
+            GcReason::Lru => "lru",
+            GcReason::Unreferenced => "unreferenced",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GcCandidate {
+    /// Cache key or layer hash; for a stray local artifact, its file name
+    pub key: String,
+    pub reason: GcReason,
+    /// Bytes deleting it frees
+    pub bytes: u64,
+    /// RFC 3339 time the entry was last used; unknown for layers and files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<String>,
+}
+
+/// What a GC run deletes, or would delete in a dry run.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GcPlan {
+    /// Only reported; nothing was deleted
+    #[serde(default)]
+    pub dry_run: bool,
+    pub candidates: Vec<GcCandidate>,
+    /// Bytes freed by deleting every candidate
+    pub bytes: u64,
+}
+
+impl GcPlan {
+    pub fn push(&mut self, candidate: GcCandidate) {
+        self.bytes += candidate.bytes;
+        self.candidates.push(candidate);
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.candidates.iter().any(|candidate| candidate.key == key)
+    }
+
+    pub fn count(&self, reason: GcReason) -> usize {
+        self.candidates
+            .iter()
+            .filter(|candidate| candidate.reason == reason)
+            .count()
+    }
+
+    /// One line: how many go for each reason and the bytes freed.
+    pub fn summary(&self) -> String {
+        format!(
+            "{} by age, {} by LRU, {} unreferenced, {}",
+            self.count(GcReason::Age),
+            self.count(GcReason::Lru),
+            self.count(GcReason::Unreferenced),
+            crate::plan::format_bytes(self.bytes)
+        )
+    }
+}
+
+/// One line per candidate: reason, size, key and when it was last used.
+impl std::fmt::Display for GcPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for candidate in &self.candidates {
+            write!(
+                f,
+                "  {:<13}{:>10}  {}",
+                candidate.reason.to_string(),
+                crate::plan::format_bytes(candidate.bytes),
+                candidate.key
+            )?;
+            if let Some(ref last_used) = candidate.last_used {
+                write!(f, "  (last used {})", last_used)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a server GC run under `policy` deletes: layers no entry uses,
+/// unpinned entries unused for `max_age_days`, then the least recently used
+/// unpinned entries until the cache fits `max_size_bytes`. Layers the
+/// planned entries were the last users of are planned with them, so the
+/// plan is everything the run frees.
+pub fn plan(metadata: &dyn MetadataBackend, policy: &GcPolicy) -> Result<GcPlan> {
+    let mut plan = GcPlan::default();
+    for (hash, _path) in metadata.get_unused_layers()? {
+        let bytes = metadata.layer_size(&hash)?.unwrap_or(0);
+        plan.push(GcCandidate {
+            key: hash,
+            reason: GcReason::Unreferenced,
+            bytes,
+            last_used: None,
+        });
+    }
+
+    // References to each layer the planned entries drop
+    let mut released = HashMap::new();
+    if policy.max_age_days > 0 {
+        for hash in metadata.get_old_entries(policy.max_age_days)? {
+            if let Some(details) = metadata.entry_details(&hash)? {
+                plan_entry(&mut plan, &mut released, details, GcReason::Age);
+            }
+        }
+    }
+
+    if policy.max_size_bytes > 0 {
+        let stats = metadata.storage_stats(policy.max_age_days)?;
+        let mut used = stats.used_bytes.saturating_sub(plan.bytes);
+        if used > policy.max_size_bytes {
+            let unpinned = EntryFilter {
+                pinned: Some(false),
+                ..Default::default()
+            };
+            let (entries, _) = metadata.list_entries(&unpinned, 0, i64::MAX as u64)?;
+            // Listed most recently used first
+            for entry in entries.iter().rev() {
+                if used <= policy.max_size_bytes {
+                    break;
+                }
+                if plan.contains(&entry.hash) {
+                    continue;
+                }
+                let before = plan.bytes;
+                if let Some(details) = metadata.entry_details(&entry.hash)? {
+                    plan_entry(&mut plan, &mut released, details, GcReason::Lru);
+                }
+                used = used.saturating_sub(plan.bytes - before);
+            }
+        }
+    }
+    Ok(plan)
+}
+
+fn plan_entry(
+    plan: &mut GcPlan,
+    released: &mut HashMap<String, u64>,
+    details: EntryDetails,
+    reason: GcReason,
+) {
+    // A layered entry's bytes are in its layers, freed once unused
+    let bytes = if details.layers.is_empty() {
+        details.summary.size
+    } else {
+        0
+    };
+    plan.push(GcCandidate {
+        key: details.summary.hash,
+        reason,
+        bytes,
+        last_used: Some(details.summary.last_used),
+    });
+    for layer in details.layers {
+        let dropped = released.entry(layer.hash.clone()).or_insert(0);
+        *dropped += 1;
+        if *dropped == layer.ref_count {
+            plan.push(GcCandidate {
+                key: layer.hash,
+                reason: GcReason::Unreferenced,
+                bytes: layer.size,
+                last_used: None,
+            });
+        }
+    }
+}
+
+/// Delete what `plan` lists from the server's metadata and storage.
+pub fn apply(
+    plan: &GcPlan,
+    metadata: &dyn MetadataBackend,
+    storage: &dyn crate::storage::ArtifactStorage,
+) -> GcRunResult {
+    let mut result = GcRunResult::default();
+    for candidate in &plan.candidates {
+        let _ = storage.delete(&candidate.key);
+        if candidate.reason == GcReason::Unreferenced {
+            let _ = metadata.delete_layer_metadata(&candidate.key);
+            result.deleted_layers += 1;
+        } else {
+            let _ = metadata.delete(&candidate.key);
+            result.deleted_artifacts += 1;
+        }
+    }
+    result.freed_bytes = plan.bytes;
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -225,4 +422,45 @@ mod tests {
         assert_eq!(status.total_runs, 0);
         assert!(status.last_run.is_none());
     }
+
+    #[test]
+    fn test_dry_run_plan_matches_sweep() {
+        use crate::server::metadata::MetadataStore;
+        let metadata = MetadataStore::in_memory().unwrap();
+        let dir = tempfile::TempDir::new().unwrap();
+        let storage = crate::storage::local::LocalStorage::new(dir.path()).unwrap();
+        metadata.insert("a", "a", 100).unwrap();
+        metadata.insert("b", "b", 100).unwrap();
+        metadata.insert_layer("l1", "l1", 50).unwrap();
+        metadata
+            .insert_layered_node("c", 50, &["l1".to_string()])
+            .unwrap();
+        metadata.insert_layer("orphan", "orphan", 30).unwrap();
+
+        let mut policy = GcPolicy {
+            max_age_days: 0,
+            max_size_bytes: 1000,
+            interval_secs: 3600,
+        };
+        let idle = plan(&metadata, &policy).unwrap();
+        assert_eq!(idle.candidates.len(), 1);
+        assert_eq!(idle.candidates[0].reason, GcReason::Unreferenced);
+        assert_eq!(idle.bytes, 30);
+
+        policy.max_size_bytes = 150;
+        let over = plan(&metadata, &policy).unwrap();
+        assert!(over.count(GcReason::Lru) >= 1);
+        assert!(280 - over.bytes <= 150);
+        // The layered entry goes with the layer only it used
+        assert_eq!(over.contains("c"), over.contains("l1"));
+
+        // Planning deletes nothing; the sweep frees exactly the plan
+        assert_eq!(metadata.storage_stats(30).unwrap().used_bytes, 280);
+        let result = apply(&over, &metadata, &storage);
+        assert_eq!(result.freed_bytes, over.bytes);
+        assert_eq!(
+            metadata.storage_stats(30).unwrap().used_bytes,
+            280 - over.bytes
+        );
+    }
 }
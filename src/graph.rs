@@ -9,6 +9,18 @@ pub enum NodeKind {
         src: PathBuf,
         dst: PathBuf,
     },
+    /// COPY --from=<image>: a path taken out of an image, not the context
+    CopyFrom {
+        image: String,
+        src: PathBuf,
+        dst: PathBuf,
+    },
+    /// ADD: a local path or a URL, with an optional pinned checksum
+    Add {
+        src: String,
+        dst: PathBuf,
+        checksum: Option<String>,
+    },
     Env,
     Workdir,
     Cmd,
@@ -30,9 +42,50 @@ pub enum NodeKind {
         hook_name: String,
         params: Vec<String>,
     },
+    /// Instruction handled by a WASM plugin, e.g. `TERRAFORM apply`
+    Plugin {
+        instruction: String,
+        args: String,
+    },
+    /// BUILD: a nested build, whose steps are merged into this graph
+    Build {
+        context: PathBuf,
+        dockerfile: PathBuf,
+    },
+    /// A command that is not tied to an image: keyed by the context files
+    /// matching `inputs`, and its artifact is the files matching `outputs`
+    Task {
+        cmd: String,
+        outputs: Vec<String>,
+        inputs: Vec<String>,
+    },
     Other,
 }
 
+impl NodeKind {
+    /// Lower-case name of the variant, e.g. `run_extend`
+    pub fn label(&self) -> &'static str {
+        match self {
+            NodeKind::From => "from",
+            NodeKind::Run => "run",
+            NodeKind::Copy { .. } => "copy",
+            NodeKind::CopyFrom { .. } => "copy_from",
+            NodeKind::Add { .. } => "add",
+            NodeKind::Env => "env",
+            NodeKind::Workdir => "workdir",
+            NodeKind::Cmd => "cmd",
+            NodeKind::Git { .. } => "git",
+            NodeKind::RunExtend { .. } => "run_extend",
+            NodeKind::CopyExtend { .. } => "copy_extend",
+            NodeKind::CustomHook { .. } => "custom_hook",
+            NodeKind::Plugin { .. } => "plugin",
+            NodeKind::Build { .. } => "build",
+            NodeKind::Task { .. } => "task",
+            NodeKind::Other => "other",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Node {
     pub id: usize,
@@ -56,6 +109,9 @@ pub struct NodeMetadata {
     pub last_executed: Option<std::time::SystemTime>,
     /// Execution duration in milliseconds
     pub execution_time_ms: Option<u64>,
+    /// CPU, memory and IO the last execution used
+    #[serde(default)]
+    pub resource_usage: Option<crate::sandbox::usage::ResourceUsage>,
     /// Whether this node can be executed in parallel
     pub parallelizable: bool,
     /// Node priority for execution scheduling
@@ -70,9 +126,207 @@ pub struct NodeMetadata {
     pub output_manifest_hash: Option<String>,
     /// AI-detected extra dependencies (source paths)
     pub extra_source_paths: Vec<std::path::PathBuf>,
+    /// Where the instruction that produced this node lives in the Dockerfile
+    #[serde(default)]
+    pub span: Option<crate::docker::parser::Span>,
+    /// Digest of the INCLUDE fragment(s) this node was expanded from
+    #[serde(default)]
+    pub include_digest: Option<String>,
+    /// Manifest digest a FROM node's tag resolved to
+    #[serde(default)]
+    pub base_image_digest: Option<String>,
+    /// `# memobuild:key=value` directives written above the instruction
+    #[serde(default)]
+    pub directives: std::collections::BTreeMap<String, String>,
+    /// Network access the sandbox grants this node
+    #[serde(default)]
+    pub network: Option<crate::sandbox::network::NetworkPolicy>,
+    /// ENV values in scope at this instruction, injected into its sandbox
+    #[serde(default)]
+    pub build_env: std::collections::BTreeMap<String, String>,
+    /// Deepest source directories that changed since the last build (COPY nodes)
+    #[serde(default)]
+    pub changed_paths: Vec<String>,
+    /// `os/arch` the cache key is partitioned by; `None` for nodes declared
+    /// platform-independent, whose artifacts are shared across platforms
+    #[serde(default)]
+    pub platform: Option<String>,
+    /// Digest of the workspace files a RUN read the last time it was traced
+    #[serde(default)]
+    pub traced_inputs: Option<String>,
+    /// Shell a RUN command is handed to, from SHELL or `MEMOBUILD_SHELL`;
+    /// `None` runs it in the sandbox's default shell
+    #[serde(default)]
+    pub shell: Option<Vec<String>>,
+    /// Identity of the persistent worker a RUN command is handed to
+    #[serde(default)]
+    pub worker: Option<String>,
+    /// Digest of the files named by an `inputs` directive, or by the plugin
+    /// handling the instruction
+    #[serde(default)]
+    pub declared_inputs: Option<String>,
+    /// Digest of the content filters the source was hashed with
+    #[serde(default)]
+    pub content_filters: Option<String>,
+    /// Digest of the WASM module handling a plugin instruction
+    #[serde(default)]
+    pub plugin: Option<String>,
+    /// Context of the nested build the step belongs to, relative to the
+    /// top-level context; its commands run there
+    #[serde(default)]
+    pub nested: Option<String>,
+    /// Why the node is dirty; `None` for clean nodes
+    #[serde(default)]
+    pub dirty_reason: Option<DirtyReason>,
+    /// Stage or group the step is reported under, e.g. the `AS` name of
+    /// its stage; not part of the cache key
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+/// Why a node has to rebuild.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "detail", rename_all = "snake_case")]
+pub enum DirtyReason {
+    /// The node's cache key changed without any input below explaining it:
+    /// its instruction, directives or base image
+    ContentChanged,
+    /// A file the node copies changed; the path, relative to the source
+    InputFileChanged(String),
+    /// A node it depends on is dirty
+    ParentDirty(usize),
+    /// An ENV value in scope changed
+    EnvChanged(String),
+    /// `# memobuild:no-cache` asks for it to always run
+    Forced,
+    /// No previous build ran this instruction
+    NewNode,
+}
+
+impl DirtyReason {
+    /// The kind of reason without its detail, e.g. `input_file_changed`.
+    pub fn category(&self) -> &'static str {
+        match self {
+            DirtyReason::ContentChanged => "content_changed",
+            DirtyReason::InputFileChanged(_) => "input_file_changed",
+            DirtyReason::ParentDirty(_) => "parent_dirty",
+            DirtyReason::EnvChanged(_) => "env_changed",
+            DirtyReason::Forced => "forced",
+            DirtyReason::NewNode => "new_node",
+        }
+    }
+}
+
+impl std::fmt::Display for DirtyReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DirtyReason::ContentChanged => write!(f, "content changed"),
+            DirtyReason::InputFileChanged(path) => write!(f, "input file changed: {}", path),
+            DirtyReason::ParentDirty(id) => write!(f, "parent node {} is dirty", id),
+            DirtyReason::EnvChanged(key) => write!(f, "env changed: {}", key),
+            DirtyReason::Forced => write!(f, "forced (no-cache)"),
+            DirtyReason::NewNode => write!(f, "new node"),
+        }
+    }
+}
+
+impl NodeMetadata {
+    /// Whether the node opted out of platform partitioning with
+    /// `# memobuild:platform=any`.
+    pub fn platform_independent(&self) -> bool {
+        self.directives.get("platform").map(String::as_str) == Some("any")
+    }
+
+    /// Whether `# memobuild:no-cache` asks for the step to always run.
+    pub fn no_cache(&self) -> bool {
+        self.directives
+            .contains_key(crate::docker::directives::NO_CACHE)
+    }
+
+    /// Paths a COPY leaves out of its source, from `COPY --exclude=` or
+    /// `# memobuild:exclude=`.
+    pub fn excludes(&self) -> Vec<&str> {
+        self.directives
+            .get(crate::docker::directives::EXCLUDE)
+            .map(|value| crate::docker::directives::list(value))
+            .unwrap_or_default()
+    }
+
+    /// Longest the step may run, from `# memobuild:timeout=`.
+    pub fn timeout(&self) -> Option<std::time::Duration> {
+        self.directives
+            .get(crate::docker::directives::TIMEOUT)
+            .and_then(|value| crate::docker::directives::parse_timeout(value).ok())
+    }
+
+    /// Feed metadata that must influence the cache key into `hasher`.
+    /// Fields are only hashed when set, so keys for plain nodes stay stable.
+    pub fn hash_key_inputs(&self, hasher: &mut blake3::Hasher) {
+        if let Some(ref digest) = self.include_digest {
+            hasher.update(b"include=");
+            hasher.update(digest.as_bytes());
+        }
+        if let Some(ref digest) = self.base_image_digest {
+            hasher.update(b"base=");
+            hasher.update(digest.as_bytes());
+        }
+        if let Some(salt) = self.directives.get("salt") {
+            hasher.update(b"salt=");
+            hasher.update(salt.as_bytes());
+        }
+        if let Some(ref network) = self.network {
+            hasher.update(b"network=");
+            hasher.update(network.to_string().as_bytes());
+        }
+        if let Some(ref digest) = self.traced_inputs {
+            hasher.update(b"traced=");
+            hasher.update(digest.as_bytes());
+        }
+        if let Some(ref shell) = self.shell {
+            hasher.update(b"shell=");
+            hasher.update(shell.join("\0").as_bytes());
+        }
+        if let Some(ref worker) = self.worker {
+            hasher.update(b"worker=");
+            hasher.update(worker.as_bytes());
+        }
+        if let Some(ref digest) = self.declared_inputs {
+            hasher.update(b"inputs=");
+            hasher.update(digest.as_bytes());
+        }
+        if let Some(ref digest) = self.content_filters {
+            hasher.update(b"filters=");
+            hasher.update(digest.as_bytes());
+        }
+        if let Some(ref plugin) = self.plugin {
+            hasher.update(b"plugin=");
+            hasher.update(plugin.as_bytes());
+        }
+        if let Some(ref nested) = self.nested {
+            hasher.update(b"nested=");
+            hasher.update(nested.as_bytes());
+        }
+    }
 }
 
 impl Node {
+    /// Mark the node dirty. The first reason recorded is kept.
+    pub fn mark_dirty(&mut self, reason: DirtyReason) {
+        self.dirty = true;
+        if self.metadata.dirty_reason.is_none() {
+            self.metadata.dirty_reason = Some(reason);
+        }
+    }
+
+    /// Prefix for messages about this node, e.g. `Dockerfile:12:1: `
+    pub fn location_prefix(&self) -> String {
+        self.metadata
+            .span
+            .as_ref()
+            .map(|s| format!("{}: ", s))
+            .unwrap_or_default()
+    }
+
     /// Computes a unique key for the node based on its kind, content, dependencies, and optional context.
     /// This is the heart of incremental builds and content-addressed identities.
     pub fn compute_node_key(
@@ -82,6 +336,7 @@ impl Node {
         env_fingerprint: Option<&crate::env::EnvFingerprint>,
     ) -> String {
         let mut hasher = blake3::Hasher::new();
+        crate::core::hash_key_version(&mut hasher);
 
         // 1. Hash the kind and instruction content
         hasher.update(format!("{:?}", self.kind).as_bytes());
@@ -118,6 +373,7 @@ impl Node {
         // 6. Hash metadata that affects execution
         hasher.update(format!("parallelizable={}", self.metadata.parallelizable).as_bytes());
         hasher.update(format!("priority={}", self.metadata.priority).as_bytes());
+        self.metadata.hash_key_inputs(&mut hasher);
 
         // 7. Hash environment fingerprint for global determinism
         if let Some(fp) = env_fingerprint {
@@ -137,6 +393,268 @@ impl BuildGraph {
     pub fn new() -> Self {
         Self { nodes: Vec::new() }
     }
+
+    /// Merge a reusable graph fragment into this graph.
+    ///
+    /// Fragment node ids and dependencies are shifted past the existing nodes,
+    /// and the fragment's root nodes are made to depend on `attach_to` (if any).
+    /// Returns the ids assigned to the merged nodes.
+    pub fn merge(&mut self, fragment: BuildGraph, attach_to: Option<usize>) -> Vec<usize> {
+        let offset = self.nodes.len();
+        let mut ids = Vec::with_capacity(fragment.nodes.len());
+
+        for mut node in fragment.nodes {
+            node.id += offset;
+            for dep in &mut node.deps {
+                *dep += offset;
+            }
+            if node.deps.is_empty() {
+                if let Some(parent) = attach_to {
+                    node.deps.push(parent);
+                }
+            }
+            ids.push(node.id);
+            self.nodes.push(node);
+        }
+
+        ids
+    }
+}
+
+/// Builds a [`BuildGraph`] from named steps, for library users that do not
+/// start from a Dockerfile. Steps refer to each other by name, in any order;
+/// [`GraphBuilder::build`] checks the references, rejects cycles and numbers
+/// the nodes level by level, so dependencies always get lower ids.
+///
+/// ```
+/// use memobuild::graph::GraphBuilder;
+///
+/// let mut builder = GraphBuilder::new();
+/// builder.add_task("test", "cargo test").depends_on("build");
+/// builder
+///     .add_task("build", "cargo build")
+///     .with_inputs(["src/**", "Cargo.toml"])
+///     .with_outputs(["target/debug/app"]);
+/// let graph = builder.build().unwrap();
+/// assert_eq!(graph.nodes[1].deps, vec![0]);
+/// ```
+#[derive(Debug, Default)]
+pub struct GraphBuilder {
+    steps: Vec<PendingStep>,
+}
+
+#[derive(Debug)]
+struct PendingStep {
+    name: String,
+    content: String,
+    kind: NodeKind,
+    deps: Vec<String>,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    env: std::collections::HashMap<String, String>,
+    directives: std::collections::BTreeMap<String, String>,
+    group: Option<String>,
+}
+
+/// A step being added to a [`GraphBuilder`].
+pub struct StepBuilder<'a> {
+    step: &'a mut PendingStep,
+}
+
+impl GraphBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a [`NodeKind::Task`] running `cmd`.
+    pub fn add_task(&mut self, name: &str, cmd: &str) -> StepBuilder<'_> {
+        let kind = NodeKind::Task {
+            cmd: cmd.to_string(),
+            outputs: Vec::new(),
+            inputs: Vec::new(),
+        };
+        self.add_step(name, kind, cmd)
+    }
+
+    /// Add a step of any kind; `content` is the instruction it stands for.
+    pub fn add_step(&mut self, name: &str, kind: NodeKind, content: &str) -> StepBuilder<'_> {
+        self.steps.push(PendingStep {
+            name: name.to_string(),
+            content: content.to_string(),
+            kind,
+            deps: Vec::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            env: std::collections::HashMap::new(),
+            directives: std::collections::BTreeMap::new(),
+            group: None,
+        });
+        StepBuilder {
+            step: self.steps.last_mut().unwrap(),
+        }
+    }
+
+    /// The graph, with every step after the ones it depends on. Fails on
+    /// duplicate names, unknown dependencies, cycles, and inputs or outputs
+    /// on steps that cannot have them.
+    pub fn build(self) -> anyhow::Result<BuildGraph> {
+        let mut index = std::collections::HashMap::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            if step.name.is_empty() {
+                anyhow::bail!("Step {} has no name", i);
+            }
+            if index.insert(step.name.as_str(), i).is_some() {
+                anyhow::bail!("Two steps are named {}", step.name);
+            }
+        }
+        for step in &self.steps {
+            for dep in &step.deps {
+                if !index.contains_key(dep.as_str()) {
+                    anyhow::bail!("{} depends on unknown step {}", step.name, dep);
+                }
+            }
+            let task = matches!(step.kind, NodeKind::Task { .. });
+            if !step.outputs.is_empty() && !task {
+                anyhow::bail!("{}: only tasks have outputs", step.name);
+            }
+            let runs = matches!(step.kind, NodeKind::Run | NodeKind::RunExtend { .. });
+            if !step.inputs.is_empty() && !task && !runs {
+                anyhow::bail!("{}: only tasks and RUN steps have inputs", step.name);
+            }
+        }
+
+        // Number level by level, in the order steps were added
+        let mut ids: Vec<Option<usize>> = vec![None; self.steps.len()];
+        let mut order = Vec::with_capacity(self.steps.len());
+        while order.len() < self.steps.len() {
+            let level: Vec<usize> = (0..self.steps.len())
+                .filter(|&i| {
+                    ids[i].is_none()
+                        && self.steps[i]
+                            .deps
+                            .iter()
+                            .all(|dep| ids[index[dep.as_str()]].is_some())
+                })
+                .collect();
+            if level.is_empty() {
+                let left: Vec<&str> = (0..self.steps.len())
+                    .filter(|&i| ids[i].is_none())
+                    .map(|i| self.steps[i].name.as_str())
+                    .collect();
+                anyhow::bail!("Steps depend on each other in a cycle: {}", left.join(", "));
+            }
+            for i in level {
+                ids[i] = Some(order.len());
+                order.push(i);
+            }
+        }
+
+        let deps: Vec<Vec<usize>> = order
+            .iter()
+            .map(|&i| {
+                let mut deps: Vec<usize> = self.steps[i]
+                    .deps
+                    .iter()
+                    .filter_map(|dep| ids[index[dep.as_str()]])
+                    .collect();
+                deps.sort_unstable();
+                deps.dedup();
+                deps
+            })
+            .collect();
+        let mut steps: Vec<Option<PendingStep>> = self.steps.into_iter().map(Some).collect();
+        let mut graph = BuildGraph::new();
+        for (id, (i, deps)) in order.into_iter().zip(deps).enumerate() {
+            let mut step = steps[i].take().unwrap();
+            match step.kind {
+                NodeKind::Task {
+                    ref mut inputs,
+                    ref mut outputs,
+                    ..
+                } => {
+                    inputs.append(&mut step.inputs);
+                    outputs.append(&mut step.outputs);
+                }
+                _ if !step.inputs.is_empty() => {
+                    step.directives.insert(
+                        crate::docker::directives::INPUTS.to_string(),
+                        step.inputs.join(", "),
+                    );
+                }
+                _ => {}
+            }
+            graph.nodes.push(Node {
+                id,
+                name: step.name,
+                content: step.content,
+                kind: step.kind,
+                hash: String::new(),
+                dirty: false,
+                deps,
+                source_path: None,
+                env: step.env,
+                cache_hit: false,
+                metadata: NodeMetadata {
+                    directives: step.directives,
+                    group: step.group,
+                    ..Default::default()
+                },
+            });
+        }
+        Ok(graph)
+    }
+}
+
+impl StepBuilder<'_> {
+    /// Run after the step named `name`.
+    pub fn depends_on(self, name: &str) -> Self {
+        self.step.deps.push(name.to_string());
+        self
+    }
+
+    /// Context files the step reads, as globs. Their content becomes part of
+    /// its cache key.
+    pub fn with_inputs<I, S>(self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.step
+            .inputs
+            .extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// Files a task produces, as globs relative to the context.
+    pub fn with_outputs<I, S>(self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.step
+            .outputs
+            .extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn with_env(self, key: &str, value: &str) -> Self {
+        self.step.env.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Group the step is reported under.
+    pub fn with_group(self, group: &str) -> Self {
+        self.step.group = Some(group.to_string());
+        self
+    }
+
+    /// A `# memobuild:` directive, e.g. `timeout` or `network`.
+    pub fn with_directive(self, key: &str, value: &str) -> Self {
+        self.step
+            .directives
+            .insert(key.to_string(), value.to_string());
+        self
+    }
 }
 
 impl BuildGraph {
@@ -151,7 +669,7 @@ impl BuildGraph {
             }
         }
 
-        stack.reverse();
+        // Post-order: every node comes after its dependencies
         stack
     }
 
@@ -192,3 +710,299 @@ impl BuildGraph {
         result
     }
 }
+
+/// Why a step's cache key differs between two graphs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum ChangeReason {
+    /// The instruction text changed
+    Instruction { before: String, after: String },
+    /// ENV values set by the step changed
+    Env { keys: Vec<String> },
+    /// The files a COPY reads changed
+    Sources,
+    /// The FROM tag resolves to another digest
+    BaseImage {
+        before: Option<String>,
+        after: Option<String>,
+    },
+    /// An INCLUDE fragment the step came from changed
+    Include,
+    /// The `salt` directive changed
+    Salt,
+    /// The network policy changed
+    Network,
+    /// The shell RUN commands are handed to changed
+    Shell,
+    /// The step moved to or from `platform=any`
+    Platform,
+    /// Scheduling metadata that is part of the key changed
+    Scheduling,
+    /// The step depends on a different set of steps
+    Dependencies,
+    /// A step this one depends on is added or changed
+    Dependency { id: usize, name: String },
+    /// The keys differ for a reason not visible in the graph
+    Other,
+}
+
+impl std::fmt::Display for ChangeReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChangeReason::Instruction { before, after } => {
+                write!(f, "instruction changed: `{}` → `{}`", before, after)
+            }
+            ChangeReason::Env { keys } => write!(f, "ENV changed: {}", keys.join(", ")),
+            ChangeReason::Sources => write!(f, "source files changed"),
+            ChangeReason::BaseImage { before, after } => write!(
+                f,
+                "base image digest changed: {} → {}",
+                before.as_deref().unwrap_or("none"),
+                after.as_deref().unwrap_or("none")
+            ),
+            ChangeReason::Include => write!(f, "included fragment changed"),
+            ChangeReason::Salt => write!(f, "salt directive changed"),
+            ChangeReason::Network => write!(f, "network policy changed"),
+            ChangeReason::Shell => write!(f, "shell changed"),
+            ChangeReason::Platform => write!(f, "platform partitioning changed"),
+            ChangeReason::Scheduling => write!(f, "parallelism or priority changed"),
+            ChangeReason::Dependencies => write!(f, "depends on different steps"),
+            ChangeReason::Dependency { id, name } => {
+                write!(f, "depends on changed step [{}] {}", id, name)
+            }
+            ChangeReason::Other => write!(f, "cache key changed"),
+        }
+    }
+}
+
+/// A step present in only one of the graphs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffNode {
+    pub id: usize,
+    pub name: String,
+}
+
+/// A step present in both graphs whose cache key changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeChange {
+    pub old_id: usize,
+    pub new_id: usize,
+    pub name: String,
+    pub reasons: Vec<ChangeReason>,
+}
+
+/// What changing one graph into another does to the cache.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GraphDiff {
+    /// Steps only in the new graph, by new id
+    pub added: Vec<DiffNode>,
+    /// Steps only in the old graph, by old id
+    pub removed: Vec<DiffNode>,
+    pub changed: Vec<NodeChange>,
+    /// Steps whose cached artifacts stay valid
+    pub unchanged: usize,
+}
+
+impl GraphDiff {
+    /// Steps of the new graph that will not come from the cache.
+    pub fn invalidated(&self) -> usize {
+        self.added.len() + self.changed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compare two graphs step by step.
+///
+/// Steps are paired by their instructions in order (a longest common
+/// subsequence), and steps that were edited in place are paired with the
+/// step at the same position between two unchanged ones. A paired step is
+/// changed when one of its own key inputs changed or when something it
+/// depends on is added or changed. Hashes are compared too when both graphs
+/// have them, to catch inputs the graph does not record.
+pub fn diff(old: &BuildGraph, new: &BuildGraph) -> GraphDiff {
+    let pairs = align(old, new);
+    let mut old_for_new: Vec<Option<usize>> = vec![None; new.nodes.len()];
+    let mut new_for_old: Vec<Option<usize>> = vec![None; old.nodes.len()];
+    for &(o, n) in &pairs {
+        old_for_new[n] = Some(o);
+        new_for_old[o] = Some(n);
+    }
+
+    let mut result = GraphDiff::default();
+    let mut invalid = vec![false; new.nodes.len()];
+    for id in new.topological_order() {
+        let node = &new.nodes[id];
+        let Some(old_id) = old_for_new[id] else {
+            invalid[id] = true;
+            continue;
+        };
+        let before = &old.nodes[old_id];
+        let mut reasons = own_changes(before, node);
+
+        let mut old_deps: Vec<Option<usize>> = before
+            .deps
+            .iter()
+            .map(|&d| new_for_old.get(d).copied().flatten())
+            .collect();
+        let mut new_deps: Vec<Option<usize>> = node.deps.iter().map(|&d| Some(d)).collect();
+        old_deps.sort();
+        new_deps.sort();
+        if old_deps != new_deps {
+            reasons.push(ChangeReason::Dependencies);
+        }
+        for &dep in &node.deps {
+            if invalid.get(dep).copied().unwrap_or(false) {
+                reasons.push(ChangeReason::Dependency {
+                    id: dep,
+                    name: new.nodes[dep].name.clone(),
+                });
+            }
+        }
+
+        if reasons.is_empty()
+            && !before.hash.is_empty()
+            && !node.hash.is_empty()
+            && before.hash != node.hash
+        {
+            reasons.push(ChangeReason::Other);
+        }
+
+        if reasons.is_empty() {
+            result.unchanged += 1;
+        } else {
+            invalid[id] = true;
+            result.changed.push(NodeChange {
+                old_id,
+                new_id: id,
+                name: node.name.clone(),
+                reasons,
+            });
+        }
+    }
+
+    result.changed.sort_by_key(|c| c.new_id);
+    result.added = new
+        .nodes
+        .iter()
+        .filter(|n| old_for_new[n.id].is_none())
+        .map(|n| DiffNode {
+            id: n.id,
+            name: n.name.clone(),
+        })
+        .collect();
+    result.removed = old
+        .nodes
+        .iter()
+        .filter(|n| new_for_old[n.id].is_none())
+        .map(|n| DiffNode {
+            id: n.id,
+            name: n.name.clone(),
+        })
+        .collect();
+    result
+}
+
+/// Pair old and new node ids: identical instructions by longest common
+/// subsequence, then edited steps of the same kind between two anchors.
+fn align(old: &BuildGraph, new: &BuildGraph) -> Vec<(usize, usize)> {
+    let same = |a: &Node, b: &Node| a.kind == b.kind && a.content == b.content;
+    let (n, m) = (old.nodes.len(), new.nodes.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if same(&old.nodes[i], &new.nodes[j]) {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let (mut gap_old, mut gap_new) = (Vec::new(), Vec::new());
+    let close_gap = |gap_old: &mut Vec<usize>, gap_new: &mut Vec<usize>, pairs: &mut Vec<_>| {
+        for (&o, &n) in gap_old.iter().zip(gap_new.iter()) {
+            if old.nodes[o].kind.label() == new.nodes[n].kind.label() {
+                pairs.push((o, n));
+            }
+        }
+        gap_old.clear();
+        gap_new.clear();
+    };
+    while i < n && j < m {
+        if same(&old.nodes[i], &new.nodes[j]) {
+            close_gap(&mut gap_old, &mut gap_new, &mut pairs);
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            gap_old.push(i);
+            i += 1;
+        } else {
+            gap_new.push(j);
+            j += 1;
+        }
+    }
+    gap_old.extend(i..n);
+    gap_new.extend(j..m);
+    close_gap(&mut gap_old, &mut gap_new, &mut pairs);
+    pairs
+}
+
+/// Key inputs of `after` that differ from `before`, ignoring dependencies.
+fn own_changes(before: &Node, after: &Node) -> Vec<ChangeReason> {
+    let mut reasons = Vec::new();
+    if before.kind != after.kind || before.content != after.content {
+        reasons.push(ChangeReason::Instruction {
+            before: before.content.clone(),
+            after: after.content.clone(),
+        });
+    }
+
+    let mut keys: Vec<String> = before
+        .env
+        .keys()
+        .chain(after.env.keys())
+        .filter(|k| before.env.get(*k) != after.env.get(*k))
+        .cloned()
+        .collect();
+    keys.sort();
+    keys.dedup();
+    if !keys.is_empty() {
+        reasons.push(ChangeReason::Env { keys });
+    }
+
+    let (b, a) = (&before.metadata, &after.metadata);
+    if b.source_content_hash != a.source_content_hash {
+        reasons.push(ChangeReason::Sources);
+    }
+    if b.base_image_digest != a.base_image_digest {
+        reasons.push(ChangeReason::BaseImage {
+            before: b.base_image_digest.clone(),
+            after: a.base_image_digest.clone(),
+        });
+    }
+    if b.include_digest != a.include_digest {
+        reasons.push(ChangeReason::Include);
+    }
+    if b.directives.get("salt") != a.directives.get("salt") {
+        reasons.push(ChangeReason::Salt);
+    }
+    if b.network != a.network {
+        reasons.push(ChangeReason::Network);
+    }
+    if b.shell != a.shell {
+        reasons.push(ChangeReason::Shell);
+    }
+    if b.platform_independent() != a.platform_independent() {
+        reasons.push(ChangeReason::Platform);
+    }
+    if b.parallelizable != a.parallelizable || b.priority != a.priority {
+        reasons.push(ChangeReason::Scheduling);
+    }
+    reasons
+}
@@ -1,7 +1,6 @@
-use crate::hasher::{ignore::IgnoreRules, walker::walk_dir};
+use crate::hasher::{ignore::IgnoreRules, merkle::MerkleTree, progress::HashProgress};
 use anyhow::{Context, Result};
 use blake3::Hasher;
-use rayon::prelude::*;
 use std::fs::File;
 use std::io::{BufReader, Read};
 use std::path::Path;
@@ -11,6 +10,16 @@ const CHUNK_SIZE: usize = 64 * 1024;
 
 /// Hash a single file using BLAKE3, reading in 64 KB chunks.
 pub fn hash_file(path: &Path) -> Result<String> {
+    hash_file_inner(path, None)
+}
+
+/// [`hash_file`], counting the bytes read into `progress` and stopping
+/// between chunks once it is cancelled.
+pub fn hash_file_with_progress(path: &Path, progress: &HashProgress) -> Result<String> {
+    hash_file_inner(path, Some(progress))
+}
+
+fn hash_file_inner(path: &Path, progress: Option<&HashProgress>) -> Result<String> {
     let file = File::open(path)
         .with_context(|| format!("Cannot open file for hashing: {}", path.display()))?;
     let mut reader = BufReader::new(file);
@@ -25,33 +34,19 @@ pub fn hash_file(path: &Path) -> Result<String> {
             break;
         }
         hasher.update(&buf[..n]);
+        if let Some(progress) = progress {
+            progress.add_bytes(n as u64);
+            progress.check()?;
+        }
     }
 
     Ok(hasher.finalize().to_hex().to_string())
 }
 
-/// Hash a directory tree recursively using Rayon for parallel execution.
+/// Hash a directory tree as a Merkle tree: each directory's digest is built
+/// from its children's, so unchanged subtrees keep their digest.
 pub fn hash_dir(root: &Path, ignore: &IgnoreRules) -> Result<String> {
-    let files = walk_dir(root, ignore);
-
-    // Fix 2: Parallel hashing of file contents using Rayon
-    let results: Result<Vec<(String, String)>> = files
-        .par_iter()
-        .map(|abs_path| {
-            let rel = abs_path.strip_prefix(root).unwrap_or(abs_path.as_path());
-            let rel_path_str = rel.to_string_lossy().to_string();
-            let file_hash = hash_file(abs_path)?;
-            Ok((rel_path_str, file_hash))
-        })
-        .collect();
-
-    let mut top_hasher = Hasher::new();
-    for (rel_path, file_hash) in results? {
-        top_hasher.update(rel_path.as_bytes());
-        top_hasher.update(file_hash.as_bytes());
-    }
-
-    Ok(top_hasher.finalize().to_hex().to_string())
+    Ok(MerkleTree::build(root, ignore, None)?.root)
 }
 
 /// Dispatch: hash a file or a directory, respecting ignore rules.
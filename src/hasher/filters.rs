@@ -0,0 +1,190 @@
+//! Content filters from `memobuild.filters.json`, which leave volatile parts of
+//! input files out of their digest.
+
+use crate::hasher::file_hasher::hash_file_with_progress;
+use crate::hasher::progress::HashProgress;
+use anyhow::{Context, Result};
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// File name of the filters, in the build context
+pub const FILTERS_FILE: &str = "memobuild.filters.json";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FilterFile {
+    #[serde(default)]
+    pub filters: Vec<FilterRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilterRule {
+    /// Globs of the files the rule applies to; a directory takes in every
+    /// file under it
+    pub paths: Vec<String>,
+    /// Leave matching files out of the digest
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skip: bool,
+    /// Regular expressions whose matches are removed before hashing
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+}
+
+struct CompiledRule {
+    paths: Vec<glob::Pattern>,
+    skip: bool,
+    remove: Vec<Regex>,
+}
+
+impl CompiledRule {
+    fn matches(&self, rel: &str) -> bool {
+        Path::new(rel)
+            .ancestors()
+            .filter(|a| !a.as_os_str().is_empty())
+            .any(|a| self.paths.iter().any(|p| p.matches_path(a)))
+    }
+}
+
+/// The filters of a build context; without a filters file, none.
+#[derive(Default)]
+pub struct ContentFilters {
+    rules: Vec<CompiledRule>,
+    digest: Option<String>,
+}
+
+impl ContentFilters {
+    pub fn load(context_dir: &Path) -> Result<Self> {
+        let path = context_dir.join(FILTERS_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(content) => Self::parse(&content)
+                .with_context(|| format!("Invalid content filters {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let file: FilterFile = serde_json::from_str(content)?;
+        if file.filters.is_empty() {
+            return Ok(Self::default());
+        }
+        let mut rules = Vec::new();
+        for (i, rule) in file.filters.iter().enumerate() {
+            if rule.paths.is_empty() {
+                anyhow::bail!("filter {} has no paths", i + 1);
+            }
+            if !rule.skip && rule.remove.is_empty() {
+                anyhow::bail!("filter {} neither skips nor removes anything", i + 1);
+            }
+            rules.push(CompiledRule {
+                paths: rule
+                    .paths
+                    .iter()
+                    .map(|p| glob::Pattern::new(p).with_context(|| format!("Invalid path {}", p)))
+                    .collect::<Result<_>>()?,
+                skip: rule.skip,
+                remove: rule
+                    .remove
+                    .iter()
+                    .map(|r| Regex::new(r).with_context(|| format!("Invalid expression {}", r)))
+                    .collect::<Result<_>>()?,
+            });
+        }
+        // Of the rules rather than the text, so reformatting the file keeps
+        // the cache
+        let digest = blake3::hash(&serde_json::to_vec(&file)?)
+            .to_hex()
+            .to_string();
+        Ok(Self {
+            rules,
+            digest: Some(digest),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Digest of the rules, for cache keys; `None` without filters.
+    pub fn digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
+
+    /// Digest of the file at `path`, named `rel` in the tree being hashed,
+    /// after the rules matching it are applied; `None` when one skips it.
+    /// A file no rule matches hashes as [`hash_file`](super::file_hasher::hash_file)
+    /// does, and only filtered files are read into memory.
+    pub fn hash_file(
+        &self,
+        rel: &str,
+        path: &Path,
+        progress: &HashProgress,
+    ) -> Result<Option<String>> {
+        let matching: Vec<&CompiledRule> =
+            self.rules.iter().filter(|rule| rule.matches(rel)).collect();
+        if matching.iter().any(|rule| rule.skip) {
+            return Ok(None);
+        }
+        if matching.is_empty() {
+            return hash_file_with_progress(path, progress).map(Some);
+        }
+        let mut content = std::fs::read(path)
+            .with_context(|| format!("Cannot open file for hashing: {}", path.display()))?;
+        progress.add_bytes(content.len() as u64);
+        for regex in matching.iter().flat_map(|rule| &rule.remove) {
+            content = regex.replace_all(&content, &b""[..]).into_owned();
+        }
+        Ok(Some(blake3::hash(&content).to_hex().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters_skip_and_strip_volatile_content() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = dir.path().join("version.go");
+        let filters = ContentFilters::parse(
+            r#"{ "filters": [
+                { "paths": ["**/__pycache__", "*.pyc"], "skip": true },
+                { "paths": ["gen/*.go"], "remove": ["(?m)^// Generated at .*$"] }
+            ] }"#,
+        )
+        .unwrap();
+        let progress = HashProgress::default();
+        let hash = |rel: &str, content: &str| {
+            std::fs::write(&file, content).unwrap();
+            filters.hash_file(rel, &file, &progress).unwrap()
+        };
+
+        let first = hash("gen/version.go", "// Generated at 10:00\npackage gen\n");
+        assert_eq!(
+            first,
+            hash("gen/version.go", "// Generated at 11:30\npackage gen\n")
+        );
+        assert_ne!(
+            first,
+            hash("gen/version.go", "// Generated at 10:00\npackage v2\n")
+        );
+        // Outside the rule's paths the timestamp counts
+        assert_ne!(
+            hash("src/version.go", "// Generated at 10:00\npackage gen\n"),
+            hash("src/version.go", "// Generated at 11:30\npackage gen\n")
+        );
+        assert_eq!(hash("app/__pycache__/mod.cpython-312.pyc", "x"), None);
+        assert_eq!(hash("mod.pyc", "x"), None);
+
+        // The digest follows the rules, not their formatting
+        let reformatted = ContentFilters::parse(
+            r#"{"filters":[{"paths":["**/__pycache__","*.pyc"],"skip":true},{"paths":["gen/*.go"],"remove":["(?m)^// Generated at .*$"]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(reformatted.digest(), filters.digest());
+        assert!(ContentFilters::parse(r#"{ "filters": [{ "paths": ["*.log"] }] }"#).is_err());
+        assert!(ContentFilters::parse(r#"{ "filters": [] }"#)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -2,6 +2,7 @@ use glob::Pattern;
 use std::path::Path;
 
 /// Parsed ignore rules from .dockerignore or .gitignore
+#[derive(Clone)]
 pub struct IgnoreRules {
     patterns: Vec<Pattern>,
 }
@@ -33,6 +34,14 @@ impl IgnoreRules {
         Self { patterns }
     }
 
+    /// These rules and `patterns` besides, such as a COPY's `--exclude`
+    /// patterns on top of .dockerignore.
+    pub fn with_patterns<'a>(mut self, patterns: impl IntoIterator<Item = &'a str>) -> Self {
+        self.patterns
+            .extend(patterns.into_iter().filter_map(|p| Pattern::new(p).ok()));
+        self
+    }
+
     /// Returns true if the given path (relative to the build context root) should be ignored
     pub fn is_ignored(&self, path: &Path) -> bool {
         // Check the path itself and all its parents
@@ -69,4 +78,13 @@ mod tests {
         assert!(rules.is_ignored(Path::new("build.log")));
         assert!(!rules.is_ignored(Path::new("main.rs")));
     }
+
+    #[test]
+    fn test_with_patterns_keeps_file_rules() {
+        let rules = IgnoreRules::parse("*.log").with_patterns(["tests", "*.md"]);
+        assert!(rules.is_ignored(Path::new("build.log")));
+        assert!(rules.is_ignored(Path::new("tests/unit.rs")));
+        assert!(rules.is_ignored(Path::new("docs/guide.md")));
+        assert!(!rules.is_ignored(Path::new("src/main.rs")));
+    }
 }
@@ -0,0 +1,498 @@
+use crate::hasher::file_hasher::hash_file;
+use crate::hasher::filters::ContentFilters;
+use crate::hasher::normalize::PathNormalization;
+use crate::hasher::{ignore::IgnoreRules, progress::HashProgress, walker::walk_dir};
+use anyhow::{Context, Result};
+use blake3::Hasher;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File name of the Merkle state kept in the local cache directory
+pub const MERKLE_STATE_FILE: &str = "merkle.json";
+
+/// Files modified this close to the previous scan are rehashed even if their
+/// stat looks unchanged, since an edit in the same timestamp tick would go
+/// unnoticed otherwise.
+const RACY_WINDOW_NS: u64 = 2_000_000_000;
+
+/// What a file looked like when it was last hashed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub mtime_ns: u64,
+    pub digest: String,
+}
+
+/// Digests of a directory tree, one per directory, each composed from the
+/// digests of its children.
+///
+/// Paths are relative to the root and `/`-separated; the root itself is `""`.
+/// Building against a previous tree only rehashes files whose size or mtime
+/// changed, and comparing two trees narrows a change down to the directories
+/// it happened in.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MerkleTree {
+    pub root: String,
+    pub dirs: BTreeMap<String, String>,
+    pub files: BTreeMap<String, FileStamp>,
+    /// When the scan started, for the racy-timestamp check
+    pub scanned_at_ns: u64,
+    /// Digest of the content filters file digests were computed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filters: Option<String>,
+    /// Files whose content was read during this build
+    #[serde(skip)]
+    pub rehashed: usize,
+}
+
+impl MerkleTree {
+    /// Hash the tree under `root`, reusing digests from `previous` for files
+    /// that have not been touched since.
+    pub fn build(root: &Path, ignore: &IgnoreRules, previous: Option<&MerkleTree>) -> Result<Self> {
+        Self::build_with_progress(root, ignore, previous, &HashProgress::default())
+    }
+
+    /// [`MerkleTree::build`], reporting into `progress` and returning
+    /// [`Cancelled`](crate::hasher::Cancelled) soon after it is cancelled.
+    pub fn build_with_progress(
+        root: &Path,
+        ignore: &IgnoreRules,
+        previous: Option<&MerkleTree>,
+        progress: &HashProgress,
+    ) -> Result<Self> {
+        Self::build_filtered(root, ignore, &ContentFilters::default(), previous, progress)
+    }
+
+    /// [`MerkleTree::build_with_progress`], hashing files through `filters`.
+    /// Files a filter skips are left out of the tree. Digests of `previous`
+    /// are only reused when it was built with the same filters.
+    pub fn build_filtered(
+        root: &Path,
+        ignore: &IgnoreRules,
+        filters: &ContentFilters,
+        previous: Option<&MerkleTree>,
+        progress: &HashProgress,
+    ) -> Result<Self> {
+        let scanned_at_ns = now_ns();
+        let previous = previous.filter(|prev| prev.filters.as_deref() == filters.digest());
+        let files = walk_dir(root, ignore);
+        progress.check()?;
+        progress.add_total(files.len() as u64);
+
+        let stamped: Result<Vec<Option<(String, FileStamp, bool)>>> = files
+            .par_iter()
+            .map(|abs_path| {
+                progress.check()?;
+                progress.file_started(abs_path);
+                let rel = rel_key(abs_path.strip_prefix(root).unwrap_or(abs_path.as_path()));
+                let meta = std::fs::metadata(abs_path)
+                    .with_context(|| format!("Cannot stat {}", abs_path.display()))?;
+                let size = meta.len();
+                let mtime_ns = meta.modified().map(system_time_ns).unwrap_or(0);
+
+                let reusable = previous.and_then(|prev| {
+                    let old = prev.files.get(&rel)?;
+                    let settled = mtime_ns.saturating_add(RACY_WINDOW_NS) < prev.scanned_at_ns;
+                    (old.size == size && old.mtime_ns == mtime_ns && settled)
+                        .then(|| old.digest.clone())
+                });
+                let (digest, rehashed) = match reusable {
+                    Some(digest) => (Some(digest), false),
+                    None => {
+                        let digest = filters.hash_file(&rel, abs_path, progress)?;
+                        let read = digest.is_some();
+                        (digest, read)
+                    }
+                };
+                progress.file_done();
+                Ok(digest.map(|digest| {
+                    (
+                        rel,
+                        FileStamp {
+                            size,
+                            mtime_ns,
+                            digest,
+                        },
+                        rehashed,
+                    )
+                }))
+            })
+            .collect();
+
+        let mut tree = MerkleTree {
+            scanned_at_ns,
+            filters: filters.digest().map(String::from),
+            ..Default::default()
+        };
+        for (rel, stamp, rehashed) in stamped?.into_iter().flatten() {
+            tree.rehashed += rehashed as usize;
+            if tree.files.insert(rel.clone(), stamp).is_some() {
+                anyhow::bail!(
+                    "Several files in {} are named {} once normalized; rename them or change MEMOBUILD_PATH_NORMALIZATION",
+                    root.display(),
+                    rel
+                );
+            }
+        }
+        tree.compute_dirs();
+        Ok(tree)
+    }
+
+    /// Bring the tree up to date after `changed` paths under `root` were
+    /// created, modified or removed, reading only those paths. A changed
+    /// directory is walked again in full.
+    pub fn update_paths(
+        &mut self,
+        root: &Path,
+        ignore: &IgnoreRules,
+        changed: &[PathBuf],
+    ) -> Result<()> {
+        self.rehashed = 0;
+        for path in changed {
+            let Ok(rel) = path.strip_prefix(root) else {
+                continue;
+            };
+            if ignore.is_ignored(rel) {
+                continue;
+            }
+            let key = rel_key(rel);
+            let prefix = format!("{}/", key);
+            self.files
+                .retain(|f, _| !(key.is_empty() || *f == key || f.starts_with(&prefix)));
+
+            let found = if path.is_file() {
+                vec![path.clone()]
+            } else if path.is_dir() {
+                walk_dir(path, &IgnoreRules::empty())
+            } else {
+                Vec::new()
+            };
+            for file in found {
+                let rel = file.strip_prefix(root).unwrap_or(file.as_path());
+                if ignore.is_ignored(rel) {
+                    continue;
+                }
+                let meta = std::fs::metadata(&file)
+                    .with_context(|| format!("Cannot stat {}", file.display()))?;
+                self.files.insert(
+                    rel_key(rel),
+                    FileStamp {
+                        size: meta.len(),
+                        mtime_ns: meta.modified().map(system_time_ns).unwrap_or(0),
+                        digest: hash_file(&file)?,
+                    },
+                );
+                self.rehashed += 1;
+            }
+        }
+        self.scanned_at_ns = now_ns();
+        self.compute_dirs();
+        Ok(())
+    }
+
+    /// Fold file digests into per-directory digests, deepest directories first.
+    fn compute_dirs(&mut self) {
+        // children[dir] = name -> (is_dir, digest); dir digests filled in below
+        self.dirs.clear();
+        let mut children: BTreeMap<String, BTreeMap<String, (bool, String)>> = BTreeMap::new();
+        children.insert(String::new(), BTreeMap::new());
+        for (rel, stamp) in &self.files {
+            let (dir, name) = split_parent(rel);
+            children
+                .entry(dir.to_string())
+                .or_default()
+                .insert(name.to_string(), (false, stamp.digest.clone()));
+            // Make sure every ancestor lists the directory below it
+            let mut current = dir;
+            while !current.is_empty() {
+                let (parent, name) = split_parent(current);
+                children
+                    .entry(parent.to_string())
+                    .or_default()
+                    .entry(name.to_string())
+                    .or_insert((true, String::new()));
+                current = parent;
+            }
+        }
+
+        let mut by_depth: Vec<String> = children.keys().cloned().collect();
+        by_depth.sort_by_key(|d| std::cmp::Reverse(depth(d)));
+        for dir in by_depth {
+            let mut hasher = Hasher::new();
+            for (name, (is_dir, digest)) in &children[&dir] {
+                let digest = if *is_dir {
+                    &self.dirs[&join(&dir, name)]
+                } else {
+                    digest
+                };
+                hasher.update(if *is_dir { b"d " } else { b"f " });
+                hasher.update(name.as_bytes());
+                hasher.update(b"\0");
+                hasher.update(digest.as_bytes());
+                hasher.update(b"\n");
+            }
+            self.dirs
+                .insert(dir, hasher.finalize().to_hex().to_string());
+        }
+        self.root = self.dirs[""].clone();
+    }
+
+    /// The deepest directories whose digest differs from `previous`, i.e.
+    /// where the change actually happened. The root is reported as `.`.
+    pub fn changed_dirs(&self, previous: &MerkleTree) -> Vec<String> {
+        let all: BTreeSet<&String> = self.dirs.keys().chain(previous.dirs.keys()).collect();
+        let changed: BTreeSet<&String> = all
+            .into_iter()
+            .filter(|d| self.dirs.get(*d) != previous.dirs.get(*d))
+            .collect();
+
+        let mut not_deepest = BTreeSet::new();
+        for dir in &changed {
+            let mut current = dir.as_str();
+            while !current.is_empty() {
+                current = split_parent(current).0;
+                not_deepest.insert(current);
+            }
+        }
+        changed
+            .into_iter()
+            .filter(|d| !not_deepest.contains(d.as_str()))
+            .map(|d| {
+                if d.is_empty() {
+                    ".".to_string()
+                } else {
+                    d.clone()
+                }
+            })
+            .collect()
+    }
+}
+
+/// Merkle trees of COPY sources from the last build, keyed by source path.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MerkleState {
+    #[serde(default)]
+    pub trees: BTreeMap<String, MerkleTree>,
+}
+
+impl MerkleState {
+    /// Where the state lives for a given cache directory.
+    pub fn path_in(cache_dir: &Path) -> PathBuf {
+        cache_dir.join(MERKLE_STATE_FILE)
+    }
+
+    /// Load the state, starting empty if there is none or it is unreadable;
+    /// it is only an accelerator.
+    pub fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Sources are keyed by canonical path so `.` and an absolute path to the
+    /// same context share their trees.
+    pub fn key(source: &Path) -> String {
+        source
+            .canonicalize()
+            .unwrap_or_else(|_| source.to_path_buf())
+            .to_string_lossy()
+            .to_string()
+    }
+
+    pub fn get(&self, source: &Path) -> Option<&MerkleTree> {
+        self.trees.get(&Self::key(source))
+    }
+
+    pub fn insert(&mut self, source: &Path, tree: MerkleTree) {
+        self.trees.insert(Self::key(source), tree);
+    }
+}
+
+/// Tree key of a relative path, normalized per [`PathNormalization::current`]
+/// so the same checkout hashes alike on every platform.
+fn rel_key(rel: &Path) -> String {
+    let normalization = PathNormalization::current();
+    rel.components()
+        .map(|c| {
+            normalization
+                .apply(&c.as_os_str().to_string_lossy())
+                .into_owned()
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn split_parent(rel: &str) -> (&str, &str) {
+    rel.rsplit_once('/').unwrap_or(("", rel))
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+fn depth(dir: &str) -> usize {
+    if dir.is_empty() {
+        0
+    } else {
+        dir.matches('/').count() + 1
+    }
+}
+
+fn system_time_ns(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn now_ns() -> u64 {
+    system_time_ns(SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn make_tree() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("src/net")).unwrap();
+        fs::create_dir_all(dir.path().join("docs")).unwrap();
+        fs::write(dir.path().join("Cargo.toml"), "[package]").unwrap();
+        fs::write(dir.path().join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(dir.path().join("src/net/tcp.rs"), "// tcp").unwrap();
+        fs::write(dir.path().join("docs/README.md"), "# docs").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_change_is_located_and_only_it_is_rehashed() {
+        let dir = make_tree();
+        let mut first = MerkleTree::build(dir.path(), &IgnoreRules::empty(), None).unwrap();
+        assert_eq!(first.rehashed, 4);
+        // Pretend the scan happened well after the files were written
+        first.scanned_at_ns += 10 * RACY_WINDOW_NS;
+
+        let unchanged = MerkleTree::build(dir.path(), &IgnoreRules::empty(), Some(&first)).unwrap();
+        assert_eq!(unchanged.rehashed, 0);
+        assert_eq!(unchanged.root, first.root);
+        assert!(unchanged.changed_dirs(&first).is_empty());
+
+        fs::write(dir.path().join("src/net/tcp.rs"), "// tcp v2").unwrap();
+        let edited = MerkleTree::build(dir.path(), &IgnoreRules::empty(), Some(&first)).unwrap();
+        assert_eq!(edited.rehashed, 1);
+        assert_ne!(edited.root, first.root);
+        assert_eq!(edited.dirs["docs"], first.dirs["docs"]);
+        assert_eq!(edited.changed_dirs(&first), vec!["src/net".to_string()]);
+    }
+
+    #[test]
+    fn test_update_paths_matches_full_scan() {
+        let dir = make_tree();
+        let mut tree = MerkleTree::build(dir.path(), &IgnoreRules::empty(), None).unwrap();
+
+        fs::write(dir.path().join("src/main.rs"), "fn main() { serve() }").unwrap();
+        fs::remove_dir_all(dir.path().join("docs")).unwrap();
+        fs::create_dir(dir.path().join("benches")).unwrap();
+        fs::write(dir.path().join("benches/b.rs"), "// bench").unwrap();
+        let changed = ["src/main.rs", "docs", "benches"].map(|p| dir.path().join(p));
+        tree.update_paths(dir.path(), &IgnoreRules::empty(), &changed)
+            .unwrap();
+        assert_eq!(tree.rehashed, 2);
+
+        let full = MerkleTree::build(dir.path(), &IgnoreRules::empty(), None).unwrap();
+        assert_eq!(tree.root, full.root);
+        assert_eq!(tree.dirs, full.dirs);
+    }
+
+    #[test]
+    fn test_filtered_tree_skips_files_and_rehashes_on_new_filters() {
+        let dir = make_tree();
+        let mut plain = MerkleTree::build(dir.path(), &IgnoreRules::empty(), None).unwrap();
+        plain.scanned_at_ns += 10 * RACY_WINDOW_NS;
+        let filters =
+            ContentFilters::parse(r#"{ "filters": [{ "paths": ["docs"], "skip": true }] }"#)
+                .unwrap();
+        let progress = HashProgress::default();
+        let filtered = MerkleTree::build_filtered(
+            dir.path(),
+            &IgnoreRules::empty(),
+            &filters,
+            Some(&plain),
+            &progress,
+        )
+        .unwrap();
+        // Digests of a tree hashed without the filters are not reused
+        assert_eq!(filtered.rehashed, 3);
+        assert!(!filtered.files.contains_key("docs/README.md"));
+        assert_eq!(filtered.filters.as_deref(), filters.digest());
+
+        fs::write(dir.path().join("docs/README.md"), "# more docs").unwrap();
+        let again = MerkleTree::build_filtered(
+            dir.path(),
+            &IgnoreRules::empty(),
+            &filters,
+            None,
+            &progress,
+        )
+        .unwrap();
+        assert_eq!(again.root, filtered.root);
+    }
+
+    #[test]
+    fn test_added_file_at_root_reports_root() {
+        let dir = make_tree();
+        let first = MerkleTree::build(dir.path(), &IgnoreRules::empty(), None).unwrap();
+        fs::write(dir.path().join("build.rs"), "fn main() {}").unwrap();
+        let second = MerkleTree::build(dir.path(), &IgnoreRules::empty(), Some(&first)).unwrap();
+        assert_eq!(second.changed_dirs(&first), vec![".".to_string()]);
+    }
+
+    #[test]
+    fn test_build_reports_progress_and_cancels() {
+        let dir = make_tree();
+        let progress = HashProgress::default();
+        MerkleTree::build_with_progress(dir.path(), &IgnoreRules::empty(), None, &progress)
+            .unwrap();
+        let snapshot = progress.snapshot();
+        assert_eq!((snapshot.files, snapshot.total_files), (4, 4));
+        assert_eq!(snapshot.bytes, 9 + 12 + 6 + 6);
+
+        progress.cancel();
+        let err =
+            MerkleTree::build_with_progress(dir.path(), &IgnoreRules::empty(), None, &progress)
+                .unwrap_err();
+        assert!(crate::hasher::progress::is_cancelled(&err));
+    }
+
+    #[test]
+    fn test_names_are_normalized() {
+        let composed = TempDir::new().unwrap();
+        fs::write(composed.path().join("caf\u{e9}.txt"), "menu").unwrap();
+        let decomposed = TempDir::new().unwrap();
+        fs::write(decomposed.path().join("cafe\u{301}.txt"), "menu").unwrap();
+        let hash = |dir: &TempDir| {
+            MerkleTree::build(dir.path(), &IgnoreRules::empty(), None)
+                .unwrap()
+                .root
+        };
+        assert_eq!(hash(&composed), hash(&decomposed));
+
+        // Both spellings side by side would be one name in the tree
+        fs::write(composed.path().join("cafe\u{301}.txt"), "other").unwrap();
+        assert!(MerkleTree::build(composed.path(), &IgnoreRules::empty(), None).is_err());
+    }
+}
@@ -1,6 +1,14 @@
 pub mod file_hasher;
+pub mod filters;
 pub mod ignore;
+pub mod merkle;
+pub mod normalize;
+pub mod progress;
 pub mod walker;
 
 pub use file_hasher::hash_path;
+pub use filters::ContentFilters;
 pub use ignore::IgnoreRules;
+pub use merkle::{MerkleState, MerkleTree};
+pub use normalize::PathNormalization;
+pub use progress::{Cancelled, HashProgress};
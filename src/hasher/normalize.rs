@@ -0,0 +1,96 @@
+//! Normalization of the paths that go into tree digests, per
+//! `MEMOBUILD_PATH_NORMALIZATION`.
+
+use anyhow::Result;
+use std::borrow::Cow;
+use std::sync::OnceLock;
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathNormalization {
+    /// Compose names to Unicode NFC
+    pub nfc: bool,
+    /// Lowercase names, for case-insensitive file systems
+    pub case_fold: bool,
+}
+
+impl Default for PathNormalization {
+    fn default() -> Self {
+        Self {
+            nfc: true,
+            case_fold: false,
+        }
+    }
+}
+
+impl PathNormalization {
+    pub const NONE: Self = Self {
+        nfc: false,
+        case_fold: false,
+    };
+
+    pub fn parse(value: &str) -> Result<Self> {
+        let mut normalization = Self::NONE;
+        for option in value.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            match option.to_ascii_lowercase().as_str() {
+                "none" => {}
+                "nfc" => normalization.nfc = true,
+                "casefold" | "case-fold" => normalization.case_fold = true,
+                other => anyhow::bail!(
+                    "Unknown path normalization '{}' (expected nfc, casefold or none)",
+                    other
+                ),
+            }
+        }
+        Ok(normalization)
+    }
+
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("MEMOBUILD_PATH_NORMALIZATION") {
+            Ok(value) => Self::parse(&value),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// The setting for this process, read from the environment once.
+    /// Invalid values are reported by [`PathNormalization::from_env`] when
+    /// the build starts; here they fall back to the default.
+    pub fn current() -> Self {
+        static CURRENT: OnceLock<PathNormalization> = OnceLock::new();
+        *CURRENT.get_or_init(|| Self::from_env().unwrap_or_default())
+    }
+
+    pub fn apply<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        let mut name = Cow::Borrowed(name);
+        if self.nfc && is_nfc_quick(name.chars()) != IsNormalized::Yes {
+            name = Cow::Owned(name.nfc().collect());
+        }
+        if self.case_fold && name.chars().any(char::is_uppercase) {
+            name = Cow::Owned(name.to_lowercase());
+        }
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_normalization() {
+        let decomposed = "Cafe\u{301}/Menu.txt";
+        let composed = "Caf\u{e9}/Menu.txt";
+        let nfc = PathNormalization::default();
+        assert_eq!(nfc.apply(decomposed), composed);
+        assert!(matches!(nfc.apply(composed), Cow::Borrowed(_)));
+        assert_eq!(PathNormalization::NONE.apply(decomposed), decomposed);
+
+        let folded = PathNormalization::parse("nfc,casefold").unwrap();
+        assert_eq!(folded.apply(decomposed), "caf\u{e9}/menu.txt");
+        assert_eq!(
+            PathNormalization::parse("none").unwrap(),
+            PathNormalization::NONE
+        );
+        assert!(PathNormalization::parse("nfd").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+//! Progress and cancellation for hashing large build contexts.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Error returned by hashing that was cancelled through [`HashProgress::cancel`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Hashing was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+#[derive(Debug, Default)]
+pub struct HashProgress {
+    total_files: AtomicU64,
+    files: AtomicU64,
+    bytes: AtomicU64,
+    current: Mutex<String>,
+    cancelled: AtomicBool,
+}
+
+/// A point-in-time copy of a [`HashProgress`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HashProgressSnapshot {
+    /// Files found so far; grows as each source is walked
+    pub total_files: u64,
+    pub files: u64,
+    /// Bytes read; files whose digest was reused are not read
+    pub bytes: u64,
+    /// The file most recently started
+    pub current: String,
+}
+
+impl HashProgress {
+    pub fn add_total(&self, files: u64) {
+        self.total_files.fetch_add(files, Ordering::Relaxed);
+    }
+
+    pub fn file_started(&self, path: &Path) {
+        *self.current.lock().unwrap() = path.to_string_lossy().to_string();
+    }
+
+    pub fn file_done(&self) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HashProgressSnapshot {
+        HashProgressSnapshot {
+            total_files: self.total_files.load(Ordering::Relaxed),
+            files: self.files.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            current: self.current.lock().unwrap().clone(),
+        }
+    }
+
+    /// Ask every hasher using this to stop at its next check.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// `Err(Cancelled)` once [`HashProgress::cancel`] was called.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        Ok(())
+    }
+}
+
+/// Whether `error` comes from cancelled hashing.
+pub fn is_cancelled(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| e.is::<Cancelled>())
+}
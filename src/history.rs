@@ -0,0 +1,279 @@
+use crate::env::EnvFingerprint;
+use crate::graph::{BuildGraph, Node};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// File name of the history kept in the local cache directory
+pub const HISTORY_FILE: &str = "history.json";
+
+/// Weight of the newest run in the moving average
+const SMOOTHING: f64 = 0.3;
+
+/// Runs before a node's effect on its children is trusted
+const LIKELY_MIN_RUNS: u32 = 3;
+
+/// Share of runs after which every child ran too, for a node to count as
+/// invalidating its children
+const LIKELY_INVALIDATES: f64 = 0.8;
+
+/// What past builds observed for one instruction.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NodeHistory {
+    pub runs: u32,
+    /// Exponential moving average of execution time
+    pub avg_duration_ms: u64,
+    pub last_duration_ms: u64,
+    /// Size of the artifact the last run produced
+    #[serde(default)]
+    pub artifact_size: Option<u64>,
+    /// Cache key of the last run
+    pub last_hash: String,
+    /// Runs after which every node depending on it ran too
+    #[serde(default)]
+    pub children_rebuilt: u32,
+    /// CPU, memory and IO the last measured run used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<crate::sandbox::usage::ResourceUsage>,
+}
+
+/// Inputs one instruction was last built with, to tell why it rebuilds.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NodeInputs {
+    /// Cache key of the last build
+    pub hash: String,
+    /// Digest of the files it copied
+    #[serde(default)]
+    pub source_hash: Option<String>,
+    /// ENV values in scope
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+/// Totals of one whole build, kept to compare the next build against.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BuildTotals {
+    pub duration_ms: u64,
+    pub executed: usize,
+    pub cached: usize,
+    /// Totals per stage or group
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<crate::report::GroupSummary>,
+}
+
+impl BuildTotals {
+    pub fn from_report(report: &crate::report::BuildReport) -> Self {
+        use crate::report::NodeOutcome;
+        Self {
+            duration_ms: report.total_duration_ms,
+            executed: report.count(NodeOutcome::Executed) + report.count(NodeOutcome::Failed),
+            cached: report.count(NodeOutcome::Cached),
+            groups: report.groups(),
+        }
+    }
+
+    /// Totals of builds run one after another, such as one per platform.
+    pub fn sum(totals: impl IntoIterator<Item = Self>) -> Self {
+        totals.into_iter().fold(Self::default(), |mut acc, t| {
+            acc.duration_ms += t.duration_ms;
+            acc.executed += t.executed;
+            acc.cached += t.cached;
+            for group in t.groups {
+                match acc.groups.iter_mut().find(|g| g.group == group.group) {
+                    Some(sum) => {
+                        sum.nodes += group.nodes;
+                        sum.executed += group.executed;
+                        sum.cached += group.cached;
+                        sum.failed += group.failed;
+                        sum.duration_ms += group.duration_ms;
+                        sum.artifact_bytes += group.artifact_bytes;
+                    }
+                    None => acc.groups.push(group),
+                }
+            }
+            acc
+        })
+    }
+
+    /// Totals of the group named `name`.
+    pub fn group(&self, name: &str) -> Option<&crate::report::GroupSummary> {
+        self.groups.iter().find(|g| g.group == name)
+    }
+}
+
+/// Timings and artifact sizes from past builds, used to estimate upcoming ones.
+///
+/// Entries are keyed by instruction rather than cache key: a node that is
+/// about to rebuild has a key nobody has seen yet, but the same instruction
+/// usually takes about as long as it did last time.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BuildHistory {
+    #[serde(default)]
+    pub nodes: BTreeMap<String, NodeHistory>,
+    /// Builds recorded so far
+    #[serde(default)]
+    pub builds: u32,
+    #[serde(default)]
+    pub last_build: Option<BuildTotals>,
+    /// Environment of the last successful build, to report drift against
+    #[serde(default)]
+    pub last_env: Option<EnvFingerprint>,
+    /// Inputs of every instruction in the last build, cached or not
+    #[serde(default)]
+    pub inputs: BTreeMap<String, NodeInputs>,
+}
+
+impl BuildHistory {
+    /// Where the history lives for a given cache directory.
+    pub fn path_in(cache_dir: &Path) -> PathBuf {
+        cache_dir.join(HISTORY_FILE)
+    }
+
+    /// Load the history, starting empty if there is none yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn key(node: &Node) -> String {
+        node.name.clone()
+    }
+
+    pub fn get(&self, node: &Node) -> Option<&NodeHistory> {
+        self.nodes.get(&Self::key(node))
+    }
+
+    /// Record one execution of `node`.
+    pub fn record(&mut self, node: &Node, duration_ms: u64, artifact_size: Option<u64>) {
+        let entry = self.nodes.entry(Self::key(node)).or_default();
+        entry.avg_duration_ms = if entry.runs == 0 {
+            duration_ms
+        } else {
+            (SMOOTHING * duration_ms as f64 + (1.0 - SMOOTHING) * entry.avg_duration_ms as f64)
+                .round() as u64
+        };
+        entry.runs += 1;
+        entry.last_duration_ms = duration_ms;
+        if artifact_size.is_some() {
+            entry.artifact_size = artifact_size;
+        }
+        entry.last_hash = node.hash.clone();
+    }
+
+    /// Whether running `node` has almost always meant rebuilding the nodes
+    /// that depend on it, so they are worth preparing while it runs.
+    pub fn invalidates_children(&self, node: &Node) -> bool {
+        self.get(node).is_some_and(|h| {
+            h.runs >= LIKELY_MIN_RUNS
+                && h.children_rebuilt as f64 >= LIKELY_INVALIDATES * h.runs as f64
+        })
+    }
+
+    /// Record the totals of a finished build, replacing the previous ones.
+    pub fn record_build(&mut self, totals: BuildTotals) {
+        self.builds += 1;
+        self.last_build = Some(totals);
+    }
+
+    /// Inputs `node` was last built with, if a build recorded any.
+    pub fn inputs(&self, node: &Node) -> Option<&NodeInputs> {
+        self.inputs.get(&Self::key(node))
+    }
+
+    /// Record every node the executor actually ran in `graph`. Cache hits say
+    /// nothing about how long the work takes and are skipped, but the inputs
+    /// of every node are kept.
+    pub fn record_graph(&mut self, graph: &BuildGraph, size_of: impl Fn(&str) -> Option<u64>) {
+        let ran = |node: &Node| !node.cache_hit && node.metadata.execution_time_ms.is_some();
+        for (i, node) in graph.nodes.iter().enumerate() {
+            self.inputs.insert(
+                Self::key(node),
+                NodeInputs {
+                    hash: node.hash.clone(),
+                    source_hash: node.metadata.source_content_hash.clone(),
+                    env: node.metadata.build_env.clone(),
+                },
+            );
+            if node.cache_hit {
+                continue;
+            }
+            if let Some(duration_ms) = node.metadata.execution_time_ms {
+                self.record(node, duration_ms, size_of(&node.hash));
+                if let Some(usage) = node.metadata.resource_usage {
+                    if let Some(entry) = self.nodes.get_mut(&Self::key(node)) {
+                        entry.usage = Some(usage);
+                    }
+                }
+                let mut children = graph
+                    .nodes
+                    .iter()
+                    .filter(|n| n.deps.contains(&i))
+                    .peekable();
+                if children.peek().is_some() && children.all(ran) {
+                    if let Some(entry) = self.nodes.get_mut(&Self::key(node)) {
+                        entry.children_rebuilt += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::{dag, parser};
+
+    #[test]
+    fn test_record_averages_and_roundtrips() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = BuildHistory::path_in(dir.path());
+        let mut graph = dag::build_graph_from_instructions(
+            parser::parse_dockerfile("FROM alpine\nRUN make\n"),
+            PathBuf::from("."),
+        );
+        graph.nodes[0].cache_hit = true;
+        graph.nodes[0].metadata.execution_time_ms = Some(5);
+        graph.nodes[1].metadata.execution_time_ms = Some(1000);
+
+        let mut history = BuildHistory::load(&path).unwrap();
+        history.record_graph(&graph, |_| Some(42));
+        graph.nodes[1].metadata.execution_time_ms = Some(2000);
+        history.record_graph(&graph, |_| None);
+        history.save(&path).unwrap();
+
+        let history = BuildHistory::load(&path).unwrap();
+        assert!(history.get(&graph.nodes[0]).is_none());
+        let run = history.get(&graph.nodes[1]).unwrap();
+        assert_eq!(run.runs, 2);
+        assert_eq!(run.avg_duration_ms, 1300);
+        assert_eq!(run.artifact_size, Some(42));
+        assert_eq!(run.children_rebuilt, 0);
+
+        // make reruns and so does what comes after it
+        let mut graph = dag::build_graph_from_instructions(
+            parser::parse_dockerfile("FROM alpine\nRUN make\nRUN make test\n"),
+            PathBuf::from("."),
+        );
+        let mut history = BuildHistory::default();
+        for _ in 0..3 {
+            assert!(!history.invalidates_children(&graph.nodes[1]));
+            for node in &mut graph.nodes {
+                node.metadata.execution_time_ms = Some(10);
+            }
+            history.record_graph(&graph, |_| None);
+        }
+        assert!(history.invalidates_children(&graph.nodes[1]));
+        assert!(!history.invalidates_children(&graph.nodes[2]));
+    }
+}
@@ -1,3 +1,4 @@
+pub mod affected;
 pub mod ai;
 pub mod auth;
 pub mod auto_scaling;
@@ -8,6 +9,7 @@ pub mod cache_utils_exe;
 pub mod cluster_server;
 pub mod constants;
 pub mod core;
+pub mod daemon;
 
 pub mod dashboard;
 pub mod docker;
@@ -20,7 +22,9 @@ pub mod git;
 pub mod gc;
 pub mod graph;
 pub mod hasher;
+pub mod history;
 pub mod loadtest;
+pub mod lockfile;
 pub mod logging;
 pub mod metrics;
 pub mod tracing;
@@ -28,10 +32,16 @@ pub mod remote_cache;
 pub mod remote_exec;
 pub mod remote_router;
 pub mod network;
+pub mod plan;
+pub mod plugins;
+pub mod prepare;
+pub mod report;
 pub mod reproducible;
 pub mod sandbox;
 pub mod scalable_db;
 pub mod secrets;
 pub mod server;
 pub mod storage;
+pub mod tasks;
 pub mod tls;
+pub mod toolchains;
@@ -0,0 +1,257 @@
+//! `memobuild.lock` in the root of the build context: pinned base image digests
+//! and what each build resolved.
+
+use crate::graph::{BuildGraph, NodeKind};
+use crate::toolchains::InstalledToolchain;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub const LOCKFILE_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LockedImage {
+    /// Manifest digest, e.g. `sha256:...`
+    pub digest: String,
+    /// Unix timestamp of the registry lookup that produced the digest
+    pub resolved_at: i64,
+    /// Image creation time reported by the registry, when a policy needed it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<i64>,
+}
+
+/// Cache key a build computed for one node.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LockedNode {
+    pub id: usize,
+    pub name: String,
+    pub key: String,
+    /// Target platform, for builds with `--platform`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LockedToolchain {
+    pub version: String,
+    /// Hex SHA-256 of the download
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lockfile {
+    pub version: u32,
+    /// Base image references as written in FROM, mapped to their digests
+    #[serde(default)]
+    pub base_images: BTreeMap<String, LockedImage>,
+    /// GIT source URLs, mapped to the commit their HEAD was at
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub git: BTreeMap<String, String>,
+    /// Toolchains from `memobuild.toolchains.json`, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub toolchains: BTreeMap<String, LockedToolchain>,
+    /// Cache keys of the last build's nodes, in build order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nodes: Vec<LockedNode>,
+}
+
+impl Default for Lockfile {
+    fn default() -> Self {
+        Self {
+            version: LOCKFILE_VERSION,
+            base_images: BTreeMap::new(),
+            git: BTreeMap::new(),
+            toolchains: BTreeMap::new(),
+            nodes: Vec::new(),
+        }
+    }
+}
+
+impl Lockfile {
+    /// Load a lockfile, returning an empty one if it does not exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read lockfile {}", path.display()))?;
+        let lock: Lockfile = serde_json::from_str(&content)
+            .with_context(|| format!("Malformed lockfile {}", path.display()))?;
+        if lock.version > LOCKFILE_VERSION {
+            anyhow::bail!(
+                "Lockfile {} has version {}, this MemoBuild only understands up to {}",
+                path.display(),
+                lock.version,
+                LOCKFILE_VERSION
+            );
+        }
+        Ok(lock)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut lock = self.clone();
+        lock.version = LOCKFILE_VERSION;
+        let content = serde_json::to_string_pretty(&lock)?;
+        std::fs::write(path, content + "\n")
+            .with_context(|| format!("Failed to write lockfile {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn record_toolchains(&mut self, installed: &[InstalledToolchain]) {
+        self.toolchains = installed
+            .iter()
+            .map(|t| {
+                let locked = LockedToolchain {
+                    version: t.version.clone(),
+                    sha256: t.sha256.clone(),
+                };
+                (t.name.clone(), locked)
+            })
+            .collect();
+    }
+
+    /// Record the commit of every GIT source in `graph`, as told by
+    /// `resolve`. A source it cannot resolve is left out.
+    pub fn record_git(&mut self, graph: &BuildGraph, resolve: &dyn Fn(&str) -> Option<String>) {
+        self.git.clear();
+        for node in &graph.nodes {
+            if let NodeKind::Git { ref url, .. } = node.kind {
+                if self.git.contains_key(url) {
+                    continue;
+                }
+                match resolve(url) {
+                    Some(rev) => {
+                        self.git.insert(url.clone(), rev);
+                    }
+                    None => println!("   ⚠️  Could not resolve the revision of {}", url),
+                }
+            }
+        }
+    }
+
+    /// Record the cache keys of every node of each target's graph, once
+    /// they are computed.
+    pub fn record_nodes<'a>(
+        &mut self,
+        targets: impl IntoIterator<Item = (Option<String>, &'a BuildGraph)>,
+    ) {
+        self.nodes.clear();
+        for (platform, graph) in targets {
+            for node in &graph.nodes {
+                self.nodes.push(LockedNode {
+                    id: node.id,
+                    name: node.name.clone(),
+                    key: node.hash.clone(),
+                    platform: platform.clone(),
+                });
+            }
+        }
+    }
+
+    /// How `current` differs from this lockfile, one line per difference.
+    /// When base images were re-resolved only their digests count.
+    pub fn diff(&self, current: &Lockfile) -> Vec<String> {
+        let mut changes = Vec::new();
+        diff_maps(
+            "base image",
+            &digests(&self.base_images),
+            &digests(&current.base_images),
+            &mut changes,
+        );
+        diff_maps("git source", &self.git, &current.git, &mut changes);
+        let versions = |lock: &Lockfile| -> BTreeMap<String, String> {
+            lock.toolchains
+                .iter()
+                .map(|(name, t)| (name.clone(), format!("{} ({})", t.version, t.sha256)))
+                .collect()
+        };
+        diff_maps(
+            "toolchain",
+            &versions(self),
+            &versions(current),
+            &mut changes,
+        );
+        let keys = |lock: &Lockfile| -> BTreeMap<String, String> {
+            lock.nodes
+                .iter()
+                .map(|n| {
+                    let name = match n.platform {
+                        Some(ref platform) => format!("{} [{}] {}", n.id, platform, n.name),
+                        None => format!("{} {}", n.id, n.name),
+                    };
+                    (name, n.key.clone())
+                })
+                .collect()
+        };
+        diff_maps("node", &keys(self), &keys(current), &mut changes);
+        changes
+    }
+}
+
+fn digests(images: &BTreeMap<String, LockedImage>) -> BTreeMap<String, String> {
+    images
+        .iter()
+        .map(|(image, locked)| (image.clone(), locked.digest.clone()))
+        .collect()
+}
+
+fn diff_maps(
+    what: &str,
+    locked: &BTreeMap<String, String>,
+    current: &BTreeMap<String, String>,
+    changes: &mut Vec<String>,
+) {
+    for (name, value) in current {
+        match locked.get(name) {
+            None => changes.push(format!("{} {} is not in the lockfile", what, name)),
+            Some(old) if old != value => {
+                changes.push(format!("{} {} changed: {} -> {}", what, name, old, value))
+            }
+            Some(_) => {}
+        }
+    }
+    for name in locked.keys().filter(|name| !current.contains_key(*name)) {
+        changes.push(format!("{} {} is no longer used", what, name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::dag::build_graph_from_instructions;
+    use crate::docker::parser::parse_dockerfile;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_frozen_diff() {
+        let mut graph = build_graph_from_instructions(
+            parse_dockerfile("FROM alpine\nGIT https://example.com/repo.git /src\nRUN make\n"),
+            PathBuf::from("."),
+        );
+        for node in &mut graph.nodes {
+            node.hash = format!("key{}", node.id);
+        }
+
+        let mut locked = Lockfile::default();
+        locked.record_git(&graph, &|_| Some("abc123".to_string()));
+        locked.record_nodes([(None, &graph)]);
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("memobuild.lock");
+        locked.save(&path).unwrap();
+        let locked = Lockfile::load(&path).unwrap();
+        assert_eq!(locked.git.len(), 1);
+        assert_eq!(locked.nodes.len(), 3);
+
+        let mut current = locked.clone();
+        assert!(locked.diff(&current).is_empty());
+
+        graph.nodes[2].hash = "other".to_string();
+        current.record_nodes([(None, &graph)]);
+        current.record_git(&graph, &|_| Some("def456".to_string()));
+        let changes = locked.diff(&current);
+        assert_eq!(changes.len(), 2, "{:?}", changes);
+        assert!(changes.iter().any(|c| c.contains("abc123 -> def456")));
+        assert!(changes.iter().any(|c| c.contains("key2 -> other")));
+    }
+}
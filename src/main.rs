@@ -1,10 +1,11 @@
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
+use memobuild::env::Platform;
 use memobuild::server;
 use memobuild::{cache, docker, executor, export, logging, core};
 use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio_postgres::NoTls;
 
@@ -40,17 +41,103 @@ enum Commands {
         #[arg(long)]
         reproducible: bool,
 
-        /// Perform a dry run without executing commands
+        /// Print what would be cached, downloaded and run, without building
         #[arg(long)]
         dry_run: bool,
 
-        /// Use a specific sandbox runtime (local, containerd)
+        /// With --dry-run, also write the plan as JSON to this file
+        #[arg(long, requires = "dry_run")]
+        plan_output: Option<PathBuf>,
+
+        /// Use a specific sandbox runtime (local, overlay, containerd)
         #[arg(long)]
         sandbox: Option<String>,
 
         /// Use remote execution via scheduler
         #[arg(long)]
         remote_exec: bool,
+
+        /// Run commands as Kubernetes Jobs (configured with MEMOBUILD_K8S_*)
+        #[arg(long, conflicts_with = "remote_exec")]
+        k8s: bool,
+
+        /// Use base image digests from memobuild.lock instead of resolving tags
+        #[arg(long)]
+        locked: bool,
+
+        /// Fail if base image digests, GIT revisions, toolchains or node
+        /// cache keys differ from those recorded in memobuild.lock
+        #[arg(long)]
+        frozen: bool,
+
+        /// Fail instead of warning when the environment fingerprint differs
+        /// from the previous build's
+        #[arg(long)]
+        frozen_env: bool,
+
+        /// Build for these platforms (comma-separated, e.g.
+        /// linux/amd64,linux/arm64) and export a multi-platform image index
+        #[arg(long = "platform", value_name = "PLATFORMS", value_delimiter = ',')]
+        platforms: Vec<Platform>,
+
+        /// Write an SBOM (spdx or cyclonedx) next to the exported image
+        #[arg(long, value_name = "FORMAT")]
+        sbom: Option<export::sbom::SbomFormat>,
+
+        /// Write a SLSA provenance attestation next to the exported image,
+        /// signed when MEMOBUILD_PROVENANCE_KEY is set
+        #[arg(long)]
+        provenance: bool,
+
+        /// Write a standalone HTML report of the build to this file, also
+        /// when the build fails
+        #[arg(long, value_name = "PATH")]
+        html_report: Option<PathBuf>,
+
+        /// Write node outcomes as a JUnit XML test report to this file, also
+        /// when the build fails
+        #[arg(long, value_name = "PATH")]
+        junit: Option<PathBuf>,
+
+        /// Report lint findings, Dockerfile errors and failed nodes as CI
+        /// annotations (github or gitlab)
+        #[arg(long, value_name = "FORMAT", env = "MEMOBUILD_ANNOTATIONS")]
+        annotations: Option<export::annotations::AnnotationFormat>,
+
+        /// Start the built image with Docker and run its HEALTHCHECK before
+        /// pushing; the build fails if the check does
+        #[arg(long)]
+        smoke_test: bool,
+
+        /// Also write the image as a `docker load` archive to this file
+        #[arg(long, value_name = "PATH")]
+        docker_archive: Option<PathBuf>,
+
+        /// Store the context, Dockerfile and environment fingerprint under
+        /// the build's id, so `memobuild reproduce` can rerun the build
+        #[arg(long)]
+        snapshot: bool,
+
+        /// Fail on Dockerfile syntax errors instead of skipping the
+        /// malformed lines
+        #[arg(long)]
+        strict: bool,
+
+        /// Re-hash every local cache entry before building and remove
+        /// the ones that are missing, truncated or corrupted
+        #[arg(long)]
+        verify_cache: bool,
+
+        /// How many independent steps run at once: a number, or `auto` to
+        /// follow CPU load, memory pressure and IO wait; all by default.
+        /// `io=N` and `cpu=N` limit download and compile steps separately
+        #[arg(short, long, value_name = "N|auto", env = "MEMOBUILD_JOBS")]
+        jobs: Option<memobuild::execution::Concurrency>,
+
+        /// What to do when a step's command fails: `fail`, or `shell` to
+        /// open an interactive shell in its sandbox first
+        #[arg(long, value_name = "ACTION", default_value = "fail")]
+        on_failure: memobuild::sandbox::debug::OnFailure,
     },
     /// Visualize the dependency graph
     Graph {
@@ -62,6 +149,63 @@ enum Commands {
         #[arg(short, long, default_value = "Dockerfile")]
         file: String,
     },
+    /// Show which steps a Dockerfile change invalidates
+    Diff {
+        /// Old Dockerfile: a path, `<git-rev>:<path>` or a graph JSON file
+        old: String,
+
+        /// New Dockerfile, in the same forms
+        #[arg(default_value = "Dockerfile")]
+        new: String,
+
+        /// Path to the build context
+        #[arg(short, long, default_value = ".")]
+        path: PathBuf,
+
+        /// Emit the diff as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show which steps changes to the given files would rebuild, without
+    /// building; reads paths from stdin, one per line, when none are given
+    Affected {
+        /// Changed files, e.g. from `git diff --name-only`
+        paths: Vec<PathBuf>,
+
+        /// Path to the build context
+        #[arg(short, long, default_value = ".")]
+        path: PathBuf,
+
+        /// Path to the Dockerfile
+        #[arg(short, long, default_value = "Dockerfile")]
+        file: String,
+
+        /// Emit the affected steps as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Report Dockerfile diagnostics (unpinned images, cache-busting COPY, ...)
+    Lint {
+        /// Path to the build context
+        #[arg(default_value = ".")]
+        path: PathBuf,
+
+        /// Path to the Dockerfile
+        #[arg(short, long, default_value = "Dockerfile")]
+        file: String,
+
+        /// Emit diagnostics as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Rewrite the Dockerfile with the suggested cache-friendly order
+        #[arg(long)]
+        fix: bool,
+
+        /// Also report findings as CI annotations (github or gitlab)
+        #[arg(long, value_name = "FORMAT", env = "MEMOBUILD_ANNOTATIONS")]
+        annotations: Option<export::annotations::AnnotationFormat>,
+    },
     /// Explain the cache status for a specific node
     ExplainCache {
         /// Path to the build context
@@ -75,6 +219,98 @@ enum Commands {
         /// Specific node ID or name to explain (optional)
         node: Option<String>,
     },
+    /// Keep source hashes warm by watching the context, so builds skip the scan
+    Daemon {
+        /// Path to the build context
+        #[arg(default_value = ".")]
+        path: PathBuf,
+
+        /// Path to the Dockerfile
+        #[arg(short, long, default_value = "Dockerfile")]
+        file: String,
+    },
+    /// Inspect and manage cached artifacts
+    Cache {
+        #[command(subcommand)]
+        action: CacheCommands,
+    },
+    /// Rebuild a past build from the inputs `build --snapshot` stored
+    Reproduce {
+        /// Id of the build, as printed by `build --snapshot`
+        build_id: String,
+
+        /// Build context the snapshot was taken of, whose cache holds it
+        #[arg(long, default_value = ".")]
+        path: PathBuf,
+
+        /// Reuse cached steps instead of running every step again in an
+        /// empty cache
+        #[arg(long)]
+        use_cache: bool,
+
+        /// Fail instead of warning when the environment differs from the
+        /// snapshot's
+        #[arg(long)]
+        frozen_env: bool,
+    },
+    /// Write steps of the build with their cache keys, for `run-graph` to
+    /// execute on other machines
+    ExportGraph {
+        /// Path to the build context
+        #[arg(default_value = ".")]
+        path: PathBuf,
+
+        /// Path to the Dockerfile
+        #[arg(short, long, default_value = "Dockerfile")]
+        file: String,
+
+        /// Step to export, by id, name or instruction, with the steps it
+        /// depends on; repeat for more. Every step when none is given
+        #[arg(long = "step", value_name = "STEP")]
+        steps: Vec<String>,
+
+        /// File to write the graph to
+        #[arg(short, long, default_value = "memobuild-graph.json")]
+        output: PathBuf,
+    },
+    /// Execute a graph written by `export-graph` with the cache keys it
+    /// carries
+    RunGraph {
+        /// Graph file written by `export-graph`
+        graph: PathBuf,
+
+        /// Build context RUN steps run in
+        #[arg(long, default_value = ".")]
+        path: PathBuf,
+
+        /// Use a specific sandbox runtime (local, overlay, containerd)
+        #[arg(long)]
+        sandbox: Option<String>,
+
+        /// Fail instead of warning when the environment differs from the
+        /// one the keys were computed in
+        #[arg(long)]
+        frozen_env: bool,
+
+        /// How many independent steps run at once, as for `build`
+        #[arg(short, long, value_name = "N|auto", env = "MEMOBUILD_JOBS")]
+        jobs: Option<memobuild::execution::Concurrency>,
+    },
+    /// Check a signed provenance attestation and print its statement
+    VerifyProvenance {
+        /// Attestation written by `build --provenance`
+        file: PathBuf,
+
+        /// Ed25519 public key of the signer (hex or base64)
+        #[arg(long, env = "MEMOBUILD_PROVENANCE_PUBLIC_KEY")]
+        public_key: String,
+    },
+    /// Helpers run inside Kubernetes build Jobs
+    #[command(hide = true)]
+    Pod {
+        #[command(subcommand)]
+        action: PodCommands,
+    },
     /// Start the Remote Cache Server
     Server {
         /// Port to listen on
@@ -88,6 +324,26 @@ enum Commands {
         /// PostgreSQL connection string
         #[arg(long, env = "DATABASE_URL")]
         database_url: Option<String>,
+
+        /// Storage backend: local, s3, gcs, azure, or memory to keep
+        /// artifacts and metadata in memory only
+        #[arg(long)]
+        storage: Option<memobuild::storage::StorageBackend>,
+    },
+    /// Copy a cache server's artifacts and layers to another storage
+    /// backend, verifying each one; resumes an interrupted migration
+    MigrateStorage {
+        /// Current storage URI (a path, s3://, gs:// or az://)
+        #[arg(long)]
+        from: String,
+
+        /// New storage URI
+        #[arg(long)]
+        to: String,
+
+        /// The server's data directory, holding metadata.db
+        #[arg(long, default_value = ".memobuild-server")]
+        data_dir: PathBuf,
     },
     /// Start the Execution Scheduler
     Scheduler {
@@ -141,6 +397,126 @@ enum Commands {
         /// PostgreSQL connection string
         #[arg(long, env = "DATABASE_URL")]
         database_url: Option<String>,
+
+        /// Keep artifacts and metadata in memory only (`memory`), or on
+        /// local disk (`local`, the default)
+        #[arg(long)]
+        storage: Option<memobuild::storage::StorageBackend>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommands {
+    /// Drop a cache key so the step that produced it rebuilds
+    Invalidate {
+        /// Cache key, as shown by `explain-cache`
+        key: String,
+
+        /// Leave the remote cache untouched
+        #[arg(long)]
+        local_only: bool,
+    },
+    /// Drop entries whose keys were derived by another MemoBuild key version
+    Migrate {
+        /// Leave the remote cache untouched
+        #[arg(long)]
+        local_only: bool,
+    },
+    /// Drop local entries not written or read for a number of days
+    Prune {
+        /// Days an entry may go unused before it is dropped
+        #[arg(long, default_value_t = 30)]
+        idle_days: u64,
+
+        /// Also drop least recently used entries until the cache fits this
+        /// size, e.g. 20G
+        #[arg(long)]
+        max_size: Option<String>,
+
+        /// List what would be dropped and why, without dropping it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Pin cache entries under a label so GC and key migration keep them
+    Pin {
+        /// Cache key of the entry to pin
+        #[arg(required_unless_present = "build", conflicts_with = "build")]
+        key: Option<String>,
+
+        /// Pin every entry stored by the build with this MEMOBUILD_BUILD_ID
+        #[arg(long)]
+        build: Option<String>,
+
+        /// Label recorded with the pin, e.g. a release version
+        #[arg(long, default_value = "keep")]
+        label: String,
+
+        /// Leave the remote cache untouched
+        #[arg(long)]
+        local_only: bool,
+    },
+    /// Remove the pin from cache entries, leaving them to GC again
+    Unpin {
+        /// Cache key of the entry to unpin
+        #[arg(required_unless_present = "build", conflicts_with = "build")]
+        key: Option<String>,
+
+        /// Unpin every entry stored by the build with this MEMOBUILD_BUILD_ID
+        #[arg(long)]
+        build: Option<String>,
+
+        /// Leave the remote cache untouched
+        #[arg(long)]
+        local_only: bool,
+    },
+    /// Seed the cache with the layers of an image Docker already built from
+    /// the Dockerfile
+    ImportDocker {
+        /// Image to import, or the path of an archive with `--store archive`
+        image: String,
+
+        /// Where the image is read from: docker, containerd or archive
+        #[arg(long, default_value = "docker")]
+        store: memobuild::docker::import::ImageStore,
+
+        /// Path to the build context
+        #[arg(long, default_value = ".")]
+        path: PathBuf,
+
+        /// Path to the Dockerfile
+        #[arg(short, long, default_value = "Dockerfile")]
+        file: String,
+
+        /// Leave the remote cache untouched
+        #[arg(long)]
+        local_only: bool,
+    },
+    /// Check that the remote cache's transparency log only grew since the
+    /// last audit, and list the artifacts inserted in between
+    Audit {
+        /// Print every new entry, not just the count
+        #[arg(long)]
+        verbose: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum PodCommands {
+    /// Restore a node's input files from the remote cache
+    FetchInputs {
+        #[arg(long)]
+        manifest: String,
+        #[arg(long)]
+        dest: PathBuf,
+    },
+    /// Wait for the build container, then store its workspace as the artifact
+    UploadOutputs {
+        #[arg(long)]
+        key: String,
+        #[arg(long)]
+        dir: PathBuf,
+        #[arg(long)]
+        exit_code_file: PathBuf,
     },
 }
 
@@ -148,6 +524,21 @@ enum Commands {
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    // Pick the project-scoped local cache for the build context, not the cwd
+    match &cli.command {
+        Commands::Build { path, .. }
+        | Commands::Graph { path, .. }
+        | Commands::Diff { path, .. }
+        | Commands::Affected { path, .. }
+        | Commands::Lint { path, .. }
+        | Commands::ExplainCache { path, .. }
+        | Commands::Reproduce { path, .. }
+        | Commands::ExportGraph { path, .. }
+        | Commands::RunGraph { path, .. }
+        | Commands::Daemon { path, .. } => memobuild::cache::local::set_workspace(path),
+        _ => {}
+    }
+
     // Initialize structured logging
     logging::init_logging(cli.json_logs).ok();
 
@@ -158,8 +549,26 @@ async fn main() -> Result<()> {
             push,
             reproducible,
             dry_run,
+            plan_output,
             sandbox,
             remote_exec,
+            k8s,
+            locked,
+            frozen,
+            frozen_env,
+            platforms,
+            sbom,
+            provenance,
+            html_report,
+            junit,
+            annotations,
+            smoke_test,
+            docker_archive,
+            snapshot,
+            strict,
+            verify_cache,
+            jobs,
+            on_failure,
         } => {
             run_build(
                 path,
@@ -167,14 +576,164 @@ async fn main() -> Result<()> {
                 push,
                 reproducible,
                 dry_run,
+                plan_output,
                 sandbox,
                 remote_exec,
+                k8s,
+                locked,
+                frozen,
+                frozen_env,
+                platforms,
+                sbom,
+                provenance,
+                html_report,
+                junit,
+                annotations,
+                smoke_test,
+                docker_archive,
+                snapshot,
+                strict,
+                verify_cache,
+                jobs.unwrap_or_default(),
+                on_failure,
+                None,
             )
             .await
         }
         Commands::Graph { path, file } => run_graph(path, file).await,
+        Commands::Diff {
+            old,
+            new,
+            path,
+            json,
+        } => run_diff(path, old, new, json).await,
+        Commands::Affected {
+            paths,
+            path,
+            file,
+            json,
+        } => run_affected(path, file, paths, json),
+        Commands::Lint {
+            path,
+            file,
+            json,
+            fix,
+            annotations,
+        } => run_lint(path, file, json, fix, annotations).await,
         Commands::ExplainCache { path, file, node } => run_explain_cache(path, file, node).await,
-        Commands::Server { port, postgres, database_url } => {
+        Commands::Daemon { path, file } => {
+            let cache = Arc::new(create_cache().await?);
+            let (build_path, build_file) = (path.clone(), file.clone());
+            let jobs = match std::env::var("MEMOBUILD_JOBS") {
+                Ok(jobs) => jobs.parse().context("Invalid MEMOBUILD_JOBS")?,
+                Err(_) => memobuild::execution::Concurrency::default(),
+            };
+            let build: memobuild::daemon::BuildFn = Arc::new(move |params, observer| {
+                Box::pin(run_build(
+                    build_path.clone(),
+                    build_file.clone(),
+                    false,
+                    params.reproducible,
+                    params.dry_run,
+                    None,
+                    None,
+                    false,
+                    false,
+                    false,
+                    false,
+                    false,
+                    Vec::new(),
+                    None,
+                    false,
+                    None,
+                    None,
+                    None,
+                    false,
+                    None,
+                    false,
+                    false,
+                    false,
+                    jobs.clone(),
+                    memobuild::sandbox::debug::OnFailure::Fail,
+                    Some(observer),
+                ))
+            });
+            memobuild::daemon::run(&path, Path::new(&file), cache, Some(build)).await
+        }
+        Commands::Cache { action } => match action {
+            CacheCommands::Invalidate { key, local_only } => {
+                run_cache_invalidate(key, local_only).await
+            }
+            CacheCommands::Migrate { local_only } => run_cache_migrate(local_only).await,
+            CacheCommands::Prune {
+                idle_days,
+                max_size,
+                dry_run,
+            } => run_cache_prune(idle_days, max_size, dry_run),
+            CacheCommands::Pin {
+                key,
+                build,
+                label,
+                local_only,
+            } => run_cache_pin(key, build, Some(label), local_only).await,
+            CacheCommands::Unpin {
+                key,
+                build,
+                local_only,
+            } => run_cache_pin(key, build, None, local_only).await,
+            CacheCommands::ImportDocker {
+                image,
+                store,
+                path,
+                file,
+                local_only,
+            } => run_cache_import_docker(image, store, path, file, local_only).await,
+            CacheCommands::Audit { verbose } => run_cache_audit(verbose).await,
+        },
+        Commands::Reproduce {
+            build_id,
+            use_cache,
+            frozen_env,
+            ..
+        } => run_reproduce(build_id, use_cache, frozen_env).await,
+        Commands::ExportGraph {
+            path,
+            file,
+            steps,
+            output,
+        } => run_export_graph(path, file, steps, output).await,
+        Commands::RunGraph {
+            graph,
+            path,
+            sandbox,
+            frozen_env,
+            jobs,
+        } => run_imported_graph(graph, path, sandbox, frozen_env, jobs.unwrap_or_default()).await,
+        Commands::VerifyProvenance { file, public_key } => {
+            run_verify_provenance(&file, &public_key)
+        }
+        Commands::Pod { action } => {
+            let cache = Arc::new(create_cache().await?);
+            match action {
+                PodCommands::FetchInputs { manifest, dest } => {
+                    memobuild::execution::kubernetes::fetch_inputs(cache, &manifest, &dest).await
+                }
+                PodCommands::UploadOutputs {
+                    key,
+                    dir,
+                    exit_code_file,
+                } => {
+                    memobuild::execution::kubernetes::upload_outputs(
+                        &cache,
+                        &key,
+                        &dir,
+                        &exit_code_file,
+                    )
+                    .await
+                }
+            }
+        }
+        Commands::Server { port, postgres, database_url, storage } => {
             let webhook_url = env::var("MEMOBUILD_WEBHOOK").ok();
             let data_dir = env::current_dir()?.join(".memobuild-server");
             fs::create_dir_all(&data_dir)?;
@@ -214,178 +773,1765 @@ async fn main() -> Result<()> {
                 None
             };
 
-            server::start_server(port, data_dir, webhook_url, tls_config, admin_token, auth_db_client).await
+            server::start_server(port, data_dir, webhook_url, tls_config, admin_token, auth_db_client, storage).await
+        }
+        Commands::MigrateStorage { from, to, data_dir } => {
+            run_migrate_storage(from, to, data_dir).await
+        }
+        Commands::Scheduler { port } => start_scheduler(port).await,
+        Commands::Worker {
+            port,
+            sandbox,
+            scheduler_url,
+        } => start_worker(port, sandbox, scheduler_url).await,
+        Commands::Pull { image } => run_pull(image).await,
+        Commands::GenerateCi { provider } => run_generate_ci(provider).await,
+        Commands::Cluster {
+            port,
+            node_id,
+            peers,
+            postgres,
+            database_url,
+            storage,
+        } => start_cluster_server(port, node_id, peers, postgres, database_url, storage).await,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_build(
+    context_dir: PathBuf,
+    dockerfile_path: String,
+    push: bool,
+    reproducible: bool,
+    dry_run: bool,
+    plan_output: Option<PathBuf>,
+    sandbox_type: Option<String>,
+    remote_exec: bool,
+    k8s: bool,
+    locked: bool,
+    frozen: bool,
+    frozen_env: bool,
+    platforms: Vec<Platform>,
+    sbom: Option<export::sbom::SbomFormat>,
+    provenance: bool,
+    html_report: Option<PathBuf>,
+    junit: Option<PathBuf>,
+    annotations: Option<export::annotations::AnnotationFormat>,
+    smoke_test: bool,
+    docker_archive: Option<PathBuf>,
+    snapshot: bool,
+    strict: bool,
+    verify_cache: bool,
+    jobs: memobuild::execution::Concurrency,
+    on_failure: memobuild::sandbox::debug::OnFailure,
+    observer: Option<Arc<dyn memobuild::dashboard::BuildObserver>>,
+) -> Result<()> {
+    println!("🚀 MemoBuild Engine Starting...");
+    if platforms.len() > 1 && (sbom.is_some() || provenance) {
+        anyhow::bail!(
+            "--sbom and --provenance describe a single image and cannot be combined with several platforms yet"
+        );
+    }
+    if platforms.len() > 1 && smoke_test {
+        anyhow::bail!(
+            "--smoke-test runs a single image and cannot be combined with several platforms yet"
+        );
+    }
+    if platforms.len() > 1 && docker_archive.is_some() {
+        anyhow::bail!(
+            "--docker-archive holds a single image and cannot be combined with several platforms"
+        );
+    }
+
+    let mut env_fp = memobuild::env::EnvFingerprint::collect();
+    let cache = Arc::new(create_cache().await?);
+    if verify_cache {
+        println!("🔍 Verifying local cache...");
+        let report = cache.local.verify()?;
+        for (key, damage) in &report.removed {
+            println!("   {}", format!("Removed {}: {}", key, damage).yellow());
+        }
+        println!(
+            "   {} entries checked, {} damaged",
+            report.checked,
+            report.removed.len()
+        );
+    } else if let Some(interval) = cache::local::verify_interval_from_env() {
+        cache.local.verify_if_due(interval);
+    }
+
+    let mut toolchain_path = Vec::new();
+    let mut toolchains = Vec::new();
+    if let Some(manifest) = memobuild::toolchains::ToolchainManifest::load(&context_dir)? {
+        println!(
+            "🧰 Installing {} pinned toolchain(s)...",
+            manifest.tools.len()
+        );
+        let installed = memobuild::toolchains::install(
+            &manifest,
+            &cache,
+            &cache.local.cache_dir().join("toolchains"),
+        )
+        .await?;
+        for t in &installed {
+            println!("   {} {} -> {}", t.name, t.version, t.bin_dir.display());
+        }
+        env_fp.pin_toolchains(&installed);
+        toolchain_path = installed.iter().map(|t| t.bin_dir.clone()).collect();
+        toolchains = installed;
+    }
+    println!("   🔑 Env Fingerprint: {}", &env_fp.hash()[..8]);
+
+    let dockerfile = fs::read_to_string(&dockerfile_path)
+        .with_context(|| format!("Failed to read Dockerfile at {}", dockerfile_path))?;
+
+    if snapshot && !dry_run {
+        use memobuild::reproducible::snapshot;
+        let build_id = snapshot::build_id();
+        // Artifacts and decisions recorded by this build carry the same id
+        std::env::set_var("MEMOBUILD_BUILD_ID", &build_id);
+        let info = snapshot::SnapshotInfo {
+            build_id,
+            created_at: chrono::Utc::now().to_rfc3339(),
+            dockerfile: Path::new(&dockerfile_path)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| "Dockerfile".to_string()),
+            platforms: platforms.iter().map(|p| p.to_string()).collect(),
+            reproducible,
+            env: env_fp.clone(),
+        };
+        let path = snapshot::path_in(cache.local.cache_dir(), &info.build_id)?;
+        let bytes = snapshot::capture(&path, &info, &context_dir, Path::new(&dockerfile_path))?;
+        println!(
+            "   📸 Snapshot of build {} ({})",
+            info.build_id,
+            memobuild::plan::format_bytes(bytes)
+        );
+    }
+
+    let mut ci_annotations = Vec::new();
+    let mut graph = if memobuild::tasks::is_manifest(Path::new(&dockerfile_path)) {
+        println!("📄 Loading task manifest...");
+        memobuild::tasks::TaskManifest::parse(&dockerfile)
+            .and_then(|manifest| manifest.into_graph())
+            .with_context(|| format!("Invalid task manifest {}", dockerfile_path))?
+    } else {
+        println!("📄 Parsing Dockerfile...");
+        let instructions =
+            docker::parser::parse_dockerfile_spanned(&dockerfile, Path::new(&dockerfile_path));
+
+        let mut syntax_errors = 0;
+        for diagnostic in docker::lint::lint_dockerfile(
+            &dockerfile,
+            Path::new(&dockerfile_path),
+            &context_dir,
+        ) {
+            if diagnostic.rule == docker::lint::LintRule::SyntaxError {
+                syntax_errors += 1;
+            }
+            println!("   {}", diagnostic.to_string().yellow());
+            ci_annotations.push(export::annotations::Annotation::from(&diagnostic));
+        }
+        if syntax_errors > 0 {
+            if strict {
+                if let Some(format) = annotations {
+                    emit_annotations(format, &ci_annotations);
+                }
+                anyhow::bail!("{} Dockerfile syntax error(s)", syntax_errors);
+            }
+            println!(
+                "   ⚠️  Skipping {} malformed line(s); --strict fails the build instead",
+                syntax_errors
+            );
+        }
+
+        println!("📊 Building DAG for context: {}...", context_dir.display());
+        match docker::include::build_graph_with_includes(instructions, context_dir.clone()) {
+            Ok(graph) => graph,
+            Err(e) => {
+                if let Some(format) = annotations {
+                    ci_annotations.push(export::annotations::Annotation::from_error(&e));
+                    emit_annotations(format, &ci_annotations);
+                }
+                return Err(e);
+            }
+        }
+    };
+
+    println!("📌 Pinning base images...");
+    let lock_path = context_dir.join(memobuild::constants::LOCKFILE_NAME);
+    let mut lock = memobuild::lockfile::Lockfile::load(&lock_path)?;
+    // What --frozen compares this build's resolution against
+    let recorded = lock.clone();
+    let mode = if locked {
+        docker::resolve::LockMode::Locked
+    } else {
+        docker::resolve::LockMode::Resolve
+    };
+    let aliases = docker::resolve::stage_aliases(&dockerfile);
+    let policies = docker::policy::PolicySet::from_env();
+    let default_max_age = docker::resolve::default_max_age_from_env()?;
+    // Registry lookups use the blocking client
+    let pinned = tokio::task::block_in_place(|| {
+        docker::resolve::pin_base_images(
+            &mut graph,
+            &mut lock,
+            mode,
+            &docker::resolve::RegistryDigestSource,
+            &aliases,
+            &policies,
+            default_max_age,
+        )
+    })?;
+    if pinned.changed && !frozen {
+        lock.save(&lock_path)?;
+        println!("   Updated {}", lock_path.display());
+    }
+    // --frozen never writes the lockfile
+    let revalidate = if frozen {
+        Vec::new()
+    } else {
+        pinned.revalidate
+    };
+    if !revalidate.is_empty() {
+        println!(
+            "   ⏱️  {} base image(s) within max-age, revalidating in the background",
+            revalidate.len()
+        );
+    }
+    // Joined when the build returns, so the next build sees the new digests
+    let _revalidation = docker::resolve::Revalidation::spawn(
+        lock_path.clone(),
+        revalidate,
+        Box::new(docker::resolve::RegistryDigestSource),
+    );
+
+    let settings = memobuild::prepare::configure_steps(&mut graph, &context_dir, &env_fp)?;
+    if settings.plugin_steps > 0 {
+        println!("   🧩 {} step(s) handled by plugins", settings.plugin_steps);
+    }
+    if settings.workers > 0 {
+        println!(
+            "   🔁 {} RUN step(s) run on persistent workers",
+            settings.workers
+        );
+    }
+    let plugins = settings.plugins;
+
+    // Reported here rather than silently defaulted while hashing
+    memobuild::hasher::PathNormalization::from_env()?;
+    println!("🔍 Detecting changes (filesystem hashing)...");
+    let merkle_path = memobuild::hasher::MerkleState::path_in(cache.local.cache_dir());
+    let mut merkle = memobuild::hasher::MerkleState::load(&merkle_path);
+    let ignore = memobuild::hasher::IgnoreRules::from_file(&context_dir.join(".dockerignore"));
+    let filters = memobuild::hasher::ContentFilters::load(&context_dir)?;
+    if !filters.is_empty() {
+        println!(
+            "   🧹 Hashing sources through {}",
+            memobuild::hasher::filters::FILTERS_FILE
+        );
+    }
+    let live = memobuild::daemon::sync(cache.local.cache_dir(), &context_dir).await;
+    if live.is_some() {
+        println!("   ⚡ Using source hashes from the memobuild daemon");
+    }
+    hash_sources_with_progress(
+        &mut graph,
+        &mut merkle,
+        live.as_ref(),
+        &ignore,
+        &filters,
+        observer.clone(),
+    )
+    .await?;
+
+    if memobuild::sandbox::trace::enabled_from_env()
+        && !memobuild::sandbox::trace::tracer_available()
+    {
+        println!(
+            "⚠️  MEMOBUILD_TRACE_INPUTS is set but strace is unavailable; RUN steps are not traced"
+        );
+    }
+    let trace_path = memobuild::sandbox::trace::TraceStore::path_in(cache.local.cache_dir());
+    let store = memobuild::prepare::load_traces(cache.local.cache_dir())?;
+
+    println!("🔑 Computing cache keys...");
+    let stats =
+        memobuild::prepare::finish_keys(&mut graph, &cache, &context_dir, &env_fp, store.as_ref())
+            .await?;
+    if stats.downloaded > 0 {
+        println!("   🌐 Downloaded {} ADD source(s)", stats.downloaded);
+    }
+    if stats.extracted > 0 {
+        println!(
+            "   📦 Extracted {} COPY --from image source(s)",
+            stats.extracted
+        );
+    }
+    if stats.traced > 0 {
+        println!(
+            "   🔬 {} RUN step(s) keyed by the files they read",
+            stats.traced
+        );
+    }
+    let traces = store.map(|store| Arc::new(std::sync::Mutex::new(store)));
+
+    let history_path = memobuild::history::BuildHistory::path_in(cache.local.cache_dir());
+    let mut history = memobuild::history::BuildHistory::load(&history_path)?;
+    core::explain_changes(&mut graph, &history);
+
+    if let Some(ref previous) = history.last_env {
+        let changes = memobuild::env::drift::diff(previous, &env_fp);
+        if !changes.is_empty() {
+            let invalidated = memobuild::env::drift::invalidated_nodes(&graph, previous, &env_fp);
+            println!(
+                "{}",
+                "⚠️  Environment changed since the previous build:".yellow()
+            );
+            for change in &changes {
+                println!("   • {}", change);
+            }
+            if invalidated > 0 {
+                println!("   {} node(s) invalidated by the change", invalidated);
+            } else {
+                println!(
+                    "   Only the platform is part of cache keys; cached nodes are reused as is"
+                );
+            }
+            if frozen_env {
+                anyhow::bail!(
+                    "Environment drift with --frozen-env ({} change(s), {} node(s) invalidated)",
+                    changes.len(),
+                    invalidated
+                );
+            }
+        }
+    }
+
+    // One instance of the graph per target platform; without --platform the
+    // graph hashed above for this machine is the only one
+    let mut targets: Vec<(Option<Platform>, memobuild::graph::BuildGraph)> = Vec::new();
+    for platform in &platforms {
+        let instance = memobuild::env::platform::instantiate(&graph, &env_fp, platform);
+        targets.push((Some(platform.clone()), instance));
+    }
+    if targets.is_empty() {
+        targets.push((None, graph));
+    }
+    let multi = targets.len() > 1;
+
+    let mut resolution = lock.clone();
+    resolution.record_toolchains(&toolchains);
+    tokio::task::block_in_place(|| {
+        resolution.record_git(&targets[0].1, &|url| {
+            memobuild::git::get_remote_head_hash(url).ok()
+        })
+    });
+    resolution.record_nodes(
+        targets
+            .iter()
+            .map(|(platform, graph)| (platform.as_ref().map(|p| p.to_string()), graph)),
+    );
+    let changes = recorded.diff(&resolution);
+    if frozen && !changes.is_empty() {
+        for change in &changes {
+            println!("   • {}", change);
+        }
+        anyhow::bail!(
+            "{} differs from this build's resolution with --frozen ({} change(s))",
+            lock_path.display(),
+            changes.len()
+        );
+    }
+    if !changes.is_empty() {
+        resolution.save(&lock_path)?;
+    }
+
+    if dry_run {
+        let mut plans = std::collections::BTreeMap::new();
+        for (platform, graph) in &targets {
+            if let Some(platform) = platform {
+                println!("\n🧩 {}", platform.to_string().bold());
+            }
+            let plan = memobuild::plan::plan_build(graph, &cache, &history).await?;
+            plan.print_table();
+            plans.insert(
+                platform.as_ref().map(|p| p.to_string()).unwrap_or_default(),
+                plan,
+            );
+        }
+        if let Some(path) = plan_output {
+            let json = if platforms.is_empty() {
+                serde_json::to_string_pretty(&plans[""])?
+            } else {
+                serde_json::to_string_pretty(&plans)?
+            };
+            fs::write(&path, json)
+                .with_context(|| format!("Failed to write plan to {}", path.display()))?;
+            println!("   Plan written to {}", path.display());
+        }
+        return Ok(());
+    }
+
+    for platform in targets.iter().filter_map(|(p, _)| p.as_ref()) {
+        if !platform.runnable() {
+            println!(
+                "{}",
+                format!(
+                    "⚠️  {} binaries cannot run here (no QEMU binfmt handler); its RUN steps must cross-compile using $TARGETARCH",
+                    platform
+                )
+                .yellow()
+            );
+        }
+    }
+
+    println!("📜 Propagating artifact manifests...");
+    let mut dirty = 0;
+    let mut total_nodes = 0;
+    for (_, graph) in &mut targets {
+        let manifests = core::propagate_manifests(graph);
+
+        if let Some(ref _r) = cache.remote {
+            for (hash, manifest) in manifests {
+                let data = serde_json::to_vec(&manifest)?;
+                let cache_clone = cache.clone();
+                let hash_clone = hash.clone();
+                tokio::spawn(async move {
+                    let _ = cache_clone.put_artifact(&hash_clone, &data).await;
+                });
+            }
+        }
+
+        dirty += graph.nodes.iter().filter(|n| n.dirty).count();
+        total_nodes += graph.nodes.len();
+    }
+    println!("   {} dirty  |  {} cached", dirty, total_nodes - dirty);
+
+    if dirty > 0 {
+        println!("🚀 Initiating smart prefetching for {} nodes...", dirty);
+        let dirty_hashes: Vec<String> = targets
+            .iter()
+            .flat_map(|(_, graph)| &graph.nodes)
+            .filter(|n| n.dirty)
+            .map(|n| n.hash.clone())
+            .collect();
+        cache.clone().prefetch_artifacts(dirty_hashes);
+    }
+
+    let build_start = std::time::Instant::now();
+    let started_at = chrono::Utc::now();
+    // Children of steps that nearly always rebuild them are prepared early
+    let speculation = !matches!(
+        std::env::var("MEMOBUILD_SPECULATE").as_deref(),
+        Ok("0") | Ok("false")
+    );
+    let speculation = speculation.then(|| Arc::new(history.clone()));
+    let materializer = memobuild::sandbox::materialize::Materializer::from_env(
+        context_dir.clone(),
+        cache.local.cache_dir(),
+    )?
+    .map(Arc::new);
+    let decisions = export::decisions::DecisionSink::from_env()?;
+    let mut builds = Vec::new();
+    let mut failure = None;
+    for (platform, mut graph) in targets {
+        if let Some(ref platform) = platform {
+            println!("🧩 Building for {}...", platform);
+        }
+        let mut executor = configure_executor(
+            &cache,
+            &context_dir,
+            &toolchain_path,
+            sandbox_type.as_deref(),
+            remote_exec,
+            k8s,
+            reproducible,
+            traces.clone(),
+            materializer.clone(),
+            plugins.clone(),
+            on_failure,
+            observer.clone(),
+        )
+        .await?
+        .with_jobs(&jobs);
+        if let Some(ref history) = speculation {
+            executor = executor.with_speculation(history.clone());
+        }
+
+        let result = executor.execute(&mut graph).await;
+        let suffix = if multi { platform.as_ref() } else { None };
+        if let Some(ref path) = html_report {
+            let path = per_platform_path(path, suffix);
+            match export::html_report::write(&path, &graph, executor.report(), &dockerfile_path) {
+                Ok(()) => println!("📄 HTML report written to: {}", path.display()),
+                Err(e) => eprintln!("⚠️  {}", e),
+            }
+        }
+        if let Some(ref path) = junit {
+            let path = per_platform_path(path, suffix);
+            match export::junit::write(&path, &graph, executor.report(), &dockerfile_path) {
+                Ok(()) => println!("🧪 JUnit report written to: {}", path.display()),
+                Err(e) => eprintln!("⚠️  {}", e),
+            }
+        }
+        if annotations.is_some() {
+            ci_annotations.extend(export::annotations::from_report(&graph, executor.report()));
+        }
+        if let Some(ref sink) = decisions {
+            let platform = platform.as_ref().map(|p| p.to_string());
+            let records =
+                export::decisions::records(&graph, executor.report(), platform.as_deref());
+            if let Err(e) = sink.send(&records).await {
+                eprintln!("⚠️  {}", e);
+            }
+        }
+        builds.push((platform, graph, executor.report().clone()));
+        if let Err(e) = result {
+            failure = Some(e);
+            break;
+        }
+    }
+    if let Some(ref traces) = traces {
+        if let Err(e) = traces.lock().unwrap().save(&trace_path) {
+            eprintln!("⚠️  {}", e);
+        }
+    }
+    if let Some(ref materializer) = materializer {
+        if let Err(e) = materializer.save() {
+            eprintln!("⚠️  {}", e);
+        }
+    }
+    if let Some(format) = annotations {
+        emit_annotations(format, &ci_annotations);
+    }
+    if let Err(e) = cache.local.flush_access() {
+        eprintln!("⚠️  Failed to save cache access times: {}", e);
+    }
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    let duration = build_start.elapsed();
+
+    for (_, graph, _) in &builds {
+        history.record_graph(graph, |hash| cache.local.size(hash));
+    }
+    let summary = (!multi).then(|| {
+        memobuild::dashboard::BuildSummary::new(
+            &builds[0].2,
+            &history,
+            |hash| cache.local.size(hash),
+            memobuild::dashboard::summary::DEFAULT_TOP_N,
+            memobuild::cache::limits::large_artifact_bytes(),
+        )
+    });
+    history.record_build(memobuild::history::BuildTotals::sum(
+        builds
+            .iter()
+            .map(|(_, _, report)| memobuild::history::BuildTotals::from_report(report)),
+    ));
+    history.last_env = Some(env_fp.clone());
+    if let Err(e) = history.save(&history_path) {
+        eprintln!("⚠️  Failed to save build history: {}", e);
+    }
+    if let Err(e) = merkle.save(&merkle_path) {
+        eprintln!("⚠️  Failed to save source hash state: {}", e);
+    }
+
+    let _ = cache
+        .report_analytics(
+            dirty as u32,
+            (total_nodes - dirty) as u32,
+            duration.as_millis() as u64,
+        )
+        .await;
+
+    let (_, graph, report) = &builds[0];
+    println!("📦 Exporting OCI Image...");
+    let output_dir = if platforms.is_empty() {
+        export::export_image(graph, "memobuild-demo:latest", reproducible)?
+    } else {
+        let images: Vec<(Platform, &memobuild::graph::BuildGraph)> = builds
+            .iter()
+            .filter_map(|(p, g, _)| p.clone().map(|p| (p, g)))
+            .collect();
+        export::export_image_index(&images, "memobuild-demo:latest", reproducible)?
+    };
+    if let Some(format) = sbom {
+        tokio::task::block_in_place(|| {
+            export::export_sbom(
+                graph,
+                "memobuild-demo:latest",
+                &output_dir,
+                format,
+                &aliases,
+                reproducible,
+            )
+        })?;
+    }
+    if provenance {
+        use memobuild::reproducible::provenance;
+        let invocation = provenance::BuildInvocation {
+            dockerfile: &dockerfile_path,
+            env_fingerprint: env_fp.hash(),
+            reproducible,
+            started_at,
+            finished_at: chrono::Utc::now(),
+        };
+        let statement = provenance::statement(
+            graph,
+            report,
+            "memobuild-demo:latest",
+            &export::image_digest(&output_dir)?,
+            &invocation,
+        );
+        let signer = provenance::ProvenanceSigner::from_env()?;
+        let path = provenance::write(&output_dir, &statement, signer.as_ref())?;
+        let kind = if signer.is_some() {
+            "attestation"
+        } else {
+            "statement (unsigned)"
+        };
+        println!("🔏 Provenance {} written to: {}", kind, path.display());
+    }
+
+    if let Some(ref path) = docker_archive {
+        let platform = builds[0].0.as_ref();
+        let archive = export::docker_archive::write(
+            graph,
+            &cache,
+            "memobuild-demo:latest",
+            path,
+            reproducible,
+            platform,
+        )
+        .await?;
+        println!(
+            "🐳 docker load archive with {} layer(s) ({:.1} MB) written to: {}",
+            archive.layers,
+            archive.bytes as f64 / 1_048_576.0,
+            path.display()
+        );
+    }
+
+    if smoke_test {
+        run_smoke_test(
+            graph,
+            report,
+            &output_dir,
+            &dockerfile_path,
+            junit.as_deref(),
+        )?;
+    }
+
+    if push {
+        let registry_url =
+            env::var("MEMOBUILD_REGISTRY").unwrap_or_else(|_| "localhost:5000".to_string());
+        let repo = env::var("MEMOBUILD_REPO").unwrap_or_else(|_| "memobuild-demo".to_string());
+        let token = env::var("MEMOBUILD_TOKEN").ok();
+        let mut client = export::registry::RegistryClient::new(&registry_url, &repo);
+        if let Some(t) = token {
+            client.set_token(&t);
+        }
+        client.push(&output_dir)?;
+    }
+
+    match summary {
+        Some(summary) => summary.print(),
+        None => {
+            for (platform, _, report) in &builds {
+                let totals = memobuild::history::BuildTotals::from_report(report);
+                println!(
+                    "🧩 {:<16} {} executed  |  {} cached  |  {:.1}s",
+                    platform.as_ref().map(|p| p.to_string()).unwrap_or_default(),
+                    totals.executed,
+                    totals.cached,
+                    totals.duration_ms as f64 / 1000.0
+                );
+            }
+        }
+    }
+    if let Some(ref mirrors) = cache.mirrors {
+        if mirrors.pending() > 0 {
+            println!("⏳ Waiting for {} mirror upload(s)...", mirrors.pending());
+        }
+        let timeout =
+            std::time::Duration::from_secs(memobuild::constants::MIRROR_FLUSH_TIMEOUT_SECS);
+        if !mirrors.flush(timeout).await {
+            println!(
+                "{}",
+                format!(
+                    "⚠️  {} mirror upload(s) still queued after {}s were dropped",
+                    mirrors.pending(),
+                    timeout.as_secs()
+                )
+                .yellow()
+            );
+        }
+        if let Some(note) = mirrors.summary() {
+            println!("🪞 {}", note);
+        }
+    }
+    if let Some(note) = cache.remote_health.as_ref().and_then(|h| h.summary()) {
+        println!("{}", format!("⚠️  {}", note).yellow());
+    }
+    if let Some(note) = cache.integrity.summary() {
+        println!("{}", format!("⚠️  {}", note).yellow());
+    }
+    if let Some(note) = cache.uploads.summary() {
+        println!("📤 {}", note);
+    }
+    println!("✅ Build and Export completed successfully");
+    Ok(())
+}
+
+/// Run the image's HEALTHCHECK against the exported image and record the
+/// result in the build's JUnit report. Fails if the check does.
+fn run_smoke_test(
+    graph: &memobuild::graph::BuildGraph,
+    report: &memobuild::report::BuildReport,
+    output_dir: &Path,
+    dockerfile_path: &str,
+    junit: Option<&Path>,
+) -> Result<()> {
+    let Some(check) = docker::healthcheck::for_image(graph)? else {
+        println!(
+            "{}",
+            "⚠️  --smoke-test: the image has no HEALTHCHECK, skipping".yellow()
+        );
+        return Ok(());
+    };
+    println!("🩺 Smoke testing the image: {}", check.test.join(" "));
+    let result = tokio::task::block_in_place(|| export::smoke::run(output_dir, &check))?;
+
+    let mut report = report.clone();
+    report.smoke_test = Some(result.clone());
+    if let Some(path) = junit {
+        if let Err(e) = export::junit::write(path, graph, &report, dockerfile_path) {
+            eprintln!("⚠️  {}", e);
+        }
+    }
+
+    if !result.passed {
+        anyhow::bail!(
+            "Smoke test of {} failed: {}\n{}",
+            result.image,
+            result.error.unwrap_or_default(),
+            result.output
+        );
+    }
+    println!(
+        "   ✅ HEALTHCHECK passed after {} probe(s) ({:.1}s)",
+        result.attempts,
+        result.duration_ms as f64 / 1000.0
+    );
+    Ok(())
+}
+
+/// Executor for one build, with the sandbox and backend the flags ask for.
+#[allow(clippy::too_many_arguments)]
+async fn configure_executor(
+    cache: &Arc<cache::HybridCache>,
+    context_dir: &Path,
+    toolchain_path: &[PathBuf],
+    sandbox_type: Option<&str>,
+    remote_exec: bool,
+    k8s: bool,
+    reproducible: bool,
+    traces: Option<Arc<std::sync::Mutex<memobuild::sandbox::trace::TraceStore>>>,
+    materializer: Option<Arc<memobuild::sandbox::materialize::Materializer>>,
+    plugins: Arc<memobuild::plugins::PluginSet>,
+    on_failure: memobuild::sandbox::debug::OnFailure,
+    observer: Option<Arc<dyn memobuild::dashboard::BuildObserver>>,
+) -> Result<executor::IncrementalExecutor> {
+    let mut executor =
+        executor::IncrementalExecutor::new(cache.clone()).with_reproducible(reproducible);
+    let sandboxed = |sandbox: Arc<dyn memobuild::sandbox::Sandbox>| {
+        Arc::new(
+            memobuild::execution::backend::SandboxBackend::new(sandbox).with_on_failure(on_failure),
+        )
+    };
+
+    executor = executor.with_backend(sandboxed(Arc::new(
+        memobuild::sandbox::local::LocalSandbox::new(context_dir.to_path_buf())
+            .with_toolchain_path(toolchain_path.to_vec())
+            .with_tracing(traces.clone())
+            .with_materializer(materializer),
+    )));
+
+    if let Some(st) = sandbox_type {
+        if st == "overlay" {
+            executor = executor.with_backend(sandboxed(Arc::new(
+                memobuild::sandbox::local::LocalSandbox::new(context_dir.to_path_buf())
+                    .with_overlay(true)
+                    .with_toolchain_path(toolchain_path.to_vec())
+                    .with_tracing(traces),
+            )));
+        } else if st == "containerd" {
+            #[cfg(feature = "containerd")]
+            {
+                let sandbox = Arc::new(memobuild::sandbox::containerd::ContainerdSandbox::new(
+                    "memobuild",
+                    "/run/containerd/containerd.sock",
+                ));
+                executor = executor.with_backend(sandboxed(sandbox));
+            }
+        }
+    }
+
+    // Configure remote execution if requested
+    if remote_exec {
+        if let Ok(scheduler_url) = std::env::var("MEMOBUILD_SCHEDULER_URL") {
+            let remote_client = Arc::new(memobuild::remote_exec::client::RemoteExecClient::new(
+                &scheduler_url,
+            ));
+            executor = executor.with_remote_executor(remote_client);
+            println!("📡 Using remote execution via scheduler: {}", scheduler_url);
+        } else {
+            println!("⚠️  --remote-exec specified but MEMOBUILD_SCHEDULER_URL not set");
+        }
+    }
+
+    if k8s {
+        let config = memobuild::execution::kubernetes::KubernetesConfig::from_env()?;
+        println!(
+            "☸️  Running commands as Kubernetes Jobs in namespace {} (up to {} at once)",
+            config.namespace, config.parallelism
+        );
+        let backend = memobuild::execution::kubernetes::KubernetesBackend::new(config).await?;
+        executor = executor.with_backend(Arc::new(backend));
+    }
+
+    // Plugin steps run here whatever backend runs the commands
+    if !plugins.is_empty() {
+        executor = executor.with_backend_for(
+            "plugin",
+            Arc::new(memobuild::plugins::PluginBackend::new(plugins)),
+        );
+    }
+
+    if let Some(observer) = observer {
+        executor = executor.with_observer(observer);
+    }
+    Ok(executor)
+}
+
+/// Hash the build's COPY sources on a blocking thread, showing progress once
+/// hashing takes more than a moment and stopping it on Ctrl-C. Builds run by
+/// the daemon (`observer` set) report to the dashboard and leave Ctrl-C to
+/// the daemon.
+async fn hash_sources_with_progress(
+    graph: &mut memobuild::graph::BuildGraph,
+    merkle: &mut memobuild::hasher::MerkleState,
+    live: Option<&memobuild::hasher::MerkleState>,
+    ignore: &memobuild::hasher::IgnoreRules,
+    filters: &memobuild::hasher::ContentFilters,
+    observer: Option<Arc<dyn memobuild::dashboard::BuildObserver>>,
+) -> Result<()> {
+    use memobuild::hasher::HashProgress;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    let progress = Arc::new(HashProgress::default());
+    let hashing = Arc::new(AtomicBool::new(true));
+    let _interrupt = observer.is_none().then(|| {
+        let (progress, hashing) = (progress.clone(), hashing.clone());
+        // Once installed, the handler stays for the whole process; outside
+        // hashing, Ctrl-C still ends the build right away
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if !hashing.load(Ordering::Relaxed) || progress.is_cancelled() {
+                    std::process::exit(130);
+                }
+                progress.cancel();
+            }
+        })
+    });
+
+    let reporter = {
+        let progress = progress.clone();
+        tokio::spawn(async move {
+            let started = std::time::Instant::now();
+            let mut bar: Option<indicatif::ProgressBar> = None;
+            loop {
+                tokio::time::sleep(Duration::from_millis(250)).await;
+                let snapshot = progress.snapshot();
+                if let Some(ref observer) = observer {
+                    observer.on_event(memobuild::dashboard::BuildEvent::HashingProgress {
+                        files: snapshot.files,
+                        total_files: snapshot.total_files,
+                        bytes: snapshot.bytes,
+                        current: snapshot.current.clone(),
+                    });
+                } else if started.elapsed() > Duration::from_secs(1) {
+                    let bar = bar.get_or_insert_with(|| {
+                        let bar = indicatif::ProgressBar::new_spinner()
+                            .with_finish(indicatif::ProgressFinish::AndClear);
+                        bar.enable_steady_tick(Duration::from_millis(100));
+                        bar
+                    });
+                    bar.set_message(format!(
+                        "{}/{} files, {:.1} MB read  {}",
+                        snapshot.files,
+                        snapshot.total_files,
+                        snapshot.bytes as f64 / 1_048_576.0,
+                        snapshot.current
+                    ));
+                }
+            }
+        })
+    };
+
+    let result = tokio::task::block_in_place(|| {
+        core::hash_sources(graph, merkle, live, ignore, filters, &progress)
+    });
+    hashing.store(false, Ordering::Relaxed);
+    reporter.abort();
+    // The spinner, if any, is cleared when the aborted task drops it
+    let _ = reporter.await;
+    match result {
+        Err(e) if memobuild::hasher::progress::is_cancelled(&e) => {
+            anyhow::bail!("Build cancelled while hashing sources")
+        }
+        other => other,
+    }
+}
+
+/// `report.html` becomes `report.linux-arm64.html` when there is a platform,
+/// so per-platform outputs of one build do not overwrite each other.
+fn per_platform_path(path: &Path, platform: Option<&Platform>) -> PathBuf {
+    let Some(platform) = platform else {
+        return path.to_path_buf();
+    };
+    let tag = platform.to_string().replace('/', "-");
+    let name = match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(ext)) => format!(
+            "{}.{}.{}",
+            stem.to_string_lossy(),
+            tag,
+            ext.to_string_lossy()
+        ),
+        _ => format!("{}.{}", path.display(), tag),
+    };
+    path.with_file_name(name)
+}
+
+async fn run_graph(context_dir: PathBuf, dockerfile_path: String) -> Result<()> {
+    let dockerfile = fs::read_to_string(&dockerfile_path)?;
+    let instructions = docker::parser::parse_dockerfile(&dockerfile);
+    let graph = docker::dag::build_graph_from_instructions(instructions, context_dir);
+
+    println!("\n{}", "🕸️  Build Dependency Graph:".bold().cyan());
+    for node in &graph.nodes {
+        let deps: Vec<String> = node
+            .deps
+            .iter()
+            .map(|&id| graph.nodes[id].name.clone())
+            .collect();
+        println!("  {} [{}]", node.name.green(), node.id);
+        if !deps.is_empty() {
+            println!("    └─ depends on: {:?}", deps);
+        }
+    }
+    Ok(())
+}
+
+/// Read a Dockerfile given as a path or as `<git-rev>:<path>`.
+fn read_dockerfile_spec(spec: &str, context_dir: &Path) -> Result<String> {
+    if Path::new(spec).exists() {
+        return fs::read_to_string(spec).with_context(|| format!("Failed to read {}", spec));
+    }
+    if spec.contains(':') {
+        let output = std::process::Command::new("git")
+            .arg("show")
+            .arg(spec)
+            .current_dir(context_dir)
+            .output()
+            .context("Failed to run git")?;
+        if output.status.success() {
+            return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+        }
+        anyhow::bail!(
+            "git show {} failed: {}",
+            spec,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    anyhow::bail!("Dockerfile {} not found", spec)
+}
+
+/// Graph of a Dockerfile with its cache key inputs filled in, as a build
+/// would see them: current sources, lockfile digests, network policies.
+fn diff_graph(
+    spec: &str,
+    context_dir: &Path,
+    env_fp: &memobuild::env::EnvFingerprint,
+    merkle: &mut memobuild::hasher::MerkleState,
+    ignore: &memobuild::hasher::IgnoreRules,
+) -> Result<memobuild::graph::BuildGraph> {
+    let tasks = memobuild::tasks::is_manifest(Path::new(spec));
+    if spec.ends_with(".json") && !tasks {
+        let content =
+            fs::read_to_string(spec).with_context(|| format!("Failed to read {}", spec))?;
+        return serde_json::from_str(&content)
+            .with_context(|| format!("{} is not a build graph", spec));
+    }
+
+    let dockerfile = read_dockerfile_spec(spec, context_dir)?;
+    let mut graph = if tasks {
+        memobuild::tasks::TaskManifest::parse(&dockerfile)?.into_graph()?
+    } else {
+        let instructions = docker::parser::parse_dockerfile_spanned(&dockerfile, Path::new(spec));
+        docker::include::build_graph_with_includes(instructions, context_dir.to_path_buf())?
+    };
+
+    // No registry lookups; both sides see the same lockfile
+    let mut lock = memobuild::lockfile::Lockfile::load(
+        &context_dir.join(memobuild::constants::LOCKFILE_NAME),
+    )?;
+    let _ = docker::resolve::pin_base_images(
+        &mut graph,
+        &mut lock,
+        docker::resolve::LockMode::Locked,
+        &docker::resolve::RegistryDigestSource,
+        &docker::resolve::stage_aliases(&dockerfile),
+        &docker::policy::PolicySet::default(),
+        None,
+    );
+    memobuild::prepare::configure_steps(&mut graph, context_dir, env_fp)?;
+    core::hash_sources(
+        &mut graph,
+        merkle,
+        None,
+        ignore,
+        &memobuild::hasher::ContentFilters::load(context_dir)?,
+        &memobuild::hasher::HashProgress::default(),
+    )?;
+    core::compute_composite_hashes(&mut graph, env_fp);
+    Ok(graph)
+}
+
+async fn run_diff(context_dir: PathBuf, old: String, new: String, json: bool) -> Result<()> {
+    let cache = create_cache().await?;
+    let env_fp = memobuild::prepare::env_fingerprint(&context_dir, cache.local.cache_dir())?;
+    // Shared by both sides and never saved, so unchanged sources hash once
+    let mut merkle = memobuild::hasher::MerkleState::load(
+        &memobuild::hasher::MerkleState::path_in(cache.local.cache_dir()),
+    );
+    let ignore = memobuild::hasher::IgnoreRules::from_file(&context_dir.join(".dockerignore"));
+    let old_graph = diff_graph(&old, &context_dir, &env_fp, &mut merkle, &ignore)?;
+    let new_graph = diff_graph(&new, &context_dir, &env_fp, &mut merkle, &ignore)?;
+    let diff = memobuild::graph::diff(&old_graph, &new_graph);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+        return Ok(());
+    }
+
+    println!("\n{}", format!("🔀 {} → {}", old, new).bold().cyan());
+    if diff.is_empty() {
+        println!("  No step changes; every cached step stays valid.");
+        return Ok(());
+    }
+    for node in &diff.added {
+        println!("  {} [{}] {}", "+".green(), node.id, node.name);
+    }
+    for node in &diff.removed {
+        println!("  {} [{}] {}", "-".red(), node.id, node.name);
+    }
+    for change in &diff.changed {
+        println!("  {} [{}] {}", "~".yellow(), change.new_id, change.name);
+        for reason in &change.reasons {
+            println!("      {}", reason);
+        }
+    }
+    println!(
+        "\nThis change invalidates {} of {} steps ({} unchanged, {} removed).",
+        diff.invalidated().to_string().bold(),
+        new_graph.nodes.len(),
+        diff.unchanged,
+        diff.removed.len()
+    );
+    Ok(())
+}
+
+fn run_affected(
+    context_dir: PathBuf,
+    dockerfile_path: String,
+    mut paths: Vec<PathBuf>,
+    json: bool,
+) -> Result<()> {
+    if paths.is_empty() {
+        use std::io::BufRead;
+        for line in std::io::stdin().lock().lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                paths.push(PathBuf::from(line.trim()));
+            }
+        }
+    }
+    let dockerfile = fs::read_to_string(&dockerfile_path)
+        .with_context(|| format!("Failed to read Dockerfile at {}", dockerfile_path))?;
+    let instructions =
+        docker::parser::parse_dockerfile_spanned(&dockerfile, Path::new(&dockerfile_path));
+    let graph = docker::include::build_graph_with_includes(instructions, context_dir.clone())?;
+    let history = memobuild::history::BuildHistory::load(
+        &memobuild::history::BuildHistory::path_in(cache::LocalCache::new()?.cache_dir()),
+    )
+    .unwrap_or_default();
+    let affected = memobuild::affected::affected(&graph, &context_dir, &paths, &history);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&affected)?);
+        return Ok(());
+    }
+    if affected.is_empty() {
+        println!("No steps affected; every cached step stays valid.");
+        return Ok(());
+    }
+    for node in &affected.nodes {
+        let estimate = node
+            .estimated_ms
+            .map(|ms| format!(" (~{:.1}s)", ms as f64 / 1000.0))
+            .unwrap_or_default();
+        println!(
+            "  {} [{}] {}{}",
+            "~".yellow(),
+            node.id,
+            node.name,
+            estimate.dimmed()
+        );
+        println!("      {}", node.reason);
+    }
+    let mut summary = format!(
+        "\nThese changes rebuild {} of {} steps",
+        affected.nodes.len().to_string().bold(),
+        affected.total_nodes
+    );
+    if affected.estimated_ms > 0 {
+        summary.push_str(&format!(
+            ", about {:.1} min going by past builds",
+            affected.estimated_ms as f64 / 60_000.0
+        ));
+        if affected.unknown_duration > 0 {
+            summary.push_str(&format!(" ({} never timed)", affected.unknown_duration));
         }
-        Commands::Scheduler { port } => start_scheduler(port).await,
-        Commands::Worker {
-            port,
-            sandbox,
-            scheduler_url,
-        } => start_worker(port, sandbox, scheduler_url).await,
-        Commands::Pull { image } => run_pull(image).await,
-        Commands::GenerateCi { provider } => run_generate_ci(provider).await,
-        Commands::Cluster {
-            port,
-            node_id,
-            peers,
-            postgres,
-            database_url,
-        } => start_cluster_server(port, node_id, peers, postgres, database_url).await,
     }
+    println!("{}.", summary);
+    Ok(())
 }
 
-async fn run_build(
+/// Emit CI annotations, warning instead of failing the command.
+fn emit_annotations(
+    format: export::annotations::AnnotationFormat,
+    annotations: &[export::annotations::Annotation],
+) {
+    if let Err(e) = export::annotations::emit(format, annotations, Path::new(".")) {
+        eprintln!("⚠️  Failed to emit {} annotations: {}", format, e);
+    }
+}
+
+async fn run_lint(
     context_dir: PathBuf,
     dockerfile_path: String,
-    push: bool,
-    reproducible: bool,
-    dry_run: bool,
-    sandbox_type: Option<String>,
-    remote_exec: bool,
+    json: bool,
+    fix: bool,
+    annotations: Option<export::annotations::AnnotationFormat>,
 ) -> Result<()> {
-    println!("🚀 MemoBuild Engine Starting...");
+    let dockerfile = fs::read_to_string(&dockerfile_path)
+        .with_context(|| format!("Failed to read Dockerfile at {}", dockerfile_path))?;
+    let mut diagnostics = docker::lint::lint_dockerfile(
+        &dockerfile,
+        Path::new(&dockerfile_path),
+        &context_dir,
+    );
 
-    let env_fp = memobuild::env::EnvFingerprint::collect();
-    println!("   🔑 Env Fingerprint: {}", &env_fp.hash()[..8]);
+    // Ordering suggestions use how often each step rebuilt in past builds
+    let history = memobuild::history::BuildHistory::load(
+        &memobuild::history::BuildHistory::path_in(cache::LocalCache::new()?.cache_dir()),
+    )
+    .unwrap_or_default();
+    let reordering = docker::reorder::suggest(
+        &dockerfile,
+        Path::new(&dockerfile_path),
+        &context_dir,
+        &history,
+    );
+    diagnostics.extend(reordering.diagnostics);
+    diagnostics.sort_by_key(|d| d.span.as_ref().map(|s| s.line).unwrap_or(0));
+    if fix && reordering.fixes > 0 {
+        fs::write(&dockerfile_path, &reordering.fixed)
+            .with_context(|| format!("Failed to write {}", dockerfile_path))?;
+        eprintln!(
+            "🔧 Reordered {} ({} change(s)); review the result before committing",
+            dockerfile_path, reordering.fixes
+        );
+    } else if reordering.fixes > 0 && !json {
+        println!(
+            "💡 {} reordering(s) would improve cache hits; run with --fix to apply",
+            reordering.fixes
+        );
+    }
 
-    let cache = Arc::new(create_cache().await?);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diagnostics)?);
+    } else if diagnostics.is_empty() {
+        println!("{}", "✅ No lint findings".green());
+    } else {
+        for diagnostic in &diagnostics {
+            println!("{}", diagnostic);
+        }
+        println!("\n{} finding(s)", diagnostics.len());
+    }
+    if let Some(format) = annotations {
+        let annotations: Vec<_> = diagnostics
+            .iter()
+            .map(export::annotations::Annotation::from)
+            .collect();
+        emit_annotations(format, &annotations);
+    }
 
-    let dockerfile = fs::read_to_string(&dockerfile_path)
-        .with_context(|| format!("Failed to read Dockerfile at {}", dockerfile_path))?;
+    if diagnostics
+        .iter()
+        .any(|d| d.severity == docker::lint::Severity::Error)
+    {
+        anyhow::bail!("Lint failed with errors");
+    }
+    Ok(())
+}
 
-    println!("📄 Parsing Dockerfile...");
-    let instructions = docker::parser::parse_dockerfile(&dockerfile);
+async fn run_cache_invalidate(key: String, local_only: bool) -> Result<()> {
+    let cache = create_cache().await?;
+    let was_local = cache.invalidate(&key, local_only).await?;
+    println!(
+        "🗑️  Invalidated {}{}",
+        key,
+        if was_local { "" } else { " (not in local cache)" }
+    );
+    if !local_only && cache.remote.is_some() {
+        println!("   Removed from the remote cache as well");
+    }
+    Ok(())
+}
 
-    println!("📊 Building DAG for context: {}...", context_dir.display());
-    let mut graph = docker::dag::build_graph_from_instructions(instructions, context_dir.clone());
+async fn run_cache_migrate(local_only: bool) -> Result<()> {
+    let version = memobuild::constants::CACHE_KEY_VERSION;
+    let local = cache::LocalCache::new()?;
+    let removed = local.prune_key_versions()?;
+    println!(
+        "🧹 Removed {} local entries from other key versions (current: v{})",
+        removed, version
+    );
+    if local_only {
+        return Ok(());
+    }
+    match env::var("MEMOBUILD_REMOTE_URL") {
+        Ok(url) if url.starts_with("http://") || url.starts_with("https://") => {
+            let remote = cache::HttpRemoteCache::new(url);
+            let deleted = remote.prune_key_versions(version).await?;
+            println!("   Removed {} remote entries", deleted);
+        }
+        Ok(_) => {
+            // Keys are plain hashes there; old ones are never looked up again
+            println!("   Remote is not a cache server; stale entries expire with GC");
+        }
+        Err(_) => {}
+    }
+    Ok(())
+}
 
-    let ai_layer = memobuild::ai::AiLayer::new();
-    ai_layer.analyze(&mut graph, &env_fp, &context_dir);
+fn run_cache_prune(idle_days: u64, max_size: Option<String>, dry_run: bool) -> Result<()> {
+    let max_size = max_size
+        .as_deref()
+        .map(memobuild::remote_exec::queue::parse_memory)
+        .transpose()?;
+    let local = cache::LocalCache::new()?;
+    let plan = local.plan_prune(
+        std::time::Duration::from_secs(idle_days * 24 * 3600),
+        max_size,
+    )?;
+    if dry_run {
+        print!("{}", plan);
+        println!(
+            "🧹 Would remove {} local entries and files ({})",
+            plan.candidates.len(),
+            plan.summary()
+        );
+        return Ok(());
+    }
+    let removed = local.apply_prune(&plan)?;
+    println!(
+        "🧹 Removed {} local entries and files ({})",
+        removed.candidates.len(),
+        removed.summary()
+    );
+    Ok(())
+}
 
-    println!("🔍 Detecting changes (filesystem hashing)...");
-    core::detect_changes(&mut graph);
+async fn run_cache_import_docker(
+    image: String,
+    store: memobuild::docker::import::ImageStore,
+    context_dir: PathBuf,
+    dockerfile_path: String,
+    local_only: bool,
+) -> Result<()> {
+    use memobuild::docker::import::{self, ImageArchive, ImageStore};
 
-    println!("🔄 Propagating dirty flags...");
-    core::propagate_dirty(&mut graph);
+    let cache = if local_only {
+        cache::HybridCache::new(None)?
+    } else {
+        create_cache().await?
+    };
+    let graph = keyed_graph(
+        &context_dir,
+        &dockerfile_path,
+        &cache,
+        &memobuild::prepare::env_fingerprint(&context_dir, cache.local.cache_dir())?,
+    )
+    .await?;
+
+    let exported = (store != ImageStore::Archive).then(|| {
+        env::temp_dir().join(format!(
+            "memobuild-import-{}.tar",
+            uuid::Uuid::new_v4().simple()
+        ))
+    });
+    let result = async {
+        let path = match exported {
+            Some(ref path) => {
+                println!("📦 Exporting {}...", image);
+                import::save_image(store, &image, path)?;
+                path.clone()
+            }
+            None => PathBuf::from(&image),
+        };
+        let archive = ImageArchive::open(&path)?;
+        import::import(&graph, &archive, &cache).await
+    }
+    .await;
+    if let Some(ref path) = exported {
+        let _ = fs::remove_file(path);
+    }
+    let report = result?;
 
-    println!("🔑 Recomputing deterministic hashes...");
-    core::compute_composite_hashes(&mut graph, &env_fp);
+    println!(
+        "📥 Imported {} layer(s) ({:.1} MB) of {}; {} already cached",
+        report.imported,
+        report.bytes as f64 / 1_048_576.0,
+        image,
+        report.present
+    );
+    if let Some(id) = report.stopped_at {
+        println!(
+            "   {} and the steps after it are not in the image and will build",
+            graph.nodes[id].name
+        );
+    }
+    Ok(())
+}
 
-    println!("📜 Propagating artifact manifests...");
-    let manifests = core::propagate_manifests(&mut graph);
-
-    if let Some(ref _r) = cache.remote {
-        for (hash, manifest) in manifests {
-            let data = serde_json::to_vec(&manifest)?;
-            let cache_clone = cache.clone();
-            let hash_clone = hash.clone();
-            tokio::spawn(async move {
-                let _ = cache_clone.put_artifact(&hash_clone, &data).await;
-            });
+/// Pin the entries named by `key` or `build` under `label`, or unpin them
+/// when `label` is `None`.
+async fn run_cache_pin(
+    key: Option<String>,
+    build: Option<String>,
+    label: Option<String>,
+    local_only: bool,
+) -> Result<()> {
+    let target = cache::PinTarget::from_parts(key, build)?;
+    let (verb, icon) = match label {
+        Some(_) => ("Pinned", "📌"),
+        None => ("Unpinned", "🔓"),
+    };
+    let local = cache::LocalCache::new()?;
+    let changed = local.set_pin(&target, label.as_deref())?;
+    println!("{} {} {} local entries ({})", icon, verb, changed, target);
+    if local_only {
+        return Ok(());
+    }
+    match env::var("MEMOBUILD_REMOTE_URL") {
+        Ok(url) if url.starts_with("http://") || url.starts_with("https://") => {
+            let remote = cache::HttpRemoteCache::new(url);
+            let changed = remote.set_pin(&target, label.as_deref()).await?;
+            println!("   {} {} remote entries", verb, changed);
         }
+        Ok(_) => {
+            println!("   Remote is not a cache server; pins only apply to the local cache");
+        }
+        Err(_) => {}
     }
+    Ok(())
+}
 
-    let dirty = graph.nodes.iter().filter(|n| n.dirty).count();
+async fn run_migrate_storage(from: String, to: String, data_dir: PathBuf) -> Result<()> {
+    use memobuild::storage::migrate::{self, MigrationCheckpoint};
+    let metadata_url = std::env::var("MEMOBUILD_METADATA_URL").ok();
+    let metadata = server::metadata::open_metadata(metadata_url.as_deref(), &data_dir).await?;
+    let source = memobuild::storage::storage_from_uri(&from)?;
+    let dest = memobuild::storage::storage_from_uri(&to)?;
+    let hashes = metadata.storage_objects()?;
+
+    let checkpoint_path = MigrationCheckpoint::path_in(&data_dir);
+    let mut checkpoint = MigrationCheckpoint::load(&checkpoint_path, &from, &to)?;
+    if !checkpoint.done.is_empty() {
+        println!(
+            "↩️  Resuming: {} of {} blob(s) already migrated",
+            checkpoint.done.len(),
+            hashes.len()
+        );
+    }
     println!(
-        "   {} dirty  |  {} cached",
-        dirty,
-        graph.nodes.len() - dirty
+        "📦 Migrating {} blob(s) from {} to {}",
+        hashes.len(),
+        from,
+        to
     );
 
-    if dirty > 0 {
-        println!("🚀 Initiating smart prefetching for {} nodes...", dirty);
-        let dirty_hashes: Vec<String> = graph
-            .nodes
-            .iter()
-            .filter(|n| n.dirty)
-            .map(|n| n.hash.clone())
-            .collect();
-        cache.clone().prefetch_artifacts(dirty_hashes);
+    let pb = indicatif::ProgressBar::new(hashes.len() as u64);
+    pb.set_style(
+        indicatif::ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}")
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+    pb.set_position(checkpoint.done.len() as u64);
+    let progress = tokio::task::block_in_place(|| {
+        migrate::migrate(
+            source.as_ref(),
+            dest.as_ref(),
+            &hashes,
+            &mut checkpoint,
+            &checkpoint_path,
+            |hash, location| metadata.set_storage_path(hash, location),
+            |p| {
+                pb.set_position(p.done as u64);
+                pb.set_message(format!("{} copied", p.copied));
+            },
+        )
+    });
+    pb.finish_and_clear();
+    let progress = progress?;
+
+    println!(
+        "✅ {} copied ({:.1} MB), {} already present, {} of {} migrated",
+        progress.copied,
+        progress.bytes as f64 / 1_048_576.0,
+        progress.already_present,
+        progress.done,
+        progress.total
+    );
+    if !progress.missing.is_empty() {
+        println!(
+            "{}",
+            format!(
+                "⚠️  {} blob(s) in the metadata are missing from {}",
+                progress.missing.len(),
+                from
+            )
+            .yellow()
+        );
     }
+    println!("   Point MEMOBUILD_STORAGE_URL at {} and unset MEMOBUILD_STORAGE_FALLBACK_URL once uploads made during the migration are copied too.", to);
+    Ok(())
+}
 
-    let build_start = std::time::Instant::now();
-    let mut executor = executor::IncrementalExecutor::new(cache.clone())
-        .with_reproducible(reproducible)
-        .with_dry_run(dry_run);
+async fn run_cache_audit(verbose: bool) -> Result<()> {
+    use memobuild::server::transparency::{self, LogHead, TrustedHeads};
+    let url = match env::var("MEMOBUILD_REMOTE_URL") {
+        Ok(url) if url.starts_with("http://") || url.starts_with("https://") => url,
+        _ => anyhow::bail!("Auditing needs MEMOBUILD_REMOTE_URL to point at a cache server"),
+    };
+    let remote = cache::HttpRemoteCache::new(url.clone());
+    let local = cache::LocalCache::new()?;
+    let heads_path = TrustedHeads::path_in(local.cache_dir());
+    let mut trusted = TrustedHeads::load(&heads_path)?;
+
+    let seen = trusted
+        .heads
+        .get(&url)
+        .cloned()
+        .unwrap_or_else(LogHead::empty);
+    let proof = remote.log_consistency(seen.size).await?;
+    transparency::verify_consistency(&seen, &proof)
+        .with_context(|| format!("Transparency log of {} was rewritten", url))?;
 
-    executor = executor.with_sandbox(Arc::new(memobuild::sandbox::local::LocalSandbox::new(
-        context_dir.clone(),
-    )));
+    println!(
+        "✅ Log verified: {} entries, head {}",
+        proof.to.size,
+        &proof.to.head[..16]
+    );
+    println!(
+        "   {} insertion(s) since the last audit (at {} entries)",
+        proof.entries.len(),
+        seen.size
+    );
+    if verbose {
+        for entry in &proof.entries {
+            println!(
+                "   #{} {} {} ({} bytes, {}) at {}",
+                entry.seq, entry.kind, entry.hash, entry.size, entry.namespace, entry.logged_at
+            );
+        }
+    }
+    trusted.heads.insert(url, proof.to);
+    trusted.save(&heads_path)?;
+    Ok(())
+}
 
-    if let Some(st) = sandbox_type {
-        if st.as_str() == "containerd" {
-            #[cfg(feature = "containerd")]
-            {
-                let sandbox = Arc::new(memobuild::sandbox::containerd::ContainerdSandbox::new(
-                    "memobuild",
-                    "/run/containerd/containerd.sock",
-                ));
-                executor = executor.with_sandbox(sandbox);
+async fn run_reproduce(build_id: String, use_cache: bool, frozen_env: bool) -> Result<()> {
+    use memobuild::reproducible::snapshot;
+    let path = snapshot::path_in(cache::LocalCache::new()?.cache_dir(), &build_id)?;
+    if !path.exists() {
+        anyhow::bail!(
+            "No snapshot of build {}; snapshots are taken by `memobuild build --snapshot`",
+            build_id
+        );
+    }
+    let dir = std::env::temp_dir().join(format!(
+        "memobuild-reproduce-{}",
+        uuid::Uuid::new_v4().simple()
+    ));
+    let result = async {
+        let restored = snapshot::restore(&path, &dir)?;
+        println!(
+            "📸 Reproducing build {} from {}",
+            build_id, restored.info.created_at
+        );
+        let changes = restored.env_drift(&memobuild::env::EnvFingerprint::collect())?;
+        if !changes.is_empty() {
+            println!(
+                "{}",
+                "⚠️  Environment differs from the snapshot's:".yellow()
+            );
+            for change in &changes {
+                println!("   • {}", change);
+            }
+            if frozen_env {
+                anyhow::bail!(
+                    "Environment drift with --frozen-env ({} change(s))",
+                    changes.len()
+                );
             }
         }
+        if !use_cache {
+            // A cache of its own, so that every step runs again
+            std::env::set_var("MEMOBUILD_CACHE_DIR", dir.join("cache"));
+            std::env::remove_var("MEMOBUILD_REMOTE_URL");
+            std::env::remove_var("MEMOBUILD_REMOTE_MIRRORS");
+        }
+        let platforms = restored
+            .info
+            .platforms
+            .iter()
+            .map(|p| p.parse())
+            .collect::<Result<Vec<Platform>>>()?;
+        // Base images and GIT revisions as the build resolved them
+        let locked = restored
+            .context_dir
+            .join(memobuild::constants::LOCKFILE_NAME)
+            .exists();
+        run_build(
+            restored.context_dir.clone(),
+            restored.dockerfile.to_string_lossy().to_string(),
+            false,
+            restored.info.reproducible,
+            false,
+            None,
+            None,
+            false,
+            false,
+            locked,
+            false,
+            false,
+            platforms,
+            None,
+            false,
+            None,
+            None,
+            None,
+            false,
+            None,
+            false,
+            false,
+            false,
+            memobuild::execution::Concurrency::default(),
+            memobuild::sandbox::debug::OnFailure::Fail,
+            None,
+        )
+        .await
     }
+    .await;
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
 
-    // Configure remote execution if requested
-    if remote_exec {
-        if let Ok(scheduler_url) = std::env::var("MEMOBUILD_SCHEDULER_URL") {
-            let remote_client = Arc::new(memobuild::remote_exec::client::RemoteExecClient::new(
-                &scheduler_url,
-            ));
-            executor = executor.with_remote_executor(remote_client);
-            println!("📡 Using remote execution via scheduler: {}", scheduler_url);
-        } else {
-            println!("⚠️  --remote-exec specified but MEMOBUILD_SCHEDULER_URL not set");
-        }
+async fn run_export_graph(
+    context_dir: PathBuf,
+    dockerfile_path: String,
+    steps: Vec<String>,
+    output: PathBuf,
+) -> Result<()> {
+    use memobuild::execution::partial::{self, PartialGraph};
+    let cache = create_cache().await?;
+    if cache.remote.is_none() {
+        println!(
+            "{}",
+            "⚠️  No remote cache configured; workers cannot restore COPY sources".yellow()
+        );
     }
+    let env_fp = memobuild::prepare::env_fingerprint(&context_dir, cache.local.cache_dir())?;
+    let graph = keyed_graph(&context_dir, &dockerfile_path, &cache, &env_fp).await?;
+    let graph = partial::select(&graph, &steps)?;
+    let exported = PartialGraph::export(graph, env_fp, &context_dir, &cache).await?;
+    exported.save(&output)?;
+    println!(
+        "📦 {} step(s) exported to {}",
+        exported.graph.nodes.len(),
+        output.display()
+    );
+    Ok(())
+}
 
-    executor.execute(&mut graph).await?;
-    let duration = build_start.elapsed();
+async fn run_imported_graph(
+    graph_path: PathBuf,
+    context_dir: PathBuf,
+    sandbox_type: Option<String>,
+    frozen_env: bool,
+    jobs: memobuild::execution::Concurrency,
+) -> Result<()> {
+    let partial = memobuild::execution::partial::PartialGraph::load(&graph_path)?;
+    let changes = partial.env_drift(&memobuild::env::EnvFingerprint::collect());
+    if !changes.is_empty() {
+        println!(
+            "{}",
+            "⚠️  Environment differs from the one the keys were computed in:".yellow()
+        );
+        for change in &changes {
+            println!("   • {}", change);
+        }
+        if frozen_env {
+            anyhow::bail!(
+                "Environment drift with --frozen-env ({} change(s))",
+                changes.len()
+            );
+        }
+    }
 
-    let _ = cache
-        .report_analytics(
-            dirty as u32,
-            (graph.nodes.len() - dirty) as u32,
-            duration.as_millis() as u64,
+    let cache = Arc::new(create_cache().await?);
+    let mut toolchain_path = Vec::new();
+    if let Some(manifest) = memobuild::toolchains::ToolchainManifest::load(&context_dir)? {
+        let installed = memobuild::toolchains::install(
+            &manifest,
+            &cache,
+            &cache.local.cache_dir().join("toolchains"),
         )
-        .await;
-
-    println!("📦 Exporting OCI Image...");
-    let output_dir = export::export_image(&graph, "memobuild-demo:latest", reproducible)?;
-
-    if push {
-        let registry_url =
-            env::var("MEMOBUILD_REGISTRY").unwrap_or_else(|_| "localhost:5000".to_string());
-        let repo = env::var("MEMOBUILD_REPO").unwrap_or_else(|_| "memobuild-demo".to_string());
-        let token = env::var("MEMOBUILD_TOKEN").ok();
-        let mut client = export::registry::RegistryClient::new(&registry_url, &repo);
-        if let Some(t) = token {
-            client.set_token(&t);
-        }
-        client.push(&output_dir)?;
+        .await?;
+        toolchain_path = installed.iter().map(|t| t.bin_dir.clone()).collect();
+    }
+    let staging = env::temp_dir().join(format!(
+        "memobuild-run-graph-{}",
+        uuid::Uuid::new_v4().simple()
+    ));
+    let result = async {
+        let mut graph = partial.resolve(&cache, &context_dir, &staging).await?;
+        println!(
+            "📦 Executing {} step(s) from {}",
+            graph.nodes.len(),
+            graph_path.display()
+        );
+        let plugins = Arc::new(memobuild::plugins::PluginSet::load(&context_dir)?);
+        let mut executor = configure_executor(
+            &cache,
+            &context_dir,
+            &toolchain_path,
+            sandbox_type.as_deref(),
+            false,
+            false,
+            false,
+            None,
+            None,
+            plugins,
+            memobuild::sandbox::debug::OnFailure::Fail,
+            None,
+        )
+        .await?
+        .with_jobs(&jobs);
+        executor.execute(&mut graph).await?;
+        Ok(())
+    }
+    .await;
+    let _ = fs::remove_dir_all(&staging);
+    if let Err(e) = cache.local.flush_access() {
+        eprintln!("⚠️  Failed to save cache access times: {}", e);
     }
+    result
+}
 
-    println!("✅ Build and Export completed successfully");
+fn run_verify_provenance(file: &Path, public_key: &str) -> Result<()> {
+    use memobuild::reproducible::provenance;
+    let key = ed25519_dalek::VerifyingKey::from_bytes(&provenance::parse_key(public_key)?)
+        .context("Invalid Ed25519 public key")?;
+    let envelope: provenance::Envelope = serde_json::from_str(&fs::read_to_string(file)?)
+        .context("Not a signed provenance envelope")?;
+    let statement = provenance::verify(&envelope, &key)?;
+    println!(
+        "✅ Provenance signed by {}",
+        provenance::key_id(&key).green()
+    );
+    println!("{}", serde_json::to_string_pretty(&statement)?);
     Ok(())
 }
 
-async fn run_graph(context_dir: PathBuf, dockerfile_path: String) -> Result<()> {
-    let dockerfile = fs::read_to_string(&dockerfile_path)?;
-    let instructions = docker::parser::parse_dockerfile(&dockerfile);
-    let graph = docker::dag::build_graph_from_instructions(instructions, context_dir);
+/// The graph of the Dockerfile at `dockerfile_path` with every node's cache
+/// key as a build computes it, compared against the last build's trees
+/// without updating them.
+async fn keyed_graph(
+    context_dir: &Path,
+    dockerfile_path: &str,
+    cache: &cache::HybridCache,
+    env_fp: &memobuild::env::EnvFingerprint,
+) -> Result<memobuild::graph::BuildGraph> {
+    let dockerfile = fs::read_to_string(dockerfile_path)?;
+    let instructions =
+        docker::parser::parse_dockerfile_spanned(&dockerfile, Path::new(dockerfile_path));
+    let mut graph =
+        docker::include::build_graph_with_includes(instructions, context_dir.to_path_buf())?;
+    // No registry lookups; images missing from the lockfile keep their tag key
+    let mut lock = memobuild::lockfile::Lockfile::load(
+        &context_dir.join(memobuild::constants::LOCKFILE_NAME),
+    )?;
+    let _ = docker::resolve::pin_base_images(
+        &mut graph,
+        &mut lock,
+        docker::resolve::LockMode::Locked,
+        &docker::resolve::RegistryDigestSource,
+        &docker::resolve::stage_aliases(&dockerfile),
+        &docker::policy::PolicySet::from_env(),
+        None,
+    );
+    memobuild::prepare::configure_steps(&mut graph, context_dir, env_fp)?;
 
-    println!("\n{}", "🕸️  Build Dependency Graph:".bold().cyan());
-    for node in &graph.nodes {
-        let deps: Vec<String> = node
-            .deps
-            .iter()
-            .map(|&id| graph.nodes[id].name.clone())
-            .collect();
-        println!("  {} [{}]", node.name.green(), node.id);
-        if !deps.is_empty() {
-            println!("    └─ depends on: {:?}", deps);
-        }
-    }
-    Ok(())
+    // Compared against the last build's trees, without updating them
+    let mut merkle = memobuild::hasher::MerkleState::load(
+        &memobuild::hasher::MerkleState::path_in(cache.local.cache_dir()),
+    );
+    let ignore = memobuild::hasher::IgnoreRules::from_file(&context_dir.join(".dockerignore"));
+    let filters = memobuild::hasher::ContentFilters::load(context_dir)?;
+    let live = memobuild::daemon::sync(cache.local.cache_dir(), context_dir).await;
+    hash_sources_with_progress(
+        &mut graph,
+        &mut merkle,
+        live.as_ref(),
+        &ignore,
+        &filters,
+        None,
+    )
+    .await?;
+    let traces = memobuild::prepare::load_traces(cache.local.cache_dir())?;
+    memobuild::prepare::finish_keys(&mut graph, cache, context_dir, env_fp, traces.as_ref())
+        .await?;
+    Ok(graph)
 }
 
 async fn run_explain_cache(
@@ -393,19 +2539,18 @@ async fn run_explain_cache(
     dockerfile_path: String,
     target_node: Option<String>,
 ) -> Result<()> {
-    let env_fp = memobuild::env::EnvFingerprint::collect();
     let cache = Arc::new(create_cache().await?);
-    let dockerfile = fs::read_to_string(&dockerfile_path)?;
-    let instructions = docker::parser::parse_dockerfile(&dockerfile);
-    let mut graph = docker::dag::build_graph_from_instructions(instructions, context_dir.clone());
-
-    // AI Layer Analysis to get extra dependencies
-    let ai_layer = memobuild::ai::AiLayer::new();
-    ai_layer.analyze(&mut graph, &env_fp, &context_dir);
-
-    core::detect_changes(&mut graph);
-    core::propagate_dirty(&mut graph);
-    core::compute_composite_hashes(&mut graph, &env_fp);
+    let mut graph = keyed_graph(
+        &context_dir,
+        &dockerfile_path,
+        &cache,
+        &memobuild::prepare::env_fingerprint(&context_dir, cache.local.cache_dir())?,
+    )
+    .await?;
+    let history = memobuild::history::BuildHistory::load(
+        &memobuild::history::BuildHistory::path_in(cache.local.cache_dir()),
+    )?;
+    core::explain_changes(&mut graph, &history);
 
     println!("\n{}", "🔍 Cache Explanation:".bold().cyan());
     for node in &graph.nodes {
@@ -427,11 +2572,20 @@ async fn run_explain_cache(
             }
         );
         println!("    Hash: {}", node.hash.cyan());
+        if let Some(ref span) = node.metadata.span {
+            println!("    Defined at: {}", span);
+        }
 
         if !is_cached {
+            if let Some(ref reason) = node.metadata.dirty_reason {
+                println!("    Dirty Reason: {}", reason.to_string().yellow());
+            }
             let mut reasons = Vec::new();
             if node.source_path.is_some() {
                 reasons.push("Source files changed or untracked");
+                if !node.metadata.changed_paths.is_empty() {
+                    println!("    Changed Under: {:?}", node.metadata.changed_paths);
+                }
             }
             if !node.metadata.extra_source_paths.is_empty() {
                 reasons.push("AI-detected dependencies changed");
@@ -454,7 +2608,47 @@ async fn run_explain_cache(
 }
 
 async fn create_cache() -> Result<cache::HybridCache> {
-    cache::HybridCache::new(None)
+    let mut remote = match env::var("MEMOBUILD_REMOTE_URL") {
+        Ok(url) => Some(cache::remote_from_url(&url)?),
+        Err(_) => None,
+    };
+
+    let mirrors = cache::MirroredRemoteCache::mirrors_from_env()?;
+    let mut mirror_status = None;
+    if !mirrors.is_empty() {
+        let Some(primary) = remote else {
+            anyhow::bail!("MEMOBUILD_REMOTE_MIRRORS needs MEMOBUILD_REMOTE_URL for the primary");
+        };
+        println!(
+            "   🪞 Mirroring remote cache writes to {} backend(s)",
+            mirrors.len()
+        );
+        let mirrored = cache::MirroredRemoteCache::new(primary, mirrors);
+        mirror_status = Some(mirrored.status());
+        remote = Some(Arc::new(mirrored) as Arc<dyn cache::RemoteCache>);
+    }
+
+    if let Some(key) = cache::EncryptedRemoteCache::key_from_env()? {
+        remote = remote.map(|inner| {
+            println!("   🔒 Remote cache encryption enabled");
+            Arc::new(cache::EncryptedRemoteCache::new(inner, key)) as Arc<dyn cache::RemoteCache>
+        });
+    }
+
+    let Some(remote) = remote else {
+        return cache::HybridCache::new(None);
+    };
+    let breaker = Arc::new(cache::CircuitBreakerRemoteCache::from_env(remote));
+    breaker.probe().await;
+    let health = breaker.health();
+    // Outside the breaker, so waiting for a transfer slot doesn't count against its timeout
+    let throttled = cache::ThrottledRemoteCache::from_env(breaker)?;
+    let cache = cache::HybridCache::new(Some(Arc::new(throttled) as Arc<dyn cache::RemoteCache>))?
+        .with_remote_health(health);
+    Ok(match mirror_status {
+        Some(status) => cache.with_mirrors(status),
+        None => cache,
+    })
 }
 
 async fn _pull_base_images(instructions: &[docker::parser::Instruction]) -> Result<()> {
@@ -505,7 +2699,10 @@ async fn start_scheduler(_port: u16) -> Result<()> {
 
         // For MVP, start with empty worker list - workers will register dynamically
         // In production, this would discover workers via service registry
-        let scheduler = Arc::new(Scheduler::new(SchedulingStrategy::RoundRobin));
+        let scheduler = Arc::new(Scheduler::with_limits(
+            SchedulingStrategy::LeastLoaded,
+            memobuild::remote_exec::queue::NamespaceLimits::from_env()?,
+        ));
         let server = ExecutionServer::new(scheduler);
 
         server.start(_port).await
@@ -567,7 +2764,21 @@ async fn start_cluster_server(
     peers: Option<String>,
     use_postgres: bool,
     database_url: Option<String>,
+    storage: Option<memobuild::storage::StorageBackend>,
 ) -> Result<()> {
+    use memobuild::storage::StorageBackend;
+
+    let in_memory = match storage {
+        None | Some(StorageBackend::Local) => false,
+        Some(StorageBackend::Memory) if use_postgres => {
+            anyhow::bail!("--storage=memory cannot be combined with --postgres")
+        }
+        Some(StorageBackend::Memory) => true,
+        Some(other) => anyhow::bail!(
+            "Cluster servers store artifacts locally or in memory, not in {:?}",
+            other
+        ),
+    };
     println!("🏗️ Starting MemoBuild Clustered Cache Server...");
 
     // Generate node ID if not provided
@@ -603,7 +2814,10 @@ async fn start_cluster_server(
     }
 
     // Initialize storage backend
-    let metadata_store: Arc<dyn crate::server::metadata::MetadataStoreTrait> = if use_postgres {
+    let metadata_store: Arc<dyn crate::server::metadata::MetadataStoreTrait> = if in_memory {
+        println!("🧪 Keeping artifacts and metadata in memory until the server stops");
+        Arc::new(crate::server::metadata::MemoryMetadataStore::new())
+    } else if use_postgres {
         if let Some(db_url) = database_url {
             // Parse PostgreSQL URL
             let config = parse_postgres_url(&db_url)?;
@@ -619,9 +2833,13 @@ async fn start_cluster_server(
         Arc::new(crate::server::metadata::MetadataStore::new(&db_path)?)
     };
 
-    let storage = Arc::new(crate::server::storage::LocalStorage::new(
-        &std::env::current_dir()?.join(".memobuild-cluster"),
-    )?);
+    let storage: Arc<dyn crate::server::storage::ArtifactStorage> = if in_memory {
+        Arc::new(memobuild::storage::MemoryStorage::new())
+    } else {
+        Arc::new(crate::server::storage::LocalStorage::new(
+            &std::env::current_dir()?.join(".memobuild-cluster"),
+        )?)
+    };
 
     // Create distributed cache
     let local_cache = Arc::new(memobuild::remote_cache::HttpRemoteCache::new(format!(
@@ -0,0 +1,221 @@
+use crate::cache::HybridCache;
+use crate::graph::BuildGraph;
+use crate::history::BuildHistory;
+use anyhow::Result;
+use colored::*;
+use serde::{Deserialize, Serialize};
+
+/// What the executor would do with a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlannedAction {
+    /// Artifact is already in the local cache
+    LocalHit,
+    /// Artifact would be downloaded from the remote cache
+    RemoteHit,
+    /// Nothing cached, the node would run
+    Execute,
+}
+
+impl std::fmt::Display for PlannedAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlannedAction::LocalHit => write!(f, "cached"),
+            PlannedAction::RemoteHit => write!(f, "download"),
+            PlannedAction::Execute => write!(f, "run"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlannedNode {
+    pub id: usize,
+    pub name: String,
+    pub hash: String,
+    pub action: PlannedAction,
+    /// Expected run time from history, for nodes that would execute
+    pub estimated_ms: Option<u64>,
+    /// Expected download size from history, for remote hits
+    pub download_bytes: Option<u64>,
+    /// Why a node that would execute is dirty
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dirty_reason: Option<crate::graph::DirtyReason>,
+}
+
+/// A preview of a build: cache state of every node and what it will cost.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildPlan {
+    pub nodes: Vec<PlannedNode>,
+    /// Wall-clock estimate, taking parallel levels into account
+    pub estimated_total_ms: u64,
+    pub download_bytes: u64,
+    /// Nodes that would run but have never been timed
+    pub unknown_estimates: usize,
+}
+
+impl BuildPlan {
+    pub fn count(&self, action: PlannedAction) -> usize {
+        self.nodes.iter().filter(|n| n.action == action).count()
+    }
+
+    pub fn print_table(&self) {
+        println!(
+            "\n{}",
+            format!(
+                "{:>4}  {:<9} {:>10} {:>10}  {}",
+                "ID", "ACTION", "EST. TIME", "DOWNLOAD", "NODE"
+            )
+            .bold()
+        );
+        for node in &self.nodes {
+            let action = match node.action {
+                PlannedAction::LocalHit => node.action.to_string().green(),
+                PlannedAction::RemoteHit => node.action.to_string().cyan(),
+                PlannedAction::Execute => node.action.to_string().yellow(),
+            };
+            let time = match (node.action, node.estimated_ms) {
+                (PlannedAction::Execute, Some(ms)) => format_duration(ms),
+                (PlannedAction::Execute, None) => "?".to_string(),
+                _ => "-".to_string(),
+            };
+            let download = match (node.action, node.download_bytes) {
+                (PlannedAction::RemoteHit, Some(bytes)) => format_bytes(bytes),
+                (PlannedAction::RemoteHit, None) => "?".to_string(),
+                _ => "-".to_string(),
+            };
+            println!(
+                "{:>4}  {:<9} {:>10} {:>10}  {}",
+                node.id, action, time, download, node.name
+            );
+        }
+
+        println!(
+            "\n{} cached  |  {} to download ({})  |  {} to run, ~{}{}",
+            self.count(PlannedAction::LocalHit),
+            self.count(PlannedAction::RemoteHit),
+            format_bytes(self.download_bytes),
+            self.count(PlannedAction::Execute),
+            format_duration(self.estimated_total_ms),
+            if self.unknown_estimates > 0 {
+                format!(" (+{} never timed)", self.unknown_estimates)
+            } else {
+                String::new()
+            }
+        );
+    }
+}
+
+/// Work out what a build of `graph` would do without running anything.
+/// Hashes must already be computed. The remote cache is only asked whether
+/// it has an artifact; nothing is downloaded.
+pub async fn plan_build(
+    graph: &BuildGraph,
+    cache: &HybridCache,
+    history: &BuildHistory,
+) -> Result<BuildPlan> {
+    let mut nodes = Vec::with_capacity(graph.nodes.len());
+    for node in &graph.nodes {
+        let action = if cache.local.exists(&node.hash) {
+            PlannedAction::LocalHit
+        } else if cache.remote_has_artifact(&node.hash).await? {
+            PlannedAction::RemoteHit
+        } else {
+            PlannedAction::Execute
+        };
+        let past = history.get(node);
+        nodes.push(PlannedNode {
+            id: node.id,
+            name: node.name.clone(),
+            hash: node.hash.clone(),
+            action,
+            estimated_ms: match action {
+                PlannedAction::Execute => past.map(|h| h.avg_duration_ms),
+                _ => None,
+            },
+            download_bytes: match action {
+                PlannedAction::RemoteHit => past.and_then(|h| h.artifact_size),
+                _ => None,
+            },
+            dirty_reason: match action {
+                PlannedAction::Execute => node.metadata.dirty_reason.clone(),
+                _ => None,
+            },
+        });
+    }
+
+    // Parallel nodes of a level overlap, sequential ones add up, levels add up
+    let mut estimated_total_ms = 0;
+    for level in graph.levels() {
+        let (parallel, sequential): (Vec<_>, Vec<_>) = level
+            .iter()
+            .partition(|&&id| graph.nodes[id].metadata.parallelizable);
+        let cost = |id: &usize| nodes[*id].estimated_ms.unwrap_or(0);
+        estimated_total_ms += parallel.into_iter().map(cost).max().unwrap_or(0)
+            + sequential.into_iter().map(cost).sum::<u64>();
+    }
+
+    Ok(BuildPlan {
+        download_bytes: nodes.iter().filter_map(|n| n.download_bytes).sum(),
+        unknown_estimates: nodes
+            .iter()
+            .filter(|n| n.action == PlannedAction::Execute && n.estimated_ms.is_none())
+            .count(),
+        estimated_total_ms,
+        nodes,
+    })
+}
+
+pub(crate) fn format_duration(ms: u64) -> String {
+    if ms < 1000 {
+        format!("{}ms", ms)
+    } else if ms < 60_000 {
+        format!("{:.1}s", ms as f64 / 1000.0)
+    } else {
+        format!("{}m{:02}s", ms / 60_000, (ms % 60_000) / 1000)
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::{dag, parser};
+
+    #[tokio::test]
+    async fn test_plan_uses_history_for_misses() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::env::set_var("MEMOBUILD_CACHE_DIR", dir.path());
+        let cache = HybridCache::new(None).unwrap();
+
+        let mut graph = dag::build_graph_from_instructions(
+            parser::parse_dockerfile("FROM alpine\nRUN make\n"),
+            std::path::PathBuf::from("."),
+        );
+        graph.nodes[0].hash = "plan-test-from".into();
+        graph.nodes[1].hash = "plan-test-run".into();
+        cache.local.put("plan-test-from", b"base").unwrap();
+
+        let mut history = BuildHistory::default();
+        history.record(&graph.nodes[1], 4000, None);
+
+        let plan = plan_build(&graph, &cache, &history).await.unwrap();
+        assert_eq!(plan.nodes[0].action, PlannedAction::LocalHit);
+        assert_eq!(plan.nodes[1].action, PlannedAction::Execute);
+        assert_eq!(plan.estimated_total_ms, 4000);
+        assert_eq!(plan.unknown_estimates, 0);
+    }
+}
@@ -0,0 +1,335 @@
+//! Instruction plugins, WebAssembly modules from `MEMOBUILD_PLUGIN_DIR` that
+//! handle Dockerfile instructions MemoBuild does not know.
+
+#[cfg(feature = "wasm-plugins")]
+pub mod wasm;
+
+use crate::cache::HybridCache;
+use crate::execution::ExecutorBackend;
+use crate::graph::{BuildGraph, Node, NodeKind};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Instructions the parser handles itself; plugins cannot take them over
+const BUILT_IN: &[&str] = &[
+    "FROM",
+    "COPY",
+    "ADD",
+    "RUN",
+    "ENV",
+    "CMD",
+    "SHELL",
+    "HEALTHCHECK",
+    "GIT",
+    "RUN_EXTEND",
+    "COPY_EXTEND",
+    "HOOK",
+    "INCLUDE",
+    "BUILD",
+];
+
+/// What a plugin is asked to compute inputs for, or to run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginRequest {
+    /// Upper-case keyword, e.g. `TERRAFORM`
+    pub instruction: String,
+    /// The rest of the line, e.g. `apply -auto-approve`
+    pub args: String,
+    /// ENV values in scope at the instruction
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginOutput {
+    pub exit_code: i32,
+    /// The step's artifact
+    #[serde(default)]
+    pub output: String,
+    #[serde(default)]
+    pub stderr: String,
+}
+
+/// A loaded plugin. Implemented by [`wasm::WasmPlugin`] and, in tests, by
+/// plain Rust.
+pub trait InstructionPlugin: Send + Sync {
+    /// Name for messages, the module's file stem
+    fn name(&self) -> &str;
+
+    /// Digest of the plugin's code, part of the cache key of its steps
+    fn digest(&self) -> &str;
+
+    fn instructions(&self) -> &[String];
+
+    /// Globs of the context files `request` reads.
+    fn inputs(&self, request: &PluginRequest) -> Result<Vec<String>>;
+
+    fn execute(&self, request: &PluginRequest) -> Result<PluginOutput>;
+}
+
+/// Plugins by the instruction they handle.
+#[derive(Default)]
+pub struct PluginSet {
+    by_instruction: HashMap<String, Arc<dyn InstructionPlugin>>,
+}
+
+impl PluginSet {
+    /// Directory plugins are loaded from for a build of `context_dir`.
+    pub fn dir_for(context_dir: &Path) -> PathBuf {
+        match std::env::var("MEMOBUILD_PLUGIN_DIR") {
+            Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => context_dir.join(crate::constants::DEFAULT_PLUGIN_DIR),
+        }
+    }
+
+    /// Load every `*.wasm` in the plugin directory. A missing directory
+    /// means no plugins.
+    pub fn load(context_dir: &Path) -> Result<Self> {
+        let dir = Self::dir_for(context_dir);
+        let mut modules: Vec<PathBuf> = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|ext| ext == "wasm"))
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+        };
+        modules.sort();
+
+        let mut set = Self::default();
+        for path in modules {
+            set.add(load_module(&path, context_dir)?)?;
+        }
+        Ok(set)
+    }
+
+    /// Add `plugin` for every instruction it handles. An instruction can
+    /// only have one plugin and built-in ones cannot be replaced.
+    pub fn add(&mut self, plugin: Arc<dyn InstructionPlugin>) -> Result<()> {
+        for instruction in plugin.instructions() {
+            let valid = !instruction.is_empty()
+                && instruction
+                    .chars()
+                    .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+            if !valid {
+                anyhow::bail!(
+                    "Plugin {} handles invalid instruction {:?}; use upper case, digits and _",
+                    plugin.name(),
+                    instruction
+                );
+            }
+            if BUILT_IN.contains(&instruction.as_str()) {
+                anyhow::bail!(
+                    "Plugin {} cannot handle the built-in instruction {}",
+                    plugin.name(),
+                    instruction
+                );
+            }
+            if let Some(other) = self.by_instruction.get(instruction) {
+                anyhow::bail!(
+                    "Plugins {} and {} both handle {}",
+                    other.name(),
+                    plugin.name(),
+                    instruction
+                );
+            }
+            self.by_instruction
+                .insert(instruction.clone(), plugin.clone());
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, instruction: &str) -> Option<&Arc<dyn InstructionPlugin>> {
+        self.by_instruction.get(instruction)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_instruction.is_empty()
+    }
+}
+
+#[cfg(feature = "wasm-plugins")]
+fn load_module(path: &Path, context_dir: &Path) -> Result<Arc<dyn InstructionPlugin>> {
+    Ok(Arc::new(wasm::WasmPlugin::load(path, context_dir)?))
+}
+
+#[cfg(not(feature = "wasm-plugins"))]
+fn load_module(path: &Path, _context_dir: &Path) -> Result<Arc<dyn InstructionPlugin>> {
+    anyhow::bail!(
+        "{} is a plugin, but this memobuild was built without the wasm-plugins feature",
+        path.display()
+    )
+}
+
+/// The request a plugin node stands for.
+pub fn request(node: &Node) -> Option<PluginRequest> {
+    let NodeKind::Plugin { instruction, args } = &node.kind else {
+        return None;
+    };
+    Some(PluginRequest {
+        instruction: instruction.clone(),
+        args: args.clone(),
+        env: node.metadata.build_env.clone(),
+    })
+}
+
+/// Turn unknown instructions a plugin handles into plugin nodes and key
+/// them by the plugin and the files it reads. Must run before composite
+/// hashes are computed. Returns how many nodes plugins took.
+pub fn apply(graph: &mut BuildGraph, plugins: &PluginSet, context_dir: &Path) -> Result<usize> {
+    if plugins.is_empty() {
+        return Ok(0);
+    }
+    let mut applied = 0;
+    for node in &mut graph.nodes {
+        if !matches!(node.kind, NodeKind::Other) {
+            continue;
+        }
+        let line = node.content.trim();
+        let (keyword, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let (instruction, args) = (keyword.to_uppercase(), args.trim().to_string());
+        let Some(plugin) = plugins.get(&instruction) else {
+            continue;
+        };
+        node.kind = NodeKind::Plugin { instruction, args };
+        let request = request(node).expect("plugin node");
+        let at = node.location_prefix();
+        let inputs = plugin
+            .inputs(&request)
+            .with_context(|| format!("{}plugin {} failed to list inputs", at, plugin.name()))?;
+        if !inputs.is_empty() {
+            let patterns: Vec<&str> = inputs.iter().map(String::as_str).collect();
+            node.metadata.declared_inputs = Some(
+                crate::docker::directives::inputs_digest(context_dir, &patterns)
+                    .with_context(|| format!("{}inputs of plugin {}", at, plugin.name()))?,
+            );
+        }
+        node.metadata.plugin = Some(plugin.digest().to_string());
+        node.metadata.tags.retain(|t| t != "other");
+        node.metadata.tags.push("plugin".to_string());
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+/// Runs plugin nodes through the plugin handling their instruction.
+pub struct PluginBackend {
+    plugins: Arc<PluginSet>,
+}
+
+impl PluginBackend {
+    pub fn new(plugins: Arc<PluginSet>) -> Self {
+        Self { plugins }
+    }
+}
+
+#[async_trait]
+impl ExecutorBackend for PluginBackend {
+    fn name(&self) -> &'static str {
+        "plugin"
+    }
+
+    async fn execute(&self, node: &Node, _cache: &HybridCache) -> Result<Vec<u8>> {
+        let request = request(node).context("Not a plugin instruction")?;
+        let plugin = self
+            .plugins
+            .get(&request.instruction)
+            .with_context(|| format!("No plugin handles {}", request.instruction))?
+            .clone();
+        println!("🧩 {} runs {}", plugin.name(), node.name);
+        let name = plugin.name().to_string();
+        let output = tokio::task::spawn_blocking(move || plugin.execute(&request))
+            .await?
+            .with_context(|| format!("{}plugin {} failed", node.location_prefix(), name))?;
+        if output.exit_code != 0 {
+            anyhow::bail!(
+                "{}Plugin {} failed with exit code {}: {}",
+                node.location_prefix(),
+                name,
+                output.exit_code,
+                output.stderr
+            );
+        }
+        Ok(output.output.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::{dag, parser};
+
+    /// Stands in for a module handling `SQL migrate`
+    struct Sql {
+        instructions: Vec<String>,
+    }
+
+    fn sql() -> Arc<Sql> {
+        Arc::new(Sql {
+            instructions: vec!["SQL".to_string()],
+        })
+    }
+
+    impl InstructionPlugin for Sql {
+        fn name(&self) -> &str {
+            "sql"
+        }
+        fn digest(&self) -> &str {
+            "sql-v1"
+        }
+        fn instructions(&self) -> &[String] {
+            &self.instructions
+        }
+        fn inputs(&self, _request: &PluginRequest) -> Result<Vec<String>> {
+            Ok(vec!["migrations/*.sql".to_string()])
+        }
+        fn execute(&self, request: &PluginRequest) -> Result<PluginOutput> {
+            Ok(PluginOutput {
+                exit_code: 0,
+                output: format!("{} done with {}", request.args, request.env["DB"]),
+                stderr: String::new(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plugin_instructions() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join("migrations")).unwrap();
+        std::fs::write(dir.path().join("migrations/001.sql"), "CREATE TABLE t;").unwrap();
+
+        let mut plugins = PluginSet::default();
+        plugins.add(sql()).unwrap();
+        assert!(plugins.add(sql()).is_err());
+
+        let dockerfile = "FROM postgres\nENV DB=app\nSQL migrate\nTERRAFORM apply\n";
+        let mut graph = dag::build_graph_from_spanned(
+            parser::parse_dockerfile_spanned(dockerfile, Path::new("Dockerfile")),
+            dir.path().to_path_buf(),
+        );
+        assert_eq!(apply(&mut graph, &plugins, dir.path()).unwrap(), 1);
+        assert_eq!(
+            graph.nodes[2].kind,
+            NodeKind::Plugin {
+                instruction: "SQL".into(),
+                args: "migrate".into()
+            }
+        );
+        assert!(graph.nodes[2].metadata.declared_inputs.is_some());
+        assert_eq!(graph.nodes[2].metadata.plugin.as_deref(), Some("sql-v1"));
+        // Nobody handles TERRAFORM here
+        assert!(matches!(graph.nodes[3].kind, NodeKind::Other));
+
+        std::env::set_var("MEMOBUILD_CACHE_DIR", dir.path().join("cache"));
+        let cache = HybridCache::new(None).unwrap();
+        let artifact = PluginBackend::new(Arc::new(plugins))
+            .execute(&graph.nodes[2], &cache)
+            .await
+            .unwrap();
+        assert_eq!(artifact, b"migrate done with app");
+    }
+}
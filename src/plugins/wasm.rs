@@ -0,0 +1,143 @@
+//! Plugins compiled to WebAssembly, run with wasmtime in a fresh instance per
+//! call.
+
+use super::{InstructionPlugin, PluginOutput, PluginRequest};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use wasmtime::{Engine, Linker, Module, Store};
+use wasmtime_wasi::sync::{ambient_authority, Dir, WasiCtxBuilder};
+use wasmtime_wasi::WasiCtx;
+
+// A module exports `memory` and the functions below, which take and return
+// JSON. Results are returned as `ptr << 32 | len` of a buffer in `memory`.
+
+/// `(len: i32) -> i32`: room for a request of `len` bytes
+const ALLOC: &str = "memobuild_alloc";
+/// `() -> i64`: the upper-case keywords it handles, e.g. `["TERRAFORM"]`
+const INSTRUCTIONS: &str = "memobuild_instructions";
+/// `(ptr: i32, len: i32) -> i64`: globs of the context files a
+/// [`PluginRequest`] reads
+const INPUTS: &str = "memobuild_inputs";
+/// `(ptr: i32, len: i32) -> i64`: runs a [`PluginRequest`] and returns a
+/// [`PluginOutput`]
+const EXECUTE: &str = "memobuild_execute";
+
+pub struct WasmPlugin {
+    name: String,
+    digest: String,
+    instructions: Vec<String>,
+    engine: Engine,
+    module: Module,
+    linker: Linker<WasiCtx>,
+    context_dir: PathBuf,
+}
+
+impl WasmPlugin {
+    /// Compile the module at `path` and ask it which instructions it
+    /// handles.
+    pub fn load(path: &Path, context_dir: &Path) -> Result<Self> {
+        let bytes =
+            std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let engine = Engine::default();
+        let module = Module::new(&engine, &bytes)
+            .with_context(|| format!("{} is not a valid WebAssembly module", path.display()))?;
+        let mut linker = Linker::new(&engine);
+        wasmtime_wasi::sync::add_to_linker(&mut linker, |ctx| ctx)?;
+
+        let mut plugin = Self {
+            name: path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            digest: blake3::hash(&bytes).to_hex().to_string(),
+            instructions: Vec::new(),
+            engine,
+            module,
+            linker,
+            context_dir: context_dir.to_path_buf(),
+        };
+        let listed = plugin
+            .call(INSTRUCTIONS, None)
+            .with_context(|| format!("Plugin {} failed to list its instructions", plugin.name))?;
+        plugin.instructions = serde_json::from_slice(&listed)
+            .with_context(|| format!("Plugin {} listed invalid instructions", plugin.name))?;
+        Ok(plugin)
+    }
+
+    /// Call `export` on a fresh instance, passing `input` through `memory`,
+    /// and return the buffer it points to.
+    fn call(&self, export: &str, input: Option<&[u8]>) -> Result<Vec<u8>> {
+        let mut wasi = WasiCtxBuilder::new();
+        wasi.inherit_stderr();
+        let context = Dir::open_ambient_dir(&self.context_dir, ambient_authority())
+            .with_context(|| format!("Failed to open {}", self.context_dir.display()))?;
+        wasi.preopened_dir(context, "/context")?;
+        let mut store = Store::new(&self.engine, wasi.build());
+
+        let instance = self.linker.instantiate(&mut store, &self.module)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("Plugin exports no memory")?;
+        let packed = match input {
+            None => instance
+                .get_typed_func::<(), i64>(&mut store, export)?
+                .call(&mut store, ())?,
+            Some(input) => {
+                let len = i32::try_from(input.len()).context("Request too large")?;
+                let ptr = instance
+                    .get_typed_func::<i32, i32>(&mut store, ALLOC)?
+                    .call(&mut store, len)?;
+                memory.write(&mut store, ptr as u32 as usize, input)?;
+                instance
+                    .get_typed_func::<(i32, i32), i64>(&mut store, export)?
+                    .call(&mut store, (ptr, len))?
+            }
+        };
+
+        let packed = packed as u64;
+        let (ptr, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let mut output = vec![0; len];
+        memory
+            .read(&store, ptr, &mut output)
+            .context("Plugin returned a buffer outside its memory")?;
+        Ok(output)
+    }
+
+    fn call_json<T: serde::de::DeserializeOwned>(
+        &self,
+        export: &str,
+        request: &PluginRequest,
+    ) -> Result<T> {
+        let output = self.call(export, Some(&serde_json::to_vec(request)?))?;
+        serde_json::from_slice(&output).with_context(|| {
+            format!(
+                "Plugin {} returned invalid JSON from {}: {}",
+                self.name,
+                export,
+                String::from_utf8_lossy(&output)
+            )
+        })
+    }
+}
+
+impl InstructionPlugin for WasmPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn digest(&self) -> &str {
+        &self.digest
+    }
+
+    fn instructions(&self) -> &[String] {
+        &self.instructions
+    }
+
+    fn inputs(&self, request: &PluginRequest) -> Result<Vec<String>> {
+        self.call_json(INPUTS, request)
+    }
+
+    fn execute(&self, request: &PluginRequest) -> Result<PluginOutput> {
+        self.call_json(EXECUTE, request)
+    }
+}
@@ -0,0 +1,104 @@
+//! The steps between building a graph and knowing its cache keys, shared by
+//! builds, the daemon and the commands that show keys without building.
+
+use crate::cache::HybridCache;
+use crate::env::EnvFingerprint;
+use crate::graph::BuildGraph;
+use crate::plugins::PluginSet;
+use crate::sandbox::trace::{self, TraceStore};
+use crate::toolchains::{self, ToolchainManifest};
+use anyhow::Result;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The environment builds in `context_dir` key steps in: this machine's,
+/// with the toolchains the context pins in place of the host's, installed
+/// yet or not.
+pub fn env_fingerprint(context_dir: &Path, cache_dir: &Path) -> Result<EnvFingerprint> {
+    let mut env_fp = EnvFingerprint::collect();
+    if let Some(manifest) = ToolchainManifest::load(context_dir)? {
+        env_fp.pin_toolchains(&toolchains::planned(
+            &manifest,
+            &cache_dir.join("toolchains"),
+        )?);
+    }
+    Ok(env_fp)
+}
+
+/// What [`configure_steps`] found, for builds to report.
+pub struct StepSettings {
+    pub plugins: Arc<PluginSet>,
+    /// Steps handled by plugins
+    pub plugin_steps: usize,
+    /// RUN steps run on persistent workers
+    pub workers: usize,
+}
+
+/// Configure every step before its sources are hashed: network policies,
+/// shells, plugins, `# memobuild:` directives, persistent workers and the
+/// extra dependencies the AI layer finds.
+pub fn configure_steps(
+    graph: &mut BuildGraph,
+    context_dir: &Path,
+    env_fp: &EnvFingerprint,
+) -> Result<StepSettings> {
+    let network_default = crate::sandbox::network::default_policy_from_env()?;
+    crate::sandbox::network::apply_network_policies(graph, network_default.as_ref())?;
+    let shell_default = crate::sandbox::shell::default_from_env()?;
+    crate::sandbox::shell::apply_default(graph, shell_default.as_deref());
+    let plugins = Arc::new(PluginSet::load(context_dir)?);
+    let plugin_steps = crate::plugins::apply(graph, &plugins, context_dir)?;
+    crate::docker::directives::apply(graph, context_dir)?;
+    let workers = crate::sandbox::worker::apply_workers(graph, context_dir)?;
+    crate::ai::AiLayer::new().analyze(graph, env_fp, context_dir);
+    Ok(StepSettings {
+        plugins,
+        plugin_steps,
+        workers,
+    })
+}
+
+/// The traces RUN steps are keyed by, when `MEMOBUILD_TRACE_INPUTS` asks
+/// for them and strace is there to record them.
+pub fn load_traces(cache_dir: &Path) -> Result<Option<TraceStore>> {
+    if !trace::enabled_from_env() || !trace::tracer_available() {
+        return Ok(None);
+    }
+    TraceStore::load(&TraceStore::path_in(cache_dir)).map(Some)
+}
+
+/// What [`finish_keys`] did, for builds to report.
+pub struct KeyStats {
+    /// Remote ADD sources downloaded
+    pub downloaded: usize,
+    /// `COPY --from` image sources extracted
+    pub extracted: usize,
+    /// RUN steps keyed by the files they were traced reading
+    pub traced: usize,
+}
+
+/// Once sources are hashed: fetch remote ADD and `COPY --from` image
+/// sources, mark what changed and compute every step's cache key. With
+/// `traces`, RUN steps traced before are keyed by the files they read.
+pub async fn finish_keys(
+    graph: &mut BuildGraph,
+    cache: &HybridCache,
+    context_dir: &Path,
+    env_fp: &EnvFingerprint,
+    traces: Option<&TraceStore>,
+) -> Result<KeyStats> {
+    let downloaded = crate::docker::add::fetch_remote_sources(graph, cache).await?;
+    let extracted = crate::docker::copy_from::fetch_image_sources(graph, cache).await?;
+    crate::core::detect_changes(graph);
+    crate::core::propagate_dirty(graph);
+    let traced = match traces {
+        Some(store) => trace::apply(graph, store, context_dir)?,
+        None => 0,
+    };
+    crate::core::compute_composite_hashes(graph, env_fp);
+    Ok(KeyStats {
+        downloaded,
+        extracted,
+        traced,
+    })
+}
@@ -5,6 +5,7 @@ use std::collections::HashMap;
 use std::time::Duration;
 
 pub mod client;
+pub mod queue;
 pub mod scheduler;
 #[cfg(any(feature = "server", feature = "remote-exec"))]
 pub mod server;
@@ -29,6 +30,12 @@ pub struct ActionRequest {
     pub platform_properties: HashMap<String, String>,
     pub output_files: Vec<String>,
     pub output_directories: Vec<String>,
+    /// Namespace whose concurrency limit the action counts against
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// Higher runs first when actions wait for a worker
+    #[serde(default)]
+    pub priority: u8,
 }
 
 /// ActionResult represents the result of a remote execution.
@@ -39,6 +46,9 @@ pub struct ActionResult {
     pub exit_code: i32,
     pub stdout_raw: Vec<u8>,
     pub stderr_raw: Vec<u8>,
+    /// Names of the variables the command ran with
+    #[serde(default)]
+    pub injected_env: Vec<String>,
     pub execution_metadata: ExecutionMetadata,
 }
 
@@ -48,6 +58,9 @@ pub struct ExecutionMetadata {
     pub queued_timestamp: Option<i64>,
     pub worker_start_timestamp: Option<i64>,
     pub worker_completed_timestamp: Option<i64>,
+    /// What the command cost on the worker
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource_usage: Option<crate::sandbox::usage::ResourceUsage>,
 }
 
 #[async_trait]
@@ -0,0 +1,663 @@
+//! Execution queue of the scheduler, where actions wait for a worker that has
+//! the slots and capabilities they ask for.
+
+use crate::remote_exec::scheduler::SchedulingStrategy;
+use crate::remote_exec::ActionRequest;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+/// Slots of a worker that registered without saying how many it has
+const DEFAULT_WORKER_SLOTS: usize = 1;
+
+/// What a worker offers, sent when it registers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkerCapabilities {
+    /// OCI platform, e.g. `linux/amd64`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_bytes: Option<u64>,
+    /// Free-form capabilities, e.g. `gpu`
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Actions the worker runs at once
+    #[serde(default = "default_slots")]
+    pub slots: usize,
+}
+
+fn default_slots() -> usize {
+    DEFAULT_WORKER_SLOTS
+}
+
+impl Default for WorkerCapabilities {
+    fn default() -> Self {
+        Self {
+            platform: None,
+            memory_bytes: None,
+            tags: Vec::new(),
+            slots: DEFAULT_WORKER_SLOTS,
+        }
+    }
+}
+
+impl WorkerCapabilities {
+    /// This machine's platform and memory, with `MEMOBUILD_WORKER_TAGS`,
+    /// `MEMOBUILD_WORKER_SLOTS` (default: one per CPU) and
+    /// `MEMOBUILD_WORKER_MEMORY` overriding the detected memory.
+    pub fn from_env() -> Result<Self> {
+        let memory_bytes = match std::env::var("MEMOBUILD_WORKER_MEMORY") {
+            Ok(value) => Some(parse_memory(&value).context("Invalid MEMOBUILD_WORKER_MEMORY")?),
+            Err(_) => host_memory(),
+        };
+        let slots = match std::env::var("MEMOBUILD_WORKER_SLOTS") {
+            Ok(value) => value
+                .trim()
+                .parse::<usize>()
+                .map(|n| n.max(1))
+                .with_context(|| format!("Invalid MEMOBUILD_WORKER_SLOTS '{}'", value))?,
+            Err(_) => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        Ok(Self {
+            platform: Some(crate::env::host_platform()),
+            memory_bytes,
+            tags: std::env::var("MEMOBUILD_WORKER_TAGS")
+                .map(|tags| {
+                    crate::docker::directives::list(&tags)
+                        .into_iter()
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            slots,
+        })
+    }
+}
+
+/// `MemTotal` from `/proc/meminfo`, where there is one.
+fn host_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let kib: u64 = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+/// Parse a size such as `512M`, `8G` or `8GiB` (binary units) or a plain
+/// byte count.
+pub fn parse_memory(value: &str) -> Result<u64> {
+    let lower = value.trim().to_ascii_lowercase();
+    let digits_end = lower
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(digits_end);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid memory size '{}'", value))?;
+    let shift = match unit.trim().trim_end_matches("ib").trim_end_matches('b') {
+        "" => 0,
+        "k" => 10,
+        "m" => 20,
+        "g" => 30,
+        "t" => 40,
+        _ => anyhow::bail!("Invalid memory unit in '{}'", value),
+    };
+    Ok((number * (1u64 << shift) as f64) as u64)
+}
+
+/// What an action needs from a worker, from its `platform_properties`:
+/// `platform`, `memory` and comma-separated `tags`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Requirements {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+impl Requirements {
+    pub fn from_properties(properties: &HashMap<String, String>) -> Result<Self> {
+        Ok(Self {
+            platform: properties.get("platform").cloned(),
+            memory_bytes: properties
+                .get("memory")
+                .map(|m| parse_memory(m))
+                .transpose()?,
+            tags: properties
+                .get("tags")
+                .map(|tags| {
+                    crate::docker::directives::list(tags)
+                        .into_iter()
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Whether a worker with `caps` can run the action. A worker that did
+    /// not report its platform or memory matches no requirement on it.
+    pub fn satisfied_by(&self, caps: &WorkerCapabilities) -> bool {
+        self.platform
+            .as_ref()
+            .is_none_or(|p| caps.platform.as_ref() == Some(p))
+            && self
+                .memory_bytes
+                .is_none_or(|m| caps.memory_bytes.is_some_and(|have| have >= m))
+            && self.tags.iter().all(|t| caps.tags.contains(t))
+    }
+}
+
+/// Most actions of a namespace running at once.
+#[derive(Debug, Clone, Default)]
+pub struct NamespaceLimits {
+    default: Option<usize>,
+    namespaces: HashMap<String, usize>,
+}
+
+impl NamespaceLimits {
+    /// Parse `team-a=8,team-b=2,*=4`; `*` applies to namespaces not listed.
+    /// Namespaces without a limit are only bounded by the workers.
+    pub fn parse(value: &str) -> Result<Self> {
+        let mut limits = Self::default();
+        for item in crate::docker::directives::list(value) {
+            let (namespace, limit) = item
+                .split_once('=')
+                .with_context(|| format!("Invalid namespace limit '{}'", item))?;
+            let limit: usize = limit
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid namespace limit '{}'", item))?;
+            match namespace.trim() {
+                "*" => limits.default = Some(limit),
+                namespace => {
+                    limits.namespaces.insert(namespace.to_string(), limit);
+                }
+            }
+        }
+        Ok(limits)
+    }
+
+    /// Limits from `MEMOBUILD_EXEC_NAMESPACE_LIMITS`, none when unset.
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("MEMOBUILD_EXEC_NAMESPACE_LIMITS") {
+            Ok(value) => Self::parse(&value).context("Invalid MEMOBUILD_EXEC_NAMESPACE_LIMITS"),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn limit(&self, namespace: &str) -> Option<usize> {
+        self.namespaces.get(namespace).copied().or(self.default)
+    }
+}
+
+/// A worker slot handed to a waiting action.
+struct Assignment {
+    worker_id: String,
+    endpoint: String,
+}
+
+struct Pending {
+    id: u64,
+    namespace: String,
+    priority: u8,
+    requirements: Requirements,
+    input_digest: String,
+    enqueued: Instant,
+    enqueued_at_ms: i64,
+    tx: oneshot::Sender<Assignment>,
+}
+
+struct Running {
+    namespace: String,
+    priority: u8,
+    worker_id: String,
+    started: Instant,
+}
+
+struct WorkerSlot {
+    endpoint: String,
+    capabilities: WorkerCapabilities,
+    running: usize,
+}
+
+#[derive(Default)]
+struct QueueState {
+    next_id: u64,
+    pending: Vec<Pending>,
+    running: HashMap<u64, Running>,
+    workers: BTreeMap<String, WorkerSlot>,
+    round_robin: usize,
+}
+
+impl QueueState {
+    fn running_in(&self, namespace: &str) -> usize {
+        self.running
+            .values()
+            .filter(|r| r.namespace == namespace)
+            .count()
+    }
+}
+
+pub struct ExecutionQueue {
+    strategy: SchedulingStrategy,
+    limits: NamespaceLimits,
+    state: Mutex<QueueState>,
+}
+
+/// A worker slot held by an action; the slot is given back when this is
+/// dropped.
+pub struct Lease {
+    queue: Arc<ExecutionQueue>,
+    pub id: u64,
+    pub worker_id: String,
+    pub endpoint: String,
+    /// Unix milliseconds the action was queued at
+    pub queued_at_ms: i64,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.queue.release(self.id);
+    }
+}
+
+impl ExecutionQueue {
+    pub fn new(strategy: SchedulingStrategy, limits: NamespaceLimits) -> Self {
+        Self {
+            strategy,
+            limits,
+            state: Mutex::new(QueueState::default()),
+        }
+    }
+
+    /// Add a worker, or update one that registers again; actions it is
+    /// running keep their slots.
+    pub fn register_worker(&self, worker_id: &str, endpoint: &str, caps: WorkerCapabilities) {
+        let mut state = self.state.lock().unwrap();
+        let running = state.workers.get(worker_id).map_or(0, |w| w.running);
+        state.workers.insert(
+            worker_id.to_string(),
+            WorkerSlot {
+                endpoint: endpoint.to_string(),
+                capabilities: caps,
+                running,
+            },
+        );
+        self.dispatch(&mut state);
+    }
+
+    /// Registered workers as `(id, endpoint)`.
+    pub fn workers(&self) -> Vec<(String, String)> {
+        let state = self.state.lock().unwrap();
+        state
+            .workers
+            .iter()
+            .map(|(id, w)| (id.clone(), w.endpoint.clone()))
+            .collect()
+    }
+
+    /// Wait for a worker slot that fits `action`. Fails straight away when
+    /// no registered worker could ever run it. Dropping the future gives up
+    /// the place in the queue.
+    pub async fn acquire(self: &Arc<Self>, action: &ActionRequest) -> Result<Lease> {
+        let requirements = Requirements::from_properties(&action.platform_properties)?;
+        let (tx, rx) = oneshot::channel();
+        let queued_at_ms = chrono::Utc::now().timestamp_millis();
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            if !state
+                .workers
+                .values()
+                .any(|w| requirements.satisfied_by(&w.capabilities))
+            {
+                anyhow::bail!(
+                    "No registered worker can run this action (requires {})",
+                    serde_json::to_string(&requirements)?
+                );
+            }
+            state.next_id += 1;
+            let id = state.next_id;
+            state.pending.push(Pending {
+                id,
+                namespace: action
+                    .namespace
+                    .clone()
+                    .unwrap_or_else(|| crate::constants::DEFAULT_NAMESPACE.to_string()),
+                priority: action.priority,
+                requirements,
+                input_digest: action.input_root_digest.hash.clone(),
+                enqueued: Instant::now(),
+                enqueued_at_ms: queued_at_ms,
+                tx,
+            });
+            self.dispatch(&mut state);
+            id
+        };
+        // Made before waiting, so a slot assigned to a future that is then
+        // dropped is still given back
+        let mut lease = Lease {
+            queue: self.clone(),
+            id,
+            worker_id: String::new(),
+            endpoint: String::new(),
+            queued_at_ms,
+        };
+        let assignment = rx.await.context("Execution queue dropped the action")?;
+        lease.worker_id = assignment.worker_id;
+        lease.endpoint = assignment.endpoint;
+        Ok(lease)
+    }
+
+    fn release(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(running) = state.running.remove(&id) {
+            if let Some(worker) = state.workers.get_mut(&running.worker_id) {
+                worker.running = worker.running.saturating_sub(1);
+            }
+        }
+        self.dispatch(&mut state);
+    }
+
+    /// Hand free worker slots to waiting actions, best first.
+    fn dispatch(&self, state: &mut QueueState) {
+        state.pending.retain(|p| !p.tx.is_closed());
+        loop {
+            let best = state
+                .pending
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| {
+                    self.limits
+                        .limit(&p.namespace)
+                        .is_none_or(|limit| state.running_in(&p.namespace) < limit)
+                        && state.workers.values().any(|w| {
+                            w.running < w.capabilities.slots
+                                && p.requirements.satisfied_by(&w.capabilities)
+                        })
+                })
+                .min_by_key(|(_, p)| {
+                    (
+                        std::cmp::Reverse(p.priority),
+                        state.running_in(&p.namespace),
+                        p.enqueued,
+                    )
+                })
+                .map(|(i, _)| i);
+            let Some(index) = best else {
+                return;
+            };
+            let pending = state.pending.remove(index);
+            let Some(worker_id) = self.pick_worker(state, &pending) else {
+                state.pending.insert(index, pending);
+                return;
+            };
+            let worker = state.workers.get_mut(&worker_id).expect("picked worker");
+            let assignment = Assignment {
+                worker_id: worker_id.clone(),
+                endpoint: worker.endpoint.clone(),
+            };
+            if pending.tx.send(assignment).is_err() {
+                // Gave up waiting in the meantime
+                continue;
+            }
+            worker.running += 1;
+            state.running.insert(
+                pending.id,
+                Running {
+                    namespace: pending.namespace,
+                    priority: pending.priority,
+                    worker_id,
+                    started: Instant::now(),
+                },
+            );
+        }
+    }
+
+    /// Among the workers with a free slot that fit `pending`, the one the
+    /// scheduling strategy prefers.
+    fn pick_worker(&self, state: &mut QueueState, pending: &Pending) -> Option<String> {
+        let free: Vec<(&String, &WorkerSlot)> = state
+            .workers
+            .iter()
+            .filter(|(_, w)| {
+                w.running < w.capabilities.slots
+                    && pending.requirements.satisfied_by(&w.capabilities)
+            })
+            .collect();
+        if free.is_empty() {
+            return None;
+        }
+        let index = match self.strategy {
+            SchedulingStrategy::LeastLoaded => free
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| {
+                    // running / slots, compared without dividing
+                    (a.1.running * b.1.capabilities.slots)
+                        .cmp(&(b.1.running * a.1.capabilities.slots))
+                })
+                .map(|(i, _)| i)
+                .unwrap_or(0),
+            SchedulingStrategy::Random => {
+                use rand::Rng;
+                rand::thread_rng().gen_range(0..free.len())
+            }
+            SchedulingStrategy::RoundRobin => state.round_robin % free.len(),
+            SchedulingStrategy::DataLocality => {
+                // Same inputs, same worker, while the set of free workers holds
+                let hash = blake3::hash(pending.input_digest.as_bytes());
+                let bytes = hash.as_bytes();
+                (bytes[0] as usize + ((bytes[1] as usize) << 8)) % free.len()
+            }
+        };
+        let worker_id = free[index].0.clone();
+        state.round_robin += 1;
+        Some(worker_id)
+    }
+
+    /// What is waiting and running, per namespace too.
+    pub fn snapshot(&self) -> QueueSnapshot {
+        let state = self.state.lock().unwrap();
+        let mut namespaces: BTreeMap<String, NamespaceUsage> = BTreeMap::new();
+        let pending = state
+            .pending
+            .iter()
+            .filter(|p| !p.tx.is_closed())
+            .map(|p| {
+                self.usage(&mut namespaces, &p.namespace).queued += 1;
+                QueuedAction {
+                    id: p.id,
+                    namespace: p.namespace.clone(),
+                    priority: p.priority,
+                    requirements: p.requirements.clone(),
+                    queued_at_ms: p.enqueued_at_ms,
+                    waiting_ms: p.enqueued.elapsed().as_millis() as u64,
+                }
+            })
+            .collect();
+        let mut running: Vec<RunningAction> = state
+            .running
+            .iter()
+            .map(|(&id, r)| {
+                self.usage(&mut namespaces, &r.namespace).running += 1;
+                RunningAction {
+                    id,
+                    namespace: r.namespace.clone(),
+                    priority: r.priority,
+                    worker_id: r.worker_id.clone(),
+                    running_ms: r.started.elapsed().as_millis() as u64,
+                }
+            })
+            .collect();
+        running.sort_by_key(|r| r.id);
+        QueueSnapshot {
+            pending,
+            running,
+            namespaces,
+        }
+    }
+
+    fn usage<'a>(
+        &self,
+        namespaces: &'a mut BTreeMap<String, NamespaceUsage>,
+        namespace: &str,
+    ) -> &'a mut NamespaceUsage {
+        namespaces
+            .entry(namespace.to_string())
+            .or_insert_with(|| NamespaceUsage {
+                queued: 0,
+                running: 0,
+                limit: self.limits.limit(namespace),
+            })
+    }
+
+    /// Registered workers with their capabilities and load.
+    pub fn worker_status(&self) -> Vec<WorkerStatus> {
+        let state = self.state.lock().unwrap();
+        state
+            .workers
+            .iter()
+            .map(|(id, w)| WorkerStatus {
+                worker_id: id.clone(),
+                endpoint: w.endpoint.clone(),
+                capabilities: w.capabilities.clone(),
+                running: w.running,
+            })
+            .collect()
+    }
+}
+
+/// Served at `GET /queue`.
+#[derive(Debug, Serialize)]
+pub struct QueueSnapshot {
+    /// In the order they were queued; not the order they will run in
+    pub pending: Vec<QueuedAction>,
+    pub running: Vec<RunningAction>,
+    pub namespaces: BTreeMap<String, NamespaceUsage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueuedAction {
+    pub id: u64,
+    pub namespace: String,
+    pub priority: u8,
+    pub requirements: Requirements,
+    pub queued_at_ms: i64,
+    pub waiting_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunningAction {
+    pub id: u64,
+    pub namespace: String,
+    pub priority: u8,
+    pub worker_id: String,
+    pub running_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NamespaceUsage {
+    pub queued: usize,
+    pub running: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+/// Served at `GET /workers/status`.
+#[derive(Debug, Serialize)]
+pub struct WorkerStatus {
+    pub worker_id: String,
+    pub endpoint: String,
+    #[serde(flatten)]
+    pub capabilities: WorkerCapabilities,
+    pub running: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn action(namespace: &str, priority: u8, properties: &[(&str, &str)]) -> ActionRequest {
+        ActionRequest {
+            command: vec!["true".to_string()],
+            env: HashMap::new(),
+            input_root_digest: crate::remote_exec::Digest {
+                hash: "abc".to_string(),
+                size_bytes: 0,
+            },
+            timeout: Duration::from_secs(60),
+            platform_properties: properties
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            output_files: Vec::new(),
+            output_directories: Vec::new(),
+            namespace: Some(namespace.to_string()),
+            priority,
+        }
+    }
+
+    fn caps(slots: usize, tags: &[&str]) -> WorkerCapabilities {
+        WorkerCapabilities {
+            platform: Some("linux/amd64".to_string()),
+            memory_bytes: Some(8 << 30),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            slots,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_namespace_limits_and_requirements() {
+        let queue = Arc::new(ExecutionQueue::new(
+            SchedulingStrategy::LeastLoaded,
+            NamespaceLimits::parse("team-a=1").unwrap(),
+        ));
+        queue.register_worker("w1", "http://w1", caps(2, &[]));
+
+        let first = queue.acquire(&action("team-a", 0, &[])).await.unwrap();
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire(&action("team-a", 9, &[])).await.map(|l| l.id) }
+        });
+        // team-a is at its limit, so the free slot goes to team-b
+        let other = queue.acquire(&action("team-b", 0, &[])).await.unwrap();
+        while queue.snapshot().pending.len() != 1 {
+            tokio::task::yield_now().await;
+        }
+        let snapshot = queue.snapshot();
+        assert_eq!(snapshot.namespaces["team-a"].limit, Some(1));
+        assert_eq!(snapshot.running.len(), 2);
+
+        drop(first);
+        assert!(waiting.await.unwrap().is_ok());
+        drop(other);
+
+        let err = queue
+            .acquire(&action("team-b", 0, &[("tags", "gpu")]))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("No registered worker"));
+        assert!(queue
+            .acquire(&action(
+                "team-b",
+                0,
+                &[("memory", "4G"), ("platform", "linux/amd64")]
+            ))
+            .await
+            .is_ok());
+        assert_eq!(parse_memory("1.5GiB").unwrap(), 3 << 29);
+    }
+}
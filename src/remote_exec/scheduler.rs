@@ -1,9 +1,8 @@
+use crate::remote_exec::queue::{ExecutionQueue, NamespaceLimits, WorkerCapabilities};
 use crate::remote_exec::{ActionRequest, ActionResult, RemoteExecutor};
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use async_trait::async_trait;
-use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SchedulingStrategy {
@@ -14,78 +13,55 @@ pub enum SchedulingStrategy {
 }
 
 pub struct Scheduler {
-    strategy: SchedulingStrategy,
-    worker_endpoints: Arc<Mutex<HashMap<String, String>>>, // worker_id -> endpoint
-    next_worker_idx: Mutex<usize>,
+    queue: Arc<ExecutionQueue>,
 }
 
 impl Scheduler {
     pub fn new(strategy: SchedulingStrategy) -> Self {
+        Self::with_limits(strategy, NamespaceLimits::default())
+    }
+
+    /// A scheduler that caps the actions each namespace runs at once.
+    pub fn with_limits(strategy: SchedulingStrategy, limits: NamespaceLimits) -> Self {
         Self {
-            strategy,
-            worker_endpoints: Arc::new(Mutex::new(HashMap::new())),
-            next_worker_idx: Mutex::new(0),
+            queue: Arc::new(ExecutionQueue::new(strategy, limits)),
         }
     }
 
-    pub async fn register_worker(&self, worker_id: String, endpoint: String) {
-        let mut endpoints = self.worker_endpoints.lock().await;
-        endpoints.insert(worker_id.clone(), endpoint);
+    pub async fn register_worker(
+        &self,
+        worker_id: String,
+        endpoint: String,
+        capabilities: WorkerCapabilities,
+    ) {
+        self.queue
+            .register_worker(&worker_id, &endpoint, capabilities);
         println!("📝 Scheduler registered worker: {}", worker_id);
     }
 
     pub async fn get_available_workers(&self) -> Vec<(String, String)> {
-        let endpoints = self.worker_endpoints.lock().await;
-        endpoints
-            .iter()
-            .map(|(id, endpoint)| (id.clone(), endpoint.clone()))
-            .collect()
+        self.queue.workers()
     }
 
-    async fn select_worker(&self, action: &ActionRequest) -> Result<String> {
-        let workers = self.get_available_workers().await;
-        if workers.is_empty() {
-            return Err(anyhow!("No available workers registered with scheduler"));
-        }
-
-        match self.strategy {
-            SchedulingStrategy::Random => {
-                use rand::Rng;
-                let idx = rand::thread_rng().gen_range(0..workers.len());
-                Ok(workers[idx].1.clone())
-            }
-            SchedulingStrategy::RoundRobin => {
-                let mut next = self.next_worker_idx.lock().await;
-                let worker = &workers[*next % workers.len()];
-                *next += 1;
-                Ok(worker.1.clone())
-            }
-            SchedulingStrategy::LeastLoaded => {
-                // For MVP, fallback to RoundRobin
-                let mut next = self.next_worker_idx.lock().await;
-                let worker = &workers[*next % workers.len()];
-                *next += 1;
-                Ok(worker.1.clone())
-            }
-            SchedulingStrategy::DataLocality => {
-                // Consistent hashing based on input root digest
-                let hash_val = blake3::hash(action.input_root_digest.hash.as_bytes());
-                let bytes = hash_val.as_bytes();
-                let idx = (bytes[0] as usize + ((bytes[1] as usize) << 8)) % workers.len();
-                Ok(workers[idx].1.clone())
-            }
-        }
+    pub fn queue(&self) -> &Arc<ExecutionQueue> {
+        &self.queue
     }
 }
 
 #[async_trait]
 impl RemoteExecutor for Scheduler {
     async fn execute(&self, action: ActionRequest) -> Result<ActionResult> {
-        let worker_endpoint = self.select_worker(&action).await?;
-        println!("🎯 Dispatching action to worker: {}", worker_endpoint);
+        // Held until the worker answers, so the slot is not handed out twice
+        let lease = self.queue.acquire(&action).await?;
+        println!(
+            "🎯 Dispatching action to worker: {} ({})",
+            lease.worker_id, lease.endpoint
+        );
 
         // Create a client executor for the selected worker
-        let client = crate::remote_exec::client::RemoteExecClient::new(&worker_endpoint);
-        client.execute(action).await
+        let client = crate::remote_exec::client::RemoteExecClient::new(&lease.endpoint);
+        let mut result = client.execute(action).await?;
+        result.execution_metadata.queued_timestamp = Some(lease.queued_at_ms);
+        Ok(result)
     }
 }
@@ -1,3 +1,4 @@
+use crate::remote_exec::queue::{QueueSnapshot, WorkerCapabilities, WorkerStatus};
 use crate::remote_exec::{scheduler::Scheduler, ActionRequest, ActionResult};
 use anyhow::Result;
 use axum::{
@@ -12,6 +13,8 @@ use std::sync::Arc;
 struct WorkerRegistration {
     worker_id: String,
     endpoint: String,
+    #[serde(flatten)]
+    capabilities: WorkerCapabilities,
 }
 
 pub struct ExecutionServer {
@@ -28,6 +31,8 @@ impl ExecutionServer {
             .route("/execute", post(handle_execute))
             .route("/workers/register", post(handle_register_worker))
             .route("/workers", get(handle_list_workers))
+            .route("/workers/status", get(handle_worker_status))
+            .route("/queue", get(handle_queue))
             .layer(Extension(self.scheduler.clone()));
 
         let addr = SocketAddr::from(([0, 0, 0, 0], port));
@@ -57,7 +62,11 @@ async fn handle_register_worker(
     Json(registration): Json<WorkerRegistration>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     scheduler
-        .register_worker(registration.worker_id.clone(), registration.endpoint)
+        .register_worker(
+            registration.worker_id.clone(),
+            registration.endpoint,
+            registration.capabilities,
+        )
         .await;
 
     Ok(Json(serde_json::json!({
@@ -71,3 +80,13 @@ async fn handle_list_workers(Extension(scheduler): Extension<Arc<Scheduler>>) ->
     let worker_ids: Vec<String> = workers.into_iter().map(|(id, _)| id).collect();
     Json(worker_ids)
 }
+
+async fn handle_worker_status(
+    Extension(scheduler): Extension<Arc<Scheduler>>,
+) -> Json<Vec<WorkerStatus>> {
+    Json(scheduler.queue().worker_status())
+}
+
+async fn handle_queue(Extension(scheduler): Extension<Arc<Scheduler>>) -> Json<QueueSnapshot> {
+    Json(scheduler.queue().snapshot())
+}
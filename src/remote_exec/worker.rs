@@ -38,13 +38,16 @@ impl RemoteExecutor for WorkerNode {
             name: format!("remote-action-{}", &action.input_root_digest.hash[..8]),
             kind: NodeKind::Run,
             content: action.command.join(" "),
-            env: action.env.clone(),
+            env: HashMap::new(),
             hash: action.input_root_digest.hash.clone(),
             dirty: true,
             deps: Vec::new(),
             source_path: None,
             cache_hit: false,
-            metadata: NodeMetadata::default(),
+            metadata: NodeMetadata {
+                build_env: action.env.clone().into_iter().collect(),
+                ..Default::default()
+            },
         };
 
         // 2. Prepare Sandbox
@@ -134,11 +137,13 @@ impl RemoteExecutor for WorkerNode {
             exit_code: exec_result.exit_code,
             stdout_raw: exec_result.stdout,
             stderr_raw: exec_result.stderr,
+            injected_env: exec_result.injected_env,
             execution_metadata: ExecutionMetadata {
                 worker_id: self.id.clone(),
                 queued_timestamp: None,
                 worker_start_timestamp: Some(start_time.elapsed().as_millis() as i64),
                 worker_completed_timestamp: Some(end_time.elapsed().as_millis() as i64),
+                resource_usage: exec_result.usage,
             },
         })
     }
@@ -1,3 +1,4 @@
+use crate::remote_exec::queue::WorkerCapabilities;
 use crate::remote_exec::{worker::WorkerNode, ActionRequest, ActionResult};
 use anyhow::Result;
 use axum::{http::StatusCode, routing::post, Extension, Json, Router};
@@ -9,6 +10,8 @@ use std::sync::Arc;
 struct WorkerRegistration {
     worker_id: String,
     endpoint: String,
+    #[serde(flatten)]
+    capabilities: WorkerCapabilities,
 }
 
 pub struct WorkerServer {
@@ -50,6 +53,7 @@ impl WorkerServer {
         let registration = WorkerRegistration {
             worker_id: self.worker.id.clone(),
             endpoint: format!("http://localhost:{}", port),
+            capabilities: WorkerCapabilities::from_env()?,
         };
 
         let url = format!("{}/workers/register", scheduler_url.trim_end_matches('/'));
@@ -0,0 +1,434 @@
+use serde::{Deserialize, Serialize};
+
+/// Where a node's artifact came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheSource {
+    /// Found in the local cache
+    Local,
+    /// Downloaded from the remote cache
+    Remote,
+    /// Not cached; the node ran (or would have)
+    None,
+}
+
+/// What happened to a node during a build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeOutcome {
+    /// Artifact was restored from a cache
+    Cached,
+    /// The node ran and its artifact was stored
+    Executed,
+    /// The node ran and failed
+    Failed,
+    /// The node did not run: dry-run, or an earlier node failed
+    Skipped,
+}
+
+impl std::fmt::Display for NodeOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeOutcome::Cached => write!(f, "cached"),
+            NodeOutcome::Executed => write!(f, "executed"),
+            NodeOutcome::Failed => write!(f, "failed"),
+            NodeOutcome::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeReport {
+    pub id: usize,
+    pub name: String,
+    /// Cache key of the node
+    pub hash: String,
+    pub outcome: NodeOutcome,
+    pub cache_source: CacheSource,
+    pub duration_ms: u64,
+    /// BLAKE3 digest of the artifact, for cached and executed nodes
+    pub artifact_digest: Option<String>,
+    /// Size of the artifact in bytes, for cached and executed nodes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_bytes: Option<u64>,
+    pub error: Option<String>,
+    /// Why a node that was not cached had to run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dirty_reason: Option<crate::graph::DirtyReason>,
+    /// Stage or group the node belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// CPU, memory and IO the node's command used, when it ran and the
+    /// backend could measure it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<crate::sandbox::usage::ResourceUsage>,
+}
+
+impl NodeReport {
+    pub fn skipped(node: &crate::graph::Node) -> Self {
+        Self {
+            id: node.id,
+            name: node.name.clone(),
+            hash: node.hash.clone(),
+            outcome: NodeOutcome::Skipped,
+            cache_source: CacheSource::None,
+            duration_ms: 0,
+            artifact_digest: None,
+            artifact_bytes: None,
+            error: None,
+            dirty_reason: node.metadata.dirty_reason.clone(),
+            group: node.metadata.group.clone(),
+            usage: None,
+        }
+    }
+}
+
+/// Result of running the image's HEALTHCHECK against the built image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SmokeTestReport {
+    /// Image reference the container was started from
+    pub image: String,
+    /// Argv of the healthcheck
+    pub command: Vec<String>,
+    pub passed: bool,
+    /// Probes run, including the one that decided the outcome
+    pub attempts: u32,
+    pub duration_ms: u64,
+    /// Output of the last probe
+    pub output: String,
+    pub error: Option<String>,
+}
+
+/// Totals of the nodes of one stage or group.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupSummary {
+    pub group: String,
+    pub nodes: usize,
+    pub executed: usize,
+    pub cached: usize,
+    pub failed: usize,
+    /// Time the group's nodes ran for, summed
+    pub duration_ms: u64,
+    pub artifact_bytes: u64,
+}
+
+impl GroupSummary {
+    /// Share of the nodes that ran or were cached that came from the cache.
+    pub fn hit_rate(&self) -> f64 {
+        let considered = self.executed + self.cached + self.failed;
+        if considered == 0 {
+            return 0.0;
+        }
+        self.cached as f64 / considered as f64
+    }
+}
+
+/// Result of a build, node by node, ordered by node id.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BuildReport {
+    pub nodes: Vec<NodeReport>,
+    pub parallel_levels: usize,
+    pub total_duration_ms: u64,
+    /// Post-build HEALTHCHECK run, with `--smoke-test`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smoke_test: Option<SmokeTestReport>,
+}
+
+impl BuildReport {
+    /// True when no node failed.
+    pub fn succeeded(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &NodeReport> {
+        self.nodes
+            .iter()
+            .filter(|n| n.outcome == NodeOutcome::Failed)
+    }
+
+    pub fn count(&self, outcome: NodeOutcome) -> usize {
+        self.nodes.iter().filter(|n| n.outcome == outcome).count()
+    }
+
+    pub fn count_source(&self, source: CacheSource) -> usize {
+        self.nodes
+            .iter()
+            .filter(|n| n.outcome == NodeOutcome::Cached && n.cache_source == source)
+            .count()
+    }
+
+    /// Nodes that ran or failed, counted by why they were dirty. Nodes
+    /// without a recorded reason are left out.
+    pub fn dirty_reasons(&self) -> std::collections::BTreeMap<String, usize> {
+        let mut counts = std::collections::BTreeMap::new();
+        for node in &self.nodes {
+            if !matches!(node.outcome, NodeOutcome::Executed | NodeOutcome::Failed) {
+                continue;
+            }
+            if let Some(ref reason) = node.dirty_reason {
+                *counts.entry(reason.category().to_string()).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    /// Nodes whose artifact is larger than `threshold` bytes, largest first.
+    pub fn large_artifacts(&self, threshold: u64) -> Vec<&NodeReport> {
+        let mut large: Vec<&NodeReport> = self
+            .nodes
+            .iter()
+            .filter(|n| n.artifact_bytes.is_some_and(|bytes| bytes > threshold))
+            .collect();
+        large.sort_by(|a, b| {
+            b.artifact_bytes
+                .cmp(&a.artifact_bytes)
+                .then(a.id.cmp(&b.id))
+        });
+        large
+    }
+
+    /// Totals per group, in the order groups first appear. Nodes without
+    /// a group are left out.
+    pub fn groups(&self) -> Vec<GroupSummary> {
+        let mut groups: Vec<GroupSummary> = Vec::new();
+        for node in &self.nodes {
+            let Some(ref name) = node.group else {
+                continue;
+            };
+            let index = match groups.iter().position(|g| &g.group == name) {
+                Some(index) => index,
+                None => {
+                    groups.push(GroupSummary {
+                        group: name.clone(),
+                        ..Default::default()
+                    });
+                    groups.len() - 1
+                }
+            };
+            let group = &mut groups[index];
+            group.nodes += 1;
+            match node.outcome {
+                NodeOutcome::Executed => group.executed += 1,
+                NodeOutcome::Cached => group.cached += 1,
+                NodeOutcome::Failed => group.failed += 1,
+                NodeOutcome::Skipped => {}
+            }
+            group.duration_ms += node.duration_ms;
+            group.artifact_bytes += node.artifact_bytes.unwrap_or_default();
+        }
+        groups
+    }
+
+    pub fn node(&self, id: usize) -> Option<&NodeReport> {
+        self.nodes.iter().find(|n| n.id == id)
+    }
+
+    /// Record a node, replacing an earlier entry for the same id.
+    pub fn record(&mut self, node: NodeReport) {
+        self.nodes.retain(|n| n.id != node.id);
+        self.nodes.push(node);
+    }
+
+    /// Mark every node of `graph` without an entry as skipped and sort by id.
+    pub fn finish(&mut self, graph: &crate::graph::BuildGraph) {
+        for node in &graph.nodes {
+            if self.node(node.id).is_none() {
+                self.nodes.push(NodeReport::skipped(node));
+            }
+        }
+        self.nodes.sort_by_key(|n| n.id);
+    }
+}
+
+/// BLAKE3 digest of an artifact, as recorded in [`NodeReport::artifact_digest`].
+pub fn artifact_digest(data: &[u8]) -> String {
+    blake3::hash(data).to_hex().to_string()
+}
+
+/// Digest and size of an artifact a node produced or restored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactInfo {
+    pub digest: String,
+    pub bytes: u64,
+}
+
+impl ArtifactInfo {
+    pub fn of(data: &[u8]) -> Self {
+        Self {
+            digest: artifact_digest(data),
+            bytes: data.len() as u64,
+        }
+    }
+
+    /// Digest and size of everything `reader` yields, hashed as it is read
+    /// so the artifact is never held in memory.
+    pub fn from_reader(reader: &mut dyn std::io::Read) -> std::io::Result<Self> {
+        let mut hasher = blake3::Hasher::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut bytes = 0u64;
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            hasher.update(&buf[..n]);
+            bytes += n as u64;
+        }
+        Ok(Self {
+            digest: hasher.finalize().to_hex().to_string(),
+            bytes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{BuildGraph, Node, NodeKind, NodeMetadata};
+
+    fn node(id: usize) -> Node {
+        Node {
+            id,
+            name: format!("RUN step {}", id),
+            kind: NodeKind::Run,
+            content: format!("RUN step {}", id),
+            hash: format!("hash{}", id),
+            deps: vec![],
+            dirty: true,
+            source_path: None,
+            env: Default::default(),
+            cache_hit: false,
+            metadata: NodeMetadata::default(),
+        }
+    }
+
+    #[test]
+    fn test_finish_fills_skipped_nodes() {
+        let mut graph = BuildGraph::new();
+        graph.nodes = vec![node(0), node(1), node(2)];
+
+        let mut report = BuildReport::default();
+        report.record(NodeReport {
+            outcome: NodeOutcome::Failed,
+            error: Some("exit code 1".into()),
+            ..NodeReport::skipped(&graph.nodes[1])
+        });
+        report.record(NodeReport {
+            outcome: NodeOutcome::Cached,
+            cache_source: CacheSource::Remote,
+            artifact_digest: Some(artifact_digest(b"out")),
+            ..NodeReport::skipped(&graph.nodes[0])
+        });
+        report.finish(&graph);
+
+        let ids: Vec<usize> = report.nodes.iter().map(|n| n.id).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert!(!report.succeeded());
+        assert_eq!(report.failures().next().unwrap().id, 1);
+        assert_eq!(report.count(NodeOutcome::Skipped), 1);
+        assert_eq!(report.count_source(CacheSource::Remote), 1);
+        assert_eq!(report.count_source(CacheSource::Local), 0);
+    }
+
+    #[test]
+    fn test_large_artifacts() {
+        let mut graph = BuildGraph::new();
+        graph.nodes = vec![node(0), node(1), node(2)];
+
+        let mut report = BuildReport::default();
+        for (id, bytes) in [(0, 10), (1, 300), (2, 200)] {
+            report.record(NodeReport {
+                outcome: NodeOutcome::Executed,
+                artifact_bytes: Some(bytes),
+                ..NodeReport::skipped(&graph.nodes[id])
+            });
+        }
+        report.finish(&graph);
+
+        let ids: Vec<usize> = report.large_artifacts(100).iter().map(|n| n.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert!(report.large_artifacts(300).is_empty());
+    }
+
+    #[test]
+    fn test_groups_follow_stages() {
+        let graph = crate::docker::dag::build_graph_from_spanned(
+            crate::docker::parser::parse_dockerfile_spanned(
+                "FROM rust AS builder\nRUN cargo build\n\
+                 # memobuild:group=tests\nRUN cargo test\n\
+                 FROM debian\nCOPY --from=builder /app /app\n",
+                std::path::Path::new("Dockerfile"),
+            ),
+            std::path::PathBuf::from("."),
+        );
+        let groups: Vec<Option<&str>> = graph
+            .nodes
+            .iter()
+            .map(|n| n.metadata.group.as_deref())
+            .collect();
+        assert_eq!(
+            groups,
+            vec![
+                Some("builder"),
+                Some("builder"),
+                Some("tests"),
+                Some("stage 1"),
+                Some("stage 1")
+            ]
+        );
+
+        let mut report = BuildReport::default();
+        for (id, outcome, bytes) in [
+            (0, NodeOutcome::Cached, 10),
+            (1, NodeOutcome::Executed, 300),
+            (2, NodeOutcome::Failed, 0),
+            (4, NodeOutcome::Cached, 20),
+        ] {
+            report.record(NodeReport {
+                outcome,
+                duration_ms: 100,
+                artifact_bytes: Some(bytes),
+                ..NodeReport::skipped(&graph.nodes[id])
+            });
+        }
+        report.finish(&graph);
+
+        let summaries = report.groups();
+        let names: Vec<&str> = summaries.iter().map(|g| g.group.as_str()).collect();
+        assert_eq!(names, vec!["builder", "tests", "stage 1"]);
+        assert_eq!(summaries[0].nodes, 2);
+        assert_eq!(summaries[0].duration_ms, 200);
+        assert_eq!(summaries[0].artifact_bytes, 310);
+        assert_eq!(summaries[0].hit_rate(), 0.5);
+        assert_eq!(summaries[2].nodes, 2);
+        assert_eq!(summaries[2].hit_rate(), 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_artifact_info_of_cache_hit_is_streamed() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = crate::cache::HybridCache {
+            local: crate::cache::LocalCache::in_dir(dir.path().join("cache")).unwrap(),
+            remote: None,
+            remote_health: None,
+            mirrors: None,
+            integrity: Default::default(),
+            uploads: Default::default(),
+            dedup_uploads: true,
+            prefetch_hints: false,
+            hinted: Default::default(),
+            max_artifact_bytes: None,
+        };
+        // Larger than one read, so the digest spans several chunks
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        cache.put_artifact("key", &data).await.unwrap();
+
+        let (mut reader, source) = cache.lookup_reader("key").await.unwrap().unwrap();
+        assert_eq!(source, CacheSource::Local);
+        let info = ArtifactInfo::from_reader(&mut reader).unwrap();
+        assert_eq!(info, ArtifactInfo::of(&data));
+        assert!(cache.lookup_reader("missing").await.unwrap().is_none());
+    }
+}
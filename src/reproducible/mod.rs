@@ -1,3 +1,5 @@
 pub mod normalize;
+pub mod provenance;
+pub mod snapshot;
 
 pub use normalize::normalize_artifact;
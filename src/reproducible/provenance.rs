@@ -0,0 +1,348 @@
+//! SLSA provenance for exported images, as an in-toto Statement that a signing
+//! key wraps in a DSSE envelope.
+
+use crate::graph::{BuildGraph, NodeKind};
+use crate::report::BuildReport;
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer as _, SigningKey, Verifier as _, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+pub const STATEMENT_TYPE: &str = "https://in-toto.io/Statement/v1";
+pub const PREDICATE_TYPE: &str = "https://slsa.dev/provenance/v1";
+pub const BUILD_TYPE: &str = "https://github.com/nrelab/MemoBuild/build/v1";
+pub const PAYLOAD_TYPE: &str = "application/vnd.in-toto+json";
+/// File the attestation is written to, next to the image layout
+pub const FILE_NAME: &str = "provenance.intoto.json";
+
+/// What the build was asked to do and where it ran.
+pub struct BuildInvocation<'a> {
+    pub dockerfile: &'a str,
+    pub env_fingerprint: String,
+    pub reproducible: bool,
+    /// Wall-clock bounds of the build; left out of reproducible attestations
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+}
+
+/// The in-toto statement for an image built from `graph`. `image_digest` is
+/// the `sha256:` digest of the image manifest.
+pub fn statement(
+    graph: &BuildGraph,
+    report: &BuildReport,
+    image_name: &str,
+    image_digest: &str,
+    invocation: &BuildInvocation,
+) -> Value {
+    let mut dependencies = Vec::new();
+    for node in &graph.nodes {
+        match &node.kind {
+            NodeKind::From => {
+                let image = node.content.trim_start_matches("FROM ").trim();
+                let mut dep = json!({ "uri": format!("docker://{}", image) });
+                if let Some(hex) = node
+                    .metadata
+                    .base_image_digest
+                    .as_deref()
+                    .and_then(|d| d.strip_prefix("sha256:"))
+                {
+                    dep["digest"] = json!({ "sha256": hex });
+                }
+                dependencies.push(dep);
+            }
+            NodeKind::Add { src, .. } if crate::docker::add::is_url(src) => {
+                let mut dep = json!({ "uri": src });
+                if let Some(hex) = node
+                    .metadata
+                    .source_content_hash
+                    .as_deref()
+                    .and_then(|d| d.strip_prefix("sha256:"))
+                {
+                    dep["digest"] = json!({ "sha256": hex });
+                }
+                dependencies.push(dep);
+            }
+            NodeKind::CopyFrom { image, src, .. } => {
+                let mut dep = json!({ "uri": format!("docker://{}#{}", image, src.display()) });
+                if let Some(ref digest) = node.metadata.source_content_hash {
+                    dep["digest"] = json!({ "blake3": digest });
+                }
+                dependencies.push(dep);
+            }
+            NodeKind::Copy { .. } | NodeKind::CopyExtend { .. } | NodeKind::Add { .. } => {
+                if let Some(ref digest) = node.metadata.source_content_hash {
+                    let source = node
+                        .source_path
+                        .as_ref()
+                        .map(|p| p.display().to_string())
+                        .unwrap_or_else(|| node.name.clone());
+                    dependencies.push(json!({
+                        "uri": format!("file:{}", source),
+                        "digest": { "blake3": digest },
+                    }));
+                }
+            }
+            NodeKind::Git { url, .. } => {
+                dependencies.push(json!({ "uri": format!("git+{}", url) }));
+            }
+            _ => {}
+        }
+    }
+
+    let steps: Vec<Value> = report
+        .nodes
+        .iter()
+        .map(|n| {
+            let mut step = json!({
+                "name": n.name,
+                "annotations": {
+                    "cacheKey": n.hash,
+                    "outcome": n.outcome,
+                    "cacheSource": n.cache_source,
+                },
+            });
+            if let Some(ref digest) = n.artifact_digest {
+                step["digest"] = json!({ "blake3": digest });
+            }
+            step
+        })
+        .collect();
+
+    let metadata = if invocation.reproducible {
+        json!({})
+    } else {
+        json!({
+            "invocationId": uuid::Uuid::new_v4().to_string(),
+            "startedOn": invocation.started_at.to_rfc3339(),
+            "finishedOn": invocation.finished_at.to_rfc3339(),
+        })
+    };
+
+    json!({
+        "_type": STATEMENT_TYPE,
+        "subject": [{
+            "name": image_name,
+            "digest": { "sha256": image_digest.trim_start_matches("sha256:") },
+        }],
+        "predicateType": PREDICATE_TYPE,
+        "predicate": {
+            "buildDefinition": {
+                "buildType": BUILD_TYPE,
+                "externalParameters": {
+                    "dockerfile": invocation.dockerfile,
+                    "reproducible": invocation.reproducible,
+                },
+                "internalParameters": {
+                    "envFingerprint": invocation.env_fingerprint,
+                    "cacheKeyVersion": crate::constants::CACHE_KEY_VERSION,
+                },
+                "resolvedDependencies": dependencies,
+            },
+            "runDetails": {
+                "builder": {
+                    "id": "https://github.com/nrelab/MemoBuild",
+                    "version": { "memobuild": env!("CARGO_PKG_VERSION") },
+                },
+                "metadata": metadata,
+                "byproducts": steps,
+            },
+        },
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    #[serde(rename = "payloadType")]
+    pub payload_type: String,
+    /// Base64 of the serialized statement
+    pub payload: String,
+    pub signatures: Vec<EnvelopeSignature>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnvelopeSignature {
+    pub keyid: String,
+    /// Base64 Ed25519 signature over the DSSE pre-authentication encoding
+    pub sig: String,
+}
+
+/// DSSE pre-authentication encoding of `payload`.
+fn pae(payload_type: &str, payload: &[u8]) -> Vec<u8> {
+    let mut out = format!(
+        "DSSEv1 {} {} {} ",
+        payload_type.len(),
+        payload_type,
+        payload.len()
+    )
+    .into_bytes();
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Identifier of a public key: the first 16 hex characters of its SHA-256.
+pub fn key_id(key: &VerifyingKey) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(Sha256::digest(key.as_bytes()))[..16].to_string()
+}
+
+pub struct ProvenanceSigner {
+    key: SigningKey,
+}
+
+impl ProvenanceSigner {
+    pub fn new(seed: [u8; 32]) -> Self {
+        Self {
+            key: SigningKey::from_bytes(&seed),
+        }
+    }
+
+    /// Read the 32 byte Ed25519 seed from `MEMOBUILD_PROVENANCE_KEY` (hex or
+    /// base64) or from the file named by `MEMOBUILD_PROVENANCE_KEY_FILE`.
+    pub fn from_env() -> Result<Option<Self>> {
+        let encoded = if let Ok(key) = std::env::var("MEMOBUILD_PROVENANCE_KEY") {
+            key
+        } else if let Ok(path) = std::env::var("MEMOBUILD_PROVENANCE_KEY_FILE") {
+            std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read provenance key file {}", path))?
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(Self::new(parse_key(encoded.trim())?)))
+    }
+
+    pub fn public_key(&self) -> VerifyingKey {
+        self.key.verifying_key()
+    }
+
+    pub fn sign(&self, statement: &Value) -> Result<Envelope> {
+        let payload = serde_json::to_vec(statement)?;
+        let signature = self.key.sign(&pae(PAYLOAD_TYPE, &payload));
+        Ok(Envelope {
+            payload_type: PAYLOAD_TYPE.to_string(),
+            payload: STANDARD.encode(&payload),
+            signatures: vec![EnvelopeSignature {
+                keyid: key_id(&self.public_key()),
+                sig: STANDARD.encode(signature.to_bytes()),
+            }],
+        })
+    }
+}
+
+/// Parse a hex or base64 encoded 32 byte key.
+pub fn parse_key(encoded: &str) -> Result<[u8; 32]> {
+    let bytes = hex::decode(encoded)
+        .or_else(|_| STANDARD.decode(encoded))
+        .context("Provenance key must be hex or base64 encoded")?;
+    bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| anyhow::anyhow!("Provenance key must be 32 bytes, got {}", b.len()))
+}
+
+/// Check an envelope against `public_key` and return its statement.
+pub fn verify(envelope: &Envelope, public_key: &VerifyingKey) -> Result<Value> {
+    if envelope.payload_type != PAYLOAD_TYPE {
+        anyhow::bail!("Unexpected payload type {}", envelope.payload_type);
+    }
+    let payload = STANDARD
+        .decode(&envelope.payload)
+        .context("Envelope payload is not base64")?;
+    let message = pae(&envelope.payload_type, &payload);
+    let id = key_id(public_key);
+
+    let verified = envelope.signatures.iter().any(|s| {
+        s.keyid == id
+            && STANDARD
+                .decode(&s.sig)
+                .ok()
+                .and_then(|sig| Signature::from_slice(&sig).ok())
+                .is_some_and(|sig| public_key.verify(&message, &sig).is_ok())
+    });
+    if !verified {
+        anyhow::bail!("No valid signature from key {}", id);
+    }
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+/// Write the attestation into the image layout at `output_dir`: a signed
+/// envelope when a signer is given, the bare statement otherwise.
+pub fn write(
+    output_dir: &Path,
+    statement: &Value,
+    signer: Option<&ProvenanceSigner>,
+) -> Result<PathBuf> {
+    let path = output_dir.join(FILE_NAME);
+    let content = match signer {
+        Some(signer) => serde_json::to_string_pretty(&signer.sign(statement)?)?,
+        None => serde_json::to_string_pretty(statement)?,
+    };
+    std::fs::write(&path, content)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::dag::build_graph_from_instructions;
+    use crate::docker::parser::parse_dockerfile;
+    use crate::report::{CacheSource, NodeOutcome, NodeReport};
+
+    #[test]
+    fn test_signed_statement_round_trip() {
+        let mut graph = build_graph_from_instructions(
+            parse_dockerfile("FROM alpine:3.19\nRUN echo hi\n"),
+            std::path::PathBuf::from("."),
+        );
+        graph.nodes[0].metadata.base_image_digest = Some("sha256:abc".into());
+        let mut report = BuildReport::default();
+        report.record(NodeReport {
+            outcome: NodeOutcome::Cached,
+            cache_source: CacheSource::Remote,
+            artifact_digest: Some("d1".into()),
+            ..NodeReport::skipped(&graph.nodes[1])
+        });
+        report.finish(&graph);
+
+        let now = Utc::now();
+        let invocation = BuildInvocation {
+            dockerfile: "Dockerfile",
+            env_fingerprint: "fp".into(),
+            reproducible: true,
+            started_at: now,
+            finished_at: now,
+        };
+        let stmt = statement(&graph, &report, "app:latest", "sha256:ff", &invocation);
+        assert_eq!(stmt["subject"][0]["digest"]["sha256"], "ff");
+        let predicate = &stmt["predicate"];
+        assert_eq!(
+            predicate["buildDefinition"]["resolvedDependencies"][0]["digest"]["sha256"],
+            "abc"
+        );
+        assert_eq!(
+            predicate["runDetails"]["byproducts"][1]["digest"]["blake3"],
+            "d1"
+        );
+        assert_eq!(
+            predicate["runDetails"]["byproducts"][1]["annotations"]["cacheSource"],
+            "remote"
+        );
+        // Reproducible attestations are identical across runs
+        assert_eq!(
+            stmt,
+            statement(&graph, &report, "app:latest", "sha256:ff", &invocation)
+        );
+
+        let signer = ProvenanceSigner::new([7; 32]);
+        let envelope = signer.sign(&stmt).unwrap();
+        assert_eq!(verify(&envelope, &signer.public_key()).unwrap(), stmt);
+
+        let other = ProvenanceSigner::new([8; 32]);
+        assert!(verify(&envelope, &other.public_key()).is_err());
+
+        let mut tampered = envelope.clone();
+        tampered.payload = STANDARD.encode(b"{}");
+        assert!(verify(&tampered, &signer.public_key()).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+//! Snapshots of a build's context, Dockerfile and environment fingerprint,
+//! one gzip-compressed tar per build id in the cache directory. `ADD` URLs
+//! are not stored and are fetched again on a rerun.
+
+use crate::env::drift::EnvChange;
+use crate::env::EnvFingerprint;
+use crate::hasher::IgnoreRules;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Directory of the snapshots in the local cache directory
+pub const SNAPSHOT_DIR: &str = "snapshots";
+
+const INFO_FILE: &str = "snapshot.json";
+
+/// What the build was run with, besides its files.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    pub build_id: String,
+    pub created_at: String,
+    /// File name of the Dockerfile or task manifest
+    pub dockerfile: String,
+    #[serde(default)]
+    pub platforms: Vec<String>,
+    #[serde(default)]
+    pub reproducible: bool,
+    pub env: EnvFingerprint,
+}
+
+/// A snapshot unpacked into a directory.
+#[derive(Debug)]
+pub struct Restored {
+    pub info: SnapshotInfo,
+    pub context_dir: PathBuf,
+    pub dockerfile: PathBuf,
+}
+
+impl Restored {
+    /// Differences between the environment the snapshot was taken in and
+    /// `current`. Toolchains the context pins are installed from the same
+    /// download everywhere and are left out.
+    pub fn env_drift(&self, current: &EnvFingerprint) -> Result<Vec<EnvChange>> {
+        let (mut before, mut after) = (self.info.env.clone(), current.clone());
+        if let Some(manifest) = crate::toolchains::ToolchainManifest::load(&self.context_dir)? {
+            for name in manifest.tools.keys() {
+                before.toolchain.remove(name);
+                after.toolchain.remove(name);
+            }
+        }
+        Ok(crate::env::drift::diff(&before, &after))
+    }
+}
+
+/// Id of the running build: `MEMOBUILD_BUILD_ID` when set, a new one
+/// otherwise.
+pub fn build_id() -> String {
+    crate::cache::pin::build_id_from_env()
+        .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string())
+}
+
+/// File of the snapshot of build `build_id`.
+pub fn path_in(cache_dir: &Path, build_id: &str) -> Result<PathBuf> {
+    let valid = !build_id.is_empty()
+        && !build_id.starts_with('.')
+        && build_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        anyhow::bail!("'{}' cannot name a build snapshot", build_id);
+    }
+    Ok(cache_dir
+        .join(SNAPSHOT_DIR)
+        .join(format!("{}.tar.gz", build_id)))
+}
+
+/// Write the snapshot of `context_dir` and `dockerfile` to `dest`; returns
+/// its size.
+pub fn capture(
+    dest: &Path,
+    info: &SnapshotInfo,
+    context_dir: &Path,
+    dockerfile: &Path,
+) -> Result<u64> {
+    let parent = dest.parent().context("Snapshot path has no directory")?;
+    std::fs::create_dir_all(parent)?;
+    let tmp = parent.join(format!(".tmp-{}", uuid::Uuid::new_v4().simple()));
+    let result = write(&tmp, info, context_dir, dockerfile);
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e);
+    }
+    std::fs::rename(&tmp, dest)?;
+    Ok(std::fs::metadata(dest)?.len())
+}
+
+fn write(tmp: &Path, info: &SnapshotInfo, context_dir: &Path, dockerfile: &Path) -> Result<()> {
+    let gzip = flate2::write::GzEncoder::new(File::create(tmp)?, flate2::Compression::default());
+    let mut builder = tar::Builder::new(gzip);
+
+    let json = serde_json::to_vec_pretty(info)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(json.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, INFO_FILE, json.as_slice())?;
+
+    builder
+        .append_path_with_name(dockerfile, Path::new("dockerfile").join(&info.dockerfile))
+        .with_context(|| format!("Failed to snapshot {}", dockerfile.display()))?;
+    let ignore = IgnoreRules::from_file(&context_dir.join(".dockerignore"));
+    for path in crate::hasher::walker::walk_dir(context_dir, &ignore) {
+        let rel = path.strip_prefix(context_dir).unwrap_or(&path);
+        builder
+            .append_path_with_name(&path, Path::new("context").join(rel))
+            .with_context(|| format!("Failed to snapshot {}", path.display()))?;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// Unpack the snapshot at `path` into `dir`.
+pub fn restore(path: &Path, dir: &Path) -> Result<Restored> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    std::fs::create_dir_all(dir.join("context"))?;
+    tar::Archive::new(flate2::read::GzDecoder::new(file))
+        .unpack(dir)
+        .with_context(|| format!("Failed to unpack {}", path.display()))?;
+    let info: SnapshotInfo = serde_json::from_slice(&std::fs::read(dir.join(INFO_FILE))?)
+        .with_context(|| format!("Malformed {} in {}", INFO_FILE, path.display()))?;
+    if Path::new(&info.dockerfile).components().count() != 1 {
+        anyhow::bail!("Malformed {} in {}", INFO_FILE, path.display());
+    }
+    Ok(Restored {
+        context_dir: dir.join("context"),
+        dockerfile: dir.join("dockerfile").join(&info.dockerfile),
+        info,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let context = dir.path().join("app");
+        std::fs::create_dir_all(context.join("src")).unwrap();
+        std::fs::write(context.join("src/main.c"), "int main() {}").unwrap();
+        std::fs::write(context.join("build.log"), "noise").unwrap();
+        std::fs::write(context.join(".dockerignore"), "*.log").unwrap();
+        // Kept outside the context, as with `-f ../Dockerfile.release`
+        let dockerfile = dir.path().join("Dockerfile.release");
+        std::fs::write(&dockerfile, "FROM scratch\nCOPY src /src\n").unwrap();
+
+        let info = SnapshotInfo {
+            build_id: "ci-1842".into(),
+            created_at: "2026-01-01T00:00:00Z".into(),
+            dockerfile: "Dockerfile.release".into(),
+            platforms: Vec::new(),
+            reproducible: true,
+            env: EnvFingerprint::default(),
+        };
+        let path = path_in(&dir.path().join("cache"), &info.build_id).unwrap();
+        capture(&path, &info, &context, &dockerfile).unwrap();
+
+        let restored = restore(&path, &dir.path().join("restored")).unwrap();
+        assert_eq!(restored.info.build_id, "ci-1842");
+        assert_eq!(
+            std::fs::read_to_string(restored.context_dir.join("src/main.c")).unwrap(),
+            "int main() {}"
+        );
+        assert!(!restored.context_dir.join("build.log").exists());
+        assert_eq!(
+            std::fs::read_to_string(&restored.dockerfile).unwrap(),
+            "FROM scratch\nCOPY src /src\n"
+        );
+        assert!(restored
+            .env_drift(&EnvFingerprint::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_build_id_cannot_leave_snapshot_dir() {
+        let cache = Path::new("/cache");
+        assert!(path_in(cache, "../history").is_err());
+        assert!(path_in(cache, "a/b").is_err());
+        assert_eq!(
+            path_in(cache, "ci-7.1").unwrap(),
+            Path::new("/cache/snapshots/ci-7.1.tar.gz")
+        );
+    }
+}
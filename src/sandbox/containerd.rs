@@ -46,18 +46,25 @@ impl Sandbox for ContainerdSandbox {
 
         Ok(SandboxEnv {
             workspace_dir: temp_dir,
-            env_vars: node.env.clone(),
+            // Host variables mean nothing inside the container image
+            env_vars: crate::sandbox::scoped_env(node, &[]),
+            overlay: None,
+            materialized: None,
+            processes: None,
         })
     }
 
     async fn execute(&self, env: &SandboxEnv, node: &Node) -> Result<ExecResult> {
         let cmd = match &node.kind {
-            crate::graph::NodeKind::Run => &node.content,
+            crate::graph::NodeKind::Run | crate::graph::NodeKind::Task { .. } => &node.content,
             _ => {
                 return Ok(ExecResult {
                     exit_code: 0,
                     stdout: format!("Mock artifact for {}", node.name).into_bytes(),
                     stderr: vec![],
+                    output_diff: None,
+                    injected_env: Vec::new(),
+                    usage: None,
                 })
             }
         };
@@ -76,7 +83,16 @@ impl Sandbox for ContainerdSandbox {
         let container_id = format!("memobuild-{}", &node.hash[..12]);
 
         // 3. Build OCI Spec
-        let spec = crate::sandbox::spec::build_spec(cmd, &env.env_vars, &env.workspace_dir);
+        let isolate_network = matches!(
+            node.metadata.network,
+            Some(crate::sandbox::network::NetworkPolicy::None)
+        );
+        let spec = crate::sandbox::spec::build_spec(
+            crate::sandbox::shell::argv(node, cmd, crate::sandbox::shell::container_default()),
+            &env.env_vars,
+            &env.workspace_dir,
+            isolate_network,
+        );
         let spec_json = serde_json::to_vec(&spec)?;
 
         // 4. Create Container
@@ -109,6 +125,9 @@ impl Sandbox for ContainerdSandbox {
             exit_code: 0,
             stdout: "Container execution simulated (Requires Linux/Containerd runtime)".into(),
             stderr: vec![],
+            output_diff: None,
+            injected_env: crate::sandbox::env_names(&env.env_vars),
+            usage: None,
         })
     }
 
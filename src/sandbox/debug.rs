@@ -0,0 +1,113 @@
+//! Shells in the sandbox of a failed step, for `build --on-failure=shell`.
+
+use crate::graph::Node;
+use crate::sandbox::{shell, SandboxEnv};
+use anyhow::{Context, Result};
+use std::io::IsTerminal;
+use std::process::Command;
+use std::sync::Mutex;
+
+/// What to do when a step's command fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnFailure {
+    /// Fail the build right away
+    #[default]
+    Fail,
+    /// Open a shell in the step's sandbox first
+    Shell,
+}
+
+impl std::str::FromStr for OnFailure {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "fail" => Ok(OnFailure::Fail),
+            "shell" => Ok(OnFailure::Shell),
+            other => anyhow::bail!(
+                "Unknown failure action '{}' (expected fail or shell)",
+                other
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for OnFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OnFailure::Fail => write!(f, "fail"),
+            OnFailure::Shell => write!(f, "shell"),
+        }
+    }
+}
+
+/// Held while a shell is open
+static SHELL: Mutex<()> = Mutex::new(());
+
+/// The shell `node`'s commands run with, without its command flag.
+fn program(node: &Node) -> String {
+    shell::argv(node, "", shell::host_default()).remove(0)
+}
+
+/// Open an interactive shell in `env`, where `node` failed with
+/// `exit_code`, and wait for it to exit. Without a terminal there is no one
+/// to use it; says so and returns.
+pub fn open_shell(env: &SandboxEnv, node: &Node, exit_code: i32) -> Result<()> {
+    if !std::io::stdin().is_terminal() {
+        eprintln!(
+            "⚠️  Not opening a shell for {}: stdin is not a terminal",
+            node.name
+        );
+        return Ok(());
+    }
+    let _turn = SHELL.lock().unwrap_or_else(|e| e.into_inner());
+    let program = program(node);
+    println!(
+        "\n🐚 {}{} failed with exit code {}: {}",
+        node.location_prefix(),
+        node.name,
+        exit_code,
+        node.content
+    );
+    println!(
+        "   Opening {} in {}; exit it to continue",
+        program,
+        env.workspace_dir.display()
+    );
+    let status = Command::new(&program)
+        .env_clear()
+        .envs(&env.env_vars)
+        .current_dir(&env.workspace_dir)
+        .status()
+        .with_context(|| format!("Failed to start {}", program))?;
+    println!("   Shell exited with {}", status);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::{dag, parser};
+
+    #[test]
+    fn test_shell_follows_the_step() {
+        assert_eq!("SHELL".parse::<OnFailure>().unwrap(), OnFailure::Shell);
+        assert_eq!("fail".parse::<OnFailure>().unwrap(), OnFailure::default());
+        assert!("retry".parse::<OnFailure>().is_err());
+
+        let graph = dag::build_graph_from_instructions(
+            parser::parse_dockerfile("FROM alpine\nRUN a\nSHELL [\"bash\", \"-c\"]\nRUN b\n"),
+            std::path::PathBuf::from("."),
+        );
+        let runs: Vec<String> = graph
+            .nodes
+            .iter()
+            .filter(|n| n.kind == crate::graph::NodeKind::Run)
+            .map(program)
+            .collect();
+        assert_eq!(
+            runs,
+            vec![shell::host_default().remove(0), "bash".to_string()]
+        );
+    }
+}
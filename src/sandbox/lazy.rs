@@ -0,0 +1,403 @@
+//! Lazily materialized workspaces: a content store tree mounted read-only with
+//! FUSE, with files fetched when first read.
+
+use crate::cache::cas::{ContentStore, Tree, TreeEntry};
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Fetches an object the local store lacks, e.g. from the remote cache
+pub type Fetch = Arc<dyn Fn(&str) -> Result<Option<Vec<u8>>> + Send + Sync>;
+
+/// Inode number of the root directory
+pub const ROOT_INO: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InodeKind {
+    Dir {
+        digest: String,
+        /// Names and inodes of the entries, once listed
+        children: Option<Vec<(String, u64)>>,
+    },
+    File {
+        digest: String,
+        size: u64,
+        executable: bool,
+    },
+    Symlink {
+        target: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Inode {
+    pub parent: u64,
+    pub kind: InodeKind,
+}
+
+/// Objects fetched because a step read them.
+#[derive(Debug, Default)]
+pub struct LazyStats {
+    pub objects: AtomicU64,
+    pub bytes: AtomicU64,
+}
+
+/// A content store tree whose directories and files are loaded on use.
+pub struct LazyTree {
+    store: ContentStore,
+    fetch: Option<Fetch>,
+    /// Inode `n` is at index `n - 1`
+    inodes: Mutex<Vec<Inode>>,
+    pub stats: LazyStats,
+}
+
+impl LazyTree {
+    pub fn new(store: ContentStore, root: &str, fetch: Option<Fetch>) -> Self {
+        Self {
+            store,
+            fetch,
+            inodes: Mutex::new(vec![Inode {
+                parent: ROOT_INO,
+                kind: InodeKind::Dir {
+                    digest: root.to_string(),
+                    children: None,
+                },
+            }]),
+            stats: LazyStats::default(),
+        }
+    }
+
+    pub fn inode(&self, ino: u64) -> Option<Inode> {
+        let inodes = self.inodes.lock().unwrap();
+        inodes.get(ino.checked_sub(1)? as usize).cloned()
+    }
+
+    /// Make sure the object `digest` is in the local store, fetching it
+    /// when it is not.
+    fn ensure(&self, digest: &str) -> Result<()> {
+        if self.store.contains(digest) {
+            return Ok(());
+        }
+        let fetch = self
+            .fetch
+            .as_ref()
+            .with_context(|| format!("Object {} is not in the store", digest))?;
+        let data = fetch(digest)?
+            .with_context(|| format!("Cache integrity failure: object {} missing", digest))?;
+        if blake3::hash(&data).to_hex().as_str() != digest {
+            anyhow::bail!("Cache integrity failure: object {} is corrupt", digest);
+        }
+        self.stats.objects.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.store.put(&data)?;
+        Ok(())
+    }
+
+    /// Entries of the directory `ino`, listing it on first use.
+    pub fn children(&self, ino: u64) -> Result<Vec<(String, u64)>> {
+        let digest = match self.inode(ino).map(|inode| inode.kind) {
+            Some(InodeKind::Dir {
+                children: Some(children),
+                ..
+            }) => return Ok(children),
+            Some(InodeKind::Dir { digest, .. }) => digest,
+            _ => anyhow::bail!("Inode {} is not a directory", ino),
+        };
+        self.ensure(&digest)?;
+        let tree: Tree = self.store.get_tree(&digest)?;
+
+        let mut inodes = self.inodes.lock().unwrap();
+        // Listed by another reader meanwhile
+        if let InodeKind::Dir {
+            children: Some(ref children),
+            ..
+        } = inodes[ino as usize - 1].kind
+        {
+            return Ok(children.clone());
+        }
+        let mut children = Vec::with_capacity(tree.entries.len());
+        for entry in tree.entries {
+            let name = entry.name().to_string();
+            let kind = match entry {
+                TreeEntry::File {
+                    digest,
+                    size,
+                    executable,
+                    ..
+                } => InodeKind::File {
+                    digest,
+                    size,
+                    executable,
+                },
+                TreeEntry::Dir { digest, .. } => InodeKind::Dir {
+                    digest,
+                    children: None,
+                },
+                TreeEntry::Symlink { target, .. } => InodeKind::Symlink { target },
+            };
+            inodes.push(Inode { parent: ino, kind });
+            children.push((name, inodes.len() as u64));
+        }
+        if let InodeKind::Dir {
+            children: ref mut listed,
+            ..
+        } = inodes[ino as usize - 1].kind
+        {
+            *listed = Some(children.clone());
+        }
+        Ok(children)
+    }
+
+    pub fn lookup(&self, parent: u64, name: &str) -> Result<Option<u64>> {
+        Ok(self
+            .children(parent)?
+            .into_iter()
+            .find(|(child, _)| child == name)
+            .map(|(_, ino)| ino))
+    }
+
+    /// Inode of `path` relative to the root, if it exists.
+    pub fn resolve(&self, path: &Path) -> Result<Option<u64>> {
+        let mut ino = ROOT_INO;
+        for part in path.components() {
+            let name = part.as_os_str().to_string_lossy();
+            match self.lookup(ino, &name)? {
+                Some(child) => ino = child,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(ino))
+    }
+
+    /// Up to `len` bytes of the file `ino` from `offset`. The whole file is
+    /// fetched on its first read.
+    pub fn read(&self, ino: u64, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let Some(InodeKind::File { digest, .. }) = self.inode(ino).map(|inode| inode.kind) else {
+            anyhow::bail!("Inode {} is not a file", ino);
+        };
+        self.ensure(&digest)?;
+        self.store
+            .read_at(&digest, offset, len)?
+            .with_context(|| format!("Object {} vanished from the store", digest))
+    }
+}
+
+/// A [`LazyTree`] mounted read-only, unmounted when dropped.
+pub struct LazyMount {
+    pub tree: Arc<LazyTree>,
+    #[cfg(all(feature = "fuse", target_os = "linux"))]
+    _session: fuser::BackgroundSession,
+}
+
+impl std::fmt::Debug for LazyMount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LazyMount").finish_non_exhaustive()
+    }
+}
+
+/// Whether this build can mount lazy trees at all.
+pub fn available() -> bool {
+    cfg!(all(feature = "fuse", target_os = "linux"))
+}
+
+impl LazyMount {
+    #[cfg(all(feature = "fuse", target_os = "linux"))]
+    pub fn mount(tree: Arc<LazyTree>, mountpoint: &Path) -> Result<Self> {
+        use fuser::MountOption;
+        let options = [
+            MountOption::RO,
+            MountOption::FSName("memobuild".to_string()),
+            MountOption::DefaultPermissions,
+        ];
+        let session = fuser::spawn_mount2(fs::LazyFs(tree.clone()), mountpoint, &options)
+            .with_context(|| format!("Failed to mount FUSE at {}", mountpoint.display()))?;
+        Ok(Self {
+            tree,
+            _session: session,
+        })
+    }
+
+    #[cfg(not(all(feature = "fuse", target_os = "linux")))]
+    pub fn mount(_tree: Arc<LazyTree>, _mountpoint: &Path) -> Result<Self> {
+        anyhow::bail!("Lazy workspaces need Linux and a memobuild built with the fuse feature")
+    }
+}
+
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+mod fs {
+    use super::{InodeKind, LazyTree};
+    use fuser::{FileAttr, FileType, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request};
+    use std::ffi::OsStr;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Content never changes under a digest, so the kernel may cache freely
+    const TTL: Duration = Duration::from_secs(3600);
+
+    pub struct LazyFs(pub Arc<LazyTree>);
+
+    impl LazyFs {
+        fn attr(&self, ino: u64) -> Option<FileAttr> {
+            let (kind, size, perm) = match self.0.inode(ino)?.kind {
+                InodeKind::Dir { .. } => (FileType::Directory, 0, 0o555),
+                InodeKind::File {
+                    size, executable, ..
+                } => (
+                    FileType::RegularFile,
+                    size,
+                    if executable { 0o555 } else { 0o444 },
+                ),
+                InodeKind::Symlink { target } => (FileType::Symlink, target.len() as u64, 0o777),
+            };
+            Some(FileAttr {
+                ino,
+                size,
+                blocks: size.div_ceil(512),
+                atime: UNIX_EPOCH,
+                mtime: UNIX_EPOCH,
+                ctime: UNIX_EPOCH,
+                crtime: UNIX_EPOCH,
+                kind,
+                perm,
+                nlink: 1,
+                uid: unsafe { libc::getuid() },
+                gid: unsafe { libc::getgid() },
+                rdev: 0,
+                blksize: 4096,
+                flags: 0,
+            })
+        }
+    }
+
+    impl fuser::Filesystem for LazyFs {
+        fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+            match self.0.lookup(parent, &name.to_string_lossy()) {
+                Ok(Some(ino)) => match self.attr(ino) {
+                    Some(attr) => reply.entry(&TTL, &attr, 0),
+                    None => reply.error(libc::ENOENT),
+                },
+                Ok(None) => reply.error(libc::ENOENT),
+                Err(e) => {
+                    eprintln!("⚠️  Lazy workspace: {:#}", e);
+                    reply.error(libc::EIO)
+                }
+            }
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            match self.attr(ino) {
+                Some(attr) => reply.attr(&TTL, &attr),
+                None => reply.error(libc::ENOENT),
+            }
+        }
+
+        fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+            match self.0.inode(ino).map(|inode| inode.kind) {
+                Some(InodeKind::Symlink { target }) => reply.data(target.as_bytes()),
+                _ => reply.error(libc::EINVAL),
+            }
+        }
+
+        fn read(
+            &mut self,
+            _req: &Request<'_>,
+            ino: u64,
+            _fh: u64,
+            offset: i64,
+            size: u32,
+            _flags: i32,
+            _lock_owner: Option<u64>,
+            reply: ReplyData,
+        ) {
+            match self.0.read(ino, offset.max(0) as u64, size as usize) {
+                Ok(data) => reply.data(&data),
+                Err(e) => {
+                    eprintln!("⚠️  Lazy workspace: {:#}", e);
+                    reply.error(libc::EIO)
+                }
+            }
+        }
+
+        fn readdir(
+            &mut self,
+            _req: &Request<'_>,
+            ino: u64,
+            _fh: u64,
+            offset: i64,
+            mut reply: ReplyDirectory,
+        ) {
+            let children = match self.0.children(ino) {
+                Ok(children) => children,
+                Err(e) => {
+                    eprintln!("⚠️  Lazy workspace: {:#}", e);
+                    return reply.error(libc::EIO);
+                }
+            };
+            let parent = self.0.inode(ino).map(|inode| inode.parent).unwrap_or(ino);
+            let mut entries = vec![
+                (ino, FileType::Directory, ".".to_string()),
+                (parent, FileType::Directory, "..".to_string()),
+            ];
+            for (name, child) in children {
+                let kind = match self.0.inode(child).map(|inode| inode.kind) {
+                    Some(InodeKind::Dir { .. }) => FileType::Directory,
+                    Some(InodeKind::Symlink { .. }) => FileType::Symlink,
+                    _ => FileType::RegularFile,
+                };
+                entries.push((child, kind, name));
+            }
+            for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+                // The offset passed back is that of the next entry
+                if reply.add(ino, (i + 1) as i64, kind, name) {
+                    break;
+                }
+            }
+            reply.ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hasher::IgnoreRules;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_lazy_tree_fetches_only_what_is_read() {
+        let ctx = TempDir::new().unwrap();
+        std::fs::create_dir_all(ctx.path().join("src")).unwrap();
+        std::fs::write(ctx.path().join("src/main.rs"), "fn main() {}").unwrap();
+        std::fs::write(ctx.path().join("big.bin"), vec![9u8; 64 * 1024]).unwrap();
+        let origin_dir = TempDir::new().unwrap();
+        let origin = ContentStore::new(origin_dir.path());
+        let root = origin
+            .store_dir(ctx.path(), &IgnoreRules::empty())
+            .unwrap()
+            .root;
+
+        // An empty local store, filled from the origin on demand
+        let local_dir = TempDir::new().unwrap();
+        let fetch: Fetch = Arc::new(move |digest: &str| origin.get(digest));
+        let tree = LazyTree::new(ContentStore::new(local_dir.path()), &root, Some(fetch));
+
+        let main = tree.resolve(Path::new("src/main.rs")).unwrap().unwrap();
+        assert_eq!(tree.read(main, 3, 4).unwrap(), b"main");
+        assert!(tree.resolve(Path::new("src/missing.rs")).unwrap().is_none());
+        // Two trees and one file; big.bin was never read
+        assert_eq!(tree.stats.objects.load(Ordering::Relaxed), 3);
+        let names: Vec<String> = tree
+            .children(ROOT_INO)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, vec!["big.bin", "src"]);
+        // Reading again needs no fetch
+        assert_eq!(tree.read(main, 0, 2).unwrap(), b"fn");
+        assert_eq!(tree.stats.objects.load(Ordering::Relaxed), 3);
+    }
+}
@@ -1,25 +1,233 @@
 use crate::graph::Node;
-use crate::sandbox::{ExecResult, Sandbox, SandboxEnv};
-use anyhow::Result;
+use crate::sandbox::debug;
+use crate::sandbox::materialize::Materializer;
+use crate::sandbox::network::{self, EgressProxy, NetworkPolicy};
+use crate::sandbox::overlay::OverlayMount;
+use crate::sandbox::process::ProcessTree;
+use crate::sandbox::shell;
+use crate::sandbox::trace::{self, TraceStore};
+use crate::sandbox::usage::ResourceUsage;
+use crate::sandbox::worker::{self, WorkerPool};
+use crate::sandbox::{env_names, env_passthrough, scoped_env, ExecResult, Sandbox, SandboxEnv};
+use anyhow::{Context, Result};
 use async_trait::async_trait;
-use std::process::Command;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
 
 pub struct LocalSandbox {
     pub workspace_dir: std::path::PathBuf,
+    /// Run commands on an overlay of the workspace and use the upper layer as
+    /// the node artifact
+    pub overlay: bool,
+    /// Host variables commands may see besides the Dockerfile's ENV values
+    pub env_passthrough: Vec<String>,
+    /// Pinned toolchain directories put in front of `PATH`
+    pub toolchain_path: Vec<std::path::PathBuf>,
+    /// Where to record the files each RUN reads, when commands are traced
+    pub trace: Option<Arc<std::sync::Mutex<TraceStore>>>,
+    /// Persistent workers started for `# memobuild:worker=` steps
+    pub workers: Arc<WorkerPool>,
+    /// Gives each RUN a private working directory, when set
+    pub materializer: Option<Arc<Materializer>>,
+    /// What earlier overlay commands wrote
+    layers: Layers,
+}
+
+/// Upper layers of the overlay commands that succeeded, mounted under the
+/// later commands of their stage so those see what they wrote, and removed
+/// with the sandbox.
+#[derive(Default)]
+struct Layers {
+    /// Kept layers by stage, oldest first
+    stages: Mutex<HashMap<Option<String>, Vec<PathBuf>>>,
+    /// Upper directories of commands that succeeded, with their stage, until
+    /// their overlay is unmounted
+    succeeded: Mutex<HashMap<PathBuf, Option<String>>>,
+}
+
+impl Layers {
+    fn of(&self, node: &Node) -> Vec<PathBuf> {
+        let stages = self.stages.lock().unwrap();
+        stages
+            .get(&node.metadata.group)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn succeeded(&self, env: &SandboxEnv, node: &Node) {
+        if let Some(ref overlay) = env.overlay {
+            self.succeeded
+                .lock()
+                .unwrap()
+                .insert(overlay.upper.clone(), node.metadata.group.clone());
+        }
+    }
+
+    /// Unmount `overlay`, keeping its upper layer if its command succeeded.
+    fn release(&self, overlay: &OverlayMount) -> Result<()> {
+        let Some(stage) = self.succeeded.lock().unwrap().remove(&overlay.upper) else {
+            return overlay.unmount();
+        };
+        let layer =
+            std::env::temp_dir().join(format!("memobuild-layer-{}", uuid::Uuid::new_v4().simple()));
+        overlay.unmount_keeping_upper(&layer)?;
+        self.stages
+            .lock()
+            .unwrap()
+            .entry(stage)
+            .or_default()
+            .push(layer);
+        Ok(())
+    }
+}
+
+impl Drop for Layers {
+    fn drop(&mut self) {
+        let stages = self.stages.get_mut().unwrap_or_else(|e| e.into_inner());
+        for layer in stages.values().flatten() {
+            let _ = std::fs::remove_dir_all(layer);
+        }
+    }
 }
 
 impl LocalSandbox {
     pub fn new(workspace_dir: std::path::PathBuf) -> Self {
-        Self { workspace_dir }
+        Self {
+            workers: Arc::new(WorkerPool::new(workspace_dir.clone())),
+            workspace_dir,
+            overlay: false,
+            env_passthrough: env_passthrough(),
+            toolchain_path: Vec::new(),
+            trace: None,
+            materializer: None,
+            layers: Layers::default(),
+        }
+    }
+
+    pub fn with_overlay(mut self, overlay: bool) -> Self {
+        self.overlay = overlay;
+        self
+    }
+
+    pub fn with_toolchain_path(mut self, dirs: Vec<std::path::PathBuf>) -> Self {
+        self.toolchain_path = dirs;
+        self
+    }
+
+    pub fn with_tracing(mut self, store: Option<Arc<std::sync::Mutex<TraceStore>>>) -> Self {
+        self.trace = store;
+        self
+    }
+
+    pub fn with_materializer(mut self, materializer: Option<Arc<Materializer>>) -> Self {
+        self.materializer = materializer;
+        self
+    }
+
+    fn execute_on_worker(
+        &self,
+        env: &SandboxEnv,
+        node: &Node,
+        worker_argv: &[String],
+        cmd: &str,
+    ) -> Result<ExecResult> {
+        // The worker outlives the step, so it cannot be put in a namespace or
+        // behind a proxy for it, nor traced
+        if matches!(
+            node.metadata.network,
+            Some(NetworkPolicy::None) | Some(NetworkPolicy::Allowlist(_))
+        ) {
+            anyhow::bail!(
+                "{}{} runs on a persistent worker, which cannot have its network restricted",
+                node.location_prefix(),
+                node.name
+            );
+        }
+        let response = tokio::task::block_in_place(|| {
+            self.workers.run(
+                worker_argv,
+                cmd,
+                &env.workspace_dir,
+                &env.env_vars,
+                node.metadata.timeout().unwrap_or(worker::REQUEST_TIMEOUT),
+            )
+        })
+        .with_context(|| format!("{}{}", node.location_prefix(), node.name))?;
+
+        let output_diff = match response.exit_code {
+            0 => capture_diff(env)?,
+            _ => None,
+        };
+        if response.exit_code == 0 {
+            self.layers.succeeded(env, node);
+        }
+        Ok(ExecResult {
+            exit_code: response.exit_code,
+            stdout: response.output.into_bytes(),
+            stderr: Vec::new(),
+            output_diff,
+            injected_env: env_names(&env.env_vars),
+            // The worker's process outlives the step
+            usage: None,
+        })
+    }
+
+    fn env_for(&self, node: &Node) -> Result<std::collections::HashMap<String, String>> {
+        let mut env = scoped_env(node, &self.env_passthrough);
+        if !self.toolchain_path.is_empty() {
+            let path = crate::toolchains::prepend_path(
+                &self.toolchain_path,
+                env.get("PATH").map(String::as_str),
+            )?;
+            env.insert("PATH".to_string(), path);
+        }
+        Ok(env)
     }
 }
 
 #[async_trait]
 impl Sandbox for LocalSandbox {
     async fn prepare(&self, node: &Node) -> Result<SandboxEnv> {
+        let runs_command = matches!(
+            node.kind,
+            crate::graph::NodeKind::Run
+                | crate::graph::NodeKind::RunExtend { .. }
+                | crate::graph::NodeKind::Task { .. }
+        );
+        // Steps of a nested build run in its context
+        let nested = |dir: &std::path::Path| match node.metadata.nested {
+            Some(ref rel) => dir.join(rel),
+            None => dir.to_path_buf(),
+        };
+        if self.overlay && runs_command {
+            let overlay = OverlayMount::mount(&self.workspace_dir, &self.layers.of(node))?;
+            return Ok(SandboxEnv {
+                workspace_dir: nested(&overlay.merged),
+                env_vars: self.env_for(node)?,
+                overlay: Some(overlay),
+                materialized: None,
+                processes: Some(Arc::new(ProcessTree::new())),
+            });
+        }
+        if let (Some(materializer), true) = (&self.materializer, runs_command) {
+            let materialized = tokio::task::block_in_place(|| materializer.materialize())?;
+            return Ok(SandboxEnv {
+                workspace_dir: nested(&materialized.dir),
+                env_vars: self.env_for(node)?,
+                overlay: None,
+                materialized: Some(Arc::new(materialized)),
+                processes: Some(Arc::new(ProcessTree::new())),
+            });
+        }
+
         Ok(SandboxEnv {
-            workspace_dir: self.workspace_dir.clone(),
-            env_vars: node.env.clone(),
+            workspace_dir: nested(&self.workspace_dir),
+            env_vars: self.env_for(node)?,
+            overlay: None,
+            materialized: None,
+            processes: Some(Arc::new(ProcessTree::new())),
         })
     }
 
@@ -27,6 +235,7 @@ impl Sandbox for LocalSandbox {
         let cmd = match &node.kind {
             crate::graph::NodeKind::Run => node.content.clone(),
             crate::graph::NodeKind::RunExtend { command, .. } => command.clone(),
+            crate::graph::NodeKind::Task { cmd, .. } => cmd.clone(),
             crate::graph::NodeKind::CustomHook { hook_name, params } => {
                 format!("{} {}", hook_name, params.join(" "))
             }
@@ -54,6 +263,9 @@ impl Sandbox for LocalSandbox {
                         stdout: format!("Copied {} to {}", src.display(), dst.display())
                             .into_bytes(),
                         stderr: Vec::new(),
+                        output_diff: None,
+                        injected_env: Vec::new(),
+                        usage: None,
                     });
                 }
             }
@@ -63,34 +275,328 @@ impl Sandbox for LocalSandbox {
                     exit_code: 0,
                     stdout: format!("Artifact for {}", node.name).into_bytes(),
                     stderr: Vec::new(),
+                    output_diff: None,
+                    injected_env: Vec::new(),
+                    usage: None,
                 });
             }
         };
 
-        let output = if cfg!(target_os = "windows") {
-            Command::new("cmd")
-                .arg("/C")
-                .arg(cmd)
-                .envs(&env.env_vars)
-                .current_dir(&env.workspace_dir)
-                .output()?
-        } else {
-            Command::new("sh")
-                .arg("-c")
-                .arg(cmd)
-                .envs(&env.env_vars)
-                .current_dir(&env.workspace_dir)
-                .output()?
+        if let (crate::graph::NodeKind::Run, Some(worker_argv)) = (&node.kind, worker::spec(node)) {
+            return self.execute_on_worker(env, node, &worker_argv, &cmd);
+        }
+
+        let argv = shell::argv(node, &cmd, shell::host_default());
+        let mut command = Command::new(&argv[0]);
+        command.args(&argv[1..]);
+        command
+            .env_clear()
+            .envs(&env.env_vars)
+            .current_dir(&env.workspace_dir);
+
+        let runs_command = matches!(
+            node.kind,
+            crate::graph::NodeKind::Run
+                | crate::graph::NodeKind::RunExtend { .. }
+                | crate::graph::NodeKind::Task { .. }
+        );
+        let trace_log = match self.trace {
+            Some(_) if runs_command => {
+                let log = std::env::temp_dir().join(format!(
+                    "memobuild-trace-{}.log",
+                    uuid::Uuid::new_v4().simple()
+                ));
+                command = trace::wrap(&command, &log);
+                Some(log)
+            }
+            _ => None,
+        };
+
+        // Kept alive until the command exits
+        let mut _proxy = None;
+        match node.metadata.network {
+            Some(NetworkPolicy::None) => {
+                if !network::isolation_available() {
+                    anyhow::bail!(
+                        "{} requires network isolation, but network namespaces are unavailable",
+                        node.name
+                    );
+                }
+                let mut isolated = Command::new("unshare");
+                isolated
+                    .args(["--net", "--map-root-user", "--"])
+                    .arg(command.get_program())
+                    .args(command.get_args())
+                    .env_clear()
+                    .envs(&env.env_vars)
+                    .current_dir(&env.workspace_dir);
+                command = isolated;
+            }
+            Some(NetworkPolicy::Allowlist(ref hosts)) => {
+                let proxy = EgressProxy::start(NetworkPolicy::Allowlist(hosts.clone()))?;
+                command.envs(proxy.env_vars());
+                _proxy = Some(proxy);
+            }
+            Some(NetworkPolicy::Full) | None => {}
+        }
+
+        if let Some(ref processes) = env.processes {
+            processes.configure(&mut command);
+        }
+        let child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(ref processes) = env.processes {
+            processes.track(child.id());
+        }
+        let limit = node.metadata.timeout();
+        let waited = wait_for(child, limit, env.processes.as_deref())?;
+        let (output, mut usage) = waited.with_context(|| {
+            format!(
+                "{}{} timed out after {:?}",
+                node.location_prefix(),
+                node.name,
+                limit.unwrap_or_default()
+            )
+        })?;
+        if let (Some(usage), Some(processes)) = (usage.as_mut(), env.processes.as_deref()) {
+            processes.read_usage(usage);
+        }
+
+        if let (Some(store), Some(log)) = (&self.trace, &trace_log) {
+            if output.status.success() {
+                let files = trace::parse_log(
+                    &std::fs::read_to_string(log).unwrap_or_default(),
+                    &env.workspace_dir,
+                );
+                let mut store = store.lock().unwrap();
+                match files {
+                    Some(files) => store.record(node, files),
+                    None => store.forget(node),
+                }
+            }
+            let _ = std::fs::remove_file(log);
+        }
+
+        let outputs = crate::tasks::outputs(&node.kind);
+        let output_diff = match output.status.success() {
+            // A task's artifact is what it declares, not everything it wrote
+            true if !outputs.is_empty() => Some(
+                crate::tasks::collect_outputs(&env.workspace_dir, outputs)
+                    .with_context(|| format!("{}{}", node.location_prefix(), node.name))?,
+            ),
+            true => capture_diff(env)?,
+            false => None,
         };
+        if output.status.success() {
+            self.layers.succeeded(env, node);
+        }
 
         Ok(ExecResult {
             exit_code: output.status.code().unwrap_or(1),
             stdout: output.stdout,
             stderr: output.stderr,
+            output_diff,
+            injected_env: env_names(&env.env_vars),
+            usage,
         })
     }
 
-    async fn cleanup(&self, _env: &SandboxEnv) -> Result<()> {
+    async fn shell(&self, env: &SandboxEnv, node: &Node, exit_code: i32) -> Result<()> {
+        tokio::task::block_in_place(|| debug::open_shell(env, node, exit_code))
+    }
+
+    async fn cleanup(&self, env: &SandboxEnv) -> Result<()> {
+        // Daemons left behind by the command must not outlive the node, and
+        // must be gone before the overlay can be unmounted
+        if let Some(ref processes) = env.processes {
+            processes.kill()?;
+        }
+        if let Some(ref overlay) = env.overlay {
+            self.layers.release(overlay)?;
+        }
+        if let Some(ref materialized) = env.materialized {
+            materialized.remove()?;
+        }
         Ok(())
     }
 }
+
+/// What the command wrote, when the workspace is an overlay or private.
+fn capture_diff(env: &SandboxEnv) -> Result<Option<Vec<u8>>> {
+    if let Some(ref overlay) = env.overlay {
+        return Ok(Some(overlay.capture_diff()?));
+    }
+    match env.materialized {
+        Some(ref materialized) => Ok(Some(materialized.capture_diff()?)),
+        None => Ok(None),
+    }
+}
+
+/// Wait for `child` like `wait_with_output`, also returning what it cost
+/// where that can be measured. Whatever it left running is killed once it
+/// exits, so background jobs holding its output open cannot stall the read.
+/// With a `limit`, kill it and everything it spawned once the limit has
+/// passed; `None` means it was killed.
+fn wait_for(
+    mut child: std::process::Child,
+    limit: Option<std::time::Duration>,
+    processes: Option<&ProcessTree>,
+) -> Result<Option<(std::process::Output, Option<ResourceUsage>)>> {
+    fn drain<R: std::io::Read + Send + 'static>(
+        pipe: Option<R>,
+    ) -> std::thread::JoinHandle<Vec<u8>> {
+        std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buf);
+            }
+            buf
+        })
+    }
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = limit.map(|limit| std::time::Instant::now() + limit);
+    let status = loop {
+        match deadline {
+            None => break Some(reap(&mut child, true)?.context("Command was not reaped")?),
+            Some(deadline) => {
+                if let Some(status) = reap(&mut child, false)? {
+                    break Some(status);
+                }
+                if std::time::Instant::now() >= deadline {
+                    if let Some(processes) = processes {
+                        processes.kill()?;
+                    }
+                    let _ = child.kill();
+                    let _ = reap(&mut child, true);
+                    break None;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+        }
+    };
+    if let Some(processes) = processes {
+        processes.signal();
+    }
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    Ok(status.map(|(status, usage)| {
+        (
+            std::process::Output {
+                status,
+                stdout,
+                stderr,
+            },
+            usage,
+        )
+    }))
+}
+
+/// Collect `child` once it exited, waiting for it when `block`. On Unix
+/// this is `wait4`, whose rusage covers the child and its reaped children.
+#[cfg(unix)]
+fn reap(
+    child: &mut std::process::Child,
+    block: bool,
+) -> Result<Option<(std::process::ExitStatus, Option<ResourceUsage>)>> {
+    use std::os::unix::process::ExitStatusExt;
+    let flags = if block { 0 } else { libc::WNOHANG };
+    let mut status = 0;
+    // SAFETY: zeroed rusage is a valid value for wait4 to fill in
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: the pid is our unreaped child; both out-pointers are valid
+        let pid = unsafe { libc::wait4(child.id() as i32, &mut status, flags, &mut rusage) };
+        match pid {
+            0 => return Ok(None),
+            -1 => {
+                let err = std::io::Error::last_os_error();
+                if err.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(err).context("Failed to wait for command");
+                }
+            }
+            _ => {
+                return Ok(Some((
+                    std::process::ExitStatus::from_raw(status),
+                    Some(ResourceUsage::from_rusage(&rusage)),
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn reap(
+    child: &mut std::process::Child,
+    block: bool,
+) -> Result<Option<(std::process::ExitStatus, Option<ResourceUsage>)>> {
+    let status = match block {
+        true => Some(child.wait()?),
+        false => child.try_wait()?,
+    };
+    Ok(status.map(|status| (status, None)))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_execute_measures_usage() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let graph = crate::docker::dag::build_graph_from_instructions(
+            crate::docker::parser::parse_dockerfile(
+                "FROM alpine\nRUN head -c 16000000 /dev/urandom | cksum\n",
+            ),
+            dir.path().to_path_buf(),
+        );
+        let sandbox = LocalSandbox::new(dir.path().to_path_buf());
+        let node = &graph.nodes[1];
+        let env = sandbox.prepare(node).await.unwrap();
+        let result = sandbox.execute(&env, node).await;
+        sandbox.cleanup(&env).await.unwrap();
+
+        let result = result.unwrap();
+        assert_eq!(result.exit_code, 0);
+        let usage = result.usage.unwrap();
+        assert!(usage.cpu_ms() > 0);
+        assert!(usage.peak_rss_bytes > 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_background_jobs_do_not_stall_output() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let graph = crate::docker::dag::build_graph_from_instructions(
+            crate::docker::parser::parse_dockerfile("FROM alpine\nRUN sleep 30 & echo $!\n"),
+            dir.path().to_path_buf(),
+        );
+        let sandbox = LocalSandbox::new(dir.path().to_path_buf());
+        let node = &graph.nodes[1];
+        let env = sandbox.prepare(node).await.unwrap();
+        let started = std::time::Instant::now();
+        let result = sandbox.execute(&env, node).await.unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+
+        let orphan: i32 = String::from_utf8_lossy(&result.stdout)
+            .trim()
+            .parse()
+            .unwrap();
+        // Killed when the command exited; at most a zombie once reparented
+        let running = || {
+            let state =
+                std::fs::read_to_string(format!("/proc/{}/stat", orphan)).unwrap_or_default();
+            !state.is_empty() && !state.contains(") Z")
+        };
+        for _ in 0..50 {
+            if !running() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert!(!running());
+        sandbox.cleanup(&env).await.unwrap();
+    }
+}
@@ -0,0 +1,604 @@
+//! Private working directories for RUN steps, built the way
+//! `MEMOBUILD_MATERIALIZE` picks.
+
+use crate::cache::cas::ContentStore;
+use crate::sandbox::lazy::{LazyMount, LazyTree};
+use crate::sandbox::overlay::OverlayMount;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Instant, SystemTime};
+
+/// File in the cache directory the timings are kept in
+pub const TIMINGS_FILE: &str = "materialize.json";
+
+/// Contexts above this size are never copied by `auto` (1 GB)
+pub const COPY_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Bytes of context that count as much as one file when scaling timings
+const BYTES_PER_UNIT: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Strategy {
+    Copy,
+    Hardlink,
+    Overlay,
+    Projection,
+    Lazy,
+}
+
+impl Strategy {
+    /// Order `auto` tries unmeasured strategies in
+    pub const PREFERENCE: [Strategy; 5] = [
+        Strategy::Overlay,
+        Strategy::Hardlink,
+        Strategy::Projection,
+        Strategy::Lazy,
+        Strategy::Copy,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Strategy::Copy => "copy",
+            Strategy::Hardlink => "hardlink",
+            Strategy::Overlay => "overlay",
+            Strategy::Projection => "projection",
+            Strategy::Lazy => "lazy",
+        }
+    }
+
+    /// Whether the strategy can work here at all. A strategy that passes
+    /// may still fail, e.g. an overlay mount without the privileges for it.
+    pub fn supported(self, workspace: &Path, scratch: &Path) -> bool {
+        match self {
+            Strategy::Copy => true,
+            Strategy::Overlay => cfg!(target_os = "linux"),
+            Strategy::Projection => cfg!(unix),
+            Strategy::Hardlink => same_device(workspace, scratch),
+            Strategy::Lazy => crate::sandbox::lazy::available(),
+        }
+    }
+
+    /// Whether the setup time grows with the size of the context
+    fn scales(self) -> bool {
+        !matches!(self, Strategy::Overlay | Strategy::Lazy)
+    }
+}
+
+impl std::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Strategy::PREFERENCE
+            .into_iter()
+            .find(|strategy| strategy.name().eq_ignore_ascii_case(s.trim()))
+            .with_context(|| {
+                format!(
+                    "Unknown materialization strategy '{}' (expected auto, copy, hardlink, overlay, projection or lazy)",
+                    s
+                )
+            })
+    }
+}
+
+#[cfg(unix)]
+fn same_device(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_device(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// Setup times of one strategy, summed over its runs.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Timing {
+    pub runs: u32,
+    pub total_ms: u64,
+    /// Files plus bytes / 64 KB of the contexts it set up
+    pub total_units: u64,
+}
+
+impl Timing {
+    /// Expected setup time for a context of `units`
+    fn estimate_ms(&self, strategy: Strategy, units: u64) -> f64 {
+        if !strategy.scales() {
+            return self.total_ms as f64 / self.runs.max(1) as f64;
+        }
+        self.total_ms as f64 * units as f64 / self.total_units.max(1) as f64
+    }
+}
+
+/// Measured setup times, kept in the cache directory between builds.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Timings {
+    #[serde(default)]
+    pub strategies: BTreeMap<String, Timing>,
+}
+
+impl Timings {
+    pub fn path_in(cache_dir: &Path) -> PathBuf {
+        cache_dir.join(TIMINGS_FILE)
+    }
+
+    /// Load the timings, starting empty if there are none yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn get(&self, strategy: Strategy) -> Option<&Timing> {
+        self.strategies.get(strategy.name())
+    }
+
+    pub fn record(&mut self, strategy: Strategy, units: u64, ms: u64) {
+        let timing = self
+            .strategies
+            .entry(strategy.name().to_string())
+            .or_default();
+        timing.runs += 1;
+        timing.total_ms += ms;
+        timing.total_units += units;
+    }
+}
+
+/// Files and bytes of a context.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ContextSize {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl ContextSize {
+    pub fn of(dir: &Path) -> Self {
+        let mut size = Self::default();
+        for entry in walkdir::WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            if let Ok(meta) = entry.metadata() {
+                if !meta.is_dir() {
+                    size.files += 1;
+                    size.bytes += meta.len();
+                }
+            }
+        }
+        size
+    }
+
+    fn units(self) -> u64 {
+        self.files + self.bytes / BYTES_PER_UNIT
+    }
+}
+
+/// The strategy `auto` picks out of `candidates` for a context of `size`:
+/// the first one never measured, else the one expected to be fastest.
+pub fn choose(candidates: &[Strategy], timings: &Timings, size: ContextSize) -> Option<Strategy> {
+    let candidates: Vec<Strategy> = candidates
+        .iter()
+        .copied()
+        .filter(|&s| s != Strategy::Copy || size.bytes <= COPY_MAX_BYTES)
+        .collect();
+    if let Some(&unmeasured) = candidates.iter().find(|&&s| timings.get(s).is_none()) {
+        return Some(unmeasured);
+    }
+    candidates.into_iter().min_by(|&a, &b| {
+        let estimate = |s: Strategy| timings.get(s).unwrap().estimate_ms(s, size.units());
+        estimate(a).total_cmp(&estimate(b))
+    })
+}
+
+/// Which strategy `MEMOBUILD_MATERIALIZE` asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Choice {
+    Auto,
+    Fixed(Strategy),
+}
+
+impl std::str::FromStr for Choice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.trim().eq_ignore_ascii_case("auto") {
+            return Ok(Choice::Auto);
+        }
+        Ok(Choice::Fixed(s.parse()?))
+    }
+}
+
+/// Sets up the working directories of one build's RUN steps.
+pub struct Materializer {
+    choice: Choice,
+    workspace: PathBuf,
+    scratch: PathBuf,
+    timings: Mutex<Timings>,
+    timings_path: PathBuf,
+    /// Size of the workspace, measured on first use
+    size: OnceLock<ContextSize>,
+    /// Strategies that failed this build and are not tried again
+    failed: Mutex<HashSet<Strategy>>,
+    /// Where `lazy` keeps the context
+    store: ContentStore,
+    /// Root of the context in `store`, stored on first use
+    lazy_root: Mutex<Option<String>>,
+}
+
+impl Materializer {
+    pub fn new(choice: Choice, workspace: PathBuf, cache_dir: &Path) -> Result<Self> {
+        let timings_path = Timings::path_in(cache_dir);
+        Ok(Self {
+            choice,
+            workspace,
+            scratch: std::env::temp_dir(),
+            timings: Mutex::new(Timings::load(&timings_path)?),
+            timings_path,
+            size: OnceLock::new(),
+            failed: Mutex::new(HashSet::new()),
+            store: ContentStore::in_cache_dir(cache_dir),
+            lazy_root: Mutex::new(None),
+        })
+    }
+
+    /// A materializer for `MEMOBUILD_MATERIALIZE`, or `None` when it is
+    /// unset and steps run in the workspace itself.
+    pub fn from_env(workspace: PathBuf, cache_dir: &Path) -> Result<Option<Self>> {
+        match std::env::var("MEMOBUILD_MATERIALIZE") {
+            Ok(value) if !value.trim().is_empty() => {
+                let choice = value.parse().context("Invalid MEMOBUILD_MATERIALIZE")?;
+                Ok(Some(Self::new(choice, workspace, cache_dir)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Put scratch directories under `dir` instead of the temp directory,
+    /// e.g. so hard links can reach the workspace.
+    pub fn with_scratch(mut self, dir: PathBuf) -> Self {
+        self.scratch = dir;
+        self
+    }
+
+    pub fn timings(&self) -> Timings {
+        self.timings.lock().unwrap().clone()
+    }
+
+    /// Keep the timings measured so far for later builds.
+    pub fn save(&self) -> Result<()> {
+        self.timings.lock().unwrap().save(&self.timings_path)
+    }
+
+    /// A fresh working directory with the content of the workspace. With
+    /// `auto`, a strategy that fails is skipped for the rest of the build
+    /// and the next one is tried.
+    pub fn materialize(&self) -> Result<Materialized> {
+        let size = *self.size.get_or_init(|| ContextSize::of(&self.workspace));
+        loop {
+            let strategy = match self.choice {
+                Choice::Fixed(strategy) => strategy,
+                Choice::Auto => {
+                    let failed = self.failed.lock().unwrap().clone();
+                    let candidates: Vec<Strategy> = Strategy::PREFERENCE
+                        .into_iter()
+                        .filter(|s| !failed.contains(s))
+                        .filter(|s| s.supported(&self.workspace, &self.scratch))
+                        .collect();
+                    let timings = self.timings.lock().unwrap();
+                    choose(&candidates, &timings, size)
+                        .context("No way to materialize the workspace is left")?
+                }
+            };
+            let started = Instant::now();
+            let created = match strategy {
+                Strategy::Lazy => self.mount_lazy(),
+                _ => Materialized::create(strategy, &self.workspace, &self.scratch),
+            };
+            match created {
+                Ok(materialized) => {
+                    let ms = started.elapsed().as_millis() as u64;
+                    self.timings
+                        .lock()
+                        .unwrap()
+                        .record(strategy, size.units(), ms);
+                    return Ok(materialized);
+                }
+                Err(e) if self.choice == Choice::Auto => {
+                    eprintln!("⚠️  {} workspace unavailable: {:#}", strategy, e);
+                    self.failed.lock().unwrap().insert(strategy);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// A `lazy` working directory, storing the context in the content store
+    /// first if this build has not yet.
+    fn mount_lazy(&self) -> Result<Materialized> {
+        let root = {
+            let mut lazy_root = self.lazy_root.lock().unwrap();
+            match *lazy_root {
+                Some(ref root) => root.clone(),
+                None => {
+                    let stored = self
+                        .store
+                        .store_dir(&self.workspace, &crate::hasher::IgnoreRules::empty())?;
+                    lazy_root.insert(stored.root).clone()
+                }
+            }
+        };
+        let tree = LazyTree::new(self.store.clone(), &root, None);
+        Materialized::lazy(Arc::new(tree), &self.scratch)
+    }
+}
+
+/// Size and modification time of an entry, to tell what a step wrote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+    is_dir: bool,
+}
+
+impl Stamp {
+    fn of(meta: &std::fs::Metadata) -> Self {
+        Self {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+            is_dir: meta.is_dir(),
+        }
+    }
+}
+
+/// A step's working directory, removed by [`Materialized::remove`].
+#[derive(Debug)]
+pub struct Materialized {
+    pub strategy: Strategy,
+    /// Where the step runs
+    pub dir: PathBuf,
+    /// Scratch directory holding `dir`
+    root: PathBuf,
+    overlay: Option<OverlayMount>,
+    /// The read-only tree under the overlay of a `lazy` directory, taken
+    /// out to unmount it
+    lazy: Mutex<Option<LazyMount>>,
+    /// Entries as set up, relative to `dir`; empty for overlays
+    baseline: BTreeMap<PathBuf, Stamp>,
+}
+
+impl Materialized {
+    pub fn create(strategy: Strategy, workspace: &Path, scratch: &Path) -> Result<Self> {
+        if strategy == Strategy::Overlay {
+            let overlay = OverlayMount::mount(workspace, &[])?;
+            return Ok(Self {
+                strategy,
+                dir: overlay.merged.clone(),
+                root: PathBuf::new(),
+                overlay: Some(overlay),
+                lazy: Mutex::new(None),
+                baseline: BTreeMap::new(),
+            });
+        }
+        if strategy == Strategy::Lazy {
+            anyhow::bail!("A lazy workspace is mounted from the content store, not created");
+        }
+
+        let workspace = workspace
+            .canonicalize()
+            .with_context(|| format!("Workspace {} not found", workspace.display()))?;
+        let root = scratch.join(format!(
+            "memobuild-{}-{}",
+            strategy,
+            uuid::Uuid::new_v4().simple()
+        ));
+        let dir = root.join("workspace");
+        let populated = populate(strategy, &workspace, &dir);
+        if let Err(e) = populated {
+            let _ = std::fs::remove_dir_all(&root);
+            return Err(e);
+        }
+        Ok(Self {
+            strategy,
+            baseline: scan(&dir),
+            dir,
+            root,
+            overlay: None,
+            lazy: Mutex::new(None),
+        })
+    }
+
+    /// Mount `tree` read-only under `scratch` with an overlay on top for
+    /// the step's writes.
+    pub fn lazy(tree: Arc<LazyTree>, scratch: &Path) -> Result<Self> {
+        let root = scratch.join(format!("memobuild-lazy-{}", uuid::Uuid::new_v4().simple()));
+        let lower = root.join("lower");
+        std::fs::create_dir_all(&lower)?;
+        let mounted = LazyMount::mount(tree, &lower)
+            .and_then(|mount| Ok((OverlayMount::mount(&lower, &[])?, mount)));
+        let (overlay, mount) = match mounted {
+            Ok(mounted) => mounted,
+            Err(e) => {
+                // A FUSE mount is released when `mounted` is dropped above
+                let _ = std::fs::remove_dir_all(&root);
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            strategy: Strategy::Lazy,
+            dir: overlay.merged.clone(),
+            root,
+            overlay: Some(overlay),
+            lazy: Mutex::new(Some(mount)),
+            baseline: BTreeMap::new(),
+        })
+    }
+
+    /// Pack what the step added or changed into a deterministic tarball.
+    /// Files it deleted are not recorded.
+    pub fn capture_diff(&self) -> Result<Vec<u8>> {
+        if let Some(ref overlay) = self.overlay {
+            return overlay.capture_diff();
+        }
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.mode(tar::HeaderMode::Deterministic);
+        builder.follow_symlinks(false);
+        for (rel, stamp) in scan(&self.dir) {
+            if self.baseline.get(&rel) == Some(&stamp) {
+                continue;
+            }
+            builder
+                .append_path_with_name(self.dir.join(&rel), &rel)
+                .with_context(|| format!("Failed to capture {}", rel.display()))?;
+        }
+        Ok(builder.into_inner()?)
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        if let Some(ref overlay) = self.overlay {
+            overlay.unmount()?;
+        }
+        // The FUSE mount goes after the overlay on top of it
+        drop(self.lazy.lock().unwrap().take());
+        if self.root.as_os_str().is_empty() {
+            return Ok(());
+        }
+        std::fs::remove_dir_all(&self.root)
+            .with_context(|| format!("Failed to remove {}", self.root.display()))
+    }
+}
+
+/// Recreate the tree of `workspace` at `dir` the way `strategy` does.
+fn populate(strategy: Strategy, workspace: &Path, dir: &Path) -> Result<()> {
+    for entry in walkdir::WalkDir::new(workspace).follow_links(false) {
+        let entry = entry?;
+        let rel = entry.path().strip_prefix(workspace)?;
+        let target = dir.join(rel);
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            std::fs::create_dir_all(&target)?;
+            continue;
+        }
+        let linked = match strategy {
+            Strategy::Hardlink if !file_type.is_symlink() => {
+                std::fs::hard_link(entry.path(), &target)
+            }
+            Strategy::Projection => symlink(entry.path(), &target),
+            _ if file_type.is_symlink() => symlink(&std::fs::read_link(entry.path())?, &target),
+            _ => std::fs::copy(entry.path(), &target).map(|_| ()),
+        };
+        linked.with_context(|| format!("Failed to materialize {}", rel.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(not(unix))]
+fn symlink(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::fs::copy(original, link).map(|_| ())
+}
+
+/// Every entry under `dir`, sorted, without following symlinks.
+fn scan(dir: &Path) -> BTreeMap<PathBuf, Stamp> {
+    walkdir::WalkDir::new(dir)
+        .min_depth(1)
+        .follow_links(false)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|entry| {
+            let stamp = Stamp::of(&entry.metadata().ok()?);
+            let rel = entry.path().strip_prefix(dir).ok()?.to_path_buf();
+            Some((rel, stamp))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_materialized_workspace_captures_writes() {
+        let dir = TempDir::new().unwrap();
+        let workspace = dir.path().join("ctx");
+        std::fs::create_dir_all(workspace.join("src")).unwrap();
+        std::fs::write(workspace.join("src/main.c"), "int main;").unwrap();
+        let scratch = dir.path().join("scratch");
+        std::fs::create_dir_all(&scratch).unwrap();
+
+        for strategy in [Strategy::Copy, Strategy::Hardlink, Strategy::Projection] {
+            if !strategy.supported(&workspace, &scratch) {
+                continue;
+            }
+            let materialized = Materialized::create(strategy, &workspace, &scratch).unwrap();
+            let main = std::fs::read_to_string(materialized.dir.join("src/main.c")).unwrap();
+            assert_eq!(main, "int main;");
+            std::fs::create_dir(materialized.dir.join("out")).unwrap();
+            std::fs::write(materialized.dir.join("out/app"), "binary").unwrap();
+
+            let diff = materialized.capture_diff().unwrap();
+            let mut archive = tar::Archive::new(diff.as_slice());
+            let names: Vec<String> = archive
+                .entries()
+                .unwrap()
+                .map(|e| e.unwrap().path().unwrap().display().to_string())
+                .collect();
+            assert_eq!(names, vec!["out", "out/app"], "{}", strategy);
+            materialized.remove().unwrap();
+            assert!(!workspace.join("out").exists());
+        }
+    }
+
+    #[test]
+    fn test_auto_prefers_unmeasured_then_fastest() {
+        let all = [Strategy::Hardlink, Strategy::Projection, Strategy::Copy];
+        let small = ContextSize {
+            files: 100,
+            bytes: 1024,
+        };
+        let mut timings = Timings::default();
+        assert_eq!(choose(&all, &timings, small), Some(Strategy::Hardlink));
+
+        timings.record(Strategy::Hardlink, 100, 50);
+        timings.record(Strategy::Projection, 100, 20);
+        assert_eq!(choose(&all, &timings, small), Some(Strategy::Copy));
+        timings.record(Strategy::Copy, 100, 400);
+        assert_eq!(choose(&all, &timings, small), Some(Strategy::Projection));
+
+        // Never copied once the context is too large
+        let huge = ContextSize {
+            files: 10,
+            bytes: COPY_MAX_BYTES + 1,
+        };
+        assert_eq!(choose(&[Strategy::Copy], &timings, huge), None);
+        assert_eq!(
+            "Hardlink".parse::<Choice>().unwrap(),
+            Choice::Fixed(Strategy::Hardlink)
+        );
+        assert!("tmpfs".parse::<Choice>().is_err());
+    }
+}
@@ -2,7 +2,7 @@ use crate::graph::Node;
 use anyhow::Result;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SandboxKind {
@@ -20,6 +20,13 @@ pub struct ResourceLimits {
 pub struct SandboxEnv {
     pub workspace_dir: std::path::PathBuf,
     pub env_vars: HashMap<String, String>,
+    /// Set when the workspace is an overlay whose upper layer captures outputs
+    pub overlay: Option<overlay::OverlayMount>,
+    /// Set when the step runs in a private copy or projection of the
+    /// workspace, see [`materialize`]
+    pub materialized: Option<std::sync::Arc<materialize::Materialized>>,
+    /// Processes the command spawned, killed on cleanup
+    pub processes: Option<std::sync::Arc<process::ProcessTree>>,
 }
 
 #[derive(Debug, Clone)]
@@ -27,6 +34,48 @@ pub struct ExecResult {
     pub exit_code: i32,
     pub stdout: Vec<u8>,
     pub stderr: Vec<u8>,
+    /// Tarball of exactly what the command wrote, when the sandbox can capture it
+    pub output_diff: Option<Vec<u8>>,
+    /// Names of the variables the command ran with, sorted
+    pub injected_env: Vec<String>,
+    /// What the command cost, when the sandbox can measure it
+    pub usage: Option<usage::ResourceUsage>,
+}
+
+/// Host variables allowed into sandboxes: `MEMOBUILD_ENV_PASSTHROUGH`
+/// (comma-separated) or [`crate::constants::DEFAULT_ENV_PASSTHROUGH`].
+pub fn env_passthrough() -> Vec<String> {
+    match std::env::var("MEMOBUILD_ENV_PASSTHROUGH") {
+        Ok(list) => list
+            .split(',')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect(),
+        Err(_) => crate::constants::DEFAULT_ENV_PASSTHROUGH
+            .iter()
+            .map(|name| name.to_string())
+            .collect(),
+    }
+}
+
+/// The complete environment for running `node`: the allowlisted host
+/// variables, overridden by the ENV values in scope. Nothing else from the
+/// host leaks in, so builds do not depend on whoever's shell started them.
+pub fn scoped_env(node: &Node, passthrough: &[String]) -> HashMap<String, String> {
+    let mut env: BTreeMap<String, String> = passthrough
+        .iter()
+        .filter_map(|name| std::env::var(name).ok().map(|value| (name.clone(), value)))
+        .collect();
+    env.extend(node.metadata.build_env.clone());
+    env.extend(node.env.clone());
+    env.into_iter().collect()
+}
+
+/// Sorted variable names, for [`ExecResult::injected_env`].
+pub fn env_names(env: &HashMap<String, String>) -> Vec<String> {
+    let mut names: Vec<String> = env.keys().cloned().collect();
+    names.sort();
+    names
 }
 
 #[async_trait]
@@ -34,9 +83,60 @@ pub trait Sandbox: Send + Sync {
     async fn prepare(&self, node: &Node) -> Result<SandboxEnv>;
     async fn execute(&self, env: &SandboxEnv, node: &Node) -> Result<ExecResult>;
     async fn cleanup(&self, env: &SandboxEnv) -> Result<()>;
+
+    /// Open an interactive shell in `env`, before cleanup, after `node`
+    /// failed in it with `exit_code`; returns once the shell exits.
+    async fn shell(&self, _env: &SandboxEnv, node: &Node, _exit_code: i32) -> Result<()> {
+        anyhow::bail!("{} ran in a sandbox that cannot open a shell", node.name)
+    }
 }
 
 #[cfg(feature = "containerd")]
 pub mod containerd;
+pub mod debug;
+pub mod lazy;
 pub mod local;
+pub mod materialize;
+pub mod network;
+pub mod overlay;
+pub mod process;
+pub mod shell;
 pub mod spec;
+pub mod trace;
+pub mod usage;
+pub mod worker;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{NodeKind, NodeMetadata};
+
+    #[test]
+    fn test_scoped_env_drops_host_variables() {
+        std::env::set_var("MEMOBUILD_TEST_LEAK", "host");
+        std::env::set_var("MEMOBUILD_TEST_PASS", "host");
+        let node = Node {
+            id: 0,
+            name: "run".into(),
+            content: "make".into(),
+            kind: NodeKind::Run,
+            hash: String::new(),
+            dirty: true,
+            source_path: None,
+            env: HashMap::new(),
+            cache_hit: false,
+            deps: vec![],
+            metadata: NodeMetadata {
+                build_env: [("MEMOBUILD_TEST_PASS".to_string(), "dockerfile".to_string())]
+                    .into_iter()
+                    .chain([("CC".to_string(), "clang".to_string())])
+                    .collect(),
+                ..Default::default()
+            },
+        };
+
+        let env = scoped_env(&node, &["MEMOBUILD_TEST_PASS".to_string()]);
+        assert_eq!(env_names(&env), vec!["CC", "MEMOBUILD_TEST_PASS"]);
+        assert_eq!(env["MEMOBUILD_TEST_PASS"], "dockerfile");
+    }
+}
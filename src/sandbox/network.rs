@@ -0,0 +1,327 @@
+use crate::graph::{BuildGraph, NodeKind};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+/// Network access a node is allowed while it runs.
+///
+/// Declared per instruction with `# memobuild:network=none|full|allow:host,...`
+/// or for the whole build with `MEMOBUILD_NETWORK`. The policy is part of the
+/// node's cache key, so granting a node network access invalidates it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NetworkPolicy {
+    /// No network at all; the command runs in an empty network namespace
+    None,
+    /// Only these hosts, reached through an egress proxy. `*.example.com`
+    /// and `.example.com` match any subdomain.
+    Allowlist(Vec<String>),
+    /// Unrestricted access to the host network
+    Full,
+}
+
+impl NetworkPolicy {
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        match s {
+            "none" | "off" => return Ok(NetworkPolicy::None),
+            "full" | "host" => return Ok(NetworkPolicy::Full),
+            _ => {}
+        }
+        let Some(hosts) = s.strip_prefix("allow:") else {
+            anyhow::bail!(
+                "Invalid network policy '{}', expected none, full or allow:<host>,...",
+                s
+            );
+        };
+        let mut hosts: Vec<String> = hosts
+            .split(',')
+            .map(|h| h.trim().to_ascii_lowercase())
+            .filter(|h| !h.is_empty())
+            .collect();
+        if hosts.is_empty() {
+            anyhow::bail!("Network allowlist is empty, use 'none' to disable the network");
+        }
+        hosts.sort();
+        hosts.dedup();
+        Ok(NetworkPolicy::Allowlist(hosts))
+    }
+
+    /// Whether `host` (without port) may be contacted under this policy.
+    pub fn allows(&self, host: &str) -> bool {
+        match self {
+            NetworkPolicy::None => false,
+            NetworkPolicy::Full => true,
+            NetworkPolicy::Allowlist(hosts) => {
+                let host = host.trim_end_matches('.').to_ascii_lowercase();
+                hosts.iter().any(|pattern| {
+                    match pattern
+                        .strip_prefix("*.")
+                        .or_else(|| pattern.strip_prefix('.'))
+                    {
+                        Some(suffix) => host == suffix || host.ends_with(&format!(".{}", suffix)),
+                        None => host == *pattern,
+                    }
+                })
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for NetworkPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkPolicy::None => write!(f, "none"),
+            NetworkPolicy::Full => write!(f, "full"),
+            NetworkPolicy::Allowlist(hosts) => write!(f, "allow:{}", hosts.join(",")),
+        }
+    }
+}
+
+/// Attach a network policy to every command node: its `network` directive if
+/// present, otherwise `default`. Must run before composite hashes are computed.
+pub fn apply_network_policies(
+    graph: &mut BuildGraph,
+    default: Option<&NetworkPolicy>,
+) -> Result<()> {
+    for node in &mut graph.nodes {
+        if !matches!(
+            node.kind,
+            NodeKind::Run | NodeKind::RunExtend { .. } | NodeKind::Task { .. }
+        ) {
+            continue;
+        }
+        node.metadata.network = match node.metadata.directives.get("network") {
+            Some(value) => {
+                Some(
+                    NetworkPolicy::parse(value).with_context(|| match node.metadata.span {
+                        Some(ref span) => format!("{}: invalid network directive", span),
+                        None => format!("{}: invalid network directive", node.name),
+                    })?,
+                )
+            }
+            None => default.cloned(),
+        };
+    }
+    Ok(())
+}
+
+/// Build-wide default policy from `MEMOBUILD_NETWORK`.
+pub fn default_policy_from_env() -> Result<Option<NetworkPolicy>> {
+    match std::env::var("MEMOBUILD_NETWORK") {
+        Ok(value) if !value.trim().is_empty() => Ok(Some(NetworkPolicy::parse(&value)?)),
+        _ => Ok(None),
+    }
+}
+
+/// Whether unprivileged network namespaces work on this host. Checked once.
+pub fn isolation_available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        cfg!(target_os = "linux")
+            && std::process::Command::new("unshare")
+                .args(["--net", "--map-root-user", "true"])
+                .output()
+                .map(|o| o.status.success())
+                .unwrap_or(false)
+    })
+}
+
+/// A minimal HTTP forward proxy on loopback that only lets allowlisted hosts
+/// through. Handles `CONNECT` for TLS and absolute-URI requests for plain HTTP.
+///
+/// Commands reach it through `HTTP_PROXY`/`HTTPS_PROXY`, so a tool that
+/// ignores the proxy variables is not contained by it.
+pub struct EgressProxy {
+    addr: std::net::SocketAddr,
+    stop: Arc<AtomicBool>,
+}
+
+impl EgressProxy {
+    pub fn start(policy: NetworkPolicy) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").context("Failed to bind egress proxy")?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let policy = Arc::new(policy);
+
+        let stop_flag = stop.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stop_flag.load(Ordering::SeqCst) {
+                    break;
+                }
+                let Ok(stream) = stream else { continue };
+                let policy = policy.clone();
+                std::thread::spawn(move || {
+                    let _ = handle_client(stream, &policy);
+                });
+            }
+        });
+
+        Ok(Self { addr, stop })
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Proxy variables in both spellings, since tools disagree on the case.
+    pub fn env_vars(&self) -> Vec<(String, String)> {
+        let url = self.url();
+        ["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"]
+            .into_iter()
+            .map(|k| (k.to_string(), url.clone()))
+            .chain([
+                ("NO_PROXY".to_string(), String::new()),
+                ("no_proxy".to_string(), String::new()),
+            ])
+            .collect()
+    }
+}
+
+impl Drop for EgressProxy {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the accept loop so the thread sees the flag
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+fn handle_client(mut client: TcpStream, policy: &NetworkPolicy) -> Result<()> {
+    client.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut reader = BufReader::new(client.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut head = vec![request_line.clone()];
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+        head.push(line);
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+
+    let (host, port) = if method.eq_ignore_ascii_case("CONNECT") {
+        split_host_port(&target, 443)
+    } else {
+        let rest = target
+            .strip_prefix("http://")
+            .context("Proxy only forwards absolute http:// URLs")?;
+        let authority = rest.split('/').next().unwrap_or_default();
+        split_host_port(authority, 80)
+    };
+
+    if !policy.allows(&host) {
+        eprintln!("🚫 Network policy {} blocked access to {}", policy, host);
+        client.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")?;
+        return Ok(());
+    }
+
+    let mut upstream = TcpStream::connect((host.as_str(), port))?;
+    client.set_read_timeout(None)?;
+    if method.eq_ignore_ascii_case("CONNECT") {
+        client.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")?;
+    } else {
+        for line in &head {
+            upstream.write_all(line.as_bytes())?;
+        }
+        upstream.write_all(b"\r\n")?;
+    }
+
+    // Whatever the client already sent past the header goes upstream first
+    let buffered = reader.buffer().to_vec();
+    upstream.write_all(&buffered)?;
+
+    let mut client_read = reader.into_inner();
+    let mut upstream_write = upstream.try_clone()?;
+    let forward = std::thread::spawn(move || {
+        let _ = std::io::copy(&mut client_read, &mut upstream_write);
+        let _ = upstream_write.shutdown(Shutdown::Write);
+    });
+    let _ = std::io::copy(&mut upstream, &mut client);
+    let _ = client.shutdown(Shutdown::Write);
+    let _ = forward.join();
+    Ok(())
+}
+
+fn split_host_port(authority: &str, default_port: u16) -> (String, u16) {
+    if let Some(rest) = authority.strip_prefix('[') {
+        // [ipv6]:port
+        if let Some((host, port)) = rest.split_once(']') {
+            let port = port.trim_start_matches(':').parse().unwrap_or(default_port);
+            return (host.to_string(), port);
+        }
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) => match port.parse() {
+            Ok(port) => (host.to_string(), port),
+            Err(_) => (authority.to_string(), default_port),
+        },
+        None => (authority.to_string(), default_port),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::{dag, parser};
+    use std::io::Read;
+
+    #[test]
+    fn test_parse_and_allow() {
+        let policy = NetworkPolicy::parse("allow:Crates.io,*.github.com,crates.io").unwrap();
+        assert_eq!(policy.to_string(), "allow:*.github.com,crates.io");
+        assert!(policy.allows("crates.io"));
+        assert!(policy.allows("codeload.github.com"));
+        assert!(!policy.allows("evilgithub.com"));
+        assert!(!policy.allows("static.crates.io"));
+        assert_eq!(NetworkPolicy::parse("none").unwrap(), NetworkPolicy::None);
+        assert!(NetworkPolicy::parse("allow:").is_err());
+        assert!(NetworkPolicy::parse("sometimes").is_err());
+    }
+
+    #[test]
+    fn test_directive_sets_policy_and_key() {
+        let content =
+            "FROM alpine\n# memobuild:network=allow:crates.io\nRUN cargo fetch\nRUN make\n";
+        let spanned = parser::parse_dockerfile_spanned(content, std::path::Path::new("Dockerfile"));
+        let mut graph = dag::build_graph_from_spanned(spanned, std::path::PathBuf::from("."));
+        apply_network_policies(&mut graph, Some(&NetworkPolicy::None)).unwrap();
+
+        assert_eq!(
+            graph.nodes[1].metadata.network,
+            Some(NetworkPolicy::Allowlist(vec!["crates.io".into()]))
+        );
+        assert_eq!(graph.nodes[2].metadata.network, Some(NetworkPolicy::None));
+        assert_eq!(graph.nodes[0].metadata.network, None);
+
+        let key = |graph: &BuildGraph| {
+            let mut hasher = blake3::Hasher::new();
+            graph.nodes[1].metadata.hash_key_inputs(&mut hasher);
+            hasher.finalize()
+        };
+        let before = key(&graph);
+        graph.nodes[1].metadata.network = Some(NetworkPolicy::Full);
+        assert_ne!(before, key(&graph));
+    }
+
+    #[test]
+    fn test_proxy_rejects_unlisted_host() {
+        let proxy =
+            EgressProxy::start(NetworkPolicy::Allowlist(vec!["example.invalid".into()])).unwrap();
+        let mut stream = TcpStream::connect(proxy.addr).unwrap();
+        stream
+            .write_all(b"CONNECT blocked.invalid:443 HTTP/1.1\r\nHost: blocked.invalid\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 403"));
+    }
+}
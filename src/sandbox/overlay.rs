@@ -0,0 +1,193 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// An overlay mount with the workspace as the read-only lower layer, under
+/// the layers of earlier steps when there are any.
+///
+/// Everything the command writes lands in `upper`, so the upper directory is
+/// exactly the node's output — no scanning or diffing of the workspace needed.
+/// Deleted files show up as overlayfs whiteouts (0/0 character devices).
+#[derive(Debug, Clone)]
+pub struct OverlayMount {
+    root: PathBuf,
+    pub upper: PathBuf,
+    pub merged: PathBuf,
+    /// Mounted with `fuse-overlayfs` because the kernel mount was not permitted
+    fuse: bool,
+}
+
+impl OverlayMount {
+    /// Mount `lower` with `layers` over it, the last on top, under a fresh
+    /// scratch directory. Tries the kernel overlay driver first and falls
+    /// back to `fuse-overlayfs` for rootless builds.
+    pub fn mount(lower: &Path, layers: &[PathBuf]) -> Result<Self> {
+        if !cfg!(target_os = "linux") {
+            anyhow::bail!("overlay sandbox is only available on Linux");
+        }
+
+        let root =
+            std::env::temp_dir().join(format!("memobuild-overlay-{}", uuid::Uuid::new_v4()));
+        let upper = root.join("upper");
+        let work = root.join("work");
+        let merged = root.join("merged");
+        for dir in [&upper, &work, &merged] {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let lower = lower
+            .canonicalize()
+            .with_context(|| format!("Workspace {} not found", lower.display()))?;
+        let options = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            lowerdir(&lower, layers),
+            upper.display(),
+            work.display()
+        );
+
+        let kernel = Command::new("mount")
+            .args(["-t", "overlay", "overlay", "-o", &options])
+            .arg(&merged)
+            .output();
+        let fuse = match kernel {
+            Ok(out) if out.status.success() => false,
+            _ => {
+                let out = Command::new("fuse-overlayfs")
+                    .args(["-o", &options])
+                    .arg(&merged)
+                    .output();
+                match out {
+                    Ok(out) if out.status.success() => true,
+                    Ok(out) => {
+                        let _ = std::fs::remove_dir_all(&root);
+                        anyhow::bail!(
+                            "Failed to mount overlay (need root or fuse-overlayfs): {}",
+                            String::from_utf8_lossy(&out.stderr).trim()
+                        );
+                    }
+                    Err(e) => {
+                        let _ = std::fs::remove_dir_all(&root);
+                        anyhow::bail!(
+                            "Failed to mount overlay (need root or fuse-overlayfs): {}",
+                            e
+                        );
+                    }
+                }
+            }
+        };
+
+        Ok(Self {
+            root,
+            upper,
+            merged,
+            fuse,
+        })
+    }
+
+    /// Pack the upper layer into a deterministic tarball.
+    pub fn capture_diff(&self) -> Result<Vec<u8>> {
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.mode(tar::HeaderMode::Deterministic);
+        builder.follow_symlinks(false);
+
+        let mut entries: Vec<PathBuf> = walkdir::WalkDir::new(&self.upper)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .map(|e| e.into_path())
+            .collect();
+        entries.sort();
+
+        for path in entries {
+            let rel = path.strip_prefix(&self.upper)?;
+            builder
+                .append_path_with_name(&path, rel)
+                .with_context(|| format!("Failed to capture {}", rel.display()))?;
+        }
+        Ok(builder.into_inner()?)
+    }
+
+    pub fn unmount(&self) -> Result<()> {
+        self.release()?;
+        std::fs::remove_dir_all(&self.root)?;
+        Ok(())
+    }
+
+    /// Unmount, moving the upper layer to `layer` to mount under later
+    /// steps.
+    pub fn unmount_keeping_upper(&self, layer: &Path) -> Result<()> {
+        self.release()?;
+        std::fs::rename(&self.upper, layer)
+            .with_context(|| format!("Failed to keep the upper layer {}", self.upper.display()))?;
+        std::fs::remove_dir_all(&self.root)?;
+        Ok(())
+    }
+
+    fn release(&self) -> Result<()> {
+        let status = if self.fuse {
+            Command::new("fusermount").arg("-u").arg(&self.merged).status()
+        } else {
+            Command::new("umount").arg(&self.merged).status()
+        };
+        match status {
+            Ok(s) if s.success() => {}
+            _ => anyhow::bail!("Failed to unmount overlay at {}", self.merged.display()),
+        }
+        Ok(())
+    }
+}
+
+/// The `lowerdir` option for `layers` over `lower`: overlayfs stacks lower
+/// directories from the leftmost down.
+fn lowerdir(lower: &Path, layers: &[PathBuf]) -> String {
+    let dirs: Vec<String> = layers
+        .iter()
+        .rev()
+        .map(PathBuf::as_path)
+        .chain(std::iter::once(lower))
+        .map(|dir| dir.display().to_string())
+        .collect();
+    dirs.join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_capture_diff_lists_upper_layer() {
+        let dir = TempDir::new().unwrap();
+        let upper = dir.path().join("upper");
+        std::fs::create_dir_all(upper.join("out")).unwrap();
+        std::fs::write(upper.join("out/app.bin"), b"binary").unwrap();
+
+        let overlay = OverlayMount {
+            root: dir.path().to_path_buf(),
+            upper: upper.clone(),
+            merged: dir.path().join("merged"),
+            fuse: false,
+        };
+        let diff = overlay.capture_diff().unwrap();
+        assert_eq!(diff, overlay.capture_diff().unwrap());
+
+        let mut archive = tar::Archive::new(diff.as_slice());
+        let names: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[1], "out/app.bin");
+    }
+
+    #[test]
+    fn test_later_layers_stack_on_top() {
+        let layers = vec![PathBuf::from("/tmp/first"), PathBuf::from("/tmp/second")];
+        assert_eq!(
+            lowerdir(Path::new("/ctx"), &layers),
+            "/tmp/second:/tmp/first:/ctx"
+        );
+        assert_eq!(lowerdir(Path::new("/ctx"), &[]), "/ctx");
+    }
+}
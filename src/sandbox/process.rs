@@ -0,0 +1,187 @@
+use crate::sandbox::usage::ResourceUsage;
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Everything a node's command spawned, so cleanup can tear it down.
+///
+/// The command starts in its own process group, which catches ordinary
+/// background jobs. On Linux with a delegated cgroup v2 subtree it also gets
+/// its own cgroup, which catches daemons that `setsid()` out of the group.
+#[derive(Debug, Default)]
+pub struct ProcessTree {
+    pgid: Mutex<Option<i32>>,
+    cgroup: Option<PathBuf>,
+}
+
+impl ProcessTree {
+    pub fn new() -> Self {
+        Self {
+            pgid: Mutex::new(None),
+            cgroup: create_cgroup(),
+        }
+    }
+
+    /// Make the spawned command lead a new process group.
+    pub fn configure(&self, command: &mut Command) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+        #[cfg(not(unix))]
+        let _ = command;
+    }
+
+    /// Record a freshly spawned child. Anything it forks before being moved
+    /// into the cgroup is still covered by the process group.
+    pub fn track(&self, pid: u32) {
+        *self.pgid.lock().unwrap() = Some(pid as i32);
+        if let Some(ref cgroup) = self.cgroup {
+            let _ = std::fs::write(cgroup.join("cgroup.procs"), pid.to_string());
+        }
+    }
+
+    /// Replace `usage` with what the tree's cgroup measured, which also
+    /// covers processes that left the process group. Must be called
+    /// before [`kill`](Self::kill) removes the cgroup.
+    pub fn read_usage(&self, usage: &mut ResourceUsage) {
+        if let Some(ref cgroup) = self.cgroup {
+            usage.read_cgroup(cgroup);
+        }
+    }
+
+    /// Kill every process still alive in the tree, keeping the cgroup and
+    /// what it measured. Background jobs holding the command's output pipes
+    /// open would otherwise keep its output from ever ending.
+    pub fn signal(&self) {
+        if let Some(pgid) = *self.pgid.lock().unwrap() {
+            // Negative pid signals the whole group
+            send_sigkill(-pgid);
+        }
+        if let Some(ref cgroup) = self.cgroup {
+            if !cgroup.exists() {
+                return;
+            }
+            if std::fs::write(cgroup.join("cgroup.kill"), "1").is_err() {
+                // Kernels before 5.14 have no cgroup.kill
+                let procs =
+                    std::fs::read_to_string(cgroup.join("cgroup.procs")).unwrap_or_default();
+                for pid in procs.lines().filter_map(|p| p.trim().parse::<i32>().ok()) {
+                    send_sigkill(pid);
+                }
+            }
+        }
+    }
+
+    /// Kill every process still alive in the tree and remove its cgroup.
+    pub fn kill(&self) -> Result<()> {
+        self.signal();
+        self.pgid.lock().unwrap().take();
+
+        if let Some(ref cgroup) = self.cgroup {
+            // Already torn down, e.g. by a timeout before cleanup
+            if !cgroup.exists() {
+                return Ok(());
+            }
+            // rmdir fails while members are still exiting, so retry briefly
+            let mut removed = std::fs::remove_dir(cgroup);
+            for _ in 0..50 {
+                if removed.is_ok() {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                removed = std::fs::remove_dir(cgroup);
+            }
+            removed.with_context(|| format!("Failed to remove cgroup {}", cgroup.display()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn send_sigkill(pid: i32) {
+    // SAFETY: plain syscall; ESRCH just means the process already exited
+    unsafe {
+        libc::kill(pid, libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn send_sigkill(_pid: i32) {}
+
+/// A child of our own cgroup, if this process may create one (root, or a
+/// systemd-delegated subtree for rootless builds).
+fn create_cgroup() -> Option<PathBuf> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+    // cgroup v2 has a single "0::/path" line
+    let own = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = own.lines().find_map(|l| l.strip_prefix("0::"))?;
+    let dir = PathBuf::from(CGROUP_ROOT)
+        .join(path.trim_start_matches('/'))
+        .join(format!("memobuild-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir(&dir).ok()?;
+    // Only cgroup2 fills a new directory with its interface files; anywhere
+    // else (a hybrid host's tmpfs of v1 controllers) it is a plain directory
+    if !dir.join("cgroup.procs").exists() {
+        let _ = std::fs::remove_dir(&dir);
+        return None;
+    }
+    Some(dir)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kill_reaps_background_children() {
+        let tree = ProcessTree {
+            pgid: Mutex::new(None),
+            cgroup: None,
+        };
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 30 & echo $!"]);
+        tree.configure(&mut command);
+        command.stdout(std::process::Stdio::piped());
+
+        let mut child = command.spawn().unwrap();
+        tree.track(child.id());
+        // Only the first line: the orphan keeps the pipe open
+        let mut line = String::new();
+        std::io::BufRead::read_line(
+            &mut std::io::BufReader::new(child.stdout.take().unwrap()),
+            &mut line,
+        )
+        .unwrap();
+        child.wait().unwrap();
+        let orphan: i32 = line.trim().parse().unwrap();
+        assert!(is_running(orphan));
+
+        tree.kill().unwrap();
+        assert!(exits_soon(orphan));
+    }
+
+    /// Whether `pid` is alive and not a zombie.
+    fn is_running(pid: i32) -> bool {
+        let state = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+        !state.is_empty() && !state.contains(") Z")
+    }
+
+    /// Whether `pid` stops running within a second. A reparented orphan may
+    /// linger as a zombie, which counts as gone.
+    fn exits_soon(pid: i32) -> bool {
+        for _ in 0..50 {
+            if !is_running(pid) {
+                return true;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        false
+    }
+}
@@ -0,0 +1,109 @@
+//! The shell RUN commands are handed to, set by SHELL or `MEMOBUILD_SHELL`.
+
+use crate::graph::{BuildGraph, Node, NodeKind};
+use anyhow::{Context, Result};
+
+/// Shell of the local sandbox when nothing else is configured
+pub fn host_default() -> Vec<String> {
+    if cfg!(target_os = "windows") {
+        vec!["cmd".into(), "/S".into(), "/C".into()]
+    } else {
+        vec!["sh".into(), "-c".into()]
+    }
+}
+
+/// Shell inside containers and on remote workers when nothing else is
+/// configured
+pub fn container_default() -> Vec<String> {
+    vec!["/bin/sh".into(), "-c".into()]
+}
+
+/// Parse a shell from its JSON form (`["bash", "-c"]`, as in SHELL) or as
+/// whitespace-separated words (`bash -euo pipefail -c`).
+pub fn parse(spec: &str) -> Result<Vec<String>> {
+    let spec = spec.trim();
+    let words: Vec<String> = if spec.starts_with('[') {
+        serde_json::from_str(spec)
+            .with_context(|| format!("SHELL must be a JSON array of strings, got {}", spec))?
+    } else {
+        spec.split_whitespace().map(str::to_string).collect()
+    };
+    if words.is_empty() || words[0].is_empty() {
+        anyhow::bail!("Empty shell");
+    }
+    Ok(words)
+}
+
+/// The shell `MEMOBUILD_SHELL` asks for, if set.
+pub fn default_from_env() -> Result<Option<Vec<String>>> {
+    match std::env::var("MEMOBUILD_SHELL") {
+        Ok(spec) if !spec.trim().is_empty() => parse(&spec)
+            .map(Some)
+            .context("Invalid MEMOBUILD_SHELL"),
+        _ => Ok(None),
+    }
+}
+
+/// Give RUN nodes without a SHELL the configured default.
+pub fn apply_default(graph: &mut BuildGraph, default: Option<&[String]>) {
+    let Some(default) = default else {
+        return;
+    };
+    for node in &mut graph.nodes {
+        if matches!(node.kind, NodeKind::Run | NodeKind::RunExtend { .. })
+            && node.metadata.shell.is_none()
+        {
+            node.metadata.shell = Some(default.to_vec());
+        }
+    }
+}
+
+/// Full argv running `command` in the node's shell, or in `fallback` if it
+/// has none.
+pub fn argv(node: &Node, command: &str, fallback: Vec<String>) -> Vec<String> {
+    let mut argv = node.metadata.shell.clone().unwrap_or(fallback);
+    argv.push(command.to_string());
+    argv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::{dag, parser};
+
+    #[test]
+    fn test_shell_per_stage_and_default() {
+        assert_eq!(
+            parse(r#"["bash", "-euo", "pipefail", "-c"]"#).unwrap(),
+            vec!["bash", "-euo", "pipefail", "-c"]
+        );
+        assert_eq!(parse("bash -c").unwrap(), vec!["bash", "-c"]);
+        assert!(parse("[]").is_err());
+        assert!(parse("[\"bash\"").is_err());
+
+        let mut graph = dag::build_graph_from_instructions(
+            parser::parse_dockerfile(
+                "FROM alpine\nRUN a\nSHELL [\"bash\", \"-c\"]\nRUN b\nFROM alpine\nRUN c\n",
+            ),
+            std::path::PathBuf::from("."),
+        );
+        let shells = |graph: &BuildGraph| -> Vec<Option<Vec<String>>> {
+            graph
+                .nodes
+                .iter()
+                .filter(|n| n.kind == NodeKind::Run)
+                .map(|n| n.metadata.shell.clone())
+                .collect()
+        };
+        let bash = Some(vec!["bash".to_string(), "-c".to_string()]);
+        assert_eq!(shells(&graph), vec![None, bash.clone(), None]);
+
+        let zsh = vec!["zsh".to_string(), "-c".to_string()];
+        apply_default(&mut graph, Some(&zsh));
+        assert_eq!(shells(&graph), vec![Some(zsh.clone()), bash, Some(zsh)]);
+        assert_eq!(
+            argv(&graph.nodes[1], "make", host_default()),
+            vec!["zsh", "-c", "make"]
+        );
+    }
+}
@@ -2,9 +2,16 @@ use oci_spec::runtime::*;
 use std::collections::HashMap;
 use std::path::Path;
 
-pub fn build_spec(cmd: &str, env: &HashMap<String, String>, rootfs: &Path) -> Spec {
+/// With `isolate_network` the container gets its own, empty network namespace;
+/// otherwise it shares the host network.
+pub fn build_spec(
+    args: Vec<String>,
+    env: &HashMap<String, String>,
+    rootfs: &Path,
+    isolate_network: bool,
+) -> Spec {
     let process = ProcessBuilder::default()
-        .args(vec!["/bin/sh".into(), "-c".into(), cmd.into()])
+        .args(args)
         .env(
             env.iter()
                 .map(|(k, v)| format!("{}={}", k, v))
@@ -21,7 +28,7 @@ pub fn build_spec(cmd: &str, env: &HashMap<String, String>, rootfs: &Path) -> Sp
         .build()
         .unwrap();
 
-    let namespaces = vec![
+    let mut namespaces = vec![
         LinuxNamespaceBuilder::default()
             .typ(LinuxNamespaceType::Pid)
             .build()
@@ -39,6 +46,14 @@ pub fn build_spec(cmd: &str, env: &HashMap<String, String>, rootfs: &Path) -> Sp
             .build()
             .unwrap(),
     ];
+    if isolate_network {
+        namespaces.push(
+            LinuxNamespaceBuilder::default()
+                .typ(LinuxNamespaceType::Network)
+                .build()
+                .unwrap(),
+        );
+    }
 
     let linux = LinuxBuilder::default()
         .namespaces(namespaces)
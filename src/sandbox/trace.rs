@@ -0,0 +1,430 @@
+//! File-level inputs of RUN steps, recorded under `strace` with
+//! `MEMOBUILD_TRACE_INPUTS=1`.
+
+use crate::graph::{BuildGraph, Node, NodeKind};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+/// File the traces are kept in, in the local cache directory
+pub const TRACES_FILE: &str = "traces.json";
+
+/// Syscalls whose first path argument is read (or checked for existence)
+const READ_SYSCALLS: &[&str] = &[
+    "open",
+    "openat",
+    "openat2",
+    "stat",
+    "lstat",
+    "newfstatat",
+    "fstatat64",
+    "statx",
+    "access",
+    "faccessat",
+    "faccessat2",
+    "readlink",
+    "readlinkat",
+    "execve",
+    "execveat",
+];
+
+/// Flags that make an `open` a write
+const WRITE_FLAGS: &[&str] = &["O_WRONLY", "O_RDWR", "O_CREAT", "O_TRUNC"];
+
+/// Syscalls that create, change or remove their first path argument
+const WRITE_SYSCALLS: &[&str] = &[
+    "creat",
+    "mkdir",
+    "mkdirat",
+    "unlink",
+    "unlinkat",
+    "rmdir",
+    "rename",
+    "renameat",
+    "renameat2",
+    "link",
+    "linkat",
+    "symlink",
+    "symlinkat",
+    "truncate",
+    "chmod",
+    "fchmodat",
+    "utimensat",
+];
+
+/// Whether `MEMOBUILD_TRACE_INPUTS` asks for traced RUN steps.
+pub fn enabled_from_env() -> bool {
+    matches!(
+        std::env::var("MEMOBUILD_TRACE_INPUTS").as_deref(),
+        Ok("1") | Ok("true")
+    )
+}
+
+/// Whether `strace` can be run on this machine.
+pub fn tracer_available() -> bool {
+    cfg!(target_os = "linux")
+        && Command::new("strace")
+            .arg("-V")
+            .output()
+            .map(|o| o.status.success())
+            .unwrap_or(false)
+}
+
+/// `command` run under `strace`, writing the trace to `log`. Environment and
+/// working directory are carried over.
+pub fn wrap(command: &Command, log: &Path) -> Command {
+    let mut traced = Command::new("strace");
+    traced
+        .args(["-f", "-qq", "-e", "trace=%file,%process,chdir", "-o"])
+        .arg(log)
+        .arg("--")
+        .arg(command.get_program())
+        .args(command.get_args())
+        .env_clear();
+    for (key, value) in command.get_envs() {
+        if let Some(value) = value {
+            traced.env(key, value);
+        }
+    }
+    if let Some(dir) = command.get_current_dir() {
+        traced.current_dir(dir);
+    }
+    traced
+}
+
+/// Files under `workspace` that the traced command read, as paths relative
+/// to it, from an `strace -f` log. `None` when it read relative to a
+/// directory descriptor not opened in the log, so what it read is unknown.
+pub fn parse_log(log: &str, workspace: &Path) -> Option<BTreeSet<String>> {
+    let mut cwd: HashMap<u32, PathBuf> = HashMap::new();
+    // What each process's descriptors were opened on
+    let mut fds: HashMap<u32, HashMap<i32, PathBuf>> = HashMap::new();
+    let mut written: BTreeSet<String> = BTreeSet::new();
+    let mut read: BTreeSet<String> = BTreeSet::new();
+
+    for line in log.lines() {
+        let (pid, call) = match line.split_once(' ') {
+            Some((pid, rest)) if pid.chars().all(|c| c.is_ascii_digit()) => {
+                (pid.parse().unwrap_or(0), rest.trim_start())
+            }
+            _ => (0, line),
+        };
+        let Some((syscall, args)) = call.split_once('(') else {
+            continue;
+        };
+        let result = call.rsplit_once(" = ").map(|(_, r)| r.trim());
+        let failed = result.is_some_and(|r| r.starts_with('-'));
+        let dir = cwd
+            .get(&pid)
+            .cloned()
+            .unwrap_or_else(|| workspace.to_path_buf());
+
+        match syscall {
+            "clone" | "clone3" | "fork" | "vfork" => {
+                if let Some(child) = result.and_then(|r| r.parse::<u32>().ok()) {
+                    cwd.insert(child, dir);
+                    if let Some(open) = fds.get(&pid).cloned() {
+                        fds.insert(child, open);
+                    }
+                }
+            }
+            "chdir" if !failed => {
+                if let Some(path) = first_string(args) {
+                    cwd.insert(pid, normalize(&dir.join(path)));
+                }
+            }
+            _ => {
+                let Some(path) = first_string(args) else {
+                    continue;
+                };
+                let relative_to_fd = matches!(
+                    syscall,
+                    "openat"
+                        | "openat2"
+                        | "newfstatat"
+                        | "fstatat64"
+                        | "statx"
+                        | "faccessat"
+                        | "faccessat2"
+                        | "readlinkat"
+                        | "execveat"
+                        | "mkdirat"
+                        | "unlinkat"
+                        | "renameat"
+                        | "renameat2"
+                        | "linkat"
+                        | "symlinkat"
+                        | "fchmodat"
+                        | "utimensat"
+                ) && !args.starts_with("AT_FDCWD")
+                    && !path.starts_with('/');
+                let base = match relative_to_fd {
+                    true => {
+                        let fd = args.split(',').next().and_then(|fd| fd.parse::<i32>().ok());
+                        fd.and_then(|fd| fds.get(&pid)?.get(&fd)).cloned()
+                    }
+                    false => Some(dir),
+                };
+                let writes = WRITE_SYSCALLS.contains(&syscall)
+                    || (syscall.starts_with("open")
+                        && WRITE_FLAGS.iter().any(|flag| args.contains(flag)));
+                let Some(base) = base else {
+                    // A write that cannot be placed only makes the trace
+                    // list an output as an input too
+                    if !writes && READ_SYSCALLS.contains(&syscall) && !failed {
+                        return None;
+                    }
+                    continue;
+                };
+                let full = normalize(&base.join(&path));
+                if syscall.starts_with("open") {
+                    if let Some(fd) = result.and_then(|r| r.parse::<i32>().ok()) {
+                        fds.entry(pid).or_default().insert(fd, full.clone());
+                    }
+                }
+                let Some(rel) = full
+                    .strip_prefix(workspace)
+                    .ok()
+                    .map(|p| p.to_string_lossy().replace('\\', "/"))
+                else {
+                    continue;
+                };
+                if rel.is_empty() {
+                    continue;
+                }
+
+                if writes {
+                    if !read.contains(&rel) {
+                        written.insert(rel);
+                    }
+                } else if READ_SYSCALLS.contains(&syscall) && !written.contains(&rel) {
+                    read.insert(rel);
+                }
+            }
+        }
+    }
+    Some(read)
+}
+
+/// The first double-quoted argument, unescaped.
+fn first_string(args: &str) -> Option<String> {
+    let start = args.find('"')? + 1;
+    let mut out = String::new();
+    let mut chars = args[start..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(out),
+            '\\' => match chars.next()? {
+                'n' => out.push('\n'),
+                't' => out.push('\t'),
+                other => out.push(other),
+            },
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+/// Resolve `.` and `..` without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// The files one RUN instruction read the last time it ran traced.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct RunTrace {
+    /// Paths relative to the workspace
+    pub files: Vec<String>,
+    /// Unix timestamp of the traced run
+    pub recorded_at: i64,
+}
+
+/// Traces of past runs, keyed by step, see [`TraceStore::key`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TraceStore {
+    #[serde(default)]
+    pub runs: BTreeMap<String, RunTrace>,
+}
+
+impl TraceStore {
+    /// Where the traces live for a given cache directory.
+    pub fn path_in(cache_dir: &Path) -> PathBuf {
+        cache_dir.join(TRACES_FILE)
+    }
+
+    /// Load the traces, starting empty if there are none yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// The step a trace belongs to: the instruction with its stage and
+    /// place in the graph, as the same instruction in another stage or
+    /// repeated later runs on other files.
+    pub fn key(node: &Node) -> String {
+        format!(
+            "{}#{}:{}",
+            node.metadata.group.as_deref().unwrap_or_default(),
+            node.id,
+            node.name
+        )
+    }
+
+    pub fn get(&self, node: &Node) -> Option<&RunTrace> {
+        self.runs.get(&Self::key(node))
+    }
+
+    /// Replace the trace of `node` with the files its latest run read.
+    pub fn record(&mut self, node: &Node, files: BTreeSet<String>) {
+        self.runs.insert(
+            Self::key(node),
+            RunTrace {
+                files: files.into_iter().collect(),
+                recorded_at: chrono::Utc::now().timestamp(),
+            },
+        );
+    }
+
+    /// Drop the trace of `node`, whose files are no longer known.
+    pub fn forget(&mut self, node: &Node) {
+        self.runs.remove(&Self::key(node));
+    }
+}
+
+/// Digest of the current state of `files` under `workspace`: file contents,
+/// directory listings, and which paths do not exist.
+pub fn inputs_digest(files: &[String], workspace: &Path) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    for file in files {
+        let path = workspace.join(file);
+        hasher.update(file.as_bytes());
+        hasher.update(b"\0");
+        if path.is_file() {
+            hasher.update(crate::hasher::file_hasher::hash_file(&path)?.as_bytes());
+        } else if path.is_dir() {
+            // Listing a directory (globs, `ls`) depends on its entries only
+            let mut names: Vec<String> = std::fs::read_dir(&path)?
+                .filter_map(|e| e.ok())
+                .map(|e| e.file_name().to_string_lossy().to_string())
+                .collect();
+            names.sort();
+            hasher.update(b"dir:");
+            hasher.update(names.join("\0").as_bytes());
+        } else {
+            hasher.update(b"absent");
+        }
+        hasher.update(b"\n");
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Set `traced_inputs` on every RUN node that has a trace. Returns how many
+/// nodes are keyed by traced files.
+pub fn apply(graph: &mut BuildGraph, store: &TraceStore, workspace: &Path) -> Result<usize> {
+    let mut traced = 0;
+    for node in &mut graph.nodes {
+        if !matches!(node.kind, NodeKind::Run | NodeKind::RunExtend { .. }) {
+            continue;
+        }
+        let Some(trace) = store.get(node) else {
+            continue;
+        };
+        node.metadata.traced_inputs = Some(inputs_digest(&trace.files, workspace)?);
+        traced += 1;
+    }
+    Ok(traced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_log() {
+        let log = r#"100 execve("/bin/sh", ["sh", "-c", "make"], 0x7ffd /* 3 vars */) = 0
+100 openat(AT_FDCWD, "/etc/ld.so.cache", O_RDONLY|O_CLOEXEC) = 3
+100 clone(child_stack=NULL, flags=CLONE_CHILD_SETTID|SIGCHLD) = 101
+101 chdir("src") = 0
+101 openat(AT_FDCWD, "main.c", O_RDONLY) = 3
+101 newfstatat(AT_FDCWD, "../config.h", {st_mode=S_IFREG|0644, st_size=10, ...}, 0) = 0
+101 access("missing.h", F_OK) = -1 ENOENT (No such file or directory)
+101 openat(AT_FDCWD, "main.o", O_WRONLY|O_CREAT|O_TRUNC, 0666) = 4
+101 openat(AT_FDCWD, "main.o", O_RDONLY) = 5
+101 openat(AT_FDCWD, "include", O_RDONLY|O_DIRECTORY) = 6
+101 newfstatat(6, "util.h", {st_mode=S_IFREG|0644, st_size=10, ...}, 0) = 0
+100 openat(AT_FDCWD, "Makefile", O_RDONLY <unfinished ...>
+100 +++ exited with 0 +++
+"#;
+        let files = parse_log(log, Path::new("/work")).unwrap();
+        assert_eq!(
+            files.into_iter().collect::<Vec<_>>(),
+            vec![
+                "Makefile",
+                "config.h",
+                "src/include",
+                "src/include/util.h",
+                "src/main.c",
+                "src/missing.h",
+            ]
+        );
+
+        // A read through a descriptor opened before the trace began
+        let log = "100 openat(3, \"relative\", O_RDONLY) = 6\n";
+        assert_eq!(parse_log(log, Path::new("/work")), None);
+    }
+
+    #[test]
+    fn test_traces_are_per_step() {
+        let graph = crate::docker::dag::build_graph_from_instructions(
+            crate::docker::parser::parse_dockerfile(
+                "FROM alpine AS a\nRUN make\nFROM alpine AS b\nRUN make\n",
+            ),
+            PathBuf::from("."),
+        );
+        let runs: Vec<&Node> = graph
+            .nodes
+            .iter()
+            .filter(|n| n.kind == NodeKind::Run)
+            .collect();
+        let mut store = TraceStore::default();
+        store.record(runs[0], BTreeSet::from(["a.c".to_string()]));
+        assert!(store.get(runs[1]).is_none());
+        store.forget(runs[0]);
+        assert!(store.get(runs[0]).is_none());
+    }
+
+    #[test]
+    fn test_inputs_digest_tracks_only_traced_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(dir.path().join("read.txt"), "a").unwrap();
+        std::fs::write(dir.path().join("other.txt"), "a").unwrap();
+        let files = vec!["read.txt".to_string(), "gone.txt".to_string()];
+
+        let before = inputs_digest(&files, dir.path()).unwrap();
+        std::fs::write(dir.path().join("other.txt"), "b").unwrap();
+        assert_eq!(before, inputs_digest(&files, dir.path()).unwrap());
+        std::fs::write(dir.path().join("gone.txt"), "new").unwrap();
+        assert_ne!(before, inputs_digest(&files, dir.path()).unwrap());
+    }
+}
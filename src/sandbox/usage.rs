@@ -0,0 +1,123 @@
+//! What a node's command cost: CPU time, peak memory and disk IO.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    /// CPU time spent in user space
+    pub cpu_user_ms: u64,
+    /// CPU time spent in the kernel on its behalf
+    pub cpu_system_ms: u64,
+    /// Largest resident set of the command or any of its processes
+    pub peak_rss_bytes: u64,
+    /// Bytes read from block devices
+    pub read_bytes: u64,
+    /// Bytes written to block devices
+    pub write_bytes: u64,
+}
+
+impl ResourceUsage {
+    pub fn cpu_ms(&self) -> u64 {
+        self.cpu_user_ms + self.cpu_system_ms
+    }
+
+    pub fn io_bytes(&self) -> u64 {
+        self.read_bytes + self.write_bytes
+    }
+
+    #[cfg(unix)]
+    pub fn from_rusage(usage: &libc::rusage) -> Self {
+        let ms = |t: libc::timeval| t.tv_sec as u64 * 1000 + t.tv_usec as u64 / 1000;
+        // ru_maxrss is in kilobytes on Linux and in bytes on macOS
+        let rss_unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+        Self {
+            cpu_user_ms: ms(usage.ru_utime),
+            cpu_system_ms: ms(usage.ru_stime),
+            peak_rss_bytes: usage.ru_maxrss.max(0) as u64 * rss_unit,
+            // Counted in 512-byte blocks
+            read_bytes: usage.ru_inblock.max(0) as u64 * 512,
+            write_bytes: usage.ru_oublock.max(0) as u64 * 512,
+        }
+    }
+
+    /// Replace what the cgroup at `dir` measured itself. Counters the
+    /// kernel does not offer, e.g. `memory.peak` before 5.19, are kept.
+    pub fn read_cgroup(&mut self, dir: &Path) {
+        let read = |file: &str| std::fs::read_to_string(dir.join(file)).ok();
+        if let Some(stat) = read("cpu.stat") {
+            let field = |name: &str| {
+                stat.lines()
+                    .filter_map(|line| line.split_once(' '))
+                    .find(|(key, _)| *key == name)
+                    .and_then(|(_, value)| value.trim().parse::<u64>().ok())
+            };
+            if let (Some(user), Some(system)) = (field("user_usec"), field("system_usec")) {
+                self.cpu_user_ms = user / 1000;
+                self.cpu_system_ms = system / 1000;
+            }
+        }
+        if let Some(peak) = read("memory.peak").and_then(|p| p.trim().parse().ok()) {
+            self.peak_rss_bytes = peak;
+        }
+        if let Some(stat) = read("io.stat") {
+            // One line per device: "8:0 rbytes=1024 wbytes=0 rios=1 ..."
+            let (mut read_bytes, mut write_bytes) = (0, 0);
+            for pair in stat.split_whitespace() {
+                match pair.split_once('=') {
+                    Some(("rbytes", n)) => read_bytes += n.parse::<u64>().unwrap_or(0),
+                    Some(("wbytes", n)) => write_bytes += n.parse::<u64>().unwrap_or(0),
+                    _ => {}
+                }
+            }
+            self.read_bytes = read_bytes;
+            self.write_bytes = write_bytes;
+        }
+    }
+}
+
+impl std::fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use crate::plan::{format_bytes, format_duration};
+        write!(
+            f,
+            "{} CPU, {} peak RSS, {} read, {} written",
+            format_duration(self.cpu_ms()),
+            format_bytes(self.peak_rss_bytes),
+            format_bytes(self.read_bytes),
+            format_bytes(self.write_bytes)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cgroup_counters_replace_rusage() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(
+            dir.path().join("cpu.stat"),
+            "usage_usec 3500000\nuser_usec 3000000\nsystem_usec 500000\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("io.stat"),
+            "8:0 rbytes=4096 wbytes=1024 rios=1 wios=1\n8:16 rbytes=4096 wbytes=0\n",
+        )
+        .unwrap();
+
+        let mut usage = ResourceUsage {
+            cpu_user_ms: 10,
+            peak_rss_bytes: 64 << 20,
+            ..Default::default()
+        };
+        usage.read_cgroup(dir.path());
+        assert_eq!(usage.cpu_ms(), 3500);
+        assert_eq!(usage.read_bytes, 8192);
+        assert_eq!(usage.write_bytes, 1024);
+        // No memory.peak on this kernel
+        assert_eq!(usage.peak_rss_bytes, 64 << 20);
+    }
+}
@@ -0,0 +1,311 @@
+//! Persistent workers that serve RUN steps marked with
+//! `# memobuild:worker=<program>`, one JSON request and response per line.
+
+use crate::graph::{BuildGraph, Node, NodeKind};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Directive naming the worker a RUN step is handed to
+pub const DIRECTIVE: &str = "worker";
+
+/// How long a worker may take to answer a step without a `timeout`
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Argv of the worker a node asked for, if any.
+pub fn spec(node: &Node) -> Option<Vec<String>> {
+    let value = node.metadata.directives.get(DIRECTIVE)?;
+    let argv: Vec<String> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+        .map(str::to_string)
+        .collect();
+    (!argv.is_empty()).then_some(argv)
+}
+
+/// Digest identifying a worker: its argv, and the content of every element
+/// that is a file, with the program looked up in `PATH`.
+pub fn identity(argv: &[String], context_dir: &Path) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    for (i, arg) in argv.iter().enumerate() {
+        hasher.update(arg.as_bytes());
+        hasher.update(b"\0");
+        let file = if i == 0 {
+            Some(
+                find_program(arg, context_dir)
+                    .with_context(|| format!("Worker {} not found", arg))?,
+            )
+        } else {
+            Some(context_dir.join(arg)).filter(|p| p.is_file())
+        };
+        if let Some(file) = file {
+            let digest = crate::hasher::file_hasher::hash_file(&file)?;
+            hasher.update(digest.as_bytes());
+        }
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+fn find_program(program: &str, context_dir: &Path) -> Option<PathBuf> {
+    if program.contains('/') {
+        let path = context_dir.join(program);
+        return path.is_file().then_some(path);
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|p| p.is_file())
+}
+
+/// Record the worker identity of every RUN step with a worker directive, so
+/// it enters the cache key. Must run before composite hashes are computed.
+pub fn apply_workers(graph: &mut BuildGraph, context_dir: &Path) -> Result<usize> {
+    let mut applied = 0;
+    for node in &mut graph.nodes {
+        if !matches!(node.kind, NodeKind::Run) {
+            continue;
+        }
+        let Some(argv) = spec(node) else {
+            continue;
+        };
+        node.metadata.worker = Some(
+            identity(&argv, context_dir)
+                .with_context(|| format!("{}{}", node.location_prefix(), node.name))?,
+        );
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkRequest<'a> {
+    pub id: u64,
+    pub command: &'a str,
+    pub arguments: Vec<&'a str>,
+    pub cwd: &'a Path,
+    pub env: &'a HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WorkResponse {
+    pub id: u64,
+    pub exit_code: i32,
+    #[serde(default)]
+    pub output: String,
+}
+
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    /// Lines of the worker's stdout, read on their own thread so a request
+    /// can stop waiting for them
+    lines: Receiver<std::io::Result<String>>,
+    next_id: u64,
+}
+
+impl Worker {
+    /// Start the worker in `context_dir`, running the program [`identity`]
+    /// hashed.
+    fn start(argv: &[String], context_dir: &Path, env: &HashMap<String, String>) -> Result<Self> {
+        let program = find_program(&argv[0], context_dir)
+            .with_context(|| format!("Worker {} not found", argv[0]))?;
+        let mut child = Command::new(program)
+            .args(&argv[1..])
+            .env_clear()
+            .envs(env)
+            .current_dir(context_dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .with_context(|| format!("Failed to start worker {}", argv.join(" ")))?;
+        let stdin = child.stdin.take().context("Worker has no stdin")?;
+        let stdout = BufReader::new(child.stdout.take().context("Worker has no stdout")?);
+        let (tx, lines) = mpsc::channel();
+        // Ends when the worker is stopped and its stdout closes
+        std::thread::spawn(move || {
+            for line in stdout.lines() {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Self {
+            child,
+            stdin,
+            lines,
+            next_id: 1,
+        })
+    }
+
+    fn work(
+        &mut self,
+        command: &str,
+        cwd: &Path,
+        env: &HashMap<String, String>,
+        limit: Duration,
+    ) -> Result<WorkResponse> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = WorkRequest {
+            id,
+            command,
+            arguments: command.split_whitespace().collect(),
+            cwd,
+            env,
+        };
+        serde_json::to_writer(&mut self.stdin, &request)?;
+        self.stdin.write_all(b"\n")?;
+        self.stdin.flush()?;
+
+        let line = match self.lines.recv_timeout(limit) {
+            Ok(line) => line?,
+            Err(RecvTimeoutError::Timeout) => {
+                anyhow::bail!("Worker did not answer within {:?}", limit)
+            }
+            Err(RecvTimeoutError::Disconnected) => anyhow::bail!("Worker exited before answering"),
+        };
+        let response: WorkResponse = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid worker response: {}", line.trim()))?;
+        if response.id != id {
+            anyhow::bail!(
+                "Worker answered request {} while {} was pending",
+                response.id,
+                id
+            );
+        }
+        Ok(response)
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Idle workers, by argv. Shared by every step of a build; workers are
+/// stopped when the pool is dropped.
+pub struct WorkerPool {
+    /// Directory workers are started in; each request names its own
+    home: PathBuf,
+    idle: Mutex<HashMap<Vec<String>, Vec<Worker>>>,
+}
+
+impl WorkerPool {
+    pub fn new(home: PathBuf) -> Self {
+        Self {
+            home,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run `command` in `cwd` on a worker started from `argv`, reusing an
+    /// idle one, and wait at most `limit` for its answer. A worker is
+    /// started with the environment of the first step it serves; later
+    /// steps pass theirs in the request.
+    pub fn run(
+        &self,
+        argv: &[String],
+        command: &str,
+        cwd: &Path,
+        env: &HashMap<String, String>,
+        limit: Duration,
+    ) -> Result<WorkResponse> {
+        let idle = self
+            .idle
+            .lock()
+            .unwrap()
+            .get_mut(argv)
+            .and_then(|workers| workers.pop());
+        let mut worker = match idle {
+            Some(worker) => worker,
+            None => Worker::start(argv, &self.home, env)?,
+        };
+        // A worker that failed mid-request is dropped, which stops it
+        let response = worker.work(command, cwd, env, limit)?;
+        self.idle
+            .lock()
+            .unwrap()
+            .entry(argv.to_vec())
+            .or_default()
+            .push(worker);
+        Ok(response)
+    }
+
+    /// Workers currently idle, across all programs.
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().values().map(Vec::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_worker_serves_several_requests() {
+        let dir = tempfile::TempDir::new().unwrap();
+        // Answers every request with its id and how many it has served
+        let script = dir.path().join("worker.sh");
+        std::fs::write(
+            &script,
+            "n=0\nwhile read -r line; do\n  n=$((n+1))\n  id=$(echo \"$line\" | sed 's/.*\"id\":\\([0-9]*\\).*/\\1/')\n  echo \"{\\\"id\\\":$id,\\\"exit_code\\\":0,\\\"output\\\":\\\"served $n\\\"}\"\ndone\n",
+        )
+        .unwrap();
+        let argv = vec!["sh".to_string(), script.to_string_lossy().to_string()];
+        let env: HashMap<String, String> = [(
+            "PATH".to_string(),
+            std::env::var("PATH").unwrap_or_default(),
+        )]
+        .into();
+
+        let pool = WorkerPool::new(dir.path().to_path_buf());
+        let limit = Duration::from_secs(10);
+        let first = pool
+            .run(&argv, "tsc -p a", dir.path(), &env, limit)
+            .unwrap();
+        let second = pool
+            .run(&argv, "tsc -p b", dir.path(), &env, limit)
+            .unwrap();
+        assert_eq!((first.exit_code, first.output.as_str()), (0, "served 1"));
+        assert_eq!(second.output, "served 2");
+        assert_eq!(pool.idle_count(), 1);
+
+        let id = identity(&argv, dir.path()).unwrap();
+        std::fs::write(&script, "exit 1\n").unwrap();
+        assert_ne!(identity(&argv, dir.path()).unwrap(), id);
+        assert!(identity(&["no-such-worker".to_string()], dir.path()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_worker_that_never_answers_is_stopped() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let script = dir.path().join("silent.sh");
+        std::fs::write(&script, "#!/bin/sh\nwhile read -r line; do :; done\n").unwrap();
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        // Resolved against the context like its identity, not the caller's cwd
+        let argv = vec!["./silent.sh".to_string()];
+        let env = HashMap::new();
+
+        let pool = WorkerPool::new(dir.path().to_path_buf());
+        let started = std::time::Instant::now();
+        let err = pool
+            .run(&argv, "tsc", dir.path(), &env, Duration::from_millis(200))
+            .unwrap_err();
+        assert!(err.to_string().contains("did not answer"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(pool.idle_count(), 0);
+    }
+}
@@ -0,0 +1,141 @@
+//! Prefetch hints: which artifacts build sessions fetched together, counted in
+//! memory.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Earlier fetches of a session a new fetch is paired with
+const SESSION_WINDOW: usize = 64;
+/// Sessions remembered at once; the least recently active is forgotten first
+const MAX_SESSIONS: usize = 1024;
+/// Siblings kept per artifact; the least frequent are dropped first
+const MAX_SIBLINGS: usize = 128;
+/// Artifacts with counts before pairs seen only once are dropped
+const MAX_TRACKED: usize = 100_000;
+/// Sessions that must have fetched a pair before it is hinted
+const MIN_COOCCURRENCE: u32 = 2;
+
+#[derive(Default)]
+pub struct PrefetchHints {
+    inner: Mutex<HintState>,
+}
+
+#[derive(Default)]
+struct HintState {
+    sessions: HashMap<String, Session>,
+    pairs: HashMap<String, HashMap<String, u32>>,
+}
+
+struct Session {
+    recent: VecDeque<String>,
+    last_seen: Instant,
+}
+
+impl PrefetchHints {
+    /// Record that `session` fetched `hash`.
+    pub fn record(&self, session: &str, hash: &str) {
+        let mut state = self.inner.lock().unwrap();
+        if !state.sessions.contains_key(session) && state.sessions.len() >= MAX_SESSIONS {
+            if let Some(oldest) = state
+                .sessions
+                .iter()
+                .min_by_key(|(_, s)| s.last_seen)
+                .map(|(id, _)| id.clone())
+            {
+                state.sessions.remove(&oldest);
+            }
+        }
+        let session = state
+            .sessions
+            .entry(session.to_string())
+            .or_insert_with(|| Session {
+                recent: VecDeque::new(),
+                last_seen: Instant::now(),
+            });
+        session.last_seen = Instant::now();
+        if session.recent.iter().any(|h| h == hash) {
+            return;
+        }
+        let earlier: Vec<String> = session.recent.iter().cloned().collect();
+        session.recent.push_back(hash.to_string());
+        if session.recent.len() > SESSION_WINDOW {
+            session.recent.pop_front();
+        }
+
+        for other in earlier {
+            state.bump(&other, hash);
+            state.bump(hash, &other);
+        }
+        if state.pairs.len() > MAX_TRACKED {
+            state.prune();
+        }
+    }
+
+    /// Up to `limit` artifacts fetched together with `hash` by at least
+    /// [`MIN_COOCCURRENCE`] sessions, most frequent first.
+    pub fn siblings(&self, hash: &str, limit: usize) -> Vec<String> {
+        let state = self.inner.lock().unwrap();
+        let Some(siblings) = state.pairs.get(hash) else {
+            return Vec::new();
+        };
+        let mut ranked: Vec<(&String, u32)> = siblings
+            .iter()
+            .filter(|&(_, &count)| count >= MIN_COOCCURRENCE)
+            .map(|(h, &count)| (h, count))
+            .collect();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        ranked
+            .into_iter()
+            .take(limit)
+            .map(|(h, _)| h.clone())
+            .collect()
+    }
+}
+
+impl HintState {
+    fn bump(&mut self, from: &str, to: &str) {
+        let siblings = self.pairs.entry(from.to_string()).or_default();
+        *siblings.entry(to.to_string()).or_insert(0) += 1;
+        if siblings.len() > MAX_SIBLINGS {
+            if let Some(rarest) = siblings
+                .iter()
+                .filter(|(h, _)| h.as_str() != to)
+                .min_by_key(|(_, &count)| count)
+                .map(|(h, _)| h.clone())
+            {
+                siblings.remove(&rarest);
+            }
+        }
+    }
+
+    /// Forget pairs no second session confirmed.
+    fn prune(&mut self) {
+        self.pairs.retain(|_, siblings| {
+            siblings.retain(|_, count| *count >= MIN_COOCCURRENCE);
+            !siblings.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_siblings_need_two_sessions() {
+        let hints = PrefetchHints::default();
+        for hash in ["a", "b", "c"] {
+            hints.record("build-1", hash);
+        }
+        assert!(hints.siblings("a", 10).is_empty());
+
+        hints.record("build-2", "a");
+        hints.record("build-2", "b");
+        // Fetching again in the same session counts once
+        hints.record("build-2", "a");
+        assert_eq!(hints.siblings("a", 10), vec!["b".to_string()]);
+        assert_eq!(hints.siblings("b", 10), vec!["a".to_string()]);
+        assert!(hints.siblings("c", 10).is_empty());
+    }
+}
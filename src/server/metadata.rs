@@ -1,6 +1,11 @@
+use crate::cache::clock;
+use crate::cache::origin::EntryOrigin;
+use crate::cache::pin::PinTarget;
+use crate::server::transparency::{self, LogEntry, LogHead};
 use anyhow::Result;
 use async_trait::async_trait;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::sync::Mutex;
 
@@ -31,14 +36,179 @@ pub struct CacheEntry {
     pub hit_count: u32,
 }
 
+/// Storage for the cache server's metadata: entries, layers, usage rollups
+/// and the transparency log. [`MetadataStore`] keeps it in SQLite, which is
+/// enough for one team; [`PostgresMetadataBackend`](crate::server::postgres::PostgresMetadataBackend)
+/// serves a busy org-wide server. Pick one with [`open_metadata`].
+pub trait MetadataBackend: Send + Sync {
+    fn insert(&self, hash: &str, path: &str, size: u64) -> Result<()>;
+
+    fn insert_layered_node(&self, hash: &str, size: u64, layer_hashes: &[String]) -> Result<()>;
+
+    fn insert_layer(&self, hash: &str, path: &str, size: u64) -> Result<()>;
+
+    fn get_node_layers(&self, hash: &str) -> Result<Option<Vec<String>>>;
+
+    fn layer_exists(&self, hash: &str) -> Result<bool>;
+
+    fn get_layer_path(&self, hash: &str) -> Result<Option<String>>;
+
+    /// Stored size of the layer `hash`, or `None` if there is no such layer.
+    fn layer_size(&self, hash: &str) -> Result<Option<u64>>;
+
+    fn get(&self, hash: &str) -> Result<Option<CacheEntry>>;
+
+    fn touch(&self, hash: &str) -> Result<()>;
+
+    fn exists(&self, hash: &str) -> Result<bool>;
+
+    /// Record the key version of the client that stored `hash`.
+    fn set_key_version(&self, hash: &str, version: u32) -> Result<()>;
+
+    /// Key version `hash` was stored with, or `None` if there is no entry.
+    fn key_version(&self, hash: &str) -> Result<Option<u32>>;
+
+    /// Number of entries per key version.
+    fn key_version_counts(&self) -> Result<BTreeMap<u32, u64>>;
+
+    /// Record the `os/arch` of the client that stored `hash`.
+    fn set_platform(&self, hash: &str, platform: &str) -> Result<()>;
+
+    /// Platform `hash` was stored from, or `None` if there is no entry or it
+    /// predates platform tagging.
+    fn platform(&self, hash: &str) -> Result<Option<String>>;
+
+    /// Number of entries per platform; untagged entries count as `unknown`.
+    fn platform_counts(&self) -> Result<BTreeMap<String, u64>>;
+
+    /// Record the namespace of the client that stored `hash`.
+    fn set_namespace(&self, hash: &str, namespace: &str) -> Result<()>;
+
+    /// Record the build that stored `hash`.
+    fn set_build_id(&self, hash: &str, build_id: &str) -> Result<()>;
+
+    /// Record where the client that stored `hash` ran.
+    fn set_origin(&self, hash: &str, origin: &EntryOrigin) -> Result<()>;
+
+    /// Pin the entries `target` names under `label`, or unpin them when
+    /// `label` is `None`. Returns how many entries matched.
+    fn set_pin(&self, target: &PinTarget, label: Option<&str>) -> Result<u64>;
+
+    /// A page of the entries matching `filter`, most recently used first,
+    /// with the number of matching entries.
+    fn list_entries(
+        &self,
+        filter: &EntryFilter,
+        offset: u64,
+        limit: u64,
+    ) -> Result<(Vec<EntrySummary>, u64)>;
+
+    /// Everything known about the entry `hash`: its metadata, where its blob
+    /// is stored and the layers it is made of, with how many entries share
+    /// each.
+    fn entry_details(&self, hash: &str) -> Result<Option<EntryDetails>>;
+
+    /// Hashes of entries stored from `platform`.
+    fn entries_for_platform(&self, platform: &str) -> Result<Vec<String>>;
+
+    /// Hashes of unpinned entries stored with any key version other than
+    /// `version`.
+    fn entries_not_at_key_version(&self, version: u32) -> Result<Vec<String>>;
+
+    fn delete(&self, hash: &str) -> Result<()>;
+
+    /// Make sure everything committed is durable; SQLite checkpoints its WAL
+    /// into the database file.
+    fn flush(&self) -> Result<()>;
+
+    fn get_unused_layers(&self) -> Result<Vec<(String, String)>>;
+
+    fn delete_layer_metadata(&self, hash: &str) -> Result<()>;
+
+    fn get_layer_stats(&self) -> Result<LayerStats>;
+
+    /// Entry counts and stored bytes for `/healthz`. Entries unused for
+    /// `gc_days` and unreferenced layers are what the next GC would delete.
+    fn storage_stats(&self, gc_days: u32) -> Result<StorageStats>;
+
+    /// Count a cache hit in `namespace` that served `bytes` of `hash`.
+    fn record_hit(&self, namespace: &str, hash: &str, bytes: u64) -> Result<()>;
+
+    /// Count a lookup in `namespace` the cache could not serve.
+    fn record_miss(&self, namespace: &str) -> Result<()>;
+
+    /// Count `bytes` uploaded to the cache from `namespace`.
+    fn record_upload(&self, namespace: &str, bytes: u64) -> Result<()>;
+
+    /// Usage over the last `days` days (today included), optionally for one
+    /// namespace, with the `top` artifacts that saved the most bytes.
+    fn usage_stats(&self, days: u32, namespace: Option<&str>, top: usize) -> Result<UsageStats>;
+
+    /// Append the first insertion of `hash` to the transparency log. Uploads
+    /// of a blob that is already logged add nothing and return `None`.
+    fn append_log(
+        &self,
+        kind: &str,
+        hash: &str,
+        size: u64,
+        namespace: &str,
+    ) -> Result<Option<LogEntry>>;
+
+    /// Head of the transparency log as it stood with `size` entries, or the
+    /// current head when `size` is `None`. `None` if the log is shorter.
+    fn log_head(&self, size: Option<u64>) -> Result<Option<LogHead>>;
+
+    /// Up to `limit` log entries from position `start` on.
+    fn log_entries(&self, start: u64, limit: u64) -> Result<Vec<LogEntry>>;
+
+    /// Hashes of every blob in storage: plain artifacts and layers, sorted.
+    /// Layered nodes have no blob of their own.
+    fn storage_objects(&self) -> Result<Vec<String>>;
+
+    /// Record that the blob `hash` now lives at `path`.
+    fn set_storage_path(&self, hash: &str, path: &str) -> Result<()>;
+
+    /// Unpinned entries unused for `days`, which GC deletes.
+    fn get_old_entries(&self, days: u32) -> Result<Vec<String>>;
+
+    fn record_build(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()>;
+
+    fn get_analytics(&self, limit: u32) -> Result<Vec<BuildRecord>>;
+}
+
+/// Open the metadata backend `url` names: `postgres://…` for PostgreSQL,
+/// `memory` for a SQLite database in memory, or the path of a SQLite file
+/// (`sqlite://` optional). Without a URL, `metadata.db` in `data_dir`.
+pub async fn open_metadata(url: Option<&str>, data_dir: &Path) -> Result<Box<dyn MetadataBackend>> {
+    match url.map(str::trim).filter(|url| !url.is_empty()) {
+        None => Ok(Box::new(MetadataStore::new(&data_dir.join("metadata.db"))?)),
+        Some(url) if url.starts_with("postgres://") || url.starts_with("postgresql://") => Ok(
+            Box::new(crate::server::postgres::PostgresMetadataBackend::connect(url).await?),
+        ),
+        Some("memory" | "sqlite::memory:") => Ok(Box::new(MetadataStore::in_memory()?)),
+        Some(url) => {
+            let path = Path::new(url.strip_prefix("sqlite://").unwrap_or(url));
+            Ok(Box::new(MetadataStore::new(path)?))
+        }
+    }
+}
+
+/// [`MetadataBackend`] in a SQLite database.
 pub struct MetadataStore {
     conn: Mutex<Connection>,
 }
 
 impl MetadataStore {
     pub fn new(db_path: &Path) -> Result<Self> {
-        let conn = Connection::open(db_path)?;
+        Self::with_connection(Connection::open(db_path)?)
+    }
 
+    /// A store that lives only as long as it does, for tests.
+    pub fn in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self> {
         conn.execute(
             "CREATE TABLE IF NOT EXISTS cache_entries (
                 hash TEXT PRIMARY KEY,
@@ -52,6 +222,57 @@ impl MetadataStore {
             [],
         )?;
 
+        // Databases created before key versioning lack the column; their
+        // entries count as version 0
+        let has_key_version = conn
+            .prepare("SELECT 1 FROM pragma_table_info('cache_entries') WHERE name = 'key_version'")?
+            .exists([])?;
+        if !has_key_version {
+            conn.execute(
+                "ALTER TABLE cache_entries ADD COLUMN key_version INT NOT NULL DEFAULT 0",
+                [],
+            )?;
+        }
+
+        // The platform of the client that stored an entry; unknown for older ones
+        let has_platform = conn
+            .prepare("SELECT 1 FROM pragma_table_info('cache_entries') WHERE name = 'platform'")?
+            .exists([])?;
+        if !has_platform {
+            conn.execute("ALTER TABLE cache_entries ADD COLUMN platform TEXT", [])?;
+        }
+
+        // The namespace of the client that stored an entry; unknown for older ones
+        let has_namespace = conn
+            .prepare("SELECT 1 FROM pragma_table_info('cache_entries') WHERE name = 'namespace'")?
+            .exists([])?;
+        if !has_namespace {
+            conn.execute("ALTER TABLE cache_entries ADD COLUMN namespace TEXT", [])?;
+        }
+
+        // The build that stored an entry, the label of the pin keeping it
+        // from GC, and where the client that stored it ran; none of them are
+        // set for older entries
+        for column in [
+            "build_id",
+            "pin_label",
+            "builder",
+            "ci_job",
+            "git_commit",
+            "client_version",
+            "built_at",
+        ] {
+            let exists = conn
+                .prepare("SELECT 1 FROM pragma_table_info('cache_entries') WHERE name = ?1")?
+                .exists(params![column])?;
+            if !exists {
+                conn.execute(
+                    &format!("ALTER TABLE cache_entries ADD COLUMN {} TEXT", column),
+                    [],
+                )?;
+            }
+        }
+
         conn.execute(
             "CREATE TABLE IF NOT EXISTS cache_layers (
                 layer_hash TEXT PRIMARY KEY,
@@ -76,14 +297,57 @@ impl MetadataStore {
             [],
         )?;
 
+        // Daily usage rollups per namespace, for `/stats`
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS usage_daily (
+                day TEXT,
+                namespace TEXT,
+                hits INT NOT NULL DEFAULT 0,
+                misses INT NOT NULL DEFAULT 0,
+                bytes_saved BIGINT NOT NULL DEFAULT 0,
+                bytes_uploaded BIGINT NOT NULL DEFAULT 0,
+                PRIMARY KEY(day, namespace)
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS artifact_usage_daily (
+                day TEXT,
+                namespace TEXT,
+                hash TEXT,
+                hits INT NOT NULL DEFAULT 0,
+                bytes_saved BIGINT NOT NULL DEFAULT 0,
+                PRIMARY KEY(day, namespace, hash)
+            )",
+            [],
+        )?;
+
+        // Append-only: rows are never updated or deleted, GC included
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS transparency_log (
+                seq INTEGER PRIMARY KEY,
+                kind TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                size BIGINT NOT NULL,
+                namespace TEXT NOT NULL,
+                logged_at TEXT NOT NULL,
+                chain TEXT NOT NULL,
+                UNIQUE(kind, hash)
+            )",
+            [],
+        )?;
+
         Ok(Self {
             conn: Mutex::new(conn),
         })
     }
+}
 
-    pub fn insert(&self, hash: &str, path: &str, size: u64) -> Result<()> {
+impl MetadataBackend for MetadataStore {
+    fn insert(&self, hash: &str, path: &str, size: u64) -> Result<()> {
         let conn = self.conn.lock().unwrap();
-        let now = chrono::Utc::now().to_rfc3339();
+        let now = clock::stamp_rfc3339();
         conn.execute(
             "INSERT INTO cache_entries (hash, artifact_path, size, created_at, last_used, hit_count, is_layered)
              VALUES (?1, ?2, ?3, ?4, ?4, 0, FALSE)
@@ -95,15 +359,10 @@ impl MetadataStore {
         Ok(())
     }
 
-    pub fn insert_layered_node(
-        &self,
-        hash: &str,
-        size: u64,
-        layer_hashes: &[String],
-    ) -> Result<()> {
+    fn insert_layered_node(&self, hash: &str, size: u64, layer_hashes: &[String]) -> Result<()> {
         let mut conn = self.conn.lock().unwrap();
         let tx = conn.transaction()?;
-        let now = chrono::Utc::now().to_rfc3339();
+        let now = clock::stamp_rfc3339();
 
         tx.execute(
             "INSERT INTO cache_entries (hash, artifact_path, size, created_at, last_used, hit_count, is_layered)
@@ -137,9 +396,9 @@ impl MetadataStore {
         Ok(())
     }
 
-    pub fn insert_layer(&self, hash: &str, path: &str, size: u64) -> Result<()> {
+    fn insert_layer(&self, hash: &str, path: &str, size: u64) -> Result<()> {
         let conn = self.conn.lock().unwrap();
-        let now = chrono::Utc::now().to_rfc3339();
+        let now = clock::stamp_rfc3339();
         conn.execute(
             "INSERT INTO cache_layers (layer_hash, storage_path, size, created_at, last_used)
              VALUES (?1, ?2, ?3, ?4, ?4)
@@ -150,7 +409,7 @@ impl MetadataStore {
         Ok(())
     }
 
-    pub fn get_node_layers(&self, hash: &str) -> Result<Option<Vec<String>>> {
+    fn get_node_layers(&self, hash: &str) -> Result<Option<Vec<String>>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
             "SELECT layer_hash FROM node_to_layers WHERE node_hash = ?1 ORDER BY position",
@@ -177,7 +436,7 @@ impl MetadataStore {
         Ok(Some(layers))
     }
 
-    pub fn layer_exists(&self, hash: &str) -> Result<bool> {
+    fn layer_exists(&self, hash: &str) -> Result<bool> {
         let conn = self.conn.lock().unwrap();
         let count: i64 = conn.query_row(
             "SELECT COUNT(*) FROM cache_layers WHERE layer_hash = ?1",
@@ -187,7 +446,7 @@ impl MetadataStore {
         Ok(count > 0)
     }
 
-    pub fn get_layer_path(&self, hash: &str) -> Result<Option<String>> {
+    fn get_layer_path(&self, hash: &str) -> Result<Option<String>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt =
             conn.prepare("SELECT storage_path FROM cache_layers WHERE layer_hash = ?1")?;
@@ -200,7 +459,19 @@ impl MetadataStore {
         }
     }
 
-    pub fn get(&self, hash: &str) -> Result<Option<CacheEntry>> {
+    fn layer_size(&self, hash: &str) -> Result<Option<u64>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn
+            .query_row(
+                "SELECT size FROM cache_layers WHERE layer_hash = ?1",
+                params![hash],
+                |row| row.get::<_, Option<i64>>(0),
+            )
+            .optional()?
+            .map(|size| size.unwrap_or(0).max(0) as u64))
+    }
+
+    fn get(&self, hash: &str) -> Result<Option<CacheEntry>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
             "SELECT hash, artifact_path, size, created_at, last_used, hit_count FROM cache_entries WHERE hash = ?1",
@@ -221,9 +492,9 @@ impl MetadataStore {
         }
     }
 
-    pub fn touch(&self, hash: &str) -> Result<()> {
+    fn touch(&self, hash: &str) -> Result<()> {
         let conn = self.conn.lock().unwrap();
-        let now = chrono::Utc::now().to_rfc3339();
+        let now = clock::stamp_rfc3339();
         conn.execute(
             "UPDATE cache_entries SET last_used = ?1, hit_count = hit_count + 1 WHERE hash = ?2",
             params![now, hash],
@@ -231,7 +502,7 @@ impl MetadataStore {
         Ok(())
     }
 
-    pub fn exists(&self, hash: &str) -> Result<bool> {
+    fn exists(&self, hash: &str) -> Result<bool> {
         let conn = self.conn.lock().unwrap();
         let count: i64 = conn.query_row(
             "SELECT COUNT(*) FROM cache_entries WHERE hash = ?1",
@@ -241,7 +512,247 @@ impl MetadataStore {
         Ok(count > 0)
     }
 
-    pub fn delete(&self, hash: &str) -> Result<()> {
+    fn set_key_version(&self, hash: &str, version: u32) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE cache_entries SET key_version = ?1 WHERE hash = ?2",
+            params![version, hash],
+        )?;
+        Ok(())
+    }
+
+    fn key_version(&self, hash: &str) -> Result<Option<u32>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT key_version FROM cache_entries WHERE hash = ?1")?;
+        let mut rows = stmt.query(params![hash])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn key_version_counts(&self) -> Result<BTreeMap<u32, u64>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT key_version, COUNT(*) FROM cache_entries GROUP BY key_version")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut counts = BTreeMap::new();
+        for row in rows {
+            let (version, count): (u32, i64) = row?;
+            counts.insert(version, count as u64);
+        }
+        Ok(counts)
+    }
+
+    fn set_platform(&self, hash: &str, platform: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE cache_entries SET platform = ?1 WHERE hash = ?2",
+            params![platform, hash],
+        )?;
+        Ok(())
+    }
+
+    fn platform(&self, hash: &str) -> Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT platform FROM cache_entries WHERE hash = ?1")?;
+        let mut rows = stmt.query(params![hash])?;
+        match rows.next()? {
+            Some(row) => Ok(row.get(0)?),
+            None => Ok(None),
+        }
+    }
+
+    fn platform_counts(&self) -> Result<BTreeMap<String, u64>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT COALESCE(platform, 'unknown'), COUNT(*) FROM cache_entries GROUP BY 1",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut counts = BTreeMap::new();
+        for row in rows {
+            let (platform, count): (String, i64) = row?;
+            counts.insert(platform, count as u64);
+        }
+        Ok(counts)
+    }
+
+    fn set_namespace(&self, hash: &str, namespace: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE cache_entries SET namespace = ?1 WHERE hash = ?2",
+            params![namespace, hash],
+        )?;
+        Ok(())
+    }
+
+    fn set_build_id(&self, hash: &str, build_id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE cache_entries SET build_id = ?1 WHERE hash = ?2",
+            params![build_id, hash],
+        )?;
+        Ok(())
+    }
+
+    fn set_origin(&self, hash: &str, origin: &EntryOrigin) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE cache_entries SET builder = ?1, ci_job = ?2, git_commit = ?3,
+             client_version = ?4, built_at = ?5 WHERE hash = ?6",
+            params![
+                origin.builder,
+                origin.ci_job,
+                origin.git_commit,
+                origin.version,
+                origin.built_at,
+                hash
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn set_pin(&self, target: &PinTarget, label: Option<&str>) -> Result<u64> {
+        let conn = self.conn.lock().unwrap();
+        let changed = match target {
+            PinTarget::Key(hash) => conn.execute(
+                "UPDATE cache_entries SET pin_label = ?1 WHERE hash = ?2",
+                params![label, hash],
+            )?,
+            PinTarget::Build(id) => conn.execute(
+                "UPDATE cache_entries SET pin_label = ?1 WHERE build_id = ?2",
+                params![label, id],
+            )?,
+        };
+        Ok(changed as u64)
+    }
+
+    fn list_entries(
+        &self,
+        filter: &EntryFilter,
+        offset: u64,
+        limit: u64,
+    ) -> Result<(Vec<EntrySummary>, u64)> {
+        let conn = self.conn.lock().unwrap();
+        // Unset filters compare against NULL and match everything
+        let condition =
+            "(?1 IS NULL OR julianday(created_at) <= julianday('now', '-' || ?1 || ' days'))
+             AND (?2 IS NULL OR julianday(last_used) <= julianday('now', '-' || ?2 || ' days'))
+             AND (?3 IS NULL OR size >= ?3)
+             AND (?4 IS NULL OR size <= ?4)
+             AND (?5 IS NULL OR COALESCE(namespace, 'unknown') = ?5)
+             AND (?6 IS NULL OR (pin_label IS NOT NULL) = ?6)
+             AND (?7 IS NULL OR git_commit LIKE ?7 || '%')
+             AND (?8 IS NULL OR builder = ?8)";
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM cache_entries WHERE {}", condition),
+            params![
+                filter.older_than_days,
+                filter.unused_for_days,
+                filter.min_size,
+                filter.max_size,
+                filter.namespace,
+                filter.pinned,
+                filter.git_commit,
+                filter.builder,
+            ],
+            |row| row.get(0),
+        )?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM cache_entries WHERE {} ORDER BY last_used DESC, hash LIMIT ?9 OFFSET ?10",
+            SUMMARY_COLUMNS, condition
+        ))?;
+        let rows = stmt.query_map(
+            params![
+                filter.older_than_days,
+                filter.unused_for_days,
+                filter.min_size,
+                filter.max_size,
+                filter.namespace,
+                filter.pinned,
+                filter.git_commit,
+                filter.builder,
+                limit,
+                offset,
+            ],
+            summary_from_row,
+        )?;
+        let mut entries = Vec::new();
+        for entry in rows {
+            entries.push(entry?);
+        }
+        Ok((entries, total as u64))
+    }
+
+    fn entry_details(&self, hash: &str) -> Result<Option<EntryDetails>> {
+        let conn = self.conn.lock().unwrap();
+        let found = conn
+            .query_row(
+                &format!(
+                    "SELECT {}, artifact_path FROM cache_entries WHERE hash = ?1",
+                    SUMMARY_COLUMNS
+                ),
+                params![hash],
+                |row| Ok((summary_from_row(row)?, row.get::<_, Option<String>>(16)?)),
+            )
+            .optional()?;
+        let Some((summary, storage_path)) = found else {
+            return Ok(None);
+        };
+
+        let mut stmt = conn.prepare(
+            "SELECT n.layer_hash, l.size, l.ref_count FROM node_to_layers n
+             LEFT JOIN cache_layers l ON l.layer_hash = n.layer_hash
+             WHERE n.node_hash = ?1 ORDER BY n.position",
+        )?;
+        let rows = stmt.query_map(params![hash], |row| {
+            Ok(LayerReference {
+                hash: row.get(0)?,
+                size: row.get::<_, Option<u64>>(1)?.unwrap_or(0),
+                ref_count: row.get::<_, Option<i64>>(2)?.unwrap_or(0).max(0) as u64,
+            })
+        })?;
+        let mut layers = Vec::new();
+        for layer in rows {
+            layers.push(layer?);
+        }
+        Ok(Some(EntryDetails {
+            storage_path: storage_path.filter(|_| !summary.layered),
+            summary,
+            layers,
+        }))
+    }
+
+    fn entries_for_platform(&self, platform: &str) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT hash FROM cache_entries WHERE platform = ?1")?;
+        let rows = stmt.query_map(params![platform], |row| row.get(0))?;
+
+        let mut hashes = Vec::new();
+        for hash in rows {
+            hashes.push(hash?);
+        }
+        Ok(hashes)
+    }
+
+    fn entries_not_at_key_version(&self, version: u32) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT hash FROM cache_entries WHERE key_version != ?1 AND pin_label IS NULL",
+        )?;
+        let rows = stmt.query_map(params![version], |row| row.get(0))?;
+
+        let mut hashes = Vec::new();
+        for hash in rows {
+            hashes.push(hash?);
+        }
+        Ok(hashes)
+    }
+
+    fn delete(&self, hash: &str) -> Result<()> {
         let mut conn = self.conn.lock().unwrap();
         let tx = conn.transaction()?;
 
@@ -265,7 +776,13 @@ impl MetadataStore {
         Ok(())
     }
 
-    pub fn get_unused_layers(&self) -> Result<Vec<(String, String)>> {
+    fn flush(&self) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        Ok(())
+    }
+
+    fn get_unused_layers(&self) -> Result<Vec<(String, String)>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt =
             conn.prepare("SELECT layer_hash, storage_path FROM cache_layers WHERE ref_count <= 0")?;
@@ -278,7 +795,7 @@ impl MetadataStore {
         Ok(layers)
     }
 
-    pub fn delete_layer_metadata(&self, hash: &str) -> Result<()> {
+    fn delete_layer_metadata(&self, hash: &str) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         conn.execute(
             "DELETE FROM cache_layers WHERE layer_hash = ?1",
@@ -287,7 +804,7 @@ impl MetadataStore {
         Ok(())
     }
 
-    pub fn get_layer_stats(&self) -> Result<LayerStats> {
+    fn get_layer_stats(&self) -> Result<LayerStats> {
         let conn = self.conn.lock().unwrap();
         let total_layers: i64 =
             conn.query_row("SELECT COUNT(*) FROM cache_layers", [], |row| row.get(0))?;
@@ -309,12 +826,295 @@ impl MetadataStore {
         })
     }
 
-    pub fn get_old_entries(&self, days: u32) -> Result<Vec<String>> {
+    fn storage_stats(&self, gc_days: u32) -> Result<StorageStats> {
         let conn = self.conn.lock().unwrap();
+        let (entries, entry_bytes): (i64, i64) = conn.query_row(
+            // Layered entries' bytes are counted through their layers
+            "SELECT COUNT(*), COALESCE(SUM(CASE WHEN is_layered THEN 0 ELSE size END), 0)
+             FROM cache_entries",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let layer_bytes: i64 = conn.query_row(
+            "SELECT COALESCE(SUM(size), 0) FROM cache_layers",
+            [],
+            |row| row.get(0),
+        )?;
+        let gc_backlog_entries: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM cache_entries
+             WHERE julianday(last_used) < julianday('now', '-' || ?1 || ' days', '-' || ?2 || ' seconds')
+             AND pin_label IS NULL",
+            params![gc_days, clock::skew_tolerance().as_secs()],
+            |row| row.get(0),
+        )?;
+        let gc_backlog_layers: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM cache_layers WHERE ref_count <= 0",
+            [],
+            |row| row.get(0),
+        )?;
+
+        Ok(StorageStats {
+            entries: entries as u64,
+            used_bytes: (entry_bytes + layer_bytes) as u64,
+            gc_backlog_entries: gc_backlog_entries as u64,
+            gc_backlog_layers: gc_backlog_layers as u64,
+        })
+    }
+
+    fn record_hit(&self, namespace: &str, hash: &str, bytes: u64) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let day = today();
+        tx.execute(
+            "INSERT INTO usage_daily (day, namespace, hits, bytes_saved) VALUES (?1, ?2, 1, ?3)
+             ON CONFLICT(day, namespace) DO UPDATE SET
+                hits = hits + 1,
+                bytes_saved = bytes_saved + ?3",
+            params![day, namespace, bytes],
+        )?;
+        tx.execute(
+            "INSERT INTO artifact_usage_daily (day, namespace, hash, hits, bytes_saved)
+             VALUES (?1, ?2, ?3, 1, ?4)
+             ON CONFLICT(day, namespace, hash) DO UPDATE SET
+                hits = hits + 1,
+                bytes_saved = bytes_saved + ?4",
+            params![day, namespace, hash, bytes],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn record_miss(&self, namespace: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO usage_daily (day, namespace, misses) VALUES (?1, ?2, 1)
+             ON CONFLICT(day, namespace) DO UPDATE SET misses = misses + 1",
+            params![today(), namespace],
+        )?;
+        Ok(())
+    }
+
+    fn record_upload(&self, namespace: &str, bytes: u64) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO usage_daily (day, namespace, bytes_uploaded) VALUES (?1, ?2, ?3)
+             ON CONFLICT(day, namespace) DO UPDATE SET bytes_uploaded = bytes_uploaded + ?3",
+            params![today(), namespace, bytes],
+        )?;
+        Ok(())
+    }
+
+    fn usage_stats(&self, days: u32, namespace: Option<&str>, top: usize) -> Result<UsageStats> {
+        let conn = self.conn.lock().unwrap();
+        let since = (chrono::Utc::now() - chrono::Duration::days(days.saturating_sub(1) as i64))
+            .format("%Y-%m-%d")
+            .to_string();
+
+        let mut stmt = conn.prepare(
+            "SELECT day, namespace, hits, misses, bytes_saved, bytes_uploaded FROM usage_daily
+             WHERE day >= ?1 AND (?2 IS NULL OR namespace = ?2)
+             ORDER BY day, namespace",
+        )?;
+        let rows = stmt.query_map(params![since, namespace], |row| {
+            Ok(DailyUsage {
+                day: row.get(0)?,
+                namespace: row.get(1)?,
+                hits: row.get::<_, i64>(2)? as u64,
+                misses: row.get::<_, i64>(3)? as u64,
+                bytes_saved: row.get::<_, i64>(4)? as u64,
+                bytes_uploaded: row.get::<_, i64>(5)? as u64,
+            })
+        })?;
+        let mut daily = Vec::new();
+        for row in rows {
+            daily.push(row?);
+        }
+
         let mut stmt = conn.prepare(
-            "SELECT hash FROM cache_entries WHERE last_used < datetime('now', '-' || ?1 || ' days')"
+            "SELECT namespace, hash, SUM(hits), SUM(bytes_saved) FROM artifact_usage_daily
+             WHERE day >= ?1 AND (?2 IS NULL OR namespace = ?2)
+             GROUP BY namespace, hash
+             ORDER BY SUM(bytes_saved) DESC, SUM(hits) DESC
+             LIMIT ?3",
         )?;
-        let rows = stmt.query_map(params![days], |row| row.get(0))?;
+        let rows = stmt.query_map(params![since, namespace, top as i64], |row| {
+            Ok(ArtifactUsage {
+                namespace: row.get(0)?,
+                hash: row.get(1)?,
+                hits: row.get::<_, i64>(2)? as u64,
+                bytes_saved: row.get::<_, i64>(3)? as u64,
+            })
+        })?;
+        let mut top_artifacts = Vec::new();
+        for row in rows {
+            top_artifacts.push(row?);
+        }
+
+        let hits: u64 = daily.iter().map(|d| d.hits).sum();
+        let misses: u64 = daily.iter().map(|d| d.misses).sum();
+        let mut namespaces: Vec<String> = daily.iter().map(|d| d.namespace.clone()).collect();
+        namespaces.sort();
+        namespaces.dedup();
+        Ok(UsageStats {
+            since,
+            namespaces,
+            hits,
+            misses,
+            hit_rate: if hits + misses > 0 {
+                hits as f64 / (hits + misses) as f64
+            } else {
+                0.0
+            },
+            bytes_saved: daily.iter().map(|d| d.bytes_saved).sum(),
+            bytes_uploaded: daily.iter().map(|d| d.bytes_uploaded).sum(),
+            daily,
+            top_artifacts,
+        })
+    }
+
+    fn append_log(
+        &self,
+        kind: &str,
+        hash: &str,
+        size: u64,
+        namespace: &str,
+    ) -> Result<Option<LogEntry>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let logged = tx
+            .prepare("SELECT 1 FROM transparency_log WHERE kind = ?1 AND hash = ?2")?
+            .exists(params![kind, hash])?;
+        if logged {
+            return Ok(None);
+        }
+        let last: Option<(i64, String)> = tx
+            .query_row(
+                "SELECT seq, chain FROM transparency_log ORDER BY seq DESC LIMIT 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let (seq, prev) = match last {
+            Some((seq, chain)) => (seq as u64 + 1, chain),
+            None => (0, transparency::GENESIS.to_string()),
+        };
+        let mut entry = LogEntry {
+            seq,
+            kind: kind.to_string(),
+            hash: hash.to_string(),
+            size,
+            namespace: namespace.to_string(),
+            logged_at: chrono::Utc::now().to_rfc3339(),
+            chain: String::new(),
+        };
+        entry.chain = transparency::chain_hash(&prev, &entry.leaf_hash());
+        tx.execute(
+            "INSERT INTO transparency_log (seq, kind, hash, size, namespace, logged_at, chain)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                entry.seq as i64,
+                entry.kind,
+                entry.hash,
+                entry.size,
+                entry.namespace,
+                entry.logged_at,
+                entry.chain
+            ],
+        )?;
+        tx.commit()?;
+        Ok(Some(entry))
+    }
+
+    fn log_head(&self, size: Option<u64>) -> Result<Option<LogHead>> {
+        let conn = self.conn.lock().unwrap();
+        let current: i64 = conn.query_row("SELECT COUNT(*) FROM transparency_log", [], |row| {
+            row.get(0)
+        })?;
+        let size = size.unwrap_or(current as u64);
+        if size > current as u64 {
+            return Ok(None);
+        }
+        if size == 0 {
+            return Ok(Some(LogHead::empty()));
+        }
+        let head = conn.query_row(
+            "SELECT chain FROM transparency_log WHERE seq = ?1",
+            params![(size - 1) as i64],
+            |row| row.get(0),
+        )?;
+        Ok(Some(LogHead { size, head }))
+    }
+
+    fn log_entries(&self, start: u64, limit: u64) -> Result<Vec<LogEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT seq, kind, hash, size, namespace, logged_at, chain FROM transparency_log
+             WHERE seq >= ?1 ORDER BY seq LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![start as i64, limit as i64], |row| {
+            Ok(LogEntry {
+                seq: row.get::<_, i64>(0)? as u64,
+                kind: row.get(1)?,
+                hash: row.get(2)?,
+                size: row.get::<_, i64>(3)? as u64,
+                namespace: row.get(4)?,
+                logged_at: row.get(5)?,
+                chain: row.get(6)?,
+            })
+        })?;
+        let mut entries = Vec::new();
+        for row in rows {
+            entries.push(row?);
+        }
+        Ok(entries)
+    }
+
+    fn storage_objects(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT hash FROM cache_entries WHERE NOT is_layered
+             UNION SELECT layer_hash FROM cache_layers
+             ORDER BY 1",
+        )?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+
+        let mut hashes = Vec::new();
+        for hash in rows {
+            hashes.push(hash?);
+        }
+        Ok(hashes)
+    }
+
+    fn set_storage_path(&self, hash: &str, path: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE cache_entries SET artifact_path = ?2 WHERE hash = ?1 AND NOT is_layered",
+            params![hash, path],
+        )?;
+        conn.execute(
+            "UPDATE cache_layers SET storage_path = ?2 WHERE layer_hash = ?1",
+            params![hash, path],
+        )?;
+        Ok(())
+    }
+
+    fn get_old_entries(&self, days: u32) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let tolerance = clock::skew_tolerance().as_secs();
+        // Restamp entries used while the clock ran ahead, so they age from
+        // now instead of never looking idle
+        conn.execute(
+            "UPDATE cache_entries SET last_used = ?1
+             WHERE julianday(last_used) > julianday('now', '+' || ?2 || ' seconds')",
+            params![clock::stamp_rfc3339(), tolerance],
+        )?;
+        // Compared as dates: stamps are RFC 3339, unlike datetime()'s format
+        let mut stmt = conn.prepare(
+            "SELECT hash FROM cache_entries
+             WHERE julianday(last_used) < julianday('now', '-' || ?1 || ' days', '-' || ?2 || ' seconds')
+             AND pin_label IS NULL",
+        )?;
+        let rows = stmt.query_map(params![days, tolerance], |row| row.get(0))?;
 
         let mut hashes = Vec::new();
         for hash in rows {
@@ -323,7 +1123,7 @@ impl MetadataStore {
         Ok(hashes)
     }
 
-    pub fn record_build(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()> {
+    fn record_build(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         conn.execute(
             "CREATE TABLE IF NOT EXISTS build_analytics (
@@ -344,7 +1144,7 @@ impl MetadataStore {
         Ok(())
     }
 
-    pub fn get_analytics(&self, limit: u32) -> Result<Vec<BuildRecord>> {
+    fn get_analytics(&self, limit: u32) -> Result<Vec<BuildRecord>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
             "SELECT id, timestamp, dirty_nodes, cached_nodes, duration_ms 
@@ -372,7 +1172,7 @@ impl MetadataStore {
 #[async_trait]
 impl MetadataStoreTrait for MetadataStore {
     async fn insert(&self, hash: &str, path: &str, size: u64) -> Result<()> {
-        MetadataStore::insert(self, hash, path, size)
+        MetadataBackend::insert(self, hash, path, size)
     }
 
     async fn insert_layered_node(
@@ -381,39 +1181,145 @@ impl MetadataStoreTrait for MetadataStore {
         size: u64,
         layer_hashes: &[String],
     ) -> Result<()> {
-        MetadataStore::insert_layered_node(self, hash, size, layer_hashes)
+        MetadataBackend::insert_layered_node(self, hash, size, layer_hashes)
     }
 
     async fn insert_layer(&self, hash: &str, path: &str, size: u64) -> Result<()> {
-        MetadataStore::insert_layer(self, hash, path, size)
+        MetadataBackend::insert_layer(self, hash, path, size)
     }
 
     async fn get_node_layers(&self, hash: &str) -> Result<Option<Vec<String>>> {
-        MetadataStore::get_node_layers(self, hash)
+        MetadataBackend::get_node_layers(self, hash)
     }
 
     async fn layer_exists(&self, hash: &str) -> Result<bool> {
-        MetadataStore::layer_exists(self, hash)
+        MetadataBackend::layer_exists(self, hash)
     }
 
     async fn get_layer_path(&self, hash: &str) -> Result<Option<String>> {
-        MetadataStore::get_layer_path(self, hash)
+        MetadataBackend::get_layer_path(self, hash)
     }
 
     async fn get(&self, hash: &str) -> Result<Option<CacheEntry>> {
-        MetadataStore::get(self, hash)
+        MetadataBackend::get(self, hash)
     }
 
     async fn cleanup_old_entries(&self, days: u32) -> Result<i64> {
-        let entries = MetadataStore::get_old_entries(self, days)?;
+        let entries = MetadataBackend::get_old_entries(self, days)?;
         let count = entries.len() as i64;
         for hash in entries {
-            MetadataStore::delete(self, &hash)?;
+            MetadataBackend::delete(self, &hash)?;
         }
         Ok(count)
     }
 }
 
+/// [`MetadataStoreTrait`] kept in maps, for tests, benchmarks and ephemeral
+/// CI caches. Nothing survives the process.
+#[derive(Default)]
+pub struct MemoryMetadataStore {
+    inner: Mutex<MemoryMetadata>,
+}
+
+#[derive(Default)]
+struct MemoryMetadata {
+    entries: std::collections::HashMap<String, CacheEntry>,
+    layers: std::collections::HashMap<String, String>,
+    node_layers: std::collections::HashMap<String, Vec<String>>,
+}
+
+impl MemoryMetadataStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl MetadataStoreTrait for MemoryMetadataStore {
+    async fn insert(&self, hash: &str, path: &str, size: u64) -> Result<()> {
+        let now = clock::stamp_rfc3339();
+        self.inner.lock().unwrap().entries.insert(
+            hash.to_string(),
+            CacheEntry {
+                hash: hash.to_string(),
+                artifact_path: path.to_string(),
+                size,
+                created_at: now.clone(),
+                last_used: now,
+                hit_count: 0,
+            },
+        );
+        Ok(())
+    }
+
+    async fn insert_layered_node(
+        &self,
+        hash: &str,
+        size: u64,
+        layer_hashes: &[String],
+    ) -> Result<()> {
+        self.insert(hash, "", size).await?;
+        self.inner
+            .lock()
+            .unwrap()
+            .node_layers
+            .insert(hash.to_string(), layer_hashes.to_vec());
+        Ok(())
+    }
+
+    async fn insert_layer(&self, hash: &str, path: &str, _size: u64) -> Result<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .layers
+            .insert(hash.to_string(), path.to_string());
+        Ok(())
+    }
+
+    async fn get_node_layers(&self, hash: &str) -> Result<Option<Vec<String>>> {
+        Ok(self.inner.lock().unwrap().node_layers.get(hash).cloned())
+    }
+
+    async fn layer_exists(&self, hash: &str) -> Result<bool> {
+        Ok(self.inner.lock().unwrap().layers.contains_key(hash))
+    }
+
+    async fn get_layer_path(&self, hash: &str) -> Result<Option<String>> {
+        Ok(self.inner.lock().unwrap().layers.get(hash).cloned())
+    }
+
+    async fn get(&self, hash: &str) -> Result<Option<CacheEntry>> {
+        Ok(self.inner.lock().unwrap().entries.get(hash).cloned())
+    }
+
+    async fn cleanup_old_entries(&self, days: u32) -> Result<i64> {
+        let ttl = std::time::Duration::from_secs(days as u64 * 24 * 3600);
+        let now = clock::stamp_ms();
+        let tolerance = clock::skew_tolerance();
+        let mut inner = self.inner.lock().unwrap();
+        let MemoryMetadata {
+            entries,
+            node_layers,
+            ..
+        } = &mut *inner;
+        let before = entries.len();
+        entries.retain(|_, entry| {
+            let Ok(used) = chrono::DateTime::parse_from_rfc3339(&entry.last_used) else {
+                return true;
+            };
+            let used = used.timestamp_millis();
+            if clock::clamp(used, now, tolerance) != used {
+                // Used while the clock ran ahead; age it from now
+                entry.last_used = clock::stamp_rfc3339();
+                return true;
+            }
+            !clock::is_expired(used, ttl, now, tolerance)
+        });
+        node_layers.retain(|hash, _| entries.contains_key(hash));
+        Ok((before - entries.len()) as i64)
+    }
+}
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -432,9 +1338,147 @@ pub struct LayerStats {
     pub deduplicated_size: u64,
 }
 
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct StorageStats {
+    pub entries: u64,
+    pub used_bytes: u64,
+    pub gc_backlog_entries: u64,
+    pub gc_backlog_layers: u64,
+}
+
+/// Which entries `/api/v1/artifacts` lists; unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct EntryFilter {
+    /// Only entries created at least this many days ago
+    pub older_than_days: Option<u32>,
+    /// Only entries last read at least this many days ago
+    pub unused_for_days: Option<u32>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Only entries stored from this namespace; `unknown` matches entries
+    /// stored before namespaces were recorded
+    pub namespace: Option<String>,
+    /// Only pinned entries, or only unpinned ones
+    pub pinned: Option<bool>,
+    /// Only entries built from this commit; a prefix of it is enough
+    pub git_commit: Option<String>,
+    /// Only entries stored from this machine
+    pub builder: Option<String>,
+}
+
+pub(crate) const SUMMARY_COLUMNS: &str =
+    "hash, size, created_at, last_used, hit_count, is_layered, \
+     key_version, platform, namespace, build_id, pin_label, \
+     builder, ci_job, git_commit, client_version, built_at";
+
+fn summary_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<EntrySummary> {
+    Ok(EntrySummary {
+        hash: row.get(0)?,
+        size: row.get(1)?,
+        created_at: row.get(2)?,
+        last_used: row.get(3)?,
+        hit_count: row.get(4)?,
+        layered: row.get(5)?,
+        key_version: row.get(6)?,
+        platform: row.get(7)?,
+        namespace: row.get(8)?,
+        build_id: row.get(9)?,
+        pin: row.get(10)?,
+        origin: EntryOrigin {
+            builder: row.get(11)?,
+            ci_job: row.get(12)?,
+            git_commit: row.get(13)?,
+            version: row.get(14)?,
+            built_at: row.get(15)?,
+        },
+    })
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EntrySummary {
+    pub hash: String,
+    pub size: u64,
+    pub created_at: String,
+    pub last_used: String,
+    pub hit_count: u32,
+    /// Stored as layers rather than one blob
+    pub layered: bool,
+    pub key_version: u32,
+    pub platform: Option<String>,
+    pub namespace: Option<String>,
+    pub build_id: Option<String>,
+    /// Label of the pin keeping the entry from GC
+    pub pin: Option<String>,
+    /// Where the client that stored the entry ran
+    #[serde(skip_serializing_if = "EntryOrigin::is_empty")]
+    pub origin: EntryOrigin,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LayerReference {
+    pub hash: String,
+    pub size: u64,
+    /// Entries using the layer, this one included
+    pub ref_count: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryDetails {
+    #[serde(flatten)]
+    pub summary: EntrySummary,
+    /// Where the blob is in storage; layered entries have none
+    pub storage_path: Option<String>,
+    pub layers: Vec<LayerReference>,
+}
+
+/// UTC day rollups are keyed by.
+pub(crate) fn today() -> String {
+    chrono::Utc::now().format("%Y-%m-%d").to_string()
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DailyUsage {
+    pub day: String,
+    pub namespace: String,
+    pub hits: u64,
+    pub misses: u64,
+    /// Bytes served from the cache instead of being rebuilt
+    pub bytes_saved: u64,
+    pub bytes_uploaded: u64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ArtifactUsage {
+    pub namespace: String,
+    pub hash: String,
+    pub hits: u64,
+    pub bytes_saved: u64,
+}
+
+/// Cache usage over a period, as served by `/stats`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct UsageStats {
+    /// First day included
+    pub since: String,
+    pub namespaces: Vec<String>,
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_rate: f64,
+    pub bytes_saved: u64,
+    pub bytes_uploaded: u64,
+    pub daily: Vec<DailyUsage>,
+    pub top_artifacts: Vec<ArtifactUsage>,
+}
+
 #[cfg(test)]
 mod tests {
-    use super::*;
+    // Not MetadataStoreTrait: MetadataStore implements both, and the tests
+    // of the SQLite store call it synchronously
+    use super::{
+        params, transparency, Connection, EntryFilter, EntryOrigin, LogHead, MemoryMetadataStore,
+        MetadataBackend, MetadataStore, PinTarget,
+    };
+    use std::collections::BTreeMap;
     use tempfile::NamedTempFile;
 
     #[test]
@@ -457,5 +1501,315 @@ mod tests {
         store.touch(hash).unwrap();
         let updated_entry = store.get(hash).unwrap().unwrap();
         assert_eq!(updated_entry.hit_count, 1);
+
+        let stats = store.storage_stats(7).unwrap();
+        assert_eq!(stats.entries, 1);
+        assert_eq!(stats.used_bytes, size);
+        assert_eq!(stats.gc_backlog_entries, 0);
+
+        store.insert_layer("layer-hash", "old/layer", 10).unwrap();
+        store
+            .insert_layered_node("node-hash", 10, &["layer-hash".to_string()])
+            .unwrap();
+        assert_eq!(store.storage_objects().unwrap(), vec!["layer-hash", hash]);
+        store
+            .set_storage_path(hash, "s3://bucket/sha256/x")
+            .unwrap();
+        assert_eq!(
+            store.get(hash).unwrap().unwrap().artifact_path,
+            "s3://bucket/sha256/x"
+        );
+        assert_eq!(
+            store.get_layer_path("layer-hash").unwrap().unwrap(),
+            "old/layer"
+        );
+    }
+
+    #[test]
+    fn test_usage_rollups() {
+        let db_file = NamedTempFile::new().unwrap();
+        let store = MetadataStore::new(db_file.path()).unwrap();
+
+        store.record_hit("web", "big", 1000).unwrap();
+        store.record_hit("web", "big", 1000).unwrap();
+        store.record_hit("web", "small", 10).unwrap();
+        store.record_miss("web").unwrap();
+        store.record_hit("api", "small", 10).unwrap();
+        store.record_upload("api", 500).unwrap();
+
+        let all = store.usage_stats(7, None, 10).unwrap();
+        assert_eq!(all.namespaces, vec!["api", "web"]);
+        assert_eq!((all.hits, all.misses), (4, 1));
+        assert_eq!(all.hit_rate, 0.8);
+        assert_eq!(all.bytes_saved, 2020);
+        assert_eq!(all.bytes_uploaded, 500);
+        assert_eq!(all.daily.len(), 2);
+        assert_eq!(all.top_artifacts[0].hash, "big");
+        assert_eq!(all.top_artifacts[0].hits, 2);
+
+        let web = store.usage_stats(1, Some("web"), 1).unwrap();
+        assert_eq!((web.hits, web.misses, web.bytes_saved), (3, 1, 2010));
+        assert_eq!(web.top_artifacts.len(), 1);
+    }
+
+    #[test]
+    fn test_list_and_inspect_entries() {
+        let db_file = NamedTempFile::new().unwrap();
+        let store = MetadataStore::new(db_file.path()).unwrap();
+        store.insert("small", "sm/al/small", 10).unwrap();
+        store.set_namespace("small", "web").unwrap();
+        store.insert("big", "bi/g/big", 5000).unwrap();
+        store.set_namespace("big", "api").unwrap();
+        store.insert_layer("l1", "l1", 300).unwrap();
+        store
+            .insert_layered_node("layered", 300, &["l1".to_string()])
+            .unwrap();
+
+        let (all, total) = store.list_entries(&EntryFilter::default(), 0, 2).unwrap();
+        assert_eq!((all.len(), total), (2, 3));
+        let (rest, _) = store.list_entries(&EntryFilter::default(), 2, 2).unwrap();
+        assert_eq!(rest.len(), 1);
+
+        let large = EntryFilter {
+            min_size: Some(100),
+            ..Default::default()
+        };
+        let (entries, total) = store.list_entries(&large, 0, 10).unwrap();
+        assert_eq!(total, 2);
+        assert!(entries.iter().all(|e| e.size >= 100));
+        let web = EntryFilter {
+            namespace: Some("web".to_string()),
+            ..Default::default()
+        };
+        let (entries, _) = store.list_entries(&web, 0, 10).unwrap();
+        assert_eq!(entries[0].hash, "small");
+        // Nothing is a day old yet
+        let old = EntryFilter {
+            older_than_days: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(store.list_entries(&old, 0, 10).unwrap().1, 0);
+
+        let details = store.entry_details("layered").unwrap().unwrap();
+        assert!(details.summary.layered);
+        assert_eq!(details.storage_path, None);
+        assert_eq!(details.layers[0].hash, "l1");
+        assert_eq!(details.layers[0].ref_count, 1);
+        let details = store.entry_details("big").unwrap().unwrap();
+        assert_eq!(details.storage_path.as_deref(), Some("bi/g/big"));
+        assert!(store.entry_details("missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_pinned_entries_survive_gc() {
+        let db_file = NamedTempFile::new().unwrap();
+        let store = MetadataStore::new(db_file.path()).unwrap();
+        for hash in ["release", "nightly", "scratch"] {
+            store.insert(hash, hash, 1).unwrap();
+        }
+        store.set_build_id("release", "ci-42").unwrap();
+        store.set_build_id("nightly", "ci-43").unwrap();
+        store
+            .conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE cache_entries SET last_used = datetime('now', '-90 days'), key_version = 0",
+                [],
+            )
+            .unwrap();
+
+        let build = PinTarget::Build("ci-42".to_string());
+        assert_eq!(store.set_pin(&build, Some("v1.0")).unwrap(), 1);
+        let key = PinTarget::Key("nightly".to_string());
+        assert_eq!(store.set_pin(&key, Some("keep")).unwrap(), 1);
+        assert_eq!(
+            store.get_old_entries(30).unwrap(),
+            vec!["scratch".to_string()]
+        );
+        assert_eq!(store.entries_not_at_key_version(2).unwrap().len(), 1);
+
+        let pinned = EntryFilter {
+            pinned: Some(true),
+            ..Default::default()
+        };
+        let (entries, total) = store.list_entries(&pinned, 0, 10).unwrap();
+        assert_eq!(total, 2);
+        let release = entries.iter().find(|e| e.hash == "release").unwrap();
+        assert_eq!(release.pin.as_deref(), Some("v1.0"));
+        assert_eq!(release.build_id.as_deref(), Some("ci-42"));
+
+        store.set_pin(&key, None).unwrap();
+        assert_eq!(store.get_old_entries(30).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_gc_tolerates_clock_skew() {
+        let db_file = NamedTempFile::new().unwrap();
+        let store = MetadataStore::new(db_file.path()).unwrap();
+        let conn = || store.conn.lock().unwrap();
+        let backdate = |hash: &str, modifier: &str| {
+            conn()
+                .execute(
+                    "UPDATE cache_entries SET last_used = strftime('%Y-%m-%dT%H:%M:%S+00:00', 'now', ?2)
+                     WHERE hash = ?1",
+                    params![hash, modifier],
+                )
+                .unwrap();
+        };
+        for hash in ["stale", "borderline", "future"] {
+            store.insert(hash, hash, 1).unwrap();
+        }
+        backdate("stale", "-31 days");
+        // Past 30 days, but within the default 5 minutes of skew
+        backdate("borderline", "-30 days -60 seconds");
+        backdate("future", "+365 days");
+
+        assert_eq!(
+            store.get_old_entries(30).unwrap(),
+            vec!["stale".to_string()]
+        );
+        // The future stamp was brought back to now
+        let future: f64 = conn()
+            .query_row(
+                "SELECT julianday(last_used) - julianday('now') FROM cache_entries WHERE hash = 'future'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(future.abs() < 1.0);
+    }
+
+    #[test]
+    fn test_entry_origin_is_recorded() {
+        let db_file = NamedTempFile::new().unwrap();
+        let store = MetadataStore::new(db_file.path()).unwrap();
+        store.insert("traced", "tr/ac/traced", 1).unwrap();
+        store.insert("unknown", "un/kn/unknown", 1).unwrap();
+        let origin = EntryOrigin {
+            builder: Some("runner-3".to_string()),
+            ci_job: Some("https://ci.example.com/jobs/7".to_string()),
+            git_commit: Some("0123456789abcdef".to_string()),
+            version: Some("0.4.0".to_string()),
+            built_at: Some("2026-01-01T00:00:00+00:00".to_string()),
+        };
+        store.set_origin("traced", &origin).unwrap();
+
+        let by_commit = EntryFilter {
+            git_commit: Some("0123456".to_string()),
+            ..Default::default()
+        };
+        let (entries, total) = store.list_entries(&by_commit, 0, 10).unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(entries[0].origin, origin);
+
+        let details = store.entry_details("unknown").unwrap().unwrap();
+        assert!(details.summary.origin.is_empty());
+        assert_eq!(details.storage_path.as_deref(), Some("un/kn/unknown"));
+    }
+
+    #[test]
+    fn test_transparency_log() {
+        let db_file = NamedTempFile::new().unwrap();
+        let store = MetadataStore::new(db_file.path()).unwrap();
+        assert_eq!(store.log_head(None).unwrap(), Some(LogHead::empty()));
+
+        store.append_log("artifact", "a", 1, "web").unwrap();
+        let seen = store.log_head(None).unwrap().unwrap();
+        // Re-uploads of a logged blob are not new insertions
+        let again = store.append_log("artifact", "a", 1, "web").unwrap();
+        assert!(again.is_none());
+        store.append_log("layer", "b", 2, "api").unwrap();
+        store.append_log("artifact", "c", 3, "web").unwrap();
+
+        let head = store.log_head(None).unwrap().unwrap();
+        assert_eq!(head.size, 3);
+        assert_eq!(store.log_head(Some(1)).unwrap(), Some(seen.clone()));
+        assert_eq!(store.log_head(Some(4)).unwrap(), None);
+
+        let entries = store.log_entries(seen.size, 100).unwrap();
+        assert_eq!(entries.len(), 2);
+        transparency::verify_chain(&seen, &head, &entries).unwrap();
+        transparency::verify_chain(
+            &LogHead::empty(),
+            &head,
+            &store.log_entries(0, 100).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_key_version_migration() {
+        let db_file = NamedTempFile::new().unwrap();
+        {
+            // Schema from before key versioning
+            let conn = Connection::open(db_file.path()).unwrap();
+            conn.execute(
+                "CREATE TABLE cache_entries (
+                    hash TEXT PRIMARY KEY,
+                    artifact_path TEXT,
+                    size BIGINT,
+                    created_at TIMESTAMP,
+                    last_used TIMESTAMP,
+                    hit_count INT,
+                    is_layered BOOLEAN DEFAULT FALSE
+                )",
+                [],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO cache_entries VALUES ('legacy', 'a', 1, '', '', 0, FALSE)",
+                [],
+            )
+            .unwrap();
+        }
+
+        let store = MetadataStore::new(db_file.path()).unwrap();
+        assert_eq!(store.key_version("legacy").unwrap(), Some(0));
+        assert_eq!(store.key_version("missing").unwrap(), None);
+
+        store.insert("current", "b", 1).unwrap();
+        store.set_key_version("current", 1).unwrap();
+        assert_eq!(
+            store.key_version_counts().unwrap(),
+            BTreeMap::from([(0, 1), (1, 1)])
+        );
+        assert_eq!(store.entries_not_at_key_version(1).unwrap(), vec!["legacy"]);
+
+        assert_eq!(store.platform("legacy").unwrap(), None);
+        store.set_platform("current", "darwin/arm64").unwrap();
+        assert_eq!(
+            store.platform("current").unwrap().as_deref(),
+            Some("darwin/arm64")
+        );
+        assert_eq!(
+            store.platform_counts().unwrap(),
+            BTreeMap::from([("darwin/arm64".to_string(), 1), ("unknown".to_string(), 1)])
+        );
+        assert_eq!(
+            store.entries_for_platform("darwin/arm64").unwrap(),
+            vec!["current"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_metadata_store() {
+        use super::MetadataStoreTrait;
+
+        let store = MemoryMetadataStore::new();
+        store.insert("a", "memory://a", 3).await.unwrap();
+        store
+            .insert_layered_node("b", 5, &["l1".to_string()])
+            .await
+            .unwrap();
+        store.insert_layer("l1", "memory://l1", 5).await.unwrap();
+
+        assert_eq!(store.get("a").await.unwrap().unwrap().size, 3);
+        assert_eq!(
+            store.get_node_layers("b").await.unwrap(),
+            Some(vec!["l1".to_string()])
+        );
+        assert!(store.layer_exists("l1").await.unwrap());
+        assert_eq!(store.cleanup_old_entries(1).await.unwrap(), 0);
     }
 }
@@ -1,6 +1,8 @@
-use crate::server::metadata::MetadataStore;
+use crate::cache::pin::PinTarget;
+use crate::server::metadata::{open_metadata, MetadataBackend, MetadataStore};
 use crate::server::storage::{ArtifactStorage, LocalStorage};
-use crate::storage::storage_from_env;
+use crate::server::transparency::{ConsistencyProof, LogHead};
+use crate::storage::{storage_for_backend, storage_from_env, StorageBackend};
 use anyhow::Result;
 use axum::{
     body::Bytes,
@@ -8,10 +10,10 @@ use axum::{
         ws::{Message, WebSocket, WebSocketUpgrade},
         Path, Query, State,
     },
-    http::{Request, StatusCode},
+    http::{HeaderMap, Request, StatusCode},
     middleware::{self, Next},
     response::{Html, IntoResponse, Response},
-    routing::{get, head, post, put},
+    routing::{delete, get, head, post, put},
     Json, Router,
 };
 use serde::Deserialize;
@@ -22,21 +24,356 @@ use std::sync::Arc;
 use tokio::sync::broadcast;
 // use tower_governor::GovernorLayer;
 
+pub mod hints;
 pub mod metadata;
+pub mod postgres;
+pub mod quarantine;
 pub mod storage;
+pub mod test_util;
+pub mod transparency;
+pub mod validator;
 
 pub struct AppState {
-    pub metadata: MetadataStore,
+    pub metadata: Box<dyn MetadataBackend>,
     pub storage: Arc<dyn ArtifactStorage>,
     pub webhook_url: Option<String>,
     pub tx_events: broadcast::Sender<crate::dashboard::BuildEvent>,
     pub current_dag: Arc<std::sync::Mutex<Option<crate::graph::BuildGraph>>>,
     pub auth_state: Arc<crate::auth::AuthState>,
+    pub uploads: UploadTracker,
+    /// Free space uploads must leave on the storage volume
+    pub min_free_bytes: u64,
+    /// Largest artifact accepted, if any
+    pub max_artifact_bytes: Option<u64>,
+    /// Artifacts builds fetched together, for prefetch hints
+    pub hints: hints::PrefetchHints,
+    /// Layers whose stored copy was found corrupt
+    pub quarantine: quarantine::Quarantine,
+    /// Checks stored blobs in the background
+    pub validator: validator::Validator,
+}
+
+/// Counts uploads between receiving their body and committing metadata, so
+/// shutdown can report what it is waiting for.
+#[derive(Default)]
+pub struct UploadTracker {
+    in_flight: std::sync::atomic::AtomicUsize,
+}
+
+impl UploadTracker {
+    pub fn begin(&self) -> UploadGuard<'_> {
+        self.in_flight
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        UploadGuard(self)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+pub struct UploadGuard<'a>(&'a UploadTracker);
+
+impl Drop for UploadGuard<'_> {
+    fn drop(&mut self) {
+        self.0
+            .in_flight
+            .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Whether storing `size` more bytes keeps the volume above the free-space
+/// margin. Backends that do not report space always have room.
+fn has_room(state: &AppState, size: u64) -> bool {
+    match state.storage.available_space() {
+        Some(available) => available >= size.saturating_add(state.min_free_bytes),
+        None => true,
+    }
+}
+
+/// Whether an artifact of `size` bytes is within `MEMOBUILD_MAX_ARTIFACT_BYTES`.
+/// Logs the rejection otherwise.
+fn within_size_limit(state: &AppState, hash: &str, size: u64) -> bool {
+    match crate::cache::limits::check_artifact_size(hash, size, state.max_artifact_bytes) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("❌ Rejected upload: {}", e);
+            false
+        }
+    }
+}
+
+/// How long shutdown waits for in-flight requests (`MEMOBUILD_SHUTDOWN_GRACE_SECS`).
+fn shutdown_grace() -> std::time::Duration {
+    let secs = std::env::var("MEMOBUILD_SHUTDOWN_GRACE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(crate::constants::DEFAULT_SHUTDOWN_GRACE_SECS);
+    std::time::Duration::from_secs(secs)
 }
 
 #[derive(Deserialize)]
 pub struct GcQuery {
     pub days: u32,
+    /// Also evict least recently used entries down to this many bytes;
+    /// defaults to `MEMOBUILD_GC_MAX_SIZE_BYTES`
+    #[serde(default)]
+    pub max_size_bytes: Option<u64>,
+    /// Report what would be deleted without deleting it
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Deserialize)]
+pub struct KeyVersionGcQuery {
+    /// Key version the client expects to survive; only the server's own is
+    /// accepted
+    pub keep: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct PlatformQuery {
+    /// List the entries stored from this `os/arch`
+    pub platform: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PlatformStats {
+    /// Entry count per platform of the client that stored them
+    pub entries: std::collections::BTreeMap<String, u64>,
+    /// Hashes stored from the queried platform
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hashes: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct KeyVersionStats {
+    /// Key version this server was built with
+    pub current: u32,
+    /// Entry count per key version
+    pub entries: std::collections::BTreeMap<u32, u64>,
+}
+
+/// Key version a request's cache keys were derived with. Clients that
+/// predate key versioning send no header and count as version 0.
+fn client_key_version(headers: &HeaderMap) -> u32 {
+    headers
+        .get(crate::constants::KEY_VERSION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+/// `os/arch` of the client sending a request, if it says.
+fn client_platform(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(crate::constants::PLATFORM_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+}
+
+/// Namespace usage from a request is counted under, so teams sharing a
+/// server can see their own savings.
+fn client_namespace(headers: &HeaderMap) -> &str {
+    headers
+        .get(crate::constants::NAMESPACE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty() && v.len() <= 128)
+        .unwrap_or(crate::constants::DEFAULT_NAMESPACE)
+}
+
+/// `MEMOBUILD_BUILD_ID` of the build sending a request, if it has one.
+fn client_build_id(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(crate::constants::BUILD_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 128)
+}
+
+/// The build a request belongs to, for pairing the artifacts it fetches: its
+/// build id, or else the session of the client process.
+fn client_session(headers: &HeaderMap) -> Option<&str> {
+    client_build_id(headers).or_else(|| {
+        headers
+            .get(crate::constants::SESSION_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty() && v.len() <= 128)
+    })
+}
+
+/// Machine, CI job, commit and version of the client sending a request.
+fn client_origin(headers: &HeaderMap) -> Option<crate::cache::EntryOrigin> {
+    headers
+        .get(crate::constants::ORIGIN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(crate::cache::EntryOrigin::from_header)
+}
+
+/// Record the key version, platform, namespace, build and origin of the
+/// client that stored `hash`.
+fn tag_entry(state: &AppState, hash: &str, headers: &HeaderMap) -> Result<()> {
+    state
+        .metadata
+        .set_key_version(hash, client_key_version(headers))?;
+    if let Some(platform) = client_platform(headers) {
+        state.metadata.set_platform(hash, platform)?;
+    }
+    state
+        .metadata
+        .set_namespace(hash, client_namespace(headers))?;
+    if let Some(build_id) = client_build_id(headers) {
+        state.metadata.set_build_id(hash, build_id)?;
+    }
+    if let Some(origin) = client_origin(headers) {
+        state.metadata.set_origin(hash, &origin)?;
+    }
+    Ok(())
+}
+
+/// `Ok` when the request carries an admin token: `401` without a token,
+/// `403` with one that is not an admin's.
+async fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let token = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    match state.auth_state.is_admin_token(token).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(StatusCode::FORBIDDEN),
+        Err(e) => {
+            eprintln!("Error checking token: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Whether an entry for `hash` exists and was stored by a client with the
+/// same key version. Entries from other versions are invisible to it.
+fn entry_visible(state: &AppState, hash: &str, headers: &HeaderMap) -> Result<bool> {
+    Ok(state.metadata.key_version(hash)? == Some(client_key_version(headers)))
+}
+
+/// Part of a blob a request asked for.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    /// First and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Interpret a `Range` header for a blob of `len` bytes. Only single
+/// `bytes=` ranges are served; other forms are ignored and get the whole blob.
+fn parse_range(header: &str, len: u64) -> ByteRange {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (first, last) = match (first.trim(), last.trim()) {
+        // Suffix: the last N bytes
+        ("", n) => match n.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(n) => (len.saturating_sub(n), len.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (f, "") => match f.parse::<u64>() {
+            Ok(f) => (f, len.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (f, l) => match (f.parse::<u64>(), l.parse::<u64>()) {
+            (Ok(f), Ok(l)) if f <= l => (f, l.min(len.saturating_sub(1))),
+            _ => return ByteRange::Full,
+        },
+    };
+    if len == 0 || first >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(first, last)
+}
+
+/// Serve a stored blob, honouring `If-None-Match` and a single `Range`.
+/// Blobs are content-addressed, so their hash is a strong ETag.
+fn blob_response(hash: &str, data: Vec<u8>, headers: &HeaderMap) -> Response {
+    use axum::http::header::{
+        HeaderName, ACCEPT_RANGES, CONTENT_RANGE, ETAG, IF_NONE_MATCH, IF_RANGE, RANGE,
+    };
+
+    let etag = format!("\"{}\"", hash);
+    let header = |name: HeaderName| headers.get(name).and_then(|v| v.to_str().ok());
+
+    if let Some(candidates) = header(IF_NONE_MATCH) {
+        let matches = candidates
+            .split(',')
+            .map(|t| t.trim().trim_start_matches("W/"))
+            .any(|t| t == "*" || t == etag);
+        if matches {
+            return (StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response();
+        }
+    }
+
+    // A partial copy with another validator is stale; it gets the whole blob
+    let fresh = match header(IF_RANGE) {
+        Some(validator) => validator == etag,
+        None => true,
+    };
+    let len = data.len() as u64;
+    let range = match header(RANGE) {
+        Some(r) if fresh => parse_range(r, len),
+        _ => ByteRange::Full,
+    };
+    match range {
+        ByteRange::Full => (
+            StatusCode::OK,
+            [(ETAG, etag), (ACCEPT_RANGES, "bytes".to_string())],
+            data,
+        )
+            .into_response(),
+        ByteRange::Partial(first, last) => (
+            StatusCode::PARTIAL_CONTENT,
+            [
+                (ETAG, etag),
+                (ACCEPT_RANGES, "bytes".to_string()),
+                (CONTENT_RANGE, format!("bytes {}-{}/{}", first, last, len)),
+            ],
+            data[first as usize..=last as usize].to_vec(),
+        )
+            .into_response(),
+        ByteRange::Unsatisfiable => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(CONTENT_RANGE, format!("bytes */{}", len))],
+        )
+            .into_response(),
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -62,12 +399,49 @@ pub async fn start_server(
     tls_config: Option<crate::tls::TlsConfig>,
     admin_token: Option<String>,
     auth_db_client: Option<tokio_postgres::Client>,
+    backend: Option<StorageBackend>,
 ) -> Result<()> {
-    let db_path = data_dir.join("metadata.db");
-    let metadata = MetadataStore::new(&db_path)?;
-    let storage: Arc<dyn ArtifactStorage> = match storage_from_env(&data_dir) {
-        Ok(s) => Arc::from(s),
-        Err(_) => Arc::new(LocalStorage::new(&data_dir)?),
+    // `--storage=memory` keeps blobs and metadata in memory, for CI caches
+    // that live as long as one pipeline
+    let ephemeral = backend.unwrap_or_else(StorageBackend::from_env) == StorageBackend::Memory;
+    let metadata: Box<dyn MetadataBackend> = if ephemeral {
+        println!("🧪 Keeping artifacts and metadata in memory until the server stops");
+        Box::new(MetadataStore::in_memory()?)
+    } else {
+        let url = std::env::var("MEMOBUILD_METADATA_URL").ok();
+        open_metadata(url.as_deref(), &data_dir).await?
+    };
+    let storage: Box<dyn ArtifactStorage> = match std::env::var("MEMOBUILD_STORAGE_COLD_URL") {
+        _ if ephemeral => Box::new(crate::storage::MemoryStorage::new()),
+        // Local disk in front of object storage, idle blobs moved out hourly
+        Ok(uri) if !uri.trim().is_empty() => {
+            let tiered = Arc::new(crate::storage::TieredStorage::new(
+                LocalStorage::new(&data_dir)?,
+                crate::storage::storage_from_uri(&uri)?,
+                crate::storage::TieredStorage::idle_from_env(),
+            ));
+            println!("🧊 Moving idle blobs to {}", uri);
+            spawn_demotion(tiered.clone());
+            Box::new(tiered)
+        }
+        _ => match backend.map_or_else(
+            || storage_from_env(&data_dir),
+            |backend| storage_for_backend(backend, &data_dir),
+        ) {
+            Ok(s) => s,
+            Err(_) => Box::new(LocalStorage::new(&data_dir)?),
+        },
+    };
+    // While `memobuild migrate-storage` runs, reads also look at the old storage
+    let storage: Arc<dyn ArtifactStorage> = match std::env::var("MEMOBUILD_STORAGE_FALLBACK_URL") {
+        Ok(uri) if !uri.trim().is_empty() => {
+            println!("📦 Reading from {} for blobs not migrated yet", uri);
+            Arc::new(crate::storage::migrate::ReadBothStorage::new(
+                storage,
+                crate::storage::storage_from_uri(&uri)?,
+            ))
+        }
+        _ => Arc::from(storage),
     };
 
     let (tx_events, _) = broadcast::channel(crate::constants::MAX_WS_BROADCAST_CAPACITY);
@@ -82,10 +456,87 @@ pub async fn start_server(
         tx_events,
         current_dag,
         auth_state,
+        uploads: UploadTracker::default(),
+        min_free_bytes: std::env::var("MEMOBUILD_MIN_FREE_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(crate::constants::DEFAULT_MIN_FREE_BYTES),
+        max_artifact_bytes: crate::cache::limits::max_artifact_bytes(),
+        hints: Default::default(),
+        quarantine: Default::default(),
+        validator: validator::Validator::from_env()?,
     });
+    let shutdown_state = state.clone();
+    if let Some(interval) = validator::Validator::interval_from_env() {
+        validator::spawn(state.clone(), interval);
+    }
+
+    let app = router(state);
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    println!("🌐 MemoBuild Remote Cache Server running on {}", addr);
 
-    let app = Router::new()
+    // On SIGTERM stop accepting connections and give in-flight requests
+    // `grace` to finish before the process exits
+    let grace = shutdown_grace();
+    let tracker = shutdown_state.clone();
+    let announce = move || {
+        println!(
+            "🛑 Shutting down, waiting up to {}s for {} in-flight upload(s)",
+            grace.as_secs(),
+            tracker.uploads.in_flight()
+        );
+    };
+    if let Some(tls) = tls_config {
+        let rustls_config = tls.axum_rustls_config()?;
+        let handle = axum_server::Handle::new();
+        let signal_handle = handle.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            announce();
+            signal_handle.graceful_shutdown(Some(grace));
+        });
+        axum_server::bind_rustls(addr, rustls_config)
+            .handle(handle)
+            .serve(app.into_make_service())
+            .await?;
+    } else {
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = axum::Server::bind(&addr)
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(async {
+                let _ = stop_rx.await;
+            });
+        tokio::pin!(server);
+        tokio::select! {
+            result = &mut server => result?,
+            _ = shutdown_signal() => {
+                announce();
+                let _ = stop_tx.send(());
+                if tokio::time::timeout(grace, &mut server).await.is_err() {
+                    eprintln!("⚠️  Grace period elapsed, dropping remaining connections");
+                }
+            }
+        }
+    }
+
+    let abandoned = shutdown_state.uploads.in_flight();
+    if abandoned > 0 {
+        eprintln!(
+            "⚠️  {} upload(s) did not finish and were discarded",
+            abandoned
+        );
+    }
+    shutdown_state.metadata.flush()?;
+    println!("👋 Cache server stopped");
+    Ok(())
+}
+
+/// Every route of the cache server, serving `state`.
+pub(crate) fn router(state: Arc<AppState>) -> Router {
+    Router::new()
         .route("/", get(dashboard))
+        .route("/healthz", get(healthz))
         .route("/cache/:hash", head(check_cache))
         .route("/cache/:hash", get(get_artifact))
         .route("/cache/:hash", put(put_artifact))
@@ -93,10 +544,13 @@ pub async fn start_server(
         .route("/cache/layer/:hash", head(check_layer))
         .route("/cache/layer/:hash", get(get_layer))
         .route("/cache/layer/:hash", put(put_layer))
+        .route("/cache/layer/:hash/report", post(report_corrupt_layer))
         .route("/cache/node/:hash/layers", get(get_node_layers))
         .route("/cache/node/:hash/layers", post(register_node_layers))
+        .route("/cache/hints/:hash", get(prefetch_hints))
         .route("/gc", post(gc_cache))
         .route("/gc/status", get(gc_status))
+        .route("/gc/versions", post(gc_key_versions))
         .route("/metrics", get(metrics_handler))
         .route("/analytics", post(report_analytics))
         .route("/build-event", post(receive_build_event))
@@ -104,27 +558,52 @@ pub async fn start_server(
         .route("/dag", get(get_dag))
         .route("/api/analytics", get(get_analytics_handler))
         .route("/api/layers", get(get_layer_stats_handler))
+        .route("/api/quarantine", get(list_quarantine))
+        .route("/api/validation", get(validation_status))
+        .route("/api/validation/run", post(run_validation))
+        .route("/api/key-versions", get(key_version_stats))
+        .route("/api/platforms", get(platform_stats))
+        .route("/stats", get(usage_stats))
+        .route("/log", get(log_entries))
+        .route("/log/head", get(log_head))
+        .route("/log/consistency", get(log_consistency))
+        .route("/log/verify", get(log_verify))
         .route("/ws", get(ws_handler))
+        .route("/api/v1/artifacts", get(list_artifacts))
+        .route("/api/v1/artifacts/:hash", get(inspect_artifact))
+        .route("/api/v1/artifacts/:hash", delete(delete_artifact))
+        .route("/api/v1/pins", post(pin_artifacts))
+        .route("/api/v1/pins", delete(unpin_artifacts))
         .layer(middleware::from_fn(add_api_version_header))
         // Add auth routes
-        
-        .with_state(state);
-
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
-    println!("🌐 MemoBuild Remote Cache Server running on {}", addr);
-
-    if let Some(tls) = tls_config {
-        let rustls_config = tls.axum_rustls_config()?;
-        axum_server::bind_rustls(addr, rustls_config)
-            .serve(app.into_make_service())
-            .await?;
-    } else {
-        axum::Server::bind(&addr)
-            .serve(app.into_make_service())
-            .await?;
-    }
+    
+        .with_state(state)
+}
 
-    Ok(())
+fn spawn_demotion(tiered: Arc<crate::storage::TieredStorage>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(std::time::Duration::from_secs(
+            crate::constants::TIER_DEMOTION_INTERVAL_SECS,
+        ));
+        loop {
+            ticker.tick().await;
+            let tiered = tiered.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                tiered.demote_idle(std::time::SystemTime::now())
+            })
+            .await;
+            match result {
+                Ok(Ok(report)) if report.demoted > 0 => println!(
+                    "🧊 Moved {} idle blob(s) ({:.1} MB) to cold storage",
+                    report.demoted,
+                    report.bytes as f64 / 1_048_576.0
+                ),
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => eprintln!("⚠️  Demotion pass failed: {:#}", e),
+                Err(e) => eprintln!("⚠️  Demotion pass panicked: {}", e),
+            }
+        }
+    });
 }
 
 async fn ws_handler(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> impl IntoResponse {
@@ -340,8 +819,39 @@ async fn dashboard() -> Html<String> {
             </div>
         </div>
 
+        <div class="grid" id="savings">
+            <div class="card">
+                <div class="stat-label">Bytes Saved (30d)</div>
+                <div class="stat-value" id="bytes-saved">-</div>
+            </div>
+            <div class="card">
+                <div class="stat-label">Remote Hit Rate (30d)</div>
+                <div class="stat-value" id="remote-hit-rate">-</div>
+            </div>
+            <div class="card">
+                <div class="stat-label">Remote Hits / Misses</div>
+                <div class="stat-value" id="remote-hits">-</div>
+            </div>
+            <div class="card">
+                <div class="stat-label">Namespaces</div>
+                <div class="stat-value" id="namespaces">-</div>
+            </div>
+        </div>
+
         <div id="dag-container"></div>
 
+        <table style="margin-bottom:2rem;">
+            <thead>
+                <tr>
+                    <th>Top Artifact</th>
+                    <th>Namespace</th>
+                    <th>Hits</th>
+                    <th>Bytes Saved</th>
+                </tr>
+            </thead>
+            <tbody id="top-artifacts"></tbody>
+        </table>
+
         <table>
             <thead>
                 <tr>
@@ -431,17 +941,48 @@ async fn dashboard() -> Html<String> {
                 document.getElementById('active-nodes').textContent = Math.max(0, active - 1);
             } else if (event.NodeFailed) {
                 nodesDS.update({ id: event.NodeFailed.node_id, color: { background: '#ef4444' } });
+            } else if (event.HashingProgress) {
+                const p = event.HashingProgress;
+                document.getElementById('active-nodes').textContent =
+                    `Hashing ${p.files}/${p.total_files}`;
             }
         }
 
+        function formatBytes(n) {
+            const units = ['B', 'KiB', 'MiB', 'GiB', 'TiB'];
+            let i = 0;
+            while (n >= 1024 && i < units.length - 1) { n /= 1024; i++; }
+            return (i === 0 ? n : n.toFixed(1)) + ' ' + units[i];
+        }
+
         async function loadStats() {
-            const [analyticsResp, layerResp] = await Promise.all([
+            const [analyticsResp, layerResp, usageResp] = await Promise.all([
                 fetch('/api/analytics'),
-                fetch('/api/layers')
+                fetch('/api/layers'),
+                fetch('/stats')
             ]);
             
             const data = await analyticsResp.json();
             const layers = await layerResp.json();
+            const usage = await usageResp.json();
+
+            if (usage) {
+                document.getElementById('bytes-saved').textContent = formatBytes(usage.bytes_saved);
+                document.getElementById('remote-hit-rate').textContent = Math.round(usage.hit_rate * 100) + '%';
+                document.getElementById('remote-hits').textContent = usage.hits + ' / ' + usage.misses;
+                document.getElementById('namespaces').textContent = usage.namespaces.length;
+                const top = document.getElementById('top-artifacts');
+                top.textContent = '';
+                usage.top_artifacts.forEach(a => {
+                    const tr = document.createElement('tr');
+                    [a.hash.slice(0, 12), a.namespace, a.hits, formatBytes(a.bytes_saved)].forEach(v => {
+                        const td = document.createElement('td');
+                        td.textContent = v;
+                        tr.appendChild(td);
+                    });
+                    top.appendChild(tr);
+                });
+            }
             
             if (data.length > 0) {
                 document.getElementById('total-builds').textContent = data.length;
@@ -482,16 +1023,29 @@ async fn dashboard() -> Html<String> {
 async fn check_cache(
     Path(hash): Path<String>,
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    match state.metadata.exists(&hash) {
+    match entry_visible(&state, &hash, &headers) {
         Ok(true) => {
             let _ = state.metadata.touch(&hash);
-            StatusCode::OK
+            let platform = state.metadata.platform(&hash).ok().flatten();
+            (
+                StatusCode::OK,
+                [
+                    (axum::http::header::ETAG, format!("\"{}\"", hash)),
+                    (axum::http::header::ACCEPT_RANGES, "bytes".to_string()),
+                    (
+                        axum::http::header::HeaderName::from_static("x-memobuild-platform"),
+                        platform.unwrap_or_else(|| "unknown".to_string()),
+                    ),
+                ],
+            )
+                .into_response()
         }
-        Ok(false) => StatusCode::NOT_FOUND,
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
         Err(e) => {
             eprintln!("Error checking cache: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
@@ -499,13 +1053,38 @@ async fn check_cache(
 async fn get_artifact(
     Path(hash): Path<String>,
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
+    let namespace = client_namespace(&headers);
+    match entry_visible(&state, &hash, &headers) {
+        Ok(true) => {}
+        Ok(false) => {
+            let _ = state.metadata.record_miss(namespace);
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        Err(e) => {
+            eprintln!("Error checking cache: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
     match state.storage.get(&hash) {
         Ok(Some(data)) => {
             let _ = state.metadata.touch(&hash);
-            (StatusCode::OK, data).into_response()
+            // A resumed download is the same hit as the request it continues
+            if !headers.contains_key(axum::http::header::RANGE) {
+                let _ = state
+                    .metadata
+                    .record_hit(namespace, &hash, data.len() as u64);
+                if let Some(session) = client_session(&headers) {
+                    state.hints.record(session, &hash);
+                }
+            }
+            blob_response(&hash, data, &headers)
+        }
+        Ok(None) => {
+            let _ = state.metadata.record_miss(namespace);
+            StatusCode::NOT_FOUND.into_response()
         }
-        Ok(None) => StatusCode::NOT_FOUND.into_response(),
         Err(e) => {
             eprintln!("Error getting artifact: {}", e);
             StatusCode::INTERNAL_SERVER_ERROR.into_response()
@@ -516,8 +1095,10 @@ async fn get_artifact(
 async fn put_artifact(
     Path(hash): Path<String>,
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     body: Bytes,
 ) -> impl IntoResponse {
+    let _upload = state.uploads.begin();
     // 1. CAS Verification: Verify hash of the body matches requested hash
     let mut hasher = blake3::Hasher::new();
     hasher.update(&body);
@@ -534,15 +1115,40 @@ async fn put_artifact(
     }
 
     let size = body.len() as u64;
+    if !within_size_limit(&state, &hash, size) {
+        return StatusCode::PAYLOAD_TOO_LARGE;
+    }
+    if !has_room(&state, size) {
+        eprintln!(
+            "❌ Not enough disk space to store {} ({} bytes)",
+            hash, size
+        );
+        return StatusCode::INSUFFICIENT_STORAGE;
+    }
 
     // 2. Store the blob
     match state.storage.put(&hash, &body) {
         Ok(path) => {
-            // 3. Update metadata
-            if let Err(e) = state.metadata.insert(&hash, &path, size) {
+            // 3. Update metadata and log the insertion
+            if let Err(e) = state
+                .metadata
+                .insert(&hash, &path, size)
+                .and_then(|_| tag_entry(&state, &hash, &headers))
+                .and_then(|_| {
+                    state.metadata.append_log(
+                        transparency::KIND_ARTIFACT,
+                        &hash,
+                        size,
+                        client_namespace(&headers),
+                    )
+                })
+            {
                 eprintln!("Error updating metadata: {}", e);
                 return StatusCode::INTERNAL_SERVER_ERROR;
             }
+            let _ = state
+                .metadata
+                .record_upload(client_namespace(&headers), size);
             StatusCode::CREATED
         }
         Err(e) => {
@@ -552,61 +1158,531 @@ async fn put_artifact(
     }
 }
 
+/// Drop a node's entry (and its whole-blob artifact, if any) so the next build
+/// misses and recomputes it. Shared layers stay until GC finds them unused.
+fn remove_entry(state: &AppState, hash: &str) -> StatusCode {
+    // Layered nodes have a cache_entries row too
+    match state.metadata.exists(hash) {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::NOT_FOUND,
+        Err(e) => {
+            eprintln!("Error checking cache: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+    if let Err(e) = state.metadata.delete(hash) {
+        eprintln!("Error invalidating {}: {}", hash, e);
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    if let Err(e) = state.storage.delete(hash) {
+        eprintln!("Error deleting artifact {}: {}", hash, e);
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    println!("🗑️  Invalidated {}", hash);
+    StatusCode::NO_CONTENT
+}
+
+#[derive(Deserialize)]
+pub struct ArtifactListQuery {
+    /// 1-based page number
+    pub page: Option<u64>,
+    pub per_page: Option<u64>,
+    pub older_than_days: Option<u32>,
+    pub unused_for_days: Option<u32>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub namespace: Option<String>,
+    pub pinned: Option<bool>,
+    pub git_commit: Option<String>,
+    pub builder: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ArtifactPage {
+    pub artifacts: Vec<metadata::EntrySummary>,
+    /// Artifacts matching the filters, across all pages
+    pub total: u64,
+    pub page: u64,
+    pub per_page: u64,
+}
+
+/// A page of cache entries, most recently used first. Admin only.
+async fn list_artifacts(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<ArtifactListQuery>,
+) -> Response {
+    if let Err(status) = require_admin(&state, &headers).await {
+        return status.into_response();
+    }
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(crate::constants::DEFAULT_ARTIFACT_PAGE_SIZE)
+        .clamp(1, crate::constants::MAX_ARTIFACT_PAGE_SIZE);
+    let filter = metadata::EntryFilter {
+        older_than_days: query.older_than_days,
+        unused_for_days: query.unused_for_days,
+        min_size: query.min_size,
+        max_size: query.max_size,
+        namespace: query.namespace,
+        pinned: query.pinned,
+        git_commit: query.git_commit,
+        builder: query.builder,
+    };
+    match state
+        .metadata
+        .list_entries(&filter, (page - 1) * per_page, per_page)
+    {
+        Ok((artifacts, total)) => Json(ArtifactPage {
+            artifacts,
+            total,
+            page,
+            per_page,
+        })
+        .into_response(),
+        Err(e) => {
+            eprintln!("Error listing artifacts: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Metadata of one entry with the layers it references. Admin only.
+async fn inspect_artifact(
+    Path(hash): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(status) = require_admin(&state, &headers).await {
+        return status.into_response();
+    }
+    match state.metadata.entry_details(&hash) {
+        Ok(Some(details)) => Json(details).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            eprintln!("Error inspecting {}: {}", hash, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Delete an entry so the step that produced it rebuilds. Admin only.
+async fn delete_artifact(
+    Path(hash): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> StatusCode {
+    if let Err(status) = require_admin(&state, &headers).await {
+        return status;
+    }
+    remove_entry(&state, &hash)
+}
+
+/// Entries a pin request applies to: one cache key or one build's entries.
+#[derive(Deserialize)]
+pub struct PinQuery {
+    pub key: Option<String>,
+    pub build_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PinRequest {
+    pub key: Option<String>,
+    pub build_id: Option<String>,
+    pub label: String,
+}
+
+fn pin_target(
+    key: Option<String>,
+    build_id: Option<String>,
+) -> Result<PinTarget, (StatusCode, String)> {
+    PinTarget::from_parts(key, build_id).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+/// Pin entries under a label so GC never removes them. Admin only.
+async fn pin_artifacts(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(request): Json<PinRequest>,
+) -> Response {
+    if let Err(status) = require_admin(&state, &headers).await {
+        return status.into_response();
+    }
+    let target = match pin_target(request.key, request.build_id) {
+        Ok(target) => target,
+        Err(rejection) => return rejection.into_response(),
+    };
+    if request.label.is_empty() {
+        return (StatusCode::BAD_REQUEST, "The label must not be empty").into_response();
+    }
+    set_pin(&state, &target, Some(&request.label))
+}
+
+/// Unpin entries, leaving them to GC again. Admin only.
+async fn unpin_artifacts(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<PinQuery>,
+) -> Response {
+    if let Err(status) = require_admin(&state, &headers).await {
+        return status.into_response();
+    }
+    match pin_target(query.key, query.build_id) {
+        Ok(target) => set_pin(&state, &target, None),
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+fn set_pin(state: &AppState, target: &PinTarget, label: Option<&str>) -> Response {
+    match state.metadata.set_pin(target, label) {
+        Ok(0) => StatusCode::NOT_FOUND.into_response(),
+        Ok(changed) => Json(serde_json::json!({ "entries": changed })).into_response(),
+        Err(e) => {
+            eprintln!("Error pinning {}: {}", target, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Delete what GC plans for the query, or with `dry_run` only report it,
+/// as the JSON [`GcPlan`](crate::gc::GcPlan). Deleting is admin only.
 async fn gc_cache(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Query(query): Query<GcQuery>,
-) -> impl IntoResponse {
+) -> Response {
+    if !query.dry_run {
+        if let Err(status) = require_admin(&state, &headers).await {
+            return status.into_response();
+        }
+    }
+    let mut policy = crate::gc::GcPolicy {
+        max_age_days: query.days,
+        ..Default::default()
+    };
+    if let Some(max_size_bytes) = query.max_size_bytes {
+        policy.max_size_bytes = max_size_bytes;
+    }
     println!(
-        "🧹 Running Garbage Collection for entries older than {} days",
+        "🧹 {} Garbage Collection for entries older than {} days",
+        if query.dry_run { "Planning" } else { "Running" },
         query.days
     );
 
-    match state.metadata.get_old_entries(query.days) {
+    match crate::gc::plan(state.metadata.as_ref(), &policy) {
+        Ok(mut plan) => {
+            if query.dry_run {
+                plan.dry_run = true;
+            } else {
+                let result =
+                    crate::gc::apply(&plan, state.metadata.as_ref(), state.storage.as_ref());
+                println!(
+                    "🧹 Deleted {} old artifacts and {} unused layers ({})",
+                    result.deleted_artifacts,
+                    result.deleted_layers,
+                    plan.summary()
+                );
+            }
+            (StatusCode::OK, Json(plan)).into_response()
+        }
+        Err(e) => {
+            eprintln!("GC error: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+/// Drop entries stored under key versions other than the server's, once
+/// every client has moved to it. Admin only.
+async fn gc_key_versions(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<KeyVersionGcQuery>,
+) -> Response {
+    if let Err(status) = require_admin(&state, &headers).await {
+        return status.into_response();
+    }
+    let keep = crate::constants::CACHE_KEY_VERSION;
+    if let Some(requested) = query.keep.filter(|&v| v != keep) {
+        // Would delete everything the server's own version can see
+        return (
+            StatusCode::CONFLICT,
+            format!("The server uses key version {}, not {}", keep, requested),
+        )
+            .into_response();
+    }
+    println!("🧹 Removing entries with key versions other than {}", keep);
+
+    match state.metadata.entries_not_at_key_version(keep) {
         Ok(hashes) => {
-            let mut node_count = 0;
+            let mut deleted = 0u64;
             for hash in hashes {
-                if let Ok(Some(_entry)) = state.metadata.get(&hash) {
-                    let _ = state.storage.delete(&hash);
-                    let _ = state.metadata.delete(&hash);
-                    node_count += 1;
+                let _ = state.storage.delete(&hash);
+                if state.metadata.delete(&hash).is_ok() {
+                    deleted += 1;
                 }
             }
+            let body = serde_json::json!({ "deleted": deleted });
+            (StatusCode::OK, Json(body)).into_response()
+        }
+        Err(e) => {
+            eprintln!("GC error: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
 
-            // Also clean up unused layers
-            let mut layer_count = 0;
-            if let Ok(unused_layers) = state.metadata.get_unused_layers() {
-                for (hash, _path) in unused_layers {
-                    let _ = state.storage.delete(&hash);
-                    let _ = state.metadata.delete_layer_metadata(&hash);
-                    layer_count += 1;
-                }
-            }
+async fn key_version_stats(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match state.metadata.key_version_counts() {
+        Ok(entries) => (
+            StatusCode::OK,
+            Json(KeyVersionStats {
+                current: crate::constants::CACHE_KEY_VERSION,
+                entries,
+            }),
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("Error counting key versions: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    /// Days to cover, today included
+    pub days: Option<u32>,
+    /// Only this namespace
+    pub namespace: Option<String>,
+    /// Number of top artifacts to list
+    pub top: Option<usize>,
+}
+
+/// Hits, misses and bytes saved per day and namespace, with the artifacts
+/// that saved the most, to show what the cache is worth.
+async fn usage_stats(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<StatsQuery>,
+) -> impl IntoResponse {
+    let days = query
+        .days
+        .unwrap_or(crate::constants::DEFAULT_STATS_DAYS)
+        .clamp(1, 366);
+    match state
+        .metadata
+        .usage_stats(days, query.namespace.as_deref(), query.top.unwrap_or(10))
+    {
+        Ok(stats) => (StatusCode::OK, Json(stats)).into_response(),
+        Err(e) => {
+            eprintln!("Error computing usage stats: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LogQuery {
+    /// First position to return
+    pub start: Option<u64>,
+    pub limit: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct ConsistencyQuery {
+    /// Log size the client saw before
+    pub from: u64,
+    /// Log size to prove; the current one by default
+    pub to: Option<u64>,
+}
+
+/// A page of the transparency log, with the current head.
+async fn log_entries(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<LogQuery>,
+) -> impl IntoResponse {
+    let limit = query
+        .limit
+        .unwrap_or(crate::constants::LOG_PAGE_SIZE)
+        .clamp(1, crate::constants::LOG_PAGE_SIZE);
+    let page = state.metadata.log_head(None).and_then(|head| {
+        let entries = state
+            .metadata
+            .log_entries(query.start.unwrap_or(0), limit)?;
+        Ok(serde_json::json!({ "head": head, "entries": entries }))
+    });
+    match page {
+        Ok(page) => (StatusCode::OK, Json(page)).into_response(),
+        Err(e) => {
+            eprintln!("Error reading transparency log: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn log_head(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match state.metadata.log_head(None) {
+        Ok(head) => (StatusCode::OK, Json(head)).into_response(),
+        Err(e) => {
+            eprintln!("Error reading transparency log: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// The entries that take the log from size `from` to size `to`, with both
+/// heads, for clients to check nothing before `from` was rewritten.
+async fn log_consistency(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ConsistencyQuery>,
+) -> impl IntoResponse {
+    let proof = (|| -> Result<Option<ConsistencyProof>> {
+        let Some(to) = state.metadata.log_head(query.to)? else {
+            return Ok(None);
+        };
+        if query.from > to.size {
+            return Ok(None);
+        }
+        let Some(from) = state.metadata.log_head(Some(query.from))? else {
+            return Ok(None);
+        };
+        let entries = state.metadata.log_entries(from.size, to.size - from.size)?;
+        Ok(Some(ConsistencyProof { from, to, entries }))
+    })();
+    match proof {
+        Ok(Some(proof)) => (StatusCode::OK, Json(proof)).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            eprintln!("Error building consistency proof: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
 
+/// Recompute the whole chain from the stored entries. A broken link means
+/// the log was edited outside the server.
+async fn log_verify(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let result = (|| -> Result<LogHead> {
+        let head = state
+            .metadata
+            .log_head(None)?
+            .unwrap_or_else(LogHead::empty);
+        let mut verified = LogHead::empty();
+        while verified.size < head.size {
+            let entries = state
+                .metadata
+                .log_entries(verified.size, crate::constants::LOG_PAGE_SIZE)?;
+            let Some(last) = entries.last() else {
+                anyhow::bail!(
+                    "Log ends at {} entries, expected {}",
+                    verified.size,
+                    head.size
+                );
+            };
+            let next = LogHead {
+                size: last.seq + 1,
+                head: last.chain.clone(),
+            };
+            transparency::verify_chain(&verified, &next, &entries)?;
+            verified = next;
+        }
+        Ok(verified)
+    })();
+    match result {
+        Ok(head) => (
+            StatusCode::OK,
+            Json(serde_json::json!({ "valid": true, "size": head.size, "head": head.head })),
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("❌ Transparency log verification failed: {}", e);
             (
-                StatusCode::OK,
-                format!(
-                    "Deleted {} old artifacts and {} unused layers",
-                    node_count, layer_count
-                ),
+                StatusCode::CONFLICT,
+                Json(serde_json::json!({ "valid": false, "error": e.to_string() })),
             )
+                .into_response()
         }
+    }
+}
+
+/// Entry counts per platform, plus the entries of one platform when asked
+/// with `?platform=os/arch`.
+async fn platform_stats(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<PlatformQuery>,
+) -> impl IntoResponse {
+    let stats = state.metadata.platform_counts().and_then(|entries| {
+        let hashes = match query.platform {
+            Some(ref platform) => Some(state.metadata.entries_for_platform(platform)?),
+            None => None,
+        };
+        Ok(PlatformStats { entries, hashes })
+    });
+    match stats {
+        Ok(stats) => (StatusCode::OK, Json(stats)).into_response(),
         Err(e) => {
-            eprintln!("GC error: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+            eprintln!("Error counting platforms: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
 
+/// Health for load balancers and monitoring: 200 while the metadata store
+/// answers and the volume has room above the free-space margin, 503 otherwise.
+async fn healthz(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let available = state.storage.available_space();
+    let low_space = available.is_some_and(|bytes| bytes < state.min_free_bytes);
+    let stats = match state
+        .metadata
+        .storage_stats(crate::constants::DEFAULT_GC_DAYS)
+    {
+        Ok(stats) => Some(stats),
+        Err(e) => {
+            eprintln!("Health check: metadata store error: {}", e);
+            None
+        }
+    };
+
+    let healthy = stats.is_some() && !low_space;
+    let body = serde_json::json!({
+        "status": if healthy { "ok" } else { "degraded" },
+        "storage": {
+            "available_bytes": available,
+            "min_free_bytes": state.min_free_bytes,
+            "max_artifact_bytes": state.max_artifact_bytes,
+            "used_bytes": stats.as_ref().map(|s| s.used_bytes),
+            "low_space": low_space,
+        },
+        "metadata": {
+            "ok": stats.is_some(),
+            "entries": stats.as_ref().map(|s| s.entries),
+        },
+        "gc": {
+            "backlog_entries": stats.as_ref().map(|s| s.gc_backlog_entries),
+            "backlog_layers": stats.as_ref().map(|s| s.gc_backlog_layers),
+        },
+        "uploads_in_flight": state.uploads.in_flight(),
+    });
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(body))
+}
+
 async fn gc_status() -> impl IntoResponse {
     let gc = crate::gc::GarbageCollector::from_env();
     let status = gc.status().await;
     (StatusCode::OK, Json(status)).into_response()
 }
 
-async fn metrics_handler() -> impl IntoResponse {
+async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     let registry = crate::metrics::metrics_registry();
     let metrics = registry.read().await;
-    let output = metrics.encode();
+    let mut output = metrics.encode();
+    output.push_str(&state.validator.encode_metrics());
     (
         StatusCode::OK,
         axum::response::AppendHeaders([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")]),
@@ -618,6 +1694,9 @@ async fn check_layer(
     Path(hash): Path<String>,
     State(state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
+    if state.quarantine.contains(&hash) {
+        return StatusCode::NOT_FOUND;
+    }
     match state.metadata.layer_exists(&hash) {
         Ok(true) => StatusCode::OK,
         Ok(false) => StatusCode::NOT_FOUND,
@@ -631,9 +1710,13 @@ async fn check_layer(
 async fn get_layer(
     Path(hash): Path<String>,
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
+    if state.quarantine.contains(&hash) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
     match state.storage.get(&hash) {
-        Ok(Some(data)) => (StatusCode::OK, data).into_response(),
+        Ok(Some(data)) => blob_response(&hash, data, &headers),
         Ok(None) => StatusCode::NOT_FOUND.into_response(),
         Err(e) => {
             eprintln!("Error getting layer: {}", e);
@@ -645,8 +1728,10 @@ async fn get_layer(
 async fn put_layer(
     Path(hash): Path<String>,
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     body: Bytes,
 ) -> impl IntoResponse {
+    let _upload = state.uploads.begin();
     // CAS Verification: Strict enforcement for layer integrity
     let mut hasher = blake3::Hasher::new();
     hasher.update(&body);
@@ -663,12 +1748,36 @@ async fn put_layer(
     }
 
     let size = body.len() as u64;
+    if !has_room(&state, size) {
+        eprintln!(
+            "❌ Not enough disk space to store layer {} ({} bytes)",
+            hash, size
+        );
+        return StatusCode::INSUFFICIENT_STORAGE;
+    }
     match state.storage.put(&hash, &body) {
         Ok(path) => {
-            if let Err(e) = state.metadata.insert_layer(&hash, &path, size) {
+            if let Err(e) = state
+                .metadata
+                .insert_layer(&hash, &path, size)
+                .and_then(|_| {
+                    state.metadata.append_log(
+                        transparency::KIND_LAYER,
+                        &hash,
+                        size,
+                        client_namespace(&headers),
+                    )
+                })
+            {
                 eprintln!("Error updating layer metadata: {}", e);
                 return StatusCode::INTERNAL_SERVER_ERROR;
             }
+            let _ = state
+                .metadata
+                .record_upload(client_namespace(&headers), size);
+            if state.quarantine.release(&hash) {
+                println!("🩹 Layer {} replaced by a good copy", hash);
+            }
             StatusCode::CREATED
         }
         Err(e) => {
@@ -678,6 +1787,63 @@ async fn put_layer(
     }
 }
 
+/// A client found layer `hash` missing or corrupt. Hash the stored copy and
+/// quarantine it if it is bad; see [`quarantine`].
+async fn report_corrupt_layer(
+    Path(hash): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(report): Json<quarantine::CorruptionReport>,
+) -> impl IntoResponse {
+    if !state.quarantine.contains(&hash) {
+        let data = match state.storage.get(&hash) {
+            Ok(Some(data)) => data,
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Err(e) => {
+                eprintln!("Error getting layer: {}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        let actual = blake3::hash(&data).to_hex().to_string();
+        if actual != hash {
+            eprintln!(
+                "☣️  Layer {} is stored with digest {} (reported for {}); quarantined",
+                hash,
+                actual,
+                report.key.as_deref().unwrap_or("unknown artifact")
+            );
+            if let Err(e) = state.storage.delete(&hash) {
+                eprintln!("Error dropping layer: {}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+            state.quarantine.add(&hash, &actual, &report);
+        }
+    }
+    let quarantined = state.quarantine.contains(&hash);
+    Json(serde_json::json!({ "quarantined": quarantined })).into_response()
+}
+
+async fn list_quarantine(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(state.quarantine.list())
+}
+
+async fn validation_status(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(state.validator.status())
+}
+
+/// Run a validation pass now; see [`validator`]. Admin only.
+async fn run_validation(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Err(status) = require_admin(&state, &headers).await {
+        return status.into_response();
+    }
+    match state.validator.run_pass(&state).await {
+        Ok(pass) => Json(pass).into_response(),
+        Err(e) => {
+            eprintln!("Validation pass failed: {:#}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct RegisterLayersRequest {
     pub layers: Vec<String>,
@@ -687,11 +1853,18 @@ pub struct RegisterLayersRequest {
 async fn register_node_layers(
     Path(hash): Path<String>,
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Json(payload): Json<RegisterLayersRequest>,
 ) -> impl IntoResponse {
+    let _upload = state.uploads.begin();
+    // Layers are small chunks; the whole artifact's size is only known here
+    if !within_size_limit(&state, &hash, payload.total_size) {
+        return StatusCode::PAYLOAD_TOO_LARGE;
+    }
     match state
         .metadata
         .insert_layered_node(&hash, payload.total_size, &payload.layers)
+        .and_then(|_| tag_entry(&state, &hash, &headers))
     {
         Ok(_) => StatusCode::OK,
         Err(e) => {
@@ -704,9 +1877,33 @@ async fn register_node_layers(
 async fn get_node_layers(
     Path(hash): Path<String>,
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
+    match entry_visible(&state, &hash, &headers) {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            eprintln!("Error getting node layers: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
     match state.metadata.get_node_layers(&hash) {
-        Ok(Some(layers)) => (StatusCode::OK, Json(layers)).into_response(),
+        Ok(Some(layers)) => {
+            // The client fetches the layers next; count the whole node once
+            let size = state
+                .metadata
+                .get(&hash)
+                .ok()
+                .flatten()
+                .map_or(0, |e| e.size);
+            let _ = state
+                .metadata
+                .record_hit(client_namespace(&headers), &hash, size);
+            if let Some(session) = client_session(&headers) {
+                state.hints.record(session, &hash);
+            }
+            (StatusCode::OK, Json(layers)).into_response()
+        }
         Ok(None) => StatusCode::NOT_FOUND.into_response(),
         Err(e) => {
             eprintln!("Error getting node layers: {}", e);
@@ -715,6 +1912,32 @@ async fn get_node_layers(
     }
 }
 
+#[derive(Deserialize)]
+pub struct HintQuery {
+    pub limit: Option<usize>,
+}
+
+/// Artifacts other builds fetched together with `hash`, most often first,
+/// limited to those this client could fetch.
+async fn prefetch_hints(
+    Path(hash): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<HintQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let limit = query
+        .limit
+        .unwrap_or(crate::constants::DEFAULT_PREFETCH_HINTS)
+        .min(crate::constants::MAX_PREFETCH_HINTS);
+    let hints: Vec<String> = state
+        .hints
+        .siblings(&hash, limit)
+        .into_iter()
+        .filter(|h| entry_visible(&state, h, &headers).unwrap_or(false))
+        .collect();
+    Json(hints)
+}
+
 async fn report_analytics(
     State(state): State<Arc<AppState>>,
     axum::Json(data): axum::Json<AnalyticsData>,
@@ -754,3 +1977,167 @@ async fn report_analytics(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::HttpBody;
+    use axum::http::header::{CONTENT_RANGE, ETAG, IF_NONE_MATCH, IF_RANGE, RANGE};
+
+    #[test]
+    fn test_upload_tracker() {
+        let tracker = UploadTracker::default();
+        let first = tracker.begin();
+        let second = tracker.begin();
+        assert_eq!(tracker.in_flight(), 2);
+        drop(first);
+        assert_eq!(tracker.in_flight(), 1);
+        drop(second);
+        assert_eq!(tracker.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-3", 10), ByteRange::Partial(0, 3));
+        assert_eq!(parse_range("bytes=4-", 10), ByteRange::Partial(4, 9));
+        assert_eq!(parse_range("bytes=-3", 10), ByteRange::Partial(7, 9));
+        assert_eq!(parse_range("bytes=5-100", 10), ByteRange::Partial(5, 9));
+        assert_eq!(parse_range("bytes=10-", 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), ByteRange::Full);
+        assert_eq!(parse_range("items=0-1", 10), ByteRange::Full);
+    }
+
+    #[tokio::test]
+    async fn test_blob_response_conditional_and_range() {
+        let data = b"0123456789".to_vec();
+        let mut headers = HeaderMap::new();
+        let full = blob_response("abc", data.clone(), &headers);
+        assert_eq!(full.status(), StatusCode::OK);
+        assert_eq!(full.headers()[ETAG], "\"abc\"");
+
+        headers.insert(IF_NONE_MATCH, "\"abc\"".parse().unwrap());
+        let not_modified = blob_response("abc", data.clone(), &headers);
+        assert_eq!(not_modified.status(), StatusCode::NOT_MODIFIED);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, "bytes=6-".parse().unwrap());
+        headers.insert(IF_RANGE, "\"abc\"".parse().unwrap());
+        let partial = blob_response("abc", data.clone(), &headers);
+        assert_eq!(partial.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(partial.headers()[CONTENT_RANGE], "bytes 6-9/10");
+        let body = partial.into_body().data().await.unwrap().unwrap();
+        assert_eq!(&body[..], b"6789");
+
+        // A stale validator gets the whole blob back
+        headers.insert(IF_RANGE, "\"other\"".parse().unwrap());
+        let stale = blob_response("abc", data, &headers);
+        assert_eq!(stale.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_deleting_entries_needs_admin() {
+        use crate::storage::ArtifactStorage;
+
+        let server = crate::server::test_util::TestServer::with_admin_token("admin")
+            .await
+            .unwrap();
+        let hash = blake3::hash(b"artifact").to_hex().to_string();
+        server.storage().put(&hash, b"artifact").unwrap();
+        server.metadata().insert(&hash, &hash, 8).unwrap();
+        let client = reqwest::Client::new();
+
+        let open = client
+            .delete(format!("{}/cache/{}", server.url(), hash))
+            .send()
+            .await
+            .unwrap();
+        assert!(!open.status().is_success());
+        let anonymous = client
+            .delete(format!("{}/api/v1/artifacts/{}", server.url(), hash))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(anonymous.status(), reqwest::StatusCode::UNAUTHORIZED);
+        assert!(server.metadata().exists(&hash).unwrap());
+
+        let admin = client
+            .delete(format!("{}/api/v1/artifacts/{}", server.url(), hash))
+            .bearer_auth("admin")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(admin.status(), reqwest::StatusCode::NO_CONTENT);
+        assert!(!server.metadata().exists(&hash).unwrap());
+        assert!(server.storage().get(&hash).unwrap().is_none());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_gc_deletes_only_for_admin() {
+        let server = crate::server::test_util::TestServer::with_admin_token("admin")
+            .await
+            .unwrap();
+        let client = reqwest::Client::new();
+        let url = format!("{}/gc", server.url());
+
+        let plan = client
+            .post(&url)
+            .query(&[("days", "0"), ("max_size_bytes", "0"), ("dry_run", "true")])
+            .send()
+            .await
+            .unwrap();
+        assert!(plan.status().is_success());
+        let anonymous = client
+            .post(&url)
+            .query(&[("days", "0"), ("max_size_bytes", "0")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(anonymous.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let admin = client
+            .post(&url)
+            .query(&[("days", "0"), ("max_size_bytes", "0")])
+            .bearer_auth("admin")
+            .send()
+            .await
+            .unwrap();
+        assert!(admin.status().is_success());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_key_version_gc_needs_admin_and_current_version() {
+        let server = crate::server::test_util::TestServer::with_admin_token("admin")
+            .await
+            .unwrap();
+        let hash = blake3::hash(b"old entry").to_hex().to_string();
+        server.metadata().insert(&hash, &hash, 9).unwrap();
+        let client = reqwest::Client::new();
+        let url = format!("{}/gc/versions", server.url());
+
+        let anonymous = client.post(&url).send().await.unwrap();
+        assert_eq!(anonymous.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let other = client
+            .post(&url)
+            .query(&[("keep", crate::constants::CACHE_KEY_VERSION + 1)])
+            .bearer_auth("admin")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(other.status(), reqwest::StatusCode::CONFLICT);
+        assert!(server.metadata().exists(&hash).unwrap());
+
+        let admin = client
+            .post(&url)
+            .query(&[("keep", crate::constants::CACHE_KEY_VERSION)])
+            .bearer_auth("admin")
+            .send()
+            .await
+            .unwrap();
+        assert!(admin.status().is_success());
+        // Stored without a version header, so under version 0
+        assert!(!server.metadata().exists(&hash).unwrap());
+        server.stop().await;
+    }
+}
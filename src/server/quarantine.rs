@@ -0,0 +1,111 @@
+//! Quarantine of layers that clients reported corrupt and whose stored copy
+//! does not match its digest either.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// What a client sends about a layer that failed its check.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CorruptionReport {
+    /// Cache key of the artifact the layer was fetched for
+    #[serde(default)]
+    pub key: Option<String>,
+    /// Digest of what the client received
+    #[serde(default)]
+    pub actual: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QuarantinedLayer {
+    pub hash: String,
+    /// Digest of the server's bad copy
+    pub actual: String,
+    /// Artifact the first report was about
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// RFC 3339 time of the first report
+    pub quarantined_at: String,
+    pub reports: u64,
+}
+
+#[derive(Default)]
+pub struct Quarantine {
+    layers: Mutex<BTreeMap<String, QuarantinedLayer>>,
+}
+
+impl Quarantine {
+    /// Quarantine `hash`, whose stored copy hashes to `actual`.
+    pub fn add(&self, hash: &str, actual: &str, report: &CorruptionReport) {
+        self.layers
+            .lock()
+            .unwrap()
+            .entry(hash.to_string())
+            .or_insert_with(|| QuarantinedLayer {
+                hash: hash.to_string(),
+                actual: actual.to_string(),
+                key: report.key.clone(),
+                quarantined_at: crate::cache::clock::stamp_rfc3339(),
+                reports: 0,
+            })
+            .reports += 1;
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.layers.lock().unwrap().contains_key(hash)
+    }
+
+    /// Lift the quarantine of `hash` once a good copy was stored. Returns
+    /// whether it was quarantined.
+    pub fn release(&self, hash: &str) -> bool {
+        self.layers.lock().unwrap().remove(hash).is_some()
+    }
+
+    pub fn list(&self) -> Vec<QuarantinedLayer> {
+        self.layers.lock().unwrap().values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::integrity::RejectedArtifact;
+    use crate::cache::RemoteCache;
+    use crate::server::test_util::TestServer;
+    use crate::storage::ArtifactStorage;
+
+    #[tokio::test]
+    async fn test_reported_bad_layer_is_quarantined() {
+        let server = TestServer::start().await.unwrap();
+        let remote = crate::cache::HttpRemoteCache::new(server.url());
+        let good = b"layer".to_vec();
+        let hash = blake3::hash(&good).to_hex().to_string();
+        server.storage().put(&hash, b"bit rot").unwrap();
+        server.metadata().insert_layer(&hash, &hash, 7).unwrap();
+        let rejected = RejectedArtifact {
+            key: "node".into(),
+            expected: hash.clone(),
+            actual: blake3::hash(b"bit rot").to_hex().to_string(),
+        };
+
+        assert!(remote.report_corrupt_layer(&rejected).await.unwrap());
+        assert!(server.state().quarantine.contains(&hash));
+        assert!(!remote.has_layer(&hash).await.unwrap());
+        assert_eq!(
+            server.state().quarantine.list()[0].key.as_deref(),
+            Some("node")
+        );
+
+        // A good upload lifts it, and a copy that checks out is not quarantined
+        let upload = reqwest::Client::new()
+            .put(format!("{}/cache/layer/{}", server.url(), hash))
+            .body(good)
+            .send()
+            .await
+            .unwrap();
+        assert!(upload.status().is_success());
+        assert!(remote.has_layer(&hash).await.unwrap());
+        assert!(!remote.report_corrupt_layer(&rejected).await.unwrap());
+        assert!(server.state().quarantine.list().is_empty());
+        server.stop().await;
+    }
+}
@@ -0,0 +1,166 @@
+//! In-process cache server for tests, serving the routes of `memobuild server`
+//! on an ephemeral localhost port with blobs and metadata in memory.
+
+use crate::server::metadata::{MetadataBackend, MetadataStore};
+use crate::server::{router, AppState, UploadTracker};
+use crate::storage::MemoryStorage;
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, oneshot};
+use tokio::task::JoinHandle;
+
+/// A cache server running on a task of the current runtime. It stops when
+/// [`TestServer::stop`] is called or it is dropped.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use memobuild::server::test_util::TestServer;
+///
+/// let server = TestServer::start().await?;
+/// let resp = reqwest::get(format!("{}/healthz", server.url())).await?;
+/// assert!(resp.status().is_success());
+/// server.stop().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TestServer {
+    addr: SocketAddr,
+    state: Arc<AppState>,
+    storage: Arc<MemoryStorage>,
+    stop: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    /// Start a server without an admin token: admin routes answer `401`.
+    pub async fn start() -> Result<Self> {
+        Self::start_with(None).await
+    }
+
+    /// Start a server that accepts `token` as an admin token.
+    pub async fn with_admin_token(token: &str) -> Result<Self> {
+        Self::start_with(Some(token.to_string())).await
+    }
+
+    async fn start_with(admin_token: Option<String>) -> Result<Self> {
+        let storage = Arc::new(MemoryStorage::new());
+        let (tx_events, _) = broadcast::channel(crate::constants::MAX_WS_BROADCAST_CAPACITY);
+        let state = Arc::new(AppState {
+            metadata: Box::new(MetadataStore::in_memory()?),
+            storage: storage.clone(),
+            webhook_url: None,
+            tx_events,
+            current_dag: Arc::new(std::sync::Mutex::new(None)),
+            auth_state: Arc::new(crate::auth::AuthState::new(admin_token, None)),
+            uploads: UploadTracker::default(),
+            min_free_bytes: 0,
+            max_artifact_bytes: None,
+            hints: Default::default(),
+            quarantine: Default::default(),
+            validator: Default::default(),
+        });
+
+        // Port 0 lets the OS pick a free port, so tests can run in parallel
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0))?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let server = axum::Server::from_tcp(listener)?
+            .serve(router(state.clone()).into_make_service())
+            .with_graceful_shutdown(async {
+                let _ = stop_rx.await;
+            });
+        let task = tokio::spawn(async move {
+            if let Err(e) = server.await {
+                eprintln!("Test cache server failed: {}", e);
+            }
+        });
+
+        Ok(Self {
+            addr,
+            state,
+            storage,
+            stop: Some(stop_tx),
+            task: Some(task),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Base URL to point clients at, e.g. `http://127.0.0.1:41234`.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// The server's state, to inspect or seed it directly.
+    pub fn state(&self) -> &Arc<AppState> {
+        &self.state
+    }
+
+    pub fn metadata(&self) -> &dyn MetadataBackend {
+        self.state.metadata.as_ref()
+    }
+
+    /// Blobs the server stored.
+    pub fn storage(&self) -> &MemoryStorage {
+        &self.storage
+    }
+
+    /// Stop accepting connections and wait for in-flight requests.
+    pub async fn stop(mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::ArtifactStorage;
+
+    #[tokio::test]
+    async fn test_server_round_trip() {
+        let server = TestServer::start().await.unwrap();
+        let client = reqwest::Client::new();
+        let data = b"artifact".to_vec();
+        let hash = blake3::hash(&data).to_hex().to_string();
+        let url = format!("{}/cache/{}", server.url(), hash);
+
+        let missing = client.head(&url).send().await.unwrap();
+        assert_eq!(missing.status(), reqwest::StatusCode::NOT_FOUND);
+
+        let stored = client.put(&url).body(data.clone()).send().await.unwrap();
+        assert!(stored.status().is_success());
+        assert!(server.storage().exists(&hash).unwrap());
+        assert_eq!(server.storage().len(), 1);
+
+        let fetched = client.get(&url).send().await.unwrap();
+        assert_eq!(fetched.bytes().await.unwrap().to_vec(), data);
+
+        // Admin routes need a token this server doesn't have
+        let delete = client
+            .delete(format!("{}/api/v1/artifacts/{}", server.url(), hash))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(delete.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        server.stop().await;
+    }
+}
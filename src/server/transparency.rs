@@ -0,0 +1,238 @@
+//! Transparency log of cache writes, a hash chain of every artifact and layer
+//! stored for the first time.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Head of the empty log
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Kinds of write the log records
+pub const KIND_ARTIFACT: &str = "artifact";
+pub const KIND_LAYER: &str = "layer";
+
+/// File in the local cache directory remembering the last verified head of
+/// each server's log
+pub const TRUSTED_HEADS_FILE: &str = "log-heads.json";
+
+/// One insertion into the cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// Position in the log, from 0
+    pub seq: u64,
+    /// `artifact` or `layer`
+    pub kind: String,
+    /// Content hash the blob was stored under
+    pub hash: String,
+    pub size: u64,
+    /// Namespace of the client that uploaded it
+    pub namespace: String,
+    /// RFC 3339 time of the insertion
+    pub logged_at: String,
+    /// Chain hash up to and including this entry
+    pub chain: String,
+}
+
+impl LogEntry {
+    /// Hash of the entry's own fields, without the chain.
+    pub fn leaf_hash(&self) -> String {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(b"memobuild-log-v1\0");
+        for field in [
+            self.seq.to_string().as_str(),
+            &self.kind,
+            &self.hash,
+            &self.size.to_string(),
+            &self.namespace,
+            &self.logged_at,
+        ] {
+            hasher.update(field.as_bytes());
+            hasher.update(b"\0");
+        }
+        hasher.finalize().to_hex().to_string()
+    }
+}
+
+/// Chain hash of an entry whose leaf hash is `leaf`, following `prev`.
+pub fn chain_hash(prev: &str, leaf: &str) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(prev.as_bytes());
+    hasher.update(leaf.as_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Size of the log and the chain hash of its last entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogHead {
+    pub size: u64,
+    pub head: String,
+}
+
+impl LogHead {
+    pub fn empty() -> Self {
+        Self {
+            size: 0,
+            head: GENESIS.to_string(),
+        }
+    }
+}
+
+/// Proof that the log at `to` extends the log at `from`: the entries
+/// appended in between.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConsistencyProof {
+    pub from: LogHead,
+    pub to: LogHead,
+    pub entries: Vec<LogEntry>,
+}
+
+/// Check that `entries` follow `from` in order, each chained correctly, and
+/// end at `to`.
+pub fn verify_chain(from: &LogHead, to: &LogHead, entries: &[LogEntry]) -> Result<()> {
+    if to.size < from.size {
+        anyhow::bail!("Log shrank from {} to {} entries", from.size, to.size);
+    }
+    if entries.len() as u64 != to.size - from.size {
+        anyhow::bail!(
+            "Expected {} entries between sizes {} and {}, got {}",
+            to.size - from.size,
+            from.size,
+            to.size,
+            entries.len()
+        );
+    }
+    let mut head = from.head.clone();
+    for (offset, entry) in entries.iter().enumerate() {
+        let seq = from.size + offset as u64;
+        if entry.seq != seq {
+            anyhow::bail!("Entry {} is out of place (expected {})", entry.seq, seq);
+        }
+        head = chain_hash(&head, &entry.leaf_hash());
+        if head != entry.chain {
+            anyhow::bail!(
+                "Entry {} ({}) does not chain onto its predecessor",
+                seq,
+                entry.hash
+            );
+        }
+    }
+    if head != to.head {
+        anyhow::bail!(
+            "Log head {} at size {} does not extend {} at size {}",
+            to.head,
+            to.size,
+            from.head,
+            from.size
+        );
+    }
+    Ok(())
+}
+
+/// Check a proof fetched from the server against the head the caller saw
+/// before. Its `from` must be that head, not one the server substituted.
+pub fn verify_consistency(trusted: &LogHead, proof: &ConsistencyProof) -> Result<()> {
+    if proof.from != *trusted {
+        anyhow::bail!(
+            "Server reports head {} at size {}, but {} was seen before",
+            proof.from.head,
+            proof.from.size,
+            trusted.head
+        );
+    }
+    verify_chain(&proof.from, &proof.to, &proof.entries)
+}
+
+/// Log heads a client has verified, keyed by server URL.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrustedHeads {
+    #[serde(default)]
+    pub heads: BTreeMap<String, LogHead>,
+}
+
+impl TrustedHeads {
+    pub fn path_in(cache_dir: &Path) -> PathBuf {
+        cache_dir.join(TRUSTED_HEADS_FILE)
+    }
+
+    /// Load the heads, starting empty if none were recorded yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn append(log: &mut Vec<LogEntry>, hash: &str) {
+        let prev = log
+            .last()
+            .map(|e| e.chain.clone())
+            .unwrap_or_else(|| GENESIS.to_string());
+        let mut entry = LogEntry {
+            seq: log.len() as u64,
+            kind: KIND_ARTIFACT.into(),
+            hash: hash.into(),
+            size: 10,
+            namespace: "default".into(),
+            logged_at: "2024-01-01T00:00:00+00:00".into(),
+            chain: String::new(),
+        };
+        entry.chain = chain_hash(&prev, &entry.leaf_hash());
+        log.push(entry);
+    }
+
+    fn head(log: &[LogEntry]) -> LogHead {
+        LogHead {
+            size: log.len() as u64,
+            head: log.last().map(|e| e.chain.clone()).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_consistency_proofs() {
+        let mut log = Vec::new();
+        append(&mut log, "a");
+        append(&mut log, "b");
+        let seen = head(&log);
+        append(&mut log, "c");
+        append(&mut log, "d");
+
+        let proof = ConsistencyProof {
+            from: seen.clone(),
+            to: head(&log),
+            entries: log[2..].to_vec(),
+        };
+        verify_consistency(&seen, &proof).unwrap();
+        verify_chain(&LogHead::empty(), &head(&log), &log).unwrap();
+
+        // An artifact swapped after the fact breaks the chain
+        let mut tampered = proof.clone();
+        tampered.entries[0].hash = "evil".into();
+        assert!(verify_consistency(&seen, &tampered).is_err());
+
+        // So does rewriting history before the head the client saw
+        let mut rewritten = Vec::new();
+        append(&mut rewritten, "a");
+        append(&mut rewritten, "evil");
+        append(&mut rewritten, "c");
+        let forged = ConsistencyProof {
+            from: head(&rewritten[..2]),
+            to: head(&rewritten),
+            entries: rewritten[2..].to_vec(),
+        };
+        assert!(verify_consistency(&seen, &forged).is_err());
+    }
+}
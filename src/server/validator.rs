@@ -0,0 +1,416 @@
+//! Background validation of stored blobs against their BLAKE3 digests, every
+//! `MEMOBUILD_VALIDATE_INTERVAL_MINS`.
+
+use crate::cache::integrity::{layer_matches, MISSING};
+use crate::cache::RemoteCache;
+use crate::server::quarantine::CorruptionReport;
+use crate::server::AppState;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What became of a corrupt blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Repair {
+    /// A good copy was fetched from a mirror
+    Repaired,
+    /// No mirror had one; the blob was dropped
+    Deleted,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    pub hash: String,
+    pub layer: bool,
+    /// Digest of the stored copy, or `missing`
+    pub actual: String,
+    pub action: Repair,
+    /// Mirror the good copy came from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<String>,
+    /// RFC 3339 time of the finding
+    pub at: String,
+}
+
+/// Outcome of one pass.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ValidationPass {
+    /// RFC 3339 time the pass started
+    pub started_at: String,
+    pub checked: u64,
+    pub corrupt: u64,
+    pub repaired: u64,
+    pub deleted: u64,
+    /// Blobs storage failed to read, left for the next round
+    pub errors: u64,
+    pub duration_ms: u64,
+}
+
+/// Totals since the server started, with the latest findings.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ValidationStatus {
+    pub passes: u64,
+    pub checked: u64,
+    pub corrupt: u64,
+    pub repaired: u64,
+    pub deleted: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_pass: Option<ValidationPass>,
+    /// Most recent first
+    pub findings: VecDeque<Finding>,
+}
+
+pub struct Validator {
+    mirrors: Vec<(String, Arc<dyn RemoteCache>)>,
+    /// Blobs hashed per pass; 0 checks every blob
+    sample: usize,
+    /// Position in the sorted blob list the next pass starts at
+    cursor: Mutex<usize>,
+    /// Held for a whole pass, so a pass started through the API does not
+    /// overlap the scheduled one
+    running: tokio::sync::Mutex<()>,
+    status: Mutex<ValidationStatus>,
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Self::new(Vec::new(), crate::constants::DEFAULT_VALIDATE_SAMPLE)
+    }
+}
+
+impl Validator {
+    pub fn new(mirrors: Vec<(String, Arc<dyn RemoteCache>)>, sample: usize) -> Self {
+        Self {
+            mirrors,
+            sample,
+            cursor: Mutex::new(0),
+            running: tokio::sync::Mutex::new(()),
+            status: Mutex::new(ValidationStatus::default()),
+        }
+    }
+
+    /// A validator configured by `MEMOBUILD_VALIDATE_SAMPLE` and
+    /// `MEMOBUILD_VALIDATE_MIRRORS`, a comma-separated list of remote URLs.
+    pub fn from_env() -> Result<Self> {
+        let sample = match std::env::var("MEMOBUILD_VALIDATE_SAMPLE") {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .context("Invalid MEMOBUILD_VALIDATE_SAMPLE")?,
+            Err(_) => crate::constants::DEFAULT_VALIDATE_SAMPLE,
+        };
+        let mut mirrors = Vec::new();
+        if let Ok(urls) = std::env::var("MEMOBUILD_VALIDATE_MIRRORS") {
+            for url in urls.split(',').map(str::trim).filter(|url| !url.is_empty()) {
+                let remote = crate::cache::remote_from_url(url)
+                    .with_context(|| format!("Invalid validation mirror {}", url))?;
+                mirrors.push((url.to_string(), remote));
+            }
+        }
+        Ok(Self::new(mirrors, sample))
+    }
+
+    /// Time between scheduled passes from `MEMOBUILD_VALIDATE_INTERVAL_MINS`;
+    /// `None` when set to 0.
+    pub fn interval_from_env() -> Option<Duration> {
+        let mins = std::env::var("MEMOBUILD_VALIDATE_INTERVAL_MINS")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(crate::constants::DEFAULT_VALIDATE_INTERVAL_MINS);
+        (mins > 0).then(|| Duration::from_secs(mins * 60))
+    }
+
+    pub fn status(&self) -> ValidationStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Hash the next sample of blobs in `state`'s storage, repairing or
+    /// dropping the corrupt ones.
+    pub async fn run_pass(&self, state: &AppState) -> Result<ValidationPass> {
+        let _running = self.running.lock().await;
+        let started = Instant::now();
+        let mut pass = ValidationPass {
+            started_at: crate::cache::clock::stamp_rfc3339(),
+            ..Default::default()
+        };
+
+        let objects = state.metadata.storage_objects()?;
+        for hash in self.next_sample(&objects) {
+            // Known bad; the next upload of the layer replaces it
+            if state.quarantine.contains(hash) {
+                continue;
+            }
+            pass.checked += 1;
+            let actual = match state.storage.get(hash) {
+                Ok(Some(data)) if layer_matches(hash, &data) => continue,
+                Ok(Some(data)) => blake3::hash(&data).to_hex().to_string(),
+                Ok(None) => MISSING.to_string(),
+                Err(e) => {
+                    eprintln!("⚠️  Validator could not read {}: {:#}", hash, e);
+                    pass.errors += 1;
+                    continue;
+                }
+            };
+            pass.corrupt += 1;
+            let finding = self.handle_corrupt(state, hash, actual).await?;
+            match finding.action {
+                Repair::Repaired => pass.repaired += 1,
+                Repair::Deleted => pass.deleted += 1,
+            }
+            self.record(finding);
+        }
+
+        pass.duration_ms = started.elapsed().as_millis() as u64;
+        let mut status = self.status.lock().unwrap();
+        status.passes += 1;
+        status.checked += pass.checked;
+        status.corrupt += pass.corrupt;
+        status.repaired += pass.repaired;
+        status.deleted += pass.deleted;
+        status.last_pass = Some(pass.clone());
+        Ok(pass)
+    }
+
+    /// The `sample` blobs after the cursor, wrapping around, and advance it.
+    fn next_sample<'a>(&self, objects: &'a [String]) -> Vec<&'a String> {
+        if self.sample == 0 || self.sample >= objects.len() {
+            return objects.iter().collect();
+        }
+        let mut cursor = self.cursor.lock().unwrap();
+        let start = *cursor % objects.len();
+        *cursor = start + self.sample;
+        objects
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(self.sample)
+            .collect()
+    }
+
+    async fn handle_corrupt(
+        &self,
+        state: &AppState,
+        hash: &str,
+        actual: String,
+    ) -> Result<Finding> {
+        let layer = state.metadata.layer_exists(hash)?;
+        let kind = if layer { "Layer" } else { "Artifact" };
+        let mut finding = Finding {
+            hash: hash.to_string(),
+            layer,
+            actual,
+            action: Repair::Deleted,
+            mirror: None,
+            at: crate::cache::clock::stamp_rfc3339(),
+        };
+
+        if let Some((url, data)) = self.fetch_from_mirrors(hash, layer).await {
+            let path = state.storage.put(hash, &data)?;
+            state.metadata.set_storage_path(hash, &path)?;
+            state.quarantine.release(hash);
+            println!(
+                "🩹 {} {} was stored with digest {}; repaired from {}",
+                kind, hash, finding.actual, url
+            );
+            finding.action = Repair::Repaired;
+            finding.mirror = Some(url);
+            return Ok(finding);
+        }
+
+        if finding.actual != MISSING {
+            state.storage.delete(hash)?;
+        }
+        if layer {
+            state
+                .quarantine
+                .add(hash, &finding.actual, &CorruptionReport::default());
+        } else {
+            state.metadata.delete(hash)?;
+        }
+        let message = format!(
+            "{} {} is stored with digest {} and no mirror has a good copy; deleted",
+            kind, hash, finding.actual
+        );
+        eprintln!("☣️  {}", message);
+        alert(state, message);
+        Ok(finding)
+    }
+
+    /// The first copy of `hash` a mirror has that matches its digest.
+    async fn fetch_from_mirrors(&self, hash: &str, layer: bool) -> Option<(String, Vec<u8>)> {
+        for (url, mirror) in &self.mirrors {
+            let fetched = if layer {
+                mirror.get_layer(hash).await
+            } else {
+                mirror.get(hash).await
+            };
+            match fetched {
+                Ok(Some(data)) if layer_matches(hash, &data) => return Some((url.clone(), data)),
+                Ok(Some(_)) => eprintln!("⚠️  Mirror {} also has a bad copy of {}", url, hash),
+                Ok(None) => {}
+                Err(e) => eprintln!("⚠️  Mirror {} failed for {}: {:#}", url, hash, e),
+            }
+        }
+        None
+    }
+
+    fn record(&self, finding: Finding) {
+        let mut status = self.status.lock().unwrap();
+        status.findings.push_front(finding);
+        status
+            .findings
+            .truncate(crate::constants::MAX_VALIDATION_FINDINGS);
+    }
+
+    /// The totals in the Prometheus text format.
+    pub fn encode_metrics(&self) -> String {
+        let status = self.status.lock().unwrap();
+        let mut output = String::new();
+        for (name, help, value) in [
+            ("passes", "Validation passes run", status.passes),
+            (
+                "checked",
+                "Stored blobs hashed by the validator",
+                status.checked,
+            ),
+            (
+                "corrupt",
+                "Stored blobs found missing or corrupt",
+                status.corrupt,
+            ),
+            (
+                "repaired",
+                "Corrupt blobs repaired from a mirror",
+                status.repaired,
+            ),
+            (
+                "deleted",
+                "Corrupt blobs no mirror could repair",
+                status.deleted,
+            ),
+        ] {
+            output.push_str(&format!(
+                "\n# HELP memobuild_validation_{name}_total {help}\n\
+                 # TYPE memobuild_validation_{name}_total counter\n\
+                 memobuild_validation_{name}_total {value}\n"
+            ));
+        }
+        output
+    }
+}
+
+/// Post `message` to the server's webhook, if it has one.
+fn alert(state: &AppState, message: String) {
+    let Some(webhook_url) = state.webhook_url.clone() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let payload =
+            serde_json::json!({ "text": format!("☣️ *Corrupt blob deleted*\n{}", message) });
+        if let Err(e) = reqwest::Client::new()
+            .post(&webhook_url)
+            .json(&payload)
+            .send()
+            .await
+        {
+            eprintln!("⚠️ Failed to send corruption alert: {}", e);
+        }
+    });
+}
+
+/// Run a pass of `state`'s validator every `interval`.
+pub fn spawn(state: Arc<AppState>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick completes at once; do not validate during startup
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            match state.validator.run_pass(&state).await {
+                Ok(pass) if pass.corrupt > 0 => println!(
+                    "🔎 Validated {} blob(s): {} corrupt, {} repaired, {} deleted",
+                    pass.checked, pass.corrupt, pass.repaired, pass.deleted
+                ),
+                Ok(_) => {}
+                Err(e) => eprintln!("⚠️  Validation pass failed: {:#}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::test_util::TestServer;
+    use crate::storage::ArtifactStorage;
+
+    #[tokio::test]
+    async fn test_corrupt_blobs_repaired_or_deleted() {
+        let server = TestServer::with_admin_token("admin").await.unwrap();
+        let mirror_dir = tempfile::TempDir::new().unwrap();
+        let mirror = crate::cache::FsRemoteCache::new(mirror_dir.path()).unwrap();
+
+        let blob = |content: &[u8]| blake3::hash(content).to_hex().to_string();
+        let (good, layer, lost) = (blob(b"good"), blob(b"layer"), blob(b"lost"));
+        server.storage().put(&good, b"good").unwrap();
+        server.metadata().insert(&good, &good, 4).unwrap();
+        // A rotten layer a mirror still has, and a rotten artifact it lacks
+        server.storage().put(&layer, b"rotten").unwrap();
+        server.metadata().insert_layer(&layer, &layer, 5).unwrap();
+        mirror.put_layer(&layer, b"layer").await.unwrap();
+        server.storage().put(&lost, b"rotten").unwrap();
+        server.metadata().insert(&lost, &lost, 4).unwrap();
+
+        let mirror: Arc<dyn RemoteCache> = Arc::new(mirror);
+        let validator = Validator::new(vec![("file://mirror".into(), mirror)], 0);
+        let pass = validator.run_pass(server.state()).await.unwrap();
+        assert_eq!((pass.checked, pass.corrupt), (3, 2));
+        assert_eq!((pass.repaired, pass.deleted), (1, 1));
+        assert_eq!(server.storage().get(&layer).unwrap().unwrap(), b"layer");
+        assert!(server.storage().get(&lost).unwrap().is_none());
+        assert!(server.metadata().get(&lost).unwrap().is_none());
+
+        let status = validator.status();
+        let finding = |hash: &str| status.findings.iter().find(|f| f.hash == hash).unwrap();
+        assert_eq!(finding(&lost).action, Repair::Deleted);
+        assert_eq!(finding(&layer).mirror.as_deref(), Some("file://mirror"));
+        assert!(validator
+            .encode_metrics()
+            .contains("memobuild_validation_repaired_total 1"));
+
+        // The next pass finds everything intact
+        let pass = validator.run_pass(server.state()).await.unwrap();
+        assert_eq!((pass.checked, pass.corrupt), (2, 0));
+
+        // Passes through the API are for admins
+        let client = reqwest::Client::new();
+        let run = format!("{}/api/validation/run", server.url());
+        let denied = client.post(&run).send().await.unwrap();
+        assert_eq!(denied.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let ran: ValidationPass = client
+            .post(&run)
+            .bearer_auth("admin")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(ran.checked, 2);
+        server.stop().await;
+    }
+
+    #[test]
+    fn test_sample_rotates() {
+        let objects: Vec<String> = (0..5).map(|i| i.to_string()).collect();
+        let validator = Validator::new(Vec::new(), 2);
+        let mut seen = Vec::new();
+        for _ in 0..3 {
+            seen.extend(validator.next_sample(&objects).into_iter().cloned());
+        }
+        assert_eq!(seen, vec!["0", "1", "2", "3", "4", "0"]);
+    }
+}
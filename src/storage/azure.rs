@@ -0,0 +1,267 @@
+use super::{block_on, ArtifactStorage};
+use anyhow::{Context, Result};
+use reqwest::{Client, StatusCode};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const API_VERSION: &str = "2021-08-06";
+const STORAGE_SCOPE: &str = "https://storage.azure.com/.default";
+
+/// How requests to Blob Storage are authorized.
+#[derive(Debug, Clone)]
+pub enum AzureCredential {
+    /// Shared access signature appended to every request URL
+    Sas(String),
+    /// AKS workload identity: a federated token file exchanged for an AAD token
+    WorkloadIdentity {
+        tenant_id: String,
+        client_id: String,
+        token_file: String,
+    },
+    /// VM / node managed identity via the instance metadata service
+    ManagedIdentity { client_id: Option<String> },
+}
+
+impl AzureCredential {
+    /// `AZURE_STORAGE_SAS_TOKEN` wins, then the workload identity variables
+    /// injected by AKS, then the managed identity of the host.
+    pub fn from_env() -> Self {
+        if let Ok(sas) = std::env::var("AZURE_STORAGE_SAS_TOKEN") {
+            return AzureCredential::Sas(sas.trim_start_matches('?').to_string());
+        }
+        if let (Ok(tenant_id), Ok(client_id), Ok(token_file)) = (
+            std::env::var("AZURE_TENANT_ID"),
+            std::env::var("AZURE_CLIENT_ID"),
+            std::env::var("AZURE_FEDERATED_TOKEN_FILE"),
+        ) {
+            return AzureCredential::WorkloadIdentity {
+                tenant_id,
+                client_id,
+                token_file,
+            };
+        }
+        AzureCredential::ManagedIdentity {
+            client_id: std::env::var("AZURE_CLIENT_ID").ok(),
+        }
+    }
+}
+
+/// Azure Blob Storage backed artifact storage, talking to the Blob REST API.
+pub struct AzureBlobStorage {
+    client: Client,
+    endpoint: String,
+    container: String,
+    prefix: String,
+    credential: AzureCredential,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl AzureBlobStorage {
+    /// `endpoint` overrides `https://{account}.blob.core.windows.net`, e.g. for Azurite.
+    pub fn new(
+        account: String,
+        container: String,
+        prefix: String,
+        endpoint: Option<String>,
+        credential: AzureCredential,
+    ) -> Self {
+        let endpoint =
+            endpoint.unwrap_or_else(|| format!("https://{}.blob.core.windows.net", account));
+        Self {
+            client: Client::new(),
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            container,
+            prefix,
+            credential,
+            token: Mutex::new(None),
+        }
+    }
+
+    fn blob_name(&self, hash: &str) -> String {
+        if self.prefix.is_empty() {
+            format!("sha256/{}", hash)
+        } else {
+            format!("{}/sha256/{}", self.prefix.trim_end_matches('/'), hash)
+        }
+    }
+
+    fn url(&self, hash: &str) -> String {
+        let url = format!(
+            "{}/{}/{}",
+            self.endpoint,
+            self.container,
+            self.blob_name(hash)
+        );
+        match self.credential {
+            AzureCredential::Sas(ref sas) => format!("{}?{}", url, sas),
+            _ => url,
+        }
+    }
+
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        hash: &str,
+    ) -> Result<reqwest::RequestBuilder> {
+        let mut rb = self
+            .client
+            .request(method, self.url(hash))
+            .header("x-ms-version", API_VERSION);
+        if let Some(token) = self.bearer_token().await? {
+            rb = rb.bearer_auth(token);
+        }
+        Ok(rb)
+    }
+
+    async fn bearer_token(&self) -> Result<Option<String>> {
+        if matches!(self.credential, AzureCredential::Sas(_)) {
+            return Ok(None);
+        }
+        if let Some((ref token, expires)) = *self.token.lock().unwrap() {
+            if Instant::now() < expires {
+                return Ok(Some(token.clone()));
+            }
+        }
+
+        let (token, expires_in) = match self.credential {
+            AzureCredential::WorkloadIdentity {
+                ref tenant_id,
+                ref client_id,
+                ref token_file,
+            } => {
+                let assertion = std::fs::read_to_string(token_file)
+                    .with_context(|| format!("Failed to read federated token {}", token_file))?;
+                let authority = std::env::var("AZURE_AUTHORITY_HOST")
+                    .unwrap_or_else(|_| "https://login.microsoftonline.com".to_string());
+                let resp = self
+                    .client
+                    .post(format!(
+                        "{}/{}/oauth2/v2.0/token",
+                        authority.trim_end_matches('/'),
+                        tenant_id
+                    ))
+                    .form(&[
+                        ("grant_type", "client_credentials"),
+                        ("client_id", client_id.as_str()),
+                        ("scope", STORAGE_SCOPE),
+                        (
+                            "client_assertion_type",
+                            "urn:ietf:params:oauth:client-assertion-type:jwt-bearer",
+                        ),
+                        ("client_assertion", assertion.trim()),
+                    ])
+                    .send()
+                    .await?;
+                parse_token_response(resp).await?
+            }
+            AzureCredential::ManagedIdentity { ref client_id } => {
+                let mut query = vec![
+                    ("api-version", "2018-02-01"),
+                    ("resource", "https://storage.azure.com/"),
+                ];
+                if let Some(ref id) = client_id {
+                    query.push(("client_id", id.as_str()));
+                }
+                let resp = self
+                    .client
+                    .get("http://169.254.169.254/metadata/identity/oauth2/token")
+                    .header("Metadata", "true")
+                    .query(&query)
+                    .send()
+                    .await?;
+                parse_token_response(resp).await?
+            }
+            AzureCredential::Sas(_) => unreachable!(),
+        };
+
+        // Refresh a minute early so in-flight requests never carry an expired token
+        let expires = Instant::now() + Duration::from_secs(expires_in.saturating_sub(60));
+        *self.token.lock().unwrap() = Some((token.clone(), expires));
+        Ok(Some(token))
+    }
+}
+
+async fn parse_token_response(resp: reqwest::Response) -> Result<(String, u64)> {
+    if !resp.status().is_success() {
+        anyhow::bail!("Azure token request failed: {}", resp.status());
+    }
+    let body: serde_json::Value = resp.json().await?;
+    let token = body["access_token"]
+        .as_str()
+        .context("Azure token response has no access_token")?
+        .to_string();
+    // IMDS returns expires_in as a string, AAD as a number
+    let expires_in = body["expires_in"]
+        .as_u64()
+        .or_else(|| body["expires_in"].as_str().and_then(|s| s.parse().ok()))
+        .unwrap_or(300);
+    Ok((token, expires_in))
+}
+
+impl ArtifactStorage for AzureBlobStorage {
+    fn put(&self, hash: &str, data: &[u8]) -> Result<String> {
+        block_on(async {
+            let resp = self
+                .request(reqwest::Method::PUT, hash)
+                .await?
+                .header("x-ms-blob-type", "BlockBlob")
+                .body(data.to_vec())
+                .send()
+                .await?;
+            if !resp.status().is_success() {
+                anyhow::bail!("Azure put failed: {}", resp.status());
+            }
+            Ok(format!(
+                "{}/{}/{}",
+                self.endpoint,
+                self.container,
+                self.blob_name(hash)
+            ))
+        })
+    }
+
+    fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        block_on(async {
+            let resp = self
+                .request(reqwest::Method::GET, hash)
+                .await?
+                .send()
+                .await?;
+            match resp.status() {
+                StatusCode::NOT_FOUND => Ok(None),
+                s if s.is_success() => Ok(Some(resp.bytes().await?.to_vec())),
+                s => anyhow::bail!("Azure get failed: {}", s),
+            }
+        })
+    }
+
+    fn exists(&self, hash: &str) -> Result<bool> {
+        block_on(async {
+            let resp = self
+                .request(reqwest::Method::HEAD, hash)
+                .await?
+                .send()
+                .await?;
+            match resp.status() {
+                StatusCode::NOT_FOUND => Ok(false),
+                s if s.is_success() => Ok(true),
+                s => anyhow::bail!("Azure head failed: {}", s),
+            }
+        })
+    }
+
+    fn delete(&self, hash: &str) -> Result<()> {
+        block_on(async {
+            let resp = self
+                .request(reqwest::Method::DELETE, hash)
+                .await?
+                .send()
+                .await?;
+            match resp.status() {
+                StatusCode::NOT_FOUND => Ok(()),
+                s if s.is_success() => Ok(()),
+                s => anyhow::bail!("Azure delete failed: {}", s),
+            }
+        })
+    }
+}
@@ -1,19 +1,32 @@
-use super::ArtifactStorage;
+use super::{block_on, ArtifactStorage};
 use anyhow::Result;
+use google_cloud_storage::client::{Client, ClientConfig};
+use google_cloud_storage::http::objects::delete::DeleteObjectRequest;
+use google_cloud_storage::http::objects::download::Range;
+use google_cloud_storage::http::objects::get::GetObjectRequest;
+use google_cloud_storage::http::objects::upload::{Media, UploadObjectRequest, UploadType};
+use google_cloud_storage::http::Error as GcsError;
+use tokio::sync::OnceCell;
 
 /// Google Cloud Storage backed artifact storage.
 ///
 /// Wraps the `google-cloud-storage` crate. Authentication is handled via
 /// Application Default Credentials (ADC) — set `GOOGLE_APPLICATION_CREDENTIALS`
-/// or run on GCE/GKE with a service account.
+/// to a service account key, or run on GCE/GKE where the metadata server
+/// provides the (workload identity) service account.
 pub struct GcsStorage {
+    client: OnceCell<Client>,
     bucket: String,
     prefix: String,
 }
 
 impl GcsStorage {
     pub fn new_sync(bucket: String, prefix: String) -> Self {
-        Self { bucket, prefix }
+        Self {
+            client: OnceCell::new(),
+            bucket,
+            prefix,
+        }
     }
 
     fn object_name(&self, hash: &str) -> String {
@@ -23,49 +36,95 @@ impl GcsStorage {
             format!("{}/sha256/{}", self.prefix.trim_end_matches('/'), hash)
         }
     }
+
+    async fn get_client(&self) -> Result<&Client> {
+        self.client
+            .get_or_try_init(|| async {
+                let config = ClientConfig::default()
+                    .with_auth()
+                    .await
+                    .map_err(|e| anyhow::anyhow!("GCS auth failed: {}", e))?;
+                Ok::<_, anyhow::Error>(Client::new(config))
+            })
+            .await
+    }
+
+    fn get_request(&self, hash: &str) -> GetObjectRequest {
+        GetObjectRequest {
+            bucket: self.bucket.clone(),
+            object: self.object_name(hash),
+            ..Default::default()
+        }
+    }
+}
+
+fn is_not_found(e: &GcsError) -> bool {
+    matches!(e, GcsError::Response(r) if r.code == 404)
 }
 
 impl ArtifactStorage for GcsStorage {
     fn put(&self, hash: &str, data: &[u8]) -> Result<String> {
-        let _name = self.object_name(hash);
-        let _data = data.to_vec();
-
-        // TODO: Implement actual GCS upload via google-cloud-storage client.
-        // For now, write to local disk as fallback so the trait contract is satisfied.
-        let cache_dir = std::env::var("MEMOBUILD_CACHE_DIR")
-            .unwrap_or_else(|_| "/tmp/memobuild-gcs".to_string());
-        let path = std::path::PathBuf::from(&cache_dir).join(hash);
-        std::fs::create_dir_all(&cache_dir)?;
-        std::fs::write(&path, data)?;
-
-        Ok(format!("gs://{}/{}", self.bucket, self.object_name(hash)))
+        let name = self.object_name(hash);
+        block_on(async {
+            let request = UploadObjectRequest {
+                bucket: self.bucket.clone(),
+                ..Default::default()
+            };
+            self.get_client()
+                .await?
+                .upload_object(
+                    &request,
+                    data.to_vec(),
+                    &UploadType::Simple(Media::new(name.clone())),
+                )
+                .await
+                .map_err(|e| anyhow::anyhow!("GCS put failed: {}", e))?;
+            Ok(format!("gs://{}/{}", self.bucket, name))
+        })
     }
 
     fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
-        let cache_dir = std::env::var("MEMOBUILD_CACHE_DIR")
-            .unwrap_or_else(|_| "/tmp/memobuild-gcs".to_string());
-        let path = std::path::PathBuf::from(&cache_dir).join(hash);
-        if path.exists() {
-            Ok(Some(std::fs::read(path)?))
-        } else {
-            Ok(None)
-        }
+        block_on(async {
+            match self
+                .get_client()
+                .await?
+                .download_object(&self.get_request(hash), &Range::default())
+                .await
+            {
+                Ok(data) => Ok(Some(data)),
+                Err(e) if is_not_found(&e) => Ok(None),
+                Err(e) => Err(anyhow::anyhow!("GCS get failed: {}", e)),
+            }
+        })
     }
 
     fn exists(&self, hash: &str) -> Result<bool> {
-        let cache_dir = std::env::var("MEMOBUILD_CACHE_DIR")
-            .unwrap_or_else(|_| "/tmp/memobuild-gcs".to_string());
-        let path = std::path::PathBuf::from(&cache_dir).join(hash);
-        Ok(path.exists())
+        block_on(async {
+            match self
+                .get_client()
+                .await?
+                .get_object(&self.get_request(hash))
+                .await
+            {
+                Ok(_) => Ok(true),
+                Err(e) if is_not_found(&e) => Ok(false),
+                Err(e) => Err(anyhow::anyhow!("GCS head failed: {}", e)),
+            }
+        })
     }
 
     fn delete(&self, hash: &str) -> Result<()> {
-        let cache_dir = std::env::var("MEMOBUILD_CACHE_DIR")
-            .unwrap_or_else(|_| "/tmp/memobuild-gcs".to_string());
-        let path = std::path::PathBuf::from(&cache_dir).join(hash);
-        if path.exists() {
-            std::fs::remove_file(path)?;
-        }
-        Ok(())
+        block_on(async {
+            let request = DeleteObjectRequest {
+                bucket: self.bucket.clone(),
+                object: self.object_name(hash),
+                ..Default::default()
+            };
+            match self.get_client().await?.delete_object(&request).await {
+                Ok(()) => Ok(()),
+                Err(e) if is_not_found(&e) => Ok(()),
+                Err(e) => Err(anyhow::anyhow!("GCS delete failed: {}", e)),
+            }
+        })
     }
 }
@@ -25,6 +25,120 @@ impl LocalStorage {
         let shard2 = &hash[2..4];
         self.base_dir.join(shard1).join(shard2).join(hash)
     }
+
+    /// Record that `hash` was just used, by moving its modification time to
+    /// now. Tiering reads the time back through [`LocalStorage::blobs`].
+    pub fn mark_used(&self, hash: &str) -> Result<()> {
+        let file = fs::File::options()
+            .append(true)
+            .open(self.get_sharded_path(hash))?;
+        file.set_modified(std::time::SystemTime::now())?;
+        Ok(())
+    }
+
+    /// Every blob stored, with its size and when it was last written or
+    /// marked used.
+    pub fn blobs(&self) -> Result<Vec<LocalBlob>> {
+        let mut blobs = Vec::new();
+        for entry in walkdir::WalkDir::new(&self.base_dir) {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy();
+            // Uploads in progress are written under `<hash>.tmp-<uuid>`
+            if !entry.file_type().is_file() || name.contains('.') {
+                continue;
+            }
+            let meta = entry.metadata()?;
+            blobs.push(LocalBlob {
+                hash: name.to_string(),
+                size: meta.len(),
+                last_used: meta.modified()?,
+            });
+        }
+        Ok(blobs)
+    }
+}
+
+/// Write `data` to `path` so that it is either absent or complete, even if
+/// the process dies or another writer races it. See [`AtomicFile`].
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut file = AtomicFile::create(path)?;
+    file.write_all(data)?;
+    file.commit()?;
+    Ok(())
+}
+
+/// A file written as `<path>.tmp-<uuid>` in the same directory and, on
+/// [`commit`](Self::commit), synced, renamed over `path`, with the directory
+/// synced so the rename itself survives a crash. Dropped without a commit,
+/// the temporary file is removed.
+pub(crate) struct AtomicFile {
+    file: fs::File,
+    tmp: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub(crate) fn create(path: &Path) -> std::io::Result<Self> {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".tmp-{}", uuid::Uuid::new_v4().simple()));
+        let tmp = path.with_file_name(name);
+        Ok(Self {
+            file: fs::File::create(&tmp)?,
+            tmp,
+            path: path.to_path_buf(),
+            committed: false,
+        })
+    }
+
+    pub(crate) fn commit(mut self) -> std::io::Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.tmp, &self.path)?;
+        self.committed = true;
+        sync_parent(&self.path)
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+/// Flush the directory entry of `path` to disk.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => fs::File::open(parent)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
+/// Directories cannot be opened for syncing on Windows; renames there are
+/// journaled by NTFS.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// A blob in local storage, as listed by [`LocalStorage::blobs`].
+#[derive(Debug, Clone)]
+pub struct LocalBlob {
+    pub hash: String,
+    pub size: u64,
+    pub last_used: std::time::SystemTime,
 }
 
 impl ArtifactStorage for LocalStorage {
@@ -39,9 +153,8 @@ impl ArtifactStorage for LocalStorage {
             fs::create_dir_all(parent)?;
         }
 
-        let mut file = fs::File::create(&path)
-            .with_context(|| format!("Failed to create artifact file at {}", path.display()))?;
-        file.write_all(data)?;
+        write_atomic(&path, data)
+            .with_context(|| format!("Failed to write artifact file at {}", path.display()))?;
 
         Ok(path.to_string_lossy().to_string())
     }
@@ -67,6 +180,18 @@ impl ArtifactStorage for LocalStorage {
         }
         Ok(())
     }
+
+    #[cfg(unix)]
+    fn available_space(&self) -> Option<u64> {
+        use std::os::unix::ffi::OsStrExt;
+        let path = std::ffi::CString::new(self.base_dir.as_os_str().as_bytes()).ok()?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return None;
+        }
+        #[allow(clippy::unnecessary_cast)]
+        Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
 }
 
 #[cfg(test)]
@@ -90,5 +215,22 @@ mod tests {
 
         let path = storage.get_sharded_path(hash);
         assert!(path.to_string_lossy().contains("ab/cd/abcdef"));
+
+        #[cfg(unix)]
+        assert!(storage.available_space().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_write_atomic_replaces_and_leaves_no_temp_files() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("artifact.bin");
+
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // A directory that does not exist fails cleanly
+        assert!(write_atomic(&dir.path().join("missing/artifact.bin"), b"x").is_err());
     }
 }
@@ -0,0 +1,49 @@
+use super::ArtifactStorage;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Blobs kept in a map, for tests and throwaway servers. Nothing survives
+/// the process.
+#[derive(Default)]
+pub struct MemoryStorage {
+    blobs: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of blobs stored.
+    pub fn len(&self) -> usize {
+        self.blobs.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ArtifactStorage for MemoryStorage {
+    fn put(&self, hash: &str, data: &[u8]) -> Result<String> {
+        self.blobs
+            .write()
+            .unwrap()
+            .insert(hash.to_string(), data.to_vec());
+        Ok(format!("memory://{}", hash))
+    }
+
+    fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.blobs.read().unwrap().get(hash).cloned())
+    }
+
+    fn exists(&self, hash: &str) -> Result<bool> {
+        Ok(self.blobs.read().unwrap().contains_key(hash))
+    }
+
+    fn delete(&self, hash: &str) -> Result<()> {
+        self.blobs.write().unwrap().remove(hash);
+        Ok(())
+    }
+}
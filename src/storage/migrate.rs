@@ -0,0 +1,265 @@
+//! Moving a cache server's blobs from one storage backend to another, for
+//! `memobuild migrate-storage`.
+
+use super::ArtifactStorage;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// Checkpoint file in the server's data directory
+pub const CHECKPOINT_FILE: &str = "storage-migration.json";
+
+/// The checkpoint is written after this many blobs
+const CHECKPOINT_EVERY: usize = 100;
+
+/// Progress of one migration, kept across runs.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MigrationCheckpoint {
+    /// Source and destination URIs; a checkpoint only resumes the same pair
+    pub from: String,
+    pub to: String,
+    /// Blobs already copied and verified
+    #[serde(default)]
+    pub done: BTreeSet<String>,
+    #[serde(default)]
+    pub bytes: u64,
+}
+
+impl MigrationCheckpoint {
+    pub fn path_in(data_dir: &Path) -> PathBuf {
+        data_dir.join(CHECKPOINT_FILE)
+    }
+
+    /// The checkpoint for `from` → `to`, or a fresh one if none was saved
+    /// for that pair.
+    pub fn load(path: &Path, from: &str, to: &str) -> Result<Self> {
+        let fresh = Self {
+            from: from.to_string(),
+            to: to.to_string(),
+            ..Default::default()
+        };
+        match std::fs::read_to_string(path) {
+            Ok(content) => {
+                let saved: Self = serde_json::from_str(&content)
+                    .with_context(|| format!("Failed to parse {}", path.display()))?;
+                Ok(if saved.from == from && saved.to == to {
+                    saved
+                } else {
+                    fresh
+                })
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(fresh),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Where a migration stands, passed to the progress callback after every
+/// blob.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationProgress {
+    pub total: usize,
+    /// Blobs done, including those done by earlier runs
+    pub done: usize,
+    /// Copied in this run
+    pub copied: usize,
+    /// Already in the destination with the right content
+    pub already_present: usize,
+    /// In the metadata but missing from the source
+    pub missing: Vec<String>,
+    pub bytes: u64,
+}
+
+/// Copy `hashes` from `source` to `dest`, verifying each copy by reading it
+/// back. `relocated` is called with the destination's location of every
+/// blob copied or found, so the caller can update its metadata. Returns the
+/// final progress; blobs missing from the source are listed, not fatal.
+pub fn migrate(
+    source: &dyn ArtifactStorage,
+    dest: &dyn ArtifactStorage,
+    hashes: &[String],
+    checkpoint: &mut MigrationCheckpoint,
+    checkpoint_path: &Path,
+    mut relocated: impl FnMut(&str, &str) -> Result<()>,
+    mut on_progress: impl FnMut(&MigrationProgress),
+) -> Result<MigrationProgress> {
+    let mut progress = MigrationProgress {
+        total: hashes.len(),
+        bytes: checkpoint.bytes,
+        ..Default::default()
+    };
+    progress.done = hashes
+        .iter()
+        .filter(|h| checkpoint.done.contains(*h))
+        .count();
+
+    let mut since_checkpoint = 0;
+    for hash in hashes {
+        if checkpoint.done.contains(hash) {
+            continue;
+        }
+        let Some(data) = source
+            .get(hash)
+            .with_context(|| format!("Failed to read {} from the source", hash))?
+        else {
+            progress.missing.push(hash.clone());
+            continue;
+        };
+        let digest = blake3::hash(&data);
+
+        let intact = |stored: Option<Vec<u8>>| stored.is_some_and(|s| blake3::hash(&s) == digest);
+        let location = if intact(dest.get(hash)?) {
+            progress.already_present += 1;
+            // Putting an existing blob only reports where it is
+            dest.put(hash, &data)?
+        } else {
+            let location = dest
+                .put(hash, &data)
+                .with_context(|| format!("Failed to write {} to the destination", hash))?;
+            if !intact(dest.get(hash)?) {
+                anyhow::bail!("{} did not read back intact from the destination", hash);
+            }
+            progress.copied += 1;
+            progress.bytes += data.len() as u64;
+            location
+        };
+        relocated(hash, &location)?;
+
+        checkpoint.done.insert(hash.clone());
+        checkpoint.bytes = progress.bytes;
+        progress.done += 1;
+        since_checkpoint += 1;
+        if since_checkpoint >= CHECKPOINT_EVERY {
+            checkpoint.save(checkpoint_path)?;
+            since_checkpoint = 0;
+        }
+        on_progress(&progress);
+    }
+    checkpoint.save(checkpoint_path)?;
+    Ok(progress)
+}
+
+/// Storage for the transition: writes go to `primary`, reads that miss it
+/// fall back to `fallback`, and deletes apply to both.
+pub struct ReadBothStorage {
+    primary: Box<dyn ArtifactStorage>,
+    fallback: Box<dyn ArtifactStorage>,
+}
+
+impl ReadBothStorage {
+    pub fn new(primary: Box<dyn ArtifactStorage>, fallback: Box<dyn ArtifactStorage>) -> Self {
+        Self { primary, fallback }
+    }
+}
+
+impl ArtifactStorage for ReadBothStorage {
+    fn put(&self, hash: &str, data: &[u8]) -> Result<String> {
+        self.primary.put(hash, data)
+    }
+
+    fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        match self.primary.get(hash)? {
+            Some(data) => Ok(Some(data)),
+            None => self.fallback.get(hash),
+        }
+    }
+
+    fn exists(&self, hash: &str) -> Result<bool> {
+        Ok(self.primary.exists(hash)? || self.fallback.exists(hash)?)
+    }
+
+    fn delete(&self, hash: &str) -> Result<()> {
+        self.primary.delete(hash)?;
+        self.fallback.delete(hash)
+    }
+
+    fn available_space(&self) -> Option<u64> {
+        self.primary.available_space()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+
+    #[test]
+    fn test_migrate_resumes_and_verifies() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let old = LocalStorage::new(&dir.path().join("old")).unwrap();
+        let new = LocalStorage::new(&dir.path().join("new")).unwrap();
+        let mut hashes = Vec::new();
+        for i in 0..5 {
+            let data = format!("blob {}", i).into_bytes();
+            let hash = blake3::hash(&data).to_hex().to_string();
+            old.put(&hash, &data).unwrap();
+            hashes.push(hash);
+        }
+        hashes.push("gone".to_string());
+        let path = MigrationCheckpoint::path_in(dir.path());
+
+        // An earlier run copied the first two before it was interrupted
+        let mut checkpoint = MigrationCheckpoint::load(&path, "old", "new").unwrap();
+        for hash in &hashes[..2] {
+            new.put(hash, &old.get(hash).unwrap().unwrap()).unwrap();
+            checkpoint.done.insert(hash.clone());
+        }
+        checkpoint.save(&path).unwrap();
+        // A blob the destination already has is verified, not copied again
+        new.put(&hashes[2], &old.get(&hashes[2]).unwrap().unwrap())
+            .unwrap();
+
+        let mut checkpoint = MigrationCheckpoint::load(&path, "old", "new").unwrap();
+        assert_eq!(checkpoint.done.len(), 2);
+        let mut relocated = Vec::new();
+        let mut calls = 0;
+        let progress = migrate(
+            &old,
+            &new,
+            &hashes,
+            &mut checkpoint,
+            &path,
+            |hash, _| {
+                relocated.push(hash.to_string());
+                Ok(())
+            },
+            |_| calls += 1,
+        )
+        .unwrap();
+
+        assert_eq!(progress.copied, 2);
+        assert_eq!(progress.already_present, 1);
+        assert_eq!(progress.done, 5);
+        assert_eq!(progress.missing, vec!["gone".to_string()]);
+        assert_eq!(relocated, hashes[2..5].to_vec());
+        assert_eq!(calls, 3);
+        for hash in &hashes[..5] {
+            assert_eq!(new.get(hash).unwrap(), old.get(hash).unwrap());
+        }
+        assert_eq!(
+            MigrationCheckpoint::load(&path, "old", "new")
+                .unwrap()
+                .done
+                .len(),
+            5
+        );
+        // A different pair starts over
+        assert!(MigrationCheckpoint::load(&path, "old", "other")
+            .unwrap()
+            .done
+            .is_empty());
+
+        // During the transition, reads fall back to the old storage
+        let fresh = LocalStorage::new(&dir.path().join("fresh")).unwrap();
+        let both = ReadBothStorage::new(Box::new(fresh), Box::new(old));
+        assert!(both.get(&hashes[0]).unwrap().is_some());
+        assert!(both.exists(&hashes[0]).unwrap());
+        assert!(both.get("gone").unwrap().is_none());
+    }
+}
@@ -1,6 +1,10 @@
+pub mod azure;
 pub mod gcs;
 pub mod local;
+pub mod memory;
+pub mod migrate;
 pub mod s3;
+pub mod tiered;
 
 use anyhow::Result;
 
@@ -9,11 +13,38 @@ pub trait ArtifactStorage: Send + Sync {
     fn get(&self, hash: &str) -> Result<Option<Vec<u8>>>;
     fn exists(&self, hash: &str) -> Result<bool>;
     fn delete(&self, hash: &str) -> Result<()>;
+
+    /// Bytes still writable, or `None` for backends without a meaningful
+    /// limit (object stores).
+    fn available_space(&self) -> Option<u64> {
+        None
+    }
 }
 
+impl<T: ArtifactStorage + ?Sized> ArtifactStorage for std::sync::Arc<T> {
+    fn put(&self, hash: &str, data: &[u8]) -> Result<String> {
+        (**self).put(hash, data)
+    }
+    fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get(hash)
+    }
+    fn exists(&self, hash: &str) -> Result<bool> {
+        (**self).exists(hash)
+    }
+    fn delete(&self, hash: &str) -> Result<()> {
+        (**self).delete(hash)
+    }
+    fn available_space(&self) -> Option<u64> {
+        (**self).available_space()
+    }
+}
+
+pub use azure::{AzureBlobStorage, AzureCredential};
 pub use gcs::GcsStorage;
-pub use local::LocalStorage;
+pub use local::{LocalBlob, LocalStorage};
+pub use memory::MemoryStorage;
 pub use s3::S3Storage;
+pub use tiered::TieredStorage;
 
 /// Backend selection for artifact storage.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -21,33 +52,166 @@ pub enum StorageBackend {
     Local,
     S3,
     Gcs,
+    Azure,
+    /// Blobs in process memory, lost when the server stops
+    Memory,
 }
 
 impl StorageBackend {
     pub fn from_env() -> Self {
-        match std::env::var("MEMOBUILD_STORAGE_BACKEND")
-            .unwrap_or_else(|_| "local".to_string())
-            .to_lowercase()
-            .as_str()
-        {
-            "s3" => StorageBackend::S3,
-            "gcs" => StorageBackend::Gcs,
-            _ => StorageBackend::Local,
+        std::env::var("MEMOBUILD_STORAGE_BACKEND")
+            .ok()
+            .and_then(|name| name.parse().ok())
+            .unwrap_or(StorageBackend::Local)
+    }
+}
+
+impl std::str::FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(name: &str) -> std::result::Result<Self, Self::Err> {
+        match name.to_lowercase().as_str() {
+            "local" => Ok(StorageBackend::Local),
+            "s3" => Ok(StorageBackend::S3),
+            "gcs" | "gs" => Ok(StorageBackend::Gcs),
+            "azure" | "az" => Ok(StorageBackend::Azure),
+            "memory" | "mem" => Ok(StorageBackend::Memory),
+            other => Err(format!(
+                "unknown storage backend '{}' (expected local, s3, gcs, azure or memory)",
+                other
+            )),
+        }
+    }
+}
+
+/// A storage location written as a URI:
+/// `s3://bucket/prefix`, `gs://bucket/prefix`, `az://account/container/prefix`,
+/// or a plain (optionally `file://`) path for local storage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageUri {
+    Local(std::path::PathBuf),
+    S3 {
+        bucket: String,
+        prefix: String,
+    },
+    Gcs {
+        bucket: String,
+        prefix: String,
+    },
+    Azure {
+        account: String,
+        container: String,
+        prefix: String,
+    },
+}
+
+impl StorageUri {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let Some((scheme, rest)) = uri.split_once("://") else {
+            return Ok(StorageUri::Local(uri.into()));
+        };
+        let mut parts = rest.splitn(2, '/');
+        let first = parts.next().unwrap_or_default().to_string();
+        let rest = parts
+            .next()
+            .unwrap_or_default()
+            .trim_matches('/')
+            .to_string();
+        if first.is_empty() && scheme != "file" {
+            anyhow::bail!("Storage URI '{}' has no bucket", uri);
+        }
+
+        match scheme {
+            "file" => Ok(StorageUri::Local(format!("/{}", rest).into())),
+            "s3" => Ok(StorageUri::S3 {
+                bucket: first,
+                prefix: rest,
+            }),
+            "gs" => Ok(StorageUri::Gcs {
+                bucket: first,
+                prefix: rest,
+            }),
+            "az" => {
+                let (container, prefix) = rest.split_once('/').unwrap_or((&rest, ""));
+                if container.is_empty() {
+                    anyhow::bail!("Azure storage URI '{}' needs az://account/container", uri);
+                }
+                Ok(StorageUri::Azure {
+                    account: first,
+                    container: container.to_string(),
+                    prefix: prefix.to_string(),
+                })
+            }
+            other => anyhow::bail!("Unsupported storage scheme '{}://'", other),
+        }
+    }
+
+    /// Whether the URI points at an object store rather than a local path.
+    pub fn is_remote(&self) -> bool {
+        !matches!(self, StorageUri::Local(_))
+    }
+}
+
+/// Build a concrete `ArtifactStorage` from a storage URI. Endpoint and region
+/// overrides still come from `MEMOBUILD_STORAGE_ENDPOINT` / `_REGION`.
+pub fn storage_from_uri(uri: &str) -> Result<Box<dyn ArtifactStorage>> {
+    let endpoint = std::env::var("MEMOBUILD_STORAGE_ENDPOINT").ok();
+    match StorageUri::parse(uri)? {
+        StorageUri::Local(path) => Ok(Box::new(LocalStorage::new(&path)?)),
+        StorageUri::S3 { bucket, prefix } => {
+            let region = std::env::var("MEMOBUILD_STORAGE_REGION")
+                .unwrap_or_else(|_| "us-east-1".to_string());
+            Ok(Box::new(S3Storage::new_sync(
+                bucket, endpoint, region, prefix,
+            )))
         }
+        StorageUri::Gcs { bucket, prefix } => Ok(Box::new(GcsStorage::new_sync(bucket, prefix))),
+        StorageUri::Azure {
+            account,
+            container,
+            prefix,
+        } => Ok(Box::new(AzureBlobStorage::new(
+            account,
+            container,
+            prefix,
+            endpoint,
+            AzureCredential::from_env(),
+        ))),
     }
 }
 
+/// Run an async SDK call from the synchronous `ArtifactStorage` API.
+/// Requires a multi-threaded tokio runtime.
+pub(crate) fn block_on<F: std::future::Future>(fut: F) -> F::Output {
+    tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(fut))
+}
+
 /// Factory: build a concrete `ArtifactStorage` from environment variables.
 ///
-/// * `MEMOBUILD_STORAGE_BACKEND` — `local` (default), `s3`, `gcs`
-/// * `MEMOBUILD_STORAGE_BUCKET` — bucket name (S3/GCS)
+/// * `MEMOBUILD_STORAGE_URL` — storage URI (see `StorageUri`); overrides the variables below
+/// * `MEMOBUILD_STORAGE_BACKEND` — `local` (default), `s3`, `gcs`, `azure`, `memory`
+/// * `MEMOBUILD_STORAGE_BUCKET` — bucket name (S3/GCS) or container (Azure)
+/// * `MEMOBUILD_STORAGE_ACCOUNT` — storage account (Azure)
 /// * `MEMOBUILD_STORAGE_ENDPOINT` — custom endpoint (MinIO, LocalStack)
 /// * `MEMOBUILD_STORAGE_REGION` — AWS region (default `us-east-1`)
 /// * `MEMOBUILD_STORAGE_PREFIX` — key prefix inside the bucket
 pub fn storage_from_env(base_dir: &std::path::Path) -> Result<Box<dyn ArtifactStorage>> {
-    let backend = StorageBackend::from_env();
+    if let Ok(uri) = std::env::var("MEMOBUILD_STORAGE_URL") {
+        return storage_from_uri(&uri);
+    }
+
+    storage_for_backend(StorageBackend::from_env(), base_dir)
+}
+
+/// Build the `ArtifactStorage` for `backend`; bucket and credentials come
+/// from the variables listed on [`storage_from_env`].
+pub fn storage_for_backend(
+    backend: StorageBackend,
+    base_dir: &std::path::Path,
+) -> Result<Box<dyn ArtifactStorage>> {
     match backend {
         StorageBackend::Local => Ok(Box::new(LocalStorage::new(base_dir)?)),
+        StorageBackend::Memory => Ok(Box::new(MemoryStorage::new())),
         StorageBackend::S3 => {
             let bucket = std::env::var("MEMOBUILD_STORAGE_BUCKET")
                 .expect("MEMOBUILD_STORAGE_BUCKET required for s3 backend");
@@ -57,7 +221,9 @@ pub fn storage_from_env(base_dir: &std::path::Path) -> Result<Box<dyn ArtifactSt
             let prefix = std::env::var("MEMOBUILD_STORAGE_PREFIX").unwrap_or_default();
             // S3Storage::new is async, but we construct a blocking wrapper here.
             // The server calls this at startup inside a tokio runtime.
-            Ok(Box::new(S3Storage::new_sync(bucket, endpoint, region, prefix)))
+            Ok(Box::new(S3Storage::new_sync(
+                bucket, endpoint, region, prefix,
+            )))
         }
         StorageBackend::Gcs => {
             let bucket = std::env::var("MEMOBUILD_STORAGE_BUCKET")
@@ -65,5 +231,59 @@ pub fn storage_from_env(base_dir: &std::path::Path) -> Result<Box<dyn ArtifactSt
             let prefix = std::env::var("MEMOBUILD_STORAGE_PREFIX").unwrap_or_default();
             Ok(Box::new(GcsStorage::new_sync(bucket, prefix)))
         }
+        StorageBackend::Azure => {
+            let account = std::env::var("MEMOBUILD_STORAGE_ACCOUNT").map_err(|_| {
+                anyhow::anyhow!("MEMOBUILD_STORAGE_ACCOUNT required for azure backend")
+            })?;
+            let container = std::env::var("MEMOBUILD_STORAGE_BUCKET").map_err(|_| {
+                anyhow::anyhow!("MEMOBUILD_STORAGE_BUCKET required for azure backend")
+            })?;
+            let prefix = std::env::var("MEMOBUILD_STORAGE_PREFIX").unwrap_or_default();
+            let endpoint = std::env::var("MEMOBUILD_STORAGE_ENDPOINT").ok();
+            Ok(Box::new(AzureBlobStorage::new(
+                account,
+                container,
+                prefix,
+                endpoint,
+                AzureCredential::from_env(),
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_uri_parse() {
+        assert_eq!(
+            StorageUri::parse("gs://builds/cache").unwrap(),
+            StorageUri::Gcs {
+                bucket: "builds".to_string(),
+                prefix: "cache".to_string()
+            }
+        );
+        assert_eq!(
+            StorageUri::parse("az://acct/artifacts/team-a").unwrap(),
+            StorageUri::Azure {
+                account: "acct".to_string(),
+                container: "artifacts".to_string(),
+                prefix: "team-a".to_string()
+            }
+        );
+        assert_eq!(
+            StorageUri::parse("/mnt/cache").unwrap(),
+            StorageUri::Local("/mnt/cache".into())
+        );
+        assert!(StorageUri::parse("az://acct").is_err());
+        assert!(StorageUri::parse("ftp://host/x").is_err());
+    }
+
+    #[test]
+    fn test_storage_backend_parse() {
+        assert_eq!("memory".parse(), Ok(StorageBackend::Memory));
+        assert_eq!("GS".parse(), Ok(StorageBackend::Gcs));
+        assert!("tape".parse::<StorageBackend>().is_err());
     }
 }
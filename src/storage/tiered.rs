@@ -0,0 +1,161 @@
+//! Hot/cold tiering for the cache server: idle blobs move from local disk to
+//! `MEMOBUILD_STORAGE_COLD_URL`.
+
+use super::{ArtifactStorage, LocalStorage};
+use anyhow::{Context, Result};
+use std::time::{Duration, SystemTime};
+
+/// Outcome of one demotion pass.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DemotionReport {
+    pub demoted: usize,
+    pub bytes: u64,
+    /// Blobs that stayed hot because copying them failed
+    pub failed: usize,
+}
+
+pub struct TieredStorage {
+    hot: LocalStorage,
+    cold: Box<dyn ArtifactStorage>,
+    /// Blobs unused for longer than this are moved to the cold tier
+    idle: Duration,
+}
+
+impl TieredStorage {
+    pub fn new(hot: LocalStorage, cold: Box<dyn ArtifactStorage>, idle: Duration) -> Self {
+        Self { hot, cold, idle }
+    }
+
+    /// Idle period from `MEMOBUILD_TIER_IDLE_HOURS`.
+    pub fn idle_from_env() -> Duration {
+        let hours = std::env::var("MEMOBUILD_TIER_IDLE_HOURS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(crate::constants::DEFAULT_TIER_IDLE_HOURS);
+        Duration::from_secs(hours * 3600)
+    }
+
+    /// Move every hot blob unused since `now - idle` to the cold tier. A blob
+    /// is only removed from the hot tier once the cold tier has it.
+    pub fn demote_idle(&self, now: SystemTime) -> Result<DemotionReport> {
+        let mut report = DemotionReport::default();
+        let tolerance = crate::cache::clock::skew_tolerance();
+        let cutoff = now
+            .checked_sub(self.idle + tolerance)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        for blob in self.hot.blobs()? {
+            if blob.last_used > now + tolerance {
+                // Used while the clock ran ahead; age it from now
+                let _ = self.hot.mark_used(&blob.hash);
+                continue;
+            }
+            if blob.last_used > cutoff {
+                continue;
+            }
+            let demote = || -> Result<()> {
+                let data = self
+                    .hot
+                    .get(&blob.hash)?
+                    .context("removed while being demoted")?;
+                self.cold.put(&blob.hash, &data)?;
+                if !self.cold.exists(&blob.hash)? {
+                    anyhow::bail!("not found in the cold tier after upload");
+                }
+                self.hot.delete(&blob.hash)
+            };
+            match demote() {
+                Ok(()) => {
+                    report.demoted += 1;
+                    report.bytes += blob.size;
+                }
+                Err(e) => {
+                    eprintln!("⚠️  Failed to demote {}: {:#}", blob.hash, e);
+                    report.failed += 1;
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl ArtifactStorage for TieredStorage {
+    fn put(&self, hash: &str, data: &[u8]) -> Result<String> {
+        let location = self.hot.put(hash, data)?;
+        // An upload of a blob already stored counts as a use
+        self.hot.mark_used(hash)?;
+        Ok(location)
+    }
+
+    fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        if let Some(data) = self.hot.get(hash)? {
+            // A blob demoted meanwhile is still readable below
+            let _ = self.hot.mark_used(hash);
+            return Ok(Some(data));
+        }
+        let Some(data) = self.cold.get(hash)? else {
+            return Ok(None);
+        };
+        // Promoted back; the cold copy stays so a later demotion is free
+        if let Err(e) = self.hot.put(hash, &data) {
+            eprintln!("⚠️  Failed to restore {} to the hot tier: {:#}", hash, e);
+        }
+        Ok(Some(data))
+    }
+
+    fn exists(&self, hash: &str) -> Result<bool> {
+        Ok(self.hot.exists(hash)? || self.cold.exists(hash)?)
+    }
+
+    fn delete(&self, hash: &str) -> Result<()> {
+        self.hot.delete(hash)?;
+        self.cold.delete(hash)
+    }
+
+    fn available_space(&self) -> Option<u64> {
+        // Uploads land on the hot tier; demotion frees it again
+        self.hot.available_space()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiered_storage() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let tiered = TieredStorage::new(
+            LocalStorage::new(&dir.path().join("hot")).unwrap(),
+            Box::new(LocalStorage::new(&dir.path().join("cold")).unwrap()),
+            Duration::from_secs(3600),
+        );
+        let cold = LocalStorage::new(&dir.path().join("cold")).unwrap();
+        tiered.put("aaaa1111", b"old").unwrap();
+        tiered.put("bbbb2222", b"new").unwrap();
+
+        // Last used two hours ago
+        let two_hours_ago = SystemTime::now() - Duration::from_secs(7200);
+        std::fs::File::options()
+            .append(true)
+            .open(dir.path().join("hot/blobs/sha256/aa/aa/aaaa1111"))
+            .unwrap()
+            .set_modified(two_hours_ago)
+            .unwrap();
+
+        let report = tiered.demote_idle(SystemTime::now()).unwrap();
+        assert_eq!(report.demoted, 1);
+        assert_eq!(report.bytes, 3);
+        assert!(!tiered.hot.exists("aaaa1111").unwrap());
+        assert!(cold.exists("aaaa1111").unwrap());
+        assert!(tiered.hot.exists("bbbb2222").unwrap());
+
+        // A read of a demoted blob is served from the cold tier and promoted
+        assert_eq!(tiered.get("aaaa1111").unwrap().unwrap(), b"old");
+        assert!(tiered.hot.exists("aaaa1111").unwrap());
+        assert!(tiered.exists("bbbb2222").unwrap());
+        assert!(tiered.get("cccc3333").unwrap().is_none());
+
+        tiered.delete("aaaa1111").unwrap();
+        assert!(!cold.exists("aaaa1111").unwrap());
+    }
+}
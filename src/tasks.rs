@@ -0,0 +1,235 @@
+//! Task manifests (`memobuild.tasks.json`): builds described as commands rather
+//! than a Dockerfile.
+
+use crate::graph::{BuildGraph, GraphBuilder, NodeKind};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+pub const TASKS_FILE: &str = "memobuild.tasks.json";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct TaskSpec {
+    pub cmd: String,
+    /// Context files the task reads, as globs
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    /// Files the task produces, as globs relative to the context
+    #[serde(default)]
+    pub outputs: Vec<String>,
+    /// Tasks that run first
+    #[serde(default)]
+    pub deps: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+    /// Group the task is reported under, e.g. `frontend`
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TaskManifest {
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tasks: BTreeMap<String, TaskSpec>,
+}
+
+/// Whether the build file at `path` is a task manifest rather than a
+/// Dockerfile.
+pub fn is_manifest(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name == TASKS_FILE || name.ends_with(".tasks.json"))
+}
+
+impl TaskManifest {
+    pub fn parse(content: &str) -> Result<Self> {
+        serde_json::from_str(content).context("Malformed task manifest")
+    }
+
+    /// One task node per task, every task after the ones it depends on.
+    pub fn into_graph(self) -> Result<BuildGraph> {
+        let mut builder = GraphBuilder::new();
+        for (name, task) in &self.tasks {
+            if task.cmd.trim().is_empty() {
+                anyhow::bail!("Task {} has no command", name);
+            }
+            let mut vars = self.vars.clone();
+            vars.extend(task.vars.clone());
+            let expand_all = |patterns: &[String]| {
+                patterns
+                    .iter()
+                    .map(|pattern| expand(pattern, &vars))
+                    .collect::<Result<Vec<_>>>()
+            };
+            let inputs = expand_all(&task.inputs)
+                .with_context(|| format!("Invalid inputs of task {}", name))?;
+            let outputs = expand_all(&task.outputs)
+                .with_context(|| format!("Invalid outputs of task {}", name))?;
+            vars.insert("name".to_string(), name.clone());
+            vars.insert("inputs".to_string(), inputs.join(" "));
+            vars.insert("outputs".to_string(), outputs.join(" "));
+            let cmd = expand(&task.cmd, &vars)
+                .with_context(|| format!("Invalid command of task {}", name))?;
+
+            let mut step = builder
+                .add_task(name, &cmd)
+                .with_inputs(inputs)
+                .with_outputs(outputs);
+            for dep in &task.deps {
+                step = step.depends_on(dep);
+            }
+            for (key, value) in &task.env {
+                step = step.with_env(key, value);
+            }
+            if let Some(ref group) = task.group {
+                step.with_group(group);
+            }
+        }
+        builder.build()
+    }
+}
+
+/// Replace every `{{var}}` in `template` by its value in `vars`. An
+/// unknown placeholder is an error.
+fn expand(template: &str, vars: &BTreeMap<String, String>) -> Result<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .with_context(|| format!("Unclosed placeholder in '{}'", template))?;
+        let key = after[..end].trim();
+        let value = vars
+            .get(key)
+            .with_context(|| format!("Unknown placeholder {{{{{}}}}}", key))?;
+        out.push_str(value);
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Archive of the files in `dir` matching `patterns`, the artifact of a
+/// task. A pattern matching nothing is an error: the task did not produce
+/// what it declares.
+pub fn collect_outputs(dir: &Path, patterns: &[String]) -> Result<Vec<u8>> {
+    let mut files = BTreeSet::new();
+    for pattern in patterns {
+        let mut matched = false;
+        for path in glob::glob(&dir.join(pattern).to_string_lossy())
+            .with_context(|| format!("Invalid output pattern {}", pattern))?
+        {
+            let path = path?;
+            if path.is_dir() {
+                for entry in walkdir::WalkDir::new(&path).follow_links(false) {
+                    let entry = entry?;
+                    if !entry.file_type().is_dir() {
+                        files.insert(entry.into_path());
+                    }
+                }
+            } else {
+                files.insert(path);
+            }
+            matched = true;
+        }
+        if !matched {
+            anyhow::bail!("Output {} was not produced", pattern);
+        }
+    }
+
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.mode(tar::HeaderMode::Deterministic);
+    builder.follow_symlinks(false);
+    for path in files {
+        let rel = path.strip_prefix(dir).unwrap_or(&path);
+        builder
+            .append_path_with_name(&path, rel)
+            .with_context(|| format!("Failed to collect {}", rel.display()))?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// The output patterns of a task node; empty for other nodes.
+pub fn outputs(kind: &NodeKind) -> &[String] {
+    match kind {
+        NodeKind::Task { outputs, .. } => outputs,
+        _ => &[],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_to_graph() {
+        let manifest = TaskManifest::parse(
+            r#"{
+                "vars": { "profile": "release" },
+                "tasks": {
+                    "test": { "cmd": "cargo test", "deps": ["build"] },
+                    "build": {
+                        "cmd": "cargo build --{{profile}} && cp app {{outputs}}",
+                        "inputs": ["src/**"],
+                        "outputs": ["out/{{profile}}/app"],
+                        "env": { "CARGO_INCREMENTAL": "0" }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        let graph = manifest.into_graph().unwrap();
+        assert_eq!(graph.nodes[0].name, "build");
+        assert_eq!(
+            graph.nodes[0].kind,
+            NodeKind::Task {
+                cmd: "cargo build --release && cp app out/release/app".into(),
+                outputs: vec!["out/release/app".into()],
+                inputs: vec!["src/**".into()],
+            }
+        );
+        assert_eq!(graph.nodes[0].env["CARGO_INCREMENTAL"], "0");
+        assert_eq!(graph.nodes[1].deps, vec![0]);
+
+        let cyclic = r#"{ "tasks": {
+            "a": { "cmd": "true", "deps": ["b"] },
+            "b": { "cmd": "true", "deps": ["a"] }
+        } }"#;
+        assert!(TaskManifest::parse(cyclic).unwrap().into_graph().is_err());
+        let unknown = r#"{ "tasks": { "a": { "cmd": "echo {{nope}}" } } }"#;
+        assert!(TaskManifest::parse(unknown).unwrap().into_graph().is_err());
+        assert!(is_manifest(Path::new("ci/memobuild.tasks.json")));
+        assert!(!is_manifest(Path::new("graph.json")));
+    }
+
+    #[test]
+    fn test_collect_outputs() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(dir.path().join("dist/assets")).unwrap();
+        std::fs::write(dir.path().join("dist/app.js"), "app").unwrap();
+        std::fs::write(dir.path().join("dist/assets/logo.svg"), "<svg/>").unwrap();
+        std::fs::write(dir.path().join("src.js"), "src").unwrap();
+
+        let tar = collect_outputs(dir.path(), &["dist".to_string()]).unwrap();
+        let mut archive = tar::Archive::new(tar.as_slice());
+        let names: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, vec!["dist/app.js", "dist/assets/logo.svg"]);
+        // Same files, same archive
+        assert_eq!(
+            collect_outputs(dir.path(), &["dist".to_string()]).unwrap(),
+            tar
+        );
+        assert!(collect_outputs(dir.path(), &["build/*".to_string()]).is_err());
+    }
+}
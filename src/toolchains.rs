@@ -0,0 +1,309 @@
+//! Hermetic toolchains pinned per platform in `memobuild.toolchains.json`.
+
+use crate::cache::HybridCache;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// File name of the toolchain manifest, read from the build context
+pub const TOOLCHAINS_FILE: &str = "memobuild.toolchains.json";
+
+/// Marker written once a toolchain is completely unpacked
+const COMPLETE_MARKER: &str = ".memobuild-complete";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ToolDownload {
+    pub url: String,
+    /// Hex SHA-256 of the file at `url`
+    pub sha256: String,
+    /// Directory inside the archive holding the executables
+    #[serde(default)]
+    pub bin: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Tool {
+    pub version: String,
+    /// Downloads keyed by `<os>-<arch>`, e.g. `linux-x86_64` or `macos-aarch64`
+    pub platforms: BTreeMap<String, ToolDownload>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ToolchainManifest {
+    #[serde(default)]
+    pub tools: BTreeMap<String, Tool>,
+}
+
+/// A toolchain ready to use.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstalledToolchain {
+    pub name: String,
+    pub version: String,
+    pub sha256: String,
+    /// Directory to put on `PATH`
+    pub bin_dir: PathBuf,
+}
+
+/// `<os>-<arch>` of this machine, as used in the manifest.
+pub fn current_platform() -> String {
+    format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH)
+}
+
+impl ToolchainManifest {
+    /// Load the manifest in `context_dir`, if there is one.
+    pub fn load(context_dir: &Path) -> Result<Option<Self>> {
+        let path = context_dir.join(TOOLCHAINS_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map(Some)
+                .with_context(|| format!("Malformed toolchain manifest {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    /// The download of every tool for `platform`. A tool without one fails
+    /// the build rather than silently falling back to the host's copy.
+    pub fn resolve(&self, platform: &str) -> Result<Vec<(&str, &Tool, &ToolDownload)>> {
+        self.tools
+            .iter()
+            .map(|(name, tool)| {
+                let download = tool.platforms.get(platform).with_context(|| {
+                    format!(
+                        "Toolchain {} {} has no download for {}",
+                        name, tool.version, platform
+                    )
+                })?;
+                Ok((name.as_str(), tool, download))
+            })
+            .collect()
+    }
+}
+
+/// Cache key of a toolchain archive, derived from its pinned digest.
+pub fn cache_key(sha256: &str) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"toolchain\0");
+    hasher.update(sha256.to_ascii_lowercase().as_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Make every tool in `manifest` available under `root`, downloading only
+/// what neither `root` nor the cache has yet.
+pub async fn install(
+    manifest: &ToolchainManifest,
+    cache: &HybridCache,
+    root: &Path,
+) -> Result<Vec<InstalledToolchain>> {
+    let mut installed = Vec::new();
+    for (name, tool, download) in manifest.resolve(&current_platform())? {
+        let toolchain = planned_one(root, name, tool, download);
+        let sha256 = toolchain.sha256.clone();
+        let dir = install_dir(root, name, tool, &sha256);
+
+        if !dir.join(COMPLETE_MARKER).exists() {
+            let key = cache_key(&sha256);
+            let data = match cache.get_artifact(&key).await? {
+                Some(data) if sha256_hex(&data) == sha256 => data,
+                _ => {
+                    println!("   ⬇️  Downloading {} {}...", name, tool.version);
+                    let data = fetch(&download.url).await?;
+                    let actual = sha256_hex(&data);
+                    if actual != sha256 {
+                        anyhow::bail!(
+                            "Toolchain {} {} from {} has SHA-256 {}, expected {}",
+                            name,
+                            tool.version,
+                            download.url,
+                            actual,
+                            sha256
+                        );
+                    }
+                    cache
+                        .put_artifact_named(
+                            &key,
+                            &format!("toolchain-{}-{}", name, tool.version),
+                            &data,
+                        )
+                        .await?;
+                    data
+                }
+            };
+            unpack(&data, &download.url, name, &dir)?;
+        }
+
+        installed.push(toolchain);
+    }
+    Ok(installed)
+}
+
+/// What [`install`] reports for every tool in `manifest` under `root`,
+/// without downloading or unpacking anything: enough to key steps as a
+/// build with these toolchains would.
+pub fn planned(manifest: &ToolchainManifest, root: &Path) -> Result<Vec<InstalledToolchain>> {
+    Ok(manifest
+        .resolve(&current_platform())?
+        .into_iter()
+        .map(|(name, tool, download)| planned_one(root, name, tool, download))
+        .collect())
+}
+
+fn planned_one(
+    root: &Path,
+    name: &str,
+    tool: &Tool,
+    download: &ToolDownload,
+) -> InstalledToolchain {
+    let sha256 = download.sha256.to_ascii_lowercase();
+    let dir = install_dir(root, name, tool, &sha256);
+    InstalledToolchain {
+        name: name.to_string(),
+        version: tool.version.clone(),
+        sha256,
+        bin_dir: match download.bin {
+            Some(ref bin) => dir.join(bin),
+            None => dir,
+        },
+    }
+}
+
+fn install_dir(root: &Path, name: &str, tool: &Tool, sha256: &str) -> PathBuf {
+    root.join(format!(
+        "{}-{}-{}",
+        name,
+        tool.version,
+        &sha256[..12.min(sha256.len())]
+    ))
+}
+
+async fn fetch(url: &str) -> Result<Vec<u8>> {
+    let resp = reqwest::get(url)
+        .await
+        .with_context(|| format!("Failed to download {}", url))?;
+    if !resp.status().is_success() {
+        anyhow::bail!("Failed to download {}: {}", url, resp.status());
+    }
+    Ok(resp.bytes().await?.to_vec())
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Unpack a `.tar.gz`/`.tgz`/`.tar` archive, or install a single executable,
+/// into `dir`. Work happens in a sibling directory that is renamed into place.
+fn unpack(data: &[u8], url: &str, name: &str, dir: &Path) -> Result<()> {
+    let parent = dir.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(parent)?;
+    let tmp = parent.join(format!(".tmp-{}", uuid::Uuid::new_v4().simple()));
+    std::fs::create_dir_all(&tmp)?;
+
+    let file_name = url
+        .rsplit('/')
+        .next()
+        .unwrap_or(url)
+        .split('?')
+        .next()
+        .unwrap_or_default();
+    let result = (|| -> Result<()> {
+        if file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz") {
+            tar::Archive::new(flate2::read::GzDecoder::new(data)).unpack(&tmp)?;
+        } else if file_name.ends_with(".tar") {
+            tar::Archive::new(data).unpack(&tmp)?;
+        } else if file_name.ends_with(".zip") || file_name.ends_with(".xz") {
+            anyhow::bail!("Unsupported toolchain archive format: {}", file_name);
+        } else {
+            let path = tmp.join(name);
+            std::fs::write(&path, data)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+            }
+        }
+        std::fs::write(tmp.join(COMPLETE_MARKER), b"")?;
+        if dir.exists() {
+            std::fs::remove_dir_all(dir)?;
+        }
+        std::fs::rename(&tmp, dir)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_dir_all(&tmp);
+    }
+    result.with_context(|| format!("Failed to unpack toolchain {} into {}", name, dir.display()))
+}
+
+/// `PATH` with the toolchain directories in front of `path`.
+pub fn prepend_path(toolchains: &[PathBuf], path: Option<&str>) -> Result<String> {
+    let mut dirs: Vec<PathBuf> = toolchains.to_vec();
+    if let Some(path) = path {
+        dirs.extend(std::env::split_paths(path));
+    }
+    Ok(std::env::join_paths(dirs)?.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_install_from_cache() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = HybridCache {
+            local: crate::cache::LocalCache::in_dir(dir.path().join("cache")).unwrap(),
+            remote: None,
+            remote_health: None,
+            mirrors: None,
+            integrity: Default::default(),
+            uploads: Default::default(),
+            dedup_uploads: true,
+            prefetch_hints: false,
+            hinted: Default::default(),
+            max_artifact_bytes: None,
+        };
+
+        let binary = b"#!/bin/sh\necho 1.2.3\n";
+        let sha256 = sha256_hex(binary);
+        cache
+            .put_artifact(&cache_key(&sha256), binary)
+            .await
+            .unwrap();
+
+        let mut platforms = BTreeMap::new();
+        platforms.insert(
+            current_platform(),
+            ToolDownload {
+                // Never fetched: the archive is already cached
+                url: "https://invalid.example/tool".into(),
+                sha256: sha256.clone(),
+                bin: None,
+            },
+        );
+        let mut manifest = ToolchainManifest::default();
+        manifest.tools.insert(
+            "tool".into(),
+            Tool {
+                version: "1.2.3".into(),
+                platforms,
+            },
+        );
+
+        let root = dir.path().join("toolchains");
+        let installed = install(&manifest, &cache, &root).await.unwrap();
+        assert_eq!(installed.len(), 1);
+        assert_eq!(installed[0].sha256, sha256);
+        assert_eq!(
+            std::fs::read(installed[0].bin_dir.join("tool")).unwrap(),
+            binary
+        );
+
+        let path = prepend_path(&[installed[0].bin_dir.clone()], Some("/usr/bin")).unwrap();
+        assert!(path.starts_with(installed[0].bin_dir.to_str().unwrap()));
+
+        manifest.tools.get_mut("tool").unwrap().platforms.clear();
+        assert!(manifest.resolve(&current_platform()).is_err());
+    }
+}
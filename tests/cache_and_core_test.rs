@@ -40,6 +40,309 @@ mod cache_tests {
         let get_result = cache.get_artifact(hash).await;
         assert!(get_result.is_ok(), "Get should succeed");
     }
+
+    #[tokio::test]
+    async fn test_cache_invalidate() {
+        let cache = HybridCache::new(None).expect("Failed to create cache");
+
+        let hash = "test_hash_invalidate";
+        cache.put_artifact(hash, b"stale").await.unwrap();
+        assert!(cache.invalidate(hash, false).await.unwrap());
+
+        // The next build must miss
+        assert!(cache.get_artifact(hash).await.unwrap().is_none());
+        assert!(!cache.invalidate(hash, false).await.unwrap());
+    }
+
+    #[test]
+    fn test_pinned_entry_stays_pinned() {
+        use memobuild::cache::{LocalCache, PinTarget};
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = LocalCache::in_dir(dir.path().to_path_buf()).unwrap();
+        cache.put("release_key", b"v1").unwrap();
+        let target = PinTarget::Key("release_key".to_string());
+        assert_eq!(cache.set_pin(&target, Some("v1.0")).unwrap(), 1);
+
+        // Rebuilding the entry and reloading the index keep the pin
+        cache.put("release_key", b"v1 again").unwrap();
+        let reloaded = LocalCache::in_dir(dir.path().to_path_buf()).unwrap();
+        assert_eq!(reloaded.pin("release_key").as_deref(), Some("v1.0"));
+
+        assert_eq!(reloaded.set_pin(&target, None).unwrap(), 1);
+        assert_eq!(reloaded.pin("release_key"), None);
+        let missing = PinTarget::Key("missing".to_string());
+        assert_eq!(reloaded.set_pin(&missing, Some("keep")).unwrap(), 0);
+        assert!(PinTarget::from_parts(None, None).is_err());
+    }
+
+    #[test]
+    fn test_verify_removes_damaged_entries() {
+        use memobuild::cache::local::Damage;
+        use memobuild::cache::LocalCache;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = LocalCache::in_dir(dir.path().to_path_buf()).unwrap();
+        cache.put("intact", b"intact artifact").unwrap();
+        cache.put("truncated", b"truncated artifact").unwrap();
+        cache.put("flipped", b"flipped artifact").unwrap();
+        cache.put("missing", b"missing artifact").unwrap();
+        std::fs::write(dir.path().join("truncated.bin"), b"trunc").unwrap();
+        std::fs::write(dir.path().join("flipped.bin"), b"flipped artifacT").unwrap();
+        std::fs::remove_file(dir.path().join("missing.bin")).unwrap();
+
+        let mut report = cache.verify().unwrap();
+        report.removed.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(report.checked, 4);
+        assert_eq!(
+            report.removed,
+            vec![
+                ("flipped".to_string(), Damage::Corrupted),
+                ("missing".to_string(), Damage::Missing),
+                (
+                    "truncated".to_string(),
+                    Damage::SizeMismatch {
+                        expected: 18,
+                        actual: 5
+                    }
+                ),
+            ]
+        );
+        assert!(cache.exists("intact"));
+        assert!(!cache.exists("flipped"));
+        assert_eq!(cache.verify().unwrap().removed.len(), 0);
+    }
+
+    #[test]
+    fn test_prune_idle_tolerates_clock_skew() {
+        use memobuild::cache::LocalCache;
+        use std::time::Duration;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = LocalCache::in_dir(dir.path().to_path_buf()).unwrap();
+        for key in ["stale", "read", "borderline", "future"] {
+            cache.put(key, key.as_bytes()).unwrap();
+        }
+
+        // Backdate the stamps as if written by machines with other clocks
+        let now = chrono::Utc::now().timestamp();
+        let day = 86_400;
+        let index_path = dir.path().join("index.json");
+        let mut index: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&index_path).unwrap()).unwrap();
+        for (key, at) in [
+            ("stale", now - 10 * day),
+            ("read", now - 10 * day),
+            ("borderline", now - day - 60),
+            ("future", now + 365 * day),
+        ] {
+            index[key]["created_at"] = at.into();
+        }
+        std::fs::write(&index_path, index.to_string()).unwrap();
+
+        let cache = LocalCache::in_dir(dir.path().to_path_buf()).unwrap();
+        assert!(cache.get_data("read").unwrap().is_some());
+        cache.flush_access().unwrap();
+        let cache = LocalCache::in_dir(dir.path().to_path_buf()).unwrap();
+
+        assert_eq!(
+            cache.prune_idle(Duration::from_secs(day as u64)).unwrap(),
+            1
+        );
+        assert!(!cache.exists("stale"));
+        assert!(cache.exists("read"));
+        assert!(cache.exists("borderline"));
+        assert!(cache.exists("future"));
+    }
+
+    #[test]
+    fn test_prune_dry_run_reports_reasons() {
+        use memobuild::cache::LocalCache;
+        use memobuild::gc::GcReason;
+        use std::time::{Duration, SystemTime};
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = LocalCache::in_dir(dir.path().to_path_buf()).unwrap();
+        for key in ["old", "mid", "new"] {
+            cache.put(key, b"0123456789").unwrap();
+        }
+        let now = chrono::Utc::now().timestamp();
+        let day = 86_400;
+        let index_path = dir.path().join("index.json");
+        let mut index: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&index_path).unwrap()).unwrap();
+        index["old"]["created_at"] = (now - 10 * day).into();
+        index["mid"]["created_at"] = (now - 2 * day).into();
+        std::fs::write(&index_path, index.to_string()).unwrap();
+        // Left behind by a crashed build, and one still being recorded
+        let stray = dir.path().join("stray.bin");
+        std::fs::write(&stray, "1234567").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&stray)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(7200))
+            .unwrap();
+        std::fs::write(dir.path().join("fresh.bin"), "1234567").unwrap();
+
+        let cache = LocalCache::in_dir(dir.path().to_path_buf()).unwrap();
+        let plan = cache
+            .plan_prune(Duration::from_secs(5 * day as u64), Some(10))
+            .unwrap();
+        let planned: Vec<(&str, GcReason)> = plan
+            .candidates
+            .iter()
+            .map(|c| (c.key.as_str(), c.reason))
+            .collect();
+        assert_eq!(
+            planned,
+            vec![
+                ("old", GcReason::Age),
+                ("mid", GcReason::Lru),
+                ("stray.bin", GcReason::Unreferenced),
+            ]
+        );
+        assert_eq!(plan.bytes, 27);
+        // Planning removes nothing
+        assert!(cache.exists("old") && cache.exists("mid") && stray.exists());
+
+        assert_eq!(cache.apply_prune(&plan).unwrap(), plan);
+        assert!(!cache.exists("old") && !cache.exists("mid") && !stray.exists());
+        assert!(cache.exists("new"));
+        assert!(dir.path().join("fresh.bin").exists());
+    }
+
+    #[test]
+    fn test_project_root_and_cache_scope() {
+        use memobuild::cache::local::{project_root, CacheScope};
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let repo = dir.path().join("repo");
+        let nested = repo.join("services").join("api");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::create_dir(repo.join(".git")).unwrap();
+        let repo = repo.canonicalize().unwrap();
+        assert_eq!(project_root(&nested), repo);
+
+        let outside = dir.path().join("scratch");
+        std::fs::create_dir(&outside).unwrap();
+        assert_eq!(project_root(&outside), outside.canonicalize().unwrap());
+
+        assert_eq!(
+            "project".parse::<CacheScope>().unwrap(),
+            CacheScope::Project
+        );
+        assert_eq!("Global".parse::<CacheScope>().unwrap(), CacheScope::Global);
+        assert!("shared".parse::<CacheScope>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upload_skips_layers_remote_has() {
+        use memobuild::cache::{FsRemoteCache, LocalCache, RemoteCache};
+        use std::sync::Arc;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let remote: Arc<dyn RemoteCache> =
+            Arc::new(FsRemoteCache::new(&dir.path().join("remote")).unwrap());
+        let agent = |name: &str| {
+            let mut cache = HybridCache::new(Some(remote.clone())).unwrap();
+            cache.local = LocalCache::in_dir(dir.path().join(name)).unwrap();
+            cache
+        };
+        let data = b"identical output of two CI agents".to_vec();
+
+        let first = agent("a");
+        first.put_artifact("key_a", &data).await.unwrap();
+        assert_eq!(first.uploads.skipped().0, 0);
+        assert!(first.uploads.summary().is_none());
+
+        // A second agent producing the same bytes uploads nothing
+        let second = agent("b");
+        second.put_artifact("key_b", &data).await.unwrap();
+        assert_eq!(second.uploads.uploaded().0, 0);
+        assert_eq!(second.uploads.skipped().1, data.len() as u64);
+        assert!(second.uploads.summary().is_some());
+
+        let third = agent("c").with_upload_dedup(false);
+        third.put_artifact("key_c", &data).await.unwrap();
+        assert_eq!(third.uploads.skipped().0, 0);
+        assert_eq!(third.uploads.uploaded().1, data.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_artifact_roundtrip() {
+        use memobuild::cache::{FsRemoteCache, LocalCache, RemoteCache};
+        use memobuild::report::CacheSource;
+        use std::io::Read;
+        use std::sync::Arc;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let remote: Arc<dyn RemoteCache> =
+            Arc::new(FsRemoteCache::new(&dir.path().join("remote")).unwrap());
+        let agent = |name: &str| {
+            let mut cache = HybridCache::new(Some(remote.clone())).unwrap();
+            cache.local = LocalCache::in_dir(dir.path().join(name)).unwrap();
+            cache
+        };
+        // Spans several layers
+        let data: Vec<u8> = (0..(2 * memobuild::cache::utils::CHUNK_SIZE + 17))
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        let writer = agent("a");
+        let size = writer
+            .put_from_reader("big", "artifact", &mut data.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(writer.local.get_data("big").unwrap().unwrap(), data);
+
+        let reader = agent("b");
+        let (mut stream, source) = reader.lookup_reader("big").await.unwrap().unwrap();
+        assert_eq!(source, CacheSource::Remote);
+        let mut restored = Vec::new();
+        stream.read_to_end(&mut restored).unwrap();
+        assert_eq!(restored, data);
+        // The download landed in the local cache
+        let (_, source) = reader.lookup_reader("big").await.unwrap().unwrap();
+        assert_eq!(source, CacheSource::Local);
+        assert!(reader.lookup_reader("missing").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_artifact_roundtrip() {
+        use memobuild::cache::{EncryptedRemoteCache, FsRemoteCache, LocalCache, RemoteCache};
+        use std::sync::Arc;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let server: Arc<dyn RemoteCache> =
+            Arc::new(FsRemoteCache::new(&dir.path().join("remote")).unwrap());
+        let agent = |name: &str, key: [u8; 32]| {
+            let remote = EncryptedRemoteCache::new(server.clone(), key);
+            let mut cache =
+                HybridCache::new(Some(Arc::new(remote) as Arc<dyn RemoteCache>)).unwrap();
+            cache.local = LocalCache::in_dir(dir.path().join(name)).unwrap();
+            cache
+        };
+        let data = b"build output that must not reach the server in clear".to_vec();
+
+        agent("a", [4u8; 32])
+            .put_artifact("key", &data)
+            .await
+            .unwrap();
+
+        // The server only holds ciphertext
+        let names = server.get_node_layers("key").await.unwrap().unwrap();
+        assert!(!names.contains(&blake3::hash(&data).to_hex().to_string()));
+        for name in &names {
+            let stored = server.get_layer(name).await.unwrap().unwrap();
+            assert!(!stored.windows(data.len()).any(|w| w == data.as_slice()));
+        }
+
+        let restored = agent("b", [4u8; 32]).get_artifact("key").await.unwrap();
+        assert_eq!(restored, Some(data));
+        assert!(agent("c", [5u8; 32]).get_artifact("key").await.is_err());
+    }
 }
 
 /// Tests for hasher module
@@ -168,6 +471,59 @@ mod change_detection_tests {
         assert!(node.dirty, "Dirty flag should track rebuild necessity");
     }
 
+    #[test]
+    fn test_salt_directive_changes_key() {
+        let key = |metadata: &NodeMetadata| {
+            let mut hasher = blake3::Hasher::new();
+            metadata.hash_key_inputs(&mut hasher);
+            hasher.finalize()
+        };
+        let mut metadata = NodeMetadata::default();
+        let plain = key(&metadata);
+
+        metadata.directives.insert("salt".into(), "v2".into());
+        let v2 = key(&metadata);
+        assert_ne!(plain, v2, "Salt should change the key");
+
+        metadata.directives.insert("salt".into(), "v3".into());
+        assert_ne!(v2, key(&metadata), "Bumping the salt should change the key");
+    }
+
+    #[test]
+    fn test_platform_partitions_key() {
+        use memobuild::docker::{dag, parser};
+        use memobuild::env::EnvFingerprint;
+
+        let dockerfile = "FROM alpine\nRUN make\n# memobuild:platform=any\nRUN ./fetch-docs.sh\n";
+        let hashes = |os: &str, arch: &str| {
+            let mut graph = dag::build_graph_from_spanned(
+                parser::parse_dockerfile_spanned(dockerfile, std::path::Path::new("Dockerfile")),
+                std::path::PathBuf::from("."),
+            );
+            let env_fp = EnvFingerprint {
+                os: os.into(),
+                arch: arch.into(),
+                ..Default::default()
+            };
+            memobuild::core::compute_composite_hashes(&mut graph, &env_fp);
+            graph
+        };
+        let linux = hashes("linux", "x86_64");
+        let mac = hashes("macos", "aarch64");
+
+        assert_ne!(linux.nodes[1].hash, mac.nodes[1].hash);
+        assert_eq!(
+            linux.nodes[1].metadata.platform.as_deref(),
+            Some("linux/amd64")
+        );
+        assert_eq!(
+            mac.nodes[1].metadata.platform.as_deref(),
+            Some("darwin/arm64")
+        );
+        assert_eq!(linux.nodes[2].hash, mac.nodes[2].hash);
+        assert_eq!(linux.nodes[2].metadata.platform, None);
+    }
+
     #[test]
     fn test_node_key_generation() {
         let node = Node {
@@ -245,6 +601,154 @@ mod change_detection_tests {
             }
         }
     }
+
+    #[test]
+    fn test_graph_builder_validates_steps() {
+        use memobuild::graph::GraphBuilder;
+
+        let mut builder = GraphBuilder::new();
+        builder
+            .add_task("package", "tar czf app.tgz dist")
+            .depends_on("build")
+            .depends_on("lint");
+        builder.add_task("lint", "eslint src");
+        builder
+            .add_task("build", "npm run build")
+            .with_inputs(["src/**"])
+            .with_outputs(["dist"])
+            .with_env("NODE_ENV", "production");
+        builder
+            .add_step("setup", NodeKind::Run, "npm ci")
+            .with_inputs(["package-lock.json"]);
+        let graph = builder.build().unwrap();
+
+        let names: Vec<&str> = graph.nodes.iter().map(|n| n.name.as_str()).collect();
+        assert_eq!(names, vec!["lint", "build", "setup", "package"]);
+        assert_eq!(graph.nodes[3].deps, vec![0, 1]);
+        assert_eq!(graph.levels(), vec![vec![0, 1, 2], vec![3]]);
+        assert_eq!(
+            graph.nodes[1].kind,
+            NodeKind::Task {
+                cmd: "npm run build".into(),
+                outputs: vec!["dist".into()],
+                inputs: vec!["src/**".into()],
+            }
+        );
+        assert_eq!(graph.nodes[1].env["NODE_ENV"], "production");
+        // RUN steps take their inputs as a directive
+        assert_eq!(
+            graph.nodes[2].metadata.directives["inputs"],
+            "package-lock.json"
+        );
+
+        let mut cyclic = GraphBuilder::new();
+        cyclic.add_task("a", "true").depends_on("b");
+        cyclic.add_task("b", "true").depends_on("a");
+        assert!(cyclic.build().is_err());
+
+        let mut unknown = GraphBuilder::new();
+        unknown.add_task("a", "true").depends_on("missing");
+        assert!(unknown.build().is_err());
+
+        let mut duplicate = GraphBuilder::new();
+        duplicate.add_task("a", "true");
+        duplicate.add_task("a", "false");
+        assert!(duplicate.build().is_err());
+
+        let mut env_outputs = GraphBuilder::new();
+        env_outputs
+            .add_step("env", NodeKind::Env, "ENV A=1")
+            .with_outputs(["out"]);
+        assert!(env_outputs.build().is_err());
+    }
+
+    #[test]
+    fn test_graph_diff_reports_invalidated_steps() {
+        use memobuild::docker::{dag, parser};
+        use memobuild::graph::{diff, ChangeReason};
+
+        let graph = |dockerfile: &str| {
+            dag::build_graph_from_instructions(
+                parser::parse_dockerfile(dockerfile),
+                std::path::PathBuf::from("."),
+            )
+        };
+        let old = graph("FROM alpine\nRUN apk add make\nRUN make\nRUN make test\n");
+        let new = graph("FROM alpine\nRUN apk add make gcc\nRUN make\nENV CI=1\nRUN make test\n");
+
+        let d = diff(&old, &new);
+        assert_eq!(d.unchanged, 1, "FROM is untouched");
+        assert_eq!(d.added.len(), 1);
+        assert_eq!(d.added[0].id, 3);
+        assert!(d.removed.is_empty());
+        assert_eq!(d.invalidated(), 4);
+
+        let ids: Vec<usize> = d.changed.iter().map(|c| c.new_id).collect();
+        assert_eq!(ids, vec![1, 2, 4]);
+        assert!(matches!(
+            d.changed[0].reasons[0],
+            ChangeReason::Instruction { .. }
+        ));
+        assert!(d.changed[1]
+            .reasons
+            .iter()
+            .any(|r| matches!(r, ChangeReason::Dependency { id: 1, .. })));
+        assert!(d.changed[2]
+            .reasons
+            .iter()
+            .any(|r| matches!(r, ChangeReason::Dependency { id: 3, .. })));
+
+        assert!(diff(&old, &old).is_empty());
+        let back = diff(&new, &old);
+        assert_eq!(back.removed.len(), 1);
+        assert_eq!(back.removed[0].id, 3);
+    }
+    #[test]
+    fn test_dirty_reasons_since_last_build() {
+        use memobuild::core;
+        use memobuild::docker::{dag, parser};
+        use memobuild::graph::DirtyReason;
+        use memobuild::history::BuildHistory;
+
+        let dockerfile = "FROM alpine\nENV MODE=debug\nRUN make\nRUN make test\n";
+        let mut graph = dag::build_graph_from_instructions(
+            parser::parse_dockerfile(dockerfile),
+            std::path::PathBuf::from("."),
+        );
+        core::detect_changes(&mut graph);
+        assert!(graph
+            .nodes
+            .iter()
+            .all(|n| n.metadata.dirty_reason == Some(DirtyReason::NewNode)));
+
+        for node in &mut graph.nodes {
+            node.hash = format!("hash{}", node.id);
+        }
+        let mut history = BuildHistory::default();
+        history.record_graph(&graph, |_| None);
+
+        core::explain_changes(&mut graph, &history);
+        assert!(graph
+            .nodes
+            .iter()
+            .all(|n| n.metadata.dirty_reason.is_none()));
+
+        graph.nodes[2].hash = "changed".into();
+        graph.nodes[2]
+            .metadata
+            .build_env
+            .insert("MODE".into(), "release".into());
+        core::explain_changes(&mut graph, &history);
+        assert_eq!(graph.nodes[1].metadata.dirty_reason, None);
+        assert_eq!(
+            graph.nodes[2].metadata.dirty_reason,
+            Some(DirtyReason::EnvChanged("MODE".into()))
+        );
+        assert_eq!(
+            graph.nodes[3].metadata.dirty_reason,
+            Some(DirtyReason::ParentDirty(2))
+        );
+    }
 }
 
 /// Environment fingerprinting tests
@@ -125,6 +125,45 @@ RUN npm install --only=production
     );
 }
 
+#[test]
+fn test_independent_copies() {
+    // Copies of unrelated files don't depend on each other; the next RUN
+    // waits for all of them, and overlapping copies stay ordered
+    let dockerfile_content = r#"
+FROM node:16-alpine
+WORKDIR /app
+COPY package.json .
+COPY package-lock.json .
+RUN npm ci
+COPY src/ /app/src/
+COPY config/ /etc/app/
+COPY src/main.js /app/src/
+"#;
+
+    let instructions = docker::parser::parse_dockerfile(dockerfile_content);
+    let graph = docker::dag::build_graph_from_instructions(
+        instructions,
+        std::env::current_dir().unwrap_or_default(),
+    );
+    assert_eq!(graph.nodes.len(), 8);
+
+    assert_eq!(graph.nodes[2].deps, vec![1]);
+    assert_eq!(graph.nodes[3].deps, vec![1]);
+    let mut run_deps = graph.nodes[4].deps.clone();
+    run_deps.sort();
+    assert_eq!(run_deps, vec![1, 2, 3]);
+
+    assert_eq!(graph.nodes[5].deps, vec![4]);
+    assert_eq!(graph.nodes[6].deps, vec![4]);
+    assert_eq!(graph.nodes[7].deps, vec![4, 5]);
+
+    let levels = graph.levels();
+    let level_of = |id: usize| levels.iter().position(|l| l.contains(&id)).unwrap();
+    assert_eq!(level_of(2), level_of(3));
+    assert_eq!(level_of(5), level_of(6));
+    assert!(level_of(7) > level_of(5));
+}
+
 #[test]
 fn test_content_addressed_identities() {
     // Test that compute_node_key produces consistent, content-addressed hashes
@@ -184,7 +223,7 @@ async fn test_end_to_end_build_with_remote_cache() {
     let port = 9991;
     let server_path_clone = server_path.clone();
     tokio::spawn(async move {
-        server::start_server(port, server_path_clone, None, None, None, None)
+        server::start_server(port, server_path_clone, None, None, None, None, None)
             .await
             .ok();
     });
@@ -3,7 +3,7 @@
 mod executor_tests {
     use memobuild::graph::{BuildGraph, Node, NodeKind, NodeMetadata};
 
-    fn create_mock_graph() -> BuildGraph {
+    pub(crate) fn create_mock_graph() -> BuildGraph {
         // Create a simple linear DAG: FROM -> COPY -> RUN
         let mut graph = BuildGraph::new();
         graph.nodes = vec![
@@ -188,6 +188,24 @@ RUN cd /app && ls
         assert_eq!(instructions.len(), 4);
     }
 
+    #[test]
+    fn test_spans_are_threaded_into_nodes() {
+        let dockerfile = "FROM alpine:3.19\n\n  RUN echo hi\n";
+
+        let instructions =
+            docker::parser::parse_dockerfile_spanned(dockerfile, std::path::Path::new("Dockerfile"));
+        assert_eq!(instructions[1].span.line, 3);
+        assert_eq!(instructions[1].span.column, 3);
+
+        let dag = docker::dag::build_graph_from_spanned(
+            instructions,
+            std::env::current_dir().unwrap_or_default(),
+        );
+        let span = dag.nodes[1].metadata.span.as_ref().unwrap();
+        assert_eq!(span.to_string(), "Dockerfile:3:3");
+        assert_eq!(dag.nodes[1].location_prefix(), "Dockerfile:3:3: ");
+    }
+
     #[test]
     fn test_dag_building_from_dockerfile() {
         let dockerfile = r#"
@@ -252,6 +270,7 @@ RUN python3 --version
 /// Cache behavior tests
 #[cfg(test)]
 mod cache_behavior_tests {
+    use crate::executor_tests::create_mock_graph;
     use memobuild::graph::{BuildGraph, Node, NodeKind, NodeMetadata};
 
     #[test]
@@ -327,4 +346,129 @@ mod cache_behavior_tests {
         assert_eq!(node.metadata.priority, 0);
         assert!(node.metadata.tags.is_empty());
     }
+
+    #[tokio::test]
+    async fn test_execute_graph_reports_outcomes() {
+        use memobuild::report::{CacheSource, NodeOutcome};
+        use std::sync::Arc;
+
+        let cache_dir = tempfile::tempdir().unwrap();
+        std::env::set_var("MEMOBUILD_CACHE_DIR", cache_dir.path());
+        let cache = Arc::new(memobuild::cache::HybridCache::new(None).unwrap());
+
+        // FROM and COPY without a source path need no sandbox
+        let mut graph = create_mock_graph();
+        graph.nodes.truncate(2);
+
+        let first = memobuild::executor::execute_graph(&mut graph, cache.clone(), None, false)
+            .await
+            .unwrap();
+        assert!(first.succeeded());
+        assert_eq!(first.count(NodeOutcome::Executed), 2);
+        for node in &first.nodes {
+            assert_eq!(node.cache_source, CacheSource::None);
+        }
+
+        let second = memobuild::executor::execute_graph(&mut graph, cache, None, false)
+            .await
+            .unwrap();
+        assert_eq!(second.count_source(CacheSource::Local), 2);
+        for (before, after) in first.nodes.iter().zip(&second.nodes) {
+            assert_eq!(after.outcome, NodeOutcome::Cached);
+            assert_eq!(before.artifact_digest, after.artifact_digest);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_build_hooks() {
+        use memobuild::execution::{BuildHook, NodeEnd};
+        use memobuild::report::{BuildReport, CacheSource, NodeOutcome};
+        use std::sync::{Arc, Mutex};
+
+        /// Records every call and keeps COPY artifacts out of the cache
+        #[derive(Default)]
+        struct NoCopyUploads {
+            events: Mutex<Vec<String>>,
+        }
+
+        impl BuildHook for NoCopyUploads {
+            fn name(&self) -> &str {
+                "no-copy-uploads"
+            }
+            fn on_graph_resolved(&self, graph: &BuildGraph) -> anyhow::Result<()> {
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(format!("resolved {}", graph.nodes.len()));
+                Ok(())
+            }
+            fn on_node_start(&self, node: &Node) -> anyhow::Result<()> {
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(format!("start {}", node.name));
+                Ok(())
+            }
+            fn on_cache_hit(&self, node: &Node, _source: CacheSource) {
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(format!("hit {}", node.name));
+            }
+            fn allow_store(&self, node: &Node, _artifact: &[u8]) -> bool {
+                !node.name.starts_with("COPY")
+            }
+            fn on_node_end(&self, node: &Node, end: &NodeEnd<'_>) {
+                assert!(end.error.is_none());
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(format!("end {}", node.name));
+            }
+            fn on_build_end(&self, report: &BuildReport, error: Option<&anyhow::Error>) {
+                assert!(error.is_none());
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(format!("build end {}", report.nodes.len()));
+            }
+        }
+
+        let cache_dir = tempfile::tempdir().unwrap();
+        std::env::set_var("MEMOBUILD_CACHE_DIR", cache_dir.path());
+        let cache = Arc::new(memobuild::cache::HybridCache::new(None).unwrap());
+        let mut graph = create_mock_graph();
+        graph.nodes.truncate(2);
+        for node in &mut graph.nodes {
+            node.hash = format!("hooks_{}", node.hash);
+        }
+
+        let hook = Arc::new(NoCopyUploads::default());
+        let mut executor = memobuild::executor::IncrementalExecutor::new(cache.clone())
+            .with_quiet(true)
+            .with_hook(hook.clone());
+        executor.execute(&mut graph).await.unwrap();
+        assert_eq!(
+            *hook.events.lock().unwrap(),
+            vec![
+                "resolved 2",
+                "start FROM nginx",
+                "end FROM nginx",
+                "start COPY app",
+                "end COPY app",
+                "build end 2",
+            ]
+        );
+
+        // FROM was stored and is restored; the refused COPY runs again
+        hook.events.lock().unwrap().clear();
+        executor.execute(&mut graph).await.unwrap();
+        assert!(hook
+            .events
+            .lock()
+            .unwrap()
+            .contains(&"hit FROM nginx".to_string()));
+        assert_eq!(executor.report().count(NodeOutcome::Cached), 1);
+        assert_eq!(executor.report().count(NodeOutcome::Executed), 1);
+    }
 }